@@ -0,0 +1,1051 @@
+//! HTTP API
+//!
+//! What the routes of the HTTP server take and send back, as described in the
+//! OpenAPI document it serves at `/openapi.json`:
+//!
+//! - `ApiError`: the envelope of every failure, `{"success": false, "error": ...}`,
+//!   with the offending `fields` of invalid bodies
+//! - `ValidJson`: JSON bodies checked against their `Validate` rules, answered
+//!   400 before the handler runs when they break one
+//! - `OptionalJson`: the same for bodies a route can do without; an empty body
+//!   is none, a broken one is still answered 400
+//! - `json_error` and `query_error`: the same envelope for bodies and query
+//!   strings that don't parse
+//! - the bodies of every route that succeeds, from the auth, session and API
+//!   key routes to those of workflows, schedules, triggers, webhooks,
+//!   pipelines, schemas, search and documents
+//!
+//! Documents, and what scripts return, are described as plain objects.
+
+use crate::auth::{api_keys, oidc, sessions};
+use crate::{pipelines, scheduler, triggers, webhooks, workflow};
+use actix_web::dev::Payload;
+use actix_web::error::{InternalError, JsonPayloadError, QueryPayloadError};
+use actix_web::http::StatusCode;
+use actix_web::{mime, web, FromRequest, HttpMessage, HttpRequest, HttpResponse, ResponseError};
+use chrono::{DateTime, Utc};
+use futures::future::LocalBoxFuture;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::HashMap;
+use utoipa::ToSchema;
+use validator::{Validate, ValidationError, ValidationErrors, ValidationErrorsKind};
+
+/// Body of every failed request
+#[derive(Debug, Clone, PartialEq, Serialize, ToSchema)]
+pub struct ApiError {
+    /// Always `false`
+    pub success: bool,
+    pub error: String,
+    /// What is wrong with each field of an invalid body
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub fields: Vec<FieldError>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, ToSchema)]
+pub struct FieldError {
+    /// As `email`, `user.email` or `items[2].name`
+    pub field: String,
+    pub message: String,
+}
+
+impl ApiError {
+    pub fn new(error: impl Into<String>) -> Self {
+        Self {
+            success: false,
+            error: error.into(),
+            fields: Vec::new(),
+        }
+    }
+
+    /// The failures of a body's validation, field by field
+    pub fn invalid(errors: &ValidationErrors) -> Self {
+        let mut fields = Vec::new();
+        field_errors(errors, "", &mut fields);
+        fields.sort_by(|a, b| a.field.cmp(&b.field));
+        let summary = fields.iter()
+            .map(|f| format!("'{}' {}", f.field, f.message))
+            .collect::<Vec<_>>()
+            .join(", ");
+        Self {
+            success: false,
+            error: format!("Invalid request: {}", summary),
+            fields,
+        }
+    }
+}
+
+fn field_errors(errors: &ValidationErrors, prefix: &str, fields: &mut Vec<FieldError>) {
+    for (name, kind) in errors.errors() {
+        let path = if prefix.is_empty() { name.to_string() } else { format!("{}.{}", prefix, name) };
+        match kind {
+            ValidationErrorsKind::Field(errors) => fields.extend(errors.iter().map(|e| FieldError {
+                field: path.clone(),
+                message: describe(e),
+            })),
+            ValidationErrorsKind::Struct(errors) => field_errors(errors, &path, fields),
+            ValidationErrorsKind::List(items) => {
+                for (index, errors) in items {
+                    field_errors(errors, &format!("{}[{}]", path, index), fields);
+                }
+            }
+        }
+    }
+}
+
+/// What a broken rule means for the client, unless the rule says it itself
+fn describe(e: &ValidationError) -> String {
+    if let Some(message) = &e.message {
+        return message.to_string();
+    }
+    let param = |name: &str| e.params.get(name).map(|value| value.to_string());
+    let empty = matches!(e.params.get("value"), Some(Value::String(s)) if s.is_empty());
+    match (e.code.as_ref(), param("min"), param("max")) {
+        ("email", _, _) => "must be an email address".to_string(),
+        ("url", _, _) => "must be a URL".to_string(),
+        ("length", Some(_), _) if empty => "must not be empty".to_string(),
+        ("length", Some(min), Some(max)) => format!("must be {} to {} characters", min, max),
+        ("length", Some(min), None) => format!("must be at least {} characters", min),
+        ("length", None, Some(max)) => format!("must be at most {} characters", max),
+        ("range", Some(min), Some(max)) => format!("must be between {} and {}", min, max),
+        ("range", Some(min), None) => format!("must be at least {}", min),
+        ("range", None, Some(max)) => format!("must be at most {}", max),
+        _ => "is invalid".to_string(),
+    }
+}
+
+/// A refusal with the envelope, for extractors
+fn refusal(status: StatusCode, error: ApiError) -> actix_web::Error {
+    let message = error.error.clone();
+    InternalError::from_response(message, HttpResponse::build(status).json(error)).into()
+}
+
+/// A JSON body that passed its `Validate` rules; handlers are not called with
+/// invalid ones, which are answered 400 with what is wrong with each field
+#[derive(Debug)]
+pub struct ValidJson<T>(pub T);
+
+impl<T> ValidJson<T> {
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+impl<T> std::ops::Deref for ValidJson<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T: DeserializeOwned + Validate + 'static> FromRequest for ValidJson<T> {
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        let body = web::Json::<T>::from_request(req, payload);
+        Box::pin(async move {
+            let body = body.await?.into_inner();
+            match body.validate() {
+                Ok(()) => Ok(ValidJson(body)),
+                Err(errors) => Err(refusal(StatusCode::BAD_REQUEST, ApiError::invalid(&errors))),
+            }
+        })
+    }
+}
+
+/// A JSON body a route can do without: `None` when the request has no body,
+/// otherwise parsed and validated as `ValidJson` would, so a body that is
+/// there but doesn't parse is answered 400 rather than ignored
+#[derive(Debug)]
+pub struct OptionalJson<T>(pub Option<T>);
+
+impl<T> OptionalJson<T> {
+    pub fn into_inner(self) -> Option<T> {
+        self.0
+    }
+}
+
+impl<T: DeserializeOwned + Validate + 'static> FromRequest for OptionalJson<T> {
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        let req = req.clone();
+        let bytes = web::Bytes::from_request(&req, payload);
+        Box::pin(async move {
+            let bytes = bytes.await?;
+            if bytes.iter().all(u8::is_ascii_whitespace) {
+                return Ok(OptionalJson(None));
+            }
+            let is_json = matches!(req.mime_type(), Ok(Some(mime))
+                if mime.subtype() == mime::JSON || mime.suffix() == Some(mime::JSON));
+            if !is_json {
+                return Err(json_error(JsonPayloadError::ContentType, &req));
+            }
+            let body: T = serde_json::from_slice(&bytes)
+                .map_err(|e| json_error(JsonPayloadError::Deserialize(e), &req))?;
+            match body.validate() {
+                Ok(()) => Ok(OptionalJson(Some(body))),
+                Err(errors) => Err(refusal(StatusCode::BAD_REQUEST, ApiError::invalid(&errors))),
+            }
+        })
+    }
+}
+
+/// Error handler of `web::JsonConfig`: bodies that are not JSON, or not of the
+/// expected shape, are answered with the envelope, and 400 rather than 422
+pub fn json_error(e: JsonPayloadError, _req: &HttpRequest) -> actix_web::Error {
+    match &e {
+        JsonPayloadError::Deserialize(cause) => {
+            refusal(StatusCode::BAD_REQUEST, ApiError::new(format!("Invalid JSON body: {}", cause)))
+        }
+        JsonPayloadError::ContentType => refusal(
+            e.status_code(),
+            ApiError::new("Expected a JSON body with Content-Type: application/json"),
+        ),
+        _ => refusal(e.status_code(), ApiError::new(e.to_string())),
+    }
+}
+
+/// Error handler of `web::QueryConfig`, as `json_error` for query strings
+pub fn query_error(e: QueryPayloadError, _req: &HttpRequest) -> actix_web::Error {
+    let error = match &e {
+        QueryPayloadError::Deserialize(cause) => format!("Invalid query string: {}", cause),
+        _ => e.to_string(),
+    };
+    refusal(StatusCode::BAD_REQUEST, ApiError::new(error))
+}
+
+/// `GET /health`
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct Health {
+    /// `healthy` whenever the server answers
+    pub status: String,
+    pub service: String,
+    pub version: String,
+    pub timestamp: DateTime<Utc>,
+}
+
+/// A plain success
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct Done {
+    /// Always `true`
+    pub success: bool,
+}
+
+/// A success worth telling the user about
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct Message {
+    /// Always `true`
+    pub success: bool,
+    pub message: String,
+}
+
+/// A login code was emailed, to send back with `/auth/verify-code`
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct CodeSent {
+    /// Always `true`
+    pub success: bool,
+    pub message: String,
+    /// Always `true`
+    pub requires_code: bool,
+}
+
+/// Tokens of a session: signing in with a code, a link, a second factor or a
+/// provider, and refreshing, all answer with one
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct Session {
+    /// Always `true`
+    pub success: bool,
+    pub message: String,
+    /// Bearer token of the other routes
+    pub access_token: String,
+    /// Same as `access_token`, for older clients
+    #[schema(deprecated)]
+    pub token: String,
+    pub expires_at: Option<String>,
+    /// For `/auth/refresh`, once the access token expires
+    pub refresh_token: Option<String>,
+    pub refresh_expires_at: Option<String>,
+    pub session_id: Option<String>,
+    /// Always `false`
+    pub requires_code: bool,
+    /// Who signed in; not sent on refresh
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user: Option<SessionUser>,
+    /// Recovery codes left, after signing in with one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub recovery_codes_left: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct SessionUser {
+    pub email: Option<String>,
+    pub workspace: String,
+}
+
+/// A sign-in right so far, waiting for the code of the user's authenticator
+/// app at `/auth/two-factor/verify`
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct TwoFactorChallenge {
+    /// Always `true`
+    pub success: bool,
+    pub message: String,
+    /// Always `true`
+    pub requires_two_factor: bool,
+    pub challenge: String,
+    pub challenge_expires_at: Option<String>,
+    /// `false` when the user's role requires a second factor they must enroll
+    /// first, with the challenge, at `/auth/two-factor/enroll`
+    pub enrolled: bool,
+    /// Always `false`
+    pub requires_code: bool,
+}
+
+/// What signing in answers: a session, or the challenge of the second factor
+#[derive(Debug, Clone, Serialize, ToSchema)]
+#[serde(untagged)]
+pub enum SignIn {
+    Session(Session),
+    Challenge(TwoFactorChallenge),
+}
+
+/// An account of a provider linked to the signed-in user
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct Linked {
+    /// Always `true`
+    pub success: bool,
+    /// Always `true`
+    pub linked: bool,
+    pub provider: String,
+    pub email: Option<String>,
+}
+
+/// What a provider's callback answers: a sign-in, unless the flow was started
+/// to link the account
+#[derive(Debug, Clone, Serialize, ToSchema)]
+#[serde(untagged)]
+pub enum OidcSignIn {
+    SignIn(SignIn),
+    Linked(Linked),
+}
+
+/// A new second factor: the secret for the authenticator app, and the recovery
+/// codes, only ever shown here
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct Enrollment {
+    /// Always `true`
+    pub success: bool,
+    /// Base32, for apps that can't scan the QR code
+    pub secret: String,
+    /// `otpauth://` URI the QR code holds
+    pub uri: String,
+    pub qr_svg: String,
+    pub recovery_codes: Vec<String>,
+    pub message: String,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct RecoveryCodes {
+    /// Always `true`
+    pub success: bool,
+    pub recovery_codes: Vec<String>,
+}
+
+/// A provider users of the workspace may sign in with
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct Provider {
+    pub id: String,
+    pub name: String,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct Providers {
+    /// Always `true`
+    pub success: bool,
+    pub providers: Vec<Provider>,
+}
+
+/// Where to send the browser, to sign in with or link an account of a provider
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct Authorization {
+    /// Always `true`
+    pub success: bool,
+    pub url: String,
+    /// When the sign-in must be finished by
+    pub expires_at: String,
+}
+
+/// An account of a provider linked to a user
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct Identity {
+    pub provider: String,
+    /// The account's id at the provider
+    pub subject: String,
+    pub email: Option<String>,
+    pub linked_at: DateTime<Utc>,
+    pub last_used_at: Option<DateTime<Utc>>,
+}
+
+impl From<&oidc::Identity> for Identity {
+    fn from(identity: &oidc::Identity) -> Self {
+        Self {
+            provider: identity.provider.clone(),
+            subject: identity.subject.clone(),
+            email: identity.email.clone(),
+            linked_at: identity.linked_at,
+            last_used_at: identity.last_used_at,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct Identities {
+    /// Always `true`
+    pub success: bool,
+    pub identities: Vec<Identity>,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct Unlinked {
+    /// Always `true`
+    pub success: bool,
+    /// Always `true`
+    pub unlinked: bool,
+}
+
+/// A session of the caller, on one of their devices
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct ActiveSession {
+    pub id: String,
+    /// User agent of the client
+    pub device: Option<String>,
+    /// Address of the last sign-in or refresh
+    pub ip: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub last_used_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+    /// The session of the token listing them
+    pub current: bool,
+}
+
+impl ActiveSession {
+    pub fn new(session: &sessions::Session, current: Option<&str>) -> Self {
+        Self {
+            id: session.id.clone(),
+            device: session.device.clone(),
+            ip: session.ip.clone(),
+            created_at: session.created_at,
+            last_used_at: session.last_used_at,
+            expires_at: session.expires_at,
+            current: current == Some(session.id.as_str()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct ActiveSessions {
+    /// Always `true`
+    pub success: bool,
+    pub sessions: Vec<ActiveSession>,
+}
+
+/// Whether a logout or revocation ended a session
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct Revoked {
+    /// Always `true`
+    pub success: bool,
+    pub revoked: bool,
+}
+
+/// How many sessions logging out everywhere ended
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct RevokedAll {
+    /// Always `true`
+    pub success: bool,
+    pub revoked: u64,
+}
+
+/// An API key, without the key itself
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct ApiKey {
+    pub id: String,
+    /// The service account the key acts as
+    pub account: String,
+    pub roles: Vec<String>,
+    /// Start of the key, to tell keys apart
+    pub prefix: String,
+    pub created_by: String,
+    pub created_at: DateTime<Utc>,
+    /// Never, when not set
+    pub expires_at: Option<DateTime<Utc>>,
+    pub last_used_at: Option<DateTime<Utc>>,
+    /// The key this one was rotated from
+    pub replaces: Option<String>,
+}
+
+impl From<&api_keys::ApiKey> for ApiKey {
+    fn from(key: &api_keys::ApiKey) -> Self {
+        Self {
+            id: key.id.clone(),
+            account: key.account.clone(),
+            roles: key.roles.clone(),
+            prefix: key.prefix(),
+            created_by: key.created_by.clone(),
+            created_at: key.created_at,
+            expires_at: key.expires_at,
+            last_used_at: key.last_used_at,
+            replaces: key.replaces.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct ApiKeys {
+    /// Always `true`
+    pub success: bool,
+    pub api_keys: Vec<ApiKey>,
+}
+
+/// A created or rotated key: the only time the key itself is shown
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct IssuedApiKey {
+    /// Always `true`
+    pub success: bool,
+    /// Sent as `Authorization: ApiKey <key>`
+    pub key: String,
+    pub api_key: ApiKey,
+}
+
+impl From<&api_keys::IssuedKey> for IssuedApiKey {
+    fn from(issued: &api_keys::IssuedKey) -> Self {
+        Self {
+            success: true,
+            key: issued.key.clone(),
+            api_key: ApiKey::from(&issued.api_key),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct AuditEntries {
+    /// Always `true`
+    pub success: bool,
+    /// Newest first; `before` and `after` hold the fields a write changed
+    #[schema(value_type = Vec<Object>)]
+    pub entries: Vec<Value>,
+}
+
+/// `GET /users`
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct Users {
+    /// Always `true`
+    pub success: bool,
+    /// Those of the workspace the caller may read, without the fields they may not see
+    #[schema(value_type = Vec<Object>)]
+    pub users: Vec<Value>,
+}
+
+/// `POST /execute`
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct Executed {
+    /// Always `true`
+    pub success: bool,
+    /// What the script returned
+    #[schema(value_type = Object)]
+    pub result: Value,
+}
+
+/// A document of a collection, without the fields the caller may not see
+#[derive(Debug, Clone, Serialize, ToSchema)]
+#[schema(value_type = Object)]
+pub struct Document(pub Value);
+
+/// `POST /data/{collection}`
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct Stored {
+    /// Always `true`
+    pub success: bool,
+    pub id: String,
+    /// The document as stored, with its `_id`, `createdAt` and `workspace`
+    #[schema(value_type = Object)]
+    pub data: Value,
+}
+
+/// A document, or a user, updated
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct Updated {
+    /// Always `true`
+    pub success: bool,
+    pub id: String,
+}
+
+/// `DELETE /data/{collection}/{id}`
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct Deleted {
+    /// Always `true`
+    pub success: bool,
+    pub id: String,
+    /// Always `true`
+    pub deleted: bool,
+    pub count: u64,
+}
+
+/// What a webhook's script returned, sent with the webhook's status
+#[derive(Debug, Clone, Serialize, ToSchema)]
+#[schema(value_type = Object)]
+pub struct ScriptOutput(pub Value);
+
+/// `GET /.well-known/jwks.json`
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct Jwks {
+    /// Public keys of the RS256 keys, by `kid`
+    #[schema(value_type = Vec<Object>)]
+    pub keys: Vec<Value>,
+}
+
+/// A durable script run
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct Workflow {
+    pub id: String,
+    pub workspace: Option<String>,
+    /// Who started it; the server itself when not set
+    pub started_by: Option<String>,
+    /// `running`, `sleeping`, `waiting`, `completed` or `failed`
+    pub status: String,
+    /// Retries and timeouts of its calls
+    #[schema(value_type = Object)]
+    pub policy: Value,
+    /// When a sleeping workflow resumes
+    pub wake_at: Option<DateTime<Utc>>,
+    /// The event a waiting workflow is blocked on
+    pub event: Option<String>,
+    /// What the script returned, once completed
+    #[schema(value_type = Option<Object>)]
+    pub result: Option<Value>,
+    pub error: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+impl From<&workflow::WorkflowRecord> for Workflow {
+    fn from(record: &workflow::WorkflowRecord) -> Self {
+        Self {
+            id: record.id.clone(),
+            workspace: record.workspace().map(str::to_string),
+            started_by: record.caller.as_ref().map(|caller| caller.sub.clone()),
+            status: record.status.as_str().to_string(),
+            policy: serde_json::to_value(record.policy).unwrap_or(Value::Null),
+            wake_at: record.wake_at,
+            event: record.event.clone(),
+            result: record.result.as_ref().map(|r| r.to_plain_json()),
+            error: record.error.clone(),
+            created_at: record.created_at,
+            updated_at: record.updated_at,
+        }
+    }
+}
+
+/// The workflows an event resumed
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct ResumedWorkflows {
+    /// Always `true`
+    pub success: bool,
+    pub resumed: Vec<Workflow>,
+}
+
+/// A script run on a cron expression
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct Schedule {
+    pub name: String,
+    /// 5 fields, or 6-7 with seconds
+    pub cron: String,
+    /// IANA timezone the expression is evaluated in
+    pub timezone: String,
+    pub script: String,
+    pub workspace: Option<String>,
+    pub enabled: bool,
+    pub next_run: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+impl From<&scheduler::Schedule> for Schedule {
+    fn from(schedule: &scheduler::Schedule) -> Self {
+        Self {
+            name: schedule.name.clone(),
+            cron: schedule.cron.clone(),
+            timezone: schedule.timezone.clone(),
+            script: schedule.script.clone(),
+            workspace: schedule.workspace.clone(),
+            enabled: schedule.enabled,
+            next_run: schedule.next_run,
+            created_at: schedule.created_at,
+            updated_at: schedule.updated_at,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct Schedules {
+    /// Always `true`
+    pub success: bool,
+    pub schedules: Vec<Schedule>,
+}
+
+/// One run of a schedule
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct ScheduleRun {
+    pub id: String,
+    /// Name of the schedule
+    pub schedule: String,
+    pub workspace: Option<String>,
+    /// `succeeded`, `failed`, or `skipped` when the previous run was still going
+    pub status: String,
+    /// What the script returned
+    #[schema(value_type = Option<Object>)]
+    pub result: Option<Value>,
+    pub error: Option<String>,
+    pub started_at: DateTime<Utc>,
+    pub finished_at: DateTime<Utc>,
+}
+
+impl From<&scheduler::ScheduleRun> for ScheduleRun {
+    fn from(run: &scheduler::ScheduleRun) -> Self {
+        Self {
+            id: run.id.clone(),
+            schedule: run.schedule.clone(),
+            workspace: run.workspace.clone(),
+            status: run.status.as_str().to_string(),
+            result: run.result.as_ref().map(|r| r.to_plain_json()),
+            error: run.error.clone(),
+            started_at: run.started_at,
+            finished_at: run.finished_at,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct ScheduleRuns {
+    /// Always `true`
+    pub success: bool,
+    /// Newest first
+    pub runs: Vec<ScheduleRun>,
+}
+
+/// A script run when an event happens in the workspace
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct Trigger {
+    pub workspace: String,
+    pub name: String,
+    /// As `database.inserted`
+    pub event: String,
+    /// Payload fields that must be equal
+    #[schema(value_type = Object)]
+    pub filter: Value,
+    pub script: String,
+    pub enabled: bool,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+impl From<&triggers::Trigger> for Trigger {
+    fn from(trigger: &triggers::Trigger) -> Self {
+        Self {
+            workspace: trigger.workspace.clone(),
+            name: trigger.name.clone(),
+            event: trigger.event.clone(),
+            filter: Value::Object(trigger.filter.iter()
+                .map(|(k, v)| (k.clone(), v.to_plain_json()))
+                .collect()),
+            script: trigger.script.clone(),
+            enabled: trigger.enabled,
+            created_at: trigger.created_at,
+            updated_at: trigger.updated_at,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct Triggers {
+    /// Always `true`
+    pub success: bool,
+    pub triggers: Vec<Trigger>,
+}
+
+/// How a webhook checks its callers, without the secret
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct WebhookAuth {
+    /// `hmac` or `token`
+    #[serde(rename = "type")]
+    pub kind: String,
+    /// Header carrying the signature or the token
+    pub header: String,
+    /// Of the signature, for `hmac`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prefix: Option<String>,
+}
+
+impl From<&webhooks::WebhookAuth> for WebhookAuth {
+    fn from(auth: &webhooks::WebhookAuth) -> Self {
+        match auth {
+            webhooks::WebhookAuth::Hmac { header, prefix, .. } => Self {
+                kind: "hmac".to_string(),
+                header: header.clone(),
+                prefix: Some(prefix.clone()),
+            },
+            webhooks::WebhookAuth::Token { header, .. } => Self {
+                kind: "token".to_string(),
+                header: header.clone(),
+                prefix: None,
+            },
+        }
+    }
+}
+
+/// A webhook route bound to a script
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct Webhook {
+    pub workspace: String,
+    pub name: String,
+    /// Where callers post, as `/hooks/{workspace}/{name}`
+    pub path: String,
+    pub auth: WebhookAuth,
+    pub script: String,
+    /// Answered when the script succeeds
+    pub status: u16,
+    pub enabled: bool,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+impl From<&webhooks::WebhookRoute> for Webhook {
+    fn from(route: &webhooks::WebhookRoute) -> Self {
+        Self {
+            workspace: route.workspace.clone(),
+            name: route.name.clone(),
+            path: format!("/hooks/{}/{}", route.workspace, route.name),
+            auth: WebhookAuth::from(&route.auth),
+            script: route.script.clone(),
+            status: route.status,
+            enabled: route.enabled,
+            created_at: route.created_at,
+            updated_at: route.updated_at,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct Webhooks {
+    /// Always `true`
+    pub success: bool,
+    pub webhooks: Vec<Webhook>,
+}
+
+/// An aggregation pipeline saved under a name
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct Pipeline {
+    pub workspace: String,
+    pub name: String,
+    pub collection: String,
+    /// Stages, with `{"$param": ...}` placeholders
+    #[schema(value_type = Vec<Object>)]
+    pub pipeline: Value,
+    /// Defaults of the parameters; null when required
+    #[schema(value_type = Object)]
+    pub parameters: HashMap<String, Value>,
+    pub description: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+impl From<&pipelines::SavedPipeline> for Pipeline {
+    fn from(saved: &pipelines::SavedPipeline) -> Self {
+        Self {
+            workspace: saved.workspace.clone(),
+            name: saved.name.clone(),
+            collection: saved.collection.clone(),
+            pipeline: saved.pipeline.clone(),
+            parameters: saved.parameters.clone(),
+            description: saved.description.clone(),
+            created_at: saved.created_at,
+            updated_at: saved.updated_at,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct Pipelines {
+    /// Always `true`
+    pub success: bool,
+    pub pipelines: Vec<Pipeline>,
+}
+
+/// `POST /pipelines/{name}/run`
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct PipelineRun {
+    /// Always `true`
+    pub success: bool,
+    /// The documents it gave
+    #[schema(value_type = Vec<Object>)]
+    pub data: Vec<Value>,
+    pub count: u64,
+    /// Whether `limit` cut the documents short
+    pub truncated: bool,
+}
+
+impl PipelineRun {
+    /// From what `database.aggregate` returns
+    pub fn from_result(mut result: Value) -> Self {
+        Self {
+            success: true,
+            data: array(result["data"].take()),
+            count: count(&result["count"]),
+            truncated: result["truncated"] == Value::Bool(true),
+        }
+    }
+}
+
+/// `GET /schemas`
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct Schemas {
+    /// Always `true`
+    pub success: bool,
+    /// Each as its `collection` and its `schema`
+    #[schema(value_type = Vec<Object>)]
+    pub schemas: Vec<Value>,
+}
+
+/// `GET /schemas/{collection}`
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct CollectionSchema {
+    /// Always `true`
+    pub success: bool,
+    pub collection: String,
+    /// JSON Schema of the collection's documents
+    #[schema(value_type = Object)]
+    pub schema: Value,
+}
+
+/// The schema or the search of a collection set
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct Configured {
+    /// Always `true`
+    pub success: bool,
+    pub collection: String,
+}
+
+/// `POST /schemas/{collection}/validate`
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct ValidationReport {
+    /// Always `true`
+    pub success: bool,
+    pub checked: u64,
+    pub valid: u64,
+    pub invalid: u64,
+    /// The invalid ones, each as its `_id` and its `errors`, up to `limit`
+    #[schema(value_type = Vec<Object>)]
+    pub documents: Vec<Value>,
+}
+
+impl ValidationReport {
+    /// From what `database.validate_collection` returns
+    pub fn from_result(mut result: Value) -> Self {
+        Self {
+            success: true,
+            checked: count(&result["checked"]),
+            valid: count(&result["valid"]),
+            invalid: count(&result["invalid"]),
+            documents: array(result["documents"].take()),
+        }
+    }
+}
+
+/// `GET /policy`
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct WorkspacePolicy {
+    /// Always `true`
+    pub success: bool,
+    /// Roles and what they may read and write
+    #[schema(value_type = Object)]
+    pub policy: Value,
+    /// Whether the workspace has none of its own yet
+    pub default: bool,
+}
+
+/// `GET /search/{collection}`
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct SearchResults {
+    /// Always `true`
+    pub success: bool,
+    /// Each as its `id`, `score`, `document` and the `highlights` of its fields
+    #[schema(value_type = Vec<Object>)]
+    pub hits: Vec<Value>,
+    /// Documents matching, over all pages
+    pub total: u64,
+    /// By facet, its most common values as `value` and `count`
+    #[schema(value_type = Object)]
+    pub facets: Value,
+}
+
+impl SearchResults {
+    /// From what `database.search` returns
+    pub fn from_result(mut result: Value) -> Self {
+        Self {
+            success: true,
+            hits: array(result["hits"].take()),
+            total: count(&result["total"]),
+            facets: match result["facets"].take() {
+                facets @ Value::Object(_) => facets,
+                _ => Value::Object(Default::default()),
+            },
+        }
+    }
+}
+
+/// `GET /search/{collection}/config`
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct SearchConfig {
+    /// Always `true`
+    pub success: bool,
+    pub collection: String,
+    /// Fields indexed, and facets
+    #[schema(value_type = Object)]
+    pub config: Value,
+}
+
+/// `POST /search/{collection}/reindex`
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct Reindexed {
+    /// Always `true`
+    pub success: bool,
+    pub indexed: u64,
+}
+
+impl Reindexed {
+    /// From what `database.reindex_search` returns
+    pub fn from_result(result: &Value) -> Self {
+        Self {
+            success: true,
+            indexed: count(&result["indexed"]),
+        }
+    }
+}
+
+/// The elements of an array scripts returned; nothing for anything else
+fn array(value: Value) -> Vec<Value> {
+    match value {
+        Value::Array(values) => values,
+        _ => Vec::new(),
+    }
+}
+
+/// A count scripts returned, which they keep as a float
+fn count(value: &Value) -> u64 {
+    value.as_u64()
+        .or_else(|| value.as_f64().filter(|n| *n >= 0.0).map(|n| n as u64))
+        .unwrap_or(0)
+}
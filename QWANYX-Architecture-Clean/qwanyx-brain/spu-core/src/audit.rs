@@ -0,0 +1,370 @@
+//! Audit Log
+//!
+//! Append-only record of who did what: auth events (registrations, sign-ins,
+//! refreshes, logouts, API keys), every write made through the database
+//! coprocessor, whether a request's script, a trigger's or a migration's, and
+//! changes to a workspace's webhooks, triggers, schedules, pipelines, search
+//! configurations and declarative migration steps. Each
+//! entry names the actor, workspace, action and target, the fields the write
+//! changed as they were before and after, the client's IP and the script
+//! behind it.
+//!
+//! Entries live in each workspace's `spu_audit` collection of the document
+//! store. Writes made in a transaction are logged in it, so their entries are
+//! kept only if it commits. Scripts cannot write to the collection, and entries
+//! are only removed once older than the retention (`AUDIT_RETENTION_DAYS`,
+//! forever when not set).
+//!
+//! Who is acting comes from the task's `Context`: the HTTP server gives every
+//! request one with the client's IP and, once authenticated, the caller;
+//! triggers, webhooks, schedules, workflows and migrations add their script.
+
+use crate::store::{DocumentStore, FindOptions, Namespace};
+use chrono::{DateTime, Duration, Utc};
+use mongodb::bson::{doc, oid::ObjectId, Bson, Document};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::future::Future;
+use std::sync::Arc;
+use tracing::{error, info};
+use utoipa::IntoParams;
+
+pub const AUDIT_COLLECTION: &str = "spu_audit";
+
+/// Actor of the entries of scripts running for no one
+pub const SYSTEM_ACTOR: &str = "system";
+
+/// Entries `query` returns unless told otherwise
+const DEFAULT_LIMIT: i64 = 100;
+
+/// Most entries `query` returns in one call
+const MAX_LIMIT: i64 = 1000;
+
+tokio::task_local! {
+    static CONTEXT: Context;
+}
+
+/// Who is behind what the current task does
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Context {
+    pub actor: Option<String>,
+    pub ip: Option<String>,
+    /// The stored script running, e.g. `trigger:notify-sales` or `migration:3`
+    pub script: Option<String>,
+}
+
+impl Context {
+    /// The context of the current task; empty outside of any
+    pub fn current() -> Self {
+        CONTEXT.try_with(Clone::clone).unwrap_or_default()
+    }
+
+    pub fn with_actor(mut self, actor: impl Into<String>) -> Self {
+        self.actor = Some(actor.into());
+        self
+    }
+
+    pub fn with_ip(mut self, ip: impl Into<String>) -> Self {
+        self.ip = Some(ip.into()).filter(|ip: &String| !ip.is_empty());
+        self
+    }
+
+    pub fn with_script(mut self, script: impl Into<String>) -> Self {
+        self.script = Some(script.into());
+        self
+    }
+}
+
+/// Run `future` in `context`
+pub async fn scope<F: Future>(context: Context, future: F) -> F::Output {
+    CONTEXT.scope(context, future).await
+}
+
+/// Run `future` as the stored script `script`, in the current context otherwise
+pub async fn as_script<F: Future>(script: impl Into<String>, future: F) -> F::Output {
+    scope(Context::current().with_script(script), future).await
+}
+
+/// One thing that happened
+#[derive(Debug, Clone, PartialEq)]
+pub struct AuditEntry {
+    pub id: String,
+    pub workspace: String,
+    pub actor: String,
+    /// Dotted like event names, e.g. `database.updated` or `auth.signed_in`
+    pub action: String,
+    /// What was acted on, e.g. `users/64f1...` for a document
+    pub target: Option<String>,
+    /// The fields a write changed, as they were; the whole document for deletes
+    pub before: Option<Document>,
+    /// The fields a write changed, as they are now; the whole document for inserts
+    pub after: Option<Document>,
+    pub ip: Option<String>,
+    pub script: Option<String>,
+    pub at: DateTime<Utc>,
+}
+
+impl AuditEntry {
+    /// An entry for `action` in the current context, by its actor if it has one
+    pub fn new(workspace: &str, action: &str) -> Self {
+        let context = Context::current();
+        Self {
+            id: ObjectId::new().to_hex(),
+            workspace: workspace.to_string(),
+            actor: context.actor.unwrap_or_else(|| SYSTEM_ACTOR.to_string()),
+            action: action.to_string(),
+            target: None,
+            before: None,
+            after: None,
+            ip: context.ip,
+            script: context.script,
+            // As precise as stored entries
+            at: DateTime::from_timestamp_millis(Utc::now().timestamp_millis()).unwrap_or_default(),
+        }
+    }
+
+    pub fn by(mut self, actor: impl Into<String>) -> Self {
+        self.actor = actor.into();
+        self
+    }
+
+    pub fn on(mut self, target: impl Into<String>) -> Self {
+        self.target = Some(target.into());
+        self
+    }
+
+    /// Keep the fields that differ between `before` and `after`; either may be
+    /// missing, for inserts and deletes
+    pub fn changes(mut self, before: Option<&Document>, after: Option<&Document>) -> Self {
+        match (before, after) {
+            (Some(before), Some(after)) => {
+                let (before, after) = diff(before, after);
+                self.before = Some(before);
+                self.after = Some(after);
+            }
+            (before, after) => {
+                self.before = before.cloned();
+                self.after = after.cloned();
+            }
+        }
+        self
+    }
+
+    /// Whether a write changed nothing
+    pub fn is_empty(&self) -> bool {
+        self.before.as_ref().is_some_and(Document::is_empty) && self.after.as_ref().is_some_and(Document::is_empty)
+    }
+
+    pub fn to_json(&self) -> Value {
+        let document = |value: &Option<Document>| value.clone()
+            .map(|document| Bson::Document(document).into_relaxed_extjson())
+            .unwrap_or(Value::Null);
+        json!({
+            "id": self.id,
+            "workspace": self.workspace,
+            "actor": self.actor,
+            "action": self.action,
+            "target": self.target,
+            "before": document(&self.before),
+            "after": document(&self.after),
+            "ip": self.ip,
+            "script": self.script,
+            "at": self.at.to_rfc3339(),
+        })
+    }
+
+    fn to_document(&self) -> Result<Document, String> {
+        let id = ObjectId::parse_str(&self.id).map_err(|e| format!("Invalid audit entry id: {}", e))?;
+        let optional = |value: &Option<String>| value.clone().map(Bson::String).unwrap_or(Bson::Null);
+        let document = |value: &Option<Document>| value.clone().map(Bson::Document).unwrap_or(Bson::Null);
+        Ok(doc! {
+            "_id": id,
+            "actor": &self.actor,
+            "action": &self.action,
+            "target": optional(&self.target),
+            "before": document(&self.before),
+            "after": document(&self.after),
+            "ip": optional(&self.ip),
+            "script": optional(&self.script),
+            "at": mongodb::bson::DateTime::from_millis(self.at.timestamp_millis()),
+        })
+    }
+
+    fn from_document(workspace: &str, document: &Document) -> Result<Self, String> {
+        let corrupt = |e: mongodb::bson::document::ValueAccessError| format!("Corrupt audit entry: {}", e);
+        let optional = |name: &str| document.get_str(name).ok().map(str::to_string);
+        let changes = |name: &str| document.get_document(name).ok().cloned();
+        Ok(Self {
+            id: document.get_object_id("_id").map_err(corrupt)?.to_hex(),
+            workspace: workspace.to_string(),
+            actor: document.get_str("actor").map_err(corrupt)?.to_string(),
+            action: document.get_str("action").map_err(corrupt)?.to_string(),
+            target: optional("target"),
+            before: changes("before"),
+            after: changes("after"),
+            ip: optional("ip"),
+            script: optional("script"),
+            at: DateTime::from_timestamp_millis(document.get_datetime("at").map_err(corrupt)?.timestamp_millis())
+                .unwrap_or_default(),
+        })
+    }
+}
+
+/// The top-level fields whose values differ, as they are in each document
+fn diff(before: &Document, after: &Document) -> (Document, Document) {
+    let mut old = Document::new();
+    let mut new = Document::new();
+    for (field, value) in before {
+        if after.get(field) != Some(value) {
+            old.insert(field.clone(), value.clone());
+        }
+    }
+    for (field, value) in after {
+        if before.get(field) != Some(value) {
+            new.insert(field.clone(), value.clone());
+        }
+    }
+    (old, new)
+}
+
+/// Which entries `query` returns, newest first
+#[derive(Debug, Clone, Default, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct AuditQuery {
+    pub actor: Option<String>,
+    /// An action, or a prefix of them: `auth` for every `auth.*` action
+    pub action: Option<String>,
+    /// A target, or a collection: `users` for every `users/...` document
+    pub target: Option<String>,
+    pub since: Option<DateTime<Utc>>,
+    pub until: Option<DateTime<Utc>>,
+    pub limit: Option<i64>,
+    pub skip: Option<u64>,
+}
+
+impl AuditQuery {
+    fn filter(&self) -> Document {
+        let mut filter = Document::new();
+        let mut clauses = Vec::new();
+        if let Some(actor) = &self.actor {
+            filter.insert("actor", actor);
+        }
+        if let Some(action) = &self.action {
+            clauses.push(prefixed("action", action, '.'));
+        }
+        if let Some(target) = &self.target {
+            clauses.push(prefixed("target", target, '/'));
+        }
+        if !clauses.is_empty() {
+            filter.insert("$and", clauses);
+        }
+        let mut at = Document::new();
+        if let Some(since) = self.since {
+            at.insert("$gte", mongodb::bson::DateTime::from_millis(since.timestamp_millis()));
+        }
+        if let Some(until) = self.until {
+            at.insert("$lt", mongodb::bson::DateTime::from_millis(until.timestamp_millis()));
+        }
+        if !at.is_empty() {
+            filter.insert("at", at);
+        }
+        filter
+    }
+}
+
+/// `field` is `value`, or starts with it and `separator`
+fn prefixed(field: &str, value: &str, separator: char) -> Document {
+    let pattern = format!("^{}{}", regex::escape(value), regex::escape(&separator.to_string()));
+    doc! { "$or": [{ field: value }, { field: { "$regex": pattern } }] }
+}
+
+/// Records entries and answers queries over them
+pub struct AuditLog {
+    store: Arc<dyn DocumentStore>,
+    retention: Option<Duration>,
+}
+
+impl AuditLog {
+    /// Keeps entries forever
+    pub fn new(store: Arc<dyn DocumentStore>) -> Self {
+        Self { store, retention: None }
+    }
+
+    /// Entries older than `AUDIT_RETENTION_DAYS` are removed; kept forever when not set
+    pub fn from_env(store: Arc<dyn DocumentStore>) -> Self {
+        let days = std::env::var("AUDIT_RETENTION_DAYS").ok().and_then(|v| v.parse::<i64>().ok());
+        Self::new(store).with_retention(days.filter(|days| *days > 0).map(Duration::days))
+    }
+
+    pub fn with_retention(mut self, retention: Option<Duration>) -> Self {
+        self.retention = retention;
+        self
+    }
+
+    pub fn retention(&self) -> Option<Duration> {
+        self.retention
+    }
+
+    /// Append an entry, as part of `transaction` if given
+    pub async fn record(&self, entry: &AuditEntry, transaction: Option<&str>) -> Result<(), String> {
+        let ns = Namespace::new(&entry.workspace, AUDIT_COLLECTION).in_transaction(transaction);
+        self.store.insert(ns, entry.to_document()?).await
+            .map(|_| ())
+            .map_err(|e| format!("Failed to record audit entry: {}", e))
+    }
+
+    /// `record`, logging rather than returning failures: what was audited has
+    /// already happened
+    pub async fn log(&self, entry: &AuditEntry, transaction: Option<&str>) {
+        if let Err(e) = self.record(entry, transaction).await {
+            error!("{} ({} by {} in {})", e, entry.action, entry.actor, entry.workspace);
+        }
+    }
+
+    pub async fn query(&self, workspace: &str, query: &AuditQuery) -> Result<Vec<AuditEntry>, String> {
+        let limit = query.limit.unwrap_or(DEFAULT_LIMIT);
+        if !(1..=MAX_LIMIT).contains(&limit) {
+            return Err(format!("'limit' must be between 1 and {}", MAX_LIMIT));
+        }
+        let options = FindOptions {
+            sort: Some(doc! { "at": -1, "_id": -1 }),
+            limit: Some(limit),
+            skip: query.skip,
+            ..FindOptions::default()
+        };
+        let documents = self.store.find(Namespace::new(workspace, AUDIT_COLLECTION), query.filter(), options).await
+            .map_err(|e| format!("Failed to read the audit log: {}", e))?;
+        documents.iter().map(|document| AuditEntry::from_document(workspace, document)).collect()
+    }
+
+    /// Remove the entries of a workspace older than the retention; none when
+    /// entries are kept forever
+    pub async fn prune(&self, workspace: &str, now: DateTime<Utc>) -> Result<u64, String> {
+        let Some(retention) = self.retention else {
+            return Ok(0);
+        };
+        let cutoff = mongodb::bson::DateTime::from_millis((now - retention).timestamp_millis());
+        let removed = self.store.delete(Namespace::new(workspace, AUDIT_COLLECTION), doc! { "at": { "$lt": cutoff } }).await
+            .map_err(|e| format!("Failed to prune the audit log: {}", e))?;
+        if removed > 0 {
+            info!("Pruned {} audit entries of {}", removed, workspace);
+        }
+        Ok(removed)
+    }
+
+    /// Prune the workspaces' entries every `interval`, if they are not kept forever
+    pub fn spawn_pruner(self: Arc<Self>, workspaces: Vec<String>, interval: std::time::Duration) -> Option<tokio::task::JoinHandle<()>> {
+        self.retention?;
+        Some(tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            loop {
+                ticker.tick().await;
+                for workspace in &workspaces {
+                    if let Err(e) = self.prune(workspace, Utc::now()).await {
+                        error!("{}", e);
+                    }
+                }
+            }
+        }))
+    }
+}
@@ -0,0 +1,487 @@
+//! API keys
+//!
+//! Machine clients - deploy scripts, other services - authenticate with an API
+//! key instead of signing in: `Authorization: ApiKey spk_autodin.<id>.<secret>`.
+//! A key acts as a service account of one workspace, with the roles it was
+//! given, which the workspace's policy then applies like any user's.
+//!
+//! Only a hash of each key is kept; the key itself is shown once, when it is
+//! created or rotated. Rotating gives the service account a new key and lets
+//! the old one work for a grace period, so clients can be moved over. Keys may
+//! expire, and record when they were last used. Creating, rotating and
+//! revoking keys goes in the audit log, if given one (`with_audit`).
+
+use super::{bson_date, chrono_date};
+use super::tokens::Claims;
+use crate::audit::{AuditEntry, AuditLog};
+use crate::workspaces::WorkspaceDatabases;
+use async_trait::async_trait;
+use chrono::{DateTime, Duration, Utc};
+use mongodb::{Collection, bson::{doc, Bson, Document}};
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::Arc;
+use thiserror::Error;
+use tokio::sync::RwLock;
+use tracing::info;
+
+/// Start of every key, telling it apart from tokens in logs and secret scanners
+pub const KEY_PREFIX: &str = "spk_";
+
+/// How stale `last_used_at` may get before a use is recorded again, sparing a
+/// write on every request
+const LAST_USED_RESOLUTION: Duration = Duration::minutes(1);
+
+/// How long the claims of a key that never expires are good for
+const CLAIMS_TTL: Duration = Duration::hours(1);
+
+/// A key, without the key itself
+#[derive(Debug, Clone, PartialEq)]
+pub struct ApiKey {
+    pub id: String,
+    pub workspace: String,
+    /// The service account the key acts as, kept across rotations
+    pub account: String,
+    pub roles: Vec<String>,
+    /// Hex SHA-256 of the key
+    pub key_hash: String,
+    /// Who created or rotated the key
+    pub created_by: String,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub last_used_at: Option<DateTime<Utc>>,
+    pub revoked_at: Option<DateTime<Utc>>,
+    /// The key this one was rotated from
+    pub replaces: Option<String>,
+}
+
+impl ApiKey {
+    pub fn is_active(&self, now: DateTime<Utc>) -> bool {
+        self.revoked_at.is_none() && self.expires_at.is_none_or(|at| at > now)
+    }
+
+    /// Whom requests made with the key are for
+    pub fn sub(&self) -> String {
+        format!("service:{}", self.account)
+    }
+
+    /// What a request made with the key may do, as if it carried a token
+    pub fn claims(&self, now: DateTime<Utc>) -> Claims {
+        let exp = self.expires_at.unwrap_or(now + CLAIMS_TTL);
+        Claims {
+            sub: self.sub(),
+            workspace: self.workspace.clone(),
+            email: None,
+            roles: self.roles.clone(),
+            exp: exp.timestamp(),
+            iat: now.timestamp(),
+            jti: self.id.clone(),
+            sid: None,
+        }
+    }
+
+    /// Start of the key, telling keys apart without showing them
+    pub fn prefix(&self) -> String {
+        format!("{}{}.{}", KEY_PREFIX, self.workspace, self.id)
+    }
+}
+
+/// A key as created or rotated: the only time the key itself is known
+#[derive(Debug, Clone, PartialEq)]
+pub struct IssuedKey {
+    pub key: String,
+    pub api_key: ApiKey,
+}
+
+/// Why a key was not created, rotated or accepted
+#[derive(Debug, Error, PartialEq)]
+pub enum ApiKeyError {
+    /// Also for keys that don't exist, so as not to tell which do
+    #[error("Invalid API key")]
+    Invalid,
+
+    #[error("API key expired")]
+    Expired,
+
+    #[error("API key revoked")]
+    Revoked,
+
+    #[error("API key not found")]
+    NotFound,
+
+    #[error("Invalid arguments: {0}")]
+    InvalidArguments(String),
+
+    #[error("Storage error: {0}")]
+    Storage(String),
+}
+
+impl From<String> for ApiKeyError {
+    fn from(e: String) -> Self {
+        ApiKeyError::Storage(e)
+    }
+}
+
+/// The key of an `Authorization: ApiKey <key>` header, if it is one
+pub fn api_key(authorization: Option<&str>) -> Option<&str> {
+    let (scheme, key) = authorization?.trim().split_once(' ')?;
+    (scheme.eq_ignore_ascii_case("apikey") && !key.trim().is_empty()).then(|| key.trim())
+}
+
+/// The workspace and id a key names, whether or not it exists
+fn parse(key: &str) -> Option<(&str, &str)> {
+    let rest = key.strip_prefix(KEY_PREFIX)?;
+    let mut parts = rest.rsplitn(3, '.');
+    let (_secret, id, workspace) = (parts.next()?, parts.next()?, parts.next()?);
+    (!workspace.is_empty() && !id.is_empty()).then_some((workspace, id))
+}
+
+// ================================================================================
+// STORAGE
+// ================================================================================
+
+/// Persistence for API keys
+#[async_trait]
+pub trait ApiKeyStore: Send + Sync {
+    async fn insert(&self, key: &ApiKey) -> Result<(), String>;
+
+    async fn load(&self, workspace: &str, id: &str) -> Result<Option<ApiKey>, String>;
+
+    /// Keys of a workspace not revoked, expired ones included
+    async fn list(&self, workspace: &str) -> Result<Vec<ApiKey>, String>;
+
+    /// Mark a key revoked; false if it is missing or already revoked
+    async fn revoke(&self, workspace: &str, id: &str, at: DateTime<Utc>) -> Result<bool, String>;
+
+    /// Make a key expire by `at` at the latest
+    async fn expire(&self, workspace: &str, id: &str, at: DateTime<Utc>) -> Result<(), String>;
+
+    /// Record a use of the key
+    async fn touch(&self, workspace: &str, id: &str, at: DateTime<Utc>) -> Result<(), String>;
+}
+
+/// In-memory store, for tests and local development
+#[derive(Default)]
+pub struct MemoryApiKeyStore {
+    keys: RwLock<HashMap<(String, String), ApiKey>>,
+}
+
+impl MemoryApiKeyStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[async_trait]
+impl ApiKeyStore for MemoryApiKeyStore {
+    async fn insert(&self, key: &ApiKey) -> Result<(), String> {
+        let mut keys = self.keys.write().await;
+        keys.insert((key.workspace.clone(), key.id.clone()), key.clone());
+        Ok(())
+    }
+
+    async fn load(&self, workspace: &str, id: &str) -> Result<Option<ApiKey>, String> {
+        let keys = self.keys.read().await;
+        Ok(keys.get(&(workspace.to_string(), id.to_string())).cloned())
+    }
+
+    async fn list(&self, workspace: &str) -> Result<Vec<ApiKey>, String> {
+        let keys = self.keys.read().await;
+        let mut listed: Vec<ApiKey> = keys.values()
+            .filter(|k| k.workspace == workspace && k.revoked_at.is_none())
+            .cloned()
+            .collect();
+        listed.sort_by(|a, b| (&a.account, a.created_at).cmp(&(&b.account, b.created_at)));
+        Ok(listed)
+    }
+
+    async fn revoke(&self, workspace: &str, id: &str, at: DateTime<Utc>) -> Result<bool, String> {
+        let mut keys = self.keys.write().await;
+        match keys.get_mut(&(workspace.to_string(), id.to_string())) {
+            Some(key) if key.revoked_at.is_none() => {
+                key.revoked_at = Some(at);
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    async fn expire(&self, workspace: &str, id: &str, at: DateTime<Utc>) -> Result<(), String> {
+        let mut keys = self.keys.write().await;
+        if let Some(key) = keys.get_mut(&(workspace.to_string(), id.to_string())) {
+            key.expires_at = Some(key.expires_at.map_or(at, |expires_at| expires_at.min(at)));
+        }
+        Ok(())
+    }
+
+    async fn touch(&self, workspace: &str, id: &str, at: DateTime<Utc>) -> Result<(), String> {
+        let mut keys = self.keys.write().await;
+        if let Some(key) = keys.get_mut(&(workspace.to_string(), id.to_string())) {
+            key.last_used_at = Some(at);
+        }
+        Ok(())
+    }
+}
+
+/// MongoDB store
+///
+/// Keys live in the workspace's own database, in `spu_api_keys` keyed by id.
+pub struct MongoApiKeyStore {
+    databases: WorkspaceDatabases,
+}
+
+impl MongoApiKeyStore {
+    pub fn new(databases: WorkspaceDatabases) -> Self {
+        Self { databases }
+    }
+
+    fn keys(&self, workspace: &str) -> Result<Collection<Document>, String> {
+        Ok(self.databases.database(workspace)?.collection("spu_api_keys"))
+    }
+
+    fn to_document(key: &ApiKey) -> Document {
+        let date = |value: Option<DateTime<Utc>>| value.map(|at| Bson::DateTime(bson_date(at))).unwrap_or(Bson::Null);
+        doc! {
+            "_id": &key.id,
+            "account": &key.account,
+            "roles": &key.roles,
+            "key_hash": &key.key_hash,
+            "created_by": &key.created_by,
+            "created_at": bson_date(key.created_at),
+            "expires_at": date(key.expires_at),
+            "last_used_at": date(key.last_used_at),
+            "revoked_at": date(key.revoked_at),
+            "replaces": key.replaces.clone().map(Bson::String).unwrap_or(Bson::Null),
+        }
+    }
+
+    fn from_document(workspace: &str, document: &Document) -> Result<ApiKey, String> {
+        let corrupt = |e: mongodb::bson::document::ValueAccessError| format!("Corrupt API key document: {}", e);
+        let date = |name: &str| document.get_datetime(name).ok().map(chrono_date);
+        Ok(ApiKey {
+            id: document.get_str("_id").map_err(corrupt)?.to_string(),
+            workspace: workspace.to_string(),
+            account: document.get_str("account").map_err(corrupt)?.to_string(),
+            roles: document.get_array("roles").map_err(corrupt)?
+                .iter()
+                .filter_map(|role| role.as_str().map(str::to_string))
+                .collect(),
+            key_hash: document.get_str("key_hash").map_err(corrupt)?.to_string(),
+            created_by: document.get_str("created_by").map_err(corrupt)?.to_string(),
+            created_at: chrono_date(document.get_datetime("created_at").map_err(corrupt)?),
+            expires_at: date("expires_at"),
+            last_used_at: date("last_used_at"),
+            revoked_at: date("revoked_at"),
+            replaces: document.get_str("replaces").ok().map(str::to_string),
+        })
+    }
+}
+
+#[async_trait]
+impl ApiKeyStore for MongoApiKeyStore {
+    async fn insert(&self, key: &ApiKey) -> Result<(), String> {
+        self.keys(&key.workspace)?.insert_one(Self::to_document(key), None).await
+            .map_err(|e| format!("Failed to save API key: {}", e))?;
+        Ok(())
+    }
+
+    async fn load(&self, workspace: &str, id: &str) -> Result<Option<ApiKey>, String> {
+        // Keys name their workspace before they are checked: one that isn't
+        // served has no keys, and no database is looked into for it
+        let Ok(keys) = self.keys(workspace) else { return Ok(None) };
+        let document = keys.find_one(doc! { "_id": id }, None).await
+            .map_err(|e| format!("Failed to load API key: {}", e))?;
+        document.map(|document| Self::from_document(workspace, &document)).transpose()
+    }
+
+    async fn list(&self, workspace: &str) -> Result<Vec<ApiKey>, String> {
+        use futures::TryStreamExt;
+        let options = mongodb::options::FindOptions::builder().sort(doc! { "account": 1, "created_at": 1 }).build();
+        let documents: Vec<Document> = self.keys(workspace)?
+            .find(doc! { "revoked_at": Bson::Null }, options).await
+            .map_err(|e| format!("Failed to list API keys: {}", e))?
+            .try_collect().await
+            .map_err(|e| format!("Failed to list API keys: {}", e))?;
+        documents.iter().map(|document| Self::from_document(workspace, document)).collect()
+    }
+
+    async fn revoke(&self, workspace: &str, id: &str, at: DateTime<Utc>) -> Result<bool, String> {
+        let result = self.keys(workspace)?
+            .update_one(doc! { "_id": id, "revoked_at": Bson::Null }, doc! { "$set": { "revoked_at": bson_date(at) } }, None).await
+            .map_err(|e| format!("Failed to revoke API key: {}", e))?;
+        Ok(result.modified_count == 1)
+    }
+
+    async fn expire(&self, workspace: &str, id: &str, at: DateTime<Utc>) -> Result<(), String> {
+        // Keys that never expired get `at`, the others the earlier of the two
+        let filter = doc! { "_id": id, "expires_at": Bson::Null };
+        self.keys(workspace)?
+            .update_one(filter, doc! { "$set": { "expires_at": bson_date(at) } }, None).await
+            .map_err(|e| format!("Failed to expire API key: {}", e))?;
+        self.keys(workspace)?
+            .update_one(doc! { "_id": id }, doc! { "$min": { "expires_at": bson_date(at) } }, None).await
+            .map_err(|e| format!("Failed to expire API key: {}", e))?;
+        Ok(())
+    }
+
+    async fn touch(&self, workspace: &str, id: &str, at: DateTime<Utc>) -> Result<(), String> {
+        self.keys(workspace)?
+            .update_one(doc! { "_id": id }, doc! { "$set": { "last_used_at": bson_date(at) } }, None).await
+            .map_err(|e| format!("Failed to record API key use: {}", e))?;
+        Ok(())
+    }
+}
+
+// ================================================================================
+// API KEYS
+// ================================================================================
+
+/// Creates, rotates and revokes API keys, and checks the keys of requests
+pub struct ApiKeys {
+    store: Arc<dyn ApiKeyStore>,
+    audit: Option<Arc<AuditLog>>,
+}
+
+impl ApiKeys {
+    pub fn new(store: Arc<dyn ApiKeyStore>) -> Self {
+        Self { store, audit: None }
+    }
+
+    /// Log the keys created, rotated and revoked in this audit log
+    pub fn with_audit(mut self, audit: Arc<AuditLog>) -> Self {
+        self.audit = Some(audit);
+        self
+    }
+
+    /// A new key for the service account `account` of a workspace, with
+    /// `roles`, expiring after `ttl` if given
+    pub async fn create(&self, workspace: &str, account: &str, roles: Vec<String>, ttl: Option<Duration>, created_by: &str) -> Result<IssuedKey, ApiKeyError> {
+        if account.trim().is_empty() {
+            return Err(ApiKeyError::InvalidArguments("'account' cannot be empty".to_string()));
+        }
+        if ttl.is_some_and(|ttl| ttl <= Duration::zero()) {
+            return Err(ApiKeyError::InvalidArguments("keys must expire in the future".to_string()));
+        }
+        let now = Utc::now();
+        let issued = self.issue(workspace, account.trim(), roles, now, ttl.map(|ttl| now + ttl), created_by, None).await?;
+        info!("Created API key {} for service account {} in {}", issued.api_key.id, account, workspace);
+        self.audit(AuditEntry::new(workspace, "auth.api_key_created").by(created_by), &issued.api_key, None).await;
+        Ok(issued)
+    }
+
+    /// A new key for the account of key `id`, with its roles and lifetime; the
+    /// old key keeps working for `grace`, if that is longer than zero
+    pub async fn rotate(&self, workspace: &str, id: &str, grace: Duration, rotated_by: &str) -> Result<IssuedKey, ApiKeyError> {
+        let now = Utc::now();
+        let old = match self.store.load(workspace, id).await? {
+            Some(key) if key.is_active(now) => key,
+            _ => return Err(ApiKeyError::NotFound),
+        };
+        let grace_ends = now.checked_add_signed(grace)
+            .ok_or_else(|| ApiKeyError::InvalidArguments("grace period out of range".to_string()))?;
+        let expires_at = old.expires_at.map(|at| now + (at - old.created_at));
+        let issued = self.issue(workspace, &old.account, old.roles.clone(), now, expires_at, rotated_by, Some(old.id.clone())).await?;
+
+        if grace > Duration::zero() {
+            self.store.expire(workspace, id, grace_ends).await?;
+        } else {
+            self.store.revoke(workspace, id, now).await?;
+        }
+        info!("Rotated API key {} of {} in {} to {}", id, old.account, workspace, issued.api_key.id);
+        self.audit(AuditEntry::new(workspace, "auth.api_key_rotated").by(rotated_by), &issued.api_key, Some(&old)).await;
+        Ok(issued)
+    }
+
+    /// Revoke a key; false if it is missing or already revoked
+    pub async fn revoke(&self, workspace: &str, id: &str) -> Result<bool, ApiKeyError> {
+        let revoked = self.store.revoke(workspace, id, Utc::now()).await?;
+        if revoked {
+            info!("Revoked API key {} in {}", id, workspace);
+            if let (Some(audit), Some(key)) = (&self.audit, self.store.load(workspace, id).await?) {
+                let entry = AuditEntry::new(workspace, "auth.api_key_revoked").on(format!("api_keys/{}", id));
+                audit.log(&entry.changes(Some(&audited(&key)), None), None).await;
+            }
+        }
+        Ok(revoked)
+    }
+
+    /// Keys of a workspace that still work
+    pub async fn list(&self, workspace: &str) -> Result<Vec<ApiKey>, ApiKeyError> {
+        let now = Utc::now();
+        let keys = self.store.list(workspace).await?;
+        Ok(keys.into_iter().filter(|k| k.is_active(now)).collect())
+    }
+
+    /// Log a key given out, as the one `replaced` if it was rotated
+    async fn audit(&self, entry: AuditEntry, key: &ApiKey, replaced: Option<&ApiKey>) {
+        if let Some(audit) = &self.audit {
+            let entry = entry
+                .on(format!("api_keys/{}", key.id))
+                .changes(replaced.map(audited).as_ref(), Some(&audited(key)));
+            audit.log(&entry, None).await;
+        }
+    }
+
+    /// The claims of a request made with `key`, recording its use
+    pub async fn authenticate(&self, key: &str) -> Result<Claims, ApiKeyError> {
+        let (workspace, id) = parse(key).ok_or(ApiKeyError::Invalid)?;
+        let api_key = self.store.load(workspace, id).await?.ok_or(ApiKeyError::Invalid)?;
+        if api_key.key_hash != hash(key) {
+            return Err(ApiKeyError::Invalid);
+        }
+        let now = Utc::now();
+        if api_key.revoked_at.is_some() {
+            return Err(ApiKeyError::Revoked);
+        }
+        if api_key.expires_at.is_some_and(|at| at <= now) {
+            return Err(ApiKeyError::Expired);
+        }
+        if api_key.last_used_at.is_none_or(|at| now - at >= LAST_USED_RESOLUTION) {
+            self.store.touch(workspace, id, now).await?;
+        }
+        Ok(api_key.claims(now))
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    async fn issue(&self, workspace: &str, account: &str, roles: Vec<String>, now: DateTime<Utc>, expires_at: Option<DateTime<Utc>>, created_by: &str, replaces: Option<String>) -> Result<IssuedKey, ApiKeyError> {
+        let id = uuid::Uuid::new_v4().simple().to_string();
+        let key = format!("{}{}.{}.{}", KEY_PREFIX, workspace, id, random_secret());
+        let api_key = ApiKey {
+            id,
+            workspace: workspace.to_string(),
+            account: account.to_string(),
+            roles,
+            key_hash: hash(&key),
+            created_by: created_by.to_string(),
+            created_at: now,
+            expires_at,
+            last_used_at: None,
+            revoked_at: None,
+            replaces,
+        };
+        self.store.insert(&api_key).await?;
+        Ok(IssuedKey { key, api_key })
+    }
+}
+
+fn random_secret() -> String {
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    hex::encode(bytes)
+}
+
+fn hash(key: &str) -> String {
+    hex::encode(Sha256::digest(key.as_bytes()))
+}
+
+/// What the audit log keeps of a key: never its hash
+fn audited(key: &ApiKey) -> Document {
+    let date = |value: Option<DateTime<Utc>>| value.map(|at| Bson::DateTime(bson_date(at))).unwrap_or(Bson::Null);
+    doc! {
+        "id": &key.id,
+        "account": &key.account,
+        "roles": &key.roles,
+        "expires_at": date(key.expires_at),
+    }
+}
@@ -0,0 +1,417 @@
+//! One-time login codes
+//!
+//! A code is six digits sent by email. Only an HMAC of it is kept, with its
+//! expiry and the failed attempts made against it, one code per email and
+//! workspace: issuing a new code replaces the previous one, using a code
+//! consumes it, and too many wrong guesses burn it.
+//!
+//! Issuing is rate-limited per email and per client IP, in fixed windows, so
+//! codes cannot be guessed by asking for new ones either.
+
+use super::{bson_date, chrono_date};
+use crate::workspaces::WorkspaceDatabases;
+use async_trait::async_trait;
+use chrono::{DateTime, Duration, Utc};
+use hmac::{Hmac, Mac};
+use mongodb::{Collection, bson::{doc, Document}};
+use rand::Rng;
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::sync::Arc;
+use thiserror::Error;
+use tokio::sync::RwLock;
+use tracing::{info, warn};
+
+/// A code as stored: never the code itself
+#[derive(Debug, Clone, PartialEq)]
+pub struct CodeRecord {
+    pub workspace: String,
+    /// Trimmed and lowercased
+    pub email: String,
+    /// Hex HMAC-SHA256 of the code
+    pub hash: String,
+    pub expires_at: DateTime<Utc>,
+    /// Wrong codes tried so far
+    pub attempts: u32,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Limits on codes
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CodePolicy {
+    /// How long a code stays valid
+    pub ttl: Duration,
+    /// Wrong codes after which a code is burnt
+    pub max_attempts: u32,
+    /// Codes one email may be sent per window
+    pub per_email: u32,
+    /// Codes one IP may ask for per window, whatever the email
+    pub per_ip: u32,
+    pub window: Duration,
+}
+
+impl Default for CodePolicy {
+    fn default() -> Self {
+        Self {
+            ttl: Duration::minutes(10),
+            max_attempts: 5,
+            per_email: 5,
+            per_ip: 20,
+            window: Duration::minutes(15),
+        }
+    }
+}
+
+impl CodePolicy {
+    /// The defaults, overridden by `AUTH_CODE_TTL_MINUTES`, `AUTH_CODE_MAX_ATTEMPTS`,
+    /// `AUTH_CODE_PER_EMAIL`, `AUTH_CODE_PER_IP` and `AUTH_CODE_WINDOW_MINUTES`
+    pub fn from_env() -> Self {
+        let number = |name: &str| std::env::var(name).ok().and_then(|v| v.parse::<u32>().ok());
+        let defaults = Self::default();
+        Self {
+            ttl: number("AUTH_CODE_TTL_MINUTES").map(|m| Duration::minutes(m as i64)).unwrap_or(defaults.ttl),
+            max_attempts: number("AUTH_CODE_MAX_ATTEMPTS").unwrap_or(defaults.max_attempts),
+            per_email: number("AUTH_CODE_PER_EMAIL").unwrap_or(defaults.per_email),
+            per_ip: number("AUTH_CODE_PER_IP").unwrap_or(defaults.per_ip),
+            window: number("AUTH_CODE_WINDOW_MINUTES").map(|m| Duration::minutes(m as i64)).unwrap_or(defaults.window),
+        }
+    }
+}
+
+/// Why a code was not issued or not accepted
+#[derive(Debug, Error, PartialEq)]
+pub enum CodeError {
+    #[error("Too many code requests, try again later")]
+    RateLimited,
+
+    /// Also when no code was issued, so as not to tell which emails have one
+    #[error("Invalid code")]
+    Invalid,
+
+    #[error("Code expired, request a new one")]
+    Expired,
+
+    #[error("Too many attempts, request a new code")]
+    TooManyAttempts,
+
+    #[error("Storage error: {0}")]
+    Storage(String),
+}
+
+impl From<String> for CodeError {
+    fn from(e: String) -> Self {
+        CodeError::Storage(e)
+    }
+}
+
+// ================================================================================
+// STORAGE
+// ================================================================================
+
+/// Persistence for codes and request counts
+#[async_trait]
+pub trait CodeStore: Send + Sync {
+    /// Save a code, replacing any other code of the same email and workspace
+    async fn replace(&self, record: &CodeRecord) -> Result<(), String>;
+
+    async fn load(&self, workspace: &str, email: &str) -> Result<Option<CodeRecord>, String>;
+
+    /// Count a wrong attempt; returns the attempts so far, or None if there is no code
+    async fn fail(&self, workspace: &str, email: &str) -> Result<Option<u32>, String>;
+
+    /// Delete the code if its hash is `hash`; false if it was already used or replaced
+    async fn consume(&self, workspace: &str, email: &str, hash: &str) -> Result<bool, String>;
+
+    /// Delete the code whatever it is
+    async fn remove(&self, workspace: &str, email: &str) -> Result<(), String>;
+
+    /// Count a request under `key` in the window starting at `window_start`;
+    /// returns the requests of that window so far, this one included
+    async fn hit(&self, workspace: &str, key: &str, window_start: DateTime<Utc>, expires_at: DateTime<Utc>) -> Result<u32, String>;
+}
+
+type Window = (DateTime<Utc>, u32);
+
+/// In-memory store, for tests and local development
+#[derive(Default)]
+pub struct MemoryCodeStore {
+    codes: RwLock<HashMap<(String, String), CodeRecord>>,
+    /// Start of the current window and requests in it, per workspace and key
+    hits: RwLock<HashMap<(String, String), Window>>,
+}
+
+impl MemoryCodeStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[async_trait]
+impl CodeStore for MemoryCodeStore {
+    async fn replace(&self, record: &CodeRecord) -> Result<(), String> {
+        let mut codes = self.codes.write().await;
+        codes.insert((record.workspace.clone(), record.email.clone()), record.clone());
+        Ok(())
+    }
+
+    async fn load(&self, workspace: &str, email: &str) -> Result<Option<CodeRecord>, String> {
+        let codes = self.codes.read().await;
+        Ok(codes.get(&(workspace.to_string(), email.to_string())).cloned())
+    }
+
+    async fn fail(&self, workspace: &str, email: &str) -> Result<Option<u32>, String> {
+        let mut codes = self.codes.write().await;
+        Ok(codes.get_mut(&(workspace.to_string(), email.to_string())).map(|record| {
+            record.attempts += 1;
+            record.attempts
+        }))
+    }
+
+    async fn consume(&self, workspace: &str, email: &str, hash: &str) -> Result<bool, String> {
+        let mut codes = self.codes.write().await;
+        let key = (workspace.to_string(), email.to_string());
+        if codes.get(&key).is_some_and(|record| record.hash == hash) {
+            codes.remove(&key);
+            return Ok(true);
+        }
+        Ok(false)
+    }
+
+    async fn remove(&self, workspace: &str, email: &str) -> Result<(), String> {
+        let mut codes = self.codes.write().await;
+        codes.remove(&(workspace.to_string(), email.to_string()));
+        Ok(())
+    }
+
+    async fn hit(&self, workspace: &str, key: &str, window_start: DateTime<Utc>, _expires_at: DateTime<Utc>) -> Result<u32, String> {
+        let mut hits = self.hits.write().await;
+        let entry = hits.entry((workspace.to_string(), key.to_string())).or_insert((window_start, 0));
+        if entry.0 != window_start {
+            *entry = (window_start, 0);
+        }
+        entry.1 += 1;
+        Ok(entry.1)
+    }
+}
+
+/// MongoDB store
+///
+/// Codes live in the workspace's own database, in `spu_auth_codes` keyed by
+/// email, and request counts in `spu_rate_limits` keyed by key and window.
+/// Both carry an `expires_at` date, for the TTL indexes of `create_indexes`
+/// to clean up.
+pub struct MongoCodeStore {
+    databases: WorkspaceDatabases,
+}
+
+impl MongoCodeStore {
+    pub fn new(databases: WorkspaceDatabases) -> Self {
+        Self { databases }
+    }
+
+    /// Have expired codes and request counts deleted, in every workspace
+    pub async fn create_indexes(&self) -> Result<(), String> {
+        self.databases.expire_at("spu_auth_codes", "expires_at").await?;
+        self.databases.expire_at("spu_rate_limits", "expires_at").await
+    }
+
+    fn codes(&self, workspace: &str) -> Result<Collection<Document>, String> {
+        Ok(self.databases.database(workspace)?.collection("spu_auth_codes"))
+    }
+
+    fn limits(&self, workspace: &str) -> Result<Collection<Document>, String> {
+        Ok(self.databases.database(workspace)?.collection("spu_rate_limits"))
+    }
+
+    fn from_document(workspace: &str, document: &Document) -> Result<CodeRecord, String> {
+        let corrupt = |e: mongodb::bson::document::ValueAccessError| format!("Corrupt code document: {}", e);
+        Ok(CodeRecord {
+            workspace: workspace.to_string(),
+            email: document.get_str("_id").map_err(corrupt)?.to_string(),
+            hash: document.get_str("hash").map_err(corrupt)?.to_string(),
+            expires_at: chrono_date(document.get_datetime("expires_at").map_err(corrupt)?),
+            attempts: document.get_i32("attempts").map_err(corrupt)? as u32,
+            created_at: chrono_date(document.get_datetime("created_at").map_err(corrupt)?),
+        })
+    }
+}
+
+#[async_trait]
+impl CodeStore for MongoCodeStore {
+    async fn replace(&self, record: &CodeRecord) -> Result<(), String> {
+        let document = doc! {
+            "_id": &record.email,
+            "hash": &record.hash,
+            "expires_at": bson_date(record.expires_at),
+            "attempts": record.attempts as i32,
+            "created_at": bson_date(record.created_at),
+        };
+        let options = mongodb::options::ReplaceOptions::builder().upsert(true).build();
+        self.codes(&record.workspace)?
+            .replace_one(doc! { "_id": &record.email }, document, options).await
+            .map_err(|e| format!("Failed to save code: {}", e))?;
+        Ok(())
+    }
+
+    async fn load(&self, workspace: &str, email: &str) -> Result<Option<CodeRecord>, String> {
+        let document = self.codes(workspace)?.find_one(doc! { "_id": email }, None).await
+            .map_err(|e| format!("Failed to load code: {}", e))?;
+        document.map(|document| Self::from_document(workspace, &document)).transpose()
+    }
+
+    async fn fail(&self, workspace: &str, email: &str) -> Result<Option<u32>, String> {
+        let options = mongodb::options::FindOneAndUpdateOptions::builder()
+            .return_document(mongodb::options::ReturnDocument::After)
+            .build();
+        let document = self.codes(workspace)?
+            .find_one_and_update(doc! { "_id": email }, doc! { "$inc": { "attempts": 1 } }, options).await
+            .map_err(|e| format!("Failed to count attempt: {}", e))?;
+        Ok(document.and_then(|document| document.get_i32("attempts").ok()).map(|attempts| attempts as u32))
+    }
+
+    async fn consume(&self, workspace: &str, email: &str, hash: &str) -> Result<bool, String> {
+        let result = self.codes(workspace)?.delete_one(doc! { "_id": email, "hash": hash }, None).await
+            .map_err(|e| format!("Failed to consume code: {}", e))?;
+        Ok(result.deleted_count == 1)
+    }
+
+    async fn remove(&self, workspace: &str, email: &str) -> Result<(), String> {
+        self.codes(workspace)?.delete_one(doc! { "_id": email }, None).await
+            .map_err(|e| format!("Failed to delete code: {}", e))?;
+        Ok(())
+    }
+
+    async fn hit(&self, workspace: &str, key: &str, window_start: DateTime<Utc>, expires_at: DateTime<Utc>) -> Result<u32, String> {
+        let options = mongodb::options::FindOneAndUpdateOptions::builder()
+            .upsert(true)
+            .return_document(mongodb::options::ReturnDocument::After)
+            .build();
+        let update = doc! {
+            "$inc": { "count": 1 },
+            "$setOnInsert": { "expires_at": bson_date(expires_at) },
+        };
+        let id = format!("{}@{}", key, window_start.timestamp());
+        let document = self.limits(workspace)?.find_one_and_update(doc! { "_id": id }, update, options).await
+            .map_err(|e| format!("Failed to count request: {}", e))?;
+        Ok(document.and_then(|document| document.get_i32("count").ok()).unwrap_or(1) as u32)
+    }
+}
+
+// ================================================================================
+// CODES
+// ================================================================================
+
+/// Issues and checks login codes
+pub struct LoginCodes {
+    store: Arc<dyn CodeStore>,
+    secret: Vec<u8>,
+    policy: CodePolicy,
+}
+
+impl LoginCodes {
+    /// Codes hashed with `secret`, which must stay the same for codes to verify
+    pub fn new(store: Arc<dyn CodeStore>, secret: &str) -> Self {
+        Self { store, secret: secret.as_bytes().to_vec(), policy: CodePolicy::default() }
+    }
+
+    pub fn with_policy(mut self, policy: CodePolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    pub fn policy(&self) -> &CodePolicy {
+        &self.policy
+    }
+
+    /// A new code for `email`, replacing any earlier one; `ip` is the client
+    /// asking, when known. Returns the code, to be sent, and its expiry.
+    pub async fn issue(&self, workspace: &str, email: &str, ip: Option<&str>) -> Result<(String, DateTime<Utc>), CodeError> {
+        let email = normalize(email);
+        self.throttle(workspace, &email, ip).await?;
+
+        let now = Utc::now();
+        let code = format!("{:06}", rand::thread_rng().gen_range(0..1_000_000));
+        let record = CodeRecord {
+            workspace: workspace.to_string(),
+            hash: self.hash(workspace, &email, &code),
+            email,
+            expires_at: now + self.policy.ttl,
+            attempts: 0,
+            created_at: now,
+        };
+        self.store.replace(&record).await?;
+        info!("Issued a login code for {} in {}", record.email, workspace);
+        Ok((code, record.expires_at))
+    }
+
+    /// Count a request for a code, or for anything else sent to `email` to sign
+    /// in with, such as a magic link; refused past the limits of the policy
+    pub async fn throttle(&self, workspace: &str, email: &str, ip: Option<&str>) -> Result<(), CodeError> {
+        let email = normalize(email);
+        let now = Utc::now();
+        if !self.allow(workspace, &format!("email:{}", email), self.policy.per_email, now).await? {
+            warn!("Too many code requests for {} in {}", email, workspace);
+            return Err(CodeError::RateLimited);
+        }
+        if let Some(ip) = ip {
+            if !self.allow(workspace, &format!("ip:{}", ip), self.policy.per_ip, now).await? {
+                warn!("Too many code requests from {} in {}", ip, workspace);
+                return Err(CodeError::RateLimited);
+            }
+        }
+        Ok(())
+    }
+
+    /// Check a code and consume it
+    pub async fn verify(&self, workspace: &str, email: &str, code: &str) -> Result<(), CodeError> {
+        let email = normalize(email);
+        let record = self.store.load(workspace, &email).await?.ok_or(CodeError::Invalid)?;
+        if record.attempts >= self.policy.max_attempts {
+            return Err(CodeError::TooManyAttempts);
+        }
+        if record.expires_at <= Utc::now() {
+            self.store.remove(workspace, &email).await?;
+            return Err(CodeError::Expired);
+        }
+
+        let expected = hex::decode(&record.hash).map_err(|e| CodeError::Storage(format!("Corrupt code hash: {}", e)))?;
+        if self.mac(workspace, &email, code.trim()).verify_slice(&expected).is_ok() {
+            // Used once: a concurrent check of the same code loses here
+            return match self.store.consume(workspace, &email, &record.hash).await? {
+                true => Ok(()),
+                false => Err(CodeError::Invalid),
+            };
+        }
+
+        match self.store.fail(workspace, &email).await? {
+            Some(attempts) if attempts >= self.policy.max_attempts => {
+                warn!("Login code for {} in {} burnt after {} wrong attempts", email, workspace, attempts);
+                Err(CodeError::TooManyAttempts)
+            }
+            _ => Err(CodeError::Invalid),
+        }
+    }
+
+    async fn allow(&self, workspace: &str, key: &str, limit: u32, now: DateTime<Utc>) -> Result<bool, CodeError> {
+        let window = self.policy.window.num_seconds().max(1);
+        let start = now.timestamp() - now.timestamp().rem_euclid(window);
+        let window_start = DateTime::from_timestamp(start, 0).unwrap_or(now);
+        let hits = self.store.hit(workspace, key, window_start, window_start + self.policy.window).await?;
+        Ok(hits <= limit)
+    }
+
+    fn mac(&self, workspace: &str, email: &str, code: &str) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.secret)
+            .expect("HMAC accepts keys of any length");
+        mac.update(format!("{}\0{}\0{}", workspace, email, code).as_bytes());
+        mac
+    }
+
+    fn hash(&self, workspace: &str, email: &str, code: &str) -> String {
+        hex::encode(self.mac(workspace, email, code).finalize().into_bytes())
+    }
+}
+
+fn normalize(email: &str) -> String {
+    email.trim().to_lowercase()
+}
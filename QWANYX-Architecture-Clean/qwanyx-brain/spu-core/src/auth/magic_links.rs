@@ -0,0 +1,256 @@
+//! Magic links
+//!
+//! Signing in by clicking a link sent by email instead of typing a code. The
+//! link opens a page of the workspace's site with a token,
+//! `mlk_autodin.<id>.<expiry>.<signature>`, which the page sends back to be
+//! traded for a session. Tokens are signed, so forged or altered ones are
+//! turned away before any lookup, and each works once: the link is kept, with
+//! the email it was sent to, until it is used or expires.
+//!
+//! Asking for links counts against the same limits as asking for codes (see
+//! `LoginCodes::throttle`).
+
+use super::{bson_date, chrono_date};
+use crate::workspaces::WorkspaceDatabases;
+use async_trait::async_trait;
+use chrono::{DateTime, Duration, Utc};
+use hmac::{Hmac, Mac};
+use mongodb::{Collection, bson::{doc, Document}};
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::sync::Arc;
+use thiserror::Error;
+use tokio::sync::RwLock;
+use tracing::info;
+
+/// Start of every token, telling it apart from codes and keys in logs
+pub const TOKEN_PREFIX: &str = "mlk_";
+
+/// Characters left as they are in URLs
+const UNRESERVED: &AsciiSet = &NON_ALPHANUMERIC.remove(b'-').remove(b'.').remove(b'_').remove(b'~');
+
+/// A link as stored: never its token
+#[derive(Debug, Clone, PartialEq)]
+pub struct LinkRecord {
+    pub workspace: String,
+    pub id: String,
+    /// Trimmed and lowercased
+    pub email: String,
+    pub expires_at: DateTime<Utc>,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Why a link was not accepted
+#[derive(Debug, Error, PartialEq)]
+pub enum LinkError {
+    /// Also for forged links and links used already
+    #[error("Invalid link")]
+    Invalid,
+
+    #[error("Link expired, request a new one")]
+    Expired,
+
+    #[error("Storage error: {0}")]
+    Storage(String),
+}
+
+impl From<String> for LinkError {
+    fn from(e: String) -> Self {
+        LinkError::Storage(e)
+    }
+}
+
+/// The page `page` opening the sign-in of `token`
+pub fn link(page: &str, token: &str) -> String {
+    let separator = if page.contains('?') { '&' } else { '?' };
+    format!("{}{}token={}", page, separator, utf8_percent_encode(token, UNRESERVED))
+}
+
+/// How long links stay valid: `AUTH_LINK_TTL_MINUTES`, 15 minutes by default
+pub fn ttl_from_env() -> Duration {
+    std::env::var("AUTH_LINK_TTL_MINUTES").ok()
+        .and_then(|v| v.parse::<u32>().ok())
+        .map(|m| Duration::minutes(m as i64))
+        .unwrap_or(Duration::minutes(15))
+}
+
+/// The workspace, id and expiry a token names, whether or not it exists
+fn parse(signed: &str) -> Option<(&str, &str, i64)> {
+    let rest = signed.strip_prefix(TOKEN_PREFIX)?;
+    let mut parts = rest.rsplitn(3, '.');
+    let (expiry, id, workspace) = (parts.next()?, parts.next()?, parts.next()?);
+    let expiry = expiry.parse().ok()?;
+    (!workspace.is_empty() && !id.is_empty()).then_some((workspace, id, expiry))
+}
+
+// ================================================================================
+// STORAGE
+// ================================================================================
+
+/// Persistence for links not used yet
+#[async_trait]
+pub trait LinkStore: Send + Sync {
+    async fn insert(&self, record: &LinkRecord) -> Result<(), String>;
+
+    /// Delete a link and return it; None if it was already used, so that each
+    /// is used once
+    async fn consume(&self, workspace: &str, id: &str) -> Result<Option<LinkRecord>, String>;
+}
+
+/// In-memory store, for tests and local development
+#[derive(Default)]
+pub struct MemoryLinkStore {
+    links: RwLock<HashMap<(String, String), LinkRecord>>,
+}
+
+impl MemoryLinkStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[async_trait]
+impl LinkStore for MemoryLinkStore {
+    async fn insert(&self, record: &LinkRecord) -> Result<(), String> {
+        let mut links = self.links.write().await;
+        // Forget the expired ones while at it
+        let now = Utc::now();
+        links.retain(|_, link| link.expires_at > now);
+        links.insert((record.workspace.clone(), record.id.clone()), record.clone());
+        Ok(())
+    }
+
+    async fn consume(&self, workspace: &str, id: &str) -> Result<Option<LinkRecord>, String> {
+        let mut links = self.links.write().await;
+        Ok(links.remove(&(workspace.to_string(), id.to_string())))
+    }
+}
+
+/// MongoDB store
+///
+/// Links live in the workspace's own database, in `spu_magic_links` keyed by
+/// id, with an `expires_at` date for the TTL index of `create_indexes` to
+/// clean up.
+pub struct MongoLinkStore {
+    databases: WorkspaceDatabases,
+}
+
+impl MongoLinkStore {
+    pub fn new(databases: WorkspaceDatabases) -> Self {
+        Self { databases }
+    }
+
+    /// Have expired links deleted, in every workspace
+    pub async fn create_indexes(&self) -> Result<(), String> {
+        self.databases.expire_at("spu_magic_links", "expires_at").await
+    }
+
+    fn links(&self, workspace: &str) -> Result<Collection<Document>, String> {
+        Ok(self.databases.database(workspace)?.collection("spu_magic_links"))
+    }
+
+    fn from_document(workspace: &str, document: &Document) -> Result<LinkRecord, String> {
+        let corrupt = |e: mongodb::bson::document::ValueAccessError| format!("Corrupt magic link document: {}", e);
+        Ok(LinkRecord {
+            workspace: workspace.to_string(),
+            id: document.get_str("_id").map_err(corrupt)?.to_string(),
+            email: document.get_str("email").map_err(corrupt)?.to_string(),
+            expires_at: chrono_date(document.get_datetime("expires_at").map_err(corrupt)?),
+            created_at: chrono_date(document.get_datetime("created_at").map_err(corrupt)?),
+        })
+    }
+}
+
+#[async_trait]
+impl LinkStore for MongoLinkStore {
+    async fn insert(&self, record: &LinkRecord) -> Result<(), String> {
+        let document = doc! {
+            "_id": &record.id,
+            "email": &record.email,
+            "expires_at": bson_date(record.expires_at),
+            "created_at": bson_date(record.created_at),
+        };
+        self.links(&record.workspace)?.insert_one(document, None).await
+            .map_err(|e| format!("Failed to save magic link: {}", e))?;
+        Ok(())
+    }
+
+    async fn consume(&self, workspace: &str, id: &str) -> Result<Option<LinkRecord>, String> {
+        let document = self.links(workspace)?.find_one_and_delete(doc! { "_id": id }, None).await
+            .map_err(|e| format!("Failed to consume magic link: {}", e))?;
+        document.map(|document| Self::from_document(workspace, &document)).transpose()
+    }
+}
+
+// ================================================================================
+// LINKS
+// ================================================================================
+
+/// Issues and checks magic-link tokens
+pub struct MagicLinks {
+    store: Arc<dyn LinkStore>,
+    secret: Vec<u8>,
+    ttl: Duration,
+}
+
+impl MagicLinks {
+    /// Tokens signed with `secret`, which must stay the same for links to work
+    pub fn new(store: Arc<dyn LinkStore>, secret: &str) -> Self {
+        Self { store, secret: secret.as_bytes().to_vec(), ttl: Duration::minutes(15) }
+    }
+
+    pub fn with_ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    pub fn ttl(&self) -> Duration {
+        self.ttl
+    }
+
+    /// A new token signing `email` in, and its expiry
+    pub async fn issue(&self, workspace: &str, email: &str) -> Result<(String, DateTime<Utc>), LinkError> {
+        let now = Utc::now();
+        let record = LinkRecord {
+            workspace: workspace.to_string(),
+            id: uuid::Uuid::new_v4().simple().to_string(),
+            email: email.trim().to_lowercase(),
+            // Whole seconds, as in the token
+            expires_at: DateTime::from_timestamp((now + self.ttl).timestamp(), 0).unwrap_or(now),
+            created_at: now,
+        };
+        let signed = format!("{}{}.{}.{}", TOKEN_PREFIX, workspace, record.id, record.expires_at.timestamp());
+        let token = format!("{}.{}", signed, hex::encode(self.mac(&signed).finalize().into_bytes()));
+        self.store.insert(&record).await?;
+        info!("Issued a magic link for {} in {}", record.email, workspace);
+        Ok((token, record.expires_at))
+    }
+
+    /// Check a token of `workspace` and use it up; returns the email it signs in
+    pub async fn verify(&self, workspace: &str, token: &str) -> Result<String, LinkError> {
+        let (signed, signature) = token.trim().rsplit_once('.').ok_or(LinkError::Invalid)?;
+        let (named, id, expiry) = parse(signed).ok_or(LinkError::Invalid)?;
+        let signature = hex::decode(signature).map_err(|_| LinkError::Invalid)?;
+        if named != workspace || self.mac(signed).verify_slice(&signature).is_err() {
+            return Err(LinkError::Invalid);
+        }
+        if expiry <= Utc::now().timestamp() {
+            return Err(LinkError::Expired);
+        }
+
+        // Gone once used: a concurrent click on the same link loses here
+        let record = self.store.consume(workspace, id).await?.ok_or(LinkError::Invalid)?;
+        if record.expires_at <= Utc::now() {
+            return Err(LinkError::Expired);
+        }
+        Ok(record.email)
+    }
+
+    fn mac(&self, signed: &str) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.secret)
+            .expect("HMAC accepts keys of any length");
+        mac.update(signed.as_bytes());
+        mac
+    }
+}
@@ -0,0 +1,39 @@
+//! Authentication
+//!
+//! The pieces behind the `auth` coprocessor and the `/auth` routes, each with
+//! in-memory and MongoDB storage where they keep state:
+//!
+//! - `codes`: one-time login codes sent by email
+//! - `magic_links`: single-use sign-in links sent by email
+//! - `two_factor`: authenticator apps and recovery codes as a second factor
+//! - `oidc`: signing in with Google, Microsoft and other OpenID Connect providers
+//! - `tokens`: signed access tokens and the keys they are signed with
+//! - `sessions`: refresh tokens, sessions per device, and revocation
+//! - `policy`: what each role may read and write in a workspace
+//! - `api_keys`: keys machine clients authenticate with instead of signing in
+//! - `users`: who signs in, with which id and roles
+//!
+//! The MongoDB stores are built over the server's one client (see
+//! `crate::store::connect_mongo`) and keep dates with `bson_date` and
+//! `chrono_date`.
+
+pub mod api_keys;
+pub mod codes;
+pub mod magic_links;
+pub mod oidc;
+pub mod policy;
+pub mod sessions;
+pub mod tokens;
+pub mod two_factor;
+pub mod users;
+
+use chrono::{DateTime, Utc};
+
+/// Dates as the MongoDB stores keep them, to the millisecond
+pub(crate) fn bson_date(date: DateTime<Utc>) -> mongodb::bson::DateTime {
+    mongodb::bson::DateTime::from_millis(date.timestamp_millis())
+}
+
+pub(crate) fn chrono_date(date: &mongodb::bson::DateTime) -> DateTime<Utc> {
+    DateTime::from_timestamp_millis(date.timestamp_millis()).unwrap_or_default()
+}
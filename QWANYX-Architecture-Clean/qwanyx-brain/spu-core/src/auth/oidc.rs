@@ -0,0 +1,773 @@
+//! OpenID Connect sign-in
+//!
+//! Signing in with an account of another provider, such as Google or
+//! Microsoft, instead of an email code. Each workspace lists its providers in
+//! the registry (`Workspace::providers`):
+//!
+//! ```json
+//! {"id": "google", "name": "Google", "issuer": "https://accounts.google.com",
+//!  "client_id": "1234.apps.googleusercontent.com", "client_secret_env": "GOOGLE_CLIENT_SECRET",
+//!  "redirect_uri": "https://autodin.be/auth/oidc/google"}
+//! ```
+//!
+//! Signing in is the authorization code flow with PKCE. `authorize` gives the
+//! provider's URL to send the browser to, with a fresh state, nonce and code
+//! challenge kept until the provider sends the browser back to `redirect_uri`
+//! with a code. The page there hands code and state to `callback`, which trades
+//! them for an ID token at the provider and checks it against the keys the
+//! provider publishes: signature, issuer, audience, expiry and nonce. Endpoints
+//! and keys are discovered from `{issuer}/.well-known/openid-configuration`.
+//! Microsoft's multi-tenant issuer, `.../{tenantid}/v2.0`, is matched against
+//! the token's `tid`.
+//!
+//! An account of a provider (its `sub`) is an identity, linked to one user of
+//! the workspace. The first time, it is linked to the user of its email if the
+//! provider verified the email: `email_verified`, or `trust_email` for
+//! providers that only give verified ones without saying so. Signed-in users
+//! link more identities by starting `authorize` as themselves, and unlink them.
+
+use super::{bson_date, chrono_date};
+use crate::audit::{AuditEntry, AuditLog};
+use crate::workspaces::{WorkspaceDatabases, Workspaces};
+use super::tokens::TokenKey;
+use async_trait::async_trait;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::{DateTime, Duration, Utc};
+use jsonwebtoken::{Algorithm, Validation};
+use mongodb::{Collection, bson::{doc, Document}, options::UpdateOptions};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::Arc;
+use thiserror::Error;
+use tokio::sync::RwLock;
+use tracing::{info, warn};
+
+/// How long a sign-in started with `authorize` may take
+const FLOW_TTL_MINUTES: i64 = 10;
+
+/// Least time between two fetches of a provider's keys, for tokens naming a
+/// key it does not have
+const KEYS_REFRESH_SECONDS: i64 = 60;
+
+/// Placeholder in the issuer of Microsoft's multi-tenant endpoints
+const TENANT_PLACEHOLDER: &str = "{tenantid}";
+
+/// A provider users of a workspace may sign in with
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ProviderConfig {
+    /// Name in URLs, e.g. `google`
+    pub id: String,
+    /// Display name; the id when not set
+    #[serde(default)]
+    pub name: Option<String>,
+    /// e.g. `https://accounts.google.com`
+    pub issuer: String,
+    pub client_id: String,
+    /// Environment variable holding the client secret; none for public clients
+    #[serde(default)]
+    pub client_secret_env: Option<String>,
+    /// The page of the workspace's site the provider sends the browser back to
+    pub redirect_uri: String,
+    #[serde(default = "default_scopes")]
+    pub scopes: Vec<String>,
+    /// Take emails as verified without `email_verified`
+    #[serde(default)]
+    pub trust_email: bool,
+}
+
+fn default_scopes() -> Vec<String> {
+    vec!["openid".to_string(), "email".to_string(), "profile".to_string()]
+}
+
+impl ProviderConfig {
+    pub fn name(&self) -> &str {
+        self.name.as_deref().unwrap_or(&self.id)
+    }
+
+    pub(crate) fn validate(&self) -> Result<(), String> {
+        if self.id.trim().is_empty() || !self.id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+            return Err(format!("Invalid sign-in provider id '{}'", self.id));
+        }
+        for (field, value) in [("issuer", &self.issuer), ("client_id", &self.client_id), ("redirect_uri", &self.redirect_uri)] {
+            if value.trim().is_empty() {
+                return Err(format!("Sign-in provider '{}' has an empty {}", self.id, field));
+            }
+        }
+        Ok(())
+    }
+
+    fn client_secret(&self) -> Option<String> {
+        self.client_secret_env.as_ref().and_then(|name| std::env::var(name).ok())
+    }
+}
+
+/// An account of a provider, linked to a user
+#[derive(Debug, Clone, PartialEq)]
+pub struct Identity {
+    pub workspace: String,
+    pub provider: String,
+    /// The account's `sub` at the provider
+    pub subject: String,
+    /// The user it signs in, as in their token's `sub`
+    pub user: String,
+    /// As the provider gave it, verified or not
+    pub email: Option<String>,
+    pub linked_at: DateTime<Utc>,
+    pub last_used_at: Option<DateTime<Utc>>,
+}
+
+/// A sign-in sent to a provider and not back yet
+#[derive(Debug, Clone, PartialEq)]
+pub struct Flow {
+    pub workspace: String,
+    /// Hex SHA-256 of the state
+    pub id: String,
+    pub provider: String,
+    /// PKCE code verifier
+    pub verifier: String,
+    pub nonce: String,
+    /// The signed-in user linking an identity, if not signing in
+    pub user: Option<String>,
+    pub expires_at: DateTime<Utc>,
+}
+
+/// Where to send the browser to sign in at a provider
+#[derive(Debug, Clone, PartialEq)]
+pub struct Authorization {
+    pub url: String,
+    pub expires_at: DateTime<Utc>,
+}
+
+/// A sign-in back from its provider
+#[derive(Debug, Clone, PartialEq)]
+pub struct Callback {
+    pub identity: Identity,
+    /// Whether the flow linked the identity to a signed-in user instead of
+    /// signing in
+    pub linked: bool,
+}
+
+#[derive(Debug, Error, PartialEq)]
+pub enum OidcError {
+    #[error("Unknown sign-in provider '{0}'")]
+    UnknownProvider(String),
+
+    /// Also for states used already
+    #[error("Sign-in expired, start again")]
+    InvalidState,
+
+    #[error("Sign-in provider failed: {0}")]
+    Provider(String),
+
+    #[error("Invalid ID token: {0}")]
+    InvalidToken(String),
+
+    #[error("The provider has not verified the email of this account")]
+    UnverifiedEmail,
+
+    #[error("Not a user of workspace {0}")]
+    NotAdmitted(String),
+
+    #[error("This account is linked to another user")]
+    AlreadyLinked,
+
+    #[error("Linked account not found")]
+    NotFound,
+
+    #[error("Storage error: {0}")]
+    Storage(String),
+}
+
+impl From<String> for OidcError {
+    fn from(e: String) -> Self {
+        OidcError::Storage(e)
+    }
+}
+
+/// The endpoints of a provider, from its discovery document
+#[derive(Debug, Clone, Deserialize)]
+struct Discovery {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    jwks_uri: String,
+}
+
+/// The claims of an ID token used here
+#[derive(Debug, Deserialize)]
+struct IdClaims {
+    iss: String,
+    sub: String,
+    #[serde(default)]
+    nonce: Option<String>,
+    #[serde(default)]
+    email: Option<String>,
+    /// A boolean, or a string with some providers
+    #[serde(default)]
+    email_verified: Option<Value>,
+    /// Microsoft's tenant
+    #[serde(default)]
+    tid: Option<String>,
+}
+
+/// A provider's published keys
+struct ProviderKeys {
+    keys: Vec<TokenKey>,
+    fetched_at: DateTime<Utc>,
+}
+
+// ================================================================================
+// STORAGE
+// ================================================================================
+
+/// Persistence for flows under way and linked identities
+#[async_trait]
+pub trait OidcStore: Send + Sync {
+    async fn insert_flow(&self, flow: &Flow) -> Result<(), String>;
+
+    /// Delete a flow and return it; None if it was already used, so that
+    /// each state is used once
+    async fn take_flow(&self, workspace: &str, id: &str) -> Result<Option<Flow>, String>;
+
+    async fn identity(&self, workspace: &str, provider: &str, subject: &str) -> Result<Option<Identity>, String>;
+
+    /// Link an identity; false if it is linked already
+    async fn insert_identity(&self, identity: &Identity) -> Result<bool, String>;
+
+    async fn touch_identity(&self, workspace: &str, provider: &str, subject: &str, at: DateTime<Utc>) -> Result<(), String>;
+
+    async fn identities(&self, workspace: &str, user: &str) -> Result<Vec<Identity>, String>;
+
+    /// Unlink an identity of `user`; false if they had no such identity
+    async fn remove_identity(&self, workspace: &str, user: &str, provider: &str, subject: &str) -> Result<bool, String>;
+}
+
+/// In-memory store, for tests and local development
+#[derive(Default)]
+pub struct MemoryOidcStore {
+    flows: RwLock<HashMap<(String, String), Flow>>,
+    /// By workspace, provider and subject
+    identities: RwLock<HashMap<(String, String, String), Identity>>,
+}
+
+impl MemoryOidcStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+fn identity_key(workspace: &str, provider: &str, subject: &str) -> (String, String, String) {
+    (workspace.to_string(), provider.to_string(), subject.to_string())
+}
+
+#[async_trait]
+impl OidcStore for MemoryOidcStore {
+    async fn insert_flow(&self, flow: &Flow) -> Result<(), String> {
+        let mut flows = self.flows.write().await;
+        // Forget the abandoned ones while at it
+        let now = Utc::now();
+        flows.retain(|_, flow| flow.expires_at > now);
+        flows.insert((flow.workspace.clone(), flow.id.clone()), flow.clone());
+        Ok(())
+    }
+
+    async fn take_flow(&self, workspace: &str, id: &str) -> Result<Option<Flow>, String> {
+        let mut flows = self.flows.write().await;
+        Ok(flows.remove(&(workspace.to_string(), id.to_string())))
+    }
+
+    async fn identity(&self, workspace: &str, provider: &str, subject: &str) -> Result<Option<Identity>, String> {
+        let identities = self.identities.read().await;
+        Ok(identities.get(&identity_key(workspace, provider, subject)).cloned())
+    }
+
+    async fn insert_identity(&self, identity: &Identity) -> Result<bool, String> {
+        let mut identities = self.identities.write().await;
+        let key = identity_key(&identity.workspace, &identity.provider, &identity.subject);
+        if identities.contains_key(&key) {
+            return Ok(false);
+        }
+        identities.insert(key, identity.clone());
+        Ok(true)
+    }
+
+    async fn touch_identity(&self, workspace: &str, provider: &str, subject: &str, at: DateTime<Utc>) -> Result<(), String> {
+        let mut identities = self.identities.write().await;
+        if let Some(identity) = identities.get_mut(&identity_key(workspace, provider, subject)) {
+            identity.last_used_at = Some(at);
+        }
+        Ok(())
+    }
+
+    async fn identities(&self, workspace: &str, user: &str) -> Result<Vec<Identity>, String> {
+        let identities = self.identities.read().await;
+        let mut found: Vec<Identity> = identities.values()
+            .filter(|identity| identity.workspace == workspace && identity.user == user)
+            .cloned()
+            .collect();
+        found.sort_by_key(|identity| identity.linked_at);
+        Ok(found)
+    }
+
+    async fn remove_identity(&self, workspace: &str, user: &str, provider: &str, subject: &str) -> Result<bool, String> {
+        let mut identities = self.identities.write().await;
+        let key = identity_key(workspace, provider, subject);
+        if identities.get(&key).is_some_and(|identity| identity.user == user) {
+            identities.remove(&key);
+            return Ok(true);
+        }
+        Ok(false)
+    }
+}
+
+/// MongoDB store
+///
+/// Flows live in the workspace's own database, in `spu_oidc_flows` keyed by
+/// id with an `expires_at` date for the TTL index of `create_indexes` to
+/// clean up, and identities in `spu_identities` keyed by `{provider}:{subject}`.
+pub struct MongoOidcStore {
+    databases: WorkspaceDatabases,
+}
+
+impl MongoOidcStore {
+    pub fn new(databases: WorkspaceDatabases) -> Self {
+        Self { databases }
+    }
+
+    /// Have flows that were never finished deleted once expired, in every workspace
+    pub async fn create_indexes(&self) -> Result<(), String> {
+        self.databases.expire_at("spu_oidc_flows", "expires_at").await
+    }
+
+    fn flows(&self, workspace: &str) -> Result<Collection<Document>, String> {
+        Ok(self.databases.database(workspace)?.collection("spu_oidc_flows"))
+    }
+
+    fn identities_of(&self, workspace: &str) -> Result<Collection<Document>, String> {
+        Ok(self.databases.database(workspace)?.collection("spu_identities"))
+    }
+
+    fn flow_from_document(workspace: &str, document: &Document) -> Result<Flow, String> {
+        let corrupt = |e: mongodb::bson::document::ValueAccessError| format!("Corrupt sign-in flow document: {}", e);
+        Ok(Flow {
+            workspace: workspace.to_string(),
+            id: document.get_str("_id").map_err(corrupt)?.to_string(),
+            provider: document.get_str("provider").map_err(corrupt)?.to_string(),
+            verifier: document.get_str("verifier").map_err(corrupt)?.to_string(),
+            nonce: document.get_str("nonce").map_err(corrupt)?.to_string(),
+            user: document.get_str("user").ok().map(str::to_string),
+            expires_at: chrono_date(document.get_datetime("expires_at").map_err(corrupt)?),
+        })
+    }
+
+    fn identity_from_document(workspace: &str, document: &Document) -> Result<Identity, String> {
+        let corrupt = |e: mongodb::bson::document::ValueAccessError| format!("Corrupt identity document: {}", e);
+        Ok(Identity {
+            workspace: workspace.to_string(),
+            provider: document.get_str("provider").map_err(corrupt)?.to_string(),
+            subject: document.get_str("subject").map_err(corrupt)?.to_string(),
+            user: document.get_str("user").map_err(corrupt)?.to_string(),
+            email: document.get_str("email").ok().map(str::to_string),
+            linked_at: chrono_date(document.get_datetime("linked_at").map_err(corrupt)?),
+            last_used_at: document.get_datetime("last_used_at").ok().map(chrono_date),
+        })
+    }
+}
+
+fn identity_id(provider: &str, subject: &str) -> String {
+    format!("{}:{}", provider, subject)
+}
+
+#[async_trait]
+impl OidcStore for MongoOidcStore {
+    async fn insert_flow(&self, flow: &Flow) -> Result<(), String> {
+        let document = doc! {
+            "_id": &flow.id,
+            "provider": &flow.provider,
+            "verifier": &flow.verifier,
+            "nonce": &flow.nonce,
+            "user": &flow.user,
+            "expires_at": bson_date(flow.expires_at),
+        };
+        self.flows(&flow.workspace)?.insert_one(document, None).await
+            .map_err(|e| format!("Failed to save sign-in flow: {}", e))?;
+        Ok(())
+    }
+
+    async fn take_flow(&self, workspace: &str, id: &str) -> Result<Option<Flow>, String> {
+        let document = self.flows(workspace)?.find_one_and_delete(doc! { "_id": id }, None).await
+            .map_err(|e| format!("Failed to load sign-in flow: {}", e))?;
+        document.map(|document| Self::flow_from_document(workspace, &document)).transpose()
+    }
+
+    async fn identity(&self, workspace: &str, provider: &str, subject: &str) -> Result<Option<Identity>, String> {
+        let document = self.identities_of(workspace)?.find_one(doc! { "_id": identity_id(provider, subject) }, None).await
+            .map_err(|e| format!("Failed to load identity: {}", e))?;
+        document.map(|document| Self::identity_from_document(workspace, &document)).transpose()
+    }
+
+    async fn insert_identity(&self, identity: &Identity) -> Result<bool, String> {
+        // Only inserted when absent, so that two links of the same account
+        // cannot both win
+        let update = doc! {
+            "$setOnInsert": {
+                "provider": &identity.provider,
+                "subject": &identity.subject,
+                "user": &identity.user,
+                "email": &identity.email,
+                "linked_at": bson_date(identity.linked_at),
+            }
+        };
+        let options = UpdateOptions::builder().upsert(true).build();
+        let result = self.identities_of(&identity.workspace)?
+            .update_one(doc! { "_id": identity_id(&identity.provider, &identity.subject) }, update, options).await
+            .map_err(|e| format!("Failed to link identity: {}", e))?;
+        Ok(result.upserted_id.is_some())
+    }
+
+    async fn touch_identity(&self, workspace: &str, provider: &str, subject: &str, at: DateTime<Utc>) -> Result<(), String> {
+        self.identities_of(workspace)?
+            .update_one(doc! { "_id": identity_id(provider, subject) }, doc! { "$set": { "last_used_at": bson_date(at) } }, None).await
+            .map_err(|e| format!("Failed to update identity: {}", e))?;
+        Ok(())
+    }
+
+    async fn identities(&self, workspace: &str, user: &str) -> Result<Vec<Identity>, String> {
+        use futures::TryStreamExt;
+        let options = mongodb::options::FindOptions::builder().sort(doc! { "linked_at": 1 }).build();
+        let documents: Vec<Document> = self.identities_of(workspace)?.find(doc! { "user": user }, options).await
+            .map_err(|e| format!("Failed to list identities: {}", e))?
+            .try_collect().await
+            .map_err(|e| format!("Failed to list identities: {}", e))?;
+        documents.iter().map(|document| Self::identity_from_document(workspace, document)).collect()
+    }
+
+    async fn remove_identity(&self, workspace: &str, user: &str, provider: &str, subject: &str) -> Result<bool, String> {
+        let result = self.identities_of(workspace)?
+            .delete_one(doc! { "_id": identity_id(provider, subject), "user": user }, None).await
+            .map_err(|e| format!("Failed to unlink identity: {}", e))?;
+        Ok(result.deleted_count > 0)
+    }
+}
+
+// ================================================================================
+// SIGN-IN
+// ================================================================================
+
+/// Signs users in with the providers of their workspace
+pub struct Oidc {
+    store: Arc<dyn OidcStore>,
+    workspaces: Arc<Workspaces>,
+    http: reqwest::Client,
+    /// By issuer
+    discoveries: RwLock<HashMap<String, Discovery>>,
+    /// By `jwks_uri`
+    keys: RwLock<HashMap<String, ProviderKeys>>,
+    audit: Option<Arc<AuditLog>>,
+}
+
+impl Oidc {
+    /// Sign-ins with the providers `workspaces` list
+    pub fn new(store: Arc<dyn OidcStore>, workspaces: Arc<Workspaces>) -> Self {
+        let http = reqwest::Client::builder()
+            .timeout(std::time::Duration::from_secs(10))
+            .redirect(reqwest::redirect::Policy::none())
+            .build()
+            .expect("the HTTP client has a valid configuration");
+        Self {
+            store,
+            workspaces,
+            http,
+            discoveries: RwLock::new(HashMap::new()),
+            keys: RwLock::new(HashMap::new()),
+            audit: None,
+        }
+    }
+
+    /// Log linking and unlinking in this audit log
+    pub fn with_audit(mut self, audit: Arc<AuditLog>) -> Self {
+        self.audit = Some(audit);
+        self
+    }
+
+    /// The workspaces it signs in to
+    pub fn workspaces(&self) -> &Workspaces {
+        &self.workspaces
+    }
+
+    /// The providers of `workspace`
+    pub fn providers(&self, workspace: &str) -> &[ProviderConfig] {
+        self.workspaces.get(workspace).map(|w| w.providers.as_slice()).unwrap_or_default()
+    }
+
+    pub fn provider(&self, workspace: &str, id: &str) -> Result<&ProviderConfig, OidcError> {
+        self.providers(workspace).iter()
+            .find(|provider| provider.id == id)
+            .ok_or_else(|| OidcError::UnknownProvider(id.to_string()))
+    }
+
+    /// Start signing in with `provider`, or linking it to `user` if given
+    pub async fn authorize(&self, workspace: &str, provider: &str, user: Option<&str>) -> Result<Authorization, OidcError> {
+        let provider = self.provider(workspace, provider)?;
+        let discovery = self.discovery(provider).await?;
+
+        let state = random_hex(32);
+        let verifier = URL_SAFE_NO_PAD.encode(random_bytes(32));
+        let challenge = URL_SAFE_NO_PAD.encode(Sha256::digest(verifier.as_bytes()));
+        let flow = Flow {
+            workspace: workspace.to_string(),
+            id: flow_id(&state),
+            provider: provider.id.clone(),
+            verifier,
+            nonce: random_hex(16),
+            user: user.map(str::to_string),
+            expires_at: Utc::now() + Duration::minutes(FLOW_TTL_MINUTES),
+        };
+
+        let url = reqwest::Url::parse_with_params(&discovery.authorization_endpoint, &[
+            ("response_type", "code"),
+            ("client_id", provider.client_id.as_str()),
+            ("redirect_uri", provider.redirect_uri.as_str()),
+            ("scope", provider.scopes.join(" ").as_str()),
+            ("state", state.as_str()),
+            ("nonce", flow.nonce.as_str()),
+            ("code_challenge", challenge.as_str()),
+            ("code_challenge_method", "S256"),
+        ]).map_err(|e| OidcError::Provider(format!("invalid authorization endpoint: {}", e)))?;
+
+        self.store.insert_flow(&flow).await?;
+        Ok(Authorization { url: url.to_string(), expires_at: flow.expires_at })
+    }
+
+    /// Finish a flow with the code and state the provider sent back: the
+    /// identity signing in, or linked
+    pub async fn callback(&self, workspace: &str, provider: &str, state: &str, code: &str) -> Result<Callback, OidcError> {
+        let provider = self.provider(workspace, provider)?;
+        let flow = self.store.take_flow(workspace, &flow_id(state.trim())).await?
+            .filter(|flow| flow.provider == provider.id && flow.expires_at > Utc::now())
+            .ok_or(OidcError::InvalidState)?;
+
+        let discovery = self.discovery(provider).await?;
+        let id_token = self.exchange(provider, &discovery, &flow, code).await?;
+        let claims = self.validate(provider, &discovery, &id_token, &flow.nonce).await?;
+        let now = Utc::now();
+
+        let existing = self.store.identity(workspace, &provider.id, &claims.sub).await?;
+        match (flow.user, existing) {
+            // Linking an account already linked
+            (Some(user), Some(identity)) => {
+                if identity.user != user {
+                    return Err(OidcError::AlreadyLinked);
+                }
+                self.store.touch_identity(workspace, &provider.id, &claims.sub, now).await?;
+                Ok(Callback { identity, linked: true })
+            }
+            (Some(user), None) => {
+                let identity = self.link(workspace, &provider.id, &claims, &user).await?;
+                Ok(Callback { identity, linked: true })
+            }
+            (None, Some(mut identity)) => {
+                self.store.touch_identity(workspace, &provider.id, &claims.sub, now).await?;
+                identity.last_used_at = Some(now);
+                Ok(Callback { identity, linked: false })
+            }
+            // First sign-in with this account: the user of its email
+            (None, None) => {
+                let verified = provider.trust_email || match &claims.email_verified {
+                    Some(Value::Bool(verified)) => *verified,
+                    Some(Value::String(verified)) => verified == "true",
+                    _ => false,
+                };
+                let email = claims.email.as_deref()
+                    .map(|email| email.trim().to_lowercase())
+                    .filter(|email| verified && !email.is_empty())
+                    .ok_or(OidcError::UnverifiedEmail)?;
+                if let Some(registered) = self.workspaces.get(workspace) {
+                    if !registered.admits(&email) {
+                        return Err(OidcError::NotAdmitted(registered.name().to_string()));
+                    }
+                }
+                let mut identity = self.link(workspace, &provider.id, &claims, &email).await?;
+                identity.last_used_at = Some(now);
+                Ok(Callback { identity, linked: false })
+            }
+        }
+    }
+
+    /// The identities linked to `user`
+    pub async fn identities(&self, workspace: &str, user: &str) -> Result<Vec<Identity>, OidcError> {
+        Ok(self.store.identities(workspace, user).await?)
+    }
+
+    /// Unlink an identity of `user`
+    pub async fn unlink(&self, workspace: &str, user: &str, provider: &str, subject: &str) -> Result<(), OidcError> {
+        if !self.store.remove_identity(workspace, user, provider, subject).await? {
+            return Err(OidcError::NotFound);
+        }
+        info!("Unlinked {} account {} from {} in {}", provider, subject, user, workspace);
+        self.audit(workspace, "auth.identity_unlinked", user, provider, subject).await;
+        Ok(())
+    }
+
+    async fn link(&self, workspace: &str, provider: &str, claims: &IdClaims, user: &str) -> Result<Identity, OidcError> {
+        let identity = Identity {
+            workspace: workspace.to_string(),
+            provider: provider.to_string(),
+            subject: claims.sub.clone(),
+            user: user.to_string(),
+            email: claims.email.clone(),
+            linked_at: Utc::now(),
+            last_used_at: None,
+        };
+        if !self.store.insert_identity(&identity).await? {
+            // Linked by a concurrent sign-in: fine if to the same user
+            return match self.store.identity(workspace, provider, &claims.sub).await? {
+                Some(linked) if linked.user == user => Ok(linked),
+                _ => Err(OidcError::AlreadyLinked),
+            };
+        }
+        info!("Linked {} account {} to {} in {}", provider, claims.sub, user, workspace);
+        self.audit(workspace, "auth.identity_linked", user, provider, &claims.sub).await;
+        Ok(identity)
+    }
+
+    /// The provider's endpoints, fetched once
+    async fn discovery(&self, provider: &ProviderConfig) -> Result<Discovery, OidcError> {
+        if let Some(discovery) = self.discoveries.read().await.get(&provider.issuer) {
+            return Ok(discovery.clone());
+        }
+        let url = format!("{}/.well-known/openid-configuration", provider.issuer.trim_end_matches('/'));
+        let discovery: Discovery = self.get_json(&url).await?;
+        self.discoveries.write().await.insert(provider.issuer.clone(), discovery.clone());
+        Ok(discovery)
+    }
+
+    /// Trade the code for the ID token, proving the flow with its verifier
+    async fn exchange(&self, provider: &ProviderConfig, discovery: &Discovery, flow: &Flow, code: &str) -> Result<String, OidcError> {
+        let mut form = vec![
+            ("grant_type", "authorization_code".to_string()),
+            ("code", code.trim().to_string()),
+            ("redirect_uri", provider.redirect_uri.clone()),
+            ("client_id", provider.client_id.clone()),
+            ("code_verifier", flow.verifier.clone()),
+        ];
+        if let Some(secret) = provider.client_secret() {
+            form.push(("client_secret", secret));
+        }
+
+        let response = self.http.post(&discovery.token_endpoint).form(&form).send().await
+            .map_err(|e| OidcError::Provider(e.to_string()))?;
+        let status = response.status();
+        let body: Value = response.json().await
+            .map_err(|e| OidcError::Provider(format!("unreadable token response: {}", e)))?;
+        if !status.is_success() {
+            let error = body.get("error_description").or_else(|| body.get("error"))
+                .and_then(Value::as_str)
+                .unwrap_or("token request refused");
+            warn!("{} refused a code for {}: {}", provider.id, flow.workspace, error);
+            return Err(OidcError::Provider(error.to_string()));
+        }
+        body.get("id_token").and_then(Value::as_str)
+            .map(str::to_string)
+            .ok_or_else(|| OidcError::Provider("no ID token in the token response".to_string()))
+    }
+
+    /// Check an ID token's signature and claims
+    async fn validate(&self, provider: &ProviderConfig, discovery: &Discovery, id_token: &str, nonce: &str) -> Result<IdClaims, OidcError> {
+        let invalid = |e: String| OidcError::InvalidToken(e);
+        let header = jsonwebtoken::decode_header(id_token).map_err(|e| invalid(e.to_string()))?;
+        if header.alg != Algorithm::RS256 {
+            return Err(invalid(format!("unsupported algorithm {:?}", header.alg)));
+        }
+        let kid = header.kid.ok_or_else(|| invalid("no key id".to_string()))?;
+
+        let mut validation = Validation::new(Algorithm::RS256);
+        validation.set_audience(&[&provider.client_id]);
+        validation.set_required_spec_claims(&["exp", "iss", "aud", "sub"]);
+        let claims: IdClaims = self.with_key(&discovery.jwks_uri, &kid, |key| {
+            jsonwebtoken::decode::<IdClaims>(id_token, key.decoding(), &validation)
+                .map(|data| data.claims)
+                .map_err(|e| invalid(e.to_string()))
+        }).await?;
+
+        // Multi-tenant issuers name the tenant the token says
+        let issuer = match &claims.tid {
+            Some(tid) => discovery.issuer.replace(TENANT_PLACEHOLDER, tid),
+            None => discovery.issuer.clone(),
+        };
+        if claims.iss != issuer {
+            return Err(invalid(format!("issued by {}", claims.iss)));
+        }
+        if claims.nonce.as_deref() != Some(nonce) {
+            return Err(invalid("nonce mismatch".to_string()));
+        }
+        Ok(claims)
+    }
+
+    /// Run `f` with the provider key `kid`, fetching the provider's keys when
+    /// it has a key not seen yet
+    async fn with_key<T>(&self, jwks_uri: &str, kid: &str, f: impl Fn(&TokenKey) -> Result<T, OidcError>) -> Result<T, OidcError> {
+        let stale = {
+            let keys = self.keys.read().await;
+            match keys.get(jwks_uri) {
+                Some(known) => match known.keys.iter().find(|key| key.kid() == kid) {
+                    Some(key) => return f(key),
+                    None => Utc::now() - known.fetched_at > Duration::seconds(KEYS_REFRESH_SECONDS),
+                },
+                None => true,
+            }
+        };
+        if !stale {
+            return Err(OidcError::InvalidToken(format!("unknown key '{}'", kid)));
+        }
+
+        let jwks: Value = self.get_json(jwks_uri).await?;
+        let fetched: Vec<TokenKey> = jwks.get("keys").and_then(Value::as_array).into_iter().flatten()
+            .filter(|jwk| jwk.get("kty").and_then(Value::as_str) == Some("RSA"))
+            .filter(|jwk| jwk.get("use").and_then(Value::as_str).is_none_or(|usage| usage == "sig"))
+            .filter_map(|jwk| {
+                let field = |name: &str| jwk.get(name).and_then(Value::as_str);
+                TokenKey::rs256_public(field("kid")?, field("n")?, field("e")?).ok()
+            })
+            .collect();
+        let mut keys = self.keys.write().await;
+        keys.insert(jwks_uri.to_string(), ProviderKeys { keys: fetched, fetched_at: Utc::now() });
+        let key = keys[jwks_uri].keys.iter().find(|key| key.kid() == kid)
+            .ok_or_else(|| OidcError::InvalidToken(format!("unknown key '{}'", kid)))?;
+        f(key)
+    }
+
+    async fn get_json<T: serde::de::DeserializeOwned>(&self, url: &str) -> Result<T, OidcError> {
+        let response = self.http.get(url).send().await
+            .map_err(|e| OidcError::Provider(e.to_string()))?;
+        if !response.status().is_success() {
+            return Err(OidcError::Provider(format!("{} answered {}", url, response.status())));
+        }
+        response.json().await.map_err(|e| OidcError::Provider(format!("unreadable {}: {}", url, e)))
+    }
+
+    async fn audit(&self, workspace: &str, action: &str, user: &str, provider: &str, subject: &str) {
+        if let Some(audit) = &self.audit {
+            let entry = AuditEntry::new(workspace, action).by(user).on(format!("identities/{}", identity_id(provider, subject)));
+            audit.log(&entry, None).await;
+        }
+    }
+}
+
+fn flow_id(state: &str) -> String {
+    hex::encode(Sha256::digest(state.as_bytes()))
+}
+
+fn random_bytes(len: usize) -> Vec<u8> {
+    let mut bytes = vec![0u8; len];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    bytes
+}
+
+fn random_hex(len: usize) -> String {
+    hex::encode(random_bytes(len))
+}
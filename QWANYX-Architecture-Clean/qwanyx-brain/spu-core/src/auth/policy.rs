@@ -0,0 +1,475 @@
+//! Access Policies
+//!
+//! Who may read and write what in a workspace. A caller - the user a script
+//! runs for - has the roles of their token in the token's workspace, and no
+//! access at all to other workspaces. Each workspace has one policy:
+//!
+//! - `admin`: roles allowed everything, policy and schemas included
+//! - `collections`: rules per collection, `*` for collections not listed
+//! - `two_factor`: roles that must sign in with a second factor (see
+//!   `crate::auth::two_factor`), `*` for everyone
+//!
+//! A rule lists the roles reading (`read`) or writing (`write`) every document
+//! of a collection, and those limited to the documents they own (`read_own`,
+//! `write_own`): the documents whose `owner` field is their id. `*` stands for
+//! any role. `fields` narrow who sees (`read`) or sets (`write`) single fields.
+//!
+//! ```json
+//! {
+//!   "admin": ["admin"],
+//!   "two_factor": ["admin", "professionnel"],
+//!   "collections": {
+//!     "requests": {
+//!       "read": ["professionnel"],
+//!       "read_own": ["*"],
+//!       "write_own": ["particulier"],
+//!       "fields": { "internal_notes": { "read": ["professionnel"], "write": ["professionnel"] } }
+//!     }
+//!   }
+//! }
+//! ```
+//!
+//! Collections named `spu_*` hold the server's own state and are left to admins.
+//! Workspaces without a policy get `Policy::default()`.
+
+use super::tokens::Claims;
+use crate::store::{DocumentStore, FindOptions, Namespace};
+use mongodb::bson::{doc, oid::ObjectId, Bson, Document};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fmt;
+use thiserror::Error;
+
+/// Collection of each workspace holding its policy, as a single document
+pub const POLICY_COLLECTION: &str = "spu_policy";
+
+/// `_id` of the policy document in `POLICY_COLLECTION`
+pub const POLICY_ID: &str = "policy";
+
+/// Collections holding the server's own state
+pub const INTERNAL_PREFIX: &str = "spu_";
+
+/// Any role, or any collection not named otherwise
+pub const ANY: &str = "*";
+
+/// The user a script runs for
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Caller {
+    pub sub: String,
+    /// Where `roles` apply
+    pub workspace: String,
+    pub roles: Vec<String>,
+}
+
+impl Caller {
+    pub fn new(sub: impl Into<String>, workspace: impl Into<String>, roles: Vec<String>) -> Self {
+        Self { sub: sub.into(), workspace: workspace.into(), roles }
+    }
+
+    /// Whether the caller has one of the roles; `*` matches anyone
+    pub fn has_any(&self, roles: &[String]) -> bool {
+        roles.iter().any(|role| role == ANY || self.roles.contains(role))
+    }
+}
+
+impl From<&Claims> for Caller {
+    fn from(claims: &Claims) -> Self {
+        Self::new(&claims.sub, &claims.workspace, claims.roles.clone())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operation {
+    Read,
+    Write,
+}
+
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Operation::Read => write!(f, "read"),
+            Operation::Write => write!(f, "write"),
+        }
+    }
+}
+
+#[derive(Debug, Error, PartialEq)]
+pub enum PolicyError {
+    #[error("{0}")]
+    Forbidden(String),
+
+    #[error("Invalid policy: {0}")]
+    Invalid(String),
+}
+
+/// Rules of one field
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FieldRules {
+    /// Roles that see the field; anyone reading the document when absent
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub read: Option<Vec<String>>,
+    /// Roles that set it; anyone writing the document when absent
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub write: Option<Vec<String>>,
+}
+
+/// Rules of one collection
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rules {
+    #[serde(default)]
+    pub read: Vec<String>,
+    #[serde(default)]
+    pub read_own: Vec<String>,
+    #[serde(default)]
+    pub write: Vec<String>,
+    #[serde(default)]
+    pub write_own: Vec<String>,
+    /// Field holding the id of a document's owner
+    #[serde(default = "default_owner")]
+    pub owner: String,
+    /// By dotted path
+    #[serde(default)]
+    pub fields: HashMap<String, FieldRules>,
+}
+
+fn default_owner() -> String {
+    "owner".to_string()
+}
+
+impl Default for Rules {
+    fn default() -> Self {
+        Self {
+            read: Vec::new(),
+            read_own: Vec::new(),
+            write: Vec::new(),
+            write_own: Vec::new(),
+            owner: default_owner(),
+            fields: HashMap::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Policy {
+    #[serde(default = "default_admin")]
+    pub admin: Vec<String>,
+    #[serde(default)]
+    pub collections: HashMap<String, Rules>,
+    /// Roles that must sign in with a second factor
+    #[serde(default)]
+    pub two_factor: Vec<String>,
+}
+
+fn default_admin() -> Vec<String> {
+    vec!["admin".to_string(), "superuser".to_string()]
+}
+
+impl Default for Policy {
+    /// Admins do everything; other users read and write the documents they own,
+    /// and their own user but not its roles or account type
+    fn default() -> Self {
+        let anyone = vec![ANY.to_string()];
+        let mut users = Rules {
+            read_own: anyone.clone(),
+            write_own: anyone.clone(),
+            owner: "_id".to_string(),
+            ..Rules::default()
+        };
+        for field in ["role", "roles", "accountType", "workspace"] {
+            users.fields.insert(field.to_string(), FieldRules { read: None, write: Some(Vec::new()) });
+        }
+        let others = Rules {
+            read_own: anyone.clone(),
+            write_own: anyone,
+            ..Rules::default()
+        };
+
+        Self {
+            admin: default_admin(),
+            collections: HashMap::from([("users".to_string(), users), (ANY.to_string(), others)]),
+            two_factor: Vec::new(),
+        }
+    }
+}
+
+impl Policy {
+    pub fn from_json(value: Value) -> Result<Self, PolicyError> {
+        let policy: Policy = serde_json::from_value(value)
+            .map_err(|e| PolicyError::Invalid(e.to_string()))?;
+        for (collection, rules) in &policy.collections {
+            if rules.owner.is_empty() {
+                return Err(PolicyError::Invalid(format!("{}: 'owner' cannot be empty", collection)));
+            }
+            if rules.fields.keys().any(|path| path.is_empty() || path.starts_with('$')) {
+                return Err(PolicyError::Invalid(format!("{}: field paths must be plain dotted paths", collection)));
+            }
+        }
+        Ok(policy)
+    }
+
+    pub fn to_json(&self) -> Value {
+        serde_json::to_value(self).unwrap_or(Value::Null)
+    }
+
+    /// Whether the caller administers the workspace
+    pub fn is_admin(&self, caller: &Caller, workspace: &str) -> bool {
+        caller.workspace == workspace && caller.roles.iter().any(|role| self.admin.contains(role))
+    }
+
+    /// Whether one of `roles` must sign in with a second factor
+    pub fn requires_two_factor(&self, roles: &[String]) -> bool {
+        self.two_factor.iter().any(|role| role == ANY || roles.contains(role))
+    }
+
+    /// Refuse callers that don't administer the workspace
+    pub fn require_admin(&self, caller: &Caller, workspace: &str) -> Result<(), PolicyError> {
+        member(caller, workspace)?;
+        if self.is_admin(caller, workspace) {
+            Ok(())
+        } else {
+            Err(PolicyError::Forbidden(format!("Only admins of {} may do this", workspace)))
+        }
+    }
+
+    /// What the caller may read or write of a collection
+    pub fn grant(&self, caller: &Caller, workspace: &str, collection: &str, operation: Operation) -> Result<Grant, PolicyError> {
+        member(caller, workspace)?;
+        if self.is_admin(caller, workspace) {
+            return Ok(Grant::all());
+        }
+        let refused = || PolicyError::Forbidden(format!("Not allowed to {} {}", operation, collection));
+        if collection.starts_with(INTERNAL_PREFIX) {
+            return Err(refused());
+        }
+        let rules = self.collections.get(collection)
+            .or_else(|| self.collections.get(ANY))
+            .ok_or_else(refused)?;
+
+        let (every, own) = match operation {
+            Operation::Read => (&rules.read, &rules.read_own),
+            Operation::Write => (&rules.write, &rules.write_own),
+        };
+        let access = if caller.has_any(every) {
+            Access::All
+        } else if caller.has_any(own) {
+            Access::Own { field: rules.owner.clone(), owner: caller.sub.clone() }
+        } else {
+            return Err(refused());
+        };
+
+        let mut denied: Vec<String> = rules.fields.iter()
+            .filter(|(_, field)| {
+                let roles = match operation {
+                    Operation::Read => &field.read,
+                    Operation::Write => &field.write,
+                };
+                roles.as_ref().is_some_and(|roles| !caller.has_any(roles))
+            })
+            .map(|(path, _)| path.clone())
+            .collect();
+        denied.sort();
+
+        Ok(Grant { operation, access, denied })
+    }
+}
+
+/// The policy set for a workspace in `store`, if any
+pub async fn stored<S: DocumentStore + ?Sized>(store: &S, workspace: &str) -> Result<Option<Policy>, String> {
+    let ns = Namespace::new(workspace, POLICY_COLLECTION);
+    let found = store.find(ns, doc! { "_id": POLICY_ID }, FindOptions::default()).await
+        .map_err(|e| format!("Failed to load policy: {}", e))?;
+    found.first()
+        .map(|document| {
+            let corrupt = |e: String| format!("Corrupt policy document: {}", e);
+            let json = document.get_str("policy").map_err(|e| corrupt(e.to_string()))?;
+            let value = serde_json::from_str(json).map_err(|e| corrupt(e.to_string()))?;
+            Policy::from_json(value).map_err(|e| corrupt(e.to_string()))
+        })
+        .transpose()
+}
+
+fn member(caller: &Caller, workspace: &str) -> Result<(), PolicyError> {
+    if caller.workspace == workspace {
+        Ok(())
+    } else {
+        Err(PolicyError::Forbidden(format!("Signed in to {}, not {}", caller.workspace, workspace)))
+    }
+}
+
+/// Which documents of a collection a grant covers
+#[derive(Debug, Clone, PartialEq)]
+pub enum Access {
+    All,
+    /// Those whose `field` is `owner`
+    Own { field: String, owner: String },
+}
+
+/// What a caller may do with one collection
+#[derive(Debug, Clone, PartialEq)]
+pub struct Grant {
+    pub operation: Operation,
+    pub access: Access,
+    /// Fields the caller may not see, or not set
+    pub denied: Vec<String>,
+}
+
+impl Grant {
+    /// Everything, for admins and scripts that run for no one
+    pub fn all() -> Self {
+        Self { operation: Operation::Read, access: Access::All, denied: Vec::new() }
+    }
+
+    /// Whether the grant covers every document and field
+    pub fn is_unrestricted(&self) -> bool {
+        self.access == Access::All && self.denied.is_empty()
+    }
+
+    /// Narrow a filter to the documents the grant covers
+    pub fn restrict(&self, filter: Document) -> Document {
+        let Access::Own { field, owner } = &self.access else {
+            return filter;
+        };
+        let owner = owner_value(field, owner);
+        if !filter.contains_key(field) {
+            // At the top, where upserts pick it up
+            let mut filter = filter;
+            filter.insert(field.clone(), owner);
+            return filter;
+        }
+        let mut own = Document::new();
+        own.insert(field.clone(), owner);
+        let mut restricted = Document::new();
+        restricted.insert("$and", vec![Bson::Document(filter), Bson::Document(own)]);
+        restricted
+    }
+
+    /// Refuse a filter or sort on fields the caller may not see
+    pub fn check_filter(&self, filter: &Document) -> Result<(), PolicyError> {
+        if self.denied.is_empty() {
+            return Ok(());
+        }
+        let paths = filter_paths(filter)
+            .ok_or_else(|| PolicyError::Forbidden("Filter cannot use expressions on this collection".to_string()))?;
+        self.check_paths(paths.iter().map(String::as_str))
+    }
+
+    /// Refuse an update setting fields the caller may not set, or giving a
+    /// document away
+    pub fn check_update(&self, update: &Document) -> Result<(), PolicyError> {
+        let paths = update_paths(update);
+        self.check_paths(paths.iter().map(String::as_str))?;
+        if let Access::Own { field, .. } = &self.access {
+            if let Some(path) = paths.iter().find(|path| overlaps(path, field)) {
+                return Err(PolicyError::Forbidden(format!("Not allowed to write {}", path)));
+            }
+        }
+        Ok(())
+    }
+
+    /// Check a document about to be inserted, making the caller its owner when
+    /// they may only write their own documents
+    pub fn admit(&self, document: &mut Document) -> Result<bool, PolicyError> {
+        let mut paths = Vec::new();
+        leaf_paths(document, "", &mut paths);
+        self.check_paths(paths.iter().map(String::as_str))?;
+
+        let Access::Own { field, owner } = &self.access else {
+            return Ok(false);
+        };
+        let owner = owner_value(field, owner);
+        match document.get(field) {
+            None => {
+                document.insert(field.clone(), owner);
+                Ok(true)
+            }
+            Some(value) if *value == owner => Ok(false),
+            Some(_) => Err(PolicyError::Forbidden(format!("Not allowed to write documents owned by others ({})", field))),
+        }
+    }
+
+    /// Remove the fields the caller may not see
+    pub fn hide(&self, document: &mut Document) {
+        for path in &self.denied {
+            crate::store::query::remove_path(document, path);
+        }
+    }
+
+    fn check_paths<'a>(&self, paths: impl IntoIterator<Item = &'a str>) -> Result<(), PolicyError> {
+        for path in paths {
+            if let Some(denied) = self.denied.iter().find(|denied| overlaps(path, denied)) {
+                return Err(PolicyError::Forbidden(format!("Not allowed to {} {}", self.operation, denied)));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The owner as stored: user ids in `_id` are ObjectIds when they look like one
+fn owner_value(field: &str, owner: &str) -> Bson {
+    match ObjectId::parse_str(owner) {
+        Ok(oid) if field == "_id" => Bson::ObjectId(oid),
+        _ => Bson::String(owner.to_string()),
+    }
+}
+
+/// Whether one path is the other or lies inside it
+fn overlaps(a: &str, b: &str) -> bool {
+    let inside = |inner: &str, outer: &str| inner.len() > outer.len()
+        && inner.starts_with(outer)
+        && inner.as_bytes()[outer.len()] == b'.';
+    a == b || inside(a, b) || inside(b, a)
+}
+
+/// Fields a filter looks at; none when it uses expressions such as `$expr`
+/// or `$where`, which may look at any
+fn filter_paths(filter: &Document) -> Option<Vec<String>> {
+    let mut paths = Vec::new();
+    for (key, value) in filter {
+        match key.as_str() {
+            "$and" | "$or" | "$nor" => {
+                let Bson::Array(clauses) = value else { continue };
+                for clause in clauses {
+                    if let Bson::Document(clause) = clause {
+                        paths.extend(filter_paths(clause)?);
+                    }
+                }
+            }
+            "$comment" => {}
+            operator if operator.starts_with('$') => return None,
+            field => paths.push(field.to_string()),
+        }
+    }
+    Some(paths)
+}
+
+/// Fields an update document sets or removes
+fn update_paths(update: &Document) -> Vec<String> {
+    let mut paths = Vec::new();
+    for (operator, fields) in update {
+        let Bson::Document(fields) = fields else {
+            paths.push(operator.clone());
+            continue;
+        };
+        for (field, value) in fields {
+            paths.push(field.clone());
+            if let ("$rename", Bson::String(to)) = (operator.as_str(), value) {
+                paths.push(to.clone());
+            }
+        }
+    }
+    paths
+}
+
+fn leaf_paths(document: &Document, prefix: &str, paths: &mut Vec<String>) {
+    for (key, value) in document {
+        let path = if prefix.is_empty() { key.clone() } else { format!("{}.{}", prefix, key) };
+        match value {
+            Bson::Document(inner) if !inner.is_empty() => leaf_paths(inner, &path, paths),
+            _ => paths.push(path),
+        }
+    }
+}
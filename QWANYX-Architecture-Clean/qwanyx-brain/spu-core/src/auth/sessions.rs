@@ -0,0 +1,567 @@
+//! Sessions and refresh tokens
+//!
+//! Signing in starts a session: a short-lived access token plus a refresh token
+//! that gets new access tokens until the session expires or is revoked. Only a
+//! hash of the refresh token is kept, and every refresh rotates it. Presenting
+//! the token a refresh replaced means it was copied, so the session is revoked.
+//!
+//! Access tokens carry their session id as `sid`. Logging out revokes the
+//! session and puts its id and the token's `jti` on a revocation list, which
+//! the auth middleware checks; entries expire with the last access token they
+//! could concern.
+
+use super::{bson_date, chrono_date};
+use super::tokens::{Claims, TokenError, Tokens};
+use crate::workspaces::WorkspaceDatabases;
+use async_trait::async_trait;
+use chrono::{DateTime, Duration, Utc};
+use mongodb::{Collection, bson::{doc, Bson, Document}};
+use rand::RngCore;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::Arc;
+use thiserror::Error;
+use tokio::sync::RwLock;
+use tracing::{info, warn};
+
+/// A signed-in device
+#[derive(Debug, Clone, PartialEq)]
+pub struct Session {
+    pub id: String,
+    pub workspace: String,
+    /// Whom the session's access tokens are for
+    pub sub: String,
+    pub email: Option<String>,
+    pub roles: Vec<String>,
+    /// Hex SHA-256 of the current refresh token
+    pub refresh_hash: String,
+    /// Hash of the refresh token the last refresh replaced
+    pub previous_hash: Option<String>,
+    /// User agent of the client
+    pub device: Option<String>,
+    /// Address of the last sign-in or refresh
+    pub ip: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub last_used_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+    pub revoked_at: Option<DateTime<Utc>>,
+}
+
+impl Session {
+    pub fn is_active(&self, now: DateTime<Utc>) -> bool {
+        self.revoked_at.is_none() && self.expires_at > now
+    }
+
+    /// As listed to its user, without hashes
+    pub fn to_json(&self) -> Value {
+        json!({
+            "id": self.id,
+            "device": self.device,
+            "ip": self.ip,
+            "created_at": self.created_at.to_rfc3339(),
+            "last_used_at": self.last_used_at.to_rfc3339(),
+            "expires_at": self.expires_at.to_rfc3339(),
+        })
+    }
+}
+
+/// Limits on sessions
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SessionPolicy {
+    /// How long a session lasts from sign-in, however often it is refreshed
+    pub refresh_ttl: Duration,
+}
+
+impl Default for SessionPolicy {
+    fn default() -> Self {
+        Self { refresh_ttl: Duration::days(30) }
+    }
+}
+
+impl SessionPolicy {
+    /// The defaults, overridden by `AUTH_SESSION_DAYS`
+    pub fn from_env() -> Self {
+        match std::env::var("AUTH_SESSION_DAYS").ok().and_then(|v| v.parse::<i64>().ok()) {
+            Some(days) if days > 0 => Self { refresh_ttl: Duration::days(days) },
+            _ => Self::default(),
+        }
+    }
+}
+
+/// A signed-in client's tokens
+#[derive(Debug, Clone, PartialEq)]
+pub struct Grant {
+    pub access_token: String,
+    pub claims: Claims,
+    pub refresh_token: String,
+    pub session: Session,
+}
+
+/// Why a session was not started, refreshed or accepted
+#[derive(Debug, Error, PartialEq)]
+pub enum SessionError {
+    /// Also when there is no such session, so as not to tell which exist
+    #[error("Invalid refresh token")]
+    Invalid,
+
+    #[error("Session expired, sign in again")]
+    Expired,
+
+    #[error("Session revoked, sign in again")]
+    Revoked,
+
+    #[error("{0}")]
+    Token(#[from] TokenError),
+
+    #[error("Storage error: {0}")]
+    Storage(String),
+}
+
+impl From<String> for SessionError {
+    fn from(e: String) -> Self {
+        SessionError::Storage(e)
+    }
+}
+
+// ================================================================================
+// STORAGE
+// ================================================================================
+
+/// Persistence for sessions and the revocation list
+#[async_trait]
+pub trait SessionStore: Send + Sync {
+    async fn insert(&self, session: &Session) -> Result<(), String>;
+
+    async fn load(&self, workspace: &str, id: &str) -> Result<Option<Session>, String>;
+
+    /// Replace the refresh hash if it is still `old_hash`; false if another
+    /// refresh got there first
+    async fn rotate(&self, workspace: &str, id: &str, old_hash: &str, new_hash: &str, used_at: DateTime<Utc>, ip: Option<&str>) -> Result<bool, String>;
+
+    /// Mark a session revoked; false if it is missing or already revoked
+    async fn revoke(&self, workspace: &str, id: &str, at: DateTime<Utc>) -> Result<bool, String>;
+
+    /// Revoke every session of `sub`; returns the ids revoked
+    async fn revoke_all(&self, workspace: &str, sub: &str, at: DateTime<Utc>) -> Result<Vec<String>, String>;
+
+    /// Sessions of `sub` not revoked, expired ones included
+    async fn list(&self, workspace: &str, sub: &str) -> Result<Vec<Session>, String>;
+
+    /// Put a token or session id on the revocation list until `expires_at`
+    async fn deny(&self, workspace: &str, key: &str, expires_at: DateTime<Utc>) -> Result<(), String>;
+
+    /// Whether any of `keys` is on the revocation list
+    async fn is_denied(&self, workspace: &str, keys: &[&str]) -> Result<bool, String>;
+}
+
+/// In-memory store, for tests and local development
+#[derive(Default)]
+pub struct MemorySessionStore {
+    sessions: RwLock<HashMap<(String, String), Session>>,
+    denied: RwLock<HashMap<(String, String), DateTime<Utc>>>,
+}
+
+impl MemorySessionStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[async_trait]
+impl SessionStore for MemorySessionStore {
+    async fn insert(&self, session: &Session) -> Result<(), String> {
+        let mut sessions = self.sessions.write().await;
+        sessions.insert((session.workspace.clone(), session.id.clone()), session.clone());
+        Ok(())
+    }
+
+    async fn load(&self, workspace: &str, id: &str) -> Result<Option<Session>, String> {
+        let sessions = self.sessions.read().await;
+        Ok(sessions.get(&(workspace.to_string(), id.to_string())).cloned())
+    }
+
+    async fn rotate(&self, workspace: &str, id: &str, old_hash: &str, new_hash: &str, used_at: DateTime<Utc>, ip: Option<&str>) -> Result<bool, String> {
+        let mut sessions = self.sessions.write().await;
+        match sessions.get_mut(&(workspace.to_string(), id.to_string())) {
+            Some(session) if session.refresh_hash == old_hash && session.revoked_at.is_none() => {
+                session.previous_hash = Some(std::mem::replace(&mut session.refresh_hash, new_hash.to_string()));
+                session.last_used_at = used_at;
+                if let Some(ip) = ip {
+                    session.ip = Some(ip.to_string());
+                }
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    async fn revoke(&self, workspace: &str, id: &str, at: DateTime<Utc>) -> Result<bool, String> {
+        let mut sessions = self.sessions.write().await;
+        match sessions.get_mut(&(workspace.to_string(), id.to_string())) {
+            Some(session) if session.revoked_at.is_none() => {
+                session.revoked_at = Some(at);
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    async fn revoke_all(&self, workspace: &str, sub: &str, at: DateTime<Utc>) -> Result<Vec<String>, String> {
+        let mut sessions = self.sessions.write().await;
+        Ok(sessions.values_mut()
+            .filter(|s| s.workspace == workspace && s.sub == sub && s.revoked_at.is_none())
+            .map(|s| {
+                s.revoked_at = Some(at);
+                s.id.clone()
+            })
+            .collect())
+    }
+
+    async fn list(&self, workspace: &str, sub: &str) -> Result<Vec<Session>, String> {
+        let sessions = self.sessions.read().await;
+        let mut listed: Vec<Session> = sessions.values()
+            .filter(|s| s.workspace == workspace && s.sub == sub && s.revoked_at.is_none())
+            .cloned()
+            .collect();
+        listed.sort_by_key(|s| std::cmp::Reverse(s.last_used_at));
+        Ok(listed)
+    }
+
+    async fn deny(&self, workspace: &str, key: &str, expires_at: DateTime<Utc>) -> Result<(), String> {
+        let mut denied = self.denied.write().await;
+        let now = Utc::now();
+        denied.retain(|_, until| *until > now);
+        denied.insert((workspace.to_string(), key.to_string()), expires_at);
+        Ok(())
+    }
+
+    async fn is_denied(&self, workspace: &str, keys: &[&str]) -> Result<bool, String> {
+        let denied = self.denied.read().await;
+        let now = Utc::now();
+        Ok(keys.iter().any(|key| {
+            denied.get(&(workspace.to_string(), key.to_string())).is_some_and(|until| *until > now)
+        }))
+    }
+}
+
+/// MongoDB store
+///
+/// Sessions live in the workspace's own database, in `spu_sessions` keyed by
+/// id, and the revocation list in `spu_revoked` keyed by token or session id.
+/// Both carry an `expires_at` date, for the TTL indexes of `create_indexes`
+/// to clean up.
+pub struct MongoSessionStore {
+    databases: WorkspaceDatabases,
+}
+
+impl MongoSessionStore {
+    pub fn new(databases: WorkspaceDatabases) -> Self {
+        Self { databases }
+    }
+
+    /// Have expired sessions, and revocations of tokens expired anyway, deleted
+    /// in every workspace
+    pub async fn create_indexes(&self) -> Result<(), String> {
+        self.databases.expire_at("spu_sessions", "expires_at").await?;
+        self.databases.expire_at("spu_revoked", "expires_at").await
+    }
+
+    fn sessions(&self, workspace: &str) -> Result<Collection<Document>, String> {
+        Ok(self.databases.database(workspace)?.collection("spu_sessions"))
+    }
+
+    fn revoked(&self, workspace: &str) -> Result<Collection<Document>, String> {
+        Ok(self.databases.database(workspace)?.collection("spu_revoked"))
+    }
+
+    fn to_document(session: &Session) -> Document {
+        let optional = |value: &Option<String>| value.clone().map(Bson::String).unwrap_or(Bson::Null);
+        doc! {
+            "_id": &session.id,
+            "sub": &session.sub,
+            "email": optional(&session.email),
+            "roles": &session.roles,
+            "refresh_hash": &session.refresh_hash,
+            "previous_hash": optional(&session.previous_hash),
+            "device": optional(&session.device),
+            "ip": optional(&session.ip),
+            "created_at": bson_date(session.created_at),
+            "last_used_at": bson_date(session.last_used_at),
+            "expires_at": bson_date(session.expires_at),
+            "revoked_at": session.revoked_at.map(|at| Bson::DateTime(bson_date(at))).unwrap_or(Bson::Null),
+        }
+    }
+
+    fn from_document(workspace: &str, document: &Document) -> Result<Session, String> {
+        let corrupt = |e: mongodb::bson::document::ValueAccessError| format!("Corrupt session document: {}", e);
+        let optional = |name: &str| document.get_str(name).ok().map(str::to_string);
+        Ok(Session {
+            id: document.get_str("_id").map_err(corrupt)?.to_string(),
+            workspace: workspace.to_string(),
+            sub: document.get_str("sub").map_err(corrupt)?.to_string(),
+            email: optional("email"),
+            roles: document.get_array("roles").map_err(corrupt)?
+                .iter()
+                .filter_map(|role| role.as_str().map(str::to_string))
+                .collect(),
+            refresh_hash: document.get_str("refresh_hash").map_err(corrupt)?.to_string(),
+            previous_hash: optional("previous_hash"),
+            device: optional("device"),
+            ip: optional("ip"),
+            created_at: chrono_date(document.get_datetime("created_at").map_err(corrupt)?),
+            last_used_at: chrono_date(document.get_datetime("last_used_at").map_err(corrupt)?),
+            expires_at: chrono_date(document.get_datetime("expires_at").map_err(corrupt)?),
+            revoked_at: document.get_datetime("revoked_at").ok().map(chrono_date),
+        })
+    }
+}
+
+#[async_trait]
+impl SessionStore for MongoSessionStore {
+    async fn insert(&self, session: &Session) -> Result<(), String> {
+        self.sessions(&session.workspace)?.insert_one(Self::to_document(session), None).await
+            .map_err(|e| format!("Failed to save session: {}", e))?;
+        Ok(())
+    }
+
+    async fn load(&self, workspace: &str, id: &str) -> Result<Option<Session>, String> {
+        let document = self.sessions(workspace)?.find_one(doc! { "_id": id }, None).await
+            .map_err(|e| format!("Failed to load session: {}", e))?;
+        document.map(|document| Self::from_document(workspace, &document)).transpose()
+    }
+
+    async fn rotate(&self, workspace: &str, id: &str, old_hash: &str, new_hash: &str, used_at: DateTime<Utc>, ip: Option<&str>) -> Result<bool, String> {
+        let mut set = doc! {
+            "refresh_hash": new_hash,
+            "previous_hash": old_hash,
+            "last_used_at": bson_date(used_at),
+        };
+        if let Some(ip) = ip {
+            set.insert("ip", ip);
+        }
+        let result = self.sessions(workspace)?
+            .update_one(doc! { "_id": id, "refresh_hash": old_hash, "revoked_at": Bson::Null }, doc! { "$set": set }, None).await
+            .map_err(|e| format!("Failed to rotate session: {}", e))?;
+        Ok(result.modified_count == 1)
+    }
+
+    async fn revoke(&self, workspace: &str, id: &str, at: DateTime<Utc>) -> Result<bool, String> {
+        let result = self.sessions(workspace)?
+            .update_one(doc! { "_id": id, "revoked_at": Bson::Null }, doc! { "$set": { "revoked_at": bson_date(at) } }, None).await
+            .map_err(|e| format!("Failed to revoke session: {}", e))?;
+        Ok(result.modified_count == 1)
+    }
+
+    async fn revoke_all(&self, workspace: &str, sub: &str, at: DateTime<Utc>) -> Result<Vec<String>, String> {
+        let ids: Vec<String> = self.list(workspace, sub).await?.into_iter().map(|s| s.id).collect();
+        self.sessions(workspace)?
+            .update_many(doc! { "_id": { "$in": &ids }, "revoked_at": Bson::Null }, doc! { "$set": { "revoked_at": bson_date(at) } }, None).await
+            .map_err(|e| format!("Failed to revoke sessions: {}", e))?;
+        Ok(ids)
+    }
+
+    async fn list(&self, workspace: &str, sub: &str) -> Result<Vec<Session>, String> {
+        use futures::TryStreamExt;
+        let options = mongodb::options::FindOptions::builder().sort(doc! { "last_used_at": -1 }).build();
+        let documents: Vec<Document> = self.sessions(workspace)?
+            .find(doc! { "sub": sub, "revoked_at": Bson::Null }, options).await
+            .map_err(|e| format!("Failed to list sessions: {}", e))?
+            .try_collect().await
+            .map_err(|e| format!("Failed to list sessions: {}", e))?;
+        documents.iter().map(|document| Self::from_document(workspace, document)).collect()
+    }
+
+    async fn deny(&self, workspace: &str, key: &str, expires_at: DateTime<Utc>) -> Result<(), String> {
+        let options = mongodb::options::UpdateOptions::builder().upsert(true).build();
+        self.revoked(workspace)?
+            .update_one(doc! { "_id": key }, doc! { "$max": { "expires_at": bson_date(expires_at) } }, options).await
+            .map_err(|e| format!("Failed to revoke token: {}", e))?;
+        Ok(())
+    }
+
+    async fn is_denied(&self, workspace: &str, keys: &[&str]) -> Result<bool, String> {
+        let filter = doc! { "_id": { "$in": keys }, "expires_at": { "$gt": bson_date(Utc::now()) } };
+        let count = self.revoked(workspace)?.count_documents(filter, None).await
+            .map_err(|e| format!("Failed to check revocations: {}", e))?;
+        Ok(count > 0)
+    }
+}
+
+// ================================================================================
+// SESSIONS
+// ================================================================================
+
+/// Starts, refreshes and ends sessions, and checks access tokens against them
+pub struct Sessions {
+    store: Arc<dyn SessionStore>,
+    tokens: Arc<Tokens>,
+    policy: SessionPolicy,
+}
+
+impl Sessions {
+    pub fn new(store: Arc<dyn SessionStore>, tokens: Arc<Tokens>) -> Self {
+        Self { store, tokens, policy: SessionPolicy::default() }
+    }
+
+    pub fn with_policy(mut self, policy: SessionPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    pub fn store(&self) -> Arc<dyn SessionStore> {
+        self.store.clone()
+    }
+
+    pub fn tokens(&self) -> &Arc<Tokens> {
+        &self.tokens
+    }
+
+    pub fn policy(&self) -> &SessionPolicy {
+        &self.policy
+    }
+
+    /// Start a session for the holder of `claims`, from `device` at `ip` when known
+    pub async fn start(&self, mut claims: Claims, device: Option<&str>, ip: Option<&str>) -> Result<Grant, SessionError> {
+        let now = Utc::now();
+        let id = uuid::Uuid::new_v4().to_string();
+        let refresh_token = format!("{}.{}", id, random_secret());
+        let session = Session {
+            id: id.clone(),
+            workspace: claims.workspace.clone(),
+            sub: claims.sub.clone(),
+            email: claims.email.clone(),
+            roles: claims.roles.clone(),
+            refresh_hash: hash(&refresh_token),
+            previous_hash: None,
+            device: device.map(str::to_string),
+            ip: ip.map(str::to_string),
+            created_at: now,
+            last_used_at: now,
+            expires_at: now + self.policy.refresh_ttl,
+            revoked_at: None,
+        };
+        self.store.insert(&session).await?;
+
+        claims.sid = Some(id);
+        let access_token = self.tokens.sign(&claims)?;
+        info!("Started session {} for {} in {}", session.id, session.sub, session.workspace);
+        Ok(Grant { access_token, claims, refresh_token, session })
+    }
+
+    /// A new access token and refresh token for `refresh_token`, which stops working
+    pub async fn refresh(&self, workspace: &str, refresh_token: &str, ip: Option<&str>) -> Result<Grant, SessionError> {
+        let id = refresh_token.split_once('.').map(|(id, _)| id).ok_or(SessionError::Invalid)?;
+        let mut session = self.store.load(workspace, id).await?.ok_or(SessionError::Invalid)?;
+        let now = Utc::now();
+        if session.revoked_at.is_some() {
+            return Err(SessionError::Revoked);
+        }
+        if session.expires_at <= now {
+            return Err(SessionError::Expired);
+        }
+
+        let presented = hash(refresh_token);
+        if session.previous_hash.as_deref() == Some(presented.as_str()) {
+            // Someone else has the token that replaced this one
+            warn!("Refresh token of session {} in {} used twice, revoking it", id, workspace);
+            self.revoke(workspace, id).await?;
+            return Err(SessionError::Revoked);
+        }
+        if session.refresh_hash != presented {
+            return Err(SessionError::Invalid);
+        }
+
+        let refresh_token = format!("{}.{}", id, random_secret());
+        let refresh_hash = hash(&refresh_token);
+        if !self.store.rotate(workspace, id, &presented, &refresh_hash, now, ip).await? {
+            return Err(SessionError::Invalid);
+        }
+        session.previous_hash = Some(std::mem::replace(&mut session.refresh_hash, refresh_hash));
+        session.last_used_at = now;
+        if let Some(ip) = ip {
+            session.ip = Some(ip.to_string());
+        }
+
+        let mut claims = self.tokens.claims(&session.sub, workspace);
+        claims.email = session.email.clone();
+        claims.roles = session.roles.clone();
+        claims.sid = Some(session.id.clone());
+        let access_token = self.tokens.sign(&claims)?;
+        Ok(Grant { access_token, claims, refresh_token, session })
+    }
+
+    /// Verify an access token, refusing those of ended sessions
+    pub async fn authenticate(&self, token: &str) -> Result<Claims, SessionError> {
+        let claims = self.tokens.verify(token)?;
+        let mut keys = vec![claims.jti.as_str()];
+        keys.extend(claims.sid.as_deref());
+        if self.store.is_denied(&claims.workspace, &keys).await? {
+            return Err(SessionError::Token(TokenError::Revoked));
+        }
+        Ok(claims)
+    }
+
+    /// End the session of `claims` and revoke the token itself; false if the
+    /// token had no session or it had already ended
+    pub async fn logout(&self, claims: &Claims) -> Result<bool, SessionError> {
+        let until = self.access_expiry(claims);
+        self.store.deny(&claims.workspace, &claims.jti, until).await?;
+        let ended = match &claims.sid {
+            Some(sid) => self.revoke(&claims.workspace, sid).await?,
+            None => false,
+        };
+        info!("Logged out {} in {}", claims.sub, claims.workspace);
+        Ok(ended)
+    }
+
+    /// End every session of the holder of `claims`; returns how many ended
+    pub async fn logout_all(&self, claims: &Claims) -> Result<usize, SessionError> {
+        let now = Utc::now();
+        let until = self.access_expiry(claims);
+        self.store.deny(&claims.workspace, &claims.jti, until).await?;
+        let ids = self.store.revoke_all(&claims.workspace, &claims.sub, now).await?;
+        for id in &ids {
+            self.store.deny(&claims.workspace, id, now + self.tokens.ttl()).await?;
+        }
+        info!("Logged out {} sessions of {} in {}", ids.len(), claims.sub, claims.workspace);
+        Ok(ids.len())
+    }
+
+    /// End one session of `sub`; false if it is not theirs or already ended
+    pub async fn revoke_for(&self, workspace: &str, sub: &str, id: &str) -> Result<bool, SessionError> {
+        match self.store.load(workspace, id).await? {
+            Some(session) if session.sub == sub => self.revoke(workspace, id).await,
+            _ => Ok(false),
+        }
+    }
+
+    /// Active sessions of `sub`, most recently used first
+    pub async fn list(&self, workspace: &str, sub: &str) -> Result<Vec<Session>, SessionError> {
+        let now = Utc::now();
+        let sessions = self.store.list(workspace, sub).await?;
+        Ok(sessions.into_iter().filter(|s| s.is_active(now)).collect())
+    }
+
+    async fn revoke(&self, workspace: &str, id: &str) -> Result<bool, SessionError> {
+        let now = Utc::now();
+        let revoked = self.store.revoke(workspace, id, now).await?;
+        // Access tokens already out live at most one more lifetime
+        self.store.deny(workspace, id, now + self.tokens.ttl()).await?;
+        Ok(revoked)
+    }
+
+    fn access_expiry(&self, claims: &Claims) -> DateTime<Utc> {
+        DateTime::from_timestamp(claims.exp, 0).unwrap_or_else(|| Utc::now() + self.tokens.ttl())
+    }
+}
+
+fn random_secret() -> String {
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    hex::encode(bytes)
+}
+
+fn hash(refresh_token: &str) -> String {
+    hex::encode(Sha256::digest(refresh_token.as_bytes()))
+}
@@ -0,0 +1,415 @@
+//! Signed access tokens
+//!
+//! JWTs signed with HS256 or RS256, carrying `sub`, `workspace`, `roles`, `exp`,
+//! `iat` and `jti`. Keys are kept in a `KeySet`, each under a `kid` written in
+//! the token header: one key signs, the others only verify. Rotating is adding
+//! the new key, making it active, and dropping the old one once the tokens it
+//! signed have expired.
+//!
+//! The public half of RSA keys is published as a JWKS so that other services
+//! can verify tokens on their own; HMAC secrets never leave the server.
+//!
+//! A key set file, named by `JWT_KEYS_FILE`, looks like:
+//!
+//! ```json
+//! {
+//!   "active": "2026-10",
+//!   "keys": [
+//!     { "kid": "2026-10", "alg": "RS256", "private_key_file": "keys/2026-10.pem" },
+//!     { "kid": "2026-04", "alg": "RS256", "n": "0vx7...", "e": "AQAB" },
+//!     { "kid": "legacy", "alg": "HS256", "secret": "..." }
+//!   ]
+//! }
+//! ```
+//!
+//! Without a key set file, tokens are signed with `JWT_SECRET_KEY`, which also
+//! keys the hashes of login codes and magic links. The server refuses to start
+//! without it, unless `JWT_ALLOW_DEV_SECRET=true` lets it use a public
+//! development secret.
+
+use crate::Data;
+use base64::{engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD}, Engine};
+use chrono::{Duration, Utc};
+use jsonwebtoken::{errors::ErrorKind, Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use ring::rsa::{KeyPair as RsaKeyPair, PublicKeyComponents};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use thiserror::Error;
+use tracing::warn;
+
+/// Secret used without `JWT_SECRET_KEY` when `JWT_ALLOW_DEV_SECRET=true`; anyone
+/// can sign tokens with it
+pub const DEVELOPMENT_SECRET: &str = "qwanyx-secret-key-change-this-in-production";
+
+/// The server's secret, from `JWT_SECRET_KEY`
+///
+/// Missing, it is an error, unless `JWT_ALLOW_DEV_SECRET=true` allows the
+/// development secret.
+pub fn secret_from_env() -> Result<String, TokenError> {
+    match std::env::var("JWT_SECRET_KEY") {
+        Ok(secret) if !secret.is_empty() => Ok(secret),
+        _ if std::env::var("JWT_ALLOW_DEV_SECRET").is_ok_and(|v| v == "true") => {
+            warn!("JWT_SECRET_KEY is not set, using the development secret");
+            Ok(DEVELOPMENT_SECRET.to_string())
+        }
+        _ => Err(TokenError::Key(
+            "JWT_SECRET_KEY is not set; set JWT_ALLOW_DEV_SECRET=true to use the development secret".to_string(),
+        )),
+    }
+}
+
+/// What a token says about its holder
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Claims {
+    /// The user id, or the email of users known by email only
+    pub sub: String,
+    pub workspace: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub email: Option<String>,
+    #[serde(default)]
+    pub roles: Vec<String>,
+    pub exp: i64,
+    pub iat: i64,
+    /// Unique per token
+    pub jti: String,
+    /// The session the token was issued for, if any
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sid: Option<String>,
+}
+
+impl Claims {
+    /// The claims as scripts see them in `$auth`
+    pub fn to_data(&self) -> Data {
+        Data::from_json(serde_json::to_value(self).unwrap_or(Value::Null))
+    }
+}
+
+/// Why a token was not issued or not accepted
+#[derive(Debug, Error, PartialEq)]
+pub enum TokenError {
+    #[error("Missing token")]
+    Missing,
+
+    #[error("Token expired")]
+    Expired,
+
+    #[error("Token revoked")]
+    Revoked,
+
+    #[error("Invalid token: {0}")]
+    Invalid(String),
+
+    /// A key or key set that cannot be used
+    #[error("Invalid key: {0}")]
+    Key(String),
+}
+
+// ================================================================================
+// KEYS
+// ================================================================================
+
+/// One signing or verification key
+pub struct TokenKey {
+    kid: String,
+    algorithm: Algorithm,
+    /// None for keys that only verify
+    encoding: Option<EncodingKey>,
+    decoding: DecodingKey,
+    /// Modulus and exponent of RSA keys, for the JWKS
+    rsa: Option<(Vec<u8>, Vec<u8>)>,
+}
+
+impl TokenKey {
+    /// An HMAC key
+    pub fn hs256(kid: &str, secret: &[u8]) -> Self {
+        Self {
+            kid: kid.to_string(),
+            algorithm: Algorithm::HS256,
+            encoding: Some(EncodingKey::from_secret(secret)),
+            decoding: DecodingKey::from_secret(secret),
+            rsa: None,
+        }
+    }
+
+    /// An RSA key from its PEM private key, PKCS#8 or PKCS#1
+    pub fn rs256(kid: &str, private_pem: &str) -> Result<Self, TokenError> {
+        let der = pem_der(private_pem).map_err(|e| key_error(kid, e))?;
+        let pair = if private_pem.contains("BEGIN RSA PRIVATE KEY") {
+            RsaKeyPair::from_der(&der)
+        } else {
+            RsaKeyPair::from_pkcs8(&der)
+        }.map_err(|e| key_error(kid, e))?;
+        let public = PublicKeyComponents::<Vec<u8>>::from(pair.public());
+        let encoding = EncodingKey::from_rsa_pem(private_pem.as_bytes()).map_err(|e| key_error(kid, e))?;
+
+        Ok(Self {
+            kid: kid.to_string(),
+            algorithm: Algorithm::RS256,
+            encoding: Some(encoding),
+            decoding: DecodingKey::from_rsa_raw_components(&public.n, &public.e),
+            rsa: Some((public.n, public.e)),
+        })
+    }
+
+    /// An RSA public key, as base64url `n` and `e` the way a JWKS has them;
+    /// it only verifies
+    pub fn rs256_public(kid: &str, n: &str, e: &str) -> Result<Self, TokenError> {
+        let n = URL_SAFE_NO_PAD.decode(n).map_err(|e| key_error(kid, format!("'n': {}", e)))?;
+        let e = URL_SAFE_NO_PAD.decode(e).map_err(|e| key_error(kid, format!("'e': {}", e)))?;
+        Ok(Self {
+            kid: kid.to_string(),
+            algorithm: Algorithm::RS256,
+            encoding: None,
+            decoding: DecodingKey::from_rsa_raw_components(&n, &e),
+            rsa: Some((n, e)),
+        })
+    }
+
+    /// A key as written in a key set file
+    pub fn from_json(value: &Value) -> Result<Self, TokenError> {
+        let field = |name: &str| value.get(name).and_then(Value::as_str);
+        let kid = field("kid").ok_or_else(|| TokenError::Key("every key needs a 'kid'".to_string()))?;
+
+        match field("alg") {
+            Some("HS256") => match field("secret") {
+                Some(secret) if !secret.is_empty() => Ok(Self::hs256(kid, secret.as_bytes())),
+                _ => Err(key_error(kid, "HS256 keys need a 'secret'")),
+            },
+            Some("RS256") => {
+                let pem = match (field("private_key"), field("private_key_file")) {
+                    (Some(pem), _) => Some(pem.to_string()),
+                    (None, Some(path)) => Some(std::fs::read_to_string(path)
+                        .map_err(|e| key_error(kid, format!("cannot read {}: {}", path, e)))?),
+                    (None, None) => None,
+                };
+                match (pem, field("n"), field("e")) {
+                    (Some(pem), _, _) => Self::rs256(kid, &pem),
+                    (None, Some(n), Some(e)) => Self::rs256_public(kid, n, e),
+                    _ => Err(key_error(kid, "RS256 keys need a private key or 'n' and 'e'")),
+                }
+            }
+            Some(other) => Err(key_error(kid, format!("unsupported algorithm '{}'", other))),
+            None => Err(key_error(kid, "missing 'alg'")),
+        }
+    }
+
+    pub fn kid(&self) -> &str {
+        &self.kid
+    }
+
+    pub fn algorithm(&self) -> Algorithm {
+        self.algorithm
+    }
+
+    /// Whether this key can sign, not only verify
+    pub fn can_sign(&self) -> bool {
+        self.encoding.is_some()
+    }
+
+    pub(crate) fn decoding(&self) -> &DecodingKey {
+        &self.decoding
+    }
+
+    /// The public JWK of RSA keys; None for HMAC keys
+    pub fn jwk(&self) -> Option<Value> {
+        self.rsa.as_ref().map(|(n, e)| json!({
+            "kty": "RSA",
+            "use": "sig",
+            "alg": "RS256",
+            "kid": self.kid,
+            "n": URL_SAFE_NO_PAD.encode(n),
+            "e": URL_SAFE_NO_PAD.encode(e),
+        }))
+    }
+}
+
+/// Keys by `kid`, one of them signing
+pub struct KeySet {
+    keys: Vec<TokenKey>,
+    active: String,
+}
+
+impl KeySet {
+    /// A set signing with `active`
+    pub fn new(active: TokenKey) -> Self {
+        Self { active: active.kid.clone(), keys: vec![active] }
+    }
+
+    /// Also accept tokens signed with `key`, replacing any other key of the
+    /// same `kid`; the active key is never replaced
+    pub fn with_key(mut self, key: TokenKey) -> Self {
+        if key.kid != self.active {
+            self.keys.retain(|k| k.kid != key.kid);
+            self.keys.push(key);
+        }
+        self
+    }
+
+    /// A key set file's contents; without `active`, the first key that can sign signs
+    pub fn from_json(value: &Value) -> Result<Self, TokenError> {
+        let keys = value.get("keys").and_then(Value::as_array)
+            .ok_or_else(|| TokenError::Key("the key set needs a 'keys' array".to_string()))?
+            .iter()
+            .map(TokenKey::from_json)
+            .collect::<Result<Vec<_>, _>>()?;
+        for (i, key) in keys.iter().enumerate() {
+            if keys[..i].iter().any(|k| k.kid == key.kid) {
+                return Err(key_error(&key.kid, "listed twice"));
+            }
+        }
+
+        let active = match value.get("active").and_then(Value::as_str) {
+            Some(kid) => keys.iter().find(|k| k.kid == kid)
+                .ok_or_else(|| key_error(kid, "the active key is not in the set"))?,
+            None => keys.iter().find(|k| k.can_sign())
+                .ok_or_else(|| TokenError::Key("no key in the set can sign".to_string()))?,
+        };
+        if !active.can_sign() {
+            return Err(key_error(&active.kid, "the active key has no private key"));
+        }
+        let active = active.kid.clone();
+        Ok(Self { keys, active })
+    }
+
+    pub fn get(&self, kid: &str) -> Option<&TokenKey> {
+        self.keys.iter().find(|k| k.kid == kid)
+    }
+
+    /// The key new tokens are signed with
+    pub fn active(&self) -> &TokenKey {
+        self.get(&self.active).expect("the active key is in the set")
+    }
+
+    pub fn kids(&self) -> Vec<&str> {
+        self.keys.iter().map(|k| k.kid.as_str()).collect()
+    }
+
+    /// The JWKs of the RS256 keys; HMAC keys have nothing to publish
+    pub fn public_keys(&self) -> Vec<Value> {
+        self.keys.iter().filter_map(TokenKey::jwk).collect()
+    }
+
+    /// The public keys, as served at `/.well-known/jwks.json`
+    pub fn jwks(&self) -> Value {
+        json!({ "keys": self.public_keys() })
+    }
+}
+
+// ================================================================================
+// TOKENS
+// ================================================================================
+
+/// Issues and verifies access tokens
+pub struct Tokens {
+    keys: KeySet,
+    ttl: Duration,
+}
+
+impl Tokens {
+    pub fn new(keys: KeySet) -> Self {
+        Self { keys, ttl: Duration::minutes(15) }
+    }
+
+    /// How long tokens stay valid; short, as sessions refresh them
+    pub fn with_ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    /// Keys from the file named by `JWT_KEYS_FILE`, or else one HS256 key from
+    /// `secret_from_env`; lifetime from `JWT_TTL_MINUTES`
+    pub fn from_env() -> Result<Self, TokenError> {
+        let keys = match std::env::var("JWT_KEYS_FILE") {
+            Ok(path) => {
+                let contents = std::fs::read_to_string(&path)
+                    .map_err(|e| TokenError::Key(format!("cannot read {}: {}", path, e)))?;
+                let value = serde_json::from_str(&contents)
+                    .map_err(|e| TokenError::Key(format!("{} is not JSON: {}", path, e)))?;
+                KeySet::from_json(&value)?
+            }
+            Err(_) => KeySet::new(TokenKey::hs256("default", secret_from_env()?.as_bytes())),
+        };
+
+        let tokens = Self::new(keys);
+        Ok(match std::env::var("JWT_TTL_MINUTES").ok().and_then(|v| v.parse::<i64>().ok()) {
+            Some(minutes) if minutes > 0 => tokens.with_ttl(Duration::minutes(minutes)),
+            _ => tokens,
+        })
+    }
+
+    pub fn keys(&self) -> &KeySet {
+        &self.keys
+    }
+
+    pub fn ttl(&self) -> Duration {
+        self.ttl
+    }
+
+    /// Fresh claims for `sub` in `workspace`, valid from now for the token lifetime
+    pub fn claims(&self, sub: &str, workspace: &str) -> Claims {
+        let now = Utc::now();
+        Claims {
+            sub: sub.to_string(),
+            workspace: workspace.to_string(),
+            email: None,
+            roles: Vec::new(),
+            exp: (now + self.ttl).timestamp(),
+            iat: now.timestamp(),
+            jti: uuid::Uuid::new_v4().to_string(),
+            sid: None,
+        }
+    }
+
+    /// Sign `claims` with the active key
+    pub fn sign(&self, claims: &Claims) -> Result<String, TokenError> {
+        let key = self.keys.active();
+        let encoding = key.encoding.as_ref().ok_or_else(|| key_error(&key.kid, "cannot sign"))?;
+        let mut header = Header::new(key.algorithm);
+        header.kid = Some(key.kid.clone());
+        jsonwebtoken::encode(&header, claims, encoding).map_err(|e| key_error(&key.kid, e))
+    }
+
+    /// Check the signature and expiry of `token` and return its claims
+    pub fn verify(&self, token: &str) -> Result<Claims, TokenError> {
+        let header = jsonwebtoken::decode_header(token).map_err(|e| TokenError::Invalid(e.to_string()))?;
+        let kid = header.kid.ok_or_else(|| TokenError::Invalid("no key id".to_string()))?;
+        let key = self.keys.get(&kid)
+            .ok_or_else(|| TokenError::Invalid(format!("unknown key '{}'", kid)))?;
+        // The key decides the algorithm, never the token
+        if header.alg != key.algorithm {
+            return Err(TokenError::Invalid(format!("key '{}' is not {:?}", kid, header.alg)));
+        }
+
+        let mut validation = Validation::new(key.algorithm);
+        validation.set_required_spec_claims(&["exp", "sub"]);
+        jsonwebtoken::decode::<Claims>(token, &key.decoding, &validation)
+            .map(|data| data.claims)
+            .map_err(|e| match e.kind() {
+                ErrorKind::ExpiredSignature => TokenError::Expired,
+                _ => TokenError::Invalid(e.to_string()),
+            })
+    }
+}
+
+/// The token of an `Authorization: Bearer ...` header value
+pub fn bearer(authorization: Option<&str>) -> Result<&str, TokenError> {
+    match authorization.map(str::trim) {
+        Some(value) => match value.split_once(' ') {
+            Some((scheme, token)) if scheme.eq_ignore_ascii_case("bearer") && !token.trim().is_empty() => Ok(token.trim()),
+            _ => Err(TokenError::Invalid("expected a bearer token".to_string())),
+        },
+        None => Err(TokenError::Missing),
+    }
+}
+
+fn pem_der(pem: &str) -> Result<Vec<u8>, String> {
+    let body: String = pem.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with("-----"))
+        .collect();
+    STANDARD.decode(body).map_err(|e| format!("not a PEM key: {}", e))
+}
+
+fn key_error(kid: &str, e: impl std::fmt::Display) -> TokenError {
+    TokenError::Key(format!("{}: {}", kid, e))
+}
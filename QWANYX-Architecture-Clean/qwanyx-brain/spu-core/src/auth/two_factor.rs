@@ -0,0 +1,829 @@
+//! Second factor
+//!
+//! Users may add a second factor to signing in: an authenticator app giving
+//! TOTP codes (RFC 6238: six digits every 30 seconds, HMAC-SHA1), and
+//! single-use recovery codes for when the phone is lost. Enrolling gives the
+//! app's secret as an `otpauth://` URI and its QR code; it takes effect once a
+//! first code from the app confirms it. Each workspace's policy names the roles
+//! that must have one (`Policy::two_factor`).
+//!
+//! A sign-in with the right email code or magic link, by a user with a second
+//! factor or a role requiring one, does not start a session but a challenge: a
+//! token good for a few minutes and a few wrong codes, traded for the session
+//! with a code of the app or a recovery code. Users whose role requires a
+//! second factor they don't have yet enroll with the challenge instead of a
+//! token, and the code confirming the enrollment signs them in.
+//!
+//! A code is accepted once: the time step of the last one used is kept. Wrong
+//! codes given to disable the second factor or renew its recovery codes count
+//! against the user, who is locked out of both for a while after a few. The
+//! app's secret has to be readable to check codes; recovery codes and
+//! challenges are kept hashed. With an audit log (`with_audit`), enrolling,
+//! disabling and recovery codes are logged.
+
+use super::{bson_date, chrono_date};
+use super::policy;
+use super::tokens::Claims;
+use crate::audit::{AuditEntry, AuditLog};
+use crate::store::DocumentStore;
+use crate::workspaces::WorkspaceDatabases;
+use async_trait::async_trait;
+use chrono::{DateTime, Duration, Utc};
+use data_encoding::BASE32_NOPAD;
+use hmac::{Hmac, Mac};
+use mongodb::{Collection, bson::{doc, Bson, Document}};
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use rand::RngCore;
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::Arc;
+use thiserror::Error;
+use tokio::sync::RwLock;
+use tracing::{info, warn};
+
+/// Characters left as they are in `otpauth://` URIs
+const UNRESERVED: &AsciiSet = &NON_ALPHANUMERIC.remove(b'-').remove(b'.').remove(b'_').remove(b'~');
+
+/// Bytes of an app's secret, as RFC 4226 recommends
+const SECRET_LEN: usize = 20;
+
+/// Characters of recovery codes, in two groups
+const RECOVERY_CODE_LEN: usize = 10;
+
+/// The second factor of a user
+#[derive(Debug, Clone, PartialEq)]
+pub struct Enrollment {
+    pub workspace: String,
+    /// The user as in their token's `sub`
+    pub user: String,
+    /// The app's secret
+    pub secret: Vec<u8>,
+    /// When a first code confirmed it; not in effect before
+    pub confirmed_at: Option<DateTime<Utc>>,
+    /// Hex HMAC-SHA256 of the recovery codes not used yet
+    pub recovery_codes: Vec<String>,
+    /// Time step of the last code accepted; this one and earlier ones are not
+    pub last_step: i64,
+    /// Wrong codes given to change it since the last right one
+    pub failures: u32,
+    pub failed_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+}
+
+impl Enrollment {
+    pub fn is_confirmed(&self) -> bool {
+        self.confirmed_at.is_some()
+    }
+
+    /// Whether too many wrong codes were given to change it, lately
+    pub fn is_locked(&self, policy: &TwoFactorPolicy, now: DateTime<Utc>) -> bool {
+        self.failures >= policy.max_attempts
+            && self.failed_at.is_some_and(|at| at + policy.lockout > now)
+    }
+}
+
+/// What a user enrolling needs: the only time the secret and recovery codes
+/// are given out
+#[derive(Debug, Clone, PartialEq)]
+pub struct Setup {
+    /// Base32, for typing into the app
+    pub secret: String,
+    /// `otpauth://totp/...`, for the app to scan
+    pub uri: String,
+    /// The URI as a QR code
+    pub qr_svg: String,
+    pub recovery_codes: Vec<String>,
+}
+
+/// A sign-in waiting for its second factor
+#[derive(Debug, Clone, PartialEq)]
+pub struct Challenge {
+    pub workspace: String,
+    /// Hex SHA-256 of the challenge token
+    pub id: String,
+    /// Who is signing in, as in their token's `sub`
+    pub user: String,
+    pub email: Option<String>,
+    pub roles: Vec<String>,
+    pub device: Option<String>,
+    pub ip: Option<String>,
+    pub expires_at: DateTime<Utc>,
+    /// Wrong codes tried so far
+    pub attempts: u32,
+}
+
+/// The second factor a code was checked against
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Factor {
+    App,
+    /// With the recovery codes left
+    RecoveryCode(usize),
+}
+
+/// Limits on codes and challenges
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TwoFactorPolicy {
+    pub digits: u32,
+    /// Seconds each code lasts
+    pub period: i64,
+    /// Codes of the steps this far before or after the current one are
+    /// accepted too, for clocks that drift
+    pub skew: i64,
+    /// Recovery codes given when enrolling
+    pub recovery_codes: usize,
+    /// How long a challenge stays valid
+    pub challenge_ttl: Duration,
+    /// Wrong codes after which a challenge is burnt, and changes to a second
+    /// factor are refused for `lockout`
+    pub max_attempts: u32,
+    /// How long a user who gave too many wrong codes can't change their second factor
+    pub lockout: Duration,
+}
+
+impl Default for TwoFactorPolicy {
+    fn default() -> Self {
+        Self {
+            digits: 6,
+            period: 30,
+            skew: 1,
+            recovery_codes: 10,
+            challenge_ttl: Duration::minutes(5),
+            max_attempts: 5,
+            lockout: Duration::minutes(15),
+        }
+    }
+}
+
+/// Why a second factor was not enrolled, changed or accepted
+#[derive(Debug, Error, PartialEq)]
+pub enum TwoFactorError {
+    #[error("No second factor enrolled")]
+    NotEnrolled,
+
+    #[error("A second factor is already enrolled")]
+    AlreadyEnrolled,
+
+    /// Disabling one the user's role requires
+    #[error("A second factor is required for your role")]
+    Required,
+
+    /// Also for codes used already
+    #[error("Invalid code")]
+    InvalidCode,
+
+    #[error("Too many attempts, sign in again")]
+    TooManyAttempts,
+
+    /// Changes refused after too many wrong codes, for the policy's `lockout`
+    #[error("Too many wrong codes, try again later")]
+    Locked,
+
+    /// Also for challenges that never existed or were passed already
+    #[error("Sign-in expired, sign in again")]
+    ChallengeExpired,
+
+    #[error("Storage error: {0}")]
+    Storage(String),
+}
+
+impl From<String> for TwoFactorError {
+    fn from(e: String) -> Self {
+        TwoFactorError::Storage(e)
+    }
+}
+
+/// The TOTP code of time step `step` for `secret` (RFC 6238 with HMAC-SHA1)
+pub fn totp(secret: &[u8], step: i64, digits: u32) -> String {
+    let mut mac = Hmac::<Sha1>::new_from_slice(secret)
+        .expect("HMAC accepts keys of any length");
+    mac.update(&step.to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+    // Dynamic truncation, RFC 4226 section 5.3
+    let offset = (hash[hash.len() - 1] & 0x0f) as usize;
+    let binary = u32::from_be_bytes([hash[offset] & 0x7f, hash[offset + 1], hash[offset + 2], hash[offset + 3]]);
+    format!("{:0width$}", binary as u64 % 10u64.pow(digits), width = digits as usize)
+}
+
+// ================================================================================
+// STORAGE
+// ================================================================================
+
+/// Persistence for enrollments and challenges
+#[async_trait]
+pub trait TwoFactorStore: Send + Sync {
+    /// Save an enrollment, replacing any other of the same user
+    async fn save(&self, enrollment: &Enrollment) -> Result<(), String>;
+
+    async fn load(&self, workspace: &str, user: &str) -> Result<Option<Enrollment>, String>;
+
+    /// Delete an enrollment; false if there was none
+    async fn remove(&self, workspace: &str, user: &str) -> Result<bool, String>;
+
+    /// Record the use of the code of time step `step`; false if it, or a later
+    /// one, was used already
+    async fn use_step(&self, workspace: &str, user: &str, step: i64) -> Result<bool, String>;
+
+    /// Use up a recovery code; false if it is not one left
+    async fn use_recovery_code(&self, workspace: &str, user: &str, hash: &str) -> Result<bool, String>;
+
+    /// Count a wrong code given to change an enrollment; returns the failures
+    /// so far, or None if there is no enrollment
+    async fn fail_code(&self, workspace: &str, user: &str, at: DateTime<Utc>) -> Result<Option<u32>, String>;
+
+    /// Forget the wrong codes of an enrollment, once a right one is given
+    async fn clear_failures(&self, workspace: &str, user: &str) -> Result<(), String>;
+
+    async fn insert_challenge(&self, challenge: &Challenge) -> Result<(), String>;
+
+    async fn load_challenge(&self, workspace: &str, id: &str) -> Result<Option<Challenge>, String>;
+
+    /// Count a wrong code; returns the attempts so far, or None if there is no challenge
+    async fn fail_challenge(&self, workspace: &str, id: &str) -> Result<Option<u32>, String>;
+
+    /// Delete a challenge; false if it was already gone, so that each is passed once
+    async fn remove_challenge(&self, workspace: &str, id: &str) -> Result<bool, String>;
+}
+
+/// In-memory store, for tests and local development
+#[derive(Default)]
+pub struct MemoryTwoFactorStore {
+    enrollments: RwLock<HashMap<(String, String), Enrollment>>,
+    challenges: RwLock<HashMap<(String, String), Challenge>>,
+}
+
+impl MemoryTwoFactorStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[async_trait]
+impl TwoFactorStore for MemoryTwoFactorStore {
+    async fn save(&self, enrollment: &Enrollment) -> Result<(), String> {
+        let mut enrollments = self.enrollments.write().await;
+        enrollments.insert((enrollment.workspace.clone(), enrollment.user.clone()), enrollment.clone());
+        Ok(())
+    }
+
+    async fn load(&self, workspace: &str, user: &str) -> Result<Option<Enrollment>, String> {
+        let enrollments = self.enrollments.read().await;
+        Ok(enrollments.get(&(workspace.to_string(), user.to_string())).cloned())
+    }
+
+    async fn remove(&self, workspace: &str, user: &str) -> Result<bool, String> {
+        let mut enrollments = self.enrollments.write().await;
+        Ok(enrollments.remove(&(workspace.to_string(), user.to_string())).is_some())
+    }
+
+    async fn use_step(&self, workspace: &str, user: &str, step: i64) -> Result<bool, String> {
+        let mut enrollments = self.enrollments.write().await;
+        match enrollments.get_mut(&(workspace.to_string(), user.to_string())) {
+            Some(enrollment) if enrollment.last_step < step => {
+                enrollment.last_step = step;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    async fn use_recovery_code(&self, workspace: &str, user: &str, hash: &str) -> Result<bool, String> {
+        let mut enrollments = self.enrollments.write().await;
+        let Some(enrollment) = enrollments.get_mut(&(workspace.to_string(), user.to_string())) else {
+            return Ok(false);
+        };
+        let before = enrollment.recovery_codes.len();
+        enrollment.recovery_codes.retain(|code| code != hash);
+        Ok(enrollment.recovery_codes.len() < before)
+    }
+
+    async fn fail_code(&self, workspace: &str, user: &str, at: DateTime<Utc>) -> Result<Option<u32>, String> {
+        let mut enrollments = self.enrollments.write().await;
+        Ok(enrollments.get_mut(&(workspace.to_string(), user.to_string())).map(|enrollment| {
+            enrollment.failures += 1;
+            enrollment.failed_at = Some(at);
+            enrollment.failures
+        }))
+    }
+
+    async fn clear_failures(&self, workspace: &str, user: &str) -> Result<(), String> {
+        let mut enrollments = self.enrollments.write().await;
+        if let Some(enrollment) = enrollments.get_mut(&(workspace.to_string(), user.to_string())) {
+            enrollment.failures = 0;
+            enrollment.failed_at = None;
+        }
+        Ok(())
+    }
+
+    async fn insert_challenge(&self, challenge: &Challenge) -> Result<(), String> {
+        let mut challenges = self.challenges.write().await;
+        // Forget the expired ones while at it
+        let now = Utc::now();
+        challenges.retain(|_, challenge| challenge.expires_at > now);
+        challenges.insert((challenge.workspace.clone(), challenge.id.clone()), challenge.clone());
+        Ok(())
+    }
+
+    async fn load_challenge(&self, workspace: &str, id: &str) -> Result<Option<Challenge>, String> {
+        let challenges = self.challenges.read().await;
+        Ok(challenges.get(&(workspace.to_string(), id.to_string())).cloned())
+    }
+
+    async fn fail_challenge(&self, workspace: &str, id: &str) -> Result<Option<u32>, String> {
+        let mut challenges = self.challenges.write().await;
+        Ok(challenges.get_mut(&(workspace.to_string(), id.to_string())).map(|challenge| {
+            challenge.attempts += 1;
+            challenge.attempts
+        }))
+    }
+
+    async fn remove_challenge(&self, workspace: &str, id: &str) -> Result<bool, String> {
+        let mut challenges = self.challenges.write().await;
+        Ok(challenges.remove(&(workspace.to_string(), id.to_string())).is_some())
+    }
+}
+
+/// MongoDB store
+///
+/// Enrollments live in the workspace's own database, in `spu_two_factor`
+/// keyed by user, and challenges in `spu_sign_in_challenges` keyed by hash,
+/// with an `expires_at` date for the TTL index of `create_indexes` to clean up.
+pub struct MongoTwoFactorStore {
+    databases: WorkspaceDatabases,
+}
+
+impl MongoTwoFactorStore {
+    pub fn new(databases: WorkspaceDatabases) -> Self {
+        Self { databases }
+    }
+
+    /// Have expired challenges deleted, in every workspace
+    pub async fn create_indexes(&self) -> Result<(), String> {
+        self.databases.expire_at("spu_sign_in_challenges", "expires_at").await
+    }
+
+    fn enrollments(&self, workspace: &str) -> Result<Collection<Document>, String> {
+        Ok(self.databases.database(workspace)?.collection("spu_two_factor"))
+    }
+
+    fn challenges(&self, workspace: &str) -> Result<Collection<Document>, String> {
+        Ok(self.databases.database(workspace)?.collection("spu_sign_in_challenges"))
+    }
+
+    fn from_document(workspace: &str, document: &Document) -> Result<Enrollment, String> {
+        let corrupt = |e: mongodb::bson::document::ValueAccessError| format!("Corrupt two-factor document: {}", e);
+        Ok(Enrollment {
+            workspace: workspace.to_string(),
+            user: document.get_str("_id").map_err(corrupt)?.to_string(),
+            secret: hex::decode(document.get_str("secret").map_err(corrupt)?)
+                .map_err(|e| format!("Corrupt two-factor secret: {}", e))?,
+            confirmed_at: document.get_datetime("confirmed_at").ok().map(chrono_date),
+            recovery_codes: strings(document.get_array("recovery_codes").map_err(corrupt)?),
+            last_step: document.get_i64("last_step").map_err(corrupt)?,
+            failures: document.get_i32("failures").unwrap_or(0) as u32,
+            failed_at: document.get_datetime("failed_at").ok().map(chrono_date),
+            created_at: chrono_date(document.get_datetime("created_at").map_err(corrupt)?),
+        })
+    }
+
+    fn challenge_from_document(workspace: &str, document: &Document) -> Result<Challenge, String> {
+        let corrupt = |e: mongodb::bson::document::ValueAccessError| format!("Corrupt challenge document: {}", e);
+        let optional = |name: &str| document.get_str(name).ok().map(str::to_string);
+        Ok(Challenge {
+            workspace: workspace.to_string(),
+            id: document.get_str("_id").map_err(corrupt)?.to_string(),
+            user: document.get_str("user").map_err(corrupt)?.to_string(),
+            email: optional("email"),
+            roles: strings(document.get_array("roles").map_err(corrupt)?),
+            device: optional("device"),
+            ip: optional("ip"),
+            expires_at: chrono_date(document.get_datetime("expires_at").map_err(corrupt)?),
+            attempts: document.get_i32("attempts").map_err(corrupt)? as u32,
+        })
+    }
+}
+
+#[async_trait]
+impl TwoFactorStore for MongoTwoFactorStore {
+    async fn save(&self, enrollment: &Enrollment) -> Result<(), String> {
+        let document = doc! {
+            "_id": &enrollment.user,
+            "secret": hex::encode(&enrollment.secret),
+            "confirmed_at": enrollment.confirmed_at.map(|at| Bson::DateTime(bson_date(at))).unwrap_or(Bson::Null),
+            "recovery_codes": &enrollment.recovery_codes,
+            "last_step": enrollment.last_step,
+            "failures": enrollment.failures as i32,
+            "failed_at": enrollment.failed_at.map(|at| Bson::DateTime(bson_date(at))).unwrap_or(Bson::Null),
+            "created_at": bson_date(enrollment.created_at),
+        };
+        let options = mongodb::options::ReplaceOptions::builder().upsert(true).build();
+        self.enrollments(&enrollment.workspace)?
+            .replace_one(doc! { "_id": &enrollment.user }, document, options).await
+            .map_err(|e| format!("Failed to save second factor: {}", e))?;
+        Ok(())
+    }
+
+    async fn load(&self, workspace: &str, user: &str) -> Result<Option<Enrollment>, String> {
+        let document = self.enrollments(workspace)?.find_one(doc! { "_id": user }, None).await
+            .map_err(|e| format!("Failed to load second factor: {}", e))?;
+        document.map(|document| Self::from_document(workspace, &document)).transpose()
+    }
+
+    async fn remove(&self, workspace: &str, user: &str) -> Result<bool, String> {
+        let result = self.enrollments(workspace)?.delete_one(doc! { "_id": user }, None).await
+            .map_err(|e| format!("Failed to delete second factor: {}", e))?;
+        Ok(result.deleted_count == 1)
+    }
+
+    async fn use_step(&self, workspace: &str, user: &str, step: i64) -> Result<bool, String> {
+        let result = self.enrollments(workspace)?
+            .update_one(doc! { "_id": user, "last_step": { "$lt": step } }, doc! { "$set": { "last_step": step } }, None).await
+            .map_err(|e| format!("Failed to record code use: {}", e))?;
+        Ok(result.modified_count == 1)
+    }
+
+    async fn use_recovery_code(&self, workspace: &str, user: &str, hash: &str) -> Result<bool, String> {
+        let result = self.enrollments(workspace)?
+            .update_one(doc! { "_id": user, "recovery_codes": hash }, doc! { "$pull": { "recovery_codes": hash } }, None).await
+            .map_err(|e| format!("Failed to use recovery code: {}", e))?;
+        Ok(result.modified_count == 1)
+    }
+
+    async fn fail_code(&self, workspace: &str, user: &str, at: DateTime<Utc>) -> Result<Option<u32>, String> {
+        let options = mongodb::options::FindOneAndUpdateOptions::builder()
+            .return_document(mongodb::options::ReturnDocument::After)
+            .build();
+        let update = doc! { "$inc": { "failures": 1 }, "$set": { "failed_at": bson_date(at) } };
+        let document = self.enrollments(workspace)?
+            .find_one_and_update(doc! { "_id": user }, update, options).await
+            .map_err(|e| format!("Failed to count wrong code: {}", e))?;
+        Ok(document.and_then(|document| document.get_i32("failures").ok()).map(|failures| failures as u32))
+    }
+
+    async fn clear_failures(&self, workspace: &str, user: &str) -> Result<(), String> {
+        self.enrollments(workspace)?
+            .update_one(doc! { "_id": user }, doc! { "$set": { "failures": 0, "failed_at": Bson::Null } }, None).await
+            .map_err(|e| format!("Failed to clear wrong codes: {}", e))?;
+        Ok(())
+    }
+
+    async fn insert_challenge(&self, challenge: &Challenge) -> Result<(), String> {
+        let optional = |value: &Option<String>| value.clone().map(Bson::String).unwrap_or(Bson::Null);
+        let document = doc! {
+            "_id": &challenge.id,
+            "user": &challenge.user,
+            "email": optional(&challenge.email),
+            "roles": &challenge.roles,
+            "device": optional(&challenge.device),
+            "ip": optional(&challenge.ip),
+            "expires_at": bson_date(challenge.expires_at),
+            "attempts": challenge.attempts as i32,
+        };
+        self.challenges(&challenge.workspace)?.insert_one(document, None).await
+            .map_err(|e| format!("Failed to save challenge: {}", e))?;
+        Ok(())
+    }
+
+    async fn load_challenge(&self, workspace: &str, id: &str) -> Result<Option<Challenge>, String> {
+        let document = self.challenges(workspace)?.find_one(doc! { "_id": id }, None).await
+            .map_err(|e| format!("Failed to load challenge: {}", e))?;
+        document.map(|document| Self::challenge_from_document(workspace, &document)).transpose()
+    }
+
+    async fn fail_challenge(&self, workspace: &str, id: &str) -> Result<Option<u32>, String> {
+        let options = mongodb::options::FindOneAndUpdateOptions::builder()
+            .return_document(mongodb::options::ReturnDocument::After)
+            .build();
+        let document = self.challenges(workspace)?
+            .find_one_and_update(doc! { "_id": id }, doc! { "$inc": { "attempts": 1 } }, options).await
+            .map_err(|e| format!("Failed to count attempt: {}", e))?;
+        Ok(document.and_then(|document| document.get_i32("attempts").ok()).map(|attempts| attempts as u32))
+    }
+
+    async fn remove_challenge(&self, workspace: &str, id: &str) -> Result<bool, String> {
+        let result = self.challenges(workspace)?.delete_one(doc! { "_id": id }, None).await
+            .map_err(|e| format!("Failed to delete challenge: {}", e))?;
+        Ok(result.deleted_count == 1)
+    }
+}
+
+// ================================================================================
+// SECOND FACTOR
+// ================================================================================
+
+/// Enrolls and checks second factors, and holds sign-ins waiting for one
+pub struct TwoFactor {
+    store: Arc<dyn TwoFactorStore>,
+    secret: Vec<u8>,
+    policy: TwoFactorPolicy,
+    policies: Option<Arc<dyn DocumentStore>>,
+    audit: Option<Arc<AuditLog>>,
+}
+
+impl TwoFactor {
+    /// Recovery codes hashed with `secret`, which must stay the same for them to work
+    pub fn new(store: Arc<dyn TwoFactorStore>, secret: &str) -> Self {
+        Self {
+            store,
+            secret: secret.as_bytes().to_vec(),
+            policy: TwoFactorPolicy::default(),
+            policies: None,
+            audit: None,
+        }
+    }
+
+    pub fn with_policy(mut self, policy: TwoFactorPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Read the roles requiring a second factor from the workspace policies in
+    /// this store; none do otherwise
+    pub fn with_policies(mut self, store: Arc<dyn DocumentStore>) -> Self {
+        self.policies = Some(store);
+        self
+    }
+
+    /// Log enrollments, disabling and recovery codes in this audit log
+    pub fn with_audit(mut self, audit: Arc<AuditLog>) -> Self {
+        self.audit = Some(audit);
+        self
+    }
+
+    pub fn policy(&self) -> &TwoFactorPolicy {
+        &self.policy
+    }
+
+    /// Whether the policy of `workspace` requires a second factor of one of `roles`
+    pub async fn required(&self, workspace: &str, roles: &[String]) -> Result<bool, TwoFactorError> {
+        let Some(store) = &self.policies else {
+            return Ok(false);
+        };
+        let policy = policy::stored(store.as_ref(), workspace).await?.unwrap_or_default();
+        Ok(policy.requires_two_factor(roles))
+    }
+
+    /// Whether `user` has a second factor in effect
+    pub async fn is_enrolled(&self, workspace: &str, user: &str) -> Result<bool, TwoFactorError> {
+        Ok(self.store.load(workspace, user).await?.is_some_and(|enrollment| enrollment.is_confirmed()))
+    }
+
+    /// A new secret and recovery codes for `user`, replacing any enrollment
+    /// not confirmed yet; the app shows them as `account` of `issuer`
+    pub async fn enroll(&self, workspace: &str, user: &str, account: &str, issuer: &str) -> Result<Setup, TwoFactorError> {
+        if self.is_enrolled(workspace, user).await? {
+            return Err(TwoFactorError::AlreadyEnrolled);
+        }
+        let mut secret = vec![0u8; SECRET_LEN];
+        rand::thread_rng().fill_bytes(&mut secret);
+        let recovery_codes = self.recovery_codes();
+        let enrollment = Enrollment {
+            workspace: workspace.to_string(),
+            user: user.to_string(),
+            recovery_codes: recovery_codes.iter().map(|code| self.hash(workspace, user, code)).collect(),
+            secret,
+            confirmed_at: None,
+            last_step: 0,
+            failures: 0,
+            failed_at: None,
+            created_at: Utc::now(),
+        };
+        self.store.save(&enrollment).await?;
+        info!("Second factor of {} in {} waiting for confirmation", user, workspace);
+
+        let uri = self.uri(&enrollment.secret, account, issuer);
+        let qr_svg = qrcode::QrCode::new(uri.as_bytes())
+            .map(|qr| qr.render::<qrcode::render::svg::Color>().min_dimensions(200, 200).build())
+            .unwrap_or_default();
+        Ok(Setup { secret: BASE32_NOPAD.encode(&enrollment.secret), uri, qr_svg, recovery_codes })
+    }
+
+    /// Put the enrollment of `user` in effect with a first code of the app
+    pub async fn confirm(&self, workspace: &str, user: &str, code: &str) -> Result<(), TwoFactorError> {
+        let mut enrollment = self.store.load(workspace, user).await?.ok_or(TwoFactorError::NotEnrolled)?;
+        if enrollment.is_confirmed() {
+            return Err(TwoFactorError::AlreadyEnrolled);
+        }
+        let step = self.check_app(&enrollment, code).ok_or(TwoFactorError::InvalidCode)?;
+        enrollment.confirmed_at = Some(Utc::now());
+        enrollment.last_step = step;
+        self.store.save(&enrollment).await?;
+        info!("Second factor of {} in {} enrolled", user, workspace);
+        self.audit(workspace, "auth.two_factor_enrolled", user).await;
+        Ok(())
+    }
+
+    /// Check a code of `user`'s app, or one of their recovery codes, using it up
+    pub async fn verify(&self, workspace: &str, user: &str, code: &str) -> Result<Factor, TwoFactorError> {
+        let enrollment = match self.store.load(workspace, user).await? {
+            Some(enrollment) if enrollment.is_confirmed() => enrollment,
+            _ => return Err(TwoFactorError::NotEnrolled),
+        };
+        if let Some(step) = self.check_app(&enrollment, code) {
+            // A code seen a moment ago, by a concurrent sign-in or an onlooker, loses here
+            return match self.store.use_step(workspace, user, step).await? {
+                true => Ok(Factor::App),
+                false => Err(TwoFactorError::InvalidCode),
+            };
+        }
+
+        let hash = self.hash(workspace, user, code);
+        if enrollment.recovery_codes.contains(&hash) && self.store.use_recovery_code(workspace, user, &hash).await? {
+            let left = enrollment.recovery_codes.len() - 1;
+            warn!("Recovery code of {} in {} used, {} left", user, workspace, left);
+            self.audit(workspace, "auth.recovery_code_used", user).await;
+            return Ok(Factor::RecoveryCode(left));
+        }
+        Err(TwoFactorError::InvalidCode)
+    }
+
+    /// Remove `user`'s second factor, given one of their codes; refused when
+    /// one of `roles` requires it
+    pub async fn disable(&self, workspace: &str, user: &str, roles: &[String], code: &str) -> Result<(), TwoFactorError> {
+        if self.required(workspace, roles).await? {
+            return Err(TwoFactorError::Required);
+        }
+        self.verify_change(workspace, user, code).await?;
+        self.store.remove(workspace, user).await?;
+        info!("Second factor of {} in {} disabled", user, workspace);
+        self.audit(workspace, "auth.two_factor_disabled", user).await;
+        Ok(())
+    }
+
+    /// New recovery codes for `user`, given one of their codes; the old ones
+    /// stop working
+    pub async fn regenerate_recovery_codes(&self, workspace: &str, user: &str, code: &str) -> Result<Vec<String>, TwoFactorError> {
+        self.verify_change(workspace, user, code).await?;
+        let mut enrollment = self.store.load(workspace, user).await?.ok_or(TwoFactorError::NotEnrolled)?;
+        let recovery_codes = self.recovery_codes();
+        enrollment.recovery_codes = recovery_codes.iter().map(|code| self.hash(workspace, user, code)).collect();
+        self.store.save(&enrollment).await?;
+        self.audit(workspace, "auth.recovery_codes_regenerated", user).await;
+        Ok(recovery_codes)
+    }
+
+    /// `verify` for changes to the second factor: wrong codes count against
+    /// the user, as they do against a challenge when signing in, and after
+    /// `max_attempts` of them changes are refused for `lockout`
+    async fn verify_change(&self, workspace: &str, user: &str, code: &str) -> Result<Factor, TwoFactorError> {
+        let now = Utc::now();
+        if self.store.load(workspace, user).await?.is_some_and(|enrollment| enrollment.is_locked(&self.policy, now)) {
+            return Err(TwoFactorError::Locked);
+        }
+        match self.verify(workspace, user, code).await {
+            Ok(factor) => {
+                self.store.clear_failures(workspace, user).await?;
+                Ok(factor)
+            }
+            Err(TwoFactorError::InvalidCode) => match self.store.fail_code(workspace, user, now).await? {
+                Some(failures) if failures >= self.policy.max_attempts => {
+                    warn!("Second factor of {} in {} locked after {} wrong codes", user, workspace, failures);
+                    Err(TwoFactorError::Locked)
+                }
+                _ => Err(TwoFactorError::InvalidCode),
+            },
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Hold the sign-in of `claims` until its second factor is given; returns
+    /// the challenge token and the challenge
+    pub async fn challenge(&self, claims: &Claims, device: Option<&str>, ip: Option<&str>) -> Result<(String, Challenge), TwoFactorError> {
+        let mut bytes = [0u8; 32];
+        rand::thread_rng().fill_bytes(&mut bytes);
+        let token = hex::encode(bytes);
+        let challenge = Challenge {
+            workspace: claims.workspace.clone(),
+            id: challenge_id(&token),
+            user: claims.sub.clone(),
+            email: claims.email.clone(),
+            roles: claims.roles.clone(),
+            device: device.map(str::to_string),
+            ip: ip.map(str::to_string),
+            expires_at: Utc::now() + self.policy.challenge_ttl,
+            attempts: 0,
+        };
+        self.store.insert_challenge(&challenge).await?;
+        Ok((token, challenge))
+    }
+
+    /// The sign-in a challenge token holds, if still valid
+    pub async fn pending(&self, workspace: &str, token: &str) -> Result<Challenge, TwoFactorError> {
+        let challenge = self.store.load_challenge(workspace, &challenge_id(token.trim())).await?
+            .ok_or(TwoFactorError::ChallengeExpired)?;
+        if challenge.expires_at <= Utc::now() {
+            self.store.remove_challenge(workspace, &challenge.id).await?;
+            return Err(TwoFactorError::ChallengeExpired);
+        }
+        if challenge.attempts >= self.policy.max_attempts {
+            return Err(TwoFactorError::TooManyAttempts);
+        }
+        Ok(challenge)
+    }
+
+    /// Trade a challenge token and a code for the sign-in it holds. The code
+    /// is one of the user's app or a recovery code, or, if their enrollment
+    /// is not confirmed yet, the first code of the app confirming it. Wrong
+    /// codes count against the challenge.
+    pub async fn pass(&self, workspace: &str, token: &str, code: &str) -> Result<(Challenge, Factor), TwoFactorError> {
+        let challenge = self.pending(workspace, token).await?;
+        let enrollment = self.store.load(workspace, &challenge.user).await?.ok_or(TwoFactorError::NotEnrolled)?;
+        let checked = if enrollment.is_confirmed() {
+            self.verify(workspace, &challenge.user, code).await
+        } else {
+            self.confirm(workspace, &challenge.user, code).await.map(|_| Factor::App)
+        };
+
+        match checked {
+            Ok(factor) => {
+                // Passed once: a concurrent pass of the same challenge loses here
+                if !self.store.remove_challenge(workspace, &challenge.id).await? {
+                    return Err(TwoFactorError::ChallengeExpired);
+                }
+                Ok((challenge, factor))
+            }
+            Err(TwoFactorError::InvalidCode) => match self.store.fail_challenge(workspace, &challenge.id).await? {
+                Some(attempts) if attempts >= self.policy.max_attempts => {
+                    warn!("Sign-in of {} in {} burnt after {} wrong codes", challenge.user, workspace, attempts);
+                    self.store.remove_challenge(workspace, &challenge.id).await?;
+                    Err(TwoFactorError::TooManyAttempts)
+                }
+                _ => Err(TwoFactorError::InvalidCode),
+            },
+            Err(e) => Err(e),
+        }
+    }
+
+    /// The time step of an app code of `enrollment` not used yet, if `code` is one
+    fn check_app(&self, enrollment: &Enrollment, code: &str) -> Option<i64> {
+        let code = code.trim();
+        if code.len() != self.policy.digits as usize || !code.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+        let current = Utc::now().timestamp().div_euclid(self.policy.period);
+        (current - self.policy.skew..=current + self.policy.skew)
+            .filter(|step| *step > enrollment.last_step)
+            .find(|step| same(&totp(&enrollment.secret, *step, self.policy.digits), code))
+    }
+
+    fn uri(&self, secret: &[u8], account: &str, issuer: &str) -> String {
+        let issuer = utf8_percent_encode(issuer, UNRESERVED).to_string();
+        format!(
+            "otpauth://totp/{}:{}?secret={}&issuer={}&algorithm=SHA1&digits={}&period={}",
+            issuer,
+            utf8_percent_encode(account, UNRESERVED),
+            BASE32_NOPAD.encode(secret),
+            issuer,
+            self.policy.digits,
+            self.policy.period,
+        )
+    }
+
+    fn recovery_codes(&self) -> Vec<String> {
+        (0..self.policy.recovery_codes)
+            .map(|_| {
+                let mut bytes = [0u8; RECOVERY_CODE_LEN];
+                rand::thread_rng().fill_bytes(&mut bytes);
+                let code: String = BASE32_NOPAD.encode(&bytes).to_lowercase().chars().take(RECOVERY_CODE_LEN).collect();
+                format!("{}-{}", &code[..RECOVERY_CODE_LEN / 2], &code[RECOVERY_CODE_LEN / 2..])
+            })
+            .collect()
+    }
+
+    fn hash(&self, workspace: &str, user: &str, code: &str) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.secret)
+            .expect("HMAC accepts keys of any length");
+        mac.update(format!("{}\0{}\0{}", workspace, user, normalize(code)).as_bytes());
+        hex::encode(mac.finalize().into_bytes())
+    }
+
+    async fn audit(&self, workspace: &str, action: &str, user: &str) {
+        if let Some(audit) = &self.audit {
+            let entry = AuditEntry::new(workspace, action).by(user).on(format!("two_factor/{}", user));
+            audit.log(&entry, None).await;
+        }
+    }
+}
+
+fn challenge_id(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+/// Recovery codes as typed: any case, with or without the dash
+fn normalize(code: &str) -> String {
+    let code: String = code.chars().filter(|c| c.is_ascii_alphanumeric()).collect::<String>().to_lowercase();
+    if code.len() == RECOVERY_CODE_LEN {
+        format!("{}-{}", &code[..RECOVERY_CODE_LEN / 2], &code[RECOVERY_CODE_LEN / 2..])
+    } else {
+        code
+    }
+}
+
+/// Compare codes in constant time
+fn same(a: &str, b: &str) -> bool {
+    a.len() == b.len() && a.bytes().zip(b.bytes()).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+fn strings(values: &[Bson]) -> Vec<String> {
+    values.iter().filter_map(|value| value.as_str().map(str::to_string)).collect()
+}
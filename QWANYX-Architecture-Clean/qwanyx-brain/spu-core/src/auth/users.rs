@@ -0,0 +1,75 @@
+//! Users
+//!
+//! The `users` collection of each workspace, as signing in sees it. A user's
+//! id - the `sub` of their tokens - is the `_id` of their document, and their
+//! roles are its `roles`, `role` and `accountType`. Users may not set these on
+//! themselves (see `Policy::default()`), and those registering themselves pick
+//! one of `ACCOUNT_TYPES`.
+//!
+//! People without a document, such as those a workspace admits by their email
+//! domain, are known by their email and have no roles.
+
+use crate::store::{DocumentStore, FindOptions, Namespace};
+use mongodb::bson::{doc, oid::ObjectId, Bson, Document};
+
+/// Collection of each workspace holding its users
+pub const USERS_COLLECTION: &str = "users";
+
+/// Account types people may register with, which are also their role
+pub const ACCOUNT_TYPES: &[&str] = &["particulier", "professionnel"];
+
+/// A user of a workspace
+#[derive(Debug, Clone, PartialEq)]
+pub struct User {
+    pub id: String,
+    pub email: Option<String>,
+    pub roles: Vec<String>,
+}
+
+impl User {
+    pub fn from_document(document: &Document) -> Option<Self> {
+        let id = match document.get("_id")? {
+            Bson::ObjectId(oid) => oid.to_hex(),
+            Bson::String(id) => id.clone(),
+            _ => return None,
+        };
+        let mut roles: Vec<String> = match document.get("roles") {
+            Some(Bson::Array(roles)) => roles.iter().filter_map(|role| role.as_str().map(str::to_string)).collect(),
+            _ => Vec::new(),
+        };
+        for field in ["role", "accountType"] {
+            if let Ok(role) = document.get_str(field) {
+                if !role.is_empty() && !roles.iter().any(|known| known == role) {
+                    roles.push(role.to_string());
+                }
+            }
+        }
+        Some(Self {
+            id,
+            email: document.get_str("email").ok().map(|email| email.trim().to_lowercase()),
+            roles,
+        })
+    }
+}
+
+/// The user of an email in a workspace, whatever its case
+pub async fn by_email<S: DocumentStore + ?Sized>(store: &S, workspace: &str, email: &str) -> Result<Option<User>, String> {
+    let exactly = format!("^{}$", regex::escape(email.trim()));
+    find(store, workspace, doc! { "email": { "$regex": exactly, "$options": "i" } }).await
+}
+
+/// The user of an id in a workspace
+pub async fn by_id<S: DocumentStore + ?Sized>(store: &S, workspace: &str, id: &str) -> Result<Option<User>, String> {
+    let id = match ObjectId::parse_str(id) {
+        Ok(oid) => Bson::ObjectId(oid),
+        Err(_) => Bson::String(id.to_string()),
+    };
+    find(store, workspace, doc! { "_id": id }).await
+}
+
+async fn find<S: DocumentStore + ?Sized>(store: &S, workspace: &str, filter: Document) -> Result<Option<User>, String> {
+    let options = FindOptions { limit: Some(1), ..FindOptions::default() };
+    let found = store.find(Namespace::new(workspace, USERS_COLLECTION), filter, options).await
+        .map_err(|e| format!("Failed to load user: {}", e))?;
+    Ok(found.first().and_then(User::from_document))
+}
@@ -0,0 +1,112 @@
+//! BSON ⇄ Data
+//!
+//! Round-trip-safe conversion between MongoDB values and script data. Doubles,
+//! strings, booleans, null, arrays, documents, integers, dates, generic binary and
+//! ObjectIds map onto their `Data` equivalent; every other BSON type becomes its
+//! extended JSON form, e.g. `{"$timestamp": {...}}`, and is turned back into the
+//! same BSON type on the way in.
+//!
+//! Int32 is widened to Int64 when written back, and field order is not kept, as
+//! `Data::Object` is unordered.
+
+use crate::Data;
+use mongodb::bson::{spec::BinarySubtype, Binary, Bson, DateTime, Document};
+use std::collections::HashMap;
+
+/// Extended JSON keys that mark a single-key object as a typed BSON value
+const EXTJSON_KEYS: [&str; 15] = [
+    "$oid", "$date", "$numberInt", "$numberLong", "$numberDouble", "$numberDecimal",
+    "$binary", "$uuid", "$regularExpression", "$timestamp", "$symbol", "$code",
+    "$minKey", "$maxKey", "$undefined",
+];
+
+/// Convert a BSON value to script data, without losing its type
+pub fn bson_to_data(value: &Bson) -> Data {
+    match value {
+        Bson::Double(d) => Data::Number(*d),
+        Bson::String(s) => Data::String(s.clone()),
+        Bson::Boolean(b) => Data::Bool(*b),
+        Bson::Null => Data::Null,
+        Bson::Array(items) => Data::Array(items.iter().map(bson_to_data).collect()),
+        Bson::Document(doc) => document_to_data(doc),
+        Bson::Int32(i) => Data::Integer(i64::from(*i)),
+        Bson::Int64(i) => Data::Integer(*i),
+        Bson::ObjectId(oid) => Data::Id(*oid),
+        Bson::Binary(binary) if binary.subtype == BinarySubtype::Generic => Data::Bytes(binary.bytes.clone()),
+        Bson::DateTime(dt) => match chrono::DateTime::from_timestamp_millis(dt.timestamp_millis()) {
+            Some(dt) => Data::DateTime(dt),
+            // Beyond what chrono can represent
+            None => tagged_extjson(value),
+        },
+        other => tagged_extjson(other),
+    }
+}
+
+/// Convert a BSON document to a `Data::Object`
+pub fn document_to_data(doc: &Document) -> Data {
+    Data::Object(doc.iter().map(|(k, v)| (k.clone(), bson_to_data(v))).collect())
+}
+
+/// Convert script data to a BSON value
+///
+/// Extended JSON forms become the BSON type they describe; a malformed one, like
+/// `{"$oid": "nope"}`, is an error rather than being stored as a document.
+pub fn data_to_bson(value: &Data) -> Result<Bson, String> {
+    match value {
+        Data::Null => Ok(Bson::Null),
+        Data::Bool(b) => Ok(Bson::Boolean(*b)),
+        Data::Number(n) => Ok(Bson::Double(*n)),
+        Data::String(s) => Ok(Bson::String(s.clone())),
+        Data::Array(items) => items.iter()
+            .map(data_to_bson)
+            .collect::<Result<Vec<_>, _>>()
+            .map(Bson::Array),
+        Data::Object(obj) if is_extjson(obj) => {
+            Bson::try_from(extjson(value))
+                .map_err(|e| format!("Invalid extended JSON value {}: {}", value.to_json(), e))
+        }
+        Data::Object(obj) => data_to_document(obj).map(Bson::Document),
+        // Runtime object references only exist inside a script run
+        Data::ObjectRef(id) => Ok(Bson::String(id.0.clone())),
+        Data::Integer(i) => Ok(Bson::Int64(*i)),
+        Data::DateTime(dt) => Ok(Bson::DateTime(DateTime::from_millis(dt.timestamp_millis()))),
+        Data::Bytes(bytes) => Ok(Bson::Binary(Binary { subtype: BinarySubtype::Generic, bytes: bytes.clone() })),
+        Data::Id(oid) => Ok(Bson::ObjectId(*oid)),
+    }
+}
+
+/// Convert the fields of a `Data::Object` to a BSON document
+pub fn data_to_document(obj: &HashMap<String, Data>) -> Result<Document, String> {
+    obj.iter()
+        .map(|(k, v)| data_to_bson(v).map(|bson| (k.clone(), bson)))
+        .collect()
+}
+
+/// Whether an object is an extended JSON value rather than a document
+pub fn is_extjson(obj: &HashMap<String, Data>) -> bool {
+    match obj.len() {
+        1 => obj.keys().all(|k| EXTJSON_KEYS.contains(&k.as_str())),
+        2 => obj.contains_key("$code") && obj.contains_key("$scope"),
+        _ => false,
+    }
+}
+
+/// Extended JSON object as `Data`, kept as an object even where `Data::from_json`
+/// would turn it into a typed value
+fn tagged_extjson(value: &Bson) -> Data {
+    match value.clone().into_relaxed_extjson() {
+        serde_json::Value::Object(obj) => Data::Object(obj.into_iter().map(|(k, v)| (k, Data::from_json(v))).collect()),
+        other => Data::from_json(other),
+    }
+}
+
+/// JSON for the extended JSON parser, which wants integers where scripts have
+/// floats (`$timestamp` parts, `$date` milliseconds)
+fn extjson(value: &Data) -> serde_json::Value {
+    match value {
+        Data::Number(n) if n.fract() == 0.0 && n.abs() < 9.0e15 => serde_json::Value::from(*n as i64),
+        Data::Array(items) => serde_json::Value::Array(items.iter().map(extjson).collect()),
+        Data::Object(obj) => serde_json::Value::Object(obj.iter().map(|(k, v)| (k.clone(), extjson(v))).collect()),
+        other => other.to_json(),
+    }
+}
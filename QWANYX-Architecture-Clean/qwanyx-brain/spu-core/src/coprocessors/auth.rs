@@ -2,12 +2,46 @@
 //! 
 //! Handles user authentication logic
 //! Delegates email sending to EmailCoprocessor and database operations to DatabaseCoprocessor
+//!
+//! Login codes are kept hashed in a `CodeStore` (in memory unless given one),
+//! used once, and rate-limited; see `crate::auth::codes`. A verified code
+//! starts a session: a short-lived signed access token and a refresh token
+//! (see `crate::auth::tokens` and `crate::auth::sessions`).
+//!
+//! Instead of a code, users may ask for a magic link (`generate_magic_link`,
+//! `verify_magic_link`; see `crate::auth::magic_links`). Users with a second
+//! factor, or whose role requires one, get a challenge instead of a session,
+//! traded for it with a code of their authenticator app (`verify_two_factor`;
+//! see `crate::auth::two_factor`).
+//!
+//! Users may also sign in with an OpenID Connect provider of their workspace
+//! (`oidc_authorize`, `oidc_callback`), and link more providers to their
+//! account (`link_identity`; see `crate::auth::oidc`).
+//!
+//! Signed-in users are the users of their workspace's `users` collection
+//! (`with_users`; see `crate::auth::users`): their tokens carry the id and
+//! roles of that document, never ones a script names.
+//!
+//! With an audit log (`with_audit`), registrations, code requests, sign-ins,
+//! refreshes and logouts are logged, refused ones included.
 
 use crate::{Coprocessor, CoprocessorError, Data, Health, MethodSignature};
+use crate::audit::{AuditEntry, AuditLog};
+use crate::auth::codes::{CodeError, CodePolicy, CodeStore, LoginCodes, MemoryCodeStore};
+use crate::auth::magic_links::{self, LinkError, LinkStore, MagicLinks, MemoryLinkStore};
+use crate::auth::oidc::{Authorization, MemoryOidcStore, Oidc, OidcError};
+use crate::auth::sessions::{Grant, MemorySessionStore, SessionError, SessionPolicy, SessionStore, Sessions};
+use crate::auth::tokens::{Claims, KeySet, TokenError, TokenKey, Tokens};
+use crate::auth::two_factor::{Challenge, Factor, MemoryTwoFactorStore, TwoFactor, TwoFactorError};
+use crate::auth::users::{self, User};
+use crate::events::{Event, EventBus};
+use crate::store::DocumentStore;
+use crate::workspaces::Workspaces;
 use async_trait::async_trait;
+use rand::RngCore;
 use std::collections::HashMap;
+use std::sync::Arc;
 use tracing::{error, info};
-use rand::Rng;
 
 /// Auth Coprocessor
 /// 
@@ -15,19 +49,138 @@ use rand::Rng;
 /// This follows the orchestrator pattern - auth knows the logic but delegates the work.
 pub struct AuthCoprocessor {
     jwt_secret: String,
+    codes: LoginCodes,
+    links: MagicLinks,
+    sessions: Arc<Sessions>,
+    two_factor: Arc<TwoFactor>,
+    oidc: Arc<Oidc>,
+    /// Where the users of each workspace are; everyone is known by email without one
+    users: Option<Arc<dyn DocumentStore>>,
+    events: Option<EventBus>,
+    audit: Option<Arc<AuditLog>>,
 }
 
+/// Actor of the auth events of clients not known yet
+const ANONYMOUS: &str = "anonymous";
+
 impl AuthCoprocessor {
+    /// With a random secret of its own, for tests and embedding; the server
+    /// shares its secret with `from_secret`
     pub fn new() -> Self {
+        let mut bytes = [0u8; 32];
+        rand::thread_rng().fill_bytes(&mut bytes);
+        Self::from_secret(&hex::encode(bytes))
+    }
+    
+    /// Hash codes and links, and sign tokens until given others, with `jwt_secret`
+    pub fn from_secret(jwt_secret: &str) -> Self {
+        let jwt_secret = jwt_secret.to_string();
         Self {
-            jwt_secret: std::env::var("JWT_SECRET_KEY")
-                .unwrap_or_else(|_| "qwanyx-secret-key-change-this-in-production".to_string()),
+            codes: LoginCodes::new(Arc::new(MemoryCodeStore::new()), &jwt_secret),
+            links: MagicLinks::new(Arc::new(MemoryLinkStore::new()), &jwt_secret),
+            two_factor: Arc::new(TwoFactor::new(Arc::new(MemoryTwoFactorStore::new()), &jwt_secret)),
+            oidc: Arc::new(Oidc::new(
+                Arc::new(MemoryOidcStore::new()),
+                Arc::new(Workspaces::of(&["autodin"]).expect("a single workspace is a valid registry")),
+            )),
+            sessions: Arc::new(Sessions::new(
+                Arc::new(MemorySessionStore::new()),
+                Arc::new(Tokens::new(KeySet::new(TokenKey::hs256("default", jwt_secret.as_bytes())))),
+            )),
+            jwt_secret,
+            users: None,
+            events: None,
+            audit: None,
         }
     }
     
-    fn generate_code() -> String {
-        let mut rng = rand::thread_rng();
-        format!("{:06}", rng.gen_range(100000..999999))
+    /// Keep login codes in this store, with these limits
+    pub fn with_codes(mut self, store: Arc<dyn CodeStore>, policy: CodePolicy) -> Self {
+        self.codes = LoginCodes::new(store, &self.jwt_secret).with_policy(policy);
+        self
+    }
+    
+    /// Keep magic links in this store, valid for `ttl`
+    pub fn with_links(mut self, store: Arc<dyn LinkStore>, ttl: chrono::Duration) -> Self {
+        self.links = MagicLinks::new(store, &self.jwt_secret).with_ttl(ttl);
+        self
+    }
+    
+    /// Share these second factors, and the sign-ins waiting for them, with the
+    /// HTTP server
+    pub fn with_two_factor(mut self, two_factor: Arc<TwoFactor>) -> Self {
+        self.two_factor = two_factor;
+        self
+    }
+    
+    /// Sign in with the OpenID Connect providers of these workspaces, sharing
+    /// linked identities with the HTTP server
+    pub fn with_oidc(mut self, oidc: Arc<Oidc>) -> Self {
+        self.oidc = oidc;
+        self
+    }
+    
+    /// Sign and verify tokens with these keys instead of one HS256 key from the secret
+    pub fn with_tokens(mut self, tokens: Arc<Tokens>) -> Self {
+        let policy = *self.sessions.policy();
+        self.sessions = Arc::new(Sessions::new(self.sessions.store(), tokens).with_policy(policy));
+        self
+    }
+    
+    /// Keep sessions in this store, with these limits
+    pub fn with_session_store(mut self, store: Arc<dyn SessionStore>, policy: SessionPolicy) -> Self {
+        self.sessions = Arc::new(Sessions::new(store, self.sessions.tokens().clone()).with_policy(policy));
+        self
+    }
+    
+    /// Share these sessions, and their tokens, with the HTTP server
+    pub fn with_sessions(mut self, sessions: Arc<Sessions>) -> Self {
+        self.sessions = sessions;
+        self
+    }
+    
+    /// Sign users in with the id and roles of their document in this store
+    pub fn with_users(mut self, store: Arc<dyn DocumentStore>) -> Self {
+        self.users = Some(store);
+        self
+    }
+    
+    /// Publish `auth.user_registered` and `auth.user_verified` events on this bus
+    pub fn with_events(mut self, events: EventBus) -> Self {
+        self.events = Some(events);
+        self
+    }
+    
+    /// Log auth events in this audit log
+    pub fn with_audit(mut self, audit: Arc<AuditLog>) -> Self {
+        self.audit = Some(audit);
+        self
+    }
+    
+    fn publish(&self, name: &str, workspace: &str, payload: HashMap<String, Data>) {
+        if let Some(events) = &self.events {
+            events.publish(Event::new(name, workspace, Data::Object(payload)));
+        }
+    }
+    
+    /// The workspace a call names, or else the default of the registry given to `with_oidc`
+    fn workspace_of(&self, obj: &HashMap<String, Data>) -> String {
+        match obj.get("workspace") {
+            Some(Data::String(s)) => s.clone(),
+            _ => self.oidc.workspaces().default_workspace().id.clone(),
+        }
+    }
+    
+    /// Log an auth event by `actor`, from `ip` unless the request says otherwise
+    async fn audit(&self, workspace: &str, action: &str, actor: &str, target: Option<String>, ip: Option<&str>) {
+        if let Some(audit) = &self.audit {
+            let mut entry = AuditEntry::new(workspace, action).by(actor);
+            entry.target = target;
+            if entry.ip.is_none() {
+                entry.ip = ip.map(str::to_string);
+            }
+            audit.log(&entry, None).await;
+        }
     }
 }
 
@@ -54,6 +207,11 @@ impl Coprocessor for AuthCoprocessor {
                         "email": { 
                             "type": "string",
                             "description": "Email address to generate code for"
+                        },
+                        "workspace": { "type": "string" },
+                        "ip": {
+                            "type": "string",
+                            "description": "Address of the client asking, for rate limiting"
                         }
                     },
                     "required": ["email"]
@@ -79,7 +237,13 @@ impl Coprocessor for AuthCoprocessor {
                     "type": "object",
                     "properties": {
                         "email": { "type": "string" },
-                        "code": { "type": "string" }
+                        "code": { "type": "string" },
+                        "workspace": { "type": "string" },
+                        "device": {
+                            "type": "string",
+                            "description": "User agent of the client, shown in its session"
+                        },
+                        "ip": { "type": "string" }
                     },
                     "required": ["email", "code"]
                 })),
@@ -91,6 +255,10 @@ impl Coprocessor for AuthCoprocessor {
                             "type": ["string", "null"],
                             "description": "JWT token if valid"
                         },
+                        "expires_at": { "type": ["string", "null"] },
+                        "refresh_token": { "type": ["string", "null"] },
+                        "refresh_expires_at": { "type": ["string", "null"] },
+                        "session_id": { "type": ["string", "null"] },
                         "error": {
                             "type": ["string", "null"],
                             "description": "Error message if invalid"
@@ -108,8 +276,12 @@ impl Coprocessor for AuthCoprocessor {
                         "firstName": { "type": "string" },
                         "lastName": { "type": "string" },
                         "phone": { "type": "string" },
-                        "accountType": { "type": "string" },
-                        "workspace": { "type": "string" }
+                        "accountType": {
+                            "type": "string",
+                            "enum": ["particulier", "professionnel"]
+                        },
+                        "workspace": { "type": "string" },
+                        "ip": { "type": "string" }
                     },
                     "required": ["email"]
                 })),
@@ -129,20 +301,342 @@ impl Coprocessor for AuthCoprocessor {
             },
             MethodSignature {
                 name: "generate_token".to_string(),
-                description: "Generate a JWT token for a user".to_string(),
+                description: "Generate a JWT token for a stored user".to_string(),
                 input_schema: Some(serde_json::json!({
                     "type": "object",
                     "properties": {
-                        "user_id": { "type": "string" },
                         "email": { "type": "string" },
                         "workspace": { "type": "string" }
                     },
-                    "required": ["user_id", "email"]
+                    "required": ["email"]
+                })),
+                output_schema: Some(serde_json::json!({
+                    "type": "object",
+                    "properties": {
+                        "token": { "type": "string" },
+                        "expires_at": { "type": "string" }
+                    }
+                })),
+            },
+            MethodSignature {
+                name: "verify_token".to_string(),
+                description: "Check a JWT token and return its claims".to_string(),
+                input_schema: Some(serde_json::json!({
+                    "type": "object",
+                    "properties": {
+                        "token": { "type": "string" }
+                    },
+                    "required": ["token"]
+                })),
+                output_schema: Some(serde_json::json!({
+                    "type": "object",
+                    "properties": {
+                        "valid": { "type": "boolean" },
+                        "claims": {
+                            "type": ["object", "null"],
+                            "description": "sub, workspace, email, roles, exp, iat and jti"
+                        },
+                        "error": { "type": ["string", "null"] }
+                    }
+                })),
+            },
+            MethodSignature {
+                name: "refresh".to_string(),
+                description: "Exchange a refresh token for new access and refresh tokens".to_string(),
+                input_schema: Some(serde_json::json!({
+                    "type": "object",
+                    "properties": {
+                        "refresh_token": { "type": "string" },
+                        "workspace": { "type": "string" },
+                        "ip": { "type": "string" }
+                    },
+                    "required": ["refresh_token"]
+                })),
+                output_schema: Some(serde_json::json!({
+                    "type": "object",
+                    "properties": {
+                        "valid": { "type": "boolean" },
+                        "token": { "type": ["string", "null"] },
+                        "expires_at": { "type": ["string", "null"] },
+                        "refresh_token": { "type": ["string", "null"] },
+                        "refresh_expires_at": { "type": ["string", "null"] },
+                        "session_id": { "type": ["string", "null"] },
+                        "error": { "type": ["string", "null"] }
+                    }
+                })),
+            },
+            MethodSignature {
+                name: "logout".to_string(),
+                description: "End the session of an access token".to_string(),
+                input_schema: Some(serde_json::json!({
+                    "type": "object",
+                    "properties": {
+                        "token": { "type": "string" }
+                    },
+                    "required": ["token"]
+                })),
+                output_schema: Some(serde_json::json!({
+                    "type": "object",
+                    "properties": {
+                        "revoked": { "type": "boolean" }
+                    }
+                })),
+            },
+            MethodSignature {
+                name: "logout_all".to_string(),
+                description: "End every session of the holder of an access token".to_string(),
+                input_schema: Some(serde_json::json!({
+                    "type": "object",
+                    "properties": {
+                        "token": { "type": "string" }
+                    },
+                    "required": ["token"]
                 })),
                 output_schema: Some(serde_json::json!({
+                    "type": "object",
+                    "properties": {
+                        "revoked": { "type": "number" }
+                    }
+                })),
+            },
+            MethodSignature {
+                name: "sessions".to_string(),
+                description: "List the active sessions of the holder of an access token".to_string(),
+                input_schema: Some(serde_json::json!({
+                    "type": "object",
+                    "properties": {
+                        "token": { "type": "string" }
+                    },
+                    "required": ["token"]
+                })),
+                output_schema: Some(serde_json::json!({
+                    "type": "object",
+                    "properties": {
+                        "sessions": {
+                            "type": "array",
+                            "description": "id, device, ip, created_at, last_used_at, expires_at and current"
+                        }
+                    }
+                })),
+            },
+            MethodSignature {
+                name: "revoke_session".to_string(),
+                description: "End one session of the holder of an access token".to_string(),
+                input_schema: Some(serde_json::json!({
+                    "type": "object",
+                    "properties": {
+                        "token": { "type": "string" },
+                        "session_id": { "type": "string" }
+                    },
+                    "required": ["token", "session_id"]
+                })),
+                output_schema: Some(serde_json::json!({
+                    "type": "object",
+                    "properties": {
+                        "revoked": { "type": "boolean" }
+                    }
+                })),
+            },
+            MethodSignature {
+                name: "generate_magic_link".to_string(),
+                description: "Generate a single-use sign-in link, limited like codes".to_string(),
+                input_schema: Some(serde_json::json!({
+                    "type": "object",
+                    "properties": {
+                        "email": { "type": "string" },
+                        "workspace": { "type": "string" },
+                        "ip": { "type": "string" },
+                        "url": {
+                            "type": "string",
+                            "description": "Page the link opens, given the token as `?token=`"
+                        }
+                    },
+                    "required": ["email"]
+                })),
+                output_schema: Some(serde_json::json!({
+                    "type": "object",
+                    "properties": {
+                        "token": { "type": "string" },
+                        "link": {
+                            "type": "string",
+                            "description": "The page with the token, when 'url' is given"
+                        },
+                        "expires_at": { "type": "string" }
+                    }
+                })),
+            },
+            MethodSignature {
+                name: "verify_magic_link".to_string(),
+                description: "Sign in with the token of a magic link, as verify_code does".to_string(),
+                input_schema: Some(serde_json::json!({
+                    "type": "object",
+                    "properties": {
+                        "token": { "type": "string" },
+                        "workspace": { "type": "string" },
+                        "device": { "type": "string" },
+                        "ip": { "type": "string" }
+                    },
+                    "required": ["token"]
+                })),
+                output_schema: Some(serde_json::json!({
+                    "type": "object",
+                    "description": "As verify_code, with the email signed in"
+                })),
+            },
+            MethodSignature {
+                name: "verify_two_factor".to_string(),
+                description: "Trade the challenge of a sign-in and a code of the user's app, or a recovery code, for the session".to_string(),
+                input_schema: Some(serde_json::json!({
+                    "type": "object",
+                    "properties": {
+                        "challenge": { "type": "string" },
+                        "code": { "type": "string" },
+                        "workspace": { "type": "string" },
+                        "ip": { "type": "string" }
+                    },
+                    "required": ["challenge", "code"]
+                })),
+                output_schema: Some(serde_json::json!({
+                    "type": "object",
+                    "description": "As verify_code, with recovery_codes_left when a recovery code was used"
+                })),
+            },
+            MethodSignature {
+                name: "enroll_two_factor".to_string(),
+                description: "Start enrolling an authenticator app, for the holder of an access token or of a sign-in challenge".to_string(),
+                input_schema: Some(serde_json::json!({
+                    "type": "object",
+                    "properties": {
+                        "token": { "type": "string" },
+                        "challenge": {
+                            "type": "string",
+                            "description": "Instead of a token, for users whose role requires a second factor they don't have yet"
+                        },
+                        "workspace": { "type": "string" },
+                        "issuer": {
+                            "type": "string",
+                            "description": "Name the app shows; the workspace when absent"
+                        }
+                    }
+                })),
+                output_schema: Some(serde_json::json!({
+                    "type": "object",
+                    "properties": {
+                        "secret": { "type": "string", "description": "Base32" },
+                        "uri": { "type": "string", "description": "otpauth:// URI" },
+                        "qr_svg": { "type": "string" },
+                        "recovery_codes": { "type": "array", "items": { "type": "string" } }
+                    }
+                })),
+            },
+            MethodSignature {
+                name: "confirm_two_factor".to_string(),
+                description: "Put an enrollment in effect with a first code of the app".to_string(),
+                input_schema: Some(serde_json::json!({
+                    "type": "object",
+                    "properties": {
+                        "token": { "type": "string" },
+                        "code": { "type": "string" }
+                    },
+                    "required": ["token", "code"]
+                })),
+                output_schema: Some(serde_json::json!({
+                    "type": "object",
+                    "properties": {
+                        "enabled": { "type": "boolean" }
+                    }
+                })),
+            },
+            MethodSignature {
+                name: "disable_two_factor".to_string(),
+                description: "Remove the second factor of the holder of an access token, unless their role requires one".to_string(),
+                input_schema: Some(serde_json::json!({
+                    "type": "object",
+                    "properties": {
+                        "token": { "type": "string" },
+                        "code": { "type": "string" }
+                    },
+                    "required": ["token", "code"]
+                })),
+                output_schema: Some(serde_json::json!({
+                    "type": "object",
+                    "properties": {
+                        "disabled": { "type": "boolean" }
+                    }
+                })),
+            },
+            MethodSignature {
+                name: "regenerate_recovery_codes".to_string(),
+                description: "Replace the recovery codes of the holder of an access token".to_string(),
+                input_schema: Some(serde_json::json!({
+                    "type": "object",
+                    "properties": {
+                        "token": { "type": "string" },
+                        "code": { "type": "string" }
+                    },
+                    "required": ["token", "code"]
+                })),
+                output_schema: Some(serde_json::json!({
+                    "type": "object",
+                    "properties": {
+                        "recovery_codes": { "type": "array", "items": { "type": "string" } }
+                    }
+                })),
+            },
+            MethodSignature {
+                name: "oidc_authorize".to_string(),
+                description: "Start signing in with an OpenID Connect provider of the workspace".to_string(),
+                input_schema: Some(serde_json::json!({
+                    "type": "object",
+                    "properties": {
+                        "provider": { "type": "string" },
+                        "workspace": { "type": "string" }
+                    },
+                    "required": ["provider"]
+                })),
+                output_schema: Some(serde_json::json!({
+                    "type": "object",
+                    "properties": {
+                        "url": { "type": "string", "description": "Where to send the browser" },
+                        "expires_at": { "type": "string" }
+                    }
+                })),
+            },
+            MethodSignature {
+                name: "oidc_callback".to_string(),
+                description: "Finish signing in, or linking, with the code and state the provider sent back".to_string(),
+                input_schema: Some(serde_json::json!({
+                    "type": "object",
+                    "properties": {
+                        "provider": { "type": "string" },
+                        "state": { "type": "string" },
+                        "code": { "type": "string" },
+                        "workspace": { "type": "string" },
+                        "device": { "type": "string" },
+                        "ip": { "type": "string" }
+                    },
+                    "required": ["provider", "state", "code"]
+                })),
+                output_schema: Some(serde_json::json!({
+                    "type": "object",
+                    "description": "As verify_code, with email and provider; or linked, provider and email when linking"
+                })),
+            },
+            MethodSignature {
+                name: "link_identity".to_string(),
+                description: "Start linking an account of an OpenID Connect provider to the holder of an access token".to_string(),
+                input_schema: Some(serde_json::json!({
                     "type": "object",
                     "properties": {
                         "token": { "type": "string" },
+                        "provider": { "type": "string" }
+                    },
+                    "required": ["token", "provider"]
+                })),
+                output_schema: Some(serde_json::json!({
+                    "type": "object",
+                    "properties": {
+                        "url": { "type": "string" },
                         "expires_at": { "type": "string" }
                     }
                 })),
@@ -156,6 +650,22 @@ impl Coprocessor for AuthCoprocessor {
             "verify_code" => self.verify_auth_code(args).await,
             "register" => self.register_user(args).await,
             "generate_token" => self.generate_jwt_token(args).await,
+            "verify_token" => self.verify_jwt_token(args).await,
+            "refresh" => self.refresh_session(args).await,
+            "logout" => self.logout(args).await,
+            "logout_all" => self.logout_all(args).await,
+            "sessions" => self.list_sessions(args).await,
+            "revoke_session" => self.revoke_session(args).await,
+            "generate_magic_link" => self.generate_magic_link(args).await,
+            "verify_magic_link" => self.verify_magic_link(args).await,
+            "verify_two_factor" => self.verify_two_factor(args).await,
+            "enroll_two_factor" => self.enroll_two_factor(args).await,
+            "confirm_two_factor" => self.confirm_two_factor(args).await,
+            "disable_two_factor" => self.disable_two_factor(args).await,
+            "regenerate_recovery_codes" => self.regenerate_recovery_codes(args).await,
+            "oidc_authorize" => self.oidc_authorize(args).await,
+            "oidc_callback" => self.oidc_callback(args).await,
+            "link_identity" => self.link_identity(args).await,
             _ => Err(CoprocessorError::MethodNotFound(method.to_string())),
         }
     }
@@ -167,15 +677,18 @@ impl Coprocessor for AuthCoprocessor {
 
 impl AuthCoprocessor {
     async fn generate_auth_code(&self, args: Data) -> Result<Data, CoprocessorError> {
-        let email = match args {
-            Data::Object(ref obj) => match obj.get("email") {
-                Some(Data::String(s)) => s.clone(),
-                _ => {
-                    return Err(CoprocessorError::InvalidArguments(
-                        "Missing or invalid 'email' field".to_string(),
-                    ))
-                }
-            },
+        let (email, workspace, ip) = match args {
+            Data::Object(ref obj) => {
+                let email = match obj.get("email") {
+                    Some(Data::String(s)) => s.clone(),
+                    _ => {
+                        return Err(CoprocessorError::InvalidArguments(
+                            "Missing or invalid 'email' field".to_string(),
+                        ))
+                    }
+                };
+                (email, self.workspace_of(obj), ip_of(obj))
+            }
             _ => {
                 return Err(CoprocessorError::InvalidArguments(
                     "Expected object with 'email' field".to_string(),
@@ -183,10 +696,10 @@ impl AuthCoprocessor {
             }
         };
         
-        let code = Self::generate_code();
-        let expires_at = chrono::Utc::now() + chrono::Duration::minutes(10);
-        
-        info!("Generated auth code for {}: {}", email, code);
+        let issued = self.codes.issue(&workspace, &email, ip.as_deref()).await;
+        let action = if issued.is_ok() { "auth.code_requested" } else { "auth.code_refused" };
+        self.audit(&workspace, action, &email, None, ip.as_deref()).await;
+        let (code, expires_at) = issued.map_err(code_error)?;
         
         let mut response = HashMap::new();
         response.insert("code".to_string(), Data::String(code));
@@ -196,7 +709,7 @@ impl AuthCoprocessor {
     }
     
     async fn verify_auth_code(&self, args: Data) -> Result<Data, CoprocessorError> {
-        let (email, code) = match args {
+        let (email, code, workspace, device, ip) = match args {
             Data::Object(ref obj) => {
                 let email = match obj.get("email") {
                     Some(Data::String(s)) => s.clone(),
@@ -216,7 +729,14 @@ impl AuthCoprocessor {
                     }
                 };
                 
-                (email, code)
+                let workspace = self.workspace_of(obj);
+                
+                let device = match obj.get("device") {
+                    Some(Data::String(s)) if !s.is_empty() => Some(s.clone()),
+                    _ => None,
+                };
+                
+                (email, code, workspace, device, ip_of(obj))
             }
             _ => {
                 return Err(CoprocessorError::InvalidArguments(
@@ -225,26 +745,22 @@ impl AuthCoprocessor {
             }
         };
         
-        // In a real implementation, this would check against stored codes
-        // For now, we'll accept any 6-digit code for testing
-        let valid = code.len() == 6 && code.chars().all(|c| c.is_numeric());
+        let verified = match self.codes.verify(&workspace, &email, &code).await {
+            Err(CodeError::Storage(e)) => return Err(CoprocessorError::ExecutionError(e)),
+            verified => verified,
+        };
         
-        let mut response = HashMap::new();
+        let response;
         
-        if valid {
-            // Generate a mock JWT token
-            let token = format!("jwt_token_for_{}", email);
-            response.insert("valid".to_string(), Data::Bool(true));
-            response.insert("token".to_string(), Data::String(token));
-            response.insert("error".to_string(), Data::Null);
-            
+        if verified.is_ok() {
             info!("Code verified successfully for {}", email);
+            response = self.sign_in(&workspace, &email, None, device.as_deref(), ip.as_deref()).await?;
         } else {
-            response.insert("valid".to_string(), Data::Bool(false));
-            response.insert("token".to_string(), Data::Null);
-            response.insert("error".to_string(), Data::String("Invalid code".to_string()));
+            let reason = verified.err().map(|e| e.to_string()).unwrap_or_default();
+            response = refusal(&reason);
             
-            error!("Invalid code for {}", email);
+            error!("Code refused for {}: {}", email, reason);
+            self.audit(&workspace, "auth.sign_in_failed", &email, None, ip.as_deref()).await;
         }
         
         Ok(Data::Object(response))
@@ -278,19 +794,23 @@ impl AuthCoprocessor {
                     _ => String::new(),
                 };
                 
+                // The account type is a role, so only those anyone may have
                 let account_type = match obj.get("accountType") {
-                    Some(Data::String(s)) => s.clone(),
-                    _ => "particulier".to_string(),
+                    Some(Data::String(s)) if users::ACCOUNT_TYPES.contains(&s.as_str()) => s.clone(),
+                    None => "particulier".to_string(),
+                    _ => {
+                        return Err(CoprocessorError::InvalidArguments(format!(
+                            "'accountType' must be one of {}",
+                            users::ACCOUNT_TYPES.join(", ")
+                        )))
+                    }
                 };
                 
-                let workspace = match obj.get("workspace") {
-                    Some(Data::String(s)) => s.clone(),
-                    _ => "autodin".to_string(),
-                };
+                let workspace = self.workspace_of(obj);
                 
                 // Build user data object
                 let mut user = HashMap::new();
-                user.insert("email".to_string(), Data::String(email.clone()));
+                user.insert("email".to_string(), Data::String(email.trim().to_lowercase()));
                 user.insert("firstName".to_string(), Data::String(first_name));
                 user.insert("lastName".to_string(), Data::String(last_name));
                 user.insert("phone".to_string(), Data::String(phone));
@@ -309,10 +829,25 @@ impl AuthCoprocessor {
             }
         };
         
+        let workspace = self.workspace_of(&user_data);
+        let email = match user_data.get("email") {
+            Some(Data::String(s)) => s.clone(),
+            _ => String::new(),
+        };
+        let ip = match &args {
+            Data::Object(obj) => ip_of(obj),
+            _ => None,
+        };
+        
         // Generate verification code
-        let code = Self::generate_code();
+        let (code, _) = self.codes.issue(&workspace, &email, ip.as_deref()).await
+            .map_err(code_error)?;
         
-        info!("Registered user: {:?}", user_data.get("email"));
+        info!("Registered user: {}", email);
+        self.audit(&workspace, "auth.registered", &email, None, ip.as_deref()).await;
+        let mut payload = HashMap::new();
+        payload.insert("user".to_string(), Data::Object(user_data.clone()));
+        self.publish("auth.user_registered", &workspace, payload);
         
         let mut response = HashMap::new();
         response.insert("user_data".to_string(), Data::Object(user_data));
@@ -321,56 +856,555 @@ impl AuthCoprocessor {
         Ok(Data::Object(response))
     }
     
+    /// Issues a token for a stored user, with their id and roles
     async fn generate_jwt_token(&self, args: Data) -> Result<Data, CoprocessorError> {
-        let (user_id, email, workspace) = match args {
-            Data::Object(ref obj) => {
-                let user_id = match obj.get("user_id") {
-                    Some(Data::String(s)) => s.clone(),
-                    _ => {
-                        return Err(CoprocessorError::InvalidArguments(
-                            "Missing or invalid 'user_id' field".to_string(),
-                        ))
-                    }
-                };
-                
-                let email = match obj.get("email") {
-                    Some(Data::String(s)) => s.clone(),
-                    _ => {
-                        return Err(CoprocessorError::InvalidArguments(
-                            "Missing or invalid 'email' field".to_string(),
-                        ))
-                    }
-                };
-                
-                let workspace = match obj.get("workspace") {
-                    Some(Data::String(s)) => s.clone(),
-                    _ => "autodin".to_string(),
-                };
-                
-                (user_id, email, workspace)
+        let obj = fields(&args, "email")?;
+        let email = required(obj, "email")?;
+        let workspace = self.workspace_of(obj);
+        let store = self.users.as_ref().ok_or_else(|| {
+            CoprocessorError::ExecutionError("No users to issue tokens for".to_string())
+        })?;
+        let user = users::by_email(store.as_ref(), &workspace, &email).await
+            .map_err(CoprocessorError::ExecutionError)?
+            .ok_or_else(|| CoprocessorError::ExecutionError(format!("No user {} in workspace {}", email, workspace)))?;
+        
+        let tokens = self.sessions.tokens();
+        let mut claims = tokens.claims(&user.id, &workspace);
+        claims.email = Some(email.trim().to_lowercase());
+        claims.roles = user.roles;
+        let token = tokens.sign(&claims).map_err(token_error)?;
+        
+        let mut response = HashMap::new();
+        response.insert("token".to_string(), Data::String(token));
+        response.insert("expires_at".to_string(), Data::String(expiry(claims.exp)));
+        
+        Ok(Data::Object(response))
+    }
+    
+    async fn verify_jwt_token(&self, args: Data) -> Result<Data, CoprocessorError> {
+        let token = match args {
+            Data::Object(ref obj) => match obj.get("token") {
+                Some(Data::String(s)) => s.clone(),
+                _ => {
+                    return Err(CoprocessorError::InvalidArguments(
+                        "Missing or invalid 'token' field".to_string(),
+                    ))
+                }
+            },
+            _ => {
+                return Err(CoprocessorError::InvalidArguments(
+                    "Expected object with 'token' field".to_string(),
+                ))
             }
+        };
+        
+        let mut response = HashMap::new();
+        match self.sessions.authenticate(&token).await {
+            Err(SessionError::Storage(e)) => return Err(CoprocessorError::ExecutionError(e)),
+            Ok(claims) => {
+                response.insert("valid".to_string(), Data::Bool(true));
+                response.insert("claims".to_string(), claims.to_data());
+                response.insert("error".to_string(), Data::Null);
+            }
+            Err(e) => {
+                response.insert("valid".to_string(), Data::Bool(false));
+                response.insert("claims".to_string(), Data::Null);
+                response.insert("error".to_string(), Data::String(e.to_string()));
+            }
+        }
+        
+        Ok(Data::Object(response))
+    }
+    
+    async fn refresh_session(&self, args: Data) -> Result<Data, CoprocessorError> {
+        let (refresh_token, workspace, ip) = match args {
+            Data::Object(ref obj) => match obj.get("refresh_token") {
+                Some(Data::String(s)) => (s.clone(), self.workspace_of(obj), ip_of(obj)),
+                _ => {
+                    return Err(CoprocessorError::InvalidArguments(
+                        "Missing or invalid 'refresh_token' field".to_string(),
+                    ))
+                }
+            },
             _ => {
                 return Err(CoprocessorError::InvalidArguments(
-                    "Expected object with 'user_id' and 'email' fields".to_string(),
+                    "Expected object with 'refresh_token' field".to_string(),
                 ))
             }
         };
         
-        // In a real implementation, this would use jsonwebtoken crate
-        // For now, create a mock token
-        let token = format!("jwt_{}_{}_{}_{}", 
-            user_id, 
-            email.replace('@', "_"), 
-            workspace,
-            chrono::Utc::now().timestamp()
-        );
+        match self.sessions.refresh(&workspace, &refresh_token, ip.as_deref()).await {
+            Ok(grant) => {
+                let session = Some(format!("sessions/{}", grant.session.id));
+                self.audit(&workspace, "auth.refreshed", &grant.claims.sub, session, ip.as_deref()).await;
+                Ok(Data::Object(grant_response(&grant)))
+            }
+            Err(e @ (SessionError::Storage(_) | SessionError::Token(_))) => Err(session_error(e)),
+            Err(e) => {
+                info!("Refresh refused in {}: {}", workspace, e);
+                self.audit(&workspace, "auth.refresh_refused", ANONYMOUS, None, ip.as_deref()).await;
+                Ok(Data::Object(refusal(&e.to_string())))
+            }
+        }
+    }
+    
+    async fn logout(&self, args: Data) -> Result<Data, CoprocessorError> {
+        let claims = self.caller(&args).await?;
+        let ended = self.sessions.logout(&claims).await.map_err(session_error)?;
+        let session = claims.sid.as_ref().map(|sid| format!("sessions/{}", sid));
+        self.audit(&claims.workspace, "auth.logged_out", &claims.sub, session, None).await;
+        
+        let mut response = HashMap::new();
+        response.insert("revoked".to_string(), Data::Bool(ended));
+        Ok(Data::Object(response))
+    }
+    
+    async fn logout_all(&self, args: Data) -> Result<Data, CoprocessorError> {
+        let claims = self.caller(&args).await?;
+        let ended = self.sessions.logout_all(&claims).await.map_err(session_error)?;
+        self.audit(&claims.workspace, "auth.logged_out_everywhere", &claims.sub, None, None).await;
         
-        let expires_at = chrono::Utc::now() + chrono::Duration::days(7);
+        let mut response = HashMap::new();
+        response.insert("revoked".to_string(), Data::Number(ended as f64));
+        Ok(Data::Object(response))
+    }
+    
+    async fn list_sessions(&self, args: Data) -> Result<Data, CoprocessorError> {
+        let claims = self.caller(&args).await?;
+        let sessions = self.sessions.list(&claims.workspace, &claims.sub).await.map_err(session_error)?;
         
+        let listed = sessions.iter()
+            .map(|session| {
+                let mut listed = session.to_json();
+                listed["current"] = serde_json::Value::Bool(claims.sid.as_deref() == Some(session.id.as_str()));
+                Data::from_json(listed)
+            })
+            .collect();
         let mut response = HashMap::new();
+        response.insert("sessions".to_string(), Data::Array(listed));
+        Ok(Data::Object(response))
+    }
+    
+    async fn revoke_session(&self, args: Data) -> Result<Data, CoprocessorError> {
+        let session_id = match &args {
+            Data::Object(obj) => match obj.get("session_id") {
+                Some(Data::String(s)) => s.clone(),
+                _ => {
+                    return Err(CoprocessorError::InvalidArguments(
+                        "Missing or invalid 'session_id' field".to_string(),
+                    ))
+                }
+            },
+            _ => {
+                return Err(CoprocessorError::InvalidArguments(
+                    "Expected object with 'token' and 'session_id' fields".to_string(),
+                ))
+            }
+        };
+        let claims = self.caller(&args).await?;
+        let revoked = self.sessions.revoke_for(&claims.workspace, &claims.sub, &session_id).await
+            .map_err(session_error)?;
+        if revoked {
+            let session = Some(format!("sessions/{}", session_id));
+            self.audit(&claims.workspace, "auth.session_revoked", &claims.sub, session, None).await;
+        }
+        
+        let mut response = HashMap::new();
+        response.insert("revoked".to_string(), Data::Bool(revoked));
+        Ok(Data::Object(response))
+    }
+    
+    /// A magic link for `email`, opening `url` if given; counts against the
+    /// same limits as codes
+    async fn generate_magic_link(&self, args: Data) -> Result<Data, CoprocessorError> {
+        let obj = fields(&args, "email")?;
+        let email = required(obj, "email")?;
+        let (workspace, ip) = (self.workspace_of(obj), ip_of(obj));
+        
+        let throttled = self.codes.throttle(&workspace, &email, ip.as_deref()).await;
+        let action = if throttled.is_ok() { "auth.magic_link_requested" } else { "auth.magic_link_refused" };
+        self.audit(&workspace, action, &email, None, ip.as_deref()).await;
+        throttled.map_err(code_error)?;
+        let (token, expires_at) = self.links.issue(&workspace, &email).await.map_err(link_error)?;
+        
+        let mut response = HashMap::new();
+        if let Some(url) = optional(obj, "url") {
+            response.insert("link".to_string(), Data::String(magic_links::link(&url, &token)));
+        }
         response.insert("token".to_string(), Data::String(token));
         response.insert("expires_at".to_string(), Data::String(expires_at.to_rfc3339()));
+        Ok(Data::Object(response))
+    }
+    
+    /// Sign in with the token of a magic link, as `verify_code` does with a code
+    async fn verify_magic_link(&self, args: Data) -> Result<Data, CoprocessorError> {
+        let obj = fields(&args, "token")?;
+        let token = required(obj, "token")?;
+        let (workspace, ip) = (self.workspace_of(obj), ip_of(obj));
+        
+        let response = match self.links.verify(&workspace, &token).await {
+            Ok(email) => {
+                info!("Magic link used by {}", email);
+                let device = optional(obj, "device");
+                let mut response = self.sign_in(&workspace, &email, None, device.as_deref(), ip.as_deref()).await?;
+                response.insert("email".to_string(), Data::String(email));
+                response
+            }
+            Err(e @ LinkError::Storage(_)) => return Err(link_error(e)),
+            Err(e) => {
+                info!("Magic link refused in {}: {}", workspace, e);
+                self.audit(&workspace, "auth.sign_in_failed", ANONYMOUS, None, ip.as_deref()).await;
+                refusal(&e.to_string())
+            }
+        };
+        Ok(Data::Object(response))
+    }
+    
+    /// Trade the challenge of a sign-in and a code of the user's app, or a
+    /// recovery code, for the session
+    async fn verify_two_factor(&self, args: Data) -> Result<Data, CoprocessorError> {
+        let obj = fields(&args, "challenge")?;
+        let (token, code) = (required(obj, "challenge")?, required(obj, "code")?);
+        let workspace = self.workspace_of(obj);
+        
+        // Who it is for, to log refusals by
+        let user = self.two_factor.pending(&workspace, &token).await.ok().map(|challenge| challenge.user);
+        let response = match self.two_factor.pass(&workspace, &token, &code).await {
+            Ok((challenge, factor)) => {
+                let mut claims = self.sessions.tokens().claims(&challenge.user, &challenge.workspace);
+                claims.email = challenge.email.clone();
+                claims.roles = challenge.roles.clone();
+                let mut response = self.start_session(claims, challenge.device.as_deref(), challenge.ip.as_deref()).await?;
+                if let Some(email) = challenge.email {
+                    response.insert("email".to_string(), Data::String(email));
+                }
+                if let Factor::RecoveryCode(left) = factor {
+                    response.insert("recovery_codes_left".to_string(), Data::Number(left as f64));
+                }
+                response
+            }
+            Err(e @ TwoFactorError::Storage(_)) => return Err(two_factor_error(e)),
+            Err(e) => {
+                info!("Second factor refused in {}: {}", workspace, e);
+                let actor = user.as_deref().unwrap_or(ANONYMOUS);
+                self.audit(&workspace, "auth.two_factor_failed", actor, None, ip_of(obj).as_deref()).await;
+                refusal(&e.to_string())
+            }
+        };
+        Ok(Data::Object(response))
+    }
+    
+    /// A new authenticator app secret and recovery codes for the holder of an
+    /// access token, or of a sign-in challenge when their role requires a
+    /// second factor they don't have yet
+    async fn enroll_two_factor(&self, args: Data) -> Result<Data, CoprocessorError> {
+        let obj = fields(&args, "token")?;
+        let (workspace, user, account) = match optional(obj, "challenge") {
+            Some(token) => {
+                let challenge = self.two_factor.pending(&self.workspace_of(obj), &token).await.map_err(two_factor_error)?;
+                let account = challenge.email.clone().unwrap_or_else(|| challenge.user.clone());
+                (challenge.workspace, challenge.user, account)
+            }
+            None => {
+                let claims = self.caller(&args).await?;
+                let account = claims.email.clone().unwrap_or_else(|| claims.sub.clone());
+                (claims.workspace, claims.sub, account)
+            }
+        };
+        let issuer = optional(obj, "issuer").unwrap_or_else(|| workspace.clone());
+        let setup = self.two_factor.enroll(&workspace, &user, &account, &issuer).await.map_err(two_factor_error)?;
+        
+        let mut response = HashMap::new();
+        response.insert("secret".to_string(), Data::String(setup.secret));
+        response.insert("uri".to_string(), Data::String(setup.uri));
+        response.insert("qr_svg".to_string(), Data::String(setup.qr_svg));
+        response.insert("recovery_codes".to_string(), strings(setup.recovery_codes));
+        Ok(Data::Object(response))
+    }
+    
+    /// Put the enrollment of the holder of an access token in effect with a
+    /// first code of their app
+    async fn confirm_two_factor(&self, args: Data) -> Result<Data, CoprocessorError> {
+        let code = required(fields(&args, "code")?, "code")?;
+        let claims = self.caller(&args).await?;
+        self.two_factor.confirm(&claims.workspace, &claims.sub, &code).await.map_err(two_factor_error)?;
+        
+        let mut response = HashMap::new();
+        response.insert("enabled".to_string(), Data::Bool(true));
+        Ok(Data::Object(response))
+    }
+    
+    /// Remove the second factor of the holder of an access token, given one
+    /// of their codes
+    async fn disable_two_factor(&self, args: Data) -> Result<Data, CoprocessorError> {
+        let code = required(fields(&args, "code")?, "code")?;
+        let claims = self.caller(&args).await?;
+        self.two_factor.disable(&claims.workspace, &claims.sub, &claims.roles, &code).await.map_err(two_factor_error)?;
+        
+        let mut response = HashMap::new();
+        response.insert("disabled".to_string(), Data::Bool(true));
+        Ok(Data::Object(response))
+    }
+    
+    /// New recovery codes for the holder of an access token, given one of
+    /// their codes
+    async fn regenerate_recovery_codes(&self, args: Data) -> Result<Data, CoprocessorError> {
+        let code = required(fields(&args, "code")?, "code")?;
+        let claims = self.caller(&args).await?;
+        let codes = self.two_factor.regenerate_recovery_codes(&claims.workspace, &claims.sub, &code).await
+            .map_err(two_factor_error)?;
         
+        let mut response = HashMap::new();
+        response.insert("recovery_codes".to_string(), strings(codes));
         Ok(Data::Object(response))
     }
-}
\ No newline at end of file
+    
+    /// Where to send the browser to sign in with a provider
+    async fn oidc_authorize(&self, args: Data) -> Result<Data, CoprocessorError> {
+        let obj = fields(&args, "provider")?;
+        let provider = required(obj, "provider")?;
+        let authorization = self.oidc.authorize(&self.workspace_of(obj), &provider, None).await.map_err(oidc_error)?;
+        Ok(Data::Object(authorization_response(authorization)))
+    }
+    
+    /// Where to send the browser of the holder of an access token to link an
+    /// account of a provider
+    async fn link_identity(&self, args: Data) -> Result<Data, CoprocessorError> {
+        let provider = required(fields(&args, "provider")?, "provider")?;
+        let claims = self.caller(&args).await?;
+        let authorization = self.oidc.authorize(&claims.workspace, &provider, Some(&claims.sub)).await.map_err(oidc_error)?;
+        Ok(Data::Object(authorization_response(authorization)))
+    }
+    
+    /// Sign in with the account the provider sent back, or link it to the
+    /// user who started linking
+    async fn oidc_callback(&self, args: Data) -> Result<Data, CoprocessorError> {
+        let obj = fields(&args, "provider")?;
+        let (provider, state, code) = (required(obj, "provider")?, required(obj, "state")?, required(obj, "code")?);
+        let (workspace, ip) = (self.workspace_of(obj), ip_of(obj));
+        
+        let response = match self.oidc.callback(&workspace, &provider, &state, &code).await {
+            Ok(callback) if callback.linked => {
+                let mut response = HashMap::new();
+                response.insert("linked".to_string(), Data::Bool(true));
+                response.insert("provider".to_string(), Data::String(provider));
+                response.insert("email".to_string(), callback.identity.email.map(Data::String).unwrap_or(Data::Null));
+                response
+            }
+            Ok(callback) => {
+                let identity = callback.identity;
+                info!("{} sign-in of {} in {}", provider, identity.user, workspace);
+                // Users known by email only are their email
+                let email = match &identity.email {
+                    _ if identity.user.contains('@') => identity.user.clone(),
+                    Some(email) => email.clone(),
+                    None => identity.user.clone(),
+                };
+                let device = optional(obj, "device");
+                let mut response = self.sign_in(&workspace, &email, Some(&identity.user), device.as_deref(), ip.as_deref()).await?;
+                response.insert("email".to_string(), Data::String(email));
+                response.insert("provider".to_string(), Data::String(provider));
+                response
+            }
+            Err(e @ OidcError::Storage(_)) => return Err(oidc_error(e)),
+            Err(e) => {
+                info!("{} sign-in refused in {}: {}", provider, workspace, e);
+                self.audit(&workspace, "auth.sign_in_failed", ANONYMOUS, None, ip.as_deref()).await;
+                refusal(&e.to_string())
+            }
+        };
+        Ok(Data::Object(response))
+    }
+    
+    /// Sign in a user whose code or link was right, or the user an identity
+    /// is `linked` to: a session, or a challenge if they have a second factor
+    /// or their role requires one
+    async fn sign_in(&self, workspace: &str, email: &str, linked: Option<&str>, device: Option<&str>, ip: Option<&str>) -> Result<HashMap<String, Data>, CoprocessorError> {
+        let email = email.trim().to_lowercase();
+        let user = self.user(workspace, &email, linked).await?;
+        let mut claims = self.sessions.tokens().claims(&user.id, workspace);
+        claims.email = Some(email);
+        claims.roles = user.roles;
+        
+        let enrolled = self.two_factor.is_enrolled(workspace, &claims.sub).await.map_err(two_factor_error)?;
+        if enrolled || self.two_factor.required(workspace, &claims.roles).await.map_err(two_factor_error)? {
+            let (token, challenge) = self.two_factor.challenge(&claims, device, ip).await.map_err(two_factor_error)?;
+            info!("Second factor asked of {} in {}", claims.sub, workspace);
+            self.audit(workspace, "auth.two_factor_required", &claims.sub, None, ip).await;
+            return Ok(challenge_response(&token, &challenge, enrolled));
+        }
+        self.start_session(claims, device, ip).await
+    }
+    
+    async fn start_session(&self, claims: Claims, device: Option<&str>, ip: Option<&str>) -> Result<HashMap<String, Data>, CoprocessorError> {
+        let workspace = claims.workspace.clone();
+        let email = claims.email.clone();
+        let grant = self.sessions.start(claims, device, ip).await.map_err(session_error)?;
+        let session = Some(format!("sessions/{}", grant.session.id));
+        self.audit(&workspace, "auth.signed_in", &grant.claims.sub, session, ip).await;
+        
+        if let Some(email) = email {
+            let mut payload = HashMap::new();
+            payload.insert("email".to_string(), Data::String(email));
+            self.publish("auth.user_verified", &workspace, payload);
+        }
+        Ok(grant_response(&grant))
+    }
+    
+    /// The user signing in with `email`, or the one an identity is `linked` to;
+    /// people without a document are their email, with no roles
+    async fn user(&self, workspace: &str, email: &str, linked: Option<&str>) -> Result<User, CoprocessorError> {
+        // Identities are linked to an email until their user has an id
+        let linked = linked.filter(|linked| !linked.contains('@'));
+        let found = match (&self.users, linked) {
+            (None, _) => None,
+            (Some(store), Some(id)) => users::by_id(store.as_ref(), workspace, id).await
+                .map_err(CoprocessorError::ExecutionError)?,
+            (Some(store), None) => users::by_email(store.as_ref(), workspace, email).await
+                .map_err(CoprocessorError::ExecutionError)?,
+        };
+        Ok(found.unwrap_or_else(|| User {
+            id: linked.unwrap_or(email).to_string(),
+            email: Some(email.to_string()),
+            roles: Vec::new(),
+        }))
+    }
+    
+    /// The claims of the access token in `args`, refusing revoked ones
+    async fn caller(&self, args: &Data) -> Result<Claims, CoprocessorError> {
+        let token = match args {
+            Data::Object(obj) => match obj.get("token") {
+                Some(Data::String(s)) => s.clone(),
+                _ => {
+                    return Err(CoprocessorError::InvalidArguments(
+                        "Missing or invalid 'token' field".to_string(),
+                    ))
+                }
+            },
+            _ => {
+                return Err(CoprocessorError::InvalidArguments(
+                    "Expected object with 'token' field".to_string(),
+                ))
+            }
+        };
+        self.sessions.authenticate(&token).await.map_err(session_error)
+    }
+}
+
+/// The fields of `args`, an object with at least `name`
+fn fields<'a>(args: &'a Data, name: &str) -> Result<&'a HashMap<String, Data>, CoprocessorError> {
+    match args {
+        Data::Object(obj) => Ok(obj),
+        _ => Err(CoprocessorError::InvalidArguments(format!("Expected object with '{}' field", name))),
+    }
+}
+
+fn required(obj: &HashMap<String, Data>, name: &str) -> Result<String, CoprocessorError> {
+    match obj.get(name) {
+        Some(Data::String(s)) => Ok(s.clone()),
+        _ => Err(CoprocessorError::InvalidArguments(format!("Missing or invalid '{}' field", name))),
+    }
+}
+
+fn optional(obj: &HashMap<String, Data>, name: &str) -> Option<String> {
+    match obj.get(name) {
+        Some(Data::String(s)) if !s.is_empty() => Some(s.clone()),
+        _ => None,
+    }
+}
+
+fn strings(values: Vec<String>) -> Data {
+    Data::Array(values.into_iter().map(Data::String).collect())
+}
+
+fn ip_of(obj: &HashMap<String, Data>) -> Option<String> {
+    match obj.get("ip") {
+        Some(Data::String(s)) if !s.is_empty() => Some(s.clone()),
+        _ => None,
+    }
+}
+
+fn expiry(exp: i64) -> String {
+    chrono::DateTime::from_timestamp(exp, 0).unwrap_or_default().to_rfc3339()
+}
+
+fn token_error(e: TokenError) -> CoprocessorError {
+    CoprocessorError::ExecutionError(format!("Failed to sign token: {}", e))
+}
+
+fn session_error(e: SessionError) -> CoprocessorError {
+    match e {
+        SessionError::Storage(e) => CoprocessorError::ExecutionError(format!("Session storage failed: {}", e)),
+        other => CoprocessorError::ExecutionError(other.to_string()),
+    }
+}
+
+/// The tokens of a started or refreshed session
+fn grant_response(grant: &Grant) -> HashMap<String, Data> {
+    let mut response = HashMap::new();
+    response.insert("valid".to_string(), Data::Bool(true));
+    response.insert("token".to_string(), Data::String(grant.access_token.clone()));
+    response.insert("expires_at".to_string(), Data::String(expiry(grant.claims.exp)));
+    response.insert("refresh_token".to_string(), Data::String(grant.refresh_token.clone()));
+    response.insert("refresh_expires_at".to_string(), Data::String(grant.session.expires_at.to_rfc3339()));
+    response.insert("session_id".to_string(), Data::String(grant.session.id.clone()));
+    response.insert("error".to_string(), Data::Null);
+    response
+}
+
+fn refusal(reason: &str) -> HashMap<String, Data> {
+    let mut response = HashMap::new();
+    response.insert("valid".to_string(), Data::Bool(false));
+    for field in ["token", "expires_at", "refresh_token", "refresh_expires_at", "session_id"] {
+        response.insert(field.to_string(), Data::Null);
+    }
+    response.insert("error".to_string(), Data::String(reason.to_string()));
+    response
+}
+
+/// A sign-in held until its second factor is given
+fn challenge_response(token: &str, challenge: &Challenge, enrolled: bool) -> HashMap<String, Data> {
+    let mut response = refusal("Second factor required");
+    response.insert("two_factor_required".to_string(), Data::Bool(true));
+    response.insert("challenge".to_string(), Data::String(token.to_string()));
+    response.insert("challenge_expires_at".to_string(), Data::String(challenge.expires_at.to_rfc3339()));
+    // Those not enrolled yet enroll with the challenge first
+    response.insert("enrolled".to_string(), Data::Bool(enrolled));
+    response
+}
+
+fn link_error(e: LinkError) -> CoprocessorError {
+    match e {
+        LinkError::Storage(e) => CoprocessorError::ExecutionError(format!("Magic link storage failed: {}", e)),
+        other => CoprocessorError::ExecutionError(other.to_string()),
+    }
+}
+
+fn two_factor_error(e: TwoFactorError) -> CoprocessorError {
+    match e {
+        TwoFactorError::Storage(e) => CoprocessorError::ExecutionError(format!("Two-factor storage failed: {}", e)),
+        other => CoprocessorError::ExecutionError(other.to_string()),
+    }
+}
+
+fn authorization_response(authorization: Authorization) -> HashMap<String, Data> {
+    let mut response = HashMap::new();
+    response.insert("url".to_string(), Data::String(authorization.url));
+    response.insert("expires_at".to_string(), Data::String(authorization.expires_at.to_rfc3339()));
+    response
+}
+
+fn oidc_error(e: OidcError) -> CoprocessorError {
+    match e {
+        OidcError::Storage(e) => CoprocessorError::ExecutionError(format!("Identity storage failed: {}", e)),
+        OidcError::UnknownProvider(_) => CoprocessorError::InvalidArguments(e.to_string()),
+        other => CoprocessorError::ExecutionError(other.to_string()),
+    }
+}
+
+fn code_error(e: CodeError) -> CoprocessorError {
+    match e {
+        CodeError::Storage(e) => CoprocessorError::ExecutionError(format!("Failed to issue code: {}", e)),
+        other => CoprocessorError::ExecutionError(other.to_string()),
+    }
+}
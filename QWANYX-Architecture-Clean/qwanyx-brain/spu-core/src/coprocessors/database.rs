@@ -1,73 +1,150 @@
 //! Database Coprocessor
 //! 
-//! Handles MongoDB operations
+//! Handles document storage over a `DocumentStore` - MongoDB in production, the
+//! in-memory or embedded stores for tests and local development
 //! Pure data storage and retrieval, no business logic
+//!
+//! `aggregate` runs read-only aggregation pipelines for reports; `$out` and
+//! `$merge` are refused and results are capped by `limit`.
+//!
+//! Collections with a schema (`set_schema`) get its defaults on insert, and
+//! inserts and updates that don't match it are refused. Updates are checked by
+//! applying them to the documents they match before writing.
+//!
+//! With a search engine (`with_search`), `search` runs full-text searches over
+//! the collections configured with `configure_search`; see `crate::search`.
+//!
+//! Scripts run for a user (`SPURuntime::execute_as`) get what the workspace's
+//! policy grants them (see `crate::auth::policy`): reads and writes are limited
+//! to the documents they own where the policy says so, hidden fields are left
+//! out of results, and schemas, search and the policy itself (`set_policy`)
+//! are for admins. Scripts running for no one - the server's own, confined to
+//! a workspace - read and write freely, but are no one's admin.
+//!
+//! With a workspace registry (`with_workspaces`), calls naming a workspace it
+//! does not list are refused, and calls naming none go to its default.
+//!
+//! With an audit log (`with_audit`), every write is logged with the fields it
+//! changed, as part of its transaction if it has one; see `crate::audit`. The
+//! log itself cannot be written to.
+//!
+//! Writes with an `idempotency_key` are made once: the result is recorded in
+//! the same transaction as the write, and repeats get it back without writing
+//! again; see `crate::store::idempotency`.
 
 use crate::{Coprocessor, CoprocessorError, Data, Health, MethodSignature};
+use crate::audit::{AuditEntry, AuditLog, AUDIT_COLLECTION};
+use crate::auth::policy::{self, Access, Caller, Grant, Operation, Policy, PolicyError, POLICY_COLLECTION, POLICY_ID};
+use crate::events::{Event, EventBus};
+use crate::search::{SearchConfig, SearchEngine, SearchError, SearchRequest};
+use crate::store::{idempotency, pipeline, query, schema, DocumentStore, FindOneAndUpdateOptions, FindOptions, MongoStore, Namespace, UpdateOptions, query::remove_path};
+use crate::workspaces::Workspaces;
 use async_trait::async_trait;
-use mongodb::{Client as MongoClient, Collection, bson::{doc, Document, Bson, oid::ObjectId}};
+use mongodb::bson::{doc, Document, Bson, oid::ObjectId};
 use std::collections::HashMap;
-use std::sync::Arc;
-use tracing::{error, info};
+use std::sync::{Arc, Mutex};
+use tracing::{error, info, warn};
+
+/// Documents `aggregate` returns unless told otherwise
+const DEFAULT_AGGREGATE_LIMIT: usize = 1000;
+
+/// Most documents `aggregate` returns in one call
+const MAX_AGGREGATE_LIMIT: usize = 10_000;
+
+/// Invalid documents `validate_collection` lists unless told otherwise
+const DEFAULT_REPORT_LIMIT: usize = 100;
+
+/// Documents an update_many loads at once to check them against the schema
+const UPDATE_CHECK_BATCH: usize = 500;
+
+/// Methods writing to the collection they name
+const WRITES: [&str; 6] = ["store", "update", "update_many", "insert_many", "find_one_and_update", "delete"];
 
 /// Database Coprocessor
-pub struct DatabaseCoprocessor {
-    client: Option<Arc<MongoClient>>,
+pub struct DatabaseCoprocessor<S: DocumentStore = MongoStore> {
+    store: S,
     database_name: String,
+    events: Option<EventBus>,
+    search: Option<Arc<SearchEngine>>,
+    workspaces: Option<Arc<Workspaces>>,
+    audit: Option<Arc<AuditLog>>,
+    /// Events of open transactions, published on commit
+    pending: Mutex<HashMap<String, Vec<Event>>>,
 }
 
-impl DatabaseCoprocessor {
+impl DatabaseCoprocessor<MongoStore> {
+    /// MongoDB backed; call `connect` before use
     pub fn new() -> Self {
-        // Try to connect to MongoDB
-        let mongo_uri = std::env::var("MONGO_URI")
-            .unwrap_or_else(|_| "mongodb://localhost:27017/?serverSelectionTimeoutMS=5000".to_string());
-        
+        Self::with_store(MongoStore::new())
+    }
+    
+    pub async fn connect(&mut self) -> Result<(), String> {
+        self.store.connect().await
+    }
+}
+
+impl<S: DocumentStore> DatabaseCoprocessor<S> {
+    pub fn with_store(store: S) -> Self {
         // Default database name - will be overridden by workspace when needed
         let database_name = std::env::var("DB_NAME")
             .unwrap_or_else(|_| "autodin".to_string());
         
-        // We'll connect lazily or in an async init method
         Self {
-            client: None,
+            store,
             database_name,
+            events: None,
+            search: None,
+            workspaces: None,
+            audit: None,
+            pending: Mutex::new(HashMap::new()),
         }
     }
     
-    pub async fn connect(&mut self) -> Result<(), String> {
-        let mongo_uri = std::env::var("MONGO_URI")
-            .unwrap_or_else(|_| "mongodb://localhost:27017/?serverSelectionTimeoutMS=5000".to_string());
-        
-        match MongoClient::with_uri_str(&mongo_uri).await {
-            Ok(client) => {
-                info!("Connected to MongoDB");
-                self.client = Some(Arc::new(client));
-                Ok(())
-            }
-            Err(e) => {
-                error!("Failed to connect to MongoDB: {}", e);
-                Err(format!("MongoDB connection failed: {}", e))
-            }
-        }
+    /// Publish `database.inserted` / `updated` / `deleted` events on this bus
+    pub fn with_events(mut self, events: EventBus) -> Self {
+        self.events = Some(events);
+        self
+    }
+    
+    /// Serve `search` and the search configuration methods with this engine
+    pub fn with_search(mut self, search: Arc<SearchEngine>) -> Self {
+        self.search = Some(search);
+        self
+    }
+    
+    /// Only serve the workspaces of this registry, defaulting to its default
+    pub fn with_workspaces(mut self, workspaces: Arc<Workspaces>) -> Self {
+        self.database_name = workspaces.default_workspace().id.clone();
+        self.workspaces = Some(workspaces);
+        self
+    }
+    
+    /// Log every write in this audit log, which must keep its entries in the
+    /// same store for writes in transactions to be logged
+    pub fn with_audit(mut self, audit: Arc<AuditLog>) -> Self {
+        self.audit = Some(audit);
+        self
     }
     
-    fn get_collection(&self, collection_name: &str) -> Result<Collection<Document>, String> {
-        match &self.client {
-            Some(client) => {
-                Ok(client.database(&self.database_name).collection(collection_name))
+    fn publish(&self, name: &str, workspace: &str, payload: HashMap<String, Data>, transaction: Option<&str>) {
+        if let Some(events) = &self.events {
+            let event = Event::new(name, workspace, Data::Object(payload));
+            match transaction {
+                Some(id) => self.pending.lock().unwrap().entry(id.to_string()).or_default().push(event),
+                None => events.publish(event),
             }
-            None => Err("Not connected to database".to_string())
         }
     }
 }
 
-impl Default for DatabaseCoprocessor {
+impl Default for DatabaseCoprocessor<MongoStore> {
     fn default() -> Self {
         Self::new()
     }
 }
 
 #[async_trait]
-impl Coprocessor for DatabaseCoprocessor {
+impl<S: DocumentStore> Coprocessor for DatabaseCoprocessor<S> {
     fn class_name(&self) -> String {
         "database".to_string()
     }
@@ -87,7 +164,8 @@ impl Coprocessor for DatabaseCoprocessor {
                         "data": {
                             "type": "object",
                             "description": "Data to store"
-                        }
+                        },
+                        "transaction": { "type": "string" }
                     },
                     "required": ["collection", "data"]
                 })),
@@ -113,41 +191,168 @@ impl Coprocessor for DatabaseCoprocessor {
                         "collection": { "type": "string" },
                         "filter": {
                             "type": "object",
-                            "description": "MongoDB filter query"
-                        }
+                            "description": "MongoDB filter query, operators like $in, $gt, $regex and $and included"
+                        },
+                        "projection": {
+                            "type": "object",
+                            "description": "Fields to include (1) or exclude (0)"
+                        },
+                        "sort": {
+                            "type": ["array", "string", "object"],
+                            "description": "Sort keys, e.g. [\"-createdAt\", \"name\"] or \"-createdAt,name\""
+                        },
+                        "limit": { "type": "number" },
+                        "skip": { "type": "number" },
+                        "cursor": {
+                            "type": "string",
+                            "description": "next_cursor from a previous page with the same filter and sort"
+                        },
+                        "transaction": { "type": "string" }
                     },
-                    "required": ["collection", "filter"]
+                    "required": ["collection"]
                 })),
                 output_schema: Some(serde_json::json!({
                     "type": "object",
                     "properties": {
                         "data": {
-                            "type": ["object", "null"],
-                            "description": "Retrieved document"
+                            "type": "array",
+                            "description": "Retrieved documents"
                         },
                         "found": {
                             "type": "boolean"
-                        }
+                        },
+                        "count": {
+                            "type": "number",
+                            "description": "Documents in this page"
+                        },
+                        "total": {
+                            "type": "number",
+                            "description": "Documents matching the filter"
+                        },
+                        "has_more": { "type": "boolean" },
+                        "next_cursor": { "type": ["string", "null"] }
+                    }
+                })),
+            },
+            MethodSignature {
+                name: "count".to_string(),
+                description: "Count documents matching a filter".to_string(),
+                input_schema: Some(serde_json::json!({
+                    "type": "object",
+                    "properties": {
+                        "collection": { "type": "string" },
+                        "filter": { "type": "object" },
+                        "transaction": { "type": "string" }
+                    },
+                    "required": ["collection"]
+                })),
+                output_schema: Some(serde_json::json!({
+                    "type": "object",
+                    "properties": {
+                        "count": { "type": "number" }
                     }
                 })),
             },
             MethodSignature {
                 name: "update".to_string(),
-                description: "Update data in MongoDB".to_string(),
+                description: "Update the first matching document".to_string(),
+                input_schema: Some(update_schema()),
+                output_schema: Some(update_output_schema()),
+            },
+            MethodSignature {
+                name: "update_many".to_string(),
+                description: "Update every matching document".to_string(),
+                input_schema: Some(update_schema()),
+                output_schema: Some(update_output_schema()),
+            },
+            MethodSignature {
+                name: "insert_many".to_string(),
+                description: "Store several documents at once".to_string(),
+                input_schema: Some(serde_json::json!({
+                    "type": "object",
+                    "properties": {
+                        "collection": { "type": "string" },
+                        "documents": {
+                            "type": "array",
+                            "items": { "type": "object" }
+                        },
+                        "transaction": { "type": "string" }
+                    },
+                    "required": ["collection", "documents"]
+                })),
+                output_schema: Some(serde_json::json!({
+                    "type": "object",
+                    "properties": {
+                        "ids": { "type": "array" },
+                        "inserted": { "type": "number" },
+                        "success": { "type": "boolean" }
+                    }
+                })),
+            },
+            MethodSignature {
+                name: "find_one_and_update".to_string(),
+                description: "Atomically update one document and return it, for counters and locks".to_string(),
                 input_schema: Some(serde_json::json!({
                     "type": "object",
                     "properties": {
                         "collection": { "type": "string" },
                         "filter": { "type": "object" },
-                        "update": { "type": "object" }
+                        "update": {
+                            "type": "object",
+                            "description": "Update operators, e.g. {\"$inc\": {\"seq\": 1}}"
+                        },
+                        "sort": {
+                            "type": ["array", "string", "object"],
+                            "description": "Which document to update when several match"
+                        },
+                        "projection": { "type": "object" },
+                        "upsert": { "type": "boolean" },
+                        "return": {
+                            "type": "string",
+                            "enum": ["before", "after"],
+                            "description": "Return the document as it was or as updated (default)"
+                        },
+                        "transaction": { "type": "string" }
                     },
                     "required": ["collection", "filter", "update"]
                 })),
                 output_schema: Some(serde_json::json!({
                     "type": "object",
                     "properties": {
-                        "matched": { "type": "number" },
-                        "modified": { "type": "number" }
+                        "found": { "type": "boolean" },
+                        "data": { "type": ["object", "null"] }
+                    }
+                })),
+            },
+            MethodSignature {
+                name: "aggregate".to_string(),
+                description: "Run a read-only aggregation pipeline".to_string(),
+                input_schema: Some(serde_json::json!({
+                    "type": "object",
+                    "properties": {
+                        "collection": { "type": "string" },
+                        "pipeline": {
+                            "type": "array",
+                            "items": { "type": "object" },
+                            "description": "Stages such as $match, $group, $sort and $lookup; $out and $merge are not allowed"
+                        },
+                        "limit": {
+                            "type": "number",
+                            "description": "Most documents to return (default 1000, at most 10000)"
+                        },
+                        "transaction": { "type": "string" }
+                    },
+                    "required": ["collection", "pipeline"]
+                })),
+                output_schema: Some(serde_json::json!({
+                    "type": "object",
+                    "properties": {
+                        "data": { "type": "array" },
+                        "count": { "type": "number" },
+                        "truncated": {
+                            "type": "boolean",
+                            "description": "Whether the pipeline produced more documents than the limit"
+                        }
                     }
                 })),
             },
@@ -158,7 +363,8 @@ impl Coprocessor for DatabaseCoprocessor {
                     "type": "object",
                     "properties": {
                         "collection": { "type": "string" },
-                        "filter": { "type": "object" }
+                        "filter": { "type": "object" },
+                        "transaction": { "type": "string" }
                     },
                     "required": ["collection", "filter"]
                 })),
@@ -169,32 +375,339 @@ impl Coprocessor for DatabaseCoprocessor {
                     }
                 })),
             },
+            MethodSignature {
+                name: "set_schema".to_string(),
+                description: "Register the JSON Schema documents of a collection must match".to_string(),
+                input_schema: Some(serde_json::json!({
+                    "type": "object",
+                    "properties": {
+                        "collection": { "type": "string" },
+                        "schema": {
+                            "type": "object",
+                            "description": "JSON Schema with \"type\": \"object\"; see store::schema for the keywords"
+                        }
+                    },
+                    "required": ["collection", "schema"]
+                })),
+                output_schema: None,
+            },
+            MethodSignature {
+                name: "get_schema".to_string(),
+                description: "The schema of a collection".to_string(),
+                input_schema: Some(collection_schema()),
+                output_schema: Some(serde_json::json!({
+                    "type": "object",
+                    "properties": {
+                        "found": { "type": "boolean" },
+                        "schema": { "type": ["object", "null"] }
+                    }
+                })),
+            },
+            MethodSignature {
+                name: "list_schemas".to_string(),
+                description: "The schemas of every collection of a workspace".to_string(),
+                input_schema: None,
+                output_schema: Some(serde_json::json!({
+                    "type": "object",
+                    "properties": {
+                        "schemas": { "type": "array" }
+                    }
+                })),
+            },
+            MethodSignature {
+                name: "delete_schema".to_string(),
+                description: "Stop enforcing a schema on a collection".to_string(),
+                input_schema: Some(collection_schema()),
+                output_schema: Some(serde_json::json!({
+                    "type": "object",
+                    "properties": {
+                        "deleted": { "type": "boolean" }
+                    }
+                })),
+            },
+            MethodSignature {
+                name: "validate_collection".to_string(),
+                description: "Check the documents already in a collection against its schema".to_string(),
+                input_schema: Some(serde_json::json!({
+                    "type": "object",
+                    "properties": {
+                        "collection": { "type": "string" },
+                        "limit": {
+                            "type": "number",
+                            "description": "Most invalid documents to list (default 100)"
+                        }
+                    },
+                    "required": ["collection"]
+                })),
+                output_schema: Some(serde_json::json!({
+                    "type": "object",
+                    "properties": {
+                        "checked": { "type": "number" },
+                        "valid": { "type": "number" },
+                        "invalid": { "type": "number" },
+                        "documents": {
+                            "type": "array",
+                            "description": "Invalid documents: their _id and what is wrong with them"
+                        }
+                    }
+                })),
+            },
+            MethodSignature {
+                name: "search".to_string(),
+                description: "Full-text search of a collection configured with configure_search, best matches first".to_string(),
+                input_schema: Some(serde_json::json!({
+                    "type": "object",
+                    "properties": {
+                        "collection": { "type": "string" },
+                        "query": { "type": "string", "description": "Words to look for; empty matches everything" },
+                        "filters": { "type": "object", "description": "Values hits must have, by facet" },
+                        "facets": { "type": "array", "items": { "type": "string" }, "description": "Facets to count" },
+                        "limit": { "type": "number", "description": "Hits to return (default 20, at most 100)" },
+                        "offset": { "type": "number" },
+                        "fuzzy": { "type": "boolean", "description": "Match words with typos (default true)" },
+                        "mode": { "type": "string", "enum": ["all", "any"], "description": "Whether hits need every word (default) or any" }
+                    },
+                    "required": ["collection"]
+                })),
+                output_schema: Some(serde_json::json!({
+                    "type": "object",
+                    "properties": {
+                        "hits": {
+                            "type": "array",
+                            "description": "id, score, document and highlights - matched fields with <mark>ed words"
+                        },
+                        "total": { "type": "number" },
+                        "facets": { "type": "object", "description": "Per facet, values and their counts" }
+                    }
+                })),
+            },
+            MethodSignature {
+                name: "configure_search".to_string(),
+                description: "Make a collection searchable, or change how it is searched".to_string(),
+                input_schema: Some(serde_json::json!({
+                    "type": "object",
+                    "properties": {
+                        "collection": { "type": "string" },
+                        "config": {
+                            "type": "object",
+                            "properties": {
+                                "fields": { "type": "array", "items": { "type": "string" } },
+                                "boosts": { "type": "object", "description": "Weight per field, 1 by default" },
+                                "facets": { "type": "array", "items": { "type": "string" } },
+                                "language": { "type": "string", "enum": ["french", "english"] }
+                            },
+                            "required": ["fields"]
+                        }
+                    },
+                    "required": ["collection", "config"]
+                })),
+                output_schema: None,
+            },
+            MethodSignature {
+                name: "get_search".to_string(),
+                description: "How a collection is searched".to_string(),
+                input_schema: Some(collection_schema()),
+                output_schema: Some(serde_json::json!({
+                    "type": "object",
+                    "properties": {
+                        "found": { "type": "boolean" },
+                        "config": { "type": ["object", "null"] }
+                    }
+                })),
+            },
+            MethodSignature {
+                name: "drop_search".to_string(),
+                description: "Stop searching a collection and drop its index".to_string(),
+                input_schema: Some(collection_schema()),
+                output_schema: Some(serde_json::json!({
+                    "type": "object",
+                    "properties": {
+                        "deleted": { "type": "boolean" }
+                    }
+                })),
+            },
+            MethodSignature {
+                name: "reindex_search".to_string(),
+                description: "Rebuild the search index of a collection from its documents".to_string(),
+                input_schema: Some(collection_schema()),
+                output_schema: Some(serde_json::json!({
+                    "type": "object",
+                    "properties": {
+                        "indexed": { "type": "number" }
+                    }
+                })),
+            },
+            MethodSignature {
+                name: "begin".to_string(),
+                description: "Start a transaction; pass its id as 'transaction' to other methods".to_string(),
+                input_schema: None,
+                output_schema: Some(serde_json::json!({
+                    "type": "object",
+                    "properties": {
+                        "transaction": { "type": "string" }
+                    }
+                })),
+            },
+            MethodSignature {
+                name: "set_policy".to_string(),
+                description: "Replace the access policy of the workspace (admins only)".to_string(),
+                input_schema: Some(serde_json::json!({
+                    "type": "object",
+                    "properties": {
+                        "policy": {
+                            "type": "object",
+                            "description": "Admin roles and read/write rules per collection, see auth::policy"
+                        }
+                    },
+                    "required": ["policy"]
+                })),
+                output_schema: None,
+            },
+            MethodSignature {
+                name: "get_policy".to_string(),
+                description: "The access policy of the workspace (admins only)".to_string(),
+                input_schema: None,
+                output_schema: Some(serde_json::json!({
+                    "type": "object",
+                    "properties": {
+                        "policy": { "type": "object" },
+                        "default": {
+                            "type": "boolean",
+                            "description": "Whether no policy was set and the default one applies"
+                        }
+                    }
+                })),
+            },
+            MethodSignature {
+                name: "commit".to_string(),
+                description: "Apply everything written in a transaction".to_string(),
+                input_schema: Some(transaction_schema()),
+                output_schema: None,
+            },
+            MethodSignature {
+                name: "abort".to_string(),
+                description: "Discard everything written in a transaction".to_string(),
+                input_schema: Some(transaction_schema()),
+                output_schema: None,
+            },
         ]
     }
 
     async fn invoke(&self, method: &str, args: Data) -> Result<Data, CoprocessorError> {
-        match method {
-            "store" => self.store_data(args).await,
-            "retrieve" => self.retrieve_data(args).await,
-            "update" => self.update_data(args).await,
-            "delete" => self.delete_data(args).await,
-            _ => Err(CoprocessorError::MethodNotFound(method.to_string())),
+        self.invoke_as(method, args, None).await
+    }
+    
+    async fn invoke_as(&self, method: &str, args: Data, caller: Option<&Caller>) -> Result<Data, CoprocessorError> {
+        if let (Some(workspaces), Data::Object(obj)) = (&self.workspaces, &args) {
+            let workspace = self.workspace_of(obj);
+            if workspaces.get(&workspace).is_none() {
+                return Err(CoprocessorError::InvalidArguments(format!("Unknown workspace '{}'", workspace)));
+            }
+        }
+        if let Data::Object(obj) = &args {
+            if WRITES.contains(&method) && matches!(obj.get("collection"), Some(Data::String(c)) if c == AUDIT_COLLECTION) {
+                return Err(CoprocessorError::Forbidden("The audit log cannot be written to".to_string()));
+            }
+        }
+        match idempotency::key_of(&args) {
+            Some(key) if WRITES.contains(&method) => self.write_once(method, args, caller, &key).await,
+            _ => self.dispatch(method, args, caller).await,
         }
     }
 
     async fn health(&self) -> Health {
-        match &self.client {
-            Some(_) => Health::Healthy,
-            None => Health::Unhealthy {
-                error: "Not connected to MongoDB".to_string(),
-            },
+        match self.store.health().await {
+            Ok(()) => Health::Healthy,
+            Err(error) => Health::Unhealthy { error },
         }
     }
 }
 
-impl DatabaseCoprocessor {
-    async fn store_data(&self, args: Data) -> Result<Data, CoprocessorError> {
-        let (collection_name, data, workspace) = match args {
+impl<S: DocumentStore> DatabaseCoprocessor<S> {
+    async fn dispatch(&self, method: &str, args: Data, caller: Option<&Caller>) -> Result<Data, CoprocessorError> {
+        match method {
+            "store" => self.store_data(args, caller).await,
+            "retrieve" => self.retrieve_data(args, caller).await,
+            "count" => self.count_data(args, caller).await,
+            "update" => self.update_data(args, false, caller).await,
+            "update_many" => self.update_data(args, true, caller).await,
+            "insert_many" => self.insert_many(args, caller).await,
+            "find_one_and_update" => self.find_one_and_update(args, caller).await,
+            "delete" => self.delete_data(args, caller).await,
+            "aggregate" => self.aggregate(args, caller).await,
+            "set_schema" => self.set_schema(args, caller).await,
+            "get_schema" => self.get_schema(args, caller).await,
+            "list_schemas" => self.list_schemas(args, caller).await,
+            "delete_schema" => self.delete_schema(args, caller).await,
+            "validate_collection" => self.validate_collection(args, caller).await,
+            "search" => self.search(args, caller).await,
+            "configure_search" => self.configure_search(args, caller).await,
+            "get_search" => self.get_search(args, caller).await,
+            "drop_search" => self.drop_search(args, caller).await,
+            "reindex_search" => self.reindex_search(args, caller).await,
+            "set_policy" => self.set_policy(args, caller).await,
+            "get_policy" => self.get_policy(args, caller).await,
+            "begin" => self.begin().await,
+            "commit" => self.end_transaction(args, true).await,
+            "abort" => self.end_transaction(args, false).await,
+            _ => Err(CoprocessorError::MethodNotFound(method.to_string())),
+        }
+    }
+    
+    /// A write retried with the same `key` is made once: in the caller's
+    /// transaction or one of its own, together with the record of its result
+    async fn write_once(&self, method: &str, args: Data, caller: Option<&Caller>, key: &str) -> Result<Data, CoprocessorError> {
+        let Data::Object(mut obj) = args else {
+            return self.dispatch(method, args, caller).await;
+        };
+        obj.remove("idempotency_key");
+        let workspace = self.workspace_of(&obj);
+        let failed = |e: String| {
+            error!("{}", e);
+            CoprocessorError::ExecutionError(e)
+        };
+        
+        let ns = Namespace::new(&workspace, idempotency::IDEMPOTENCY_COLLECTION);
+        if let Some(result) = idempotency::recorded(&self.store, ns, key).await.map_err(failed)? {
+            info!("{} {} was already made, not writing again", method, key);
+            return Ok(result);
+        }
+        
+        let own = match transaction_of(&obj)? {
+            Some(_) => None,
+            None => match self.store.begin().await {
+                Ok(transaction) => Some(transaction),
+                // Without transactions, a crash between the write and its record writes twice
+                Err(e) => {
+                    warn!("Recording {} {} after it, not with it: {}", method, key, e);
+                    None
+                }
+            },
+        };
+        if let Some(transaction) = &own {
+            obj.insert("transaction".to_string(), Data::String(transaction.clone()));
+        }
+        let transaction = transaction_of(&obj)?;
+        let written = match self.dispatch(method, Data::Object(obj), caller).await {
+            Ok(result) => {
+                let ns = Namespace::new(&workspace, idempotency::IDEMPOTENCY_COLLECTION).in_transaction(transaction.as_deref());
+                idempotency::record(&self.store, ns, key, method, &result).await
+                    .map(|_| result)
+                    .map_err(failed)
+            }
+            Err(e) => Err(e),
+        };
+        
+        let Some(transaction) = own else {
+            return written;
+        };
+        let mut end = HashMap::new();
+        end.insert("transaction".to_string(), Data::String(transaction));
+        self.end_transaction(Data::Object(end), written.is_ok()).await?;
+        written
+    }
+    async fn store_data(&self, args: Data, caller: Option<&Caller>) -> Result<Data, CoprocessorError> {
+        let (collection_name, data, workspace, transaction) = match args {
             Data::Object(ref obj) => {
                 let collection = match obj.get("collection") {
                     Some(Data::String(s)) => s.clone(),
@@ -220,7 +733,7 @@ impl DatabaseCoprocessor {
                     _ => self.database_name.clone()
                 };
                 
-                (collection, data, workspace)
+                (collection, data, workspace, transaction_of(obj)?)
             }
             _ => {
                 return Err(CoprocessorError::InvalidArguments(
@@ -231,20 +744,8 @@ impl DatabaseCoprocessor {
         
         info!("Storing to workspace '{}', collection '{}': {:?}", workspace, collection_name, data);
         
-        // Get the MongoDB client
-        let client = match &self.client {
-            Some(c) => c,
-            None => {
-                return Err(CoprocessorError::ServiceUnavailable)
-            }
-        };
-        
-        // Use the workspace as the database name
-        let db = client.database(&workspace);
-        let collection: Collection<Document> = db.collection(&collection_name);
-        
         // Convert Data to BSON Document
-        let document = match self.data_to_document(&data) {
+        let mut document = match self.data_to_document(&data) {
             Ok(doc) => doc,
             Err(e) => {
                 return Err(CoprocessorError::InvalidArguments(
@@ -253,16 +754,43 @@ impl DatabaseCoprocessor {
             }
         };
         
+        let grant = self.grant(caller, &workspace, &collection_name, Operation::Write).await?;
+        let claimed = grant.admit(&mut document).map_err(forbidden)?;
+        
+        // Schema defaults and the owner are part of what was stored
+        let schema = self.schema_of(&workspace, &collection_name).await?;
+        if let Some(schema) = &schema {
+            conform(schema, &collection_name, &mut document)?;
+        }
+        let data = match document_to_data(&document) {
+            Data::Object(stored) if schema.is_some() || claimed => stored,
+            _ => data,
+        };
+        
         // Insert the document
-        match collection.insert_one(document, None).await {
-            Ok(result) => {
-                let id = match result.inserted_id {
+        // The workspace is the database
+        let ns = Namespace::new(&workspace, &collection_name).in_transaction(transaction.as_deref());
+        let audited = self.audit.is_some().then(|| document.clone());
+        match self.store.insert(ns, document).await {
+            Ok(inserted_id) => {
+                if let Some(mut inserted) = audited {
+                    inserted.insert("_id", inserted_id.clone());
+                    self.audit(ns, "database.inserted", caller, None, Some(&inserted)).await;
+                }
+                
+                let id = match inserted_id {
                     Bson::ObjectId(oid) => oid.to_hex(),
                     _ => "unknown".to_string(),
                 };
                 
                 info!("Successfully stored document with ID: {}", id);
                 
+                let mut payload = HashMap::new();
+                payload.insert("collection".to_string(), Data::String(collection_name.clone()));
+                payload.insert("id".to_string(), Data::String(id.clone()));
+                payload.insert("document".to_string(), Data::Object(data));
+                self.publish("database.inserted", &workspace, payload, transaction.as_deref());
+                
                 let mut response = HashMap::new();
                 response.insert("id".to_string(), Data::String(id));
                 response.insert("success".to_string(), Data::Bool(true));
@@ -278,8 +806,8 @@ impl DatabaseCoprocessor {
         }
     }
     
-    async fn retrieve_data(&self, args: Data) -> Result<Data, CoprocessorError> {
-        let (collection_name, filter, workspace) = match args {
+    async fn retrieve_data(&self, args: Data, caller: Option<&Caller>) -> Result<Data, CoprocessorError> {
+        let (collection_name, mut query, workspace, transaction) = match args {
             Data::Object(ref obj) => {
                 let collection = match obj.get("collection") {
                     Some(Data::String(s)) => s.clone(),
@@ -290,14 +818,8 @@ impl DatabaseCoprocessor {
                     }
                 };
                 
-                let filter = match obj.get("filter") {
-                    Some(Data::Object(f)) => f.clone(),
-                    _ => {
-                        return Err(CoprocessorError::InvalidArguments(
-                            "Missing or invalid 'filter' field".to_string(),
-                        ))
-                    }
-                };
+                let query = FindQuery::parse(obj)
+                    .map_err(CoprocessorError::InvalidArguments)?;
                 
                 // Get workspace from arguments, default to database name if not provided
                 let workspace = match obj.get("workspace") {
@@ -305,97 +827,134 @@ impl DatabaseCoprocessor {
                     _ => self.database_name.clone()
                 };
                 
-                (collection, filter, workspace)
+                (collection, query, workspace, transaction_of(obj)?)
             }
             _ => {
                 return Err(CoprocessorError::InvalidArguments(
-                    "Expected object with 'collection' and 'filter' fields".to_string(),
+                    "Expected object with 'collection' field".to_string(),
                 ))
             }
         };
         
-        info!("Retrieving from workspace '{}', collection '{}' with filter: {:?}", workspace, collection_name, filter);
+        let grant = self.grant(caller, &workspace, &collection_name, Operation::Read).await?;
+        query.restrict(&grant).map_err(forbidden)?;
         
-        // Get the MongoDB client
-        let client = match &self.client {
-            Some(c) => c.clone(),
-            None => {
-                return Err(CoprocessorError::ExecutionError(
-                    "Database not connected".to_string()
-                ))
+        info!("Retrieving from workspace '{}', collection '{}' with filter: {}", workspace, collection_name, query.filter);
+        
+        // The workspace is the database - this is how the workspace system works!
+        let ns = Namespace::new(&workspace, &collection_name).in_transaction(transaction.as_deref());
+        let total = match self.store.count(ns, query.filter.clone()).await {
+            Ok(n) => n,
+            Err(e) => {
+                error!("Failed to count documents: {}", e);
+                return Err(CoprocessorError::ExecutionError(format!("Query failed: {}", e)))
             }
         };
         
-        // Use the workspace as the database name - this is how the workspace system works!
-        let db = client.database(&workspace);
-        let collection: Collection<Document> = db.collection(&collection_name);
-        
-        // Convert filter to BSON
-        use mongodb::bson::doc;
-        let bson_filter = doc! {}; // For now, empty filter to get all documents
+        let (filter, options, hidden) = query.find_options();
         
         // Query the collection
-        use futures::stream::TryStreamExt;
-        let cursor = match collection.find(bson_filter, None).await {
-            Ok(c) => c,
+        let mut results = match self.store.find(ns, filter, options).await {
+            Ok(docs) => docs,
             Err(e) => {
                 error!("Failed to query collection: {}", e);
                 return Err(CoprocessorError::ExecutionError(format!("Query failed: {}", e)))
             }
         };
         
-        // Collect results
-        let results: Vec<Document> = match cursor.try_collect().await {
-            Ok(docs) => docs,
-            Err(e) => {
-                error!("Failed to collect results: {}", e);
-                return Err(CoprocessorError::ExecutionError(format!("Failed to collect results: {}", e)))
+        // One extra document was fetched to know whether there is a next page
+        let has_more = query.limit.is_some_and(|limit| results.len() as i64 > limit);
+        let next_cursor = match (has_more, query.limit) {
+            (true, Some(limit)) => {
+                results.truncate(limit as usize);
+                results.last().map(|last| query.cursor_after(last))
             }
+            _ => None,
         };
         
-        // Convert documents to Data::Array
-        let data_array: Vec<Data> = results.iter().map(|doc| {
-            // Convert BSON Document to Data::Object
-            let mut obj = HashMap::new();
-            for (key, value) in doc.iter() {
-                // Convert BSON value to Data
-                let data_value = match value {
-                    mongodb::bson::Bson::String(s) => Data::String(s.clone()),
-                    mongodb::bson::Bson::Int32(i) => Data::Number(*i as f64),
-                    mongodb::bson::Bson::Int64(i) => Data::Number(*i as f64),
-                    mongodb::bson::Bson::Double(d) => Data::Number(*d),
-                    mongodb::bson::Bson::Boolean(b) => Data::Bool(*b),
-                    mongodb::bson::Bson::ObjectId(oid) => Data::String(oid.to_hex()),
-                    mongodb::bson::Bson::DateTime(dt) => Data::String(dt.to_string()),
-                    mongodb::bson::Bson::Null => Data::Null,
-                    mongodb::bson::Bson::Array(arr) => {
-                        // Skip complex arrays for now, just count them
-                        Data::Number(arr.len() as f64)
-                    },
-                    mongodb::bson::Bson::Document(_) => {
-                        // Skip nested documents for now
-                        Data::Null
-                    },
-                    _ => Data::Null,
-                };
-                obj.insert(key.clone(), data_value);
+        for doc in results.iter_mut() {
+            for path in &hidden {
+                remove_path(doc, path);
             }
-            Data::Object(obj)
-        }).collect();
+            grant.hide(doc);
+        }
+        
+        // Convert documents to Data::Array
+        let data_array: Vec<Data> = results.iter().map(document_to_data).collect();
         
-        info!("Retrieved {} documents from collection '{}'", data_array.len(), collection_name);
+        info!("Retrieved {} of {} documents from collection '{}'", data_array.len(), total, collection_name);
         
         // Return the results
         let mut response = HashMap::new();
-        response.insert("data".to_string(), Data::Array(data_array.clone()));
         response.insert("found".to_string(), Data::Bool(!data_array.is_empty()));
         response.insert("count".to_string(), Data::Number(data_array.len() as f64));
+        response.insert("total".to_string(), Data::Number(total as f64));
+        response.insert("has_more".to_string(), Data::Bool(has_more));
+        response.insert("next_cursor".to_string(), next_cursor.map(Data::String).unwrap_or(Data::Null));
+        response.insert("data".to_string(), Data::Array(data_array));
         
         Ok(Data::Object(response))
     }
     
-    async fn update_data(&self, args: Data) -> Result<Data, CoprocessorError> {
-        let (collection_name, filter, update, workspace) = match args {
+    async fn count_data(&self, args: Data, caller: Option<&Caller>) -> Result<Data, CoprocessorError> {
+        let (collection_name, filter, workspace, transaction) = match args {
+            Data::Object(ref obj) => {
+                let collection = match obj.get("collection") {
+                    Some(Data::String(s)) => s.clone(),
+                    _ => {
+                        return Err(CoprocessorError::InvalidArguments(
+                            "Missing or invalid 'collection' field".to_string(),
+                        ))
+                    }
+                };
+                
+                let filter = match obj.get("filter") {
+                    Some(Data::Object(f)) => filter_to_document(f)
+                        .map_err(CoprocessorError::InvalidArguments)?,
+                    None | Some(Data::Null) => Document::new(),
+                    _ => {
+                        return Err(CoprocessorError::InvalidArguments(
+                            "Invalid 'filter' field".to_string(),
+                        ))
+                    }
+                };
+                
+                let workspace = match obj.get("workspace") {
+                    Some(Data::String(s)) => s.clone(),
+                    _ => self.database_name.clone()
+                };
+                
+                (collection, filter, workspace, transaction_of(obj)?)
+            }
+            _ => {
+                return Err(CoprocessorError::InvalidArguments(
+                    "Expected object with 'collection' field".to_string(),
+                ))
+            }
+        };
+        
+        let grant = self.grant(caller, &workspace, &collection_name, Operation::Read).await?;
+        grant.check_filter(&filter).map_err(forbidden)?;
+        let filter = grant.restrict(filter);
+        
+        let ns = Namespace::new(&workspace, &collection_name).in_transaction(transaction.as_deref());
+        match self.store.count(ns, filter).await {
+            Ok(count) => {
+                let mut response = HashMap::new();
+                response.insert("count".to_string(), Data::Number(count as f64));
+                Ok(Data::Object(response))
+            }
+            Err(e) => {
+                error!("Failed to count documents: {}", e);
+                Err(CoprocessorError::ExecutionError(format!("Count failed: {}", e)))
+            }
+        }
+    }
+    
+    /// `update` and `update_many`; plain fields in `update` are set, operators
+    /// such as `$inc` or `$push` apply as they are
+    async fn update_data(&self, args: Data, many: bool, caller: Option<&Caller>) -> Result<Data, CoprocessorError> {
+        let (collection_name, filter, update, workspace, options, transaction) = match args {
             Data::Object(ref obj) => {
                 let collection = match obj.get("collection") {
                     Some(Data::String(s)) => s.clone(),
@@ -424,13 +983,18 @@ impl DatabaseCoprocessor {
                     }
                 };
                 
-                // Get workspace from args (default to autodin)
+                // Get workspace from arguments, default to database name if not provided
                 let workspace = match obj.get("workspace") {
                     Some(Data::String(s)) => s.clone(),
-                    _ => "autodin".to_string(),
+                    _ => self.database_name.clone(),
+                };
+                
+                let options = UpdateOptions {
+                    many: flag(obj, "many", many)?,
+                    upsert: flag(obj, "upsert", false)?,
                 };
                 
-                (collection, filter, update, workspace)
+                (collection, filter, update, workspace, options, transaction_of(obj)?)
             }
             _ => {
                 return Err(CoprocessorError::InvalidArguments(
@@ -439,35 +1003,49 @@ impl DatabaseCoprocessor {
             }
         };
         
-        // Use workspace-specific database
-        let client = self.client.as_ref()
-            .ok_or_else(|| CoprocessorError::ExecutionError("Database not connected".to_string()))?;
-        
-        let db = client.database(&workspace);
-        let collection = db.collection::<Document>(&collection_name);
-        
         // Convert filter to MongoDB document
-        let filter_doc = self.data_to_document(&filter)
-            .map_err(|e| CoprocessorError::ExecutionError(format!("Failed to convert filter: {}", e)))?;
+        let filter_doc = filter_to_document(&filter)
+            .map_err(|e| CoprocessorError::InvalidArguments(format!("Failed to convert filter: {}", e)))?;
         
         // Convert update data to MongoDB document
-        let update_doc = self.data_to_document(&update)
-            .map_err(|e| CoprocessorError::ExecutionError(format!("Failed to convert update: {}", e)))?;
+        let mut update_operation = self.update_to_document(&update)
+            .map_err(|e| CoprocessorError::InvalidArguments(format!("Failed to convert update: {}", e)))?;
         
-        // Create the update operation with $set
-        let update_operation = doc! {
-            "$set": update_doc
-        };
+        // What the filter matches shows in the counts, so it may only use readable fields
+        let readable = self.grant(caller, &workspace, &collection_name, Operation::Read).await?;
+        let grant = self.grant(caller, &workspace, &collection_name, Operation::Write).await?;
+        readable.check_filter(&filter_doc).map_err(forbidden)?;
+        grant.check_update(&update_operation).map_err(forbidden)?;
+        let filter_doc = grant.restrict(filter_doc);
         
         // Execute update
-        match collection.update_one(filter_doc, update_operation, None).await {
+        let ns = Namespace::new(&workspace, &collection_name).in_transaction(transaction.as_deref());
+        let (many, upsert) = (options.many, options.upsert);
+        self.check_update(ns, &filter_doc, &mut update_operation, many, None, upsert).await?;
+        let before = self.audited(ns, &filter_doc, FindOptions { limit: (!many).then_some(1), ..FindOptions::default() }).await?;
+        match self.store.update(ns, filter_doc, update_operation, options).await {
             Ok(result) => {
+                self.audit_changes(ns, "database.updated", caller, &before, result.upserted_id.clone()).await;
+                
+                let upserted_id = result.upserted_id.as_ref().map(id_to_data).unwrap_or(Data::Null);
+                
                 let mut response = HashMap::new();
-                response.insert("matched".to_string(), Data::Number(result.matched_count as f64));
-                response.insert("modified".to_string(), Data::Number(result.modified_count as f64));
+                response.insert("matched".to_string(), Data::Number(result.matched as f64));
+                response.insert("modified".to_string(), Data::Number(result.modified as f64));
+                response.insert("upserted_id".to_string(), upserted_id.clone());
                 response.insert("success".to_string(), Data::Bool(true));
                 
-                info!("Updated {} documents in {}", result.modified_count, collection_name);
+                info!("Updated {} documents in {}", result.modified, collection_name);
+                
+                if result.modified > 0 || result.upserted_id.is_some() {
+                    let mut payload = HashMap::new();
+                    payload.insert("collection".to_string(), Data::String(collection_name.clone()));
+                    payload.insert("filter".to_string(), Data::Object(filter));
+                    payload.insert("update".to_string(), Data::Object(update));
+                    payload.insert("modified".to_string(), Data::Number(result.modified as f64));
+                    payload.insert("upserted_id".to_string(), upserted_id);
+                    self.publish("database.updated", &workspace, payload, transaction.as_deref());
+                }
                 Ok(Data::Object(response))
             }
             Err(e) => {
@@ -483,8 +1061,8 @@ impl DatabaseCoprocessor {
         }
     }
     
-    async fn delete_data(&self, args: Data) -> Result<Data, CoprocessorError> {
-        let (collection_name, filter, workspace) = match args {
+    async fn delete_data(&self, args: Data, caller: Option<&Caller>) -> Result<Data, CoprocessorError> {
+        let (collection_name, filter, workspace, transaction) = match args {
             Data::Object(ref obj) => {
                 let collection = match obj.get("collection") {
                     Some(Data::String(s)) => s.clone(),
@@ -504,13 +1082,13 @@ impl DatabaseCoprocessor {
                     }
                 };
                 
-                // Get workspace from args (default to autodin)
+                // Get workspace from arguments, default to database name if not provided
                 let workspace = match obj.get("workspace") {
                     Some(Data::String(s)) => s.clone(),
-                    _ => "autodin".to_string(),
+                    _ => self.database_name.clone(),
                 };
                 
-                (collection, filter, workspace)
+                (collection, filter, workspace, transaction_of(obj)?)
             }
             _ => {
                 return Err(CoprocessorError::InvalidArguments(
@@ -519,25 +1097,37 @@ impl DatabaseCoprocessor {
             }
         };
         
-        // Use workspace-specific database
-        let client = self.client.as_ref()
-            .ok_or_else(|| CoprocessorError::ExecutionError("Database not connected".to_string()))?;
-        
-        let db = client.database(&workspace);
-        let collection = db.collection::<Document>(&collection_name);
-        
         // Convert filter to MongoDB document
-        let filter_doc = self.data_to_document(&filter)
-            .map_err(|e| CoprocessorError::ExecutionError(format!("Failed to convert filter: {}", e)))?;
+        let filter_doc = filter_to_document(&filter)
+            .map_err(|e| CoprocessorError::InvalidArguments(format!("Failed to convert filter: {}", e)))?;
+        
+        let readable = self.grant(caller, &workspace, &collection_name, Operation::Read).await?;
+        let grant = self.grant(caller, &workspace, &collection_name, Operation::Write).await?;
+        readable.check_filter(&filter_doc).map_err(forbidden)?;
+        let filter_doc = grant.restrict(filter_doc);
         
         // Execute delete
-        match collection.delete_many(filter_doc, None).await {
-            Ok(result) => {
+        let ns = Namespace::new(&workspace, &collection_name).in_transaction(transaction.as_deref());
+        let before = self.audited(ns, &filter_doc, FindOptions::default()).await?;
+        match self.store.delete(ns, filter_doc).await {
+            Ok(deleted_count) => {
+                for document in &before {
+                    self.audit(ns, "database.deleted", caller, Some(document), None).await;
+                }
+                
                 let mut response = HashMap::new();
-                response.insert("deleted_count".to_string(), Data::Number(result.deleted_count as f64));
+                response.insert("deleted_count".to_string(), Data::Number(deleted_count as f64));
                 response.insert("success".to_string(), Data::Bool(true));
                 
-                info!("Deleted {} documents from {}", result.deleted_count, collection_name);
+                info!("Deleted {} documents from {}", deleted_count, collection_name);
+                
+                if deleted_count > 0 {
+                    let mut payload = HashMap::new();
+                    payload.insert("collection".to_string(), Data::String(collection_name.clone()));
+                    payload.insert("filter".to_string(), Data::Object(filter));
+                    payload.insert("deleted".to_string(), Data::Number(deleted_count as f64));
+                    self.publish("database.deleted", &workspace, payload, transaction.as_deref());
+                }
                 Ok(Data::Object(response))
             }
             Err(e) => {
@@ -552,58 +1142,1404 @@ impl DatabaseCoprocessor {
         }
     }
     
-    fn data_to_document(&self, data: &HashMap<String, Data>) -> Result<Document, String> {
-        let mut doc = Document::new();
-        
-        for (key, value) in data.iter() {
-            match value {
-                Data::String(s) => {
-                    // Special handling for _id field - convert to ObjectId
-                    if key == "_id" {
-                        match ObjectId::parse_str(s) {
-                            Ok(oid) => {
-                                doc.insert(key.clone(), oid);
-                            }
-                            Err(_) => {
-                                // If it's not a valid ObjectId, treat as string
-                                doc.insert(key.clone(), s.clone());
-                            }
-                        }
-                    } else {
-                        doc.insert(key.clone(), s.clone());
-                    }
-                }
-                Data::Number(n) => {
-                    doc.insert(key.clone(), *n);
-                }
-                Data::Bool(b) => {
-                    doc.insert(key.clone(), *b);
-                }
-                Data::Array(arr) => {
-                    let bson_array: Vec<Bson> = arr.iter().map(|item| {
-                        match item {
-                            Data::String(s) => Bson::String(s.clone()),
-                            Data::Number(n) => Bson::Double(*n),
-                            Data::Bool(b) => Bson::Boolean(*b),
-                            _ => Bson::Null,
-                        }
-                    }).collect();
-                    doc.insert(key.clone(), bson_array);
-                }
-                Data::Object(obj) => {
-                    if let Ok(nested_doc) = self.data_to_document(obj) {
-                        doc.insert(key.clone(), nested_doc);
-                    }
-                }
-                Data::Null => {
-                    doc.insert(key.clone(), Bson::Null);
-                }
-                Data::ObjectRef(obj_id) => {
-                    doc.insert(key.clone(), Bson::String(obj_id.0.clone()));
-                }
+    async fn insert_many(&self, args: Data, caller: Option<&Caller>) -> Result<Data, CoprocessorError> {
+        let obj = match args {
+            Data::Object(obj) => obj,
+            _ => {
+                return Err(CoprocessorError::InvalidArguments(
+                    "Expected object with 'collection' and 'documents' fields".to_string(),
+                ))
+            }
+        };
+        let collection_name = collection_of(&obj)?;
+        let workspace = self.workspace_of(&obj);
+        let transaction = transaction_of(&obj)?;
+        
+        let documents = match obj.get("documents") {
+            Some(Data::Array(items)) if !items.is_empty() => items.iter()
+                .map(|item| match item {
+                    Data::Object(d) => Ok(d.clone()),
+                    _ => Err(CoprocessorError::InvalidArguments(
+                        "Every entry of 'documents' must be an object".to_string(),
+                    )),
+                })
+                .collect::<Result<Vec<_>, _>>()?,
+            _ => {
+                return Err(CoprocessorError::InvalidArguments(
+                    "Missing or invalid 'documents' field".to_string(),
+                ))
             }
+        };
+        
+        let mut bson_documents = documents.iter()
+            .map(|d| self.data_to_document(d))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| CoprocessorError::InvalidArguments(format!("Failed to convert data to BSON: {}", e)))?;
+        
+        let grant = self.grant(caller, &workspace, &collection_name, Operation::Write).await?;
+        let mut claimed = false;
+        for document in bson_documents.iter_mut() {
+            claimed |= grant.admit(document).map_err(forbidden)?;
         }
         
-        Ok(doc)
+        let schema = self.schema_of(&workspace, &collection_name).await?;
+        if let Some(schema) = &schema {
+            for (index, document) in bson_documents.iter_mut().enumerate() {
+                conform(schema, &collection_name, document).map_err(|e| match e {
+                    CoprocessorError::InvalidArguments(message) => CoprocessorError::InvalidArguments(
+                        format!("{} (document {})", message, index + 1),
+                    ),
+                    other => other,
+                })?;
+            }
+        }
+        let documents = if schema.is_some() || claimed {
+            bson_documents.iter()
+                .map(|d| match document_to_data(d) {
+                    Data::Object(stored) => stored,
+                    _ => HashMap::new(),
+                })
+                .collect()
+        } else {
+            documents
+        };
+        
+        let ns = Namespace::new(&workspace, &collection_name).in_transaction(transaction.as_deref());
+        let audited = if self.audit.is_some() { bson_documents.clone() } else { Vec::new() };
+        let ids = self.store.insert_many(ns, bson_documents).await.map_err(|e| {
+            error!("Failed to store documents: {}", e);
+            CoprocessorError::ExecutionError(format!("Failed to store documents: {}", e))
+        })?;
+        for (mut inserted, id) in audited.into_iter().zip(&ids) {
+            inserted.insert("_id", id.clone());
+            self.audit(ns, "database.inserted", caller, None, Some(&inserted)).await;
+        }
+        
+        info!("Stored {} documents in {}", ids.len(), collection_name);
+        
+        let ids: Vec<Data> = ids.iter().map(id_to_data).collect();
+        for (id, document) in ids.iter().zip(documents) {
+            let mut payload = HashMap::new();
+            payload.insert("collection".to_string(), Data::String(collection_name.clone()));
+            payload.insert("id".to_string(), id.clone());
+            payload.insert("document".to_string(), Data::Object(document));
+            self.publish("database.inserted", &workspace, payload, transaction.as_deref());
+        }
+        
+        let mut response = HashMap::new();
+        response.insert("inserted".to_string(), Data::Number(ids.len() as f64));
+        response.insert("ids".to_string(), Data::Array(ids));
+        response.insert("success".to_string(), Data::Bool(true));
+        
+        Ok(Data::Object(response))
+    }
+    
+    async fn find_one_and_update(&self, args: Data, caller: Option<&Caller>) -> Result<Data, CoprocessorError> {
+        let obj = match args {
+            Data::Object(obj) => obj,
+            _ => {
+                return Err(CoprocessorError::InvalidArguments(
+                    "Expected object with 'collection', 'filter', and 'update' fields".to_string(),
+                ))
+            }
+        };
+        let collection_name = collection_of(&obj)?;
+        let workspace = self.workspace_of(&obj);
+        let transaction = transaction_of(&obj)?;
+        
+        let filter = match obj.get("filter") {
+            Some(Data::Object(f)) => f.clone(),
+            _ => {
+                return Err(CoprocessorError::InvalidArguments(
+                    "Missing or invalid 'filter' field".to_string(),
+                ))
+            }
+        };
+        let update = match obj.get("update") {
+            Some(Data::Object(u)) => u.clone(),
+            _ => {
+                return Err(CoprocessorError::InvalidArguments(
+                    "Missing or invalid 'update' field".to_string(),
+                ))
+            }
+        };
+        
+        let filter_doc = filter_to_document(&filter)
+            .map_err(|e| CoprocessorError::InvalidArguments(format!("Failed to convert filter: {}", e)))?;
+        let update_doc = self.update_to_document(&update)
+            .map_err(|e| CoprocessorError::InvalidArguments(format!("Failed to convert update: {}", e)))?;
+        
+        let sort = parse_sort(obj.get("sort")).map_err(CoprocessorError::InvalidArguments)?;
+        let return_updated = match obj.get("return") {
+            Some(Data::String(s)) if s == "after" => true,
+            Some(Data::String(s)) if s == "before" => false,
+            None | Some(Data::Null) => true,
+            _ => {
+                return Err(CoprocessorError::InvalidArguments(
+                    "'return' must be \"before\" or \"after\"".to_string(),
+                ))
+            }
+        };
+        let options = FindOneAndUpdateOptions {
+            sort: (!sort.is_empty()).then(|| sort.into_iter().map(|(f, d)| (f, Bson::Int32(d))).collect()),
+            projection: parse_projection(obj.get("projection")).map_err(CoprocessorError::InvalidArguments)?,
+            upsert: flag(&obj, "upsert", false)?,
+            return_updated,
+        };
+        let upsert = options.upsert;
+        
+        // The document comes back, so it must be readable as well
+        let readable = self.grant(caller, &workspace, &collection_name, Operation::Read).await?;
+        let grant = self.grant(caller, &workspace, &collection_name, Operation::Write).await?;
+        readable.check_filter(&filter_doc).map_err(forbidden)?;
+        grant.check_update(&update_doc).map_err(forbidden)?;
+        let filter_doc = grant.restrict(readable.restrict(filter_doc));
+        
+        let ns = Namespace::new(&workspace, &collection_name).in_transaction(transaction.as_deref());
+        let mut update_doc = update_doc;
+        self.check_update(ns, &filter_doc, &mut update_doc, false, options.sort.clone(), upsert).await?;
+        let audited = FindOptions { sort: options.sort.clone(), limit: Some(1), ..FindOptions::default() };
+        let before = self.audited(ns, &filter_doc, audited.clone()).await?;
+        let after_filter = filter_doc.clone();
+        let mut document = self.store.find_one_and_update(ns, filter_doc, update_doc, options).await.map_err(|e| {
+            error!("Failed to update document: {}", e);
+            CoprocessorError::ExecutionError(format!("Update failed: {}", e))
+        })?;
+        // Nothing matched before an upsert: the inserted document matches now
+        let upserted = match before.is_empty() && upsert {
+            true => self.audited(ns, &after_filter, audited).await.ok()
+                .and_then(|found| found.first().and_then(|d| d.get("_id").cloned())),
+            false => None,
+        };
+        self.audit_changes(ns, "database.updated", caller, &before, upserted).await;
+        if let Some(document) = document.as_mut() {
+            readable.hide(document);
+        }
+        
+        // With an upsert something was always written, even if the document as
+        // it was before is empty
+        if document.is_some() || upsert {
+            let mut payload = HashMap::new();
+            payload.insert("collection".to_string(), Data::String(collection_name.clone()));
+            payload.insert("filter".to_string(), Data::Object(filter));
+            payload.insert("update".to_string(), Data::Object(update));
+            self.publish("database.updated", &workspace, payload, transaction.as_deref());
+        }
+        
+        let mut response = HashMap::new();
+        response.insert("found".to_string(), Data::Bool(document.is_some()));
+        response.insert("data".to_string(), document.as_ref().map(document_to_data).unwrap_or(Data::Null));
+        
+        Ok(Data::Object(response))
+    }
+    
+    async fn aggregate(&self, args: Data, caller: Option<&Caller>) -> Result<Data, CoprocessorError> {
+        let obj = match args {
+            Data::Object(obj) => obj,
+            _ => {
+                return Err(CoprocessorError::InvalidArguments(
+                    "Expected object with 'collection' and 'pipeline' fields".to_string(),
+                ))
+            }
+        };
+        let collection_name = collection_of(&obj)?;
+        let workspace = self.workspace_of(&obj);
+        let transaction = transaction_of(&obj)?;
+        
+        let stages = match obj.get("pipeline") {
+            Some(Data::Array(stages)) => stages.iter()
+                .map(pipeline_stage)
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(CoprocessorError::InvalidArguments)?,
+            _ => {
+                return Err(CoprocessorError::InvalidArguments(
+                    "Missing or invalid 'pipeline' field".to_string(),
+                ))
+            }
+        };
+        pipeline::check(&stages).map_err(CoprocessorError::InvalidArguments)?;
+        
+        let grant = self.grant(caller, &workspace, &collection_name, Operation::Read).await?;
+        for from in pipeline::lookups(&stages) {
+            if !self.grant(caller, &workspace, &from, Operation::Read).await?.is_unrestricted() {
+                return Err(CoprocessorError::Forbidden(format!("Not allowed to join {}", from)));
+            }
+        }
+        let stages = restrict_pipeline(&grant, stages);
+        
+        let limit = match obj.get("limit") {
+            Some(n) if n.as_f64().is_some_and(|n| n >= 1.0) => n.as_f64().map(|n| n as usize).unwrap_or(DEFAULT_AGGREGATE_LIMIT),
+            None | Some(Data::Null) => DEFAULT_AGGREGATE_LIMIT,
+            _ => return Err(CoprocessorError::InvalidArguments("'limit' must be a positive number".to_string())),
+        }
+        .min(MAX_AGGREGATE_LIMIT);
+        
+        // One more than the limit tells whether there was more
+        let ns = Namespace::new(&workspace, &collection_name).in_transaction(transaction.as_deref());
+        let mut documents = self.store.aggregate(ns, stages, limit + 1).await.map_err(|e| {
+            error!("Failed to aggregate {}: {}", collection_name, e);
+            CoprocessorError::ExecutionError(format!("Aggregation failed: {}", e))
+        })?;
+        let truncated = documents.len() > limit;
+        documents.truncate(limit);
+        
+        let data: Vec<Data> = documents.iter().map(document_to_data).collect();
+        let mut response = HashMap::new();
+        response.insert("count".to_string(), Data::Number(data.len() as f64));
+        response.insert("data".to_string(), Data::Array(data));
+        response.insert("truncated".to_string(), Data::Bool(truncated));
+        
+        Ok(Data::Object(response))
+    }
+    
+    async fn set_schema(&self, args: Data, caller: Option<&Caller>) -> Result<Data, CoprocessorError> {
+        let obj = match args {
+            Data::Object(obj) => obj,
+            _ => {
+                return Err(CoprocessorError::InvalidArguments(
+                    "Expected object with 'collection' and 'schema' fields".to_string(),
+                ))
+            }
+        };
+        let collection_name = collection_of(&obj)?;
+        let workspace = self.workspace_of(&obj);
+        let definition = match obj.get("schema") {
+            Some(definition @ Data::Object(_)) => definition.to_json(),
+            _ => {
+                return Err(CoprocessorError::InvalidArguments(
+                    "Missing or invalid 'schema' field".to_string(),
+                ))
+            }
+        };
+        self.require_admin(caller, &workspace).await?;
+        if collection_name == schema::SCHEMA_COLLECTION {
+            return Err(CoprocessorError::InvalidArguments(format!("{} cannot have a schema", collection_name)));
+        }
+        schema::check(&definition)
+            .map_err(|e| CoprocessorError::InvalidArguments(format!("Invalid schema: {}", e)))?;
+        
+        let ns = Namespace::new(&workspace, schema::SCHEMA_COLLECTION);
+        let update = doc! {
+            "$set": {
+                "schema": definition.to_string(),
+                "updated_at": mongodb::bson::DateTime::now(),
+            }
+        };
+        let options = UpdateOptions { many: false, upsert: true };
+        let before = self.audited(ns, &doc! { "_id": &collection_name }, FindOptions::default()).await?;
+        self.store.update(ns, doc! { "_id": &collection_name }, update, options).await.map_err(|e| {
+            error!("Failed to save the schema of {}: {}", collection_name, e);
+            CoprocessorError::ExecutionError(format!("Failed to save schema: {}", e))
+        })?;
+        self.audit_changes(ns, "schema.updated", caller, &before, Some(Bson::String(collection_name.clone()))).await;
+        info!("Saved the schema of {} in workspace {}", collection_name, workspace);
+        
+        let mut response = HashMap::new();
+        response.insert("success".to_string(), Data::Bool(true));
+        Ok(Data::Object(response))
+    }
+    
+    async fn get_schema(&self, args: Data, caller: Option<&Caller>) -> Result<Data, CoprocessorError> {
+        let obj = match args {
+            Data::Object(obj) => obj,
+            _ => {
+                return Err(CoprocessorError::InvalidArguments(
+                    "Expected object with 'collection' field".to_string(),
+                ))
+            }
+        };
+        let (workspace, collection_name) = (self.workspace_of(&obj), collection_of(&obj)?);
+        self.grant(caller, &workspace, &collection_name, Operation::Read).await?;
+        let definition = self.schema_of(&workspace, &collection_name).await?;
+        
+        let mut response = HashMap::new();
+        response.insert("found".to_string(), Data::Bool(definition.is_some()));
+        response.insert("schema".to_string(), definition.map(Data::from_json).unwrap_or(Data::Null));
+        Ok(Data::Object(response))
+    }
+    
+    async fn list_schemas(&self, args: Data, caller: Option<&Caller>) -> Result<Data, CoprocessorError> {
+        let workspace = match &args {
+            Data::Object(obj) => self.workspace_of(obj),
+            _ => self.database_name.clone(),
+        };
+        self.require_admin(caller, &workspace).await?;
+        let ns = Namespace::new(&workspace, schema::SCHEMA_COLLECTION);
+        let options = FindOptions { sort: Some(doc! { "_id": 1 }), ..FindOptions::default() };
+        let documents = self.store.find(ns, Document::new(), options).await.map_err(|e| {
+            CoprocessorError::ExecutionError(format!("Failed to list schemas: {}", e))
+        })?;
+        
+        let mut schemas = Vec::new();
+        for document in documents {
+            let definition: serde_json::Value = document.get_str("schema").ok()
+                .and_then(|json| serde_json::from_str(json).ok())
+                .ok_or_else(|| CoprocessorError::ExecutionError("Corrupt schema document".to_string()))?;
+            let mut entry = HashMap::new();
+            entry.insert("collection".to_string(), id_to_data(document.get("_id").unwrap_or(&Bson::Null)));
+            entry.insert("schema".to_string(), Data::from_json(definition));
+            schemas.push(Data::Object(entry));
+        }
+        
+        let mut response = HashMap::new();
+        response.insert("schemas".to_string(), Data::Array(schemas));
+        Ok(Data::Object(response))
+    }
+    
+    async fn delete_schema(&self, args: Data, caller: Option<&Caller>) -> Result<Data, CoprocessorError> {
+        let obj = match args {
+            Data::Object(obj) => obj,
+            _ => {
+                return Err(CoprocessorError::InvalidArguments(
+                    "Expected object with 'collection' field".to_string(),
+                ))
+            }
+        };
+        let collection_name = collection_of(&obj)?;
+        let workspace = self.workspace_of(&obj);
+        
+        self.require_admin(caller, &workspace).await?;
+        
+        let ns = Namespace::new(&workspace, schema::SCHEMA_COLLECTION);
+        let before = self.audited(ns, &doc! { "_id": &collection_name }, FindOptions::default()).await?;
+        let deleted = self.store.delete(ns, doc! { "_id": &collection_name }).await.map_err(|e| {
+            CoprocessorError::ExecutionError(format!("Failed to delete schema: {}", e))
+        })?;
+        for document in &before {
+            self.audit(ns, "schema.deleted", caller, Some(document), None).await;
+        }
+        if deleted > 0 {
+            info!("Deleted the schema of {} in workspace {}", collection_name, workspace);
+        }
+        
+        let mut response = HashMap::new();
+        response.insert("deleted".to_string(), Data::Bool(deleted > 0));
+        Ok(Data::Object(response))
+    }
+    
+    /// Check every document of a collection, for collections that had data
+    /// before their schema
+    async fn validate_collection(&self, args: Data, caller: Option<&Caller>) -> Result<Data, CoprocessorError> {
+        let obj = match args {
+            Data::Object(obj) => obj,
+            _ => {
+                return Err(CoprocessorError::InvalidArguments(
+                    "Expected object with 'collection' field".to_string(),
+                ))
+            }
+        };
+        let collection_name = collection_of(&obj)?;
+        let workspace = self.workspace_of(&obj);
+        let limit = match obj.get("limit") {
+            Some(n) if n.as_f64().is_some_and(|n| n >= 1.0) => n.as_f64().map(|n| n as usize).unwrap_or(DEFAULT_REPORT_LIMIT),
+            None | Some(Data::Null) => DEFAULT_REPORT_LIMIT,
+            _ => return Err(CoprocessorError::InvalidArguments("'limit' must be a positive number".to_string())),
+        };
+        self.require_admin(caller, &workspace).await?;
+        let definition = self.schema_of(&workspace, &collection_name).await?.ok_or_else(|| {
+            CoprocessorError::NotFound(format!("No schema is registered for '{}'", collection_name))
+        })?;
+        
+        let ns = Namespace::new(&workspace, &collection_name);
+        let documents = self.store.find(ns, Document::new(), FindOptions::default()).await.map_err(|e| {
+            CoprocessorError::ExecutionError(format!("Failed to read {}: {}", collection_name, e))
+        })?;
+        
+        let checked = documents.len();
+        let mut invalid = 0;
+        let mut reports = Vec::new();
+        for document in &documents {
+            let violations = schema::validate(&definition, document);
+            if violations.is_empty() {
+                continue;
+            }
+            invalid += 1;
+            if reports.len() < limit {
+                let mut report = HashMap::new();
+                report.insert("_id".to_string(), id_to_data(document.get("_id").unwrap_or(&Bson::Null)));
+                report.insert("errors".to_string(), Data::Array(
+                    violations.iter().map(|v| Data::String(v.to_string())).collect(),
+                ));
+                reports.push(Data::Object(report));
+            }
+        }
+        
+        let mut response = HashMap::new();
+        response.insert("checked".to_string(), Data::Number(checked as f64));
+        response.insert("valid".to_string(), Data::Number((checked - invalid) as f64));
+        response.insert("invalid".to_string(), Data::Number(invalid as f64));
+        response.insert("documents".to_string(), Data::Array(reports));
+        Ok(Data::Object(response))
+    }
+    
+    async fn begin(&self) -> Result<Data, CoprocessorError> {
+        let transaction = self.store.begin().await.map_err(|e| {
+            error!("Failed to begin transaction: {}", e);
+            CoprocessorError::ExecutionError(format!("Failed to begin transaction: {}", e))
+        })?;
+        
+        let mut response = HashMap::new();
+        response.insert("transaction".to_string(), Data::String(transaction));
+        Ok(Data::Object(response))
     }
-}
\ No newline at end of file
+    
+    /// `commit` or `abort`; events held back by the transaction are published
+    /// only once it commits
+    async fn end_transaction(&self, args: Data, commit: bool) -> Result<Data, CoprocessorError> {
+        let transaction = match &args {
+            Data::Object(obj) => transaction_of(obj)?,
+            _ => None,
+        }
+        .ok_or_else(|| CoprocessorError::InvalidArguments("Missing 'transaction' field".to_string()))?;
+        
+        let events = self.pending.lock().unwrap().remove(&transaction).unwrap_or_default();
+        let ended = if commit {
+            self.store.commit(&transaction).await
+        } else {
+            self.store.abort(&transaction).await
+        };
+        ended.map_err(|e| {
+            error!("Failed to end transaction {}: {}", transaction, e);
+            CoprocessorError::ExecutionError(e)
+        })?;
+        
+        if let (true, Some(bus)) = (commit, &self.events) {
+            for event in events {
+                bus.publish(event);
+            }
+        }
+        
+        let mut response = HashMap::new();
+        response.insert("success".to_string(), Data::Bool(true));
+        Ok(Data::Object(response))
+    }
+    
+    /// The schema registered for a collection, if any
+    async fn schema_of(&self, workspace: &str, collection: &str) -> Result<Option<serde_json::Value>, CoprocessorError> {
+        if collection == schema::SCHEMA_COLLECTION {
+            return Ok(None);
+        }
+        let ns = Namespace::new(workspace, schema::SCHEMA_COLLECTION);
+        let found = self.store.find(ns, doc! { "_id": collection }, FindOptions::default()).await.map_err(|e| {
+            error!("Failed to load the schema of {}: {}", collection, e);
+            CoprocessorError::ExecutionError(format!("Failed to load schema: {}", e))
+        })?;
+        found.first()
+            .map(|document| {
+                let json = document.get_str("schema")
+                    .map_err(|e| CoprocessorError::ExecutionError(format!("Corrupt schema document: {}", e)))?;
+                serde_json::from_str(json)
+                    .map_err(|e| CoprocessorError::ExecutionError(format!("Corrupt schema document: {}", e)))
+            })
+            .transpose()
+    }
+    
+    /// Refuse an update that would leave a document not matching the schema;
+    /// an upsert also gets the schema defaults through `$setOnInsert`
+    async fn check_update(
+        &self,
+        ns: Namespace<'_>,
+        filter: &Document,
+        update: &mut Document,
+        many: bool,
+        sort: Option<Document>,
+        upsert: bool,
+    ) -> Result<(), CoprocessorError> {
+        let Some(schema) = self.schema_of(ns.workspace, ns.collection).await? else {
+            return Ok(());
+        };
+        query::validate_update(update).map_err(CoprocessorError::InvalidArguments)?;
+        
+        // Every match of an update_many is checked, a batch at a time in `_id` order
+        let batch = if many { UPDATE_CHECK_BATCH } else { 1 };
+        let mut checked = 0;
+        loop {
+            let options = FindOptions {
+                sort: if many { Some(doc! { "_id": 1 }) } else { sort.clone() },
+                limit: Some(batch as i64),
+                skip: Some(checked as u64),
+                ..FindOptions::default()
+            };
+            let targets = self.store.find(ns, filter.clone(), options).await.map_err(|e| {
+                CoprocessorError::ExecutionError(format!("Failed to check the update: {}", e))
+            })?;
+            let found = targets.len();
+            for mut target in targets {
+                query::apply_update(&mut target, update, false).map_err(CoprocessorError::InvalidArguments)?;
+                let violations = schema::validate(&schema, &target);
+                if !violations.is_empty() {
+                    let id = target.get("_id").map(|id| id_to_data(id).to_json().to_string()).unwrap_or_default();
+                    return Err(violation(ns.collection, &format!("updating {}: {}", id, schema::describe(&violations))));
+                }
+            }
+            checked += found;
+            if !many || found < batch {
+                break;
+            }
+        }
+        
+        if upsert {
+            // What would be inserted if nothing matches
+            let mut seed = query::upsert_seed(filter).map_err(CoprocessorError::InvalidArguments)?;
+            query::apply_update(&mut seed, update, true).map_err(CoprocessorError::InvalidArguments)?;
+            let defaults = schema::defaults(&schema, &seed).map_err(CoprocessorError::ExecutionError)?;
+            if !defaults.is_empty() {
+                let on_insert = match update.get_mut("$setOnInsert") {
+                    Some(Bson::Document(on_insert)) => on_insert,
+                    _ => {
+                        update.insert("$setOnInsert", Document::new());
+                        update.get_document_mut("$setOnInsert").map_err(|e| CoprocessorError::ExecutionError(e.to_string()))?
+                    }
+                };
+                for (path, value) in defaults {
+                    on_insert.insert(path, value);
+                }
+                schema::apply_defaults(&schema, &mut seed).map_err(CoprocessorError::ExecutionError)?;
+            }
+            let violations = schema::validate(&schema, &seed);
+            if !violations.is_empty() {
+                return Err(violation(ns.collection, &format!("inserting: {}", schema::describe(&violations))));
+            }
+        }
+        Ok(())
+    }
+    
+    async fn set_policy(&self, args: Data, caller: Option<&Caller>) -> Result<Data, CoprocessorError> {
+        let obj = match args {
+            Data::Object(obj) => obj,
+            _ => {
+                return Err(CoprocessorError::InvalidArguments(
+                    "Expected object with 'policy' field".to_string(),
+                ))
+            }
+        };
+        let workspace = self.workspace_of(&obj);
+        let policy = match obj.get("policy") {
+            Some(policy @ Data::Object(_)) => Policy::from_json(policy.to_json()).map_err(forbidden)?,
+            _ => {
+                return Err(CoprocessorError::InvalidArguments(
+                    "Missing or invalid 'policy' field".to_string(),
+                ))
+            }
+        };
+        let caller = self.require_admin(caller, &workspace).await?;
+        if !policy.is_admin(caller, &workspace) {
+            return Err(CoprocessorError::InvalidArguments(
+                "The policy would take away your own admin role".to_string(),
+            ));
+        }
+        
+        let ns = Namespace::new(&workspace, POLICY_COLLECTION);
+        let update = doc! {
+            "$set": {
+                "policy": policy.to_json().to_string(),
+                "updated_at": mongodb::bson::DateTime::now(),
+                "updated_by": &caller.sub,
+            }
+        };
+        let options = UpdateOptions { many: false, upsert: true };
+        let before = self.audited(ns, &doc! { "_id": POLICY_ID }, FindOptions::default()).await?;
+        self.store.update(ns, doc! { "_id": POLICY_ID }, update, options).await.map_err(|e| {
+            error!("Failed to save the policy of {}: {}", workspace, e);
+            CoprocessorError::ExecutionError(format!("Failed to save policy: {}", e))
+        })?;
+        self.audit_changes(ns, "policy.updated", Some(caller), &before, Some(Bson::String(POLICY_ID.to_string()))).await;
+        info!("Saved the policy of workspace {}", workspace);
+        
+        let mut response = HashMap::new();
+        response.insert("success".to_string(), Data::Bool(true));
+        Ok(Data::Object(response))
+    }
+    
+    async fn get_policy(&self, args: Data, caller: Option<&Caller>) -> Result<Data, CoprocessorError> {
+        let workspace = match &args {
+            Data::Object(obj) => self.workspace_of(obj),
+            _ => self.database_name.clone(),
+        };
+        self.require_admin(caller, &workspace).await?;
+        let stored = self.stored_policy(&workspace).await?;
+        let is_default = stored.is_none();
+        let policy = stored.unwrap_or_default();
+        
+        let mut response = HashMap::new();
+        response.insert("policy".to_string(), Data::from_json(policy.to_json()));
+        response.insert("default".to_string(), Data::Bool(is_default));
+        Ok(Data::Object(response))
+    }
+    
+    /// The policy set for a workspace, if any
+    async fn stored_policy(&self, workspace: &str) -> Result<Option<Policy>, CoprocessorError> {
+        policy::stored(&self.store, workspace).await.map_err(|e| {
+            error!("Failed to load the policy of {}: {}", workspace, e);
+            CoprocessorError::ExecutionError(e)
+        })
+    }
+    
+    async fn policy_of(&self, workspace: &str) -> Result<Policy, CoprocessorError> {
+        Ok(self.stored_policy(workspace).await?.unwrap_or_default())
+    }
+    
+    /// What the caller may do with a collection; scripts running for no one
+    /// may do everything in the workspace they are confined to
+    async fn grant(&self, caller: Option<&Caller>, workspace: &str, collection: &str, operation: Operation) -> Result<Grant, CoprocessorError> {
+        match caller {
+            Some(caller) => self.policy_of(workspace).await?
+                .grant(caller, workspace, collection, operation)
+                .map_err(forbidden),
+            None => Ok(Grant::all()),
+        }
+    }
+    
+    /// The caller, when they administer the workspace; scripts running for no
+    /// one don't
+    async fn require_admin<'a>(&self, caller: Option<&'a Caller>, workspace: &str) -> Result<&'a Caller, CoprocessorError> {
+        let caller = caller.ok_or_else(|| {
+            CoprocessorError::Forbidden(format!("Only admins of {} may do this", workspace))
+        })?;
+        self.policy_of(workspace).await?
+            .require_admin(caller, workspace)
+            .map_err(forbidden)?;
+        Ok(caller)
+    }
+    
+    /// The documents a write is about to change, when writes are audited
+    async fn audited(&self, ns: Namespace<'_>, filter: &Document, options: FindOptions) -> Result<Vec<Document>, CoprocessorError> {
+        if self.audit.is_none() {
+            return Ok(Vec::new());
+        }
+        self.store.find(ns, filter.clone(), options).await.map_err(|e| {
+            CoprocessorError::ExecutionError(format!("Failed to read documents for the audit log: {}", e))
+        })
+    }
+    
+    /// Log a write to a document of a collection, as it was and as it is
+    async fn audit(&self, ns: Namespace<'_>, action: &str, caller: Option<&Caller>, before: Option<&Document>, after: Option<&Document>) {
+        let Some(audit) = &self.audit else {
+            return;
+        };
+        let target = match after.or(before).and_then(|document| document.get("_id")).map(id_to_data) {
+            Some(Data::String(id)) => format!("{}/{}", ns.collection, id),
+            Some(id) => format!("{}/{}", ns.collection, id.to_json()),
+            None => ns.collection.to_string(),
+        };
+        let mut entry = AuditEntry::new(ns.workspace, action).on(target).changes(before, after);
+        if let Some(caller) = caller {
+            entry = entry.by(&caller.sub);
+        }
+        if !entry.is_empty() {
+            audit.log(&entry, ns.transaction).await;
+        }
+    }
+    
+    /// Log the writes to the documents that were `before`, and to the one
+    /// `upserted` if any, as they now are
+    async fn audit_changes(&self, ns: Namespace<'_>, action: &str, caller: Option<&Caller>, before: &[Document], upserted: Option<Bson>) {
+        if self.audit.is_none() {
+            return;
+        }
+        let mut ids: Vec<Bson> = before.iter().filter_map(|document| document.get("_id").cloned()).collect();
+        ids.extend(upserted);
+        if ids.is_empty() {
+            return;
+        }
+        let after = match self.store.find(ns, doc! { "_id": { "$in": ids } }, FindOptions::default()).await {
+            Ok(after) => after,
+            Err(e) => {
+                error!("Failed to read documents for the audit log: {}", e);
+                return;
+            }
+        };
+        for document in &after {
+            let old = before.iter().find(|old| old.get("_id") == document.get("_id"));
+            self.audit(ns, action, caller, old, Some(document)).await;
+        }
+    }
+    
+    fn search_engine(&self) -> Result<&SearchEngine, CoprocessorError> {
+        self.search.as_deref()
+            .ok_or_else(|| CoprocessorError::ExecutionError("Search is not enabled".to_string()))
+    }
+    
+    async fn search(&self, args: Data, caller: Option<&Caller>) -> Result<Data, CoprocessorError> {
+        let obj = match &args {
+            Data::Object(obj) => obj,
+            _ => {
+                return Err(CoprocessorError::InvalidArguments(
+                    "Expected object with 'collection' and 'query' fields".to_string(),
+                ))
+            }
+        };
+        let collection_name = collection_of(obj)?;
+        let workspace = self.workspace_of(obj);
+        let mut request = SearchRequest::from_json(&args.to_json()).map_err(search_error)?;
+        
+        // The index knows nothing of owners, so only callers reading every
+        // document may search, and never on the fields they may not see
+        let grant = self.grant(caller, &workspace, &collection_name, Operation::Read).await?;
+        if grant.access != Access::All {
+            return Err(CoprocessorError::Forbidden(format!("Not allowed to search {}", collection_name)));
+        }
+        let facets: Document = request.filters.iter()
+            .map(|(facet, _)| facet)
+            .chain(&request.facets)
+            .map(|facet| (facet.clone(), Bson::Int32(1)))
+            .collect();
+        grant.check_filter(&facets).map_err(forbidden)?;
+        let engine = self.search_engine()?;
+        if !grant.denied.is_empty() {
+            // Which documents match would tell what the hidden fields say
+            let config = engine.config(&workspace, &collection_name).await.map_err(search_error)?
+                .ok_or_else(|| search_error(SearchError::NotConfigured(collection_name.clone())))?;
+            let visible: Vec<String> = config.fields.into_iter()
+                .filter(|field| grant.check_filter(&doc! { field.as_str(): 1 }).is_ok())
+                .collect();
+            if visible.is_empty() && !request.query.trim().is_empty() {
+                return Err(CoprocessorError::Forbidden(format!("Not allowed to search the text of {}", collection_name)));
+            }
+            request.fields = Some(visible);
+        }
+        
+        let mut results = engine.search(&workspace, &collection_name, &request).await
+            .map_err(search_error)?;
+        for hit in results.hits.iter_mut() {
+            grant.hide(&mut hit.document);
+            hit.highlights.retain(|field, _| grant.check_filter(&doc! { field.as_str(): 1 }).is_ok());
+        }
+        info!("Search of {} in {} found {} documents", collection_name, workspace, results.total);
+        Ok(Data::from_json(results.to_json()))
+    }
+    
+    async fn configure_search(&self, args: Data, caller: Option<&Caller>) -> Result<Data, CoprocessorError> {
+        let obj = match args {
+            Data::Object(obj) => obj,
+            _ => {
+                return Err(CoprocessorError::InvalidArguments(
+                    "Expected object with 'collection' and 'config' fields".to_string(),
+                ))
+            }
+        };
+        let collection_name = collection_of(&obj)?;
+        let config = match obj.get("config") {
+            Some(config @ Data::Object(_)) => SearchConfig::from_json(config.to_json()).map_err(search_error)?,
+            _ => {
+                return Err(CoprocessorError::InvalidArguments(
+                    "Missing or invalid 'config' field".to_string(),
+                ))
+            }
+        };
+        let workspace = self.workspace_of(&obj);
+        self.require_admin(caller, &workspace).await?;
+        self.search_engine()?.configure(&workspace, &collection_name, config).await
+            .map_err(search_error)?;
+        
+        let mut response = HashMap::new();
+        response.insert("success".to_string(), Data::Bool(true));
+        Ok(Data::Object(response))
+    }
+    
+    async fn get_search(&self, args: Data, caller: Option<&Caller>) -> Result<Data, CoprocessorError> {
+        let obj = match args {
+            Data::Object(obj) => obj,
+            _ => {
+                return Err(CoprocessorError::InvalidArguments(
+                    "Expected object with 'collection' field".to_string(),
+                ))
+            }
+        };
+        let (workspace, collection_name) = (self.workspace_of(&obj), collection_of(&obj)?);
+        self.grant(caller, &workspace, &collection_name, Operation::Read).await?;
+        let config = self.search_engine()?.config(&workspace, &collection_name).await
+            .map_err(search_error)?;
+        
+        let mut response = HashMap::new();
+        response.insert("found".to_string(), Data::Bool(config.is_some()));
+        response.insert("config".to_string(), config.map(|c| Data::from_json(c.to_json())).unwrap_or(Data::Null));
+        Ok(Data::Object(response))
+    }
+    
+    async fn drop_search(&self, args: Data, caller: Option<&Caller>) -> Result<Data, CoprocessorError> {
+        let obj = match args {
+            Data::Object(obj) => obj,
+            _ => {
+                return Err(CoprocessorError::InvalidArguments(
+                    "Expected object with 'collection' field".to_string(),
+                ))
+            }
+        };
+        let workspace = self.workspace_of(&obj);
+        self.require_admin(caller, &workspace).await?;
+        let deleted = self.search_engine()?.remove(&workspace, &collection_of(&obj)?).await
+            .map_err(search_error)?;
+        
+        let mut response = HashMap::new();
+        response.insert("deleted".to_string(), Data::Bool(deleted));
+        Ok(Data::Object(response))
+    }
+    
+    async fn reindex_search(&self, args: Data, caller: Option<&Caller>) -> Result<Data, CoprocessorError> {
+        let obj = match args {
+            Data::Object(obj) => obj,
+            _ => {
+                return Err(CoprocessorError::InvalidArguments(
+                    "Expected object with 'collection' field".to_string(),
+                ))
+            }
+        };
+        let workspace = self.workspace_of(&obj);
+        self.require_admin(caller, &workspace).await?;
+        let indexed = self.search_engine()?.reindex(&workspace, &collection_of(&obj)?).await
+            .map_err(search_error)?;
+        
+        let mut response = HashMap::new();
+        response.insert("indexed".to_string(), Data::Number(indexed as f64));
+        Ok(Data::Object(response))
+    }
+    
+    fn workspace_of(&self, obj: &HashMap<String, Data>) -> String {
+        match obj.get("workspace") {
+            Some(Data::String(s)) => s.clone(),
+            _ => self.database_name.clone(),
+        }
+    }
+    
+    /// An update document; plain fields are set, the way `update` always worked
+    fn update_to_document(&self, update: &HashMap<String, Data>) -> Result<Document, String> {
+        let operators = update.keys().filter(|key| key.starts_with('$')).count();
+        if operators == 0 {
+            return Ok(doc! { "$set": self.data_to_document(update)? });
+        }
+        if operators < update.len() {
+            return Err("Use either update operators or plain fields, not both".to_string());
+        }
+        crate::bson_data::data_to_document(update)
+    }
+    
+    fn data_to_document(&self, data: &HashMap<String, Data>) -> Result<Document, String> {
+        let mut doc = crate::bson_data::data_to_document(data)?;
+        
+        // Special handling for _id field - a hex string is an ObjectId
+        if let Some(Bson::String(s)) = doc.get("_id") {
+            if let Ok(oid) = ObjectId::parse_str(s) {
+                doc.insert("_id", oid);
+            }
+        }
+        
+        Ok(doc)
+    }
+}
+// ================================================================================
+// QUERIES
+// ================================================================================
+
+fn update_schema() -> serde_json::Value {
+    serde_json::json!({
+        "type": "object",
+        "properties": {
+            "collection": { "type": "string" },
+            "filter": { "type": "object" },
+            "update": {
+                "type": "object",
+                "description": "Fields to set, or update operators such as $set, $inc, $push and $unset"
+            },
+            "upsert": {
+                "type": "boolean",
+                "description": "Insert a document built from the filter and update when nothing matches"
+            },
+            "transaction": { "type": "string" }
+        },
+        "required": ["collection", "filter", "update"]
+    })
+}
+
+fn update_output_schema() -> serde_json::Value {
+    serde_json::json!({
+        "type": "object",
+        "properties": {
+            "matched": { "type": "number" },
+            "modified": { "type": "number" },
+            "upserted_id": { "type": ["string", "null"] }
+        }
+    })
+}
+
+fn collection_schema() -> serde_json::Value {
+    serde_json::json!({
+        "type": "object",
+        "properties": {
+            "collection": { "type": "string" }
+        },
+        "required": ["collection"]
+    })
+}
+
+fn transaction_schema() -> serde_json::Value {
+    serde_json::json!({
+        "type": "object",
+        "properties": {
+            "transaction": { "type": "string" }
+        },
+        "required": ["transaction"]
+    })
+}
+
+fn forbidden(e: PolicyError) -> CoprocessorError {
+    match e {
+        PolicyError::Forbidden(message) => CoprocessorError::Forbidden(message),
+        PolicyError::Invalid(_) => CoprocessorError::InvalidArguments(e.to_string()),
+    }
+}
+
+/// Start a pipeline with what the grant allows: the documents the caller may
+/// read, without the fields they may not see
+fn restrict_pipeline(grant: &Grant, stages: Vec<Document>) -> Vec<Document> {
+    if grant.is_unrestricted() {
+        return stages;
+    }
+    let mut restricted = Vec::new();
+    let filter = grant.restrict(Document::new());
+    if !filter.is_empty() {
+        restricted.push(doc! { "$match": filter });
+    }
+    if !grant.denied.is_empty() {
+        restricted.push(doc! { "$unset": grant.denied.clone() });
+    }
+    restricted.extend(stages);
+    restricted
+}
+
+fn search_error(e: SearchError) -> CoprocessorError {
+    match e {
+        SearchError::NotConfigured(_) => CoprocessorError::NotFound(e.to_string()),
+        SearchError::Invalid(_) => CoprocessorError::InvalidArguments(e.to_string()),
+        SearchError::Failed(_) => CoprocessorError::ExecutionError(e.to_string()),
+    }
+}
+
+fn collection_of(obj: &HashMap<String, Data>) -> Result<String, CoprocessorError> {
+    match obj.get("collection") {
+        Some(Data::String(s)) => Ok(s.clone()),
+        _ => Err(CoprocessorError::InvalidArguments(
+            "Missing or invalid 'collection' field".to_string(),
+        )),
+    }
+}
+
+/// A pipeline stage; `$match` converts like a filter so that `_id` strings
+/// match ObjectIds
+fn pipeline_stage(stage: &Data) -> Result<Document, String> {
+    match stage {
+        Data::Object(stage) => stage.iter()
+            .map(|(name, spec)| {
+                let spec = match (name.as_str(), spec) {
+                    ("$match", Data::Object(filter)) => Bson::Document(filter_to_document(filter)?),
+                    (_, spec) => crate::bson_data::data_to_bson(spec)?,
+                };
+                Ok((name.clone(), spec))
+            })
+            .collect(),
+        _ => Err("Every pipeline stage must be an object".to_string()),
+    }
+}
+
+/// Fill in the schema defaults of a document about to be inserted, and check it
+fn conform(schema: &serde_json::Value, collection: &str, document: &mut Document) -> Result<(), CoprocessorError> {
+    schema::apply_defaults(schema, document).map_err(CoprocessorError::ExecutionError)?;
+    let violations = schema::validate(schema, document);
+    if violations.is_empty() {
+        Ok(())
+    } else {
+        Err(violation(collection, &schema::describe(&violations)))
+    }
+}
+
+fn violation(collection: &str, detail: &str) -> CoprocessorError {
+    CoprocessorError::InvalidArguments(format!("{} in '{}': {}", schema::SCHEMA_VIOLATION, collection, detail))
+}
+
+/// The id returned by `begin`, when the call is part of a transaction
+fn transaction_of(obj: &HashMap<String, Data>) -> Result<Option<String>, CoprocessorError> {
+    match obj.get("transaction") {
+        Some(Data::String(s)) => Ok(Some(s.clone())),
+        None | Some(Data::Null) => Ok(None),
+        _ => Err(CoprocessorError::InvalidArguments("Invalid 'transaction' field".to_string())),
+    }
+}
+
+fn flag(obj: &HashMap<String, Data>, name: &str, default: bool) -> Result<bool, CoprocessorError> {
+    match obj.get(name) {
+        Some(Data::Bool(b)) => Ok(*b),
+        None | Some(Data::Null) => Ok(default),
+        _ => Err(CoprocessorError::InvalidArguments(format!("'{}' must be true or false", name))),
+    }
+}
+
+/// Ids as scripts see them: ObjectIds as hex strings
+fn id_to_data(id: &Bson) -> Data {
+    match id {
+        Bson::ObjectId(oid) => Data::String(oid.to_hex()),
+        other => crate::bson_data::bson_to_data(other),
+    }
+}
+
+/// Filter, projection, sort and paging for `retrieve`
+struct FindQuery {
+    filter: Document,
+    projection: Option<Document>,
+    /// Fields and directions (1 or -1), in order
+    sort: Vec<(String, i32)>,
+    limit: Option<i64>,
+    skip: Option<u64>,
+    /// Sort values of the last document of the previous page
+    after: Option<Vec<Bson>>,
+}
+
+impl FindQuery {
+    fn parse(obj: &HashMap<String, Data>) -> Result<Self, String> {
+        let filter = match obj.get("filter") {
+            Some(Data::Object(f)) => filter_to_document(f)?,
+            None | Some(Data::Null) => Document::new(),
+            _ => return Err("Invalid 'filter' field".to_string()),
+        };
+        
+        let projection = parse_projection(obj.get("projection"))?;
+        
+        let mut sort = parse_sort(obj.get("sort"))?;
+        
+        let limit = match obj.get("limit") {
+            Some(n) if n.as_f64().is_some_and(|n| n >= 1.0) => n.as_f64().map(|n| n as i64),
+            None | Some(Data::Null) => None,
+            _ => return Err("'limit' must be a positive number".to_string()),
+        };
+        
+        let skip = match obj.get("skip") {
+            Some(n) if n.as_f64().is_some_and(|n| n >= 0.0) => n.as_f64().map(|n| n as u64),
+            None | Some(Data::Null) => None,
+            _ => return Err("'skip' must be a non-negative number".to_string()),
+        };
+        
+        let cursor = match obj.get("cursor") {
+            Some(Data::String(s)) if !s.is_empty() => Some(s.clone()),
+            None | Some(Data::Null) | Some(Data::String(_)) => None,
+            _ => return Err("Invalid 'cursor' field".to_string()),
+        };
+        
+        // Sorted and paged queries end on _id so that the order is total
+        if (!sort.is_empty() || limit.is_some() || cursor.is_some()) && !sort.iter().any(|(f, _)| f == "_id") {
+            sort.push(("_id".to_string(), 1));
+        }
+        
+        let after = cursor.map(|c| decode_cursor(&c, &sort)).transpose()?;
+        
+        Ok(Self { filter, projection, sort, limit, skip, after })
+    }
+    
+    /// Keep to the documents the grant covers, refusing to filter or sort on
+    /// fields it hides
+    fn restrict(&mut self, grant: &Grant) -> Result<(), PolicyError> {
+        grant.check_filter(&self.filter)?;
+        let sorted: Document = self.sort.iter().map(|(f, d)| (f.clone(), Bson::Int32(*d))).collect();
+        grant.check_filter(&sorted)?;
+        self.filter = grant.restrict(std::mem::take(&mut self.filter));
+        Ok(())
+    }
+    
+    /// The filter past the cursor, the find options, and the fields fetched only
+    /// to build the next cursor (to strip from the results)
+    fn find_options(&self) -> (Document, FindOptions, Vec<String>) {
+        let filter = match &self.after {
+            Some(after) => {
+                // (a > x) or (a = x and b > y) or ...
+                let clauses: Vec<Bson> = (0..self.sort.len()).map(|i| {
+                    let mut clause = Document::new();
+                    for (j, (field, _)) in self.sort[..i].iter().enumerate() {
+                        clause.insert(field.clone(), after[j].clone());
+                    }
+                    let (field, direction) = &self.sort[i];
+                    let operator = if *direction < 0 { "$lt" } else { "$gt" };
+                    clause.insert(field.clone(), doc! { operator: after[i].clone() });
+                    Bson::Document(clause)
+                }).collect();
+                doc! { "$and": [Bson::Document(self.filter.clone()), doc! { "$or": clauses }] }
+            }
+            None => self.filter.clone(),
+        };
+        
+        let mut hidden = Vec::new();
+        let projection = self.projection.clone().map(|mut projection| {
+            if self.limit.is_some() {
+                let inclusive = projection.iter().any(|(k, v)| k != "_id" && *v == Bson::Int32(1));
+                for (field, _) in &self.sort {
+                    if projection.get(field) == Some(&Bson::Int32(0)) {
+                        projection.remove(field);
+                        hidden.push(field.clone());
+                    } else if inclusive && field != "_id" && !projection.contains_key(field) {
+                        projection.insert(field.clone(), 1);
+                        hidden.push(field.clone());
+                    }
+                }
+            }
+            projection
+        });
+        
+        let sort = if self.sort.is_empty() {
+            None
+        } else {
+            Some(self.sort.iter().map(|(f, d)| (f.clone(), Bson::Int32(*d))).collect::<Document>())
+        };
+        
+        let options = FindOptions {
+            projection,
+            sort,
+            limit: self.limit.map(|limit| limit + 1),
+            skip: self.skip,
+        };
+        
+        (filter, options, hidden)
+    }
+    
+    /// Opaque cursor pointing just past `last`
+    fn cursor_after(&self, last: &Document) -> String {
+        let values: Vec<serde_json::Value> = self.sort.iter()
+            .map(|(field, _)| lookup_path(last, field).cloned().unwrap_or(Bson::Null).into_canonical_extjson())
+            .collect();
+        let cursor = serde_json::json!({ "sort": self.sort, "after": values });
+        hex::encode(cursor.to_string())
+    }
+}
+
+fn decode_cursor(cursor: &str, sort: &[(String, i32)]) -> Result<Vec<Bson>, String> {
+    let invalid = || "Invalid 'cursor' field".to_string();
+    let bytes = hex::decode(cursor).map_err(|_| invalid())?;
+    let json: serde_json::Value = serde_json::from_slice(&bytes).map_err(|_| invalid())?;
+    
+    let cursor_sort: Vec<(String, i32)> = serde_json::from_value(json["sort"].clone()).map_err(|_| invalid())?;
+    if cursor_sort != sort {
+        return Err("Cursor was issued for a different sort".to_string());
+    }
+    
+    match &json["after"] {
+        serde_json::Value::Array(values) if values.len() == sort.len() => values.iter()
+            .map(|v| Bson::try_from(v.clone()).map_err(|_| invalid()))
+            .collect(),
+        _ => Err(invalid()),
+    }
+}
+
+/// Fields to include (1) or exclude (0)
+fn parse_projection(value: Option<&Data>) -> Result<Option<Document>, String> {
+    match value {
+        Some(Data::Object(p)) if !p.is_empty() => {
+            let mut projection = Document::new();
+            for (field, value) in p {
+                let include = match value {
+                    Data::Bool(b) => *b,
+                    Data::Number(_) | Data::Integer(_) => value.as_f64() != Some(0.0),
+                    _ => return Err(format!("Projection of '{}' must be 0 or 1", field)),
+                };
+                projection.insert(field.clone(), if include { 1 } else { 0 });
+            }
+            Ok(Some(projection))
+        }
+        None | Some(Data::Null) | Some(Data::Object(_)) => Ok(None),
+        _ => Err("Invalid 'projection' field".to_string()),
+    }
+}
+
+/// `"-createdAt,name"`, `["-createdAt", "name"]` or `{"createdAt": -1}`
+fn parse_sort(value: Option<&Data>) -> Result<Vec<(String, i32)>, String> {
+    let key = |s: &str| match s.trim().strip_prefix('-') {
+        Some(field) => (field.to_string(), -1),
+        None => (s.trim().trim_start_matches('+').to_string(), 1),
+    };
+    
+    match value {
+        None | Some(Data::Null) => Ok(Vec::new()),
+        Some(Data::String(s)) => Ok(s.split(',').filter(|k| !k.trim().is_empty()).map(key).collect()),
+        Some(Data::Array(items)) => items.iter()
+            .map(|item| match item {
+                Data::String(s) => Ok(key(s)),
+                _ => Err("Sort keys must be strings".to_string()),
+            })
+            .collect(),
+        Some(Data::Object(obj)) if obj.len() <= 1 => obj.iter()
+            .map(|(field, direction)| match direction.as_f64() {
+                Some(n) => Ok((field.clone(), if n < 0.0 { -1 } else { 1 })),
+                _ => Err("Sort direction must be 1 or -1".to_string()),
+            })
+            .collect(),
+        Some(Data::Object(_)) => Err("Use an array to sort on several fields".to_string()),
+        Some(_) => Err("Invalid 'sort' field".to_string()),
+    }
+}
+
+/// Convert a filter, keeping operators and nested conditions; strings compared
+/// with `_id` become ObjectIds when they are valid ones
+pub fn filter_to_document(filter: &HashMap<String, Data>) -> Result<Document, String> {
+    filter.iter()
+        .map(|(key, value)| filter_value(value, key == "_id").map(|bson| (key.clone(), bson)))
+        .collect()
+}
+
+fn filter_value(value: &Data, id_field: bool) -> Result<Bson, String> {
+    match value {
+        Data::String(s) if id_field => Ok(ObjectId::parse_str(s)
+            .map(Bson::ObjectId)
+            .unwrap_or_else(|_| Bson::String(s.clone()))),
+        Data::Array(items) => items.iter()
+            .map(|item| filter_value(item, id_field))
+            .collect::<Result<Vec<_>, _>>()
+            .map(Bson::Array),
+        Data::Object(obj) if !crate::bson_data::is_extjson(obj) => obj.iter()
+            .map(|(key, value)| {
+                // Operators apply to the enclosing field, anything else is a field itself
+                let id = if key.starts_with('$') { id_field } else { key == "_id" };
+                filter_value(value, id).map(|bson| (key.clone(), bson))
+            })
+            .collect::<Result<Document, _>>()
+            .map(Bson::Document),
+        other => crate::bson_data::data_to_bson(other),
+    }
+}
+
+fn lookup_path<'a>(doc: &'a Document, path: &str) -> Option<&'a Bson> {
+    let mut parts = path.split('.');
+    let mut current = doc.get(parts.next()?)?;
+    for part in parts {
+        current = match current {
+            Bson::Document(inner) => inner.get(part)?,
+            _ => return None,
+        };
+    }
+    Some(current)
+}
+
+/// Documents as scripts see them; `_id` stays a hex string, the way it is
+/// written and filtered on
+fn document_to_data(doc: &Document) -> Data {
+    let mut data = crate::bson_data::document_to_data(doc);
+    if let (Data::Object(obj), Some(Bson::ObjectId(oid))) = (&mut data, doc.get("_id")) {
+        obj.insert("_id".to_string(), Data::String(oid.to_hex()));
+    }
+    data
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(json: serde_json::Value) -> HashMap<String, Data> {
+        match Data::from_json(json) {
+            Data::Object(obj) => obj,
+            other => panic!("Expected object, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_filter_keeps_operators_and_converts_ids() {
+        let id = "64b7f0c2a1b2c3d4e5f60718";
+        let filter = filter_to_document(&args(serde_json::json!({
+            "_id": { "$in": [id, "not-an-id"] },
+            "$and": [{ "status": "open" }, { "price": { "$gt": 10 } }],
+            "name": { "$regex": "^Jean", "$options": "i" }
+        }))).unwrap();
+
+        let oid = ObjectId::parse_str(id).unwrap();
+        assert_eq!(filter.get_document("_id").unwrap(), &doc! { "$in": [oid, "not-an-id"] });
+        assert_eq!(filter.get_array("$and").unwrap(), &vec![
+            Bson::Document(doc! { "status": "open" }),
+            Bson::Document(doc! { "price": { "$gt": 10.0 } }),
+        ]);
+        assert_eq!(filter.get_document("name").unwrap(), &doc! { "$regex": "^Jean", "$options": "i" });
+    }
+
+    #[test]
+    fn test_parse_sort_forms() {
+        let expected = vec![("createdAt".to_string(), -1), ("name".to_string(), 1)];
+        assert_eq!(parse_sort(Some(&Data::String("-createdAt, name".to_string()))).unwrap(), expected);
+        let array = Data::Array(vec![Data::String("-createdAt".to_string()), Data::String("+name".to_string())]);
+        assert_eq!(parse_sort(Some(&array)).unwrap(), expected);
+
+        let single = Data::from_json(serde_json::json!({ "price": -1 }));
+        assert_eq!(parse_sort(Some(&single)).unwrap(), vec![("price".to_string(), -1)]);
+        let several = Data::from_json(serde_json::json!({ "price": -1, "name": 1 }));
+        assert!(parse_sort(Some(&several)).is_err());
+    }
+
+    #[test]
+    fn test_paged_query_sorts_on_id_and_fetches_one_extra() {
+        let query = FindQuery::parse(&args(serde_json::json!({
+            "filter": { "status": "open" },
+            "sort": "-price",
+            "limit": 2,
+            "skip": 4
+        }))).unwrap();
+        assert_eq!(query.sort, vec![("price".to_string(), -1), ("_id".to_string(), 1)]);
+
+        let (filter, options, hidden) = query.find_options();
+        assert_eq!(filter, doc! { "status": "open" });
+        assert_eq!(options.sort, Some(doc! { "price": -1, "_id": 1 }));
+        assert_eq!(options.limit, Some(3));
+        assert_eq!(options.skip, Some(4));
+        assert!(hidden.is_empty());
+
+        // Unpaged, unsorted queries keep natural order
+        let query = FindQuery::parse(&args(serde_json::json!({ "filter": {} }))).unwrap();
+        assert!(query.sort.is_empty());
+        assert_eq!(query.find_options().1.limit, None);
+    }
+
+    #[test]
+    fn test_cursor_continues_after_last_document() {
+        let query = FindQuery::parse(&args(serde_json::json!({ "sort": "-price", "limit": 2 }))).unwrap();
+        let oid = ObjectId::parse_str("64b7f0c2a1b2c3d4e5f60718").unwrap();
+        let cursor = query.cursor_after(&doc! { "_id": oid, "price": 12.5, "name": "Clio" });
+
+        let next = FindQuery::parse(&args(serde_json::json!({
+            "filter": { "status": "open" },
+            "sort": "-price",
+            "limit": 2,
+            "cursor": cursor
+        }))).unwrap();
+        let (filter, _, _) = next.find_options();
+        assert_eq!(filter, doc! { "$and": [
+            { "status": "open" },
+            { "$or": [
+                { "price": { "$lt": 12.5 } },
+                { "price": 12.5, "_id": { "$gt": oid } },
+            ] },
+        ] });
+
+        // The cursor is tied to its sort order
+        let other_sort = FindQuery::parse(&args(serde_json::json!({ "sort": "price", "limit": 2, "cursor": cursor })));
+        assert!(other_sort.is_err());
+        let garbage = FindQuery::parse(&args(serde_json::json!({ "limit": 2, "cursor": "zz" })));
+        assert!(garbage.is_err());
+    }
+
+    #[test]
+    fn test_projection_fetches_sort_fields_for_cursor() {
+        let query = FindQuery::parse(&args(serde_json::json!({
+            "projection": { "name": 1, "_id": 0 },
+            "sort": ["-createdAt"],
+            "limit": 10
+        }))).unwrap();
+        let (_, options, mut hidden) = query.find_options();
+        assert_eq!(options.projection, Some(doc! { "name": 1, "createdAt": 1 }));
+        hidden.sort();
+        assert_eq!(hidden, vec!["_id".to_string(), "createdAt".to_string()]);
+
+        let mut fetched = doc! { "_id": 1, "name": "Clio", "createdAt": "2024", "meta": { "a": 1, "b": 2 } };
+        for path in hidden.iter().chain(std::iter::once(&"meta.a".to_string())) {
+            remove_path(&mut fetched, path);
+        }
+        assert_eq!(fetched, doc! { "name": "Clio", "meta": { "b": 2 } });
+    }
+
+    #[test]
+    fn test_invalid_paging_arguments() {
+        assert!(FindQuery::parse(&args(serde_json::json!({ "limit": -1 }))).is_err());
+        assert!(FindQuery::parse(&args(serde_json::json!({ "skip": "ten" }))).is_err());
+        assert!(FindQuery::parse(&args(serde_json::json!({ "projection": { "name": "yes" } }))).is_err());
+        assert!(FindQuery::parse(&args(serde_json::json!({ "filter": "status=open" }))).is_err());
+    }
+}
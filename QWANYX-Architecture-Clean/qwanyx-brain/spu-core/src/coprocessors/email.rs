@@ -4,8 +4,12 @@
 //! following the universal JSON interface contract.
 
 use crate::{Coprocessor, CoprocessorError, Data, Health, MethodSignature};
+use crate::coprocessors::workspace_of;
+use crate::events::{Event, EventBus};
+use crate::workspaces::Workspaces;
 use async_trait::async_trait;
 use std::collections::HashMap;
+use std::sync::Arc;
 
 /// Email Coprocessor
 /// 
@@ -14,12 +18,26 @@ use std::collections::HashMap;
 pub struct EmailCoprocessor {
     // In a real implementation, this would hold the EmailService
     // For now, we'll provide mock functionality that matches the interface
+    events: Option<EventBus>,
+    workspaces: Option<Arc<Workspaces>>,
 }
 
 impl EmailCoprocessor {
     /// Create a new email coprocessor
     pub fn new() -> Self {
-        Self {}
+        Self { events: None, workspaces: None }
+    }
+
+    /// Publish an `email.received` event for each email found by `check_inbox`
+    pub fn with_events(mut self, events: EventBus) -> Self {
+        self.events = Some(events);
+        self
+    }
+
+    /// Check the inbox of this registry's default workspace when calls name none
+    pub fn with_workspaces(mut self, workspaces: Arc<Workspaces>) -> Self {
+        self.workspaces = Some(workspaces);
+        self
     }
 }
 
@@ -359,7 +377,7 @@ impl EmailCoprocessor {
     /// Check inbox for new emails
     async fn check_inbox(&self, args: Data) -> Result<Data, CoprocessorError> {
         // Extract optional parameters
-        let (folder, unread_only, limit) = if let Data::Object(ref obj) = args {
+        let (folder, unread_only, limit, workspace) = if let Data::Object(ref obj) = args {
             let folder = match obj.get("folder") {
                 Some(Data::String(s)) => s.clone(),
                 _ => "INBOX".to_string(),
@@ -375,9 +393,11 @@ impl EmailCoprocessor {
                 _ => 10,
             };
             
-            (folder, unread_only, limit)
+            let workspace = workspace_of(obj, self.workspaces.as_deref())?;
+            
+            (folder, unread_only, limit, workspace)
         } else {
-            ("INBOX".to_string(), true, 10)
+            ("INBOX".to_string(), true, 10, workspace_of(&HashMap::new(), self.workspaces.as_deref())?)
         };
 
         // Mock inbox check
@@ -395,6 +415,15 @@ impl EmailCoprocessor {
             emails.push(Data::Object(email));
         }
 
+        if let Some(events) = &self.events {
+            for email in &emails {
+                let mut payload = HashMap::new();
+                payload.insert("folder".to_string(), Data::String(folder.clone()));
+                payload.insert("email".to_string(), email.clone());
+                events.publish(Event::new("email.received", &workspace, Data::Object(payload)));
+            }
+        }
+
         let mut response = HashMap::new();
         response.insert("emails".to_string(), Data::Array(emails));
         response.insert("total".to_string(), Data::Number(3.0));
@@ -0,0 +1,449 @@
+//! HTTP Coprocessor
+//!
+//! Outbound HTTP calls from scripts. Every request must target a host on the
+//! calling workspace's allowlist, and redirects are checked against it too.
+//! Calls not naming a workspace are made for the default of the workspace
+//! registry (`with_workspaces`).
+//!
+//! Calls with an `idempotency_key`, as durable workflows retry them, send it as
+//! the `Idempotency-Key` header for servers that deduplicate on it.
+
+use crate::coprocessors::workspace_of;
+use crate::workspaces::Workspaces;
+use crate::{Coprocessor, CoprocessorError, Data, Health, MethodSignature};
+use async_trait::async_trait;
+use reqwest::{Method, Url};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{info, warn};
+
+const DEFAULT_TIMEOUT_MS: u64 = 10_000;
+const DEFAULT_MAX_RESPONSE_BYTES: usize = 1024 * 1024;
+const MAX_REDIRECTS: usize = 5;
+
+/// HTTP Coprocessor
+pub struct HttpCoprocessor {
+    /// Workspace -> allowed hosts; `*.example.com` matches subdomains, `host:port` pins a port
+    allowlist: HashMap<String, Vec<String>>,
+    default_timeout: Duration,
+    max_response_bytes: usize,
+    workspaces: Option<Arc<Workspaces>>,
+}
+
+/// Request body as given by the script
+enum Body {
+    Empty,
+    Json(serde_json::Value),
+    Form(Vec<(String, String)>),
+    Text(String),
+}
+
+struct HttpRequest {
+    method: Method,
+    url: Url,
+    headers: Vec<(String, String)>,
+    body: Body,
+    timeout: Duration,
+    retries: u32,
+    retry_delay: Duration,
+    workspace: String,
+}
+
+enum Failure {
+    Timeout,
+    Transport(String),
+    TooLarge,
+}
+
+impl HttpCoprocessor {
+    /// A coprocessor that allows no hosts at all
+    pub fn new() -> Self {
+        Self {
+            allowlist: HashMap::new(),
+            default_timeout: Duration::from_millis(DEFAULT_TIMEOUT_MS),
+            max_response_bytes: DEFAULT_MAX_RESPONSE_BYTES,
+            workspaces: None,
+        }
+    }
+
+    /// Configure from `HTTP_ALLOWLIST` (JSON object of workspace -> hosts),
+    /// `HTTP_TIMEOUT_MS` and `HTTP_MAX_RESPONSE_BYTES`
+    pub fn from_env() -> Self {
+        let mut coprocessor = Self::new();
+
+        if let Ok(json) = std::env::var("HTTP_ALLOWLIST") {
+            match serde_json::from_str::<HashMap<String, Vec<String>>>(&json) {
+                Ok(allowlist) => coprocessor.allowlist = allowlist,
+                Err(e) => warn!("Ignoring invalid HTTP_ALLOWLIST: {}", e),
+            }
+        }
+        if let Some(ms) = std::env::var("HTTP_TIMEOUT_MS").ok().and_then(|v| v.parse().ok()) {
+            coprocessor.default_timeout = Duration::from_millis(ms);
+        }
+        if let Some(bytes) = std::env::var("HTTP_MAX_RESPONSE_BYTES").ok().and_then(|v| v.parse().ok()) {
+            coprocessor.max_response_bytes = bytes;
+        }
+
+        coprocessor
+    }
+
+    /// Allow requests from `workspace` to these hosts
+    pub fn allow_hosts(mut self, workspace: &str, hosts: &[&str]) -> Self {
+        self.allowlist.entry(workspace.to_string())
+            .or_default()
+            .extend(hosts.iter().map(|h| h.to_lowercase()));
+        self
+    }
+
+    pub fn with_max_response_bytes(mut self, bytes: usize) -> Self {
+        self.max_response_bytes = bytes;
+        self
+    }
+
+    /// Make calls not naming a workspace for this registry's default
+    pub fn with_workspaces(mut self, workspaces: Arc<Workspaces>) -> Self {
+        self.workspaces = Some(workspaces);
+        self
+    }
+}
+
+impl Default for HttpCoprocessor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn host_allowed(allowed: &[String], url: &Url) -> bool {
+    let Some(host) = url.host_str().map(|h| h.to_lowercase()) else {
+        return false;
+    };
+    let with_port = url.port_or_known_default()
+        .map(|port| format!("{}:{}", host, port))
+        .unwrap_or_else(|| host.clone());
+
+    allowed.iter().any(|pattern| {
+        if let Some(domain) = pattern.strip_prefix("*.") {
+            host.ends_with(&format!(".{}", domain))
+        } else if pattern.contains(':') {
+            *pattern == with_port
+        } else {
+            *pattern == host
+        }
+    })
+}
+
+fn data_to_string(value: &Data) -> String {
+    match value {
+        Data::String(s) => s.clone(),
+        Data::Null => String::new(),
+        // Whole floats as integers: `page=2` rather than `page=2.0`
+        Data::Number(n) if n.fract() == 0.0 && n.abs() < 1e15 => format!("{}", *n as i64),
+        Data::Integer(i) => i.to_string(),
+        Data::DateTime(dt) => dt.to_rfc3339(),
+        Data::Id(id) => id.to_hex(),
+        other => other.to_json().to_string(),
+    }
+}
+
+fn string_pairs(value: Option<&Data>, field: &str) -> Result<Vec<(String, String)>, CoprocessorError> {
+    match value {
+        None | Some(Data::Null) => Ok(Vec::new()),
+        Some(Data::Object(obj)) => Ok(obj.iter().map(|(k, v)| (k.clone(), data_to_string(v))).collect()),
+        Some(_) => Err(CoprocessorError::InvalidArguments(
+            format!("'{}' must be an object", field),
+        )),
+    }
+}
+
+#[async_trait]
+impl Coprocessor for HttpCoprocessor {
+    fn class_name(&self) -> String {
+        "http".to_string()
+    }
+
+    fn methods(&self) -> Vec<MethodSignature> {
+        let request_properties = serde_json::json!({
+            "url": { "type": "string", "description": "Absolute http(s) URL" },
+            "headers": { "type": "object", "description": "Request headers" },
+            "query": { "type": "object", "description": "Query string parameters" },
+            "json": { "description": "JSON request body" },
+            "form": { "type": "object", "description": "Form-encoded request body" },
+            "body": { "type": "string", "description": "Raw request body" },
+            "timeout_ms": { "type": "number", "description": "Per-attempt timeout" },
+            "retries": { "type": "number", "description": "Extra attempts on network errors, 429 and 5xx" },
+            "retry_delay_ms": { "type": "number", "description": "Delay before the first retry, doubled each time" },
+            "workspace": { "type": "string", "description": "Workspace whose allowlist applies" }
+        });
+        let response_schema = serde_json::json!({
+            "type": "object",
+            "properties": {
+                "status": { "type": "number" },
+                "ok": { "type": "boolean", "description": "True for 2xx responses" },
+                "headers": { "type": "object" },
+                "body": { "description": "Parsed JSON, or text" },
+                "url": { "type": "string", "description": "Final URL after redirects" },
+                "attempts": { "type": "number" }
+            }
+        });
+
+        let simple = |name: &str, description: &str| MethodSignature {
+            name: name.to_string(),
+            description: description.to_string(),
+            input_schema: Some(serde_json::json!({
+                "type": "object",
+                "properties": request_properties.clone(),
+                "required": ["url"]
+            })),
+            output_schema: Some(response_schema.clone()),
+        };
+
+        let mut request = simple("request", "Send an HTTP request with any method");
+        if let Some(serde_json::Value::Object(schema)) = request.input_schema.as_mut() {
+            schema["properties"]["method"] = serde_json::json!({ "type": "string" });
+            schema["required"] = serde_json::json!(["method", "url"]);
+        }
+
+        vec![
+            simple("get", "Send a GET request"),
+            simple("post", "Send a POST request"),
+            simple("put", "Send a PUT request"),
+            simple("delete", "Send a DELETE request"),
+            request,
+        ]
+    }
+
+    async fn invoke(&self, method: &str, args: Data) -> Result<Data, CoprocessorError> {
+        let http_method = match method {
+            "get" => Some(Method::GET),
+            "post" => Some(Method::POST),
+            "put" => Some(Method::PUT),
+            "delete" => Some(Method::DELETE),
+            "request" => None,
+            _ => return Err(CoprocessorError::MethodNotFound(method.to_string())),
+        };
+
+        let request = self.parse_request(http_method, args)?;
+        self.send(request).await
+    }
+
+    async fn health(&self) -> Health {
+        Health::Healthy
+    }
+}
+
+impl HttpCoprocessor {
+    fn parse_request(&self, method: Option<Method>, args: Data) -> Result<HttpRequest, CoprocessorError> {
+        let obj = match args {
+            Data::Object(obj) => obj,
+            _ => {
+                return Err(CoprocessorError::InvalidArguments(
+                    "Expected object with 'url' field".to_string(),
+                ))
+            }
+        };
+
+        let method = match method {
+            Some(m) => m,
+            None => match obj.get("method") {
+                Some(Data::String(s)) => Method::from_bytes(s.to_uppercase().as_bytes())
+                    .map_err(|_| CoprocessorError::InvalidArguments(format!("Invalid method '{}'", s)))?,
+                _ => {
+                    return Err(CoprocessorError::InvalidArguments(
+                        "Missing or invalid 'method' field".to_string(),
+                    ))
+                }
+            },
+        };
+
+        let mut url = match obj.get("url") {
+            Some(Data::String(s)) => Url::parse(s)
+                .map_err(|e| CoprocessorError::InvalidArguments(format!("Invalid url '{}': {}", s, e)))?,
+            _ => {
+                return Err(CoprocessorError::InvalidArguments(
+                    "Missing or invalid 'url' field".to_string(),
+                ))
+            }
+        };
+        if url.scheme() != "http" && url.scheme() != "https" {
+            return Err(CoprocessorError::InvalidArguments(
+                format!("Unsupported scheme '{}'", url.scheme()),
+            ));
+        }
+
+        let query = string_pairs(obj.get("query"), "query")?;
+        if !query.is_empty() {
+            url.query_pairs_mut().extend_pairs(query);
+        }
+
+        let mut headers = string_pairs(obj.get("headers"), "headers")?;
+        if let Some(Data::String(key)) = obj.get("idempotency_key") {
+            if !headers.iter().any(|(name, _)| name.eq_ignore_ascii_case("idempotency-key")) {
+                headers.push(("Idempotency-Key".to_string(), key.clone()));
+            }
+        }
+
+        let body = match (obj.get("json"), obj.get("form"), obj.get("body")) {
+            (Some(json), None, None) => Body::Json(json.to_json()),
+            (None, Some(form), None) => Body::Form(string_pairs(Some(form), "form")?),
+            (None, None, Some(Data::String(text))) => Body::Text(text.clone()),
+            (None, None, Some(_)) => {
+                return Err(CoprocessorError::InvalidArguments(
+                    "'body' must be a string".to_string(),
+                ))
+            }
+            (None, None, None) => Body::Empty,
+            _ => {
+                return Err(CoprocessorError::InvalidArguments(
+                    "Use only one of 'json', 'form' or 'body'".to_string(),
+                ))
+            }
+        };
+
+        let timeout = match obj.get("timeout_ms").and_then(Data::as_f64) {
+            Some(ms) if ms > 0.0 => Duration::from_millis(ms as u64),
+            _ => self.default_timeout,
+        };
+
+        let retries = match obj.get("retries").and_then(Data::as_f64) {
+            Some(n) if n >= 0.0 => (n as u32).min(10),
+            _ => 0,
+        };
+
+        let retry_delay = match obj.get("retry_delay_ms").and_then(Data::as_f64) {
+            Some(ms) if ms >= 0.0 => Duration::from_millis(ms as u64),
+            _ => Duration::from_millis(200),
+        };
+
+        let workspace = workspace_of(&obj, self.workspaces.as_deref())?;
+
+        Ok(HttpRequest { method, url, headers, body, timeout, retries, retry_delay, workspace })
+    }
+
+    async fn send(&self, request: HttpRequest) -> Result<Data, CoprocessorError> {
+        let allowed = self.allowlist.get(&request.workspace).cloned().unwrap_or_default();
+        if !host_allowed(&allowed, &request.url) {
+            return Err(CoprocessorError::InvalidArguments(format!(
+                "Host '{}' is not on the allowlist of workspace '{}'",
+                request.url.host_str().unwrap_or(""),
+                request.workspace
+            )));
+        }
+
+        // Redirects must stay on the allowlist too
+        let client = reqwest::Client::builder()
+            .timeout(request.timeout)
+            .redirect(reqwest::redirect::Policy::custom(move |attempt| {
+                if attempt.previous().len() >= MAX_REDIRECTS {
+                    attempt.error("Too many redirects")
+                } else if host_allowed(&allowed, attempt.url()) {
+                    attempt.follow()
+                } else {
+                    attempt.error("Redirect to a host that is not on the allowlist")
+                }
+            }))
+            .build()
+            .map_err(|e| CoprocessorError::ExecutionError(format!("Failed to build HTTP client: {}", e)))?;
+
+        info!("HTTP {} {} (workspace '{}')", request.method, request.url, request.workspace);
+
+        let mut attempts = 0;
+        loop {
+            attempts += 1;
+            let outcome = self.attempt(&client, &request).await;
+
+            let retryable = match &outcome {
+                Ok((status, _)) => *status == 429 || *status >= 500,
+                Err(Failure::Timeout) | Err(Failure::Transport(_)) => true,
+                Err(Failure::TooLarge) => false,
+            };
+
+            if retryable && attempts <= request.retries {
+                let delay = request.retry_delay * 2u32.pow(attempts - 1);
+                warn!("HTTP {} {} failed, retrying in {:?}", request.method, request.url, delay);
+                tokio::time::sleep(delay).await;
+                continue;
+            }
+
+            return match outcome {
+                Ok((_, Data::Object(mut response))) => {
+                    response.insert("attempts".to_string(), Data::Number(attempts as f64));
+                    Ok(Data::Object(response))
+                }
+                Ok((_, response)) => Ok(response),
+                Err(Failure::Timeout) => Err(CoprocessorError::Timeout),
+                Err(Failure::Transport(e)) => Err(CoprocessorError::ExecutionError(
+                    format!("HTTP request failed: {}", e),
+                )),
+                Err(Failure::TooLarge) => Err(CoprocessorError::ExecutionError(
+                    format!("Response exceeds {} bytes", self.max_response_bytes),
+                )),
+            };
+        }
+    }
+
+    /// One attempt; the response is returned whatever its status
+    async fn attempt(&self, client: &reqwest::Client, request: &HttpRequest) -> Result<(u16, Data), Failure> {
+        let mut builder = client.request(request.method.clone(), request.url.clone());
+        for (name, value) in &request.headers {
+            builder = builder.header(name.as_str(), value.as_str());
+        }
+        builder = match &request.body {
+            Body::Empty => builder,
+            Body::Json(json) => builder.json(json),
+            Body::Form(pairs) => builder.form(pairs),
+            Body::Text(text) => builder.body(text.clone()),
+        };
+
+        let transport = |e: reqwest::Error| {
+            if e.is_timeout() {
+                Failure::Timeout
+            } else {
+                Failure::Transport(e.to_string())
+            }
+        };
+
+        let mut response = builder.send().await.map_err(transport)?;
+
+        if response.content_length().is_some_and(|len| len as usize > self.max_response_bytes) {
+            return Err(Failure::TooLarge);
+        }
+
+        let status = response.status().as_u16();
+        let final_url = response.url().to_string();
+        let headers: HashMap<String, Data> = response.headers().iter()
+            .filter_map(|(k, v)| v.to_str().ok().map(|v| (k.as_str().to_string(), Data::String(v.to_string()))))
+            .collect();
+        let is_json = response.headers().get(reqwest::header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .is_some_and(|v| v.contains("json"));
+
+        // Read in chunks so an oversized body without Content-Length is cut off early
+        let mut bytes = Vec::new();
+        while let Some(chunk) = response.chunk().await.map_err(transport)? {
+            if bytes.len() + chunk.len() > self.max_response_bytes {
+                return Err(Failure::TooLarge);
+            }
+            bytes.extend_from_slice(&chunk);
+        }
+
+        let body = if bytes.is_empty() {
+            Data::Null
+        } else if is_json {
+            serde_json::from_slice(&bytes)
+                .map(Data::from_json)
+                .unwrap_or_else(|_| Data::String(String::from_utf8_lossy(&bytes).into_owned()))
+        } else {
+            Data::String(String::from_utf8_lossy(&bytes).into_owned())
+        };
+
+        let mut result = HashMap::new();
+        result.insert("status".to_string(), Data::Number(status as f64));
+        result.insert("ok".to_string(), Data::Bool((200..300).contains(&status)));
+        result.insert("headers".to_string(), Data::Object(headers));
+        result.insert("body".to_string(), body);
+        result.insert("url".to_string(), Data::String(final_url));
+
+        Ok((status, Data::Object(result)))
+    }
+}
@@ -7,10 +7,26 @@ pub mod email;
 pub mod real_email;
 pub mod auth;
 pub mod database;
+pub mod http;
 
 // Re-export for convenience
 pub use semantic_compressor::SemanticCompressorCoprocessor;
 pub use email::EmailCoprocessor;
 pub use real_email::RealEmailCoprocessor;
 pub use auth::AuthCoprocessor;
-pub use database::DatabaseCoprocessor;
\ No newline at end of file
+pub use database::DatabaseCoprocessor;
+pub use http::HttpCoprocessor;
+
+use crate::workspaces::Workspaces;
+use crate::{CoprocessorError, Data};
+use std::collections::HashMap;
+
+/// The workspace a call names, or else the default of `workspaces`; without a
+/// registry, calls must name theirs
+pub(crate) fn workspace_of(obj: &HashMap<String, Data>, workspaces: Option<&Workspaces>) -> Result<String, CoprocessorError> {
+    match (obj.get("workspace"), workspaces) {
+        (Some(Data::String(s)), _) => Ok(s.clone()),
+        (_, Some(workspaces)) => Ok(workspaces.default_workspace().id.clone()),
+        _ => Err(CoprocessorError::InvalidArguments("Missing 'workspace' field".to_string())),
+    }
+}
\ No newline at end of file
@@ -2,8 +2,19 @@
 //! 
 //! Actually sends and receives emails using the same configuration
 //! as the working spu-rust implementation
+//!
+//! With a store (`with_idempotency`), emails sent with an `idempotency_key`
+//! are recorded and not sent again when the call is retried; a crash between
+//! sending and recording still sends twice.
+//!
+//! Calls not naming a workspace are made for the default of the workspace
+//! registry (`with_workspaces`).
 
 use crate::{Coprocessor, CoprocessorError, Data, Health, MethodSignature};
+use crate::coprocessors::workspace_of;
+use crate::events::{Event, EventBus};
+use crate::store::{idempotency, DocumentStore, Namespace};
+use crate::workspaces::Workspaces;
 use async_trait::async_trait;
 use lettre::{
     message::{header::ContentType, Message},
@@ -11,6 +22,7 @@ use lettre::{
     AsyncSmtpTransport, AsyncTransport,
 };
 use std::collections::HashMap;
+use std::sync::Arc;
 use tracing::{error, info};
 
 /// SMTP Configuration
@@ -45,15 +57,40 @@ impl SmtpConfig {
 /// Real Email Coprocessor using AWS SES
 pub struct RealEmailCoprocessor {
     smtp_config: SmtpConfig,
+    events: Option<EventBus>,
+    sent: Option<Arc<dyn DocumentStore>>,
+    workspaces: Option<Arc<Workspaces>>,
 }
 
 impl RealEmailCoprocessor {
     pub fn new() -> Self {
         Self {
             smtp_config: SmtpConfig::from_env(),
+            events: None,
+            sent: None,
+            workspaces: None,
         }
     }
     
+    /// Publish an `email.sent` event for each email sent
+    pub fn with_events(mut self, events: EventBus) -> Self {
+        self.events = Some(events);
+        self
+    }
+    
+    /// Record emails sent with an `idempotency_key` in `store`, in the
+    /// workspace they were sent for, so retries don't send them again
+    pub fn with_idempotency(mut self, store: Arc<dyn DocumentStore>) -> Self {
+        self.sent = Some(store);
+        self
+    }
+    
+    /// Send emails not naming a workspace for this registry's default
+    pub fn with_workspaces(mut self, workspaces: Arc<Workspaces>) -> Self {
+        self.workspaces = Some(workspaces);
+        self
+    }
+    
     async fn send_email_internal(
         &self,
         to: Vec<String>,
@@ -210,11 +247,36 @@ impl Coprocessor for RealEmailCoprocessor {
     }
 
     async fn invoke(&self, method: &str, args: Data) -> Result<Data, CoprocessorError> {
-        match method {
+        let once = self.sent.clone().zip(idempotency::key_of(&args));
+        // Only calls to deduplicate need their workspace here
+        let workspace = match (&once, &args) {
+            (Some(_), Data::Object(obj)) => workspace_of(obj, self.workspaces.as_deref())?,
+            _ => String::new(),
+        };
+        let ns = Namespace::new(&workspace, idempotency::IDEMPOTENCY_COLLECTION);
+        if let Some((store, key)) = &once {
+            let recorded = idempotency::recorded(store.as_ref(), ns, key).await.map_err(|e| {
+                error!("{}", e);
+                CoprocessorError::ExecutionError(e)
+            })?;
+            if let Some(result) = recorded {
+                info!("Email {} was already sent, not sending again", key);
+                return Ok(result);
+            }
+        }
+        
+        let result = match method {
             "send" => self.send_email(args).await,
             "send_code" => self.send_auth_code(args).await,
             _ => Err(CoprocessorError::MethodNotFound(method.to_string())),
+        }?;
+        if let Some((store, key)) = &once {
+            // Sent already; failing now would only have it sent again
+            if let Err(e) = idempotency::record(store.as_ref(), ns, key, method, &result).await {
+                error!("{}", e);
+            }
         }
+        Ok(result)
     }
 
     async fn health(&self) -> Health {
@@ -233,7 +295,7 @@ impl Coprocessor for RealEmailCoprocessor {
 impl RealEmailCoprocessor {
     async fn send_email(&self, args: Data) -> Result<Data, CoprocessorError> {
         // Extract arguments
-        let (to, subject, body, html) = match args {
+        let (to, subject, body, html, workspace) = match args {
             Data::Object(ref obj) => {
                 let to = match obj.get("to") {
                     Some(Data::String(s)) => vec![s.clone()],
@@ -272,7 +334,9 @@ impl RealEmailCoprocessor {
                     _ => None,
                 };
                 
-                (to, subject, body, html)
+                let workspace = workspace_of(obj, self.workspaces.as_deref())?;
+                
+                (to, subject, body, html, workspace)
             }
             _ => {
                 return Err(CoprocessorError::InvalidArguments(
@@ -282,10 +346,18 @@ impl RealEmailCoprocessor {
         };
 
         // Actually send the email via AWS SES
-        match self.send_email_internal(to.clone(), subject, body, html).await {
+        match self.send_email_internal(to.clone(), subject.clone(), body, html).await {
             Ok(message_id) => {
                 info!("Email sent successfully to {:?}", to);
                 
+                if let Some(events) = &self.events {
+                    let mut payload = HashMap::new();
+                    payload.insert("to".to_string(), Data::Array(to.iter().cloned().map(Data::String).collect()));
+                    payload.insert("subject".to_string(), Data::String(subject));
+                    payload.insert("message_id".to_string(), Data::String(message_id.clone()));
+                    events.publish(Event::new("email.sent", &workspace, Data::Object(payload)));
+                }
+                
                 let mut response = HashMap::new();
                 response.insert("sent".to_string(), Data::Bool(true));
                 response.insert("message_id".to_string(), Data::String(message_id));
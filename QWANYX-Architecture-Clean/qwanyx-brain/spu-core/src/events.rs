@@ -0,0 +1,78 @@
+//! Event Bus
+//!
+//! In-process broadcast of things that happened in a workspace: documents written
+//! by the database coprocessor, emails seen by the email coprocessors, users
+//! registered or verified by auth. Triggers subscribe to it to run scripts.
+
+use crate::Data;
+use chrono::{DateTime, Utc};
+use std::collections::HashMap;
+use tokio::sync::broadcast;
+use tracing::debug;
+
+tokio::task_local! {
+    /// How many triggers deep the current task is; stamped on the events it publishes
+    pub(crate) static TRIGGER_DEPTH: u32;
+}
+
+#[derive(Debug, Clone)]
+pub struct Event {
+    /// Dotted event name, e.g. `database.inserted` or `auth.user_verified`
+    pub name: String,
+    pub workspace: String,
+    pub payload: Data,
+    pub occurred_at: DateTime<Utc>,
+    /// Number of trigger runs that led to this event; 0 for direct requests
+    pub depth: u32,
+}
+
+impl Event {
+    pub fn new(name: &str, workspace: &str, payload: Data) -> Self {
+        Self {
+            name: name.to_string(),
+            workspace: workspace.to_string(),
+            payload,
+            occurred_at: Utc::now(),
+            depth: TRIGGER_DEPTH.try_with(|depth| *depth).unwrap_or(0),
+        }
+    }
+
+    /// The event as a script variable
+    pub fn to_data(&self) -> Data {
+        let mut event = HashMap::new();
+        event.insert("name".to_string(), Data::String(self.name.clone()));
+        event.insert("workspace".to_string(), Data::String(self.workspace.clone()));
+        event.insert("payload".to_string(), self.payload.clone());
+        event.insert("occurred_at".to_string(), Data::String(self.occurred_at.to_rfc3339()));
+        Data::Object(event)
+    }
+}
+
+/// Cloneable handle to the runtime's event channel
+#[derive(Clone)]
+pub struct EventBus {
+    sender: broadcast::Sender<Event>,
+}
+
+impl EventBus {
+    pub fn new(capacity: usize) -> Self {
+        let (sender, _) = broadcast::channel(capacity);
+        Self { sender }
+    }
+
+    /// Publish an event; it is dropped if nobody is subscribed
+    pub fn publish(&self, event: Event) {
+        debug!("Publishing event {} in workspace {}", event.name, event.workspace);
+        let _ = self.sender.send(event);
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.sender.subscribe()
+    }
+}
+
+impl Default for EventBus {
+    fn default() -> Self {
+        Self::new(1024)
+    }
+}
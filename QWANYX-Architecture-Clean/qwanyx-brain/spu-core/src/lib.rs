@@ -3,11 +3,28 @@
 //! Universal runtime for intelligence, regardless of substrate.
 //! Objects can be Rust services, Python models, humans, or any computational entity.
 
+pub mod bson_data;
 pub mod coprocessors;
 pub mod parser;
 pub mod simple_parser;
 pub mod runtime;
+pub mod store;
+pub mod auth;
+pub mod workflow;
+pub mod scheduler;
+pub mod events;
+pub mod triggers;
+pub mod webhooks;
+pub mod pipelines;
+pub mod migrations;
+pub mod subscriptions;
+pub mod search;
+pub mod workspaces;
+pub mod audit;
+pub mod api;
 
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine as _};
+use chrono::Datelike;
 use serde::{Deserialize, Serialize};
 use serde_json::Value as JsonValue;
 use std::collections::HashMap;
@@ -21,8 +38,11 @@ use uuid::Uuid;
 // ================================================================================
 
 /// Universal data type for all SPU operations
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
-#[serde(untagged)]
+///
+/// `to_json` keeps the typed variants as extended JSON forms (`{"$numberLong": "42"}`,
+/// `{"$date": "..."}`, `{"$binary": ...}`, `{"$oid": "..."}`) so that records and
+/// script inputs come back with their type; HTTP responses use `to_plain_json`.
+#[derive(Debug, Clone, PartialEq)]
 pub enum Data {
     Null,
     Bool(bool),
@@ -30,8 +50,13 @@ pub enum Data {
     String(String),
     Array(Vec<Data>),
     Object(HashMap<String, Data>),
-    #[serde(skip)]
     ObjectRef(ObjectId),
+    /// 64-bit integer, for ids, counters and amounts that don't fit in a float
+    Integer(i64),
+    DateTime(chrono::DateTime<chrono::Utc>),
+    Bytes(Vec<u8>),
+    /// MongoDB document id
+    Id(mongodb::bson::oid::ObjectId),
 }
 
 impl Data {
@@ -44,6 +69,9 @@ impl Data {
             JsonValue::String(s) => Data::String(s),
             JsonValue::Array(arr) => Data::Array(arr.into_iter().map(Data::from_json).collect()),
             JsonValue::Object(obj) => {
+                if let Some(typed) = Data::from_extjson(&obj) {
+                    return typed;
+                }
                 Data::Object(obj.into_iter().map(|(k, v)| (k, Data::from_json(v))).collect())
             }
         }
@@ -61,8 +89,86 @@ impl Data {
                 JsonValue::Object(obj.iter().map(|(k, v)| (k.clone(), v.to_json())).collect())
             }
             Data::ObjectRef(id) => JsonValue::String(format!("@{}", id.0)),
+            Data::Integer(i) => serde_json::json!({ "$numberLong": i.to_string() }),
+            Data::DateTime(dt) => {
+                // RFC 3339 only covers four-digit years
+                if (0..=9999).contains(&dt.year()) {
+                    serde_json::json!({ "$date": dt.to_rfc3339_opts(chrono::SecondsFormat::AutoSi, true) })
+                } else {
+                    serde_json::json!({ "$date": { "$numberLong": dt.timestamp_millis().to_string() } })
+                }
+            }
+            Data::Bytes(bytes) => serde_json::json!({
+                "$binary": { "base64": BASE64.encode(bytes), "subType": "00" }
+            }),
+            Data::Id(oid) => serde_json::json!({ "$oid": oid.to_hex() }),
         }
     }
+
+    /// Convert to the plain JSON of the HTTP API: integers as numbers, ids and
+    /// dates as strings, bytes as base64
+    pub fn to_plain_json(&self) -> JsonValue {
+        match self {
+            Data::Array(arr) => JsonValue::Array(arr.iter().map(|d| d.to_plain_json()).collect()),
+            Data::Object(obj) => {
+                JsonValue::Object(obj.iter().map(|(k, v)| (k.clone(), v.to_plain_json())).collect())
+            }
+            Data::ObjectRef(id) => JsonValue::String(id.0.clone()),
+            Data::Integer(i) => JsonValue::from(*i),
+            Data::DateTime(dt) => JsonValue::String(dt.to_rfc3339_opts(chrono::SecondsFormat::AutoSi, true)),
+            Data::Bytes(bytes) => JsonValue::String(BASE64.encode(bytes)),
+            Data::Id(oid) => JsonValue::String(oid.to_hex()),
+            other => other.to_json(),
+        }
+    }
+
+    /// Numeric value of a `Number` or `Integer`
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            Data::Number(n) => Some(*n),
+            Data::Integer(i) => Some(*i as f64),
+            _ => None,
+        }
+    }
+
+    /// The typed value an extended JSON object stands for, if it is one
+    fn from_extjson(obj: &serde_json::Map<String, JsonValue>) -> Option<Self> {
+        if obj.len() != 1 {
+            return None;
+        }
+        let (key, value) = obj.iter().next()?;
+        match (key.as_str(), value) {
+            ("$numberLong", JsonValue::String(s)) => s.parse().ok().map(Data::Integer),
+            ("$oid", JsonValue::String(s)) => mongodb::bson::oid::ObjectId::parse_str(s).ok().map(Data::Id),
+            ("$date", JsonValue::String(s)) => chrono::DateTime::parse_from_rfc3339(s).ok()
+                .map(|dt| Data::DateTime(dt.with_timezone(&chrono::Utc))),
+            ("$date", JsonValue::Object(millis)) => match millis.get("$numberLong") {
+                Some(JsonValue::String(s)) if millis.len() == 1 => s.parse().ok()
+                    .and_then(chrono::DateTime::from_timestamp_millis)
+                    .map(Data::DateTime),
+                _ => None,
+            },
+            ("$binary", JsonValue::Object(binary)) => match (binary.get("base64"), binary.get("subType")) {
+                (Some(JsonValue::String(b64)), Some(JsonValue::String(sub))) if binary.len() == 2 && sub == "00" => {
+                    BASE64.decode(b64).ok().map(Data::Bytes)
+                }
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+}
+
+impl Serialize for Data {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.to_json().serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Data {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        JsonValue::deserialize(deserializer).map(Data::from_json)
+    }
 }
 
 impl Default for Data {
@@ -115,6 +221,13 @@ pub trait Coprocessor: Send + Sync {
     /// Invoke a method on this coprocessor
     async fn invoke(&self, method: &str, args: Data) -> Result<Data, CoprocessorError>;
     
+    /// Invoke a method for a user; coprocessors enforcing permissions override
+    /// this, the others ignore who is calling
+    async fn invoke_as(&self, method: &str, args: Data, caller: Option<&auth::policy::Caller>) -> Result<Data, CoprocessorError> {
+        let _ = caller;
+        self.invoke(method, args).await
+    }
+    
     /// Check if this coprocessor can handle a method
     fn can_handle(&self, method: &str) -> bool {
         self.methods().iter().any(|m| m.name == method)
@@ -138,6 +251,14 @@ pub enum CoprocessorError {
     #[error("Execution error: {0}")]
     ExecutionError(String),
     
+    /// The caller may not do this
+    #[error("Forbidden: {0}")]
+    Forbidden(String),
+    
+    /// What the call names does not exist
+    #[error("Not found: {0}")]
+    NotFound(String),
+    
     #[error("Timeout")]
     Timeout,
     
@@ -209,7 +330,16 @@ pub enum Instruction {
     CallFn { name: String, args: Vec<Data>, target: String },
     Len { collection: String, target: String },
     Parallel { tasks: Vec<Vec<Instruction>>, target: String },
-    Race { tasks: Vec<Vec<Instruction>>, target: String }
+    Race { tasks: Vec<Vec<Instruction>>, target: String },
+    
+    // Durable workflows
+    Sleep { duration: String },
+    WaitEvent { event: String, target: String },
+    
+    // Transactions
+    Begin { object: String },
+    Commit { object: String },
+    Rollback { object: String }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
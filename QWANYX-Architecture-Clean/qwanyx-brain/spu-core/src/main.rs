@@ -4,59 +4,338 @@
 //! Executes assembly scripts and coordinates coprocessors
 
 use actix_cors::Cors;
-use actix_web::{middleware, web, App, HttpServer, HttpResponse};
-use serde::{Deserialize, Serialize};
+use actix_web::{body::MessageBody, dev::{ServiceRequest, ServiceResponse}, middleware, web, App, HttpMessage, HttpServer, HttpResponse};
+use serde::Deserialize;
 use serde_json::json;
+use futures::StreamExt;
 use std::sync::Arc;
 use tracing::{error, info};
+use utoipa::{IntoParams, OpenApi, ToSchema};
+use utoipa_scalar::{Scalar, Servable};
+use validator::Validate;
 
-use spu_core::{runtime::SPURuntime, Data};
+use spu_core::{runtime::{CallPolicy, ScriptError, SPURuntime}, Data};
+use spu_core::api::{self, ApiError, OptionalJson, ValidJson};
+use spu_core::store::{DocumentStore, MongoStore};
+use spu_core::audit::{self, AuditLog, AuditQuery};
+use spu_core::auth::api_keys::{self, ApiKeyError, ApiKeyStore, ApiKeys, MemoryApiKeyStore, MongoApiKeyStore};
+use spu_core::auth::codes::{CodePolicy, CodeStore, MemoryCodeStore, MongoCodeStore};
+use spu_core::auth::magic_links::{self, LinkStore, MemoryLinkStore, MongoLinkStore};
+use spu_core::auth::oidc::{MemoryOidcStore, MongoOidcStore, Oidc, OidcError, OidcStore};
+use spu_core::auth::policy::{self, Caller};
+use spu_core::auth::sessions::{MemorySessionStore, MongoSessionStore, SessionError, SessionPolicy, SessionStore, Sessions};
+use spu_core::auth::tokens::{self, Claims, TokenError, Tokens};
+use spu_core::auth::two_factor::{MemoryTwoFactorStore, MongoTwoFactorStore, TwoFactor, TwoFactorError, TwoFactorStore};
+use spu_core::auth::users;
+use spu_core::workflow::{MemoryWorkflowStore, MongoWorkflowStore, WorkflowEngine, WorkflowRecord, WorkflowStore};
+use spu_core::scheduler::{MemoryScheduleStore, MongoScheduleStore, Schedule, ScheduleStore, Scheduler};
+use spu_core::triggers::{MemoryTriggerStore, MongoTriggerStore, TriggerDispatcher, TriggerStore};
+use spu_core::migrations::{self, MigrateCommand, Migrator, MIGRATE_USAGE};
+use spu_core::search::{SearchEngine, SearchError};
+use spu_core::subscriptions::{self, Subscription, SubscriptionError, Subscriptions};
+use spu_core::pipelines::{MemoryPipelineStore, MongoPipelineStore, PipelineError, PipelineLibrary, PipelineStore};
+use spu_core::webhooks::{MemoryWebhookStore, MongoWebhookStore, WebhookAuth, WebhookError, WebhookRouter, WebhookStore};
+use spu_core::workspaces::{Workspace, WorkspaceDatabases, WorkspaceStore, Workspaces};
 use spu_core::coprocessors::{
     RealEmailCoprocessor, 
     SemanticCompressorCoprocessor,
     AuthCoprocessor,
     DatabaseCoprocessor,
+    HttpCoprocessor,
 };
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, ToSchema, Validate)]
 struct RegisterRequest {
+    #[validate(email)]
     email: String,
     #[serde(rename = "firstName")]
+    #[validate(length(min = 1, max = 100))]
     first_name: String,
     #[serde(rename = "lastName")]
+    #[validate(length(min = 1, max = 100))]
     last_name: String,
+    #[validate(length(max = 40))]
     phone: String,
+    /// `particulier` or `professionnel`
     #[serde(rename = "accountType")]
+    #[validate(custom(function = "account_type"))]
     account_type: String,
-    workspace: String,
+    #[serde(default)]
+    workspace: Option<String>,
 }
 
-#[derive(Debug, Deserialize)]
+/// Account types are roles, so people registering pick one anyone may have
+fn account_type(value: &str) -> Result<(), validator::ValidationError> {
+    if users::ACCOUNT_TYPES.contains(&value) {
+        return Ok(());
+    }
+    let message = format!("must be one of {}", users::ACCOUNT_TYPES.join(", "));
+    Err(validator::ValidationError::new("account_type").with_message(message.into()))
+}
+
+#[derive(Debug, Deserialize, ToSchema, Validate)]
 struct LoginRequest {
+    #[validate(email)]
     email: String,
+    #[serde(default)]
+    workspace: Option<String>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, ToSchema, Validate)]
 struct VerifyCodeRequest {
+    #[validate(email)]
     email: String,
+    #[validate(length(min = 1, max = 32))]
+    code: String,
+    #[serde(default)]
+    workspace: Option<String>,
+}
+
+#[derive(Debug, Deserialize, ToSchema, Validate)]
+struct MagicLinkRequest {
+    #[validate(length(min = 1, max = 512))]
+    token: String,
+    #[serde(default)]
+    workspace: Option<String>,
+}
+
+#[derive(Debug, Deserialize, ToSchema, Validate)]
+struct TwoFactorRequest {
+    #[validate(length(min = 1, max = 512))]
+    challenge: String,
+    #[validate(length(min = 1, max = 32))]
     code: String,
+    #[serde(default)]
+    workspace: Option<String>,
 }
 
-#[derive(Debug, Serialize)]
-struct AuthResponse {
-    success: bool,
-    message: Option<String>,
-    token: Option<String>,
-    requires_code: Option<bool>,
+#[derive(Debug, Default, Deserialize, ToSchema, Validate)]
+struct EnrollRequest {
+    /// Of a sign-in, for users whose role requires a second factor they don't
+    /// have yet; the caller's token otherwise
+    #[serde(default)]
+    challenge: Option<String>,
+    #[serde(default)]
+    workspace: Option<String>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, ToSchema, Validate)]
+struct OidcCallbackRequest {
+    /// As the provider sent them back to the redirect page
+    #[validate(length(min = 1, max = 4096))]
+    code: String,
+    #[validate(length(min = 1, max = 512))]
+    state: String,
+    #[serde(default)]
+    workspace: Option<String>,
+}
+
+#[derive(Debug, Deserialize, ToSchema, Validate)]
+struct TwoFactorCodeRequest {
+    /// Of the authenticator app, or a recovery code
+    #[validate(length(min = 1, max = 32))]
+    code: String,
+}
+
+#[derive(Debug, Deserialize, ToSchema, Validate)]
 struct ExecuteRequest {
-    script: String,  // Assembly script to execute
+    #[validate(length(min = 1))]
+    script: String,
+    #[serde(default)]
+    #[schema(value_type = Object)]
+    inputs: std::collections::HashMap<String, serde_json::Value>,
+}
+
+#[derive(Debug, Deserialize, ToSchema, Validate)]
+struct StartWorkflowRequest {
+    #[validate(length(min = 1))]
+    script: String,
+    #[serde(default)]
+    #[schema(value_type = Object)]
+    inputs: std::collections::HashMap<String, serde_json::Value>,
+    /// For calls cut short by a crash: `at_most_once` (default) or `idempotent`
+    #[serde(default)]
+    #[schema(value_type = String)]
+    policy: CallPolicy,
+}
+
+#[derive(Debug, Deserialize, ToSchema, Validate)]
+struct ScheduleRequest {
+    #[validate(length(min = 1, max = 100))]
+    name: String,
+    /// 5 fields, or 6-7 with seconds
+    #[validate(length(min = 1, max = 100))]
+    cron: String,
+    /// IANA name, UTC when not set
+    timezone: Option<String>,
+    #[validate(length(min = 1))]
+    script: String,
+    #[serde(default = "default_enabled")]
+    enabled: bool,
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct RunsQuery {
+    limit: Option<usize>,
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct ValidateQuery {
+    /// Most invalid documents to list
+    limit: Option<usize>,
+}
+
+#[derive(Debug, Deserialize, ToSchema, Validate)]
+struct WebhookRequest {
+    #[validate(length(min = 1, max = 100))]
+    name: String,
+    /// `{"type": "hmac", "secret": ...}` or `{"type": "token", "token": ...}`
+    #[schema(value_type = Object)]
+    auth: WebhookAuth,
+    #[validate(length(min = 1))]
+    script: String,
+    /// Of the answers to callers
+    #[serde(default = "default_webhook_status")]
+    #[validate(range(min = 100, max = 599))]
+    status: u16,
+    #[serde(default = "default_enabled")]
+    enabled: bool,
+}
+
+fn default_webhook_status() -> u16 {
+    200
+}
+
+#[derive(Debug, Deserialize, ToSchema, Validate)]
+struct PipelineRequest {
+    #[validate(length(min = 1, max = 100))]
+    name: String,
+    #[validate(length(min = 1, max = 100))]
+    collection: String,
+    /// MongoDB aggregation stages, with `{"$param": name}` placeholders
+    #[schema(value_type = Vec<Object>)]
+    pipeline: serde_json::Value,
+    /// Defaults of the parameters
+    #[serde(default)]
+    #[schema(value_type = Object)]
+    parameters: std::collections::HashMap<String, serde_json::Value>,
+    description: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize, ToSchema, Validate)]
+struct PipelineRunRequest {
+    #[serde(default)]
+    #[schema(value_type = Object)]
+    params: std::collections::HashMap<String, serde_json::Value>,
+    limit: Option<u64>,
+}
+
+#[derive(Debug, Deserialize, ToSchema, Validate)]
+struct ApiKeyRequest {
+    /// The service account the key acts as
+    #[validate(length(min = 1, max = 100))]
+    account: String,
+    #[serde(default)]
+    roles: Vec<String>,
+    /// Never expires when not set
+    #[validate(range(min = 1, max = 3650))]
+    expires_in_days: Option<i64>,
+}
+
+#[derive(Debug, Default, Deserialize, ToSchema, Validate)]
+struct RotateApiKeyRequest {
+    /// How long the old key keeps working, up to a week
+    #[serde(default)]
+    #[validate(range(min = 0, max = 10080))]
+    grace_minutes: i64,
+}
+
+#[derive(Debug, Deserialize, ToSchema, Validate)]
+struct TriggerRequest {
+    #[validate(length(min = 1, max = 100))]
+    name: String,
+    /// As `database.inserted` or `auth.user_registered`
+    #[validate(length(min = 1, max = 100))]
+    event: String,
+    /// Payload fields that must be equal, as `{"collection": "requests"}`
+    #[serde(default)]
+    #[schema(value_type = Object)]
+    filter: std::collections::HashMap<String, serde_json::Value>,
+    #[validate(length(min = 1))]
+    script: String,
+    #[serde(default = "default_enabled")]
+    enabled: bool,
+}
+
+/// The OpenAPI document of the routes, served at `/openapi.json` and shown at
+/// `/docs`; `spu-core openapi` prints it
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "SPU Core", description = "Orchestrator HTTP API. Failures are always answered with `ApiError`."),
+    paths(
+        health_check,
+        auth_register, auth_request_code, auth_verify_code, auth_magic_link, auth_verify_magic_link,
+        auth_verify_two_factor, enroll_two_factor, confirm_two_factor, disable_two_factor, regenerate_recovery_codes,
+        list_oidc_providers, oidc_authorize, oidc_callback, list_identities, link_identity, unlink_identity,
+        auth_refresh, auth_logout, auth_logout_all, list_sessions, revoke_session, jwks,
+        execute_assembly,
+        start_workflow, deliver_workflow_event, get_workflow, wake_workflow,
+        list_schedules, save_schedule, get_schedule, delete_schedule, run_schedule, list_schedule_runs,
+        list_triggers, save_trigger, delete_trigger,
+        list_webhooks, save_webhook, delete_webhook, call_webhook,
+        list_pipelines, save_pipeline, get_pipeline, delete_pipeline, run_pipeline,
+        list_schemas, get_schema, save_schema, delete_schema, validate_collection,
+        list_api_keys, create_api_key, rotate_api_key, revoke_api_key,
+        get_audit, get_policy, save_policy,
+        search_collection, configure_search, drop_search, get_search_config, reindex_search,
+        get_users, update_user,
+        store_data, retrieve_data, get_data_by_id, update_data, delete_data, subscribe,
+    ),
+    modifiers(&SecuritySchemes),
+    tags(
+        (name = "auth", description = "Registration and sign-in with emailed codes and links"),
+        (name = "two-factor", description = "Authenticator apps and recovery codes"),
+        (name = "oidc", description = "Sign-in with OpenID Connect providers, and linked accounts"),
+        (name = "sessions", description = "Refresh tokens, logout and the caller's sessions"),
+        (name = "api-keys", description = "Keys of service accounts; admins only"),
+        (name = "audit", description = "Who did what; admins only"),
+        (name = "policy", description = "What each role may read and write"),
+        (name = "data", description = "Documents of the caller's workspace, and their live changes"),
+    ),
+)]
+struct ApiDoc;
+
+/// How callers authenticate: the access token of a session, or an API key
+struct SecuritySchemes;
+
+impl utoipa::Modify for SecuritySchemes {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        use utoipa::openapi::security::{ApiKey, ApiKeyValue, HttpAuthScheme, HttpBuilder, SecurityScheme};
+        let components = openapi.components.get_or_insert_with(Default::default);
+        components.add_security_scheme("bearer", SecurityScheme::Http(
+            HttpBuilder::new().scheme(HttpAuthScheme::Bearer).bearer_format("JWT").build(),
+        ));
+        components.add_security_scheme("api_key", SecurityScheme::ApiKey(ApiKey::Header(
+            ApiKeyValue::with_description("Authorization", "`ApiKey spk_<workspace>.<id>.<secret>`"),
+        )));
+    }
 }
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
+    // `spu-core openapi` prints the OpenAPI document and exits
+    if std::env::args().nth(1).as_deref() == Some("openapi") {
+        let document = ApiDoc::openapi().to_pretty_json().map_err(std::io::Error::other)?;
+        println!("{}", document);
+        return Ok(());
+    }
+    
     // Load environment
     dotenv::dotenv().ok();
     
@@ -79,18 +358,157 @@ async fn main() -> std::io::Result<()> {
         Arc::new(SemanticCompressorCoprocessor::new()),
     ).await;
     
+    // MongoDB - one client for every store, or state in memory when it can't be reached
+    let mongo = match spu_core::store::connect_mongo().await {
+        Ok(client) => Some(client),
+        Err(e) => {
+            error!("{}; keeping state in memory", e);
+            None
+        }
+    };
+    
+    // Workspaces served - a broken registry must not fall back to serving any workspace
+    let workspaces = Arc::new(Workspaces::from_env().map_err(|e| {
+        error!("{}", e);
+        std::io::Error::other(e)
+    })?);
+    info!("Serving workspaces {}", workspaces.ids().join(", "));
+    
+    // Databases of the workspaces, for the stores kept next to their documents
+    let databases = mongo.as_ref().map(|client| WorkspaceDatabases::new(client.clone(), workspaces.clone()));
+    
+    // Login codes
+    let code_store: Arc<dyn CodeStore> = match &databases {
+        Some(databases) => {
+            let store = MongoCodeStore::new(databases.clone());
+            // Serve anyway; expired codes are refused, only not deleted
+            if let Err(e) = store.create_indexes().await {
+                error!("{}", e);
+            }
+            Arc::new(store)
+        }
+        None => Arc::new(MemoryCodeStore::new()),
+    };
+    // Magic links
+    let link_store: Arc<dyn LinkStore> = match &databases {
+        Some(databases) => {
+            let store = MongoLinkStore::new(databases.clone());
+            if let Err(e) = store.create_indexes().await {
+                error!("{}", e);
+            }
+            Arc::new(store)
+        }
+        None => Arc::new(MemoryLinkStore::new()),
+    };
+    // Second factors and sign-ins waiting for one
+    let two_factor_store: Arc<dyn TwoFactorStore> = match &databases {
+        Some(databases) => {
+            let store = MongoTwoFactorStore::new(databases.clone());
+            if let Err(e) = store.create_indexes().await {
+                error!("{}", e);
+            }
+            Arc::new(store)
+        }
+        None => Arc::new(MemoryTwoFactorStore::new()),
+    };
+    // Flows under way with sign-in providers and linked accounts
+    let oidc_store: Arc<dyn OidcStore> = match &databases {
+        Some(databases) => {
+            let store = MongoOidcStore::new(databases.clone());
+            if let Err(e) = store.create_indexes().await {
+                error!("{}", e);
+            }
+            Arc::new(store)
+        }
+        None => Arc::new(MemoryOidcStore::new()),
+    };
+    // Secret and token keys - a missing secret or broken key set must not fall
+    // back to the public development secret
+    let secret = tokens::secret_from_env().map_err(|e| {
+        error!("{}", e);
+        std::io::Error::other(e)
+    })?;
+    let tokens = Arc::new(Tokens::from_env().map_err(|e| {
+        error!("{}", e);
+        std::io::Error::other(e)
+    })?);
+    // Sessions and the revocation list
+    let session_store: Arc<dyn SessionStore> = match &databases {
+        Some(databases) => {
+            let store = MongoSessionStore::new(databases.clone());
+            if let Err(e) = store.create_indexes().await {
+                error!("{}", e);
+            }
+            Arc::new(store)
+        }
+        None => Arc::new(MemorySessionStore::new()),
+    };
+    let sessions = Arc::new(Sessions::new(session_store, tokens.clone()).with_policy(SessionPolicy::from_env()));
+    // API keys of machine clients
+    let api_key_store: Arc<dyn ApiKeyStore> = match &databases {
+        Some(databases) => Arc::new(MongoApiKeyStore::new(databases.clone())),
+        None => Arc::new(MemoryApiKeyStore::new()),
+    };
+    
+    runtime.register_class(
+        "http".to_string(),
+        Arc::new(HttpCoprocessor::from_env().with_workspaces(workspaces.clone())),
+    ).await;
+    
+    // Documents - MongoDB unless DOCUMENT_STORE says otherwise, each workspace in its database
+    let document_store: Arc<dyn DocumentStore> = match spu_core::store::from_env(mongo.as_ref()) {
+        Ok(store) => Arc::new(WorkspaceStore::new(store, workspaces.clone())),
+        Err(e) => {
+            // Don't fail if MongoDB can't be reached, calls report it instead
+            error!("Document store unavailable: {}", e);
+            Arc::new(WorkspaceStore::new(MongoStore::new(), workspaces.clone()))
+        }
+    };
+    
+    // Email, sent once per idempotency key
     runtime.register_class(
         "email".to_string(),
-        Arc::new(RealEmailCoprocessor::new()),
+        Arc::new(RealEmailCoprocessor::new()
+            .with_events(runtime.events().clone())
+            .with_workspaces(workspaces.clone())
+            .with_idempotency(document_store.clone())),
     ).await;
     
+    // Audit log of auth events and writes, in each workspace's database
+    let audit = Arc::new(AuditLog::from_env(document_store.clone()));
+    match audit.retention() {
+        Some(retention) => info!("Keeping audit entries for {} days", retention.num_days()),
+        None => info!("Keeping audit entries forever"),
+    }
+    audit.clone().spawn_pruner(workspaces.ids(), std::time::Duration::from_secs(3600));
+    
+    let api_keys = Arc::new(ApiKeys::new(api_key_store).with_audit(audit.clone()));
+    // Recovery codes are hashed with the secret login codes are
+    let two_factor = Arc::new(TwoFactor::new(two_factor_store, &secret)
+        .with_policies(document_store.clone())
+        .with_audit(audit.clone()));
+    let oidc = Arc::new(Oidc::new(oidc_store, workspaces.clone()).with_audit(audit.clone()));
+    let auth = AuthCoprocessor::from_secret(&secret)
+        .with_codes(code_store, CodePolicy::from_env())
+        .with_links(link_store, magic_links::ttl_from_env())
+        .with_sessions(sessions.clone())
+        .with_two_factor(two_factor.clone())
+        .with_oidc(oidc.clone())
+        .with_users(document_store.clone())
+        .with_events(runtime.events().clone())
+        .with_audit(audit.clone());
     runtime.register_class(
         "auth".to_string(),
-        Arc::new(AuthCoprocessor::new()),
+        Arc::new(auth),
     ).await;
     
-    let mut db = DatabaseCoprocessor::new();
-    let _ = db.connect().await; // Try to connect but don't fail if can't
+    // Full-text search, indexes built on first use and kept in sync with writes
+    let search = Arc::new(SearchEngine::from_env(document_store.clone()).with_audit(audit.clone()));
+    let db = DatabaseCoprocessor::with_store(document_store.clone())
+        .with_events(runtime.events().clone())
+        .with_search(search.clone())
+        .with_workspaces(workspaces.clone())
+        .with_audit(audit.clone());
     runtime.register_class(
         "database".to_string(),
         Arc::new(db),
@@ -98,6 +516,110 @@ async fn main() -> std::io::Result<()> {
     
     info!("SPU Core initialized with coprocessors");
     
+    // `spu-core migrate ...` migrates workspace databases and exits
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.first().map(String::as_str) == Some("migrate") {
+        let outcome = match MigrateCommand::parse(&args[1..], &workspaces) {
+            Ok(command) => match migrations::load(std::path::Path::new(&command.dir)) {
+                Ok(loaded) => command.run(&Migrator::new(runtime.clone(), document_store.clone(), loaded).with_audit(audit.clone())).await,
+                Err(e) => Err(e),
+            },
+            Err(e) => Err(format!("{}\n{}", e, MIGRATE_USAGE)),
+        };
+        match outcome {
+            Ok(results) => results.iter().for_each(|result| println!("{}", result)),
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        }
+        return Ok(());
+    }
+    
+    // Pending migrations, unless MIGRATE_ON_STARTUP=false
+    let migrations_dir = migrations::migrations_dir();
+    let migrate_on_startup = std::env::var("MIGRATE_ON_STARTUP").map(|v| v != "false").unwrap_or(true);
+    if migrate_on_startup && std::path::Path::new(&migrations_dir).is_dir() {
+        match migrations::load(std::path::Path::new(&migrations_dir)) {
+            Ok(loaded) => {
+                let migrator = Migrator::new(runtime.clone(), document_store.clone(), loaded).with_audit(audit.clone());
+                for workspace in workspaces.ids() {
+                    match migrator.migrate(&workspace, None, false).await {
+                        Ok(report) if !report.migrations.is_empty() => {
+                            info!("Migrated {} from version {} to {}", workspace, report.from, report.to);
+                        }
+                        Ok(_) => {}
+                        // Serve anyway; the failing migration is retried on the next start
+                        Err(e) => error!("Failed to migrate {}: {}", workspace, e),
+                    }
+                }
+            }
+            Err(e) => error!("Failed to load migrations from {}: {}", migrations_dir, e),
+        }
+    }
+    
+    // Durable workflows
+    let workflow_store: Arc<dyn WorkflowStore> = match &mongo {
+        Some(client) => Arc::new(MongoWorkflowStore::new(client.clone())),
+        None => Arc::new(MemoryWorkflowStore::new()),
+    };
+    let workflows = Arc::new(WorkflowEngine::new(runtime.clone(), workflow_store));
+    
+    let recovering = workflows.clone();
+    tokio::spawn(async move {
+        if let Err(e) = recovering.recover().await {
+            error!("Failed to recover workflows: {}", e);
+        }
+    });
+    
+    let poll_secs: u64 = std::env::var("WORKFLOW_POLL_SECS")
+        .unwrap_or_else(|_| "5".to_string())
+        .parse()
+        .unwrap_or(5);
+    workflows.clone().spawn_worker(std::time::Duration::from_secs(poll_secs));
+    
+    // Scheduled scripts
+    let schedule_store: Arc<dyn ScheduleStore> = match &mongo {
+        Some(client) => Arc::new(MongoScheduleStore::new(client.clone())),
+        None => Arc::new(MemoryScheduleStore::new()),
+    };
+    let scheduler = Arc::new(Scheduler::new(runtime.clone(), schedule_store).with_audit(audit.clone()));
+    
+    let scheduler_secs: u64 = std::env::var("SCHEDULER_POLL_SECS")
+        .unwrap_or_else(|_| "5".to_string())
+        .parse()
+        .unwrap_or(5);
+    scheduler.clone().spawn_worker(std::time::Duration::from_secs(scheduler_secs));
+    
+    // Event triggers
+    let trigger_store: Arc<dyn TriggerStore> = match &databases {
+        Some(databases) => Arc::new(MongoTriggerStore::new(databases.clone())),
+        None => Arc::new(MemoryTriggerStore::new()),
+    };
+    let triggers = Arc::new(TriggerDispatcher::new(runtime.clone(), trigger_store).with_audit(audit.clone()));
+    triggers.clone().spawn();
+    
+    // Inbound webhooks
+    let webhook_store: Arc<dyn WebhookStore> = match &databases {
+        Some(databases) => Arc::new(MongoWebhookStore::new(databases.clone())),
+        None => Arc::new(MemoryWebhookStore::new()),
+    };
+    let webhooks = Arc::new(WebhookRouter::new(runtime.clone(), webhook_store).with_audit(audit.clone()));
+    
+    // Saved aggregation pipelines
+    let pipeline_store: Arc<dyn PipelineStore> = match &databases {
+        Some(databases) => Arc::new(MongoPipelineStore::new(databases.clone())),
+        None => Arc::new(MemoryPipelineStore::new()),
+    };
+    let pipelines = Arc::new(PipelineLibrary::new(runtime.clone(), pipeline_store).with_audit(audit.clone()));
+    
+    // Live changes - a broken policy must not leave subscriptions open
+    let subscription_policy = subscriptions::policy_from_env(runtime.clone()).map_err(|e| {
+        error!("{}", e);
+        std::io::Error::other(e)
+    })?;
+    let subscriptions = Arc::new(Subscriptions::new(document_store.clone(), sessions.clone(), subscription_policy));
+    
     // Start HTTP server
     let host = std::env::var("HOST").unwrap_or_else(|_| "0.0.0.0".to_string());
     let port: u16 = std::env::var("PORT")
@@ -107,588 +629,2983 @@ async fn main() -> std::io::Result<()> {
     
     info!("Starting HTTP server on {}:{}", host, port);
     
-    HttpServer::new(move || {
-        let cors = Cors::default()
-            .allow_any_origin()
-            .allow_any_method()
-            .allow_any_header()
-            .max_age(3600);
-        
-        App::new()
-            .app_data(web::Data::new(runtime.clone()))
-            .wrap(cors)
-            .wrap(middleware::Logger::default())
-            // Health check
-            .route("/health", web::get().to(health_check))
-            // Authentication endpoints
-            .route("/auth/register", web::post().to(auth_register))
-            .route("/auth/request-code", web::post().to(auth_request_code))
-            .route("/auth/login", web::post().to(auth_request_code))  // Alias for compatibility
-            .route("/auth/verify-code", web::post().to(auth_verify_code))
-            // SPU execution
-            .route("/execute", web::post().to(execute_assembly))
-            // User management endpoints
-            .route("/users", web::get().to(get_users))
-            .route("/users/{id}", web::put().to(update_user))
-            // Generic database operations via SPU
-            .route("/data/{collection}", web::post().to(store_data))
-            .route("/data/{collection}", web::get().to(retrieve_data))
-            .route("/data/{collection}/{id}", web::get().to(get_data_by_id))
-            .route("/data/{collection}/{id}", web::put().to(update_data))
-            .route("/data/{collection}/{id}", web::delete().to(delete_data))
-    })
-    .bind((host, port))?
-    .run()
-    .await
+    let openapi = web::Data::new(ApiDoc::openapi());
+    HttpServer::new(move || {
+        // Browsers may call from the origins of any workspace
+        let origins = workspaces.clone();
+        let cors = Cors::default()
+            .allowed_origin_fn(move |origin, _| {
+                origin.to_str().map(|origin| origins.allows_origin(origin)).unwrap_or(false)
+            })
+            .allow_any_method()
+            .allow_any_header()
+            .max_age(3600);
+        
+        App::new()
+            .app_data(web::Data::new(runtime.clone()))
+            .app_data(web::Data::new(workflows.clone()))
+            .app_data(web::Data::new(scheduler.clone()))
+            .app_data(web::Data::new(triggers.clone()))
+            .app_data(web::Data::new(webhooks.clone()))
+            .app_data(web::Data::new(pipelines.clone()))
+            .app_data(web::Data::new(subscriptions.clone()))
+            .app_data(web::Data::new(tokens.clone()))
+            .app_data(web::Data::new(sessions.clone()))
+            .app_data(web::Data::new(workspaces.clone()))
+            .app_data(web::Data::new(api_keys.clone()))
+            .app_data(web::Data::new(two_factor.clone()))
+            .app_data(web::Data::new(oidc.clone()))
+            .app_data(web::Data::new(document_store.clone()))
+            .app_data(web::Data::new(audit.clone()))
+            .app_data(openapi.clone())
+            // Bodies and query strings that don't parse get the error envelope
+            .app_data(web::JsonConfig::default().error_handler(api::json_error))
+            .app_data(web::QueryConfig::default().error_handler(api::query_error))
+            .wrap(middleware::from_fn(audit_context))
+            .wrap(cors)
+            .wrap(middleware::Logger::default())
+            // Health check
+            .route("/health", web::get().to(health_check))
+            // API description, and its docs page
+            .route("/openapi.json", web::get().to(openapi_document))
+            .service(Scalar::with_url("/docs", openapi.get_ref().clone()))
+            // Authentication endpoints
+            .route("/auth/register", web::post().to(auth_register))
+            .route("/auth/request-code", web::post().to(auth_request_code))
+            .route("/auth/login", web::post().to(auth_request_code))  // Alias for compatibility
+            .route("/auth/verify-code", web::post().to(auth_verify_code))
+            .route("/auth/refresh", web::post().to(auth_refresh))
+            .route("/auth/magic-link", web::post().to(auth_magic_link))
+            .route("/auth/magic-link/verify", web::post().to(auth_verify_magic_link))
+            // Second factor: the challenge of a sign-in, or the caller's own
+            .route("/auth/two-factor/verify", web::post().to(auth_verify_two_factor))
+            .route("/auth/two-factor/enroll", web::post().to(enroll_two_factor))
+            .service(web::scope("/auth/two-factor")
+                .wrap(middleware::from_fn(require_token))
+                .route("/confirm", web::post().to(confirm_two_factor))
+                .route("/disable", web::post().to(disable_two_factor))
+                .route("/recovery-codes", web::post().to(regenerate_recovery_codes)))
+            // Sign-in with OpenID Connect providers, and the caller's linked accounts
+            .route("/auth/oidc/providers", web::get().to(list_oidc_providers))
+            .route("/auth/oidc/{provider}/authorize", web::get().to(oidc_authorize))
+            .route("/auth/oidc/{provider}/callback", web::post().to(oidc_callback))
+            .service(web::scope("/auth/identities")
+                .wrap(middleware::from_fn(require_token))
+                .route("", web::get().to(list_identities))
+                .route("/{provider}/link", web::post().to(link_identity))
+                .route("/{provider}/{subject}", web::delete().to(unlink_identity)))
+            // Sessions of the caller (bearer token required)
+            .service(web::resource("/auth/logout")
+                .wrap(middleware::from_fn(require_token))
+                .route(web::post().to(auth_logout)))
+            .service(web::resource("/auth/logout-all")
+                .wrap(middleware::from_fn(require_token))
+                .route(web::post().to(auth_logout_all)))
+            .service(web::scope("/auth/sessions")
+                .wrap(middleware::from_fn(require_token))
+                .route("", web::get().to(list_sessions))
+                .route("/{id}", web::delete().to(revoke_session)))
+            .route("/.well-known/jwks.json", web::get().to(jwks))
+            // Scripts run for the caller (admins only)
+            .service(web::resource("/execute")
+                .wrap(middleware::from_fn(require_token))
+                .route(web::post().to(execute_assembly)))
+            // Durable workflows of the caller's workspace (admins only)
+            .service(web::scope("/workflows")
+                .wrap(middleware::from_fn(require_token))
+                .route("", web::post().to(start_workflow))
+                .route("/events/{event}", web::post().to(deliver_workflow_event))
+                .route("/{id}", web::get().to(get_workflow))
+                .route("/{id}/wake", web::post().to(wake_workflow)))
+            // Scheduled scripts
+            .service(web::scope("/schedules")
+                .wrap(middleware::from_fn(require_token))
+                .route("", web::get().to(list_schedules))
+                .route("", web::post().to(save_schedule))
+                .route("/{name}", web::get().to(get_schedule))
+                .route("/{name}", web::delete().to(delete_schedule))
+                .route("/{name}/run", web::post().to(run_schedule))
+                .route("/{name}/runs", web::get().to(list_schedule_runs)))
+            // Event triggers (per workspace)
+            .service(web::scope("/triggers")
+                .wrap(middleware::from_fn(require_token))
+                .route("", web::get().to(list_triggers))
+                .route("", web::post().to(save_trigger))
+                .route("/{name}", web::delete().to(delete_trigger)))
+            // Inbound webhooks
+            .service(web::scope("/webhooks")
+                .wrap(middleware::from_fn(require_token))
+                .route("", web::get().to(list_webhooks))
+                .route("", web::post().to(save_webhook))
+                .route("/{name}", web::delete().to(delete_webhook)))
+            .route("/hooks/{workspace}/{name}", web::post().to(call_webhook))
+            // Saved aggregation pipelines (per workspace)
+            .service(web::scope("/pipelines")
+                .wrap(middleware::from_fn(require_token))
+                .route("", web::get().to(list_pipelines))
+                .route("", web::post().to(save_pipeline))
+                .route("/{name}", web::get().to(get_pipeline))
+                .route("/{name}", web::delete().to(delete_pipeline))
+                .route("/{name}/run", web::post().to(run_pipeline)))
+            // Collection schemas (per workspace)
+            .service(web::scope("/schemas")
+                .wrap(middleware::from_fn(require_token))
+                .route("", web::get().to(list_schemas))
+                .route("/{collection}", web::get().to(get_schema))
+                .route("/{collection}", web::put().to(save_schema))
+                .route("/{collection}", web::delete().to(delete_schema))
+                .route("/{collection}/validate", web::post().to(validate_collection)))
+            // API keys of the caller's workspace (admins only)
+            .service(web::scope("/api-keys")
+                .wrap(middleware::from_fn(require_token))
+                .route("", web::get().to(list_api_keys))
+                .route("", web::post().to(create_api_key))
+                .route("/{id}/rotate", web::post().to(rotate_api_key))
+                .route("/{id}", web::delete().to(revoke_api_key)))
+            // Audit log of the caller's workspace (admins only)
+            .service(web::resource("/audit")
+                .wrap(middleware::from_fn(require_token))
+                .route(web::get().to(get_audit)))
+            // Access policy of the caller's workspace (admins only)
+            .service(web::resource("/policy")
+                .wrap(middleware::from_fn(require_token))
+                .route(web::get().to(get_policy))
+                .route(web::put().to(save_policy)))
+            // Full-text search (per workspace)
+            .service(web::scope("/search")
+                .wrap(middleware::from_fn(require_token))
+                .route("/{collection}", web::get().to(search_collection))
+                .route("/{collection}", web::put().to(configure_search))
+                .route("/{collection}", web::delete().to(drop_search))
+                .route("/{collection}/config", web::get().to(get_search_config))
+                .route("/{collection}/reindex", web::post().to(reindex_search)))
+            // User management endpoints (bearer token required)
+            .service(web::scope("/users")
+                .wrap(middleware::from_fn(require_token))
+                .route("", web::get().to(get_users))
+                .route("/{id}", web::put().to(update_user)))
+            // Generic database operations via SPU (bearer token required)
+            .service(web::scope("/data")
+                .wrap(middleware::from_fn(require_token))
+                .route("/{collection}", web::post().to(store_data))
+                .route("/{collection}", web::get().to(retrieve_data))
+                .route("/{collection}/{id}", web::get().to(get_data_by_id))
+                .route("/{collection}/{id}", web::put().to(update_data))
+                .route("/{collection}/{id}", web::delete().to(delete_data)))
+            // Live changes as Server-Sent Events
+            .route("/subscribe/{collection}", web::get().to(subscribe))
+    })
+    .bind((host, port))?
+    .run()
+    .await
+}
+
+async fn openapi_document(openapi: web::Data<utoipa::openapi::OpenApi>) -> HttpResponse {
+    HttpResponse::Ok().json(openapi.get_ref())
+}
+
+#[utoipa::path(get, path = "/health", tag = "health",
+    responses((status = 200, description = "The server is up", body = api::Health)))]
+async fn health_check() -> HttpResponse {
+    HttpResponse::Ok().json(api::Health {
+        status: "healthy".to_string(),
+        service: "spu-core".to_string(),
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        timestamp: chrono::Utc::now(),
+    })
+}
+
+/// Register a user and email them a login code
+#[utoipa::path(post, path = "/auth/register", tag = "auth",
+    request_body = RegisterRequest,
+    responses(
+        (status = 200, description = "Code sent", body = api::CodeSent),
+        (status = 400, description = "Invalid body or workspace", body = ApiError),
+        (status = 403, description = "Not a user of the workspace", body = ApiError),
+        (status = 429, description = "Too many code requests", body = ApiError),
+        (status = 500, body = ApiError),
+    ))]
+async fn auth_register(
+    runtime: web::Data<Arc<SPURuntime>>,
+    http: actix_web::HttpRequest,
+    req: ValidJson<RegisterRequest>,
+) -> HttpResponse {
+    info!("Register request for: {}", req.email);
+    let workspace = match sign_in_workspace(&http, req.workspace.as_deref(), &req.email) {
+        Ok(workspace) => workspace.id,
+        Err(response) => return response,
+    };
+    
+    // Create assembly script for registration
+    let script = r#"
+        # Registration Assembly Script
+        # 1. Instantiate coprocessors
+        INSTANTIATE auth auth1
+        INSTANTIATE email email1
+        INSTANTIATE database db1
+        
+        # 2. Register user (auth will generate code and build the user)
+        CALL auth1 register $register_input auth_result
+        
+        # 3. Get the generated code and the user from auth_result
+        GET auth_result.code code
+        GET auth_result.user_data user_data
+        
+        # 4. Send email with code  
+        SET email_data {"to": "$email", "subject": "QWANYX - Verification Code", "body": "Your verification code is: $code\n\nThis code expires in 10 minutes."}
+        CALL email1 send $email_data email_result
+        
+        # 5. Store user in database
+        SET db_input {"collection": "users", "data": $user_data}
+        CALL db1 store $db_input user_id
+        
+        # 6. Return success
+        SET result {"success": true, "message": "Registration successful. Check your email for verification code.", "requires_code": true}
+    "#;
+    
+    // What the user typed goes in as data, never into the script text
+    let mut register_input = std::collections::HashMap::new();
+    register_input.insert("email".to_string(), Data::String(req.email.clone()));
+    register_input.insert("firstName".to_string(), Data::String(req.first_name.clone()));
+    register_input.insert("lastName".to_string(), Data::String(req.last_name.clone()));
+    register_input.insert("phone".to_string(), Data::String(req.phone.clone()));
+    register_input.insert("accountType".to_string(), Data::String(req.account_type.clone()));
+    register_input.insert("workspace".to_string(), Data::String(workspace.clone()));
+    register_input.insert("ip".to_string(), Data::String(client_ip(&http)));
+    let mut inputs = std::collections::HashMap::new();
+    inputs.insert("register_input".to_string(), Data::Object(register_input));
+    inputs.insert("email".to_string(), Data::String(req.email.clone()));
+    
+    // Execute the assembly script
+    info!("Starting assembly execution for registration");
+    let registered = runtime.execute_in(script, inputs, &workspace).await;
+    code_sent(registered, "Registration failed")
+}
+
+/// What a script emailing a login code returned, as sent back
+fn code_sent(result: Result<Data, String>, failed: &str) -> HttpResponse {
+    match result {
+        Ok(Data::Object(obj)) => {
+            let message = match obj.get("message") {
+                Some(Data::String(s)) => s.clone(),
+                _ => "Code sent to your email.".to_string()
+            };
+            HttpResponse::Ok().json(api::CodeSent {
+                success: true,
+                message,
+                requires_code: true,
+            })
+        }
+        Ok(_) => {
+            error!("Unexpected result type from assembly execution");
+            HttpResponse::InternalServerError().json(ApiError::new("Internal error"))
+        }
+        Err(e) if e.contains(RATE_LIMITED) => rate_limited(),
+        Err(e) => {
+            error!("Assembly execution failed: {}", e);
+            HttpResponse::InternalServerError().json(ApiError::new(format!("{}: {}", failed, e)))
+        }
+    }
+}
+
+/// What the auth coprocessor says when codes are asked for too often
+const RATE_LIMITED: &str = "Too many code requests";
+
+fn rate_limited() -> HttpResponse {
+    HttpResponse::TooManyRequests().json(ApiError::new("Too many code requests, try again later"))
+}
+
+/// The client's user agent, shown in its session
+fn user_agent(req: &actix_web::HttpRequest) -> Option<String> {
+    req.headers()
+        .get("User-Agent")
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.chars().take(200).collect())
+}
+
+/// The client's address; `X-Forwarded-For` is only believed when `TRUST_PROXY`
+/// is `true`, as anyone can send it
+fn client_ip(req: &actix_web::HttpRequest) -> String {
+    let trust_proxy = std::env::var("TRUST_PROXY").map(|v| v == "true").unwrap_or(false);
+    let ip = if trust_proxy {
+        req.connection_info().realip_remote_addr().map(|addr| addr.to_string())
+    } else {
+        req.peer_addr().map(|addr| addr.ip().to_string())
+    };
+    ip.unwrap_or_default()
+}
+
+/// `?workspace=`, of the routes working in the workspace a request names
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct WorkspaceQuery {
+    /// Unless served on the workspace's hostname; `X-Workspace` works too.
+    /// Signed-in requests may only name the workspace of their token
+    workspace: Option<String>,
+}
+
+/// The workspace a request names, with `?workspace=` or `X-Workspace`
+fn named_workspace(req: &actix_web::HttpRequest) -> Option<String> {
+    web::Query::<WorkspaceQuery>::from_query(req.query_string())
+        .ok()
+        .and_then(|query| query.into_inner().workspace)
+        .or_else(|| req.headers()
+            .get("X-Workspace")
+            .and_then(|v| v.to_str().ok())
+            .map(str::to_string))
+}
+
+/// A workspace a signed-in request names - with `?workspace=`, `X-Workspace`
+/// or the hostname it was sent to - that isn't the one of its claims
+fn other_workspace(req: &actix_web::HttpRequest, claims: &Claims) -> Option<String> {
+    let query = web::Query::<WorkspaceQuery>::from_query(req.query_string())
+        .ok()
+        .and_then(|query| query.into_inner().workspace);
+    let header = req.headers()
+        .get("X-Workspace")
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_string);
+    let served = req.app_data::<web::Data<Arc<Workspaces>>>()
+        .zip(req.headers().get("Host").and_then(|v| v.to_str().ok()))
+        .and_then(|(workspaces, host)| workspaces.by_host(host).map(|workspace| workspace.id.clone()));
+    [query, header, served].into_iter()
+        .flatten()
+        .find(|named| !named.is_empty() && *named != claims.workspace)
+}
+
+/// The workspace of a request made without a token: the one served on the
+/// hostname it was sent to, else the one `named`, else the default
+#[allow(clippy::result_large_err)]
+fn resolve_workspace(req: &actix_web::HttpRequest, named: Option<&str>) -> Result<Workspace, HttpResponse> {
+    let Some(workspaces) = req.app_data::<web::Data<Arc<Workspaces>>>() else {
+        error!("No workspace registry configured");
+        return Err(HttpResponse::InternalServerError().json(ApiError::new("Internal error")));
+    };
+    let host = req.headers().get("Host").and_then(|v| v.to_str().ok());
+    workspaces.resolve(host, named).cloned().map_err(|e| {
+        info!("Refused {} {}: {}", req.method(), req.path(), e);
+        HttpResponse::BadRequest().json(ApiError::new(e))
+    })
+}
+
+/// `resolve_workspace` for the workspace the request names
+#[allow(clippy::result_large_err)]
+fn request_workspace(req: &actix_web::HttpRequest) -> Result<Workspace, HttpResponse> {
+    resolve_workspace(req, named_workspace(req).as_deref())
+}
+
+/// The workspace `email` signs in to, refused when not one of its users
+#[allow(clippy::result_large_err)]
+fn sign_in_workspace(req: &actix_web::HttpRequest, named: Option<&str>, email: &str) -> Result<Workspace, HttpResponse> {
+    let workspace = resolve_workspace(req, named)?;
+    if !workspace.admits(email) {
+        info!("{} is not a user of workspace {}", email, workspace.id);
+        return Err(HttpResponse::Forbidden().json(ApiError::new(
+            format!("Not a user of workspace {}", workspace.name()),
+        )));
+    }
+    Ok(workspace)
+}
+
+/// The workspace of a signed-in request, the token's; naming another is refused
+#[allow(clippy::result_large_err)]
+fn token_workspace(claims: &Claims, named: Option<&str>) -> Result<String, HttpResponse> {
+    match named {
+        Some(named) if !named.is_empty() && named != claims.workspace => {
+            info!("{} of {} named workspace {}", claims.sub, claims.workspace, named);
+            Err(HttpResponse::Forbidden().json(ApiError::new(
+                format!("Signed in to {}, not {}", claims.workspace, named),
+            )))
+        }
+        _ => Ok(claims.workspace.clone()),
+    }
+}
+
+/// Email a login code to a user of the workspace; `/auth/login` is the same
+#[utoipa::path(post, path = "/auth/request-code", tag = "auth",
+    request_body = LoginRequest,
+    responses(
+        (status = 200, description = "Code sent", body = api::CodeSent),
+        (status = 400, description = "Invalid body or workspace", body = ApiError),
+        (status = 403, description = "Not a user of the workspace", body = ApiError),
+        (status = 429, description = "Too many code requests", body = ApiError),
+        (status = 500, body = ApiError),
+    ))]
+async fn auth_request_code(
+    runtime: web::Data<Arc<SPURuntime>>,
+    http: actix_web::HttpRequest,
+    req: ValidJson<LoginRequest>,
+) -> HttpResponse {
+    info!("Login code request for: {}", req.email);
+    let workspace = match sign_in_workspace(&http, req.workspace.as_deref(), &req.email) {
+        Ok(workspace) => workspace.id,
+        Err(response) => return response,
+    };
+    
+    // Assembly script to request login code
+    let script = r#"
+        # Login Code Request Assembly Script
+        INSTANTIATE auth auth1
+        INSTANTIATE email email1
+        
+        # Generate new code
+        CALL auth1 generate_code $email_input code_result
+        GET code_result.code code
+        
+        # Send email
+        SET email_data {"to": "$email", "subject": "QWANYX - Login Code", "body": "Your login code is: $code\n\nThis code expires in 10 minutes."}
+        CALL email1 send $email_data email_result
+        
+        # Return success
+        SET result {"success": true, "message": "Code sent to your email.", "requires_code": true}
+    "#;
+    
+    // The address goes in as data, never into the script text
+    let mut email_input = std::collections::HashMap::new();
+    email_input.insert("email".to_string(), Data::String(req.email.clone()));
+    email_input.insert("workspace".to_string(), Data::String(workspace));
+    email_input.insert("ip".to_string(), Data::String(client_ip(&http)));
+    let mut inputs = std::collections::HashMap::new();
+    inputs.insert("email_input".to_string(), Data::Object(email_input));
+    inputs.insert("email".to_string(), Data::String(req.email.clone()));
+    
+    // Execute the assembly script
+    code_sent(runtime.execute_with_inputs(script, inputs).await, "Failed to send code")
+}
+
+/// Sign in with an emailed code
+#[utoipa::path(post, path = "/auth/verify-code", tag = "auth",
+    request_body = VerifyCodeRequest,
+    responses(
+        (status = 200, description = "Signed in, or waiting for the second factor", body = api::SignIn),
+        (status = 400, description = "Invalid body, workspace or code", body = ApiError),
+        (status = 403, description = "Not a user of the workspace", body = ApiError),
+        (status = 500, body = ApiError),
+    ))]
+async fn auth_verify_code(
+    runtime: web::Data<Arc<SPURuntime>>,
+    http: actix_web::HttpRequest,
+    req: ValidJson<VerifyCodeRequest>,
+) -> HttpResponse {
+    info!("Verify code for: {}", req.email);
+    let workspace = match sign_in_workspace(&http, req.workspace.as_deref(), &req.email) {
+        Ok(workspace) => workspace.id,
+        Err(response) => return response,
+    };
+    
+    // Assembly script to verify code; a valid code starts a session for this device
+    let script = r#"
+        # Code Verification Assembly Script
+        INSTANTIATE auth auth1
+        
+        # Verify the code
+        CALL auth1 verify_code $verify_data verify_result
+        
+        # Get validation status
+        GET verify_result.valid is_valid
+        GET verify_result.token token
+        GET verify_result.error error_msg
+        
+        # Build response based on validation  
+        SET result $verify_result
+    "#;
+    
+    // The user agent goes in as data, never into the script text
+    let mut verify_data = std::collections::HashMap::new();
+    verify_data.insert("email".to_string(), Data::String(req.email.clone()));
+    verify_data.insert("code".to_string(), Data::String(req.code.clone()));
+    verify_data.insert("workspace".to_string(), Data::String(workspace.clone()));
+    verify_data.insert("ip".to_string(), Data::String(client_ip(&http)));
+    if let Some(device) = user_agent(&http) {
+        verify_data.insert("device".to_string(), Data::String(device));
+    }
+    let mut inputs = std::collections::HashMap::new();
+    inputs.insert("verify_data".to_string(), Data::Object(verify_data));
+    
+    sign_in_response(runtime.execute_with_inputs(script, inputs).await, Some(&req.email), &workspace)
+}
+
+/// What a sign-in script returned, as sent back: the tokens of the session,
+/// or the challenge of the second factor it waits for
+fn sign_in_response(result: Result<Data, String>, email: Option<&str>, workspace: &str) -> HttpResponse {
+    let obj = match result {
+        Ok(Data::Object(obj)) => obj,
+        Ok(_) => return HttpResponse::InternalServerError().json(ApiError::new("Internal error")),
+        Err(e) => {
+            error!("Assembly execution failed: {}", e);
+            return HttpResponse::InternalServerError().json(ApiError::new(format!("Verification failed: {}", e)));
+        }
+    };
+    let field = |name: &str| match obj.get(name) {
+        Some(Data::String(s)) => Some(s.clone()),
+        _ => None
+    };
+    let email = email.map(str::to_string).or_else(|| field("email"));
+    
+    if matches!(obj.get("valid"), Some(Data::Bool(true))) {
+        let mut session = session(&obj, "Code verified successfully.");
+        session.user = Some(api::SessionUser {
+            email,
+            workspace: workspace.to_string(),
+        });
+        HttpResponse::Ok().json(api::SignIn::Session(session))
+    } else if let Some(challenge) = field("challenge") {
+        // Right so far: the session waits for the second factor
+        HttpResponse::Ok().json(api::SignIn::Challenge(api::TwoFactorChallenge {
+            success: true,
+            message: "Enter the code of your authenticator app.".to_string(),
+            requires_two_factor: true,
+            challenge,
+            challenge_expires_at: field("challenge_expires_at"),
+            enrolled: matches!(obj.get("enrolled"), Some(Data::Bool(true))),
+            requires_code: false,
+        }))
+    } else {
+        let error = field("error").unwrap_or_else(|| "Invalid code".to_string());
+        HttpResponse::BadRequest().json(ApiError::new(error))
+    }
+}
+
+/// The tokens of a session the auth coprocessor started or refreshed
+fn session(obj: &std::collections::HashMap<String, Data>, message: &str) -> api::Session {
+    let field = |name: &str| match obj.get(name) {
+        Some(Data::String(s)) => Some(s.clone()),
+        _ => None
+    };
+    let token = field("token").unwrap_or_default();
+    api::Session {
+        success: true,
+        message: message.to_string(),
+        access_token: token.clone(),
+        token,
+        expires_at: field("expires_at"),
+        refresh_token: field("refresh_token"),
+        refresh_expires_at: field("refresh_expires_at"),
+        session_id: field("session_id"),
+        requires_code: false,
+        user: None,
+        recovery_codes_left: match obj.get("recovery_codes_left") {
+            Some(Data::Number(left)) => Some(*left as u64),
+            _ => None
+        },
+    }
+}
+
+/// The page magic links open: `/auth/magic-link` on the workspace's first
+/// origin, else `MAGIC_LINK_URL`
+fn magic_link_page(workspace: &Workspace) -> Option<String> {
+    workspace.origins.first()
+        .map(|origin| format!("{}/auth/magic-link", origin.trim_end_matches('/')))
+        .or_else(|| std::env::var("MAGIC_LINK_URL").ok().filter(|url| !url.is_empty()))
+}
+
+/// Email a single-use sign-in link, instead of a code
+#[utoipa::path(post, path = "/auth/magic-link", tag = "auth",
+    request_body = LoginRequest,
+    responses(
+        (status = 200, description = "Link sent", body = api::Message),
+        (status = 400, description = "Invalid body or workspace", body = ApiError),
+        (status = 403, description = "Not a user of the workspace", body = ApiError),
+        (status = 429, description = "Too many link requests", body = ApiError),
+        (status = 500, body = ApiError),
+    ))]
+async fn auth_magic_link(
+    runtime: web::Data<Arc<SPURuntime>>,
+    http: actix_web::HttpRequest,
+    req: ValidJson<LoginRequest>,
+) -> HttpResponse {
+    info!("Magic link request for: {}", req.email);
+    let workspace = match sign_in_workspace(&http, req.workspace.as_deref(), &req.email) {
+        Ok(workspace) => workspace,
+        Err(response) => return response,
+    };
+    let Some(page) = magic_link_page(&workspace) else {
+        error!("No page for the magic links of {}: set its origins or MAGIC_LINK_URL", workspace.id);
+        return HttpResponse::InternalServerError().json(ApiError::new("Magic links are not available"));
+    };
+    
+    let mut link_input = std::collections::HashMap::new();
+    link_input.insert("email".to_string(), Data::String(req.email.clone()));
+    link_input.insert("workspace".to_string(), Data::String(workspace.id.clone()));
+    link_input.insert("ip".to_string(), Data::String(client_ip(&http)));
+    link_input.insert("url".to_string(), Data::String(page));
+    let mut inputs = std::collections::HashMap::new();
+    inputs.insert("link_input".to_string(), Data::Object(link_input));
+    let generated = runtime.execute_with_inputs(r#"
+        INSTANTIATE auth auth1
+        CALL auth1 generate_magic_link $link_input link_result
+    "#, inputs).await;
+    
+    let link = match generated {
+        Ok(Data::Object(obj)) => match obj.get("link") {
+            Some(Data::String(link)) => link.clone(),
+            _ => String::new(),
+        },
+        Ok(_) => String::new(),
+        Err(e) if e.contains(RATE_LIMITED) => return rate_limited(),
+        Err(e) => {
+            error!("Assembly execution failed: {}", e);
+            return HttpResponse::InternalServerError().json(ApiError::new(format!("Failed to send link: {}", e)));
+        }
+    };
+    
+    // The link goes in as data, never into the script text
+    let mut email_data = std::collections::HashMap::new();
+    email_data.insert("to".to_string(), Data::String(req.email.clone()));
+    email_data.insert("subject".to_string(), Data::String(format!("{} - Sign-in link", workspace.name())));
+    email_data.insert("body".to_string(), Data::String(format!(
+        "Sign in with this link:\n\n{}\n\nIt works once and expires in {} minutes.",
+        link,
+        magic_links::ttl_from_env().num_minutes(),
+    )));
+    let mut inputs = std::collections::HashMap::new();
+    inputs.insert("email_data".to_string(), Data::Object(email_data));
+    let sent = runtime.execute_with_inputs(r#"
+        INSTANTIATE email email1
+        CALL email1 send $email_data email_result
+    "#, inputs).await;
+    
+    match sent {
+        Ok(_) => HttpResponse::Ok().json(api::Message {
+            success: true,
+            message: "Link sent to your email.".to_string(),
+        }),
+        Err(e) => {
+            error!("Failed to send magic link: {}", e);
+            HttpResponse::InternalServerError().json(ApiError::new(format!("Failed to send link: {}", e)))
+        }
+    }
+}
+
+/// Sign in with the token of a magic link, as with a code
+#[utoipa::path(post, path = "/auth/magic-link/verify", tag = "auth",
+    request_body = MagicLinkRequest,
+    responses(
+        (status = 200, description = "Signed in, or waiting for the second factor", body = api::SignIn),
+        (status = 400, description = "Invalid body, workspace or link", body = ApiError),
+        (status = 500, body = ApiError),
+    ))]
+async fn auth_verify_magic_link(
+    runtime: web::Data<Arc<SPURuntime>>,
+    http: actix_web::HttpRequest,
+    req: ValidJson<MagicLinkRequest>,
+) -> HttpResponse {
+    let workspace = match resolve_workspace(&http, req.workspace.as_deref()) {
+        Ok(workspace) => workspace.id,
+        Err(response) => return response,
+    };
+    
+    let mut verify = std::collections::HashMap::new();
+    verify.insert("token".to_string(), Data::String(req.token.clone()));
+    verify.insert("workspace".to_string(), Data::String(workspace.clone()));
+    verify.insert("ip".to_string(), Data::String(client_ip(&http)));
+    if let Some(device) = user_agent(&http) {
+        verify.insert("device".to_string(), Data::String(device));
+    }
+    let mut inputs = std::collections::HashMap::new();
+    inputs.insert("verify".to_string(), Data::Object(verify));
+    
+    let script = r#"
+        INSTANTIATE auth auth1
+        CALL auth1 verify_magic_link $verify result
+    "#;
+    sign_in_response(runtime.execute_with_inputs(script, inputs).await, None, &workspace)
+}
+
+/// Finish a sign-in waiting for its second factor with a code of the
+/// authenticator app, or a recovery code
+#[utoipa::path(post, path = "/auth/two-factor/verify", tag = "two-factor",
+    request_body = TwoFactorRequest,
+    responses(
+        (status = 200, description = "Signed in", body = api::Session),
+        (status = 400, description = "Invalid body, workspace or code", body = ApiError),
+        (status = 500, body = ApiError),
+    ))]
+async fn auth_verify_two_factor(
+    runtime: web::Data<Arc<SPURuntime>>,
+    http: actix_web::HttpRequest,
+    req: ValidJson<TwoFactorRequest>,
+) -> HttpResponse {
+    let workspace = match resolve_workspace(&http, req.workspace.as_deref()) {
+        Ok(workspace) => workspace.id,
+        Err(response) => return response,
+    };
+    
+    let mut verify = std::collections::HashMap::new();
+    verify.insert("challenge".to_string(), Data::String(req.challenge.clone()));
+    verify.insert("code".to_string(), Data::String(req.code.clone()));
+    verify.insert("workspace".to_string(), Data::String(workspace.clone()));
+    verify.insert("ip".to_string(), Data::String(client_ip(&http)));
+    let mut inputs = std::collections::HashMap::new();
+    inputs.insert("verify".to_string(), Data::Object(verify));
+    
+    let script = r#"
+        INSTANTIATE auth auth1
+        CALL auth1 verify_two_factor $verify result
+    "#;
+    sign_in_response(runtime.execute_with_inputs(script, inputs).await, None, &workspace)
+}
+
+#[derive(Debug, Deserialize, ToSchema, Validate)]
+struct RefreshRequest {
+    #[validate(length(min = 1, max = 512))]
+    refresh_token: String,
+    #[serde(default)]
+    workspace: Option<String>,
+}
+
+/// New access and refresh tokens for a refresh token, which stops working
+#[utoipa::path(post, path = "/auth/refresh", tag = "sessions",
+    request_body = RefreshRequest,
+    responses(
+        (status = 200, description = "The session's new tokens", body = api::Session),
+        (status = 400, description = "Invalid body or workspace", body = ApiError),
+        (status = 401, description = "Unknown, expired or reused refresh token", body = ApiError),
+        (status = 500, body = ApiError),
+    ))]
+async fn auth_refresh(
+    runtime: web::Data<Arc<SPURuntime>>,
+    http: actix_web::HttpRequest,
+    req: ValidJson<RefreshRequest>,
+) -> HttpResponse {
+    let workspace = match resolve_workspace(&http, req.workspace.as_deref()) {
+        Ok(workspace) => workspace.id,
+        Err(response) => return response,
+    };
+    let script = r#"
+        INSTANTIATE auth auth1
+        CALL auth1 refresh $refresh result
+    "#;
+    
+    let mut refresh = std::collections::HashMap::new();
+    refresh.insert("refresh_token".to_string(), Data::String(req.refresh_token.clone()));
+    refresh.insert("workspace".to_string(), Data::String(workspace));
+    refresh.insert("ip".to_string(), Data::String(client_ip(&http)));
+    let mut inputs = std::collections::HashMap::new();
+    inputs.insert("refresh".to_string(), Data::Object(refresh));
+    
+    match runtime.execute_with_inputs(script, inputs).await {
+        Ok(Data::Object(obj)) if matches!(obj.get("valid"), Some(Data::Bool(true))) => {
+            HttpResponse::Ok().json(session(&obj, "Session refreshed."))
+        }
+        Ok(Data::Object(obj)) => {
+            let error = match obj.get("error") {
+                Some(Data::String(s)) => s.clone(),
+                _ => "Invalid refresh token".to_string()
+            };
+            HttpResponse::Unauthorized().json(ApiError::new(error))
+        }
+        Ok(_) => HttpResponse::InternalServerError().json(ApiError::new("Internal error")),
+        Err(e) => {
+            error!("Refresh failed: {}", e);
+            HttpResponse::InternalServerError().json(ApiError::new(format!("Refresh failed: {}", e)))
+        }
+    }
+}
+
+/// Call an `auth` method that acts on the caller's sessions, passing it the
+/// caller's bearer token and `args`
+async fn session_call(
+    runtime: &SPURuntime,
+    http: &actix_web::HttpRequest,
+    method: &str,
+    mut args: std::collections::HashMap<String, Data>,
+) -> Result<serde_json::Value, HttpResponse> {
+    let authorization = http.headers().get("Authorization").and_then(|v| v.to_str().ok());
+    let token = tokens::bearer(authorization).unwrap_or_default();
+    args.insert("token".to_string(), Data::String(token.to_string()));
+    
+    let script = format!(r#"
+        INSTANTIATE auth auth1
+        CALL auth1 {} $args result
+    "#, method);
+    let mut inputs = std::collections::HashMap::new();
+    inputs.insert("args".to_string(), Data::Object(args));
+    
+    match runtime.execute_with_inputs(&script, inputs).await {
+        Ok(result) => Ok(data_to_json(&result)),
+        Err(e) => {
+            error!("Failed to {}: {}", method, e);
+            Err(HttpResponse::InternalServerError().json(ApiError::new(e)))
+        }
+    }
+}
+
+/// End the caller's session
+#[utoipa::path(post, path = "/auth/logout", tag = "sessions",
+    security(("bearer" = []), ("api_key" = [])),
+    responses(
+        (status = 200, description = "Whether the session was still going", body = api::Revoked),
+        (status = 401, body = ApiError),
+        (status = 500, body = ApiError),
+    ))]
+async fn auth_logout(
+    runtime: web::Data<Arc<SPURuntime>>,
+    http: actix_web::HttpRequest,
+    auth: web::ReqData<Claims>,
+) -> HttpResponse {
+    info!("Logout of {} in {}", auth.sub, auth.workspace);
+    match session_call(&runtime, &http, "logout", std::collections::HashMap::new()).await {
+        Ok(response) => HttpResponse::Ok().json(api::Revoked {
+            success: true,
+            revoked: response["revoked"] == json!(true),
+        }),
+        Err(response) => response,
+    }
+}
+
+/// End every session of the caller, on all devices
+#[utoipa::path(post, path = "/auth/logout-all", tag = "sessions",
+    security(("bearer" = []), ("api_key" = [])),
+    responses(
+        (status = 200, description = "How many sessions were ended", body = api::RevokedAll),
+        (status = 401, body = ApiError),
+        (status = 500, body = ApiError),
+    ))]
+async fn auth_logout_all(
+    runtime: web::Data<Arc<SPURuntime>>,
+    http: actix_web::HttpRequest,
+    auth: web::ReqData<Claims>,
+) -> HttpResponse {
+    info!("Logout of {} in {} on all devices", auth.sub, auth.workspace);
+    match session_call(&runtime, &http, "logout_all", std::collections::HashMap::new()).await {
+        Ok(response) => HttpResponse::Ok().json(api::RevokedAll {
+            success: true,
+            revoked: response["revoked"].as_f64().unwrap_or_default() as u64,
+        }),
+        Err(response) => response,
+    }
+}
+
+/// The caller's active sessions, with device and IP
+#[utoipa::path(get, path = "/auth/sessions", tag = "sessions",
+    security(("bearer" = []), ("api_key" = [])),
+    responses(
+        (status = 200, body = api::ActiveSessions),
+        (status = 401, body = ApiError),
+        (status = 500, body = ApiError),
+    ))]
+async fn list_sessions(
+    sessions: web::Data<Arc<Sessions>>,
+    auth: web::ReqData<Claims>,
+) -> HttpResponse {
+    match sessions.list(&auth.workspace, &auth.sub).await {
+        Ok(listed) => HttpResponse::Ok().json(api::ActiveSessions {
+            success: true,
+            sessions: listed.iter().map(|session| api::ActiveSession::new(session, auth.sid.as_deref())).collect(),
+        }),
+        Err(e) => {
+            error!("Failed to list the sessions of {}: {}", auth.sub, e);
+            HttpResponse::InternalServerError().json(ApiError::new(e.to_string()))
+        }
+    }
+}
+
+/// End one of the caller's sessions
+#[utoipa::path(delete, path = "/auth/sessions/{id}", tag = "sessions",
+    security(("bearer" = []), ("api_key" = [])),
+    responses(
+        (status = 200, description = "Ended", body = api::Revoked),
+        (status = 401, body = ApiError),
+        (status = 404, description = "Not one of the caller's sessions, or already ended", body = ApiError),
+        (status = 500, body = ApiError),
+    ))]
+async fn revoke_session(
+    runtime: web::Data<Arc<SPURuntime>>,
+    http: actix_web::HttpRequest,
+    path: web::Path<String>,
+) -> HttpResponse {
+    let mut args = std::collections::HashMap::new();
+    args.insert("session_id".to_string(), Data::String(path.into_inner()));
+    match session_call(&runtime, &http, "revoke_session", args).await {
+        Ok(response) if response["revoked"] == json!(true) => HttpResponse::Ok().json(api::Revoked {
+            success: true,
+            revoked: true,
+        }),
+        // Not one of the caller's sessions, or already ended
+        Ok(_) => HttpResponse::NotFound().json(ApiError::new("Session not found")),
+        Err(response) => response,
+    }
+}
+
+/// Public keys tokens are signed with, for services verifying them on their own
+#[utoipa::path(get, path = "/.well-known/jwks.json", tag = "sessions",
+    responses((status = 200, description = "JSON Web Key Set", body = api::Jwks)))]
+async fn jwks(tokens: web::Data<Arc<Tokens>>) -> HttpResponse {
+    HttpResponse::Ok()
+        .insert_header(("Cache-Control", "public, max-age=300"))
+        .json(api::Jwks { keys: tokens.keys().public_keys() })
+}
+
+fn two_factor_failure(e: TwoFactorError) -> HttpResponse {
+    let response = ApiError::new(e.to_string());
+    match e {
+        TwoFactorError::NotEnrolled => HttpResponse::NotFound().json(response),
+        TwoFactorError::AlreadyEnrolled => HttpResponse::Conflict().json(response),
+        TwoFactorError::Required => HttpResponse::Forbidden().json(response),
+        TwoFactorError::InvalidCode => HttpResponse::BadRequest().json(response),
+        TwoFactorError::TooManyAttempts | TwoFactorError::Locked => HttpResponse::TooManyRequests().json(response),
+        TwoFactorError::ChallengeExpired => HttpResponse::Unauthorized().json(response),
+        TwoFactorError::Storage(e) => {
+            error!("Two-factor storage failed: {}", e);
+            HttpResponse::InternalServerError().json(response)
+        }
+    }
+}
+
+/// Start enrolling the caller in a second factor: the secret for the
+/// authenticator app, as text and QR code, and the recovery codes, shown only
+/// here. Signed-in users send their token; users whose role requires a second
+/// factor they don't have yet send the challenge of their sign-in instead
+#[utoipa::path(post, path = "/auth/two-factor/enroll", tag = "two-factor",
+    request_body(content = Option<EnrollRequest>, description = "The challenge of a sign-in, unless sending a token"),
+    security((), ("bearer" = [])),
+    responses(
+        (status = 200, description = "Secret and recovery codes, to confirm", body = api::Enrollment),
+        (status = 400, description = "Invalid body", body = ApiError),
+        (status = 401, description = "Neither a valid token nor a pending challenge", body = ApiError),
+        (status = 409, description = "Already enrolled", body = ApiError),
+        (status = 500, body = ApiError),
+    ))]
+async fn enroll_two_factor(
+    two_factor: web::Data<Arc<TwoFactor>>,
+    sessions: web::Data<Arc<Sessions>>,
+    http: actix_web::HttpRequest,
+    body: OptionalJson<EnrollRequest>,
+) -> HttpResponse {
+    let body = body.into_inner().unwrap_or_default();
+    let caller = match &body.challenge {
+        Some(challenge) => {
+            let workspace = match resolve_workspace(&http, body.workspace.as_deref()) {
+                Ok(workspace) => workspace.id,
+                Err(response) => return response,
+            };
+            match two_factor.pending(&workspace, challenge).await {
+                Ok(challenge) => (challenge.workspace, challenge.user, challenge.email),
+                Err(e) => return two_factor_failure(e),
+            }
+        }
+        None => {
+            let authorization = http.headers().get("Authorization").and_then(|v| v.to_str().ok());
+            let token = match tokens::bearer(authorization) {
+                Ok(token) => token,
+                Err(_) => return HttpResponse::Unauthorized()
+                    .json(ApiError::new("Missing bearer token or sign-in challenge")),
+            };
+            match sessions.authenticate(token).await {
+                Ok(claims) => (claims.workspace, claims.sub, claims.email),
+                Err(e) => return HttpResponse::Unauthorized().json(ApiError::new(e.to_string())),
+            }
+        }
+    };
+    let (workspace, user, email) = caller;
+    
+    let issuer = http.app_data::<web::Data<Arc<Workspaces>>>()
+        .and_then(|workspaces| workspaces.get(&workspace))
+        .map(|w| w.name().to_string())
+        .unwrap_or_else(|| workspace.clone());
+    let account = email.unwrap_or_else(|| user.clone());
+    match two_factor.enroll(&workspace, &user, &account, &issuer).await {
+        Ok(setup) => HttpResponse::Ok().json(api::Enrollment {
+            success: true,
+            secret: setup.secret,
+            uri: setup.uri,
+            qr_svg: setup.qr_svg,
+            recovery_codes: setup.recovery_codes,
+            message: "Scan the code with your authenticator app, then confirm with a code it shows.".to_string(),
+        }),
+        Err(e) => two_factor_failure(e),
+    }
+}
+
+/// Turn on the caller's second factor with a first code of the app
+#[utoipa::path(post, path = "/auth/two-factor/confirm", tag = "two-factor",
+    request_body = TwoFactorCodeRequest,
+    security(("bearer" = [])),
+    responses(
+        (status = 200, body = api::Message),
+        (status = 400, description = "Invalid body or code", body = ApiError),
+        (status = 401, body = ApiError),
+        (status = 404, description = "Not enrolling", body = ApiError),
+        (status = 429, description = "Too many wrong codes", body = ApiError),
+    ))]
+async fn confirm_two_factor(
+    two_factor: web::Data<Arc<TwoFactor>>,
+    body: ValidJson<TwoFactorCodeRequest>,
+    auth: web::ReqData<Claims>,
+) -> HttpResponse {
+    match two_factor.confirm(&auth.workspace, &auth.sub, &body.code).await {
+        Ok(()) => HttpResponse::Ok().json(api::Message {
+            success: true,
+            message: "Second factor enabled.".to_string(),
+        }),
+        Err(e) => two_factor_failure(e),
+    }
+}
+
+/// Turn off the caller's second factor, unless their role requires one
+#[utoipa::path(post, path = "/auth/two-factor/disable", tag = "two-factor",
+    request_body = TwoFactorCodeRequest,
+    security(("bearer" = [])),
+    responses(
+        (status = 200, body = api::Message),
+        (status = 400, description = "Invalid body or code", body = ApiError),
+        (status = 401, body = ApiError),
+        (status = 403, description = "The caller's role requires a second factor", body = ApiError),
+        (status = 404, description = "Not enrolled", body = ApiError),
+        (status = 429, description = "Too many wrong codes", body = ApiError),
+    ))]
+async fn disable_two_factor(
+    two_factor: web::Data<Arc<TwoFactor>>,
+    body: ValidJson<TwoFactorCodeRequest>,
+    auth: web::ReqData<Claims>,
+) -> HttpResponse {
+    match two_factor.disable(&auth.workspace, &auth.sub, &auth.roles, &body.code).await {
+        Ok(()) => HttpResponse::Ok().json(api::Message {
+            success: true,
+            message: "Second factor disabled.".to_string(),
+        }),
+        Err(e) => two_factor_failure(e),
+    }
+}
+
+/// New recovery codes for the caller, replacing the old ones
+#[utoipa::path(post, path = "/auth/two-factor/recovery-codes", tag = "two-factor",
+    request_body = TwoFactorCodeRequest,
+    security(("bearer" = [])),
+    responses(
+        (status = 200, body = api::RecoveryCodes),
+        (status = 400, description = "Invalid body or code", body = ApiError),
+        (status = 401, body = ApiError),
+        (status = 404, description = "Not enrolled", body = ApiError),
+        (status = 429, description = "Too many wrong codes", body = ApiError),
+    ))]
+async fn regenerate_recovery_codes(
+    two_factor: web::Data<Arc<TwoFactor>>,
+    body: ValidJson<TwoFactorCodeRequest>,
+    auth: web::ReqData<Claims>,
+) -> HttpResponse {
+    match two_factor.regenerate_recovery_codes(&auth.workspace, &auth.sub, &body.code).await {
+        Ok(recovery_codes) => HttpResponse::Ok().json(api::RecoveryCodes {
+            success: true,
+            recovery_codes,
+        }),
+        Err(e) => two_factor_failure(e),
+    }
+}
+
+fn oidc_failure(e: OidcError) -> HttpResponse {
+    let response = ApiError::new(e.to_string());
+    match e {
+        OidcError::UnknownProvider(_) | OidcError::NotFound => HttpResponse::NotFound().json(response),
+        OidcError::InvalidState | OidcError::InvalidToken(_) => HttpResponse::Unauthorized().json(response),
+        OidcError::UnverifiedEmail | OidcError::NotAdmitted(_) => HttpResponse::Forbidden().json(response),
+        OidcError::AlreadyLinked => HttpResponse::Conflict().json(response),
+        OidcError::Provider(_) => HttpResponse::BadGateway().json(response),
+        OidcError::Storage(e) => {
+            error!("Identity storage failed: {}", e);
+            HttpResponse::InternalServerError().json(response)
+        }
+    }
+}
+
+/// The providers users of the workspace may sign in with, for the sign-in page
+#[utoipa::path(get, path = "/auth/oidc/providers", tag = "oidc",
+    params(("workspace" = Option<String>, Query, description = "Unless served on the workspace's hostname")),
+    responses(
+        (status = 200, body = api::Providers),
+        (status = 400, description = "Unknown workspace", body = ApiError),
+    ))]
+async fn list_oidc_providers(
+    oidc: web::Data<Arc<Oidc>>,
+    http: actix_web::HttpRequest,
+) -> HttpResponse {
+    let workspace = match request_workspace(&http) {
+        Ok(workspace) => workspace,
+        Err(response) => return response,
+    };
+    let providers = oidc.providers(&workspace.id).iter()
+        .map(|provider| api::Provider {
+            id: provider.id.clone(),
+            name: provider.name().to_string(),
+        })
+        .collect();
+    HttpResponse::Ok().json(api::Providers {
+        success: true,
+        providers,
+    })
+}
+
+/// Where to send the browser to sign in with a provider
+#[utoipa::path(get, path = "/auth/oidc/{provider}/authorize", tag = "oidc",
+    params(("workspace" = Option<String>, Query, description = "Unless served on the workspace's hostname")),
+    responses(
+        (status = 200, body = api::Authorization),
+        (status = 400, description = "Unknown workspace", body = ApiError),
+        (status = 404, description = "Unknown provider", body = ApiError),
+        (status = 502, description = "The provider could not be reached", body = ApiError),
+    ))]
+async fn oidc_authorize(
+    runtime: web::Data<Arc<SPURuntime>>,
+    http: actix_web::HttpRequest,
+    path: web::Path<String>,
+) -> HttpResponse {
+    let workspace = match request_workspace(&http) {
+        Ok(workspace) => workspace.id,
+        Err(response) => return response,
+    };
+    
+    let mut authorize = std::collections::HashMap::new();
+    authorize.insert("provider".to_string(), Data::String(path.into_inner()));
+    authorize.insert("workspace".to_string(), Data::String(workspace));
+    let mut inputs = std::collections::HashMap::new();
+    inputs.insert("authorize".to_string(), Data::Object(authorize));
+    
+    let script = r#"
+        INSTANTIATE auth auth1
+        CALL auth1 oidc_authorize $authorize result
+    "#;
+    match runtime.execute_with_inputs(script, inputs).await {
+        Ok(result) => HttpResponse::Ok().json(authorization(&data_to_json(&result))),
+        Err(e) if e.contains(UNKNOWN_PROVIDER) => HttpResponse::NotFound().json(ApiError::new(e)),
+        Err(e) => {
+            error!("Failed to start provider sign-in: {}", e);
+            HttpResponse::BadGateway().json(ApiError::new(e))
+        }
+    }
+}
+
+const UNKNOWN_PROVIDER: &str = "Unknown sign-in provider";
+
+/// Where the auth coprocessor said to send the browser
+fn authorization(result: &serde_json::Value) -> api::Authorization {
+    let field = |name: &str| result[name].as_str().unwrap_or_default().to_string();
+    api::Authorization {
+        success: true,
+        url: field("url"),
+        expires_at: field("expires_at"),
+    }
+}
+
+/// Sign in with the code and state a provider sent back to the redirect page,
+/// as with an email code; or link the account, if the flow was started to
+#[utoipa::path(post, path = "/auth/oidc/{provider}/callback", tag = "oidc",
+    request_body = OidcCallbackRequest,
+    responses(
+        (status = 200, description = "Signed in, waiting for the second factor, or linked", body = api::OidcSignIn),
+        (status = 400, description = "Invalid body or workspace, or refused by the provider", body = ApiError),
+        (status = 500, body = ApiError),
+    ))]
+async fn oidc_callback(
+    runtime: web::Data<Arc<SPURuntime>>,
+    http: actix_web::HttpRequest,
+    path: web::Path<String>,
+    req: ValidJson<OidcCallbackRequest>,
+) -> HttpResponse {
+    let workspace = match resolve_workspace(&http, req.workspace.as_deref()) {
+        Ok(workspace) => workspace.id,
+        Err(response) => return response,
+    };
+    
+    let mut callback = std::collections::HashMap::new();
+    callback.insert("provider".to_string(), Data::String(path.into_inner()));
+    callback.insert("code".to_string(), Data::String(req.code.clone()));
+    callback.insert("state".to_string(), Data::String(req.state.clone()));
+    callback.insert("workspace".to_string(), Data::String(workspace.clone()));
+    callback.insert("ip".to_string(), Data::String(client_ip(&http)));
+    if let Some(device) = user_agent(&http) {
+        callback.insert("device".to_string(), Data::String(device));
+    }
+    let mut inputs = std::collections::HashMap::new();
+    inputs.insert("callback".to_string(), Data::Object(callback));
+    
+    let script = r#"
+        INSTANTIATE auth auth1
+        CALL auth1 oidc_callback $callback result
+    "#;
+    let result = runtime.execute_with_inputs(script, inputs).await;
+    if let Ok(Data::Object(obj)) = &result {
+        if matches!(obj.get("linked"), Some(Data::Bool(true))) {
+            let field = |name: &str| match obj.get(name) {
+                Some(Data::String(s)) => Some(s.clone()),
+                _ => None
+            };
+            return HttpResponse::Ok().json(api::OidcSignIn::Linked(api::Linked {
+                success: true,
+                linked: true,
+                provider: field("provider").unwrap_or_default(),
+                email: field("email"),
+            }));
+        }
+    }
+    sign_in_response(result, None, &workspace)
+}
+
+/// The accounts of providers linked to the caller
+#[utoipa::path(get, path = "/auth/identities", tag = "oidc",
+    security(("bearer" = [])),
+    responses(
+        (status = 200, body = api::Identities),
+        (status = 401, body = ApiError),
+        (status = 500, body = ApiError),
+    ))]
+async fn list_identities(
+    oidc: web::Data<Arc<Oidc>>,
+    auth: web::ReqData<Claims>,
+) -> HttpResponse {
+    match oidc.identities(&auth.workspace, &auth.sub).await {
+        Ok(identities) => HttpResponse::Ok().json(api::Identities {
+            success: true,
+            identities: identities.iter().map(api::Identity::from).collect(),
+        }),
+        Err(e) => oidc_failure(e),
+    }
+}
+
+/// Where to send the caller's browser to link an account of a provider; the
+/// provider sends it back to the same redirect page as for signing in
+#[utoipa::path(post, path = "/auth/identities/{provider}/link", tag = "oidc",
+    security(("bearer" = [])),
+    responses(
+        (status = 200, body = api::Authorization),
+        (status = 401, body = ApiError),
+        (status = 500, description = "Unknown provider, or the provider could not be reached", body = ApiError),
+    ))]
+async fn link_identity(
+    runtime: web::Data<Arc<SPURuntime>>,
+    http: actix_web::HttpRequest,
+    path: web::Path<String>,
+) -> HttpResponse {
+    let mut args = std::collections::HashMap::new();
+    args.insert("provider".to_string(), Data::String(path.into_inner()));
+    match session_call(&runtime, &http, "link_identity", args).await {
+        Ok(response) => HttpResponse::Ok().json(authorization(&response)),
+        Err(response) => response,
+    }
+}
+
+/// Unlink an account of a provider from the caller
+#[utoipa::path(delete, path = "/auth/identities/{provider}/{subject}", tag = "oidc",
+    security(("bearer" = [])),
+    responses(
+        (status = 200, body = api::Unlinked),
+        (status = 401, body = ApiError),
+        (status = 404, description = "Not linked to the caller", body = ApiError),
+        (status = 500, body = ApiError),
+    ))]
+async fn unlink_identity(
+    oidc: web::Data<Arc<Oidc>>,
+    path: web::Path<(String, String)>,
+    auth: web::ReqData<Claims>,
+) -> HttpResponse {
+    let (provider, subject) = path.into_inner();
+    match oidc.unlink(&auth.workspace, &auth.sub, &provider, &subject).await {
+        Ok(()) => HttpResponse::Ok().json(api::Unlinked {
+            success: true,
+            unlinked: true,
+        }),
+        Err(e) => oidc_failure(e),
+    }
+}
+
+/// Why `require_token` turned a request away
+enum Refusal {
+    /// The revocation list or the API keys could not be read
+    Unavailable(String),
+    Unauthorized(String),
+}
+
+/// Refuse requests without a valid `Authorization: Bearer` token or
+/// `Authorization: ApiKey` key, or with a revoked one; handlers get the token's
+/// claims, or the key's, as `web::ReqData<Claims>`, and work in their workspace.
+/// Requests naming another workspace are refused.
+async fn require_token(
+    req: ServiceRequest,
+    next: middleware::Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let authorization = req.headers().get("Authorization").and_then(|v| v.to_str().ok());
+    let verified = match (api_keys::api_key(authorization), req.app_data::<web::Data<Arc<ApiKeys>>>()) {
+        (Some(key), Some(keys)) => keys.authenticate(key).await.map_err(|e| match e {
+            ApiKeyError::Storage(e) => Refusal::Unavailable(e),
+            e => Refusal::Unauthorized(e.to_string()),
+        }),
+        (Some(_), None) => Err(Refusal::Unauthorized("API keys are not accepted".to_string())),
+        (None, _) => match req.app_data::<web::Data<Arc<Sessions>>>() {
+            Some(sessions) => {
+                let verified = match tokens::bearer(authorization) {
+                    Ok(token) => sessions.authenticate(token).await,
+                    Err(e) => Err(SessionError::Token(e)),
+                };
+                verified.map_err(|e| match e {
+                    SessionError::Storage(e) => Refusal::Unavailable(e),
+                    e => Refusal::Unauthorized(e.to_string()),
+                })
+            }
+            None => Err(Refusal::Unauthorized(TokenError::Invalid("no keys configured".to_string()).to_string())),
+        },
+    };
+    
+    match verified {
+        Ok(claims) => {
+            if let Some(named) = other_workspace(req.request(), &claims) {
+                info!("{} of {} named workspace {}", claims.sub, claims.workspace, named);
+                let response = HttpResponse::Forbidden()
+                    .json(ApiError::new(format!("Signed in to {}, not {}", claims.workspace, named)));
+                return Ok(req.into_response(response).map_into_right_body());
+            }
+            let context = audit::Context::current().with_actor(claims.sub.clone());
+            req.extensions_mut().insert(claims);
+            audit::scope(context, next.call(req)).await.map(ServiceResponse::map_into_left_body)
+        }
+        Err(Refusal::Unavailable(e)) => {
+            // Without the revocation list, revoked tokens cannot be told apart
+            error!("Cannot check token revocation: {}", e);
+            let response = HttpResponse::ServiceUnavailable().json(ApiError::new("Authentication unavailable"));
+            Ok(req.into_response(response).map_into_right_body())
+        }
+        Err(Refusal::Unauthorized(e)) => {
+            info!("Refused {} {}: {}", req.method(), req.path(), e);
+            let response = HttpResponse::Unauthorized()
+                .insert_header(("WWW-Authenticate", "Bearer"))
+                .json(ApiError::new(e));
+            Ok(req.into_response(response).map_into_right_body())
+        }
+    }
+}
+
+/// Run every request in an audit context with the client's IP; `require_token`
+/// adds the caller
+async fn audit_context(
+    req: ServiceRequest,
+    next: middleware::Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let context = audit::Context::default().with_ip(client_ip(req.request()));
+    audit::scope(context, next.call(req)).await
+}
+
+/// Script inputs carrying the caller's identity as `$auth`
+fn auth_inputs(claims: &Claims) -> std::collections::HashMap<String, Data> {
+    let mut inputs = std::collections::HashMap::new();
+    inputs.insert("auth".to_string(), claims.to_data());
+    inputs
+}
+
+/// Start of the refusals of the caller's policy, as coprocessors word them
+const FORBIDDEN: &str = "Forbidden:";
+
+fn forbidden(e: String) -> HttpResponse {
+    info!("Refused by policy: {}", e);
+    HttpResponse::Forbidden().json(ApiError::new(e))
+}
+
+/// Run a script for the caller, in their workspace, with `inputs` and `$auth`;
+/// it is raw script text, so admins only
+#[utoipa::path(post, path = "/execute", tag = "scripts",
+    security(("bearer" = []), ("api_key" = [])),
+    request_body = ExecuteRequest,
+    responses(
+        (status = 200, body = api::Executed),
+        (status = 400, description = "Invalid body, or the script failed", body = ApiError),
+        (status = 401, body = ApiError),
+        (status = 403, description = "Not an admin, or refused by the policy", body = ApiError),
+    ))]
+async fn execute_assembly(
+    runtime: web::Data<Arc<SPURuntime>>,
+    store: web::Data<Arc<dyn DocumentStore>>,
+    req: ValidJson<ExecuteRequest>,
+    auth: web::ReqData<Claims>,
+) -> HttpResponse {
+    if let Err(response) = require_admin(store.as_ref().as_ref(), &auth).await {
+        return response;
+    }
+    info!("Executing script in {} for {}", auth.workspace, auth.sub);
+    
+    let req = req.into_inner();
+    let mut inputs: std::collections::HashMap<String, Data> = req.inputs.into_iter()
+        .map(|(k, v)| (k, Data::from_json(v)))
+        .collect();
+    inputs.extend(auth_inputs(&auth));
+    
+    match runtime.execute_as(&req.script, inputs, Caller::from(&*auth)).await {
+        Ok(result) => HttpResponse::Ok().json(api::Executed { success: true, result: data_to_json(&result) }),
+        Err(e) if e.is_forbidden() => forbidden(e.message),
+        Err(e) => {
+            info!("Script failed: {}", e);
+            HttpResponse::BadRequest().json(ApiError::new(format!("Script failed: {}", e)))
+        }
+    }
+}
+
+/// Start a durable script; it runs for the caller, in their workspace, and is
+/// raw script text, so admins only
+#[utoipa::path(post, path = "/workflows", tag = "workflows",
+    security(("bearer" = []), ("api_key" = [])),
+    request_body = StartWorkflowRequest,
+    responses(
+        (status = 200, description = "The workflow", body = api::Workflow),
+        (status = 400, description = "Invalid body or script", body = ApiError),
+        (status = 401, body = ApiError),
+        (status = 403, description = "Not an admin", body = ApiError),
+    ))]
+async fn start_workflow(
+    workflows: web::Data<Arc<WorkflowEngine>>,
+    store: web::Data<Arc<dyn DocumentStore>>,
+    req: ValidJson<StartWorkflowRequest>,
+    auth: web::ReqData<Claims>,
+) -> HttpResponse {
+    if let Err(response) = require_admin(store.as_ref().as_ref(), &auth).await {
+        return response;
+    }
+    info!("Starting durable workflow in {} for {}", auth.workspace, auth.sub);
+    
+    let req = req.into_inner();
+    let mut inputs: std::collections::HashMap<String, Data> = req.inputs.into_iter()
+        .map(|(k, v)| (k, Data::from_json(v)))
+        .collect();
+    inputs.extend(auth_inputs(&auth));
+    
+    match workflows.start_as(&req.script, inputs, req.policy, Caller::from(&*auth)).await {
+        Ok(record) => HttpResponse::Ok().json(api::Workflow::from(&record)),
+        Err(e) => {
+            error!("Failed to start workflow: {}", e);
+            HttpResponse::BadRequest().json(ApiError::new(format!("Failed to start workflow: {}", e)))
+        }
+    }
+}
+
+/// A workflow of the caller's workspace; the others are not found
+async fn workspace_workflow(
+    workflows: &WorkflowEngine,
+    id: &str,
+    claims: &Claims,
+) -> Result<WorkflowRecord, HttpResponse> {
+    match workflows.get(id).await {
+        Ok(Some(record)) if record.workspace() == Some(claims.workspace.as_str()) => Ok(record),
+        Ok(_) => Err(HttpResponse::NotFound().json(ApiError::new("Workflow not found"))),
+        Err(e) => {
+            error!("Failed to load workflow {}: {}", id, e);
+            Err(HttpResponse::InternalServerError().json(ApiError::new(format!("Failed to load workflow: {}", e))))
+        }
+    }
+}
+
+/// A workflow of the workspace; admins only
+#[utoipa::path(get, path = "/workflows/{id}", tag = "workflows",
+    security(("bearer" = []), ("api_key" = [])),
+    responses(
+        (status = 200, description = "The workflow", body = api::Workflow),
+        (status = 401, body = ApiError),
+        (status = 403, description = "Not an admin", body = ApiError),
+        (status = 404, body = ApiError),
+        (status = 500, body = ApiError),
+    ))]
+async fn get_workflow(
+    workflows: web::Data<Arc<WorkflowEngine>>,
+    store: web::Data<Arc<dyn DocumentStore>>,
+    path: web::Path<String>,
+    auth: web::ReqData<Claims>,
+) -> HttpResponse {
+    if let Err(response) = require_admin(store.as_ref().as_ref(), &auth).await {
+        return response;
+    }
+    match workspace_workflow(&workflows, &path.into_inner(), &auth).await {
+        Ok(record) => HttpResponse::Ok().json(api::Workflow::from(&record)),
+        Err(response) => response,
+    }
+}
+
+/// Resume a sleeping workflow of the workspace now; admins only
+#[utoipa::path(post, path = "/workflows/{id}/wake", tag = "workflows",
+    security(("bearer" = []), ("api_key" = [])),
+    responses(
+        (status = 200, description = "The workflow", body = api::Workflow),
+        (status = 401, body = ApiError),
+        (status = 403, description = "Not an admin", body = ApiError),
+        (status = 404, body = ApiError),
+        (status = 409, description = "Not asleep", body = ApiError),
+    ))]
+async fn wake_workflow(
+    workflows: web::Data<Arc<WorkflowEngine>>,
+    store: web::Data<Arc<dyn DocumentStore>>,
+    path: web::Path<String>,
+    auth: web::ReqData<Claims>,
+) -> HttpResponse {
+    if let Err(response) = require_admin(store.as_ref().as_ref(), &auth).await {
+        return response;
+    }
+    let id = match workspace_workflow(&workflows, &path.into_inner(), &auth).await {
+        Ok(record) => record.id,
+        Err(response) => return response,
+    };
+    info!("Waking workflow {}", id);
+    
+    match workflows.wake(&id).await {
+        Ok(record) => HttpResponse::Ok().json(api::Workflow::from(&record)),
+        Err(e) => HttpResponse::Conflict().json(ApiError::new(e)),
+    }
+}
+
+/// Resume the workflows of the workspace waiting for an event; admins only
+#[utoipa::path(post, path = "/workflows/events/{event}", tag = "workflows",
+    security(("bearer" = []), ("api_key" = [])),
+    request_body(content = Object, description = "Payload of the event"),
+    responses(
+        (status = 200, description = "The workflows it resumed", body = api::ResumedWorkflows),
+        (status = 401, body = ApiError),
+        (status = 403, description = "Not an admin", body = ApiError),
+        (status = 500, body = ApiError),
+    ))]
+async fn deliver_workflow_event(
+    workflows: web::Data<Arc<WorkflowEngine>>,
+    store: web::Data<Arc<dyn DocumentStore>>,
+    path: web::Path<String>,
+    payload: web::Json<serde_json::Value>,
+    auth: web::ReqData<Claims>,
+) -> HttpResponse {
+    if let Err(response) = require_admin(store.as_ref().as_ref(), &auth).await {
+        return response;
+    }
+    let event = path.into_inner();
+    info!("Delivering workflow event {} in {}", event, auth.workspace);
+    
+    match workflows.deliver_event_in(&auth.workspace, &event, Data::from_json(payload.into_inner())).await {
+        Ok(records) => HttpResponse::Ok().json(api::ResumedWorkflows {
+            success: true,
+            resumed: records.iter().map(api::Workflow::from).collect(),
+        }),
+        Err(e) => {
+            error!("Failed to deliver event {}: {}", event, e);
+            HttpResponse::InternalServerError().json(ApiError::new(format!("Failed to deliver event: {}", e)))
+        }
+    }
+}
+
+/// The schedules of the workspace; admins only
+#[utoipa::path(get, path = "/schedules", tag = "schedules",
+    security(("bearer" = []), ("api_key" = [])),
+    responses(
+        (status = 200, description = "The schedules", body = api::Schedules),
+        (status = 401, body = ApiError),
+        (status = 403, description = "Not an admin", body = ApiError),
+        (status = 500, body = ApiError),
+    ))]
+async fn list_schedules(
+    scheduler: web::Data<Arc<Scheduler>>,
+    store: web::Data<Arc<dyn DocumentStore>>,
+    auth: web::ReqData<Claims>,
+) -> HttpResponse {
+    if let Err(response) = require_admin(store.as_ref().as_ref(), &auth).await {
+        return response;
+    }
+    match scheduler.list_in(&auth.workspace).await {
+        Ok(schedules) => HttpResponse::Ok().json(api::Schedules {
+            success: true,
+            schedules: schedules.iter().map(api::Schedule::from).collect(),
+        }),
+        Err(e) => {
+            error!("Failed to list schedules: {}", e);
+            HttpResponse::InternalServerError().json(ApiError::new(format!("Failed to list schedules: {}", e)))
+        }
+    }
+}
+
+/// Create or replace a schedule of the workspace, whose script runs confined
+/// to it; admins only
+#[utoipa::path(post, path = "/schedules", tag = "schedules",
+    security(("bearer" = []), ("api_key" = [])),
+    request_body = ScheduleRequest,
+    responses(
+        (status = 200, description = "The schedule", body = api::Schedule),
+        (status = 400, description = "Invalid body, cron or timezone", body = ApiError),
+        (status = 401, body = ApiError),
+        (status = 403, description = "Not an admin", body = ApiError),
+    ))]
+async fn save_schedule(
+    scheduler: web::Data<Arc<Scheduler>>,
+    store: web::Data<Arc<dyn DocumentStore>>,
+    req: ValidJson<ScheduleRequest>,
+    auth: web::ReqData<Claims>,
+) -> HttpResponse {
+    if let Err(response) = require_admin(store.as_ref().as_ref(), &auth).await {
+        return response;
+    }
+    info!("Saving schedule {} in workspace {}", req.name, auth.workspace);
+    
+    match scheduler.upsert_in(&auth.workspace, &req.name, &req.cron, req.timezone.as_deref(), &req.script, req.enabled).await {
+        Ok(schedule) => HttpResponse::Ok().json(api::Schedule::from(&schedule)),
+        Err(e) => {
+            error!("Failed to save schedule {}: {}", req.name, e);
+            HttpResponse::BadRequest().json(ApiError::new(format!("Failed to save schedule: {}", e)))
+        }
+    }
+}
+
+/// A schedule of the caller's workspace; the others are not found
+async fn workspace_schedule(
+    scheduler: &Scheduler,
+    name: &str,
+    claims: &Claims,
+) -> Result<Schedule, HttpResponse> {
+    match scheduler.get_in(&claims.workspace, name).await {
+        Ok(Some(schedule)) => Ok(schedule),
+        Ok(None) => Err(HttpResponse::NotFound().json(ApiError::new("Schedule not found"))),
+        Err(e) => {
+            error!("Failed to load schedule {}: {}", name, e);
+            Err(HttpResponse::InternalServerError().json(ApiError::new(format!("Failed to load schedule: {}", e))))
+        }
+    }
+}
+
+/// A schedule of the workspace; admins only
+#[utoipa::path(get, path = "/schedules/{name}", tag = "schedules",
+    security(("bearer" = []), ("api_key" = [])),
+    responses(
+        (status = 200, description = "The schedule", body = api::Schedule),
+        (status = 401, body = ApiError),
+        (status = 403, description = "Not an admin", body = ApiError),
+        (status = 404, body = ApiError),
+        (status = 500, body = ApiError),
+    ))]
+async fn get_schedule(
+    scheduler: web::Data<Arc<Scheduler>>,
+    store: web::Data<Arc<dyn DocumentStore>>,
+    path: web::Path<String>,
+    auth: web::ReqData<Claims>,
+) -> HttpResponse {
+    if let Err(response) = require_admin(store.as_ref().as_ref(), &auth).await {
+        return response;
+    }
+    match workspace_schedule(&scheduler, &path.into_inner(), &auth).await {
+        Ok(schedule) => HttpResponse::Ok().json(api::Schedule::from(&schedule)),
+        Err(response) => response,
+    }
+}
+
+/// Delete a schedule of the workspace; admins only
+#[utoipa::path(delete, path = "/schedules/{name}", tag = "schedules",
+    security(("bearer" = []), ("api_key" = [])),
+    responses(
+        (status = 200, body = api::Done),
+        (status = 401, body = ApiError),
+        (status = 403, description = "Not an admin", body = ApiError),
+        (status = 404, body = ApiError),
+        (status = 500, body = ApiError),
+    ))]
+async fn delete_schedule(
+    scheduler: web::Data<Arc<Scheduler>>,
+    store: web::Data<Arc<dyn DocumentStore>>,
+    path: web::Path<String>,
+    auth: web::ReqData<Claims>,
+) -> HttpResponse {
+    if let Err(response) = require_admin(store.as_ref().as_ref(), &auth).await {
+        return response;
+    }
+    let name = match workspace_schedule(&scheduler, &path.into_inner(), &auth).await {
+        Ok(schedule) => schedule.name,
+        Err(response) => return response,
+    };
+    info!("Deleting schedule {} in workspace {}", name, auth.workspace);
+    
+    match scheduler.delete_in(&auth.workspace, &name).await {
+        Ok(true) => HttpResponse::Ok().json(api::Done { success: true }),
+        Ok(false) => HttpResponse::NotFound().json(ApiError::new("Schedule not found")),
+        Err(e) => {
+            error!("Failed to delete schedule {}: {}", name, e);
+            HttpResponse::InternalServerError().json(ApiError::new(format!("Failed to delete schedule: {}", e)))
+        }
+    }
+}
+
+/// Run a schedule of the workspace now; admins only
+#[utoipa::path(post, path = "/schedules/{name}/run", tag = "schedules",
+    security(("bearer" = []), ("api_key" = [])),
+    responses(
+        (status = 200, description = "The run", body = api::ScheduleRun),
+        (status = 401, body = ApiError),
+        (status = 403, description = "Not an admin", body = ApiError),
+        (status = 404, body = ApiError),
+    ))]
+async fn run_schedule(
+    scheduler: web::Data<Arc<Scheduler>>,
+    store: web::Data<Arc<dyn DocumentStore>>,
+    path: web::Path<String>,
+    auth: web::ReqData<Claims>,
+) -> HttpResponse {
+    if let Err(response) = require_admin(store.as_ref().as_ref(), &auth).await {
+        return response;
+    }
+    let name = match workspace_schedule(&scheduler, &path.into_inner(), &auth).await {
+        Ok(schedule) => schedule.name,
+        Err(response) => return response,
+    };
+    info!("Running schedule on demand: {}", name);
+    
+    match scheduler.run_now_in(&auth.workspace, &name).await {
+        Ok(run) => HttpResponse::Ok().json(api::ScheduleRun::from(&run)),
+        Err(e) => HttpResponse::NotFound().json(ApiError::new(e)),
+    }
+}
+
+/// The latest runs of a schedule of the workspace; admins only
+#[utoipa::path(get, path = "/schedules/{name}/runs", tag = "schedules",
+    security(("bearer" = []), ("api_key" = [])),
+    params(RunsQuery),
+    responses(
+        (status = 200, description = "Its latest runs", body = api::ScheduleRuns),
+        (status = 401, body = ApiError),
+        (status = 403, description = "Not an admin", body = ApiError),
+        (status = 404, body = ApiError),
+        (status = 500, body = ApiError),
+    ))]
+async fn list_schedule_runs(
+    scheduler: web::Data<Arc<Scheduler>>,
+    store: web::Data<Arc<dyn DocumentStore>>,
+    path: web::Path<String>,
+    query: web::Query<RunsQuery>,
+    auth: web::ReqData<Claims>,
+) -> HttpResponse {
+    if let Err(response) = require_admin(store.as_ref().as_ref(), &auth).await {
+        return response;
+    }
+    let name = match workspace_schedule(&scheduler, &path.into_inner(), &auth).await {
+        Ok(schedule) => schedule.name,
+        Err(response) => return response,
+    };
+    
+    match scheduler.runs_in(&auth.workspace, &name, query.limit.unwrap_or(20)).await {
+        Ok(runs) => HttpResponse::Ok().json(api::ScheduleRuns {
+            success: true,
+            runs: runs.iter().map(api::ScheduleRun::from).collect(),
+        }),
+        Err(e) => {
+            error!("Failed to list runs of schedule {}: {}", name, e);
+            HttpResponse::InternalServerError().json(ApiError::new(format!("Failed to list runs: {}", e)))
+        }
+    }
+}
+
+/// The triggers of the workspace; admins only
+#[utoipa::path(get, path = "/triggers", tag = "triggers",
+    params(WorkspaceQuery),
+    security(("bearer" = []), ("api_key" = [])),
+    responses(
+        (status = 200, description = "The triggers", body = api::Triggers),
+        (status = 401, body = ApiError),
+        (status = 403, description = "Not an admin, or another workspace", body = ApiError),
+        (status = 500, body = ApiError),
+    ))]
+async fn list_triggers(
+    triggers: web::Data<Arc<TriggerDispatcher>>,
+    store: web::Data<Arc<dyn DocumentStore>>,
+    auth: web::ReqData<Claims>,
+) -> HttpResponse {
+    if let Err(response) = require_admin(store.as_ref().as_ref(), &auth).await {
+        return response;
+    }
+    let workspace = auth.workspace.clone();
+    
+    match triggers.list(&workspace).await {
+        Ok(list) => HttpResponse::Ok().json(api::Triggers {
+            success: true,
+            triggers: list.iter().map(api::Trigger::from).collect(),
+        }),
+        Err(e) => {
+            error!("Failed to list triggers in {}: {}", workspace, e);
+            HttpResponse::InternalServerError().json(ApiError::new(format!("Failed to list triggers: {}", e)))
+        }
+    }
+}
+
+/// Create or replace a trigger of the workspace; admins only
+#[utoipa::path(post, path = "/triggers", tag = "triggers",
+    params(WorkspaceQuery),
+    security(("bearer" = []), ("api_key" = [])),
+    request_body = TriggerRequest,
+    responses(
+        (status = 200, description = "The trigger", body = api::Trigger),
+        (status = 400, description = "Invalid body", body = ApiError),
+        (status = 401, body = ApiError),
+        (status = 403, description = "Not an admin, or another workspace", body = ApiError),
+    ))]
+async fn save_trigger(
+    triggers: web::Data<Arc<TriggerDispatcher>>,
+    store: web::Data<Arc<dyn DocumentStore>>,
+    body: ValidJson<TriggerRequest>,
+    auth: web::ReqData<Claims>,
+) -> HttpResponse {
+    if let Err(response) = require_admin(store.as_ref().as_ref(), &auth).await {
+        return response;
+    }
+    let workspace = auth.workspace.clone();
+    
+    info!("Saving trigger {} on {} in workspace {}", body.name, body.event, workspace);
+    
+    let body = body.into_inner();
+    let filter = body.filter.into_iter()
+        .map(|(k, v)| (k, Data::from_json(v)))
+        .collect();
+    
+    match triggers.save(&workspace, &body.name, &body.event, filter, &body.script, body.enabled).await {
+        Ok(trigger) => HttpResponse::Ok().json(api::Trigger::from(&trigger)),
+        Err(e) => {
+            error!("Failed to save trigger {}: {}", body.name, e);
+            HttpResponse::BadRequest().json(ApiError::new(format!("Failed to save trigger: {}", e)))
+        }
+    }
+}
+
+/// Delete a trigger of the workspace; admins only
+#[utoipa::path(delete, path = "/triggers/{name}", tag = "triggers",
+    params(WorkspaceQuery),
+    security(("bearer" = []), ("api_key" = [])),
+    responses(
+        (status = 200, body = api::Done),
+        (status = 401, body = ApiError),
+        (status = 403, description = "Not an admin, or another workspace", body = ApiError),
+        (status = 404, body = ApiError),
+        (status = 500, body = ApiError),
+    ))]
+async fn delete_trigger(
+    triggers: web::Data<Arc<TriggerDispatcher>>,
+    store: web::Data<Arc<dyn DocumentStore>>,
+    path: web::Path<String>,
+    auth: web::ReqData<Claims>,
+) -> HttpResponse {
+    if let Err(response) = require_admin(store.as_ref().as_ref(), &auth).await {
+        return response;
+    }
+    let workspace = auth.workspace.clone();
+    let name = path.into_inner();
+    
+    info!("Deleting trigger {} in workspace {}", name, workspace);
+    
+    match triggers.delete(&workspace, &name).await {
+        Ok(true) => HttpResponse::Ok().json(api::Done { success: true }),
+        Ok(false) => HttpResponse::NotFound().json(ApiError::new("Trigger not found")),
+        Err(e) => {
+            error!("Failed to delete trigger {}: {}", name, e);
+            HttpResponse::InternalServerError().json(ApiError::new(format!("Failed to delete trigger: {}", e)))
+        }
+    }
+}
+
+/// The webhooks of the workspace; admins only
+#[utoipa::path(get, path = "/webhooks", tag = "webhooks",
+    params(WorkspaceQuery),
+    security(("bearer" = []), ("api_key" = [])),
+    responses(
+        (status = 200, description = "The webhooks", body = api::Webhooks),
+        (status = 401, body = ApiError),
+        (status = 403, description = "Not an admin, or another workspace", body = ApiError),
+        (status = 500, body = ApiError),
+    ))]
+async fn list_webhooks(
+    webhooks: web::Data<Arc<WebhookRouter>>,
+    store: web::Data<Arc<dyn DocumentStore>>,
+    auth: web::ReqData<Claims>,
+) -> HttpResponse {
+    if let Err(response) = require_admin(store.as_ref().as_ref(), &auth).await {
+        return response;
+    }
+    let workspace = auth.workspace.clone();
+    
+    match webhooks.list(&workspace).await {
+        Ok(routes) => HttpResponse::Ok().json(api::Webhooks {
+            success: true,
+            webhooks: routes.iter().map(api::Webhook::from).collect(),
+        }),
+        Err(e) => {
+            error!("Failed to list webhooks in {}: {}", workspace, e);
+            HttpResponse::InternalServerError().json(ApiError::new(format!("Failed to list webhooks: {}", e)))
+        }
+    }
+}
+
+/// Create or replace a webhook of the workspace; admins only
+#[utoipa::path(post, path = "/webhooks", tag = "webhooks",
+    params(WorkspaceQuery),
+    security(("bearer" = []), ("api_key" = [])),
+    request_body = WebhookRequest,
+    responses(
+        (status = 200, description = "The webhook", body = api::Webhook),
+        (status = 400, description = "Invalid body", body = ApiError),
+        (status = 401, body = ApiError),
+        (status = 403, description = "Not an admin, or another workspace", body = ApiError),
+    ))]
+async fn save_webhook(
+    webhooks: web::Data<Arc<WebhookRouter>>,
+    store: web::Data<Arc<dyn DocumentStore>>,
+    body: ValidJson<WebhookRequest>,
+    auth: web::ReqData<Claims>,
+) -> HttpResponse {
+    if let Err(response) = require_admin(store.as_ref().as_ref(), &auth).await {
+        return response;
+    }
+    let workspace = auth.workspace.clone();
+    
+    info!("Saving webhook {} in workspace {}", body.name, workspace);
+    
+    let body = body.into_inner();
+    match webhooks.save(&workspace, &body.name, body.auth, &body.script, body.status, body.enabled).await {
+        Ok(route) => HttpResponse::Ok().json(api::Webhook::from(&route)),
+        Err(e) => {
+            error!("Failed to save webhook {}: {}", body.name, e);
+            HttpResponse::BadRequest().json(ApiError::new(format!("Failed to save webhook: {}", e)))
+        }
+    }
+}
+
+/// Delete a webhook of the workspace; admins only
+#[utoipa::path(delete, path = "/webhooks/{name}", tag = "webhooks",
+    params(WorkspaceQuery),
+    security(("bearer" = []), ("api_key" = [])),
+    responses(
+        (status = 200, body = api::Done),
+        (status = 401, body = ApiError),
+        (status = 403, description = "Not an admin, or another workspace", body = ApiError),
+        (status = 404, body = ApiError),
+        (status = 500, body = ApiError),
+    ))]
+async fn delete_webhook(
+    webhooks: web::Data<Arc<WebhookRouter>>,
+    store: web::Data<Arc<dyn DocumentStore>>,
+    path: web::Path<String>,
+    auth: web::ReqData<Claims>,
+) -> HttpResponse {
+    if let Err(response) = require_admin(store.as_ref().as_ref(), &auth).await {
+        return response;
+    }
+    let workspace = auth.workspace.clone();
+    let name = path.into_inner();
+    
+    info!("Deleting webhook {} in workspace {}", name, workspace);
+    
+    match webhooks.delete(&workspace, &name).await {
+        Ok(true) => HttpResponse::Ok().json(api::Done { success: true }),
+        Ok(false) => HttpResponse::NotFound().json(ApiError::new("Webhook not found")),
+        Err(e) => {
+            error!("Failed to delete webhook {}: {}", name, e);
+            HttpResponse::InternalServerError().json(ApiError::new(format!("Failed to delete webhook: {}", e)))
+        }
+    }
+}
+
+#[utoipa::path(post, path = "/hooks/{workspace}/{name}", tag = "webhooks",
+    request_body(content = Object, description = "Given to the script as `$body`"),
+    responses(
+        (status = 200, description = "What the script returned, with the webhook's status", body = api::ScriptOutput),
+        (status = 401, description = "Bad signature or token", body = ApiError),
+        (status = 404, body = ApiError),
+        (status = 500, body = ApiError),
+    ))]
+async fn call_webhook(
+    webhooks: web::Data<Arc<WebhookRouter>>,
+    req: actix_web::HttpRequest,
+    path: web::Path<(String, String)>,
+    query: web::Query<std::collections::HashMap<String, String>>,
+    body: web::Bytes,
+) -> HttpResponse {
+    let (workspace, name) = path.into_inner();
+    
+    let headers = req.headers().iter()
+        .filter_map(|(k, v)| v.to_str().ok().map(|v| (k.as_str().to_lowercase(), v.to_string())))
+        .collect();
+    
+    match webhooks.handle(&workspace, &name, headers, query.into_inner(), &body).await {
+        Ok((status, result)) => {
+            let status = actix_web::http::StatusCode::from_u16(status)
+                .unwrap_or(actix_web::http::StatusCode::OK);
+            HttpResponse::build(status).json(api::ScriptOutput(data_to_json(&result)))
+        }
+        Err(e) => {
+            let response = ApiError::new(e.to_string());
+            match e {
+                WebhookError::NotFound => HttpResponse::NotFound().json(response),
+                WebhookError::Unauthorized(_) => HttpResponse::Unauthorized().json(response),
+                WebhookError::ScriptFailed(_) | WebhookError::Storage(_) => {
+                    HttpResponse::InternalServerError().json(response)
+                }
+            }
+        }
+    }
+}
+
+#[utoipa::path(get, path = "/pipelines", tag = "pipelines",
+    params(WorkspaceQuery),
+    security(("bearer" = []), ("api_key" = [])),
+    responses(
+        (status = 200, description = "The pipelines", body = api::Pipelines),
+        (status = 401, body = ApiError),
+        (status = 403, description = "Another workspace", body = ApiError),
+        (status = 500, body = ApiError),
+    ))]
+async fn list_pipelines(
+    pipelines: web::Data<Arc<PipelineLibrary>>,
+    auth: web::ReqData<Claims>,
+) -> HttpResponse {
+    let workspace = auth.workspace.clone();
+    
+    match pipelines.list(&workspace).await {
+        Ok(list) => HttpResponse::Ok().json(api::Pipelines {
+            success: true,
+            pipelines: list.iter().map(api::Pipeline::from).collect(),
+        }),
+        Err(e) => {
+            error!("Failed to list pipelines in {}: {}", workspace, e);
+            HttpResponse::InternalServerError().json(ApiError::new(format!("Failed to list pipelines: {}", e)))
+        }
+    }
+}
+
+/// Create or replace a pipeline of the workspace; admins only
+#[utoipa::path(post, path = "/pipelines", tag = "pipelines",
+    params(WorkspaceQuery),
+    security(("bearer" = []), ("api_key" = [])),
+    request_body = PipelineRequest,
+    responses(
+        (status = 200, description = "The pipeline", body = api::Pipeline),
+        (status = 400, description = "Invalid body, pipeline", body = ApiError),
+        (status = 401, body = ApiError),
+        (status = 403, description = "Not an admin, or another workspace", body = ApiError),
+    ))]
+async fn save_pipeline(
+    pipelines: web::Data<Arc<PipelineLibrary>>,
+    store: web::Data<Arc<dyn DocumentStore>>,
+    body: ValidJson<PipelineRequest>,
+    auth: web::ReqData<Claims>,
+) -> HttpResponse {
+    if let Err(response) = require_admin(store.as_ref().as_ref(), &auth).await {
+        return response;
+    }
+    let workspace = auth.workspace.clone();
+    
+    info!("Saving pipeline {} in workspace {}", body.name, workspace);
+    
+    let body = body.into_inner();
+    match pipelines.save(&workspace, &body.name, &body.collection, body.pipeline, body.parameters, body.description).await {
+        Ok(saved) => HttpResponse::Ok().json(api::Pipeline::from(&saved)),
+        Err(e) => {
+            error!("Failed to save pipeline {}: {}", body.name, e);
+            HttpResponse::BadRequest().json(ApiError::new(format!("Failed to save pipeline: {}", e)))
+        }
+    }
+}
+
+#[utoipa::path(get, path = "/pipelines/{name}", tag = "pipelines",
+    params(WorkspaceQuery),
+    security(("bearer" = []), ("api_key" = [])),
+    responses(
+        (status = 200, description = "The pipeline", body = api::Pipeline),
+        (status = 401, body = ApiError),
+        (status = 403, description = "Another workspace", body = ApiError),
+        (status = 404, body = ApiError),
+        (status = 500, body = ApiError),
+    ))]
+async fn get_pipeline(
+    pipelines: web::Data<Arc<PipelineLibrary>>,
+    path: web::Path<String>,
+    auth: web::ReqData<Claims>,
+) -> HttpResponse {
+    let workspace = auth.workspace.clone();
+    let name = path.into_inner();
+    
+    match pipelines.load(&workspace, &name).await {
+        Ok(Some(saved)) => HttpResponse::Ok().json(api::Pipeline::from(&saved)),
+        Ok(None) => HttpResponse::NotFound().json(ApiError::new("Pipeline not found")),
+        Err(e) => {
+            error!("Failed to load pipeline {}: {}", name, e);
+            HttpResponse::InternalServerError().json(ApiError::new(format!("Failed to load pipeline: {}", e)))
+        }
+    }
+}
+
+/// Delete a pipeline of the workspace; admins only
+#[utoipa::path(delete, path = "/pipelines/{name}", tag = "pipelines",
+    params(WorkspaceQuery),
+    security(("bearer" = []), ("api_key" = [])),
+    responses(
+        (status = 200, body = api::Done),
+        (status = 401, body = ApiError),
+        (status = 403, description = "Not an admin, or another workspace", body = ApiError),
+        (status = 404, body = ApiError),
+        (status = 500, body = ApiError),
+    ))]
+async fn delete_pipeline(
+    pipelines: web::Data<Arc<PipelineLibrary>>,
+    store: web::Data<Arc<dyn DocumentStore>>,
+    path: web::Path<String>,
+    auth: web::ReqData<Claims>,
+) -> HttpResponse {
+    if let Err(response) = require_admin(store.as_ref().as_ref(), &auth).await {
+        return response;
+    }
+    let workspace = auth.workspace.clone();
+    let name = path.into_inner();
+    
+    info!("Deleting pipeline {} in workspace {}", name, workspace);
+    
+    match pipelines.delete(&workspace, &name).await {
+        Ok(true) => HttpResponse::Ok().json(api::Done { success: true }),
+        Ok(false) => HttpResponse::NotFound().json(ApiError::new("Pipeline not found")),
+        Err(e) => {
+            error!("Failed to delete pipeline {}: {}", name, e);
+            HttpResponse::InternalServerError().json(ApiError::new(format!("Failed to delete pipeline: {}", e)))
+        }
+    }
+}
+
+/// Run a pipeline of the workspace for the caller, over what they may read
+#[utoipa::path(post, path = "/pipelines/{name}/run", tag = "pipelines",
+    params(WorkspaceQuery),
+    security(("bearer" = []), ("api_key" = [])),
+    request_body(content = Option<PipelineRunRequest>),
+    responses(
+        (status = 200, description = "The documents it gave", body = api::PipelineRun),
+        (status = 400, description = "Invalid body, or unknown parameters", body = ApiError),
+        (status = 401, body = ApiError),
+        (status = 403, description = "Not allowed to read the collection, or another workspace", body = ApiError),
+        (status = 404, body = ApiError),
+        (status = 500, body = ApiError),
+    ))]
+async fn run_pipeline(
+    pipelines: web::Data<Arc<PipelineLibrary>>,
+    path: web::Path<String>,
+    body: OptionalJson<PipelineRunRequest>,
+    auth: web::ReqData<Claims>,
+) -> HttpResponse {
+    let name = path.into_inner();
+    let body = body.into_inner().unwrap_or_default();
+    
+    match pipelines.run_as(Caller::from(&*auth), &name, body.params, body.limit).await {
+        Ok(result) => HttpResponse::Ok().json(api::PipelineRun::from_result(data_to_json(&result))),
+        Err(e) => {
+            let response = ApiError::new(e.to_string());
+            match e {
+                PipelineError::NotFound => HttpResponse::NotFound().json(response),
+                PipelineError::InvalidParameters(_) => HttpResponse::BadRequest().json(response),
+                PipelineError::Forbidden(e) => forbidden(e),
+                PipelineError::Failed(_) | PipelineError::Storage(_) => {
+                    HttpResponse::InternalServerError().json(response)
+                }
+            }
+        }
+    }
+}
+
+/// Run one database coprocessor method for the caller, with `$query` as its arguments
+async fn call_database(
+    runtime: &SPURuntime,
+    method: &str,
+    query: std::collections::HashMap<String, Data>,
+    claims: &Claims,
+) -> Result<Data, ScriptError> {
+    let script = format!("INSTANTIATE database db\nCALL db {} $query result\nDESTROY db\nRETURN $result", method);
+    let mut inputs = auth_inputs(claims);
+    inputs.insert("query".to_string(), Data::Object(query));
+    runtime.execute_as(&script, inputs, Caller::from(claims)).await
+}
+
+fn schema_query(workspace: &str, collection: Option<String>) -> std::collections::HashMap<String, Data> {
+    let mut query = std::collections::HashMap::new();
+    query.insert("workspace".to_string(), Data::String(workspace.to_string()));
+    if let Some(collection) = collection {
+        query.insert("collection".to_string(), Data::String(collection));
+    }
+    query
+}
+
+/// The filter matching the document with `id`
+fn id_filter(id: &str) -> Data {
+    let mut filter = std::collections::HashMap::new();
+    filter.insert("_id".to_string(), Data::String(id.to_string()));
+    Data::Object(filter)
+}
+
+/// The schemas of the workspace; admins only
+#[utoipa::path(get, path = "/schemas", tag = "schemas",
+    params(WorkspaceQuery),
+    security(("bearer" = []), ("api_key" = [])),
+    responses(
+        (status = 200, description = "The schemas", body = api::Schemas),
+        (status = 401, body = ApiError),
+        (status = 403, description = "Not an admin, or another workspace", body = ApiError),
+        (status = 500, body = ApiError),
+    ))]
+async fn list_schemas(
+    runtime: web::Data<Arc<SPURuntime>>,
+    auth: web::ReqData<Claims>,
+) -> HttpResponse {
+    let workspace = auth.workspace.clone();
+    
+    match call_database(&runtime, "list_schemas", schema_query(&workspace, None), &auth).await {
+        Ok(result) => match data_to_json(&result)["schemas"].take() {
+            serde_json::Value::Array(schemas) => HttpResponse::Ok().json(api::Schemas { success: true, schemas }),
+            other => {
+                error!("Unexpected schemas result: {}", other);
+                HttpResponse::InternalServerError().json(ApiError::new("Failed to list schemas"))
+            }
+        },
+        Err(e) if e.is_forbidden() => forbidden(e.message),
+        Err(e) => {
+            error!("Failed to list schemas in {}: {}", workspace, e);
+            HttpResponse::InternalServerError().json(ApiError::new(format!("Failed to list schemas: {}", e)))
+        }
+    }
+}
+
+#[utoipa::path(get, path = "/schemas/{collection}", tag = "schemas",
+    params(WorkspaceQuery),
+    security(("bearer" = []), ("api_key" = [])),
+    responses(
+        (status = 200, description = "The schema", body = api::CollectionSchema),
+        (status = 401, body = ApiError),
+        (status = 403, description = "Not allowed to read the collection, or another workspace", body = ApiError),
+        (status = 404, body = ApiError),
+        (status = 500, body = ApiError),
+    ))]
+async fn get_schema(
+    runtime: web::Data<Arc<SPURuntime>>,
+    path: web::Path<String>,
+    auth: web::ReqData<Claims>,
+) -> HttpResponse {
+    let workspace = auth.workspace.clone();
+    let collection = path.into_inner();
+    
+    match call_database(&runtime, "get_schema", schema_query(&workspace, Some(collection.clone())), &auth).await {
+        Ok(result) => {
+            let mut result = data_to_json(&result);
+            if result["found"] == json!(true) {
+                HttpResponse::Ok().json(api::CollectionSchema {
+                    success: true,
+                    collection,
+                    schema: result["schema"].take(),
+                })
+            } else {
+                HttpResponse::NotFound().json(ApiError::new("Schema not found"))
+            }
+        }
+        Err(e) if e.is_forbidden() => forbidden(e.message),
+        Err(e) => {
+            error!("Failed to load the schema of {}: {}", collection, e);
+            HttpResponse::InternalServerError().json(ApiError::new(format!("Failed to load schema: {}", e)))
+        }
+    }
+}
+
+/// Set the schema of a collection of the workspace; admins only
+#[utoipa::path(put, path = "/schemas/{collection}", tag = "schemas",
+    params(WorkspaceQuery),
+    security(("bearer" = []), ("api_key" = [])),
+    request_body(content = Object, description = "JSON Schema of the collection's documents"),
+    responses(
+        (status = 200, body = api::Configured),
+        (status = 400, description = "Invalid schema", body = ApiError),
+        (status = 401, body = ApiError),
+        (status = 403, description = "Not an admin, or another workspace", body = ApiError),
+        (status = 500, body = ApiError),
+    ))]
+async fn save_schema(
+    runtime: web::Data<Arc<SPURuntime>>,
+    path: web::Path<String>,
+    body: web::Json<serde_json::Value>,
+    auth: web::ReqData<Claims>,
+) -> HttpResponse {
+    let workspace = auth.workspace.clone();
+    let collection = path.into_inner();
+    
+    info!("Saving the schema of {} in workspace {}", collection, workspace);
+    
+    let mut query = schema_query(&workspace, Some(collection.clone()));
+    query.insert("schema".to_string(), Data::from_json(body.into_inner()));
+    match call_database(&runtime, "set_schema", query, &auth).await {
+        Ok(_) => HttpResponse::Ok().json(api::Configured { success: true, collection }),
+        Err(e) if e.is_invalid() => HttpResponse::BadRequest().json(ApiError::new(e)),
+        Err(e) if e.is_forbidden() => forbidden(e.message),
+        Err(e) => {
+            error!("Failed to save the schema of {}: {}", collection, e);
+            HttpResponse::InternalServerError().json(ApiError::new(format!("Failed to save schema: {}", e)))
+        }
+    }
+}
+
+/// Delete the schema of a collection of the workspace; admins only
+#[utoipa::path(delete, path = "/schemas/{collection}", tag = "schemas",
+    params(WorkspaceQuery),
+    security(("bearer" = []), ("api_key" = [])),
+    responses(
+        (status = 200, body = api::Done),
+        (status = 401, body = ApiError),
+        (status = 403, description = "Not an admin, or another workspace", body = ApiError),
+        (status = 404, body = ApiError),
+        (status = 500, body = ApiError),
+    ))]
+async fn delete_schema(
+    runtime: web::Data<Arc<SPURuntime>>,
+    path: web::Path<String>,
+    auth: web::ReqData<Claims>,
+) -> HttpResponse {
+    let workspace = auth.workspace.clone();
+    let collection = path.into_inner();
+    
+    info!("Deleting the schema of {} in workspace {}", collection, workspace);
+    
+    match call_database(&runtime, "delete_schema", schema_query(&workspace, Some(collection.clone())), &auth).await {
+        Ok(result) if data_to_json(&result)["deleted"] == json!(true) => {
+            HttpResponse::Ok().json(api::Done { success: true })
+        }
+        Ok(_) => HttpResponse::NotFound().json(ApiError::new("Schema not found")),
+        Err(e) if e.is_forbidden() => forbidden(e.message),
+        Err(e) => {
+            error!("Failed to delete the schema of {}: {}", collection, e);
+            HttpResponse::InternalServerError().json(ApiError::new(format!("Failed to delete schema: {}", e)))
+        }
+    }
+}
+
+/// Check the documents already in a collection; `?limit=` caps how many
+/// invalid ones are listed; admins only
+#[utoipa::path(post, path = "/schemas/{collection}/validate", tag = "schemas",
+    params(WorkspaceQuery),
+    params(ValidateQuery),
+    security(("bearer" = []), ("api_key" = [])),
+    responses(
+        (status = 200, description = "How many documents are invalid, and which", body = api::ValidationReport),
+        (status = 401, body = ApiError),
+        (status = 403, description = "Not an admin, or another workspace", body = ApiError),
+        (status = 404, description = "No schema", body = ApiError),
+        (status = 500, body = ApiError),
+    ))]
+async fn validate_collection(
+    runtime: web::Data<Arc<SPURuntime>>,
+    path: web::Path<String>,
+    params: web::Query<ValidateQuery>,
+    auth: web::ReqData<Claims>,
+) -> HttpResponse {
+    let workspace = auth.workspace.clone();
+    let collection = path.into_inner();
+    
+    let mut query = schema_query(&workspace, Some(collection.clone()));
+    if let Some(limit) = params.limit {
+        query.insert("limit".to_string(), Data::Number(limit as f64));
+    }
+    match call_database(&runtime, "validate_collection", query, &auth).await {
+        Ok(result) => HttpResponse::Ok().json(api::ValidationReport::from_result(data_to_json(&result))),
+        Err(e) if e.is_not_found() => HttpResponse::NotFound().json(ApiError::new("Schema not found")),
+        Err(e) if e.is_forbidden() => forbidden(e.message),
+        Err(e) => {
+            error!("Failed to validate {}: {}", collection, e);
+            HttpResponse::InternalServerError().json(ApiError::new(format!("Failed to validate collection: {}", e)))
+        }
+    }
+}
+
+/// The access policy of the workspace, for its admins
+#[utoipa::path(get, path = "/policy", tag = "policy",
+    params(WorkspaceQuery),
+    security(("bearer" = []), ("api_key" = [])),
+    responses(
+        (status = 200, description = "The policy, or the default one", body = api::WorkspacePolicy),
+        (status = 401, body = ApiError),
+        (status = 403, description = "Not an admin, or another workspace", body = ApiError),
+        (status = 500, body = ApiError),
+    ))]
+async fn get_policy(
+    runtime: web::Data<Arc<SPURuntime>>,
+    auth: web::ReqData<Claims>,
+) -> HttpResponse {
+    let workspace = auth.workspace.clone();
+    
+    let mut inputs = auth_inputs(&auth);
+    inputs.insert("query".to_string(), Data::Object(schema_query(&workspace, None)));
+    let script = "INSTANTIATE database db\nCALL db get_policy $query result\nDESTROY db\nRETURN $result";
+    match runtime.execute_as(script, inputs, Caller::from(&*auth)).await {
+        Ok(result) => {
+            let mut result = data_to_json(&result);
+            HttpResponse::Ok().json(api::WorkspacePolicy {
+                success: true,
+                policy: result["policy"].take(),
+                default: result["default"] == json!(true),
+            })
+        }
+        Err(e) if e.is_forbidden() => forbidden(e.message),
+        Err(e) => {
+            error!("Failed to load the policy of {}: {}", workspace, e);
+            HttpResponse::InternalServerError().json(ApiError::new(format!("Failed to load policy: {}", e)))
+        }
+    }
+}
+
+/// Replace the access policy of the workspace; admins only
+#[utoipa::path(put, path = "/policy", tag = "policy",
+    params(WorkspaceQuery),
+    security(("bearer" = []), ("api_key" = [])),
+    request_body(content = Object, description = "Roles and what they may read and write"),
+    responses(
+        (status = 200, body = api::Done),
+        (status = 400, description = "Invalid policy", body = ApiError),
+        (status = 401, body = ApiError),
+        (status = 403, description = "Not an admin, or another workspace", body = ApiError),
+        (status = 500, body = ApiError),
+    ))]
+async fn save_policy(
+    runtime: web::Data<Arc<SPURuntime>>,
+    body: web::Json<serde_json::Value>,
+    auth: web::ReqData<Claims>,
+) -> HttpResponse {
+    let workspace = auth.workspace.clone();
+    
+    info!("Saving the policy of workspace {} for {}", workspace, auth.sub);
+    
+    let mut query = schema_query(&workspace, None);
+    query.insert("policy".to_string(), Data::from_json(body.into_inner()));
+    let mut inputs = auth_inputs(&auth);
+    inputs.insert("query".to_string(), Data::Object(query));
+    let script = "INSTANTIATE database db\nCALL db set_policy $query result\nDESTROY db\nRETURN $result";
+    match runtime.execute_as(script, inputs, Caller::from(&*auth)).await {
+        Ok(_) => HttpResponse::Ok().json(api::Done { success: true }),
+        Err(e) if e.is_forbidden() => forbidden(e.message),
+        Err(e) if e.is_invalid() => HttpResponse::BadRequest().json(ApiError::new(e)),
+        Err(e) => {
+            error!("Failed to save the policy of {}: {}", workspace, e);
+            HttpResponse::InternalServerError().json(ApiError::new(format!("Failed to save policy: {}", e)))
+        }
+    }
+}
+
+/// Refuse callers that don't administer their workspace
+async fn require_admin(store: &dyn DocumentStore, claims: &Claims) -> Result<(), HttpResponse> {
+    let policy = match policy::stored(store, &claims.workspace).await {
+        Ok(policy) => policy.unwrap_or_default(),
+        Err(e) => {
+            error!("Failed to load the policy of {}: {}", claims.workspace, e);
+            return Err(HttpResponse::InternalServerError().json(ApiError::new(e)));
+        }
+    };
+    policy.require_admin(&Caller::from(claims), &claims.workspace)
+        .map_err(|e| forbidden(format!("{} {}", FORBIDDEN, e)))
+}
+
+fn api_key_failure(e: ApiKeyError) -> HttpResponse {
+    let response = ApiError::new(e.to_string());
+    match e {
+        ApiKeyError::NotFound => HttpResponse::NotFound().json(response),
+        ApiKeyError::InvalidArguments(_) => HttpResponse::BadRequest().json(response),
+        ApiKeyError::Invalid | ApiKeyError::Expired | ApiKeyError::Revoked => HttpResponse::Unauthorized().json(response),
+        ApiKeyError::Storage(e) => {
+            error!("API key storage failed: {}", e);
+            HttpResponse::InternalServerError().json(response)
+        }
+    }
+}
+
+/// The API keys of the workspace that still work; admins only
+#[utoipa::path(get, path = "/api-keys", tag = "api-keys",
+    security(("bearer" = []), ("api_key" = [])),
+    responses(
+        (status = 200, body = api::ApiKeys),
+        (status = 401, body = ApiError),
+        (status = 403, description = "Not an admin", body = ApiError),
+        (status = 500, body = ApiError),
+    ))]
+async fn list_api_keys(
+    api_keys: web::Data<Arc<ApiKeys>>,
+    store: web::Data<Arc<dyn DocumentStore>>,
+    auth: web::ReqData<Claims>,
+) -> HttpResponse {
+    if let Err(response) = require_admin(store.as_ref().as_ref(), &auth).await {
+        return response;
+    }
+    match api_keys.list(&auth.workspace).await {
+        Ok(keys) => HttpResponse::Ok().json(api::ApiKeys {
+            success: true,
+            api_keys: keys.iter().map(api::ApiKey::from).collect(),
+        }),
+        Err(e) => api_key_failure(e),
+    }
+}
+
+/// A new API key for a service account of the workspace; the key is only
+/// shown in this response. Admins only
+#[utoipa::path(post, path = "/api-keys", tag = "api-keys",
+    security(("bearer" = []), ("api_key" = [])),
+    request_body = ApiKeyRequest,
+    responses(
+        (status = 201, body = api::IssuedApiKey),
+        (status = 400, description = "Invalid body", body = ApiError),
+        (status = 401, body = ApiError),
+        (status = 403, description = "Not an admin", body = ApiError),
+        (status = 500, body = ApiError),
+    ))]
+async fn create_api_key(
+    api_keys: web::Data<Arc<ApiKeys>>,
+    store: web::Data<Arc<dyn DocumentStore>>,
+    body: ValidJson<ApiKeyRequest>,
+    auth: web::ReqData<Claims>,
+) -> HttpResponse {
+    if let Err(response) = require_admin(store.as_ref().as_ref(), &auth).await {
+        return response;
+    }
+    let body = body.into_inner();
+    let ttl = body.expires_in_days.map(chrono::Duration::days);
+    match api_keys.create(&auth.workspace, &body.account, body.roles, ttl, &auth.sub).await {
+        Ok(issued) => HttpResponse::Created().json(api::IssuedApiKey::from(&issued)),
+        Err(e) => api_key_failure(e),
+    }
+}
+
+/// Replace an API key by a new one for the same service account; the old one
+/// keeps working for `grace_minutes`. Admins only
+#[utoipa::path(post, path = "/api-keys/{id}/rotate", tag = "api-keys",
+    security(("bearer" = []), ("api_key" = [])),
+    request_body(content = Option<RotateApiKeyRequest>),
+    responses(
+        (status = 200, body = api::IssuedApiKey),
+        (status = 400, description = "Invalid body", body = ApiError),
+        (status = 401, body = ApiError),
+        (status = 403, description = "Not an admin", body = ApiError),
+        (status = 404, body = ApiError),
+        (status = 500, body = ApiError),
+    ))]
+async fn rotate_api_key(
+    api_keys: web::Data<Arc<ApiKeys>>,
+    store: web::Data<Arc<dyn DocumentStore>>,
+    path: web::Path<String>,
+    body: OptionalJson<RotateApiKeyRequest>,
+    auth: web::ReqData<Claims>,
+) -> HttpResponse {
+    if let Err(response) = require_admin(store.as_ref().as_ref(), &auth).await {
+        return response;
+    }
+    let body = body.into_inner().unwrap_or_default();
+    let grace = chrono::Duration::minutes(body.grace_minutes);
+    match api_keys.rotate(&auth.workspace, &path.into_inner(), grace, &auth.sub).await {
+        Ok(issued) => HttpResponse::Ok().json(api::IssuedApiKey::from(&issued)),
+        Err(e) => api_key_failure(e),
+    }
+}
+
+/// Revoke an API key at once; admins only
+#[utoipa::path(delete, path = "/api-keys/{id}", tag = "api-keys",
+    security(("bearer" = []), ("api_key" = [])),
+    responses(
+        (status = 200, body = api::Done),
+        (status = 401, body = ApiError),
+        (status = 403, description = "Not an admin", body = ApiError),
+        (status = 404, body = ApiError),
+        (status = 500, body = ApiError),
+    ))]
+async fn revoke_api_key(
+    api_keys: web::Data<Arc<ApiKeys>>,
+    store: web::Data<Arc<dyn DocumentStore>>,
+    path: web::Path<String>,
+    auth: web::ReqData<Claims>,
+) -> HttpResponse {
+    if let Err(response) = require_admin(store.as_ref().as_ref(), &auth).await {
+        return response;
+    }
+    match api_keys.revoke(&auth.workspace, &path.into_inner()).await {
+        Ok(true) => HttpResponse::Ok().json(api::Done { success: true }),
+        Ok(false) => api_key_failure(ApiKeyError::NotFound),
+        Err(e) => api_key_failure(e),
+    }
+}
+
+/// Entries of the audit log of the caller's workspace, newest first; admins only
+#[utoipa::path(get, path = "/audit", tag = "audit",
+    params(AuditQuery),
+    security(("bearer" = []), ("api_key" = [])),
+    responses(
+        (status = 200, body = api::AuditEntries),
+        (status = 400, description = "Invalid query", body = ApiError),
+        (status = 401, body = ApiError),
+        (status = 403, description = "Not an admin", body = ApiError),
+        (status = 500, body = ApiError),
+    ))]
+async fn get_audit(
+    audit: web::Data<Arc<AuditLog>>,
+    store: web::Data<Arc<dyn DocumentStore>>,
+    query: web::Query<AuditQuery>,
+    auth: web::ReqData<Claims>,
+) -> HttpResponse {
+    if let Err(response) = require_admin(store.as_ref().as_ref(), &auth).await {
+        return response;
+    }
+    match audit.query(&auth.workspace, &query).await {
+        Ok(entries) => HttpResponse::Ok().json(api::AuditEntries {
+            success: true,
+            entries: entries.iter().map(|entry| entry.to_json()).collect(),
+        }),
+        Err(e) if e.contains("'limit'") => HttpResponse::BadRequest().json(ApiError::new(e)),
+        Err(e) => {
+            error!("{}", e);
+            HttpResponse::InternalServerError().json(ApiError::new(e))
+        }
+    }
+}
+
+fn search_failure(e: SearchError) -> HttpResponse {
+    let response = ApiError::new(e.to_string());
+    match e {
+        SearchError::NotConfigured(_) => HttpResponse::NotFound().json(response),
+        SearchError::Invalid(_) => HttpResponse::BadRequest().json(response),
+        SearchError::Failed(_) => {
+            error!("{}", e);
+            HttpResponse::InternalServerError().json(response)
+        }
+    }
+}
+
+/// The `search` query of the database coprocessor for `?q=` words,
+/// `&facets=brand,status` to count, `&limit=`, `&offset=`, `&fuzzy=false`,
+/// `&mode=any`; any other parameter filters on a facet, as in `&brand=Renault`
+fn search_query(
+    workspace: &str,
+    collection: &str,
+    params: &std::collections::HashMap<String, String>,
+) -> Result<std::collections::HashMap<String, Data>, SearchError> {
+    let mut query = schema_query(workspace, Some(collection.to_string()));
+    let mut filters = std::collections::HashMap::new();
+    let number = |name: &str, value: &str| value.parse::<usize>()
+        .map(|n| Data::Number(n as f64))
+        .map_err(|_| SearchError::Invalid(format!("'{}' must be a positive number", name)));
+    for (name, value) in params {
+        let (key, value) = match name.as_str() {
+            "q" => ("query", Data::String(value.clone())),
+            "facets" => ("facets", Data::Array(value.split(',')
+                .filter(|f| !f.is_empty())
+                .map(|f| Data::String(f.to_string()))
+                .collect())),
+            "limit" | "offset" => (name.as_str(), number(name, value)?),
+            "fuzzy" => ("fuzzy", Data::Bool(value != "false")),
+            "mode" if value == "all" || value == "any" => ("mode", Data::String(value.clone())),
+            "mode" => return Err(SearchError::Invalid("'mode' must be all or any".to_string())),
+            _ => {
+                filters.insert(name.clone(), Data::String(value.clone()));
+                continue;
+            }
+        };
+        query.insert(key.to_string(), value);
+    }
+    query.insert("filters".to_string(), Data::Object(filters));
+    Ok(query)
+}
+
+#[utoipa::path(get, path = "/search/{collection}", tag = "search",
+    params(WorkspaceQuery),
+    security(("bearer" = []), ("api_key" = [])),
+    params(
+        ("q" = Option<String>, Query, description = "Words to look for"),
+        ("facets" = Option<String>, Query, description = "Comma-separated facets to count"),
+        ("limit" = Option<usize>, Query),
+        ("offset" = Option<usize>, Query),
+        ("fuzzy" = Option<bool>, Query, description = "`false` for exact words only"),
+        ("mode" = Option<String>, Query, description = "`all` words (default) or `any`"),
+    ),
+    responses(
+        (status = 200, description = "Hits, total and facet counts; any other parameter filters on a facet", body = api::SearchResults),
+        (status = 400, description = "Invalid parameter", body = ApiError),
+        (status = 401, body = ApiError),
+        (status = 403, description = "Not allowed to read every document or facet, or another workspace", body = ApiError),
+        (status = 404, description = "Search not configured", body = ApiError),
+        (status = 500, body = ApiError),
+    ))]
+async fn search_collection(
+    runtime: web::Data<Arc<SPURuntime>>,
+    path: web::Path<String>,
+    params: web::Query<std::collections::HashMap<String, String>>,
+    auth: web::ReqData<Claims>,
+) -> HttpResponse {
+    let workspace = auth.workspace.clone();
+    let collection = path.into_inner();
+    
+    let query = match search_query(&workspace, &collection, &params) {
+        Ok(query) => query,
+        Err(e) => return search_failure(e),
+    };
+    match call_database(&runtime, "search", query, &auth).await {
+        Ok(results) => HttpResponse::Ok().json(api::SearchResults::from_result(data_to_json(&results))),
+        Err(e) => search_call_failure(e),
+    }
 }
 
-async fn health_check() -> HttpResponse {
-    HttpResponse::Ok().json(json!({
-        "status": "healthy",
-        "service": "spu-core",
-        "version": "0.1.0",
-        "timestamp": chrono::Utc::now().to_rfc3339(),
-    }))
+/// `search_failure` for the search methods of the database coprocessor
+fn search_call_failure(e: ScriptError) -> HttpResponse {
+    if e.is_forbidden() {
+        forbidden(e.message)
+    } else if e.is_not_found() {
+        HttpResponse::NotFound().json(ApiError::new(e))
+    } else if e.is_invalid() {
+        HttpResponse::BadRequest().json(ApiError::new(e))
+    } else {
+        error!("{}", e);
+        HttpResponse::InternalServerError().json(ApiError::new(e))
+    }
 }
 
-async fn auth_register(
+/// Index a collection of the workspace for search; admins only
+#[utoipa::path(put, path = "/search/{collection}", tag = "search",
+    params(WorkspaceQuery),
+    security(("bearer" = []), ("api_key" = [])),
+    request_body(content = Object, description = "Fields to index, and facets"),
+    responses(
+        (status = 200, body = api::Configured),
+        (status = 400, description = "Invalid config", body = ApiError),
+        (status = 401, body = ApiError),
+        (status = 403, description = "Not an admin, or another workspace", body = ApiError),
+        (status = 500, body = ApiError),
+    ))]
+async fn configure_search(
     runtime: web::Data<Arc<SPURuntime>>,
-    req: web::Json<RegisterRequest>,
+    path: web::Path<String>,
+    body: web::Json<serde_json::Value>,
+    auth: web::ReqData<Claims>,
 ) -> HttpResponse {
-    info!("Register request for: {}", req.email);
+    let workspace = auth.workspace.clone();
+    let collection = path.into_inner();
     
-    // Create assembly script for registration (single-line JSON for simple parser)
-    let script = format!(r#"
-        # Registration Assembly Script
-        # 1. Instantiate coprocessors
-        INSTANTIATE auth auth1
-        INSTANTIATE email email1
-        INSTANTIATE database db1
-        
-        # 2. Create user data
-        SET user_data {{"email": "{}", "firstName": "{}", "lastName": "{}", "phone": "{}", "accountType": "{}", "workspace": "{}"}}
-        
-        # 3. Register user (auth will generate code)
-        CALL auth1 register $user_data auth_result
-        
-        # 4. Get the generated code from auth_result
-        GET auth_result.code code
-        
-        # 5. Send email with code  
-        SET email_data {{"to": "{}", "subject": "QWANYX - Verification Code", "body": "Your verification code is: $code\n\nThis code expires in 10 minutes."}}
-        CALL email1 send $email_data email_result
-        
-        # 6. Store user in database
-        SET db_input {{"collection": "users", "data": $user_data}}
-        CALL db1 store $db_input user_id
-        
-        # 7. Return success
-        SET result {{"success": true, "message": "Registration successful. Check your email for verification code.", "requires_code": true}}
-    "#, 
-        req.email, 
-        req.first_name, 
-        req.last_name, 
-        req.phone,
-        req.account_type,
-        req.workspace,
-        req.email
-    );
+    info!("Configuring search of {} in workspace {}", collection, workspace);
     
-    // Execute the assembly script
-    info!("Starting assembly execution for registration");
-    match runtime.execute(&script).await {
-        Ok(result) => {
-            // Convert result to AuthResponse
-            match result {
-                Data::Object(obj) => {
-                    let success = match obj.get("success") {
-                        Some(Data::Bool(b)) => *b,
-                        _ => false
-                    };
-                    
-                    let message = match obj.get("message") {
-                        Some(Data::String(s)) => Some(s.clone()),
-                        _ => None
-                    };
-                    
-                    let requires_code = match obj.get("requires_code") {
-                        Some(Data::Bool(b)) => Some(*b),
-                        _ => None
-                    };
-                    
-                    HttpResponse::Ok().json(AuthResponse {
-                        success,
-                        message,
-                        token: None,
-                        requires_code,
-                    })
-                }
-                _ => {
-                    error!("Unexpected result type from assembly execution");
-                    HttpResponse::InternalServerError().json(AuthResponse {
-                        success: false,
-                        message: Some("Internal error".to_string()),
-                        token: None,
-                        requires_code: None,
-                    })
-                }
-            }
-        }
-        Err(e) => {
-            error!("Assembly execution failed: {}", e);
-            HttpResponse::InternalServerError().json(AuthResponse {
-                success: false,
-                message: Some(format!("Registration failed: {}", e)),
-                token: None,
-                requires_code: None,
-            })
-        }
+    let mut query = schema_query(&workspace, Some(collection.clone()));
+    query.insert("config".to_string(), Data::from_json(body.into_inner()));
+    match call_database(&runtime, "configure_search", query, &auth).await {
+        Ok(_) => HttpResponse::Ok().json(api::Configured { success: true, collection }),
+        Err(e) => search_call_failure(e),
     }
 }
 
-async fn auth_request_code(
+#[utoipa::path(get, path = "/search/{collection}/config", tag = "search",
+    params(WorkspaceQuery),
+    security(("bearer" = []), ("api_key" = [])),
+    responses(
+        (status = 200, description = "The config", body = api::SearchConfig),
+        (status = 401, body = ApiError),
+        (status = 403, description = "Not allowed to read the collection, or another workspace", body = ApiError),
+        (status = 404, description = "Search not configured", body = ApiError),
+        (status = 500, body = ApiError),
+    ))]
+async fn get_search_config(
     runtime: web::Data<Arc<SPURuntime>>,
-    req: web::Json<LoginRequest>,
+    path: web::Path<String>,
+    auth: web::ReqData<Claims>,
 ) -> HttpResponse {
-    info!("Login code request for: {}", req.email);
-    
-    // Assembly script to request login code (single-line JSON)
-    let script = format!(r#"
-        # Login Code Request Assembly Script
-        INSTANTIATE auth auth1
-        INSTANTIATE email email1
-        
-        # Generate new code
-        SET email_input {{"email": "{}"}}
-        CALL auth1 generate_code $email_input code_result
-        GET code_result.code code
-        
-        # Send email
-        SET email_data {{"to": "{}", "subject": "QWANYX - Login Code", "body": "Your login code is: $code\n\nThis code expires in 10 minutes."}}
-        CALL email1 send $email_data email_result
-        
-        # Return success
-        SET result {{"success": true, "message": "Code sent to your email.", "requires_code": true}}
-    "#, req.email, req.email);
+    let workspace = auth.workspace.clone();
+    let collection = path.into_inner();
     
-    // Execute the assembly script
-    match runtime.execute(&script).await {
+    match call_database(&runtime, "get_search", schema_query(&workspace, Some(collection.clone())), &auth).await {
         Ok(result) => {
-            match result {
-                Data::Object(obj) => {
-                    let success = match obj.get("success") {
-                        Some(Data::Bool(b)) => *b,
-                        _ => false
-                    };
-                    
-                    let message = match obj.get("message") {
-                        Some(Data::String(s)) => Some(s.clone()),
-                        _ => None
-                    };
-                    
-                    HttpResponse::Ok().json(AuthResponse {
-                        success,
-                        message,
-                        token: None,
-                        requires_code: Some(true),
-                    })
-                }
-                _ => {
-                    HttpResponse::InternalServerError().json(AuthResponse {
-                        success: false,
-                        message: Some("Internal error".to_string()),
-                        token: None,
-                        requires_code: None,
-                    })
-                }
+            let mut result = data_to_json(&result);
+            if result["found"] == json!(true) {
+                HttpResponse::Ok().json(api::SearchConfig {
+                    success: true,
+                    collection,
+                    config: result["config"].take(),
+                })
+            } else {
+                search_failure(SearchError::NotConfigured(collection))
             }
         }
-        Err(e) => {
-            error!("Assembly execution failed: {}", e);
-            HttpResponse::InternalServerError().json(AuthResponse {
-                success: false,
-                message: Some(format!("Failed to send code: {}", e)),
-                token: None,
-                requires_code: None,
-            })
-        }
+        Err(e) => search_call_failure(e),
     }
 }
 
-async fn auth_verify_code(
+/// Stop indexing a collection of the workspace; admins only
+#[utoipa::path(delete, path = "/search/{collection}", tag = "search",
+    params(WorkspaceQuery),
+    security(("bearer" = []), ("api_key" = [])),
+    responses(
+        (status = 200, body = api::Done),
+        (status = 401, body = ApiError),
+        (status = 403, description = "Not an admin, or another workspace", body = ApiError),
+        (status = 404, description = "Search not configured", body = ApiError),
+        (status = 500, body = ApiError),
+    ))]
+async fn drop_search(
     runtime: web::Data<Arc<SPURuntime>>,
-    req: web::Json<VerifyCodeRequest>,
+    path: web::Path<String>,
+    auth: web::ReqData<Claims>,
 ) -> HttpResponse {
-    info!("Verify code for: {}", req.email);
+    let workspace = auth.workspace.clone();
+    let collection = path.into_inner();
     
-    // Assembly script to verify code (single-line JSON)
-    let script = format!(r#"
-        # Code Verification Assembly Script
-        INSTANTIATE auth auth1
-        
-        # Verify the code
-        SET verify_data {{"email": "{}", "code": "{}"}}
-        CALL auth1 verify_code $verify_data verify_result
-        
-        # Get validation status
-        GET verify_result.valid is_valid
-        GET verify_result.token token
-        GET verify_result.error error_msg
-        
-        # Build response based on validation  
-        SET result $verify_result
-    "#, req.email, req.code);
+    info!("Dropping search of {} in workspace {}", collection, workspace);
     
-    // Execute the assembly script
-    match runtime.execute(&script).await {
-        Ok(result) => {
-            info!("Verify code result type: {:?}", std::mem::discriminant(&result));
-            match result {
-                Data::Object(obj) => {
-                    let valid = match obj.get("valid") {
-                        Some(Data::Bool(b)) => *b,
-                        _ => false
-                    };
-                    
-                    if valid {
-                        let token = match obj.get("token") {
-                            Some(Data::String(s)) => Some(s.clone()),
-                            _ => None
-                        };
-                        
-                        HttpResponse::Ok().json(json!({
-                            "success": true,
-                            "message": "Code verified successfully.",
-                            "access_token": token, // Frontend expects access_token
-                            "token": token,
-                            "requires_code": false,
-                            "user": {
-                                "email": req.email,
-                                "workspace": "autodin"
-                            }
-                        }))
-                    } else {
-                        let error = match obj.get("error") {
-                            Some(Data::String(s)) => s.clone(),
-                            _ => "Invalid code".to_string()
-                        };
-                        
-                        HttpResponse::BadRequest().json(json!({
-                            "success": false,
-                            "error": error,
-                            "message": error
-                        }))
-                    }
-                }
-                _ => {
-                    HttpResponse::InternalServerError().json(json!({
-                        "success": false,
-                        "error": "Internal error"
-                    }))
-                }
-            }
-        }
-        Err(e) => {
-            error!("Assembly execution failed: {}", e);
-            HttpResponse::InternalServerError().json(json!({
-                "success": false,
-                "error": format!("Verification failed: {}", e)
-            }))
+    match call_database(&runtime, "drop_search", schema_query(&workspace, Some(collection.clone())), &auth).await {
+        Ok(result) if data_to_json(&result)["deleted"] == json!(true) => {
+            HttpResponse::Ok().json(api::Done { success: true })
         }
+        Ok(_) => search_failure(SearchError::NotConfigured(collection)),
+        Err(e) => search_call_failure(e),
     }
 }
 
-async fn execute_assembly(
+/// Rebuild the index of a collection of the workspace; admins only
+#[utoipa::path(post, path = "/search/{collection}/reindex", tag = "search",
+    params(WorkspaceQuery),
+    security(("bearer" = []), ("api_key" = [])),
+    responses(
+        (status = 200, description = "How many documents were indexed", body = api::Reindexed),
+        (status = 401, body = ApiError),
+        (status = 403, description = "Not an admin, or another workspace", body = ApiError),
+        (status = 404, description = "Search not configured", body = ApiError),
+        (status = 500, body = ApiError),
+    ))]
+async fn reindex_search(
     runtime: web::Data<Arc<SPURuntime>>,
-    req: web::Json<ExecuteRequest>,
+    path: web::Path<String>,
+    auth: web::ReqData<Claims>,
 ) -> HttpResponse {
-    info!("Executing assembly script");
-    
-    // TODO: Parse and execute the assembly script
-    // For now, return success
+    let workspace = auth.workspace.clone();
+    let collection = path.into_inner();
     
-    HttpResponse::Ok().json(json!({
-        "success": true,
-        "result": "Assembly script executed",
-    }))
+    match call_database(&runtime, "reindex_search", schema_query(&workspace, Some(collection)), &auth).await {
+        Ok(result) => HttpResponse::Ok().json(api::Reindexed::from_result(&data_to_json(&result))),
+        Err(e) => search_call_failure(e),
+    }
 }
 
+#[utoipa::path(get, path = "/users", tag = "users",
+    params(WorkspaceQuery),
+    security(("bearer" = []), ("api_key" = [])),
+    responses(
+        (status = 200, description = "The users of the workspace", body = api::Users),
+        (status = 401, body = ApiError),
+        (status = 403, body = ApiError),
+        (status = 500, body = ApiError),
+    ))]
 async fn get_users(
     runtime: web::Data<Arc<SPURuntime>>,
-    req: actix_web::HttpRequest,
+    auth: web::ReqData<Claims>,
 ) -> HttpResponse {
-    info!("Fetching users");
-    
-    // Get workspace from header or default to "autodin"
-    let workspace = req.headers()
-        .get("X-Workspace")
-        .and_then(|v| v.to_str().ok())
-        .unwrap_or("autodin");
-    
-    info!("Using workspace: {}", workspace);
-    
-    // Create assembly script to fetch users from database (single-line JSON)
-    let script = format!(r#"
-        # Fetch Users Assembly Script
-        INSTANTIATE database db1
-        
-        # Retrieve users from the workspace collection
-        SET query_params {{"collection": "users", "workspace": "{}", "filter": {{}}}}
-        CALL db1 retrieve $query_params users_result
-        
-        # Return the users
-        SET result $users_result
-    "#, workspace);
+    info!("Fetching users of {} for {}", auth.workspace, auth.sub);
     
-    // Execute the assembly script
-    match runtime.execute(&script).await {
-        Ok(result) => {
-            match result {
-                Data::Object(obj) => {
-                    // Extract users array from the result
-                    if let Some(Data::Array(users)) = obj.get("data") {
-                        // Convert Data::Array to JSON array
-                        let json_users: Vec<serde_json::Value> = users.iter().map(|user| {
-                            // Convert Data to serde_json::Value
-                            data_to_json(user)
-                        }).collect();
-                        
-                        info!("Returning {} users from database", json_users.len());
-                        HttpResponse::Ok().json(json_users)
-                    } else if let Some(Data::String(error)) = obj.get("error") {
-                        // Database might not be connected
-                        info!("Database query failed: {}, returning mock data", error);
-                        HttpResponse::Ok().json(json!([
-                            {
-                                "_id": "1",
-                                "email": "phil@pixanima.com",
-                                "firstName": "Phil",
-                                "lastName": "User",
-                                "role": "superuser",
-                                "type": "user",
-                                "workspace": workspace
-                            },
-                {
-                    "_id": "2",
-                    "email": "admin@autodin.be",
-                    "firstName": "Admin",
-                    "lastName": "User",
-                    "role": "admin",
-                    "type": "user",
-                    "workspace": workspace
-                },
-                {
-                    "_id": "3",
-                    "email": "test@test.com",
-                    "firstName": "Test",
-                    "lastName": "User",
-                    "role": "particulier",
-                    "type": "user",
-                    "workspace": workspace
-                },
-                {
-                    "_id": "4",
-                    "email": "digital1@autodin.be",
-                    "firstName": "Digital",
-                    "lastName": "Assistant 1",
-                    "role": "admin",
-                    "type": "DH",
-                    "workspace": workspace
-                },
-                {
-                    "_id": "5",
-                    "email": "digital2@autodin.be",
-                    "firstName": "Digital",
-                    "lastName": "Assistant 2",
-                    "role": "admin",
-                    "type": "DH",
-                    "workspace": workspace
-                }
-                        ]))
-                    } else {
-                        // Return empty array if no users found
-                        info!("No users found in database");
-                        HttpResponse::Ok().json(json!([]))
-                    }
-                }
-                _ => {
-                    info!("Unexpected result type, returning mock data");
-                    // Return mock data for development
-                    HttpResponse::Ok().json(json!([
-                        {
-                            "_id": "1",
-                            "email": "phil@pixanima.com",
-                            "firstName": "Phil",
-                            "lastName": "User",
-                            "role": "superuser",
-                            "type": "user",
-                            "workspace": workspace
-                        },
-                        {
-                            "_id": "2",
-                            "email": "admin@autodin.be",
-                            "firstName": "Admin",
-                            "lastName": "User",
-                            "role": "admin",
-                            "type": "user",
-                            "workspace": workspace
-                        },
-                        {
-                            "_id": "3",
-                            "email": "test@test.com",
-                            "firstName": "Test",
-                            "lastName": "User",
-                            "role": "particulier",
-                            "type": "user",
-                            "workspace": workspace
-                        }
-                    ]))
-                }
+    let mut query = schema_query(&auth.workspace, Some("users".to_string()));
+    query.insert("filter".to_string(), Data::Object(std::collections::HashMap::new()));
+    match call_database(&runtime, "retrieve", query, &auth).await {
+        Ok(result) => match data_to_json(&result)["data"].take() {
+            serde_json::Value::Array(users) => HttpResponse::Ok().json(api::Users { success: true, users }),
+            other => {
+                error!("Unexpected users result: {}", other);
+                HttpResponse::InternalServerError().json(ApiError::new("Failed to fetch users"))
             }
-        }
+        },
+        Err(e) if e.is_forbidden() => forbidden(e.message),
         Err(e) => {
             error!("Failed to fetch users: {}", e);
-            // Return mock data for development
-            HttpResponse::Ok().json(json!([
-                {
-                    "_id": "1",
-                    "email": "admin@autodin.be",
-                    "firstName": "Admin",
-                    "lastName": "User",
-                    "role": "superuser",
-                    "type": "user",
-                    "workspace": workspace
-                }
-            ]))
+            HttpResponse::InternalServerError().json(ApiError::new(format!("Failed to fetch users: {}", e)))
         }
     }
 }
 
+#[utoipa::path(put, path = "/users/{id}", tag = "users",
+    params(WorkspaceQuery),
+    security(("bearer" = []), ("api_key" = [])),
+    request_body(content = Object, description = "Fields to set"),
+    responses(
+        (status = 200, body = api::Updated),
+        (status = 401, body = ApiError),
+        (status = 403, body = ApiError),
+        (status = 500, body = ApiError),
+    ))]
 async fn update_user(
     runtime: web::Data<Arc<SPURuntime>>,
     path: web::Path<String>,
-    req: actix_web::HttpRequest,
     user_data: web::Json<serde_json::Value>,
+    auth: web::ReqData<Claims>,
 ) -> HttpResponse {
     let user_id = path.into_inner();
     
-    // Get workspace from headers or default
-    let workspace = req.headers()
-        .get("X-Workspace")
-        .and_then(|v| v.to_str().ok())
-        .unwrap_or("autodin")
-        .to_string();
+    let workspace = auth.workspace.clone();
     
-    info!("Updating user: {} in workspace: {}", user_id, workspace);
+    info!("Updating user: {} in workspace: {} for {}", user_id, workspace, auth.sub);
     
-    // Create the complete params object in one go (this is what fixed the requests UPDATE)
-    let script = format!(r#"
+    let script = r#"
         # Update User
         INSTANTIATE database db
         
-        # Create the complete params object in one go
-        SET params {{
-            "collection": "users",
-            "workspace": "{}",
-            "filter": {{"_id": "{}"}},
-            "update": {}
-        }}
-        
         CALL db update $params result
         TRACE "User updated successfully"
         
-        SET response {{
+        SET response {
             "success": true,
-            "id": "{}"
-        }}
+            "id": "$id"
+        }
         
         DESTROY db
         RETURN $response
-    "#,
-        workspace,
-        user_id,
-        user_data.to_string(),
-        user_id
-    );
-    
-    match runtime.execute(&script).await {
+    "#;
+    
+    // The id and fields go in as data, never into the script text
+    let mut params = schema_query(&workspace, Some("users".to_string()));
+    params.insert("filter".to_string(), id_filter(&user_id));
+    params.insert("update".to_string(), Data::from_json(user_data.into_inner()));
+    let mut inputs = auth_inputs(&auth);
+    inputs.insert("params".to_string(), Data::Object(params));
+    inputs.insert("id".to_string(), Data::String(user_id.clone()));
+    
+    match runtime.execute_as(script, inputs, Caller::from(&*auth)).await {
+        Err(e) if e.is_forbidden() => forbidden(e.message),
+        Ok(result) if data_to_json(&result)["success"] == json!(true) => {
+            HttpResponse::Ok().json(api::Updated { success: true, id: user_id })
+        }
         Ok(result) => {
-            match &result {
-                Data::Object(obj) => {
-                    if let Some(Data::Bool(true)) = obj.get("success") {
-                        HttpResponse::Ok().json(data_to_json(&result))
-                    } else {
-                        HttpResponse::InternalServerError().json(data_to_json(&result))
-                    }
-                }
-                _ => HttpResponse::Ok().json(data_to_json(&result))
-            }
+            error!("Unexpected result updating user {}: {}", user_id, data_to_json(&result));
+            HttpResponse::InternalServerError().json(ApiError::new("Failed to update user"))
         }
         Err(e) => {
             error!("Failed to update user: {}", e);
-            HttpResponse::InternalServerError().json(json!({
-                "success": false,
-                "error": format!("Failed to update user: {}", e)
-            }))
+            HttpResponse::InternalServerError().json(ApiError::new(format!("Failed to update user: {}", e)))
         }
     }
 }
 
 // Helper function to convert Data to serde_json::Value
 fn data_to_json(data: &Data) -> serde_json::Value {
-    match data {
-        Data::Null => serde_json::Value::Null,
-        Data::Bool(b) => serde_json::Value::Bool(*b),
-        Data::Number(n) => serde_json::Value::Number(serde_json::Number::from_f64(*n).unwrap_or(serde_json::Number::from(0))),
-        Data::String(s) => serde_json::Value::String(s.clone()),
-        Data::Array(arr) => {
-            serde_json::Value::Array(arr.iter().map(data_to_json).collect())
-        }
-        Data::Object(obj) => {
-            let mut map = serde_json::Map::new();
-            for (key, value) in obj {
-                map.insert(key.clone(), data_to_json(value));
-            }
-            serde_json::Value::Object(map)
-        }
-        Data::ObjectRef(id) => serde_json::Value::String(id.0.clone())
-    }
+    data.to_plain_json()
 }
 
 // Generic Data Management Endpoints using SPU
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, ToSchema)]
 struct DataRequest {
-    workspace: String,
+    /// Only the token's workspace may be named
+    #[serde(default)]
+    workspace: Option<String>,
+    /// The document's fields
+    #[schema(value_type = Object)]
     data: serde_json::Value,
 }
 
+#[utoipa::path(post, path = "/data/{collection}", tag = "data",
+    security(("bearer" = []), ("api_key" = [])),
+    request_body = DataRequest,
+    responses(
+        (status = 200, description = "The stored document and its id", body = api::Stored),
+        (status = 400, description = "Invalid body", body = ApiError),
+        (status = 401, body = ApiError),
+        (status = 403, body = ApiError),
+        (status = 500, body = ApiError),
+    ))]
 async fn store_data(
     runtime: web::Data<Arc<SPURuntime>>,
     path: web::Path<String>,
     request: web::Json<DataRequest>,
+    auth: web::ReqData<Claims>,
 ) -> HttpResponse {
     let collection = path.into_inner();
-    info!("Storing data to collection: {} in workspace: {}", collection, request.workspace);
+    let workspace = match token_workspace(&auth, request.workspace.as_deref()) {
+        Ok(workspace) => workspace,
+        Err(response) => return response,
+    };
+    info!("Storing data to collection: {} in workspace: {} for {}", collection, workspace, auth.sub);
     
     // Create SPU script to store data
-    let script = format!(r#"
+    let script = r#"
         # Store Data Script
         INSTANTIATE database db
         
         # Set the data with metadata
-        SET data {}
-        SET data.createdAt "{}"
-        SET data.workspace "{}"
-        
-        # Store in database
-        # Since the simple parser doesn't support nested SET, we'll use the Data::Object approach
-        # Create a wrapper that contains everything
-        SET store_request {{
-            "collection": "{}",
-            "workspace": "{}",
-            "data": {}
-        }}
+        SET data.createdAt $created_at
+        SET data.workspace $workspace
         
         CALL db store $store_request result
         GET result.id doc_id
@@ -696,163 +3613,297 @@ async fn store_data(
         
         # Return the stored document with ID
         SET data._id $doc_id
-        SET response {{
+        SET response {
             "success": true,
             "id": $doc_id,
             "data": $data
-        }}
+        }
         
         DESTROY db
         RETURN $response
-    "#,
-        request.data.to_string(),
-        chrono::Utc::now().to_rfc3339(),
-        request.workspace,
-        collection,
-        request.workspace,
-        request.data.to_string()  // For the data field in store_request
-    );
+    "#;
+    
+    // The document goes in as data, never into the script text
+    let data = Data::from_json(request.data.clone());
+    let mut store_request = schema_query(&workspace, Some(collection));
+    store_request.insert("data".to_string(), data.clone());
+    let mut inputs = auth_inputs(&auth);
+    inputs.insert("data".to_string(), data);
+    inputs.insert("created_at".to_string(), Data::String(chrono::Utc::now().to_rfc3339()));
+    inputs.insert("workspace".to_string(), Data::String(workspace));
+    inputs.insert("store_request".to_string(), Data::Object(store_request));
     
     // Execute the SPU script
-    match runtime.execute(&script).await {
-        Ok(result) => {
-            match &result {
-                Data::Object(obj) => {
-                    if let Some(Data::Bool(true)) = obj.get("success") {
-                        HttpResponse::Ok().json(data_to_json(&result))
-                    } else {
-                        HttpResponse::InternalServerError().json(data_to_json(&result))
-                    }
-                }
-                _ => HttpResponse::Ok().json(data_to_json(&result))
+    match runtime.execute_as(script, inputs, Caller::from(&*auth)).await {
+        Err(e) if e.is_forbidden() => forbidden(e.message),
+        Ok(result) => match data_to_json(&result) {
+            mut stored if stored["success"] == json!(true) => HttpResponse::Ok().json(api::Stored {
+                success: true,
+                id: stored["id"].as_str().unwrap_or_default().to_string(),
+                data: stored["data"].take(),
+            }),
+            other => {
+                error!("Unexpected result storing data: {}", other);
+                HttpResponse::InternalServerError().json(ApiError::new("Failed to store data"))
             }
+        },
+        Err(e) if e.is_invalid() => {
+            HttpResponse::BadRequest().json(ApiError::new(e))
         }
         Err(e) => {
             error!("Failed to store data: {}", e);
-            HttpResponse::InternalServerError().json(json!({
-                "success": false,
-                "error": format!("Failed to store data: {}", e)
-            }))
+            HttpResponse::InternalServerError().json(ApiError::new(format!("Failed to store data: {}", e)))
+        }
+    }
+}
+
+/// Query parameters of `GET /data/{collection}` with a special meaning; any other
+/// parameter is an equality filter on that field
+const DATA_QUERY_PARAMS: [&str; 7] = ["workspace", "filter", "fields", "sort", "limit", "skip", "cursor"];
+
+/// Build the arguments of `database.retrieve` from query parameters
+///
+/// `filter` is a JSON filter, `fields` a comma-separated list (`-field` excludes),
+/// `sort` a comma-separated list (`-field` sorts descending).
+fn data_query(
+    collection: &str,
+    workspace: &str,
+    params: &std::collections::HashMap<String, String>,
+) -> Result<std::collections::HashMap<String, Data>, String> {
+    let mut filter = match params.get("filter") {
+        Some(json) => match serde_json::from_str::<serde_json::Value>(json) {
+            Ok(value @ serde_json::Value::Object(_)) => match Data::from_json(value) {
+                Data::Object(obj) => obj,
+                _ => std::collections::HashMap::new(),
+            },
+            _ => return Err("'filter' must be a JSON object".to_string()),
+        },
+        None => std::collections::HashMap::new(),
+    };
+    for (key, value) in params {
+        if !DATA_QUERY_PARAMS.contains(&key.as_str()) {
+            filter.insert(key.clone(), Data::String(value.clone()));
         }
     }
+    
+    let mut query = std::collections::HashMap::new();
+    query.insert("collection".to_string(), Data::String(collection.to_string()));
+    query.insert("workspace".to_string(), Data::String(workspace.to_string()));
+    query.insert("filter".to_string(), Data::Object(filter));
+    
+    if let Some(fields) = params.get("fields") {
+        let projection = fields.split(',')
+            .map(str::trim)
+            .filter(|f| !f.is_empty())
+            .map(|f| match f.strip_prefix('-') {
+                Some(field) => (field.to_string(), Data::Number(0.0)),
+                None => (f.to_string(), Data::Number(1.0)),
+            })
+            .collect();
+        query.insert("projection".to_string(), Data::Object(projection));
+    }
+    if let Some(sort) = params.get("sort") {
+        query.insert("sort".to_string(), Data::String(sort.clone()));
+    }
+    for name in ["limit", "skip"] {
+        if let Some(value) = params.get(name) {
+            let n: u64 = value.parse()
+                .map_err(|_| format!("'{}' must be a non-negative integer", name))?;
+            query.insert(name.to_string(), Data::Number(n as f64));
+        }
+    }
+    if let Some(cursor) = params.get("cursor") {
+        query.insert("cursor".to_string(), Data::String(cursor.clone()));
+    }
+    
+    Ok(query)
 }
 
+#[utoipa::path(get, path = "/data/{collection}", tag = "data",
+    security(("bearer" = []), ("api_key" = [])),
+    params(
+        WorkspaceQuery,
+        ("filter" = Option<String>, Query, description = "JSON filter"),
+        ("fields" = Option<String>, Query, description = "Comma-separated fields; `-field` leaves one out"),
+        ("sort" = Option<String>, Query, description = "Comma-separated fields; `-field` sorts descending"),
+        ("limit" = Option<u64>, Query),
+        ("skip" = Option<u64>, Query),
+        ("cursor" = Option<String>, Query, description = "`X-Next-Cursor` of the previous page"),
+    ),
+    responses(
+        (status = 200, description = "The documents; any other parameter filters on that field", body = Vec<api::Document>, headers(
+            ("X-Total-Count" = u64, description = "Documents matching, over all pages"),
+            ("X-Next-Cursor" = String, description = "Of the next page, if any"),
+        )),
+        (status = 400, description = "Invalid parameter", body = ApiError),
+        (status = 401, body = ApiError),
+        (status = 403, body = ApiError),
+        (status = 500, body = ApiError),
+    ))]
 async fn retrieve_data(
     runtime: web::Data<Arc<SPURuntime>>,
     path: web::Path<String>,
     req: actix_web::HttpRequest,
+    auth: web::ReqData<Claims>,
 ) -> HttpResponse {
     let collection = path.into_inner();
     
-    // Get workspace from query params or header
     let query_params = web::Query::<std::collections::HashMap<String, String>>::from_query(
         req.query_string()
     ).unwrap_or_else(|_| web::Query(std::collections::HashMap::new()));
     
-    let workspace = query_params.get("workspace")
-        .map(|s| s.as_str())
-        .or_else(|| req.headers()
-            .get("X-Workspace")
-            .and_then(|v| v.to_str().ok()))
-        .unwrap_or("autodin")
-        .to_string();
-    
-    info!("Retrieving data from collection: {} in workspace: {}", collection, workspace);
+    // The token's workspace; `?workspace=` or `X-Workspace` may only repeat it
+    let workspace = auth.workspace.clone();
     
-    // Build filter from query params (excluding workspace)
-    let filter_params: std::collections::HashMap<String, String> = query_params
-        .iter()
-        .filter(|(k, _)| *k != "workspace")
-        .map(|(k, v)| (k.clone(), v.clone()))
-        .collect();
+    info!("Retrieving data from collection: {} in workspace: {} for {}", collection, workspace, auth.sub);
     
-    let filter = if filter_params.is_empty() {
-        "{}".to_string()
-    } else {
-        serde_json::to_string(&filter_params).unwrap_or_else(|_| "{}".to_string())
+    let query = match data_query(&collection, &workspace, &query_params) {
+        Ok(query) => query,
+        Err(e) => {
+            return HttpResponse::BadRequest().json(ApiError::new(e))
+        }
     };
     
-    let script = format!(r#"
+    let script = r#"
         # Retrieve Data Script
         INSTANTIATE database db
         
-        SET query {{
-            "collection": "{}",
-            "workspace": "{}",
-            "filter": {}
-        }}
-        
         CALL db retrieve $query result
         
-        # The result contains data, found, and count fields
-        # We just need to return the whole result which contains the data array
+        # The result contains data, count, total and next_cursor fields
         DESTROY db
         RETURN $result
-    "#, collection, workspace, filter);
+    "#;
+    
+    let mut inputs = auth_inputs(&auth);
+    inputs.insert("query".to_string(), Data::Object(query));
     
-    match runtime.execute(&script).await {
+    match runtime.execute_as(script, inputs, Caller::from(&*auth)).await {
+        Err(e) if e.is_forbidden() => forbidden(e.message),
         Ok(Data::Object(result)) => {
-            // Extract the data array from the result
-            if let Some(Data::Array(documents)) = result.get("data") {
-                let json_docs: Vec<serde_json::Value> = documents.iter()
-                    .map(|d| data_to_json(d))
-                    .collect();
-                HttpResponse::Ok().json(json_docs)
-            } else {
-                HttpResponse::Ok().json(json!([]))
+            // The body stays a plain array; paging goes in headers
+            let documents: Vec<api::Document> = match result.get("data") {
+                Some(Data::Array(documents)) => documents.iter().map(|d| api::Document(data_to_json(d))).collect(),
+                _ => Vec::new(),
+            };
+            let mut response = HttpResponse::Ok();
+            if let Some(Data::Number(total)) = result.get("total") {
+                response.insert_header(("X-Total-Count", (*total as u64).to_string()));
+            }
+            if let Some(Data::String(cursor)) = result.get("next_cursor") {
+                response.insert_header(("X-Next-Cursor", cursor.clone()));
             }
+            response.json(documents)
         }
-        Ok(Data::Array(documents)) => {
-            // Direct array result (shouldn't happen with current script)
-            let json_docs: Vec<serde_json::Value> = documents.iter()
-                .map(|d| data_to_json(d))
-                .collect();
-            HttpResponse::Ok().json(json_docs)
+        Ok(other) => {
+            error!("Unexpected result retrieving data: {}", data_to_json(&other));
+            HttpResponse::InternalServerError().json(ApiError::new("Failed to retrieve data"))
         }
-        Ok(_) => {
-            HttpResponse::Ok().json(json!([]))
+        Err(e) if e.is_invalid() => {
+            HttpResponse::BadRequest().json(ApiError::new(e))
         }
         Err(e) => {
             error!("Failed to retrieve data: {}", e);
-            HttpResponse::InternalServerError().json(json!({
-                "success": false,
-                "error": format!("Failed to retrieve data: {}", e)
-            }))
+            HttpResponse::InternalServerError().json(ApiError::new(format!("Failed to retrieve data: {}", e)))
         }
     }
 }
 
+/// Follow a collection of the token's workspace as Server-Sent Events, seeing
+/// what `GET /data/{collection}` would. Browsers' EventSource cannot set
+/// headers, so the token may also come as the `token` query parameter; `filter`
+/// is a JSON filter as for `GET /data/{collection}`.
+#[utoipa::path(get, path = "/subscribe/{collection}", tag = "data",
+    params(
+        WorkspaceQuery,
+        ("token" = Option<String>, Query, description = "For clients that can't send `Authorization`"),
+        ("filter" = Option<String>, Query, description = "JSON filter"),
+    ),
+    security(("bearer" = [])),
+    responses(
+        (status = 200, description = "Changes as Server-Sent Events", content_type = "text/event-stream", body = String),
+        (status = 400, description = "Invalid filter", body = ApiError),
+        (status = 401, body = ApiError),
+        (status = 403, body = ApiError),
+        (status = 503, body = ApiError),
+    ))]
+async fn subscribe(
+    subscriptions: web::Data<Arc<Subscriptions>>,
+    path: web::Path<String>,
+    query: web::Query<std::collections::HashMap<String, String>>,
+    req: actix_web::HttpRequest,
+) -> HttpResponse {
+    let collection = path.into_inner();
+    let token = query.get("token")
+        .map(|s| s.as_str())
+        .or_else(|| tokens::bearer(req.headers().get("Authorization").and_then(|v| v.to_str().ok())).ok());
+    
+    let filter = match query.get("filter").map(|f| subscriptions::parse_filter(f)).transpose() {
+        Ok(filter) => filter.unwrap_or_default(),
+        Err(e) => {
+            return HttpResponse::BadRequest().json(ApiError::new(e))
+        }
+    };
+    
+    let subscribed = match subscriptions.authenticate(token).await {
+        Ok(claims) => match other_workspace(&req, &claims) {
+            Some(named) => Err(SubscriptionError::Forbidden(format!("Signed in to {}, not {}", claims.workspace, named))),
+            None => {
+                let subscription = Subscription::new(&claims.workspace, &collection, filter);
+                subscriptions.subscribe(subscription, &claims).await
+            }
+        },
+        Err(e) => Err(e),
+    };
+    match subscribed {
+        Ok(notifications) => {
+            let events = notifications.map(|notification| {
+                Ok::<_, actix_web::Error>(web::Bytes::from(notification.to_sse()))
+            });
+            HttpResponse::Ok()
+                .content_type("text/event-stream")
+                .insert_header(("Cache-Control", "no-cache"))
+                // Keep reverse proxies from buffering the stream
+                .insert_header(("X-Accel-Buffering", "no"))
+                .streaming(events)
+        }
+        Err(e) => {
+            let response = ApiError::new(e.to_string());
+            match e {
+                SubscriptionError::Invalid(_) => HttpResponse::BadRequest().json(response),
+                SubscriptionError::Unauthorized(_) => HttpResponse::Unauthorized().json(response),
+                SubscriptionError::Forbidden(_) => HttpResponse::Forbidden().json(response),
+                SubscriptionError::Unavailable(_) => HttpResponse::ServiceUnavailable().json(response),
+            }
+        }
+    }
+}
+
+#[utoipa::path(get, path = "/data/{collection}/{id}", tag = "data",
+    params(WorkspaceQuery),
+    security(("bearer" = []), ("api_key" = [])),
+    responses(
+        (status = 200, description = "The document", body = api::Document),
+        (status = 401, body = ApiError),
+        (status = 403, body = ApiError),
+        (status = 404, body = ApiError),
+        (status = 500, body = ApiError),
+    ))]
 async fn get_data_by_id(
     runtime: web::Data<Arc<SPURuntime>>,
     path: web::Path<(String, String)>,
-    req: actix_web::HttpRequest,
+    auth: web::ReqData<Claims>,
 ) -> HttpResponse {
     let (collection, id) = path.into_inner();
     
-    let query_params = web::Query::<std::collections::HashMap<String, String>>::from_query(
-        req.query_string()
-    ).unwrap_or_else(|_| web::Query(std::collections::HashMap::new()));
-    
-    let workspace = query_params.get("workspace")
-        .map(|s| s.as_str())
-        .or_else(|| req.headers()
-            .get("X-Workspace")
-            .and_then(|v| v.to_str().ok()))
-        .unwrap_or("autodin")
-        .to_string();
+    let workspace = auth.workspace.clone();
     
-    info!("Getting document {} from collection: {} in workspace: {}", id, collection, workspace);
+    info!("Getting document {} from collection: {} in workspace: {} for {}", id, collection, workspace, auth.sub);
     
-    let script = format!(r#"
+    let script = r#"
         # Get Document by ID
         INSTANTIATE database db
         
-        SET query {{
-            "collection": "{}",
-            "workspace": "{}",
-            "filter": {{"_id": "{}"}}
-        }}
-        
         CALL db retrieve $query result
         GET result.data documents
         LEN $documents count
@@ -865,36 +3916,51 @@ async fn get_data_by_id(
             DESTROY db
             THROW NotFoundError "Document not found"
         ENDIF
-    "#, collection, workspace, id);
+    "#;
     
-    match runtime.execute(&script).await {
-        Ok(result) => {
-            HttpResponse::Ok().json(data_to_json(&result))
-        }
+    // The id goes in as data, never into the script text
+    let mut query = schema_query(&workspace, Some(collection));
+    query.insert("filter".to_string(), id_filter(&id));
+    query.insert("limit".to_string(), Data::Number(1.0));
+    let mut inputs = auth_inputs(&auth);
+    inputs.insert("query".to_string(), Data::Object(query));
+    
+    match runtime.execute_as(script, inputs, Caller::from(&*auth)).await {
+        Err(e) if e.is_forbidden() => forbidden(e.message),
+        Ok(result) => HttpResponse::Ok().json(api::Document(data_to_json(&result))),
         Err(e) => {
-            if e.contains("NotFoundError") {
-                HttpResponse::NotFound().json(json!({
-                    "success": false,
-                    "error": "Document not found"
-                }))
+            if e.message.contains("NotFoundError") {
+                HttpResponse::NotFound().json(ApiError::new("Document not found"))
             } else {
                 error!("Failed to get document: {}", e);
-                HttpResponse::InternalServerError().json(json!({
-                    "success": false,
-                    "error": format!("Failed to get document: {}", e)
-                }))
+                HttpResponse::InternalServerError().json(ApiError::new(format!("Failed to get document: {}", e)))
             }
         }
     }
 }
 
+#[utoipa::path(put, path = "/data/{collection}/{id}", tag = "data",
+    security(("bearer" = []), ("api_key" = [])),
+    request_body = DataRequest,
+    responses(
+        (status = 200, body = api::Updated),
+        (status = 400, description = "Invalid body", body = ApiError),
+        (status = 401, body = ApiError),
+        (status = 403, body = ApiError),
+        (status = 500, body = ApiError),
+    ))]
 async fn update_data(
     runtime: web::Data<Arc<SPURuntime>>,
     path: web::Path<(String, String)>,
     request: web::Json<DataRequest>,
+    auth: web::ReqData<Claims>,
 ) -> HttpResponse {
     let (collection, id) = path.into_inner();
-    info!("Updating document {} in collection: {} workspace: {}", id, collection, request.workspace);
+    let workspace = match token_workspace(&auth, request.workspace.as_deref()) {
+        Ok(workspace) => workspace,
+        Err(response) => return response,
+    };
+    info!("Updating document {} in collection: {} workspace: {} for {}", id, collection, workspace, auth.sub);
     
     // Merge the request data with updatedAt timestamp
     let mut update_data = request.data.as_object()
@@ -902,147 +3968,125 @@ async fn update_data(
         .unwrap_or_else(|| serde_json::Map::new());
     update_data.insert("updatedAt".to_string(), serde_json::Value::String(chrono::Utc::now().to_rfc3339()));
     
-    let script = format!(r#"
+    let script = r#"
         # Update Document
         INSTANTIATE database db
         
-        # Create the complete params object in one go
-        SET params {{
-            "collection": "{}",
-            "workspace": "{}",
-            "filter": {{"_id": "{}"}},
-            "update": {}
-        }}
-        
         CALL db update $params result
         TRACE "Document updated successfully"
         
-        SET response {{
+        SET response {
             "success": true,
-            "id": "{}"
-        }}
+            "id": "$id"
+        }
         
         DESTROY db
         RETURN $response
-    "#,
-        collection,
-        request.workspace,
-        id,
-        serde_json::Value::Object(update_data).to_string(),
-        id
-    );
-    
-    match runtime.execute(&script).await {
+    "#;
+    
+    // The id and fields go in as data, never into the script text
+    let mut params = schema_query(&workspace, Some(collection));
+    params.insert("filter".to_string(), id_filter(&id));
+    params.insert("update".to_string(), Data::from_json(serde_json::Value::Object(update_data)));
+    let mut inputs = auth_inputs(&auth);
+    inputs.insert("params".to_string(), Data::Object(params));
+    inputs.insert("id".to_string(), Data::String(id.clone()));
+    
+    match runtime.execute_as(script, inputs, Caller::from(&*auth)).await {
+        Err(e) if e.is_forbidden() => forbidden(e.message),
+        Ok(result) if data_to_json(&result)["success"] == json!(true) => {
+            HttpResponse::Ok().json(api::Updated { success: true, id })
+        }
         Ok(result) => {
-            match &result {
-                Data::Object(obj) => {
-                    if let Some(Data::Bool(true)) = obj.get("success") {
-                        HttpResponse::Ok().json(data_to_json(&result))
-                    } else {
-                        HttpResponse::InternalServerError().json(data_to_json(&result))
-                    }
-                }
-                _ => HttpResponse::Ok().json(data_to_json(&result))
-            }
+            error!("Unexpected result updating document {}: {}", id, data_to_json(&result));
+            HttpResponse::InternalServerError().json(ApiError::new("Failed to update document"))
+        }
+        Err(e) if e.is_invalid() => {
+            HttpResponse::BadRequest().json(ApiError::new(e))
         }
         Err(e) => {
             error!("Failed to update document: {}", e);
-            HttpResponse::InternalServerError().json(json!({
-                "success": false,
-                "error": format!("Failed to update document: {}", e)
-            }))
+            HttpResponse::InternalServerError().json(ApiError::new(format!("Failed to update document: {}", e)))
         }
     }
 }
 
+#[utoipa::path(delete, path = "/data/{collection}/{id}", tag = "data",
+    params(WorkspaceQuery),
+    security(("bearer" = []), ("api_key" = [])),
+    responses(
+        (status = 200, body = api::Deleted),
+        (status = 401, body = ApiError),
+        (status = 403, body = ApiError),
+        (status = 404, body = ApiError),
+        (status = 500, body = ApiError),
+    ))]
 async fn delete_data(
     runtime: web::Data<Arc<SPURuntime>>,
     path: web::Path<(String, String)>,
-    req: actix_web::HttpRequest,
+    auth: web::ReqData<Claims>,
 ) -> HttpResponse {
     let (collection, id) = path.into_inner();
     
-    // Get workspace from query params or headers
-    let query_params: web::Query<std::collections::HashMap<String, String>> = 
-        web::Query::from_query(req.query_string()).unwrap_or_else(|_| {
-            web::Query(std::collections::HashMap::new())
-        });
-    
-    let workspace = query_params.get("workspace")
-        .map(|s| s.as_str())
-        .or_else(|| req.headers()
-            .get("X-Workspace")
-            .and_then(|v| v.to_str().ok()))
-        .unwrap_or("autodin")
-        .to_string();
+    let workspace = auth.workspace.clone();
     
-    info!("Deleting document {} from collection: {} in workspace: {}", id, collection, workspace);
+    info!("Deleting document {} from collection: {} in workspace: {} for {}", id, collection, workspace, auth.sub);
     
-    let script = format!(r#"
+    let script = r#"
         # Delete Document
         INSTANTIATE database db
         
         # Delete from database
-        SET params {{
-            "collection": "{}",
-            "workspace": "{}",
-            "filter": {{"_id": "{}"}}
-        }}
-        
         CALL db delete $params result
         GET result.deleted_count count
         
         IF $count > 0
             TRACE "Document deleted successfully"
-            SET response {{
+            SET response {
                 "success": true,
-                "id": "{}",
+                "id": "$id",
                 "deleted": true,
                 "count": $count
-            }}
+            }
         ELSE
-            SET response {{
+            SET response {
                 "success": false,
                 "error": "Document not found",
                 "deleted": false
-            }}
+            }
         ENDIF
         
         DESTROY db
         RETURN $response
-    "#,
-        collection,
-        workspace,
-        id,
-        id
-    );
-    
-    match runtime.execute(&script).await {
-        Ok(result) => {
-            match &result {
-                Data::Object(obj) => {
-                    if let Some(Data::Bool(true)) = obj.get("success") {
-                        HttpResponse::Ok().json(data_to_json(&result))
-                    } else if let Some(Data::String(error)) = obj.get("error") {
-                        if error.contains("not found") {
-                            HttpResponse::NotFound().json(data_to_json(&result))
-                        } else {
-                            HttpResponse::InternalServerError().json(data_to_json(&result))
-                        }
-                    } else {
-                        HttpResponse::InternalServerError().json(data_to_json(&result))
-                    }
-                }
-                _ => HttpResponse::Ok().json(data_to_json(&result))
+    "#;
+    
+    // The id goes in as data, never into the script text
+    let mut params = schema_query(&workspace, Some(collection));
+    params.insert("filter".to_string(), id_filter(&id));
+    let mut inputs = auth_inputs(&auth);
+    inputs.insert("params".to_string(), Data::Object(params));
+    inputs.insert("id".to_string(), Data::String(id.clone()));
+    
+    match runtime.execute_as(script, inputs, Caller::from(&*auth)).await {
+        Err(e) if e.is_forbidden() => forbidden(e.message),
+        Ok(result) => match data_to_json(&result) {
+            deleted if deleted["success"] == json!(true) => HttpResponse::Ok().json(api::Deleted {
+                success: true,
+                id,
+                deleted: true,
+                count: deleted["count"].as_f64().map_or(0, |n| n as u64),
+            }),
+            missing if missing["deleted"] == json!(false) => {
+                HttpResponse::NotFound().json(ApiError::new("Document not found"))
             }
-        }
+            other => {
+                error!("Unexpected result deleting document {}: {}", id, other);
+                HttpResponse::InternalServerError().json(ApiError::new("Failed to delete document"))
+            }
+        },
         Err(e) => {
             error!("Failed to delete document: {}", e);
-            HttpResponse::InternalServerError().json(json!({
-                "success": false,
-                "error": format!("Failed to delete document: {}", e),
-                "deleted": false
-            }))
+            HttpResponse::InternalServerError().json(ApiError::new(format!("Failed to delete document: {}", e)))
         }
     }
 }
\ No newline at end of file
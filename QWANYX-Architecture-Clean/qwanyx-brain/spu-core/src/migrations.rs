@@ -0,0 +1,657 @@
+//! Migrations
+//!
+//! Versioned changes to workspace databases, kept as files in a directory
+//! (`MIGRATIONS_DIR`, default `./migrations`) and applied in version order:
+//!
+//! - `0001_uuid_indexes.json` - declarative steps, `{"description": ..., "up": [...], "down": [...]}`
+//! - `0002_backfill_status.up.spu`, with an optional `0002_backfill_status.down.spu` -
+//!   an SPU script, run with `$workspace` set to the workspace being migrated
+//!
+//! Declarative steps name an `op`:
+//!
+//! ```json
+//! {"op": "create_index", "collection": "memories", "keys": {"uuid": 1}, "unique": true}
+//! {"op": "drop_index", "collection": "memories", "name": "uuid_1"}
+//! {"op": "rename_field", "collection": "users", "from": "mail", "to": "email"}
+//! {"op": "rename_collection", "from": "memory", "to": "memories"}
+//! {"op": "script", "script": "INSTANTIATE database db\n..."}
+//! ```
+//!
+//! A JSON migration without `down` is reversed step by step when it only creates
+//! indexes and renames; any other migration needs explicit down steps to be
+//! migrated down.
+//!
+//! Each workspace database records its migrations in `spu_migrations`. A migration
+//! is claimed by inserting its record before its steps run, so two instances
+//! starting together never apply it twice. Steps are not transactional: when one
+//! fails the claim is released, and the steps before it stay applied.
+//!
+//! With an audit log (`with_audit`), every declarative step is logged; the
+//! writes of script steps are logged by the database coprocessor.
+
+use crate::audit::{self, AuditEntry, AuditLog};
+use crate::runtime::SPURuntime;
+use crate::simple_parser::SimpleParser;
+use crate::store::{DocumentStore, FindOptions, Index, Namespace, UpdateOptions};
+use crate::workspaces::Workspaces;
+use crate::Data;
+use mongodb::bson::{doc, Bson, DateTime, Document};
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+use std::sync::Arc;
+use tracing::{info, warn};
+
+/// Collection of each workspace database recording its migrations
+pub const MIGRATION_COLLECTION: &str = "spu_migrations";
+
+/// One change a migration makes
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum Step {
+    CreateIndex {
+        collection: String,
+        /// Fields and directions, e.g. `{"uuid": 1}`
+        keys: Map<String, Value>,
+        #[serde(default)]
+        unique: bool,
+        /// Defaults to the fields and directions, e.g. `uuid_1`
+        #[serde(default)]
+        name: Option<String>,
+    },
+    DropIndex { collection: String, name: String },
+    /// Rename a field in every document of a collection that has it
+    RenameField { collection: String, from: String, to: String },
+    RenameCollection { from: String, to: String },
+    /// An SPU script, run with `$workspace`
+    Script { script: String },
+}
+
+impl Step {
+    /// The index a `create_index` step creates
+    pub fn index(&self) -> Result<Option<Index>, String> {
+        let Step::CreateIndex { keys, unique, name, .. } = self else {
+            return Ok(None);
+        };
+        let mut document = Document::new();
+        for (field, direction) in keys {
+            let direction = match direction {
+                Value::Number(n) if matches!(n.as_f64(), Some(d) if d == 1.0 || d == -1.0) => Bson::Int32(n.as_f64().unwrap_or(1.0) as i32),
+                Value::String(kind) => Bson::String(kind.clone()),
+                other => return Err(format!("Invalid direction {} for index key {}", other, field)),
+            };
+            document.insert(field.clone(), direction);
+        }
+        if document.is_empty() {
+            return Err("An index needs at least one key".to_string());
+        }
+        let index = Index::new(document).unique(*unique);
+        Ok(Some(match name {
+            Some(name) => index.named(name.clone()),
+            None => index,
+        }))
+    }
+
+    /// Catch mistakes when loading rather than halfway through a migration
+    fn check(&self) -> Result<(), String> {
+        let names: Vec<&str> = match self {
+            Step::CreateIndex { collection, .. } => {
+                self.index()?;
+                vec![collection]
+            }
+            Step::DropIndex { collection, name } => vec![collection, name],
+            Step::RenameField { collection, from, to } => {
+                if from == to {
+                    return Err(format!("Renaming field {} to itself", from));
+                }
+                vec![collection, from, to]
+            }
+            Step::RenameCollection { from, to } => vec![from, to],
+            Step::Script { script } => {
+                SimpleParser::parse(script).map_err(|e| format!("Invalid script: {}", e))?;
+                Vec::new()
+            }
+        };
+        match names.iter().any(|name| name.is_empty()) {
+            true => Err(format!("Missing name in {:?}", self)),
+            false => Ok(()),
+        }
+    }
+
+    /// The step that undoes this one, if there is one
+    pub fn inverse(&self) -> Option<Step> {
+        match self {
+            Step::CreateIndex { collection, .. } => Some(Step::DropIndex {
+                collection: collection.clone(),
+                name: self.index().ok()??.name,
+            }),
+            Step::RenameField { collection, from, to } => Some(Step::RenameField {
+                collection: collection.clone(),
+                from: to.clone(),
+                to: from.clone(),
+            }),
+            Step::RenameCollection { from, to } => Some(Step::RenameCollection { from: to.clone(), to: from.clone() }),
+            Step::DropIndex { .. } | Step::Script { .. } => None,
+        }
+    }
+
+    pub fn describe(&self) -> String {
+        match self {
+            Step::CreateIndex { collection, unique, .. } => {
+                let name = self.index().ok().flatten().map(|index| index.name).unwrap_or_default();
+                let kind = if *unique { "unique index" } else { "index" };
+                format!("create {} {} on {}", kind, name, collection)
+            }
+            Step::DropIndex { collection, name } => format!("drop index {} on {}", name, collection),
+            Step::RenameField { collection, from, to } => format!("rename field {} to {} in {}", from, to, collection),
+            Step::RenameCollection { from, to } => format!("rename collection {} to {}", from, to),
+            Step::Script { .. } => "run script".to_string(),
+        }
+    }
+}
+
+/// A versioned set of steps, with those that undo them
+#[derive(Debug, Clone, PartialEq)]
+pub struct Migration {
+    pub version: u32,
+    pub name: String,
+    pub description: Option<String>,
+    pub up: Vec<Step>,
+    /// None when the migration cannot be undone
+    pub down: Option<Vec<Step>>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct MigrationFile {
+    #[serde(default)]
+    description: Option<String>,
+    up: Vec<Step>,
+    #[serde(default)]
+    down: Option<Vec<Step>>,
+}
+
+impl Migration {
+    /// A migration from a JSON file; without `down` it is undone by reversing `up`
+    /// when every step can be
+    pub fn from_json(version: u32, name: &str, text: &str) -> Result<Self, String> {
+        let file: MigrationFile = serde_json::from_str(text)
+            .map_err(|e| format!("Invalid migration {}_{}: {}", version, name, e))?;
+        let down = file.down.or_else(|| file.up.iter().rev().map(Step::inverse).collect());
+        Self::checked(Migration { version, name: name.to_string(), description: file.description, up: file.up, down })
+    }
+
+    /// A migration from SPU scripts
+    pub fn from_scripts(version: u32, name: &str, up: &str, down: Option<&str>) -> Result<Self, String> {
+        let script = |script: &str| vec![Step::Script { script: script.to_string() }];
+        Self::checked(Migration {
+            version,
+            name: name.to_string(),
+            description: None,
+            up: script(up),
+            down: down.map(script),
+        })
+    }
+
+    fn checked(self) -> Result<Self, String> {
+        if self.version == 0 {
+            return Err(format!("Migration {} has version 0; versions start at 1", self.name));
+        }
+        for step in self.up.iter().chain(self.down.iter().flatten()) {
+            step.check().map_err(|e| format!("Migration {} ({}): {}", self.version, self.name, e))?;
+        }
+        Ok(self)
+    }
+}
+
+#[derive(Default)]
+struct Files {
+    name: String,
+    json: Option<String>,
+    up: Option<String>,
+    down: Option<String>,
+}
+
+/// Every migration in a directory, by version; other files are ignored
+pub fn load(dir: &Path) -> Result<Vec<Migration>, String> {
+    let entries = std::fs::read_dir(dir)
+        .map_err(|e| format!("Failed to read migrations in {}: {}", dir.display(), e))?;
+
+    let mut files: BTreeMap<u32, Files> = BTreeMap::new();
+    for entry in entries {
+        let path = entry.map_err(|e| format!("Failed to read migrations in {}: {}", dir.display(), e))?.path();
+        let Some(file_name) = path.file_name().and_then(|name| name.to_str()) else {
+            continue;
+        };
+        let (stem, kind) = if let Some(stem) = file_name.strip_suffix(".up.spu") {
+            (stem, "up")
+        } else if let Some(stem) = file_name.strip_suffix(".down.spu") {
+            (stem, "down")
+        } else if let Some(stem) = file_name.strip_suffix(".json") {
+            (stem, "json")
+        } else {
+            continue;
+        };
+        let Some((version, name)) = stem.split_once('_').and_then(|(v, name)| Some((v.parse::<u32>().ok()?, name))) else {
+            warn!("Ignoring {}: migration files are named like 0001_name.json", file_name);
+            continue;
+        };
+
+        let text = std::fs::read_to_string(&path)
+            .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+        let entry = files.entry(version).or_default();
+        if !entry.name.is_empty() && entry.name != name {
+            return Err(format!("Migration version {} is used by both {} and {}", version, entry.name, name));
+        }
+        entry.name = name.to_string();
+        match kind {
+            "up" => entry.up = Some(text),
+            "down" => entry.down = Some(text),
+            _ => entry.json = Some(text),
+        }
+    }
+
+    files.into_iter()
+        .map(|(version, files)| match files {
+            Files { name, json: Some(json), up: None, down: None } => Migration::from_json(version, &name, &json),
+            Files { name, json: None, up: Some(up), down } => Migration::from_scripts(version, &name, &up, down.as_deref()),
+            Files { name, json: None, up: None, .. } => Err(format!("Migration {} ({}) has a down script but no up script", version, name)),
+            Files { name, .. } => Err(format!("Migration {} ({}) has both a JSON file and scripts", version, name)),
+        })
+        .collect()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Direction {
+    Up,
+    Down,
+}
+
+/// Which migrations a workspace has
+#[derive(Debug, Clone, PartialEq)]
+pub struct MigrationStatus {
+    pub workspace: String,
+    /// Highest applied version, 0 when none is
+    pub current: u32,
+    pub applied: Vec<u32>,
+    /// Known and not applied, including any below `current`
+    pub pending: Vec<u32>,
+    /// Claimed by a run that has not finished, or that died
+    pub running: Vec<u32>,
+}
+
+impl MigrationStatus {
+    pub fn to_json(&self) -> Value {
+        json!({
+            "workspace": self.workspace,
+            "current": self.current,
+            "applied": self.applied,
+            "pending": self.pending,
+            "running": self.running,
+        })
+    }
+}
+
+/// What a migration run did, or would do on a dry run
+#[derive(Debug, Clone, PartialEq)]
+pub struct MigrationReport {
+    pub workspace: String,
+    pub direction: Direction,
+    pub dry_run: bool,
+    pub from: u32,
+    pub to: u32,
+    /// Versions migrated, in order
+    pub migrations: Vec<u32>,
+    /// Each step, prefixed with its migration
+    pub steps: Vec<String>,
+}
+
+impl MigrationReport {
+    pub fn to_json(&self) -> Value {
+        json!({
+            "workspace": self.workspace,
+            "direction": match self.direction { Direction::Up => "up", Direction::Down => "down" },
+            "dry_run": self.dry_run,
+            "from": self.from,
+            "to": self.to,
+            "migrations": self.migrations,
+            "steps": self.steps,
+        })
+    }
+}
+
+/// Applies migrations to workspace databases
+pub struct Migrator {
+    runtime: Arc<SPURuntime>,
+    store: Arc<dyn DocumentStore>,
+    migrations: Vec<Migration>,
+    audit: Option<Arc<AuditLog>>,
+}
+
+impl Migrator {
+    /// `runtime` runs script steps, so its database coprocessor should use `store`
+    pub fn new(runtime: Arc<SPURuntime>, store: Arc<dyn DocumentStore>, mut migrations: Vec<Migration>) -> Self {
+        migrations.sort_by_key(|migration| migration.version);
+        Self { runtime, store, migrations, audit: None }
+    }
+
+    /// Log the declarative steps run in this audit log
+    pub fn with_audit(mut self, audit: Arc<AuditLog>) -> Self {
+        self.audit = Some(audit);
+        self
+    }
+
+    pub fn migrations(&self) -> &[Migration] {
+        &self.migrations
+    }
+
+    /// The highest known version, 0 without migrations
+    pub fn latest(&self) -> u32 {
+        self.migrations.last().map(|migration| migration.version).unwrap_or(0)
+    }
+
+    fn migration(&self, version: u32) -> Option<&Migration> {
+        self.migrations.iter().find(|migration| migration.version == version)
+    }
+
+    pub async fn status(&self, workspace: &str) -> Result<MigrationStatus, String> {
+        let records = self.store.find(Namespace::new(workspace, MIGRATION_COLLECTION), doc! {}, FindOptions {
+            sort: Some(doc! { "_id": 1 }),
+            ..FindOptions::default()
+        }).await?;
+
+        let (mut applied, mut running) = (Vec::new(), Vec::new());
+        for record in &records {
+            let version = match record.get("_id") {
+                Some(Bson::Int32(v)) => *v as u32,
+                Some(Bson::Int64(v)) => *v as u32,
+                Some(Bson::Double(v)) => *v as u32,
+                _ => continue,
+            };
+            match record.get_str("state") {
+                Ok("applied") => applied.push(version),
+                _ => running.push(version),
+            }
+        }
+        let pending = self.migrations.iter()
+            .map(|migration| migration.version)
+            .filter(|version| !applied.contains(version) && !running.contains(version))
+            .collect();
+
+        Ok(MigrationStatus {
+            workspace: workspace.to_string(),
+            current: applied.iter().copied().max().unwrap_or(0),
+            applied,
+            pending,
+            running,
+        })
+    }
+
+    /// Migrate a workspace up to `target` (default the latest version) or down to
+    /// it when it is below the current version; a dry run only reports the steps
+    pub async fn migrate(&self, workspace: &str, target: Option<u32>, dry_run: bool) -> Result<MigrationReport, String> {
+        let status = self.status(workspace).await?;
+        if let Some(version) = status.running.first() {
+            return Err(format!(
+                "Migration {} is still marked as running in {}; if that run died, delete its record from {}",
+                version, workspace, MIGRATION_COLLECTION
+            ));
+        }
+        let target = target.unwrap_or_else(|| self.latest());
+        if target != 0 && self.migration(target).is_none() && !status.applied.contains(&target) {
+            return Err(format!("Unknown migration version {}", target));
+        }
+
+        let (direction, plan) = if target < status.current {
+            let mut plan = Vec::new();
+            for version in status.applied.iter().rev().filter(|version| **version > target) {
+                let migration = self.migration(*version).ok_or_else(|| {
+                    format!("Migration {} is applied to {} but its file is missing", version, workspace)
+                })?;
+                let steps = migration.down.as_ref().ok_or_else(|| {
+                    format!("Migration {} ({}) cannot be undone: it has no down steps", version, migration.name)
+                })?;
+                plan.push((migration, steps));
+            }
+            (Direction::Down, plan)
+        } else {
+            let plan = self.migrations.iter()
+                .filter(|migration| migration.version <= target && status.pending.contains(&migration.version))
+                .map(|migration| (migration, &migration.up))
+                .collect();
+            (Direction::Up, plan)
+        };
+
+        let mut report = MigrationReport {
+            workspace: workspace.to_string(),
+            direction,
+            dry_run,
+            from: status.current,
+            to: status.current,
+            migrations: Vec::new(),
+            steps: Vec::new(),
+        };
+        for (migration, steps) in &plan {
+            report.migrations.push(migration.version);
+            report.steps.extend(steps.iter().map(|step| format!("{:04} {}: {}", migration.version, migration.name, step.describe())));
+        }
+        let mut remaining = status.applied.clone();
+        match direction {
+            Direction::Up => remaining.extend(&report.migrations),
+            Direction::Down => remaining.retain(|version| !report.migrations.contains(version)),
+        }
+        report.to = remaining.into_iter().max().unwrap_or(0);
+
+        if dry_run || plan.is_empty() {
+            return Ok(report);
+        }
+        for (migration, steps) in plan {
+            info!("Migrating {} {:?} through {} ({})", workspace, direction, migration.version, migration.name);
+            self.apply(workspace, migration, steps, direction).await?;
+        }
+        Ok(report)
+    }
+
+    /// Claim a migration, run its steps and record the outcome
+    async fn apply(&self, workspace: &str, migration: &Migration, steps: &[Step], direction: Direction) -> Result<(), String> {
+        let ns = Namespace::new(workspace, MIGRATION_COLLECTION);
+        let id = migration.version as i64;
+        match direction {
+            Direction::Up => {
+                let record = doc! { "_id": id, "name": &migration.name, "state": "running", "started_at": DateTime::now() };
+                self.store.insert(ns, record).await.map_err(|e| match e.contains("Duplicate key") {
+                    true => format!("Migration {} is already being applied to {}", migration.version, workspace),
+                    false => e,
+                })?;
+            }
+            Direction::Down => {
+                let claimed = self.store.update(
+                    ns,
+                    doc! { "_id": id, "state": "applied" },
+                    doc! { "$set": { "state": "running", "started_at": DateTime::now() } },
+                    UpdateOptions::default(),
+                ).await?;
+                if claimed.modified == 0 {
+                    return Err(format!("Migration {} is already being undone in {}", migration.version, workspace));
+                }
+            }
+        }
+
+        for (number, step) in steps.iter().enumerate() {
+            let run = self.run(workspace, step);
+            if let Err(e) = audit::as_script(format!("migration:{}", migration.version), run).await {
+                // Release the claim so the migration can be retried once fixed
+                let released = match direction {
+                    Direction::Up => self.store.delete(ns, doc! { "_id": id }).await.map(|_| ()),
+                    Direction::Down => self.store.update(ns, doc! { "_id": id }, doc! { "$set": { "state": "applied" } }, UpdateOptions::default()).await.map(|_| ()),
+                };
+                if let Err(release) = released {
+                    warn!("Failed to release migration {} in {}: {}", migration.version, workspace, release);
+                }
+                return Err(format!(
+                    "Migration {} ({}) failed at step {} ({}) in {}: {}; the steps before it were not undone",
+                    migration.version, migration.name, number + 1, step.describe(), workspace, e
+                ));
+            }
+        }
+
+        match direction {
+            Direction::Up => self.store.update(
+                ns,
+                doc! { "_id": id },
+                doc! { "$set": { "state": "applied", "applied_at": DateTime::now() } },
+                UpdateOptions::default(),
+            ).await.map(|_| ()),
+            Direction::Down => self.store.delete(ns, doc! { "_id": id }).await.map(|_| ()),
+        }
+    }
+
+    async fn run(&self, workspace: &str, step: &Step) -> Result<(), String> {
+        let (op, collection, modified) = match step {
+            Step::CreateIndex { collection, .. } => {
+                let index = step.index()?.ok_or("Not an index")?;
+                self.store.create_index(Namespace::new(workspace, collection), index).await?;
+                ("create_index", collection, None)
+            }
+            Step::DropIndex { collection, name } => {
+                self.store.drop_index(Namespace::new(workspace, collection), name).await?;
+                ("drop_index", collection, None)
+            }
+            Step::RenameField { collection, from, to } => {
+                let mut rename = Document::new();
+                rename.insert(from.clone(), to.clone());
+                let mut filter = Document::new();
+                filter.insert(from.clone(), doc! { "$exists": true });
+                let options = UpdateOptions { many: true, upsert: false };
+                let outcome = self.store.update(Namespace::new(workspace, collection), filter, doc! { "$rename": rename }, options).await?;
+                ("rename_field", collection, Some(outcome.modified))
+            }
+            Step::RenameCollection { from, to } => {
+                self.store.rename_collection(Namespace::new(workspace, from), to).await?;
+                ("rename_collection", from, None)
+            }
+            Step::Script { script } => {
+                let mut inputs = HashMap::new();
+                inputs.insert("workspace".to_string(), Data::String(workspace.to_string()));
+                return self.runtime.execute_in(script, inputs, workspace).await.map(|_| ());
+            }
+        };
+
+        if let Some(audit) = &self.audit {
+            let mut after = doc! { "step": step.describe() };
+            if let Some(modified) = modified {
+                after.insert("modified", modified as i64);
+            }
+            let entry = AuditEntry::new(workspace, &format!("migration.{}", op))
+                .on(collection.as_str())
+                .changes(None, Some(&after));
+            audit.log(&entry, None).await;
+        }
+        Ok(())
+    }
+}
+
+// ================================================================================
+// COMMAND LINE
+// ================================================================================
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MigrateAction {
+    Status,
+    Up,
+    /// Undo the latest migration, or down to `--to`
+    Down,
+}
+
+/// `spu-core migrate [status|up|down] [--workspace NAME]... [--to VERSION] [--dry-run] [--dir PATH]`
+#[derive(Debug, Clone, PartialEq)]
+pub struct MigrateCommand {
+    pub action: MigrateAction,
+    /// Defaults to every workspace of the registry
+    pub workspaces: Vec<String>,
+    pub target: Option<u32>,
+    pub dry_run: bool,
+    /// Defaults to `MIGRATIONS_DIR` or `./migrations`
+    pub dir: String,
+}
+
+pub const MIGRATE_USAGE: &str =
+    "Usage: spu-core migrate [status|up|down] [--workspace NAME]... [--to VERSION] [--dry-run] [--dir PATH]";
+
+impl MigrateCommand {
+    /// Parse the arguments after `migrate`, for the workspaces of `registry`
+    pub fn parse(args: &[String], registry: &Workspaces) -> Result<Self, String> {
+        let mut command = MigrateCommand {
+            action: MigrateAction::Up,
+            workspaces: Vec::new(),
+            target: None,
+            dry_run: false,
+            dir: migrations_dir(),
+        };
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let mut value = |flag: &str| args.next().cloned().ok_or_else(|| format!("{} needs a value", flag));
+            match arg.as_str() {
+                "status" => command.action = MigrateAction::Status,
+                "up" => command.action = MigrateAction::Up,
+                "down" => command.action = MigrateAction::Down,
+                "--workspace" | "-w" => command.workspaces.push(value(arg)?),
+                "--to" => {
+                    let target = value(arg)?;
+                    command.target = Some(target.parse().map_err(|_| format!("Invalid version '{}'", target))?);
+                }
+                "--dir" => command.dir = value(arg)?,
+                "--dry-run" => command.dry_run = true,
+                other => return Err(format!("Unknown argument '{}'", other)),
+            }
+        }
+        if let Some(unknown) = command.workspaces.iter().find(|workspace| registry.get(workspace).is_none()) {
+            return Err(format!("Unknown workspace '{}'", unknown));
+        }
+        if command.workspaces.is_empty() {
+            command.workspaces = registry.ids();
+        }
+        Ok(command)
+    }
+
+    /// Run against every workspace, returning a status or report for each
+    pub async fn run(&self, migrator: &Migrator) -> Result<Vec<Value>, String> {
+        let mut results = Vec::with_capacity(self.workspaces.len());
+        for workspace in &self.workspaces {
+            let result = match self.action {
+                MigrateAction::Status => migrator.status(workspace).await?.to_json(),
+                MigrateAction::Up => migrator.migrate(workspace, self.target, self.dry_run).await?.to_json(),
+                MigrateAction::Down => {
+                    let status = migrator.status(workspace).await?;
+                    // One migration back by default
+                    let target = self.target.unwrap_or_else(|| status.applied.iter().rev().nth(1).copied().unwrap_or(0));
+                    if target > status.current {
+                        return Err(format!("{} is at version {}, below {}", workspace, status.current, target));
+                    }
+                    if target == status.current {
+                        // Nothing to undo; migrating "down" to the current version must not apply anything
+                        MigrationReport {
+                            workspace: workspace.clone(),
+                            direction: Direction::Down,
+                            dry_run: self.dry_run,
+                            from: status.current,
+                            to: status.current,
+                            migrations: Vec::new(),
+                            steps: Vec::new(),
+                        }.to_json()
+                    } else {
+                        migrator.migrate(workspace, Some(target), self.dry_run).await?.to_json()
+                    }
+                }
+            };
+            results.push(result);
+        }
+        Ok(results)
+    }
+}
+
+/// `MIGRATIONS_DIR`, default `./migrations`
+pub fn migrations_dir() -> String {
+    std::env::var("MIGRATIONS_DIR").unwrap_or_else(|_| "./migrations".to_string())
+}
@@ -0,0 +1,429 @@
+//! Saved Pipelines
+//!
+//! Named aggregation pipelines per workspace, run over HTTP as reports. A
+//! pipeline declares parameters and uses them as `{"$param": "brand"}` anywhere
+//! in its stages; a parameter whose default is null must be given on each run.
+//!
+//! Runs go through the database coprocessor's `aggregate`, so they are
+//! read-only and capped like any other aggregation, and those made for a user
+//! (`run_as`) only see what the policy lets them read. With an audit log
+//! (`with_audit`), saving and deleting pipelines is logged.
+
+use crate::audit::{AuditEntry, AuditLog};
+use crate::auth::policy::Caller;
+use crate::runtime::{ScriptError, SPURuntime};
+use crate::store::pipeline;
+use crate::Data;
+use crate::workspaces::WorkspaceDatabases;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use mongodb::{Collection, bson::{doc, Document}};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::Arc;
+use thiserror::Error;
+use tokio::sync::RwLock;
+use tracing::{error, info};
+
+/// Script that runs a pipeline; `$query` holds the `aggregate` arguments
+const RUN_SCRIPT: &str = "INSTANTIATE database db\nCALL db aggregate $query result\nRETURN $result";
+
+/// An aggregation pipeline saved under a name
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SavedPipeline {
+    pub workspace: String,
+    pub name: String,
+    pub collection: String,
+    /// Stages as JSON, with `{"$param": ...}` placeholders
+    pub pipeline: Value,
+    /// Parameter defaults; null means the parameter is required
+    pub parameters: HashMap<String, Value>,
+    pub description: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+impl SavedPipeline {
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "workspace": self.workspace,
+            "name": self.name,
+            "collection": self.collection,
+            "pipeline": self.pipeline,
+            "parameters": self.parameters,
+            "description": self.description,
+            "created_at": self.created_at.to_rfc3339(),
+            "updated_at": self.updated_at.to_rfc3339(),
+        })
+    }
+
+    /// The stages with every placeholder replaced
+    pub fn bind(&self, params: &HashMap<String, Value>) -> Result<Value, String> {
+        if let Some(unknown) = params.keys().find(|name| !self.parameters.contains_key(*name)) {
+            return Err(format!("Unknown parameter '{}'", unknown));
+        }
+        let mut values = HashMap::new();
+        for (name, default) in &self.parameters {
+            match params.get(name).filter(|v| !v.is_null()).unwrap_or(default) {
+                Value::Null => return Err(format!("Missing parameter '{}'", name)),
+                value => values.insert(name.as_str(), value),
+            };
+        }
+        substitute(&self.pipeline, &values)
+    }
+}
+
+/// Why a pipeline could not run
+#[derive(Debug, Error)]
+pub enum PipelineError {
+    #[error("Pipeline not found")]
+    NotFound,
+
+    #[error("Invalid parameters: {0}")]
+    InvalidParameters(String),
+
+    #[error("Pipeline failed: {0}")]
+    Failed(String),
+
+    /// The caller's policy refused the pipeline's query
+    #[error("Pipeline failed: {0}")]
+    Forbidden(String),
+
+    #[error("Storage error: {0}")]
+    Storage(String),
+}
+
+fn placeholder(value: &Value) -> Option<&Value> {
+    match value {
+        Value::Object(object) if object.len() == 1 => object.get("$param"),
+        _ => None,
+    }
+}
+
+fn substitute(value: &Value, values: &HashMap<&str, &Value>) -> Result<Value, String> {
+    if let Some(name) = placeholder(value) {
+        let name = name.as_str().ok_or("$param needs a parameter name")?;
+        return values.get(name)
+            .map(|value| (*value).clone())
+            .ok_or_else(|| format!("Parameter '{}' is not declared", name));
+    }
+    match value {
+        Value::Array(items) => items.iter().map(|item| substitute(item, values)).collect::<Result<_, _>>().map(Value::Array),
+        Value::Object(object) => object.iter()
+            .map(|(key, value)| substitute(value, values).map(|value| (key.clone(), value)))
+            .collect::<Result<_, _>>()
+            .map(Value::Object),
+        other => Ok(other.clone()),
+    }
+}
+
+/// Names used by the placeholders of a pipeline
+fn placeholders(value: &Value, names: &mut Vec<String>) -> Result<(), String> {
+    if let Some(name) = placeholder(value) {
+        let name = name.as_str().ok_or("$param needs a parameter name")?;
+        names.push(name.to_string());
+        return Ok(());
+    }
+    match value {
+        Value::Array(items) => items.iter().try_for_each(|item| placeholders(item, names)),
+        Value::Object(object) => object.values().try_for_each(|value| placeholders(value, names)),
+        _ => Ok(()),
+    }
+}
+
+// ================================================================================
+// STORAGE
+// ================================================================================
+
+/// Persistence for saved pipelines, scoped by workspace
+#[async_trait]
+pub trait PipelineStore: Send + Sync {
+    async fn save(&self, pipeline: &SavedPipeline) -> Result<(), String>;
+
+    async fn load(&self, workspace: &str, name: &str) -> Result<Option<SavedPipeline>, String>;
+
+    async fn list(&self, workspace: &str) -> Result<Vec<SavedPipeline>, String>;
+
+    /// Returns false if there was no such pipeline
+    async fn delete(&self, workspace: &str, name: &str) -> Result<bool, String>;
+}
+
+/// In-memory store, for tests and local development
+#[derive(Default)]
+pub struct MemoryPipelineStore {
+    pipelines: RwLock<HashMap<(String, String), SavedPipeline>>,
+}
+
+impl MemoryPipelineStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[async_trait]
+impl PipelineStore for MemoryPipelineStore {
+    async fn save(&self, pipeline: &SavedPipeline) -> Result<(), String> {
+        let mut pipelines = self.pipelines.write().await;
+        pipelines.insert((pipeline.workspace.clone(), pipeline.name.clone()), pipeline.clone());
+        Ok(())
+    }
+
+    async fn load(&self, workspace: &str, name: &str) -> Result<Option<SavedPipeline>, String> {
+        let pipelines = self.pipelines.read().await;
+        Ok(pipelines.get(&(workspace.to_string(), name.to_string())).cloned())
+    }
+
+    async fn list(&self, workspace: &str) -> Result<Vec<SavedPipeline>, String> {
+        let pipelines = self.pipelines.read().await;
+        let mut list: Vec<SavedPipeline> = pipelines.values()
+            .filter(|p| p.workspace == workspace)
+            .cloned()
+            .collect();
+        list.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(list)
+    }
+
+    async fn delete(&self, workspace: &str, name: &str) -> Result<bool, String> {
+        let mut pipelines = self.pipelines.write().await;
+        Ok(pipelines.remove(&(workspace.to_string(), name.to_string())).is_some())
+    }
+}
+
+/// MongoDB store
+///
+/// Pipelines live in the workspace's own database, in the `spu_pipelines`
+/// collection, keyed by name.
+pub struct MongoPipelineStore {
+    databases: WorkspaceDatabases,
+}
+
+impl MongoPipelineStore {
+    pub fn new(databases: WorkspaceDatabases) -> Self {
+        Self { databases }
+    }
+
+    fn collection(&self, workspace: &str) -> Result<Collection<Document>, String> {
+        Ok(self.databases.database(workspace)?.collection("spu_pipelines"))
+    }
+
+    fn from_document(document: &Document) -> Result<SavedPipeline, String> {
+        let json = document.get_str("record")
+            .map_err(|e| format!("Corrupt pipeline document: {}", e))?;
+        serde_json::from_str(json)
+            .map_err(|e| format!("Corrupt pipeline document: {}", e))
+    }
+}
+
+#[async_trait]
+impl PipelineStore for MongoPipelineStore {
+    async fn save(&self, pipeline: &SavedPipeline) -> Result<(), String> {
+        // Stored as JSON text: the stages are full of `$` keys MongoDB won't keep
+        let json = serde_json::to_string(pipeline)
+            .map_err(|e| format!("Failed to serialize pipeline: {}", e))?;
+        let document = doc! {
+            "_id": &pipeline.name,
+            "collection": &pipeline.collection,
+            "record": json,
+        };
+
+        let options = mongodb::options::ReplaceOptions::builder().upsert(true).build();
+        self.collection(&pipeline.workspace)?
+            .replace_one(doc! { "_id": &pipeline.name }, document, options).await
+            .map_err(|e| format!("Failed to save pipeline: {}", e))?;
+        Ok(())
+    }
+
+    async fn load(&self, workspace: &str, name: &str) -> Result<Option<SavedPipeline>, String> {
+        let document = self.collection(workspace)?.find_one(doc! { "_id": name }, None).await
+            .map_err(|e| format!("Failed to load pipeline: {}", e))?;
+        document.as_ref().map(Self::from_document).transpose()
+    }
+
+    async fn list(&self, workspace: &str) -> Result<Vec<SavedPipeline>, String> {
+        use futures::stream::TryStreamExt;
+        let options = mongodb::options::FindOptions::builder().sort(doc! { "_id": 1 }).build();
+        let cursor = self.collection(workspace)?.find(None, options).await
+            .map_err(|e| format!("Query failed: {}", e))?;
+        let documents: Vec<Document> = cursor.try_collect().await
+            .map_err(|e| format!("Failed to collect results: {}", e))?;
+        documents.iter().map(Self::from_document).collect()
+    }
+
+    async fn delete(&self, workspace: &str, name: &str) -> Result<bool, String> {
+        let result = self.collection(workspace)?.delete_one(doc! { "_id": name }, None).await
+            .map_err(|e| format!("Failed to delete pipeline: {}", e))?;
+        Ok(result.deleted_count == 1)
+    }
+}
+
+// ================================================================================
+// LIBRARY
+// ================================================================================
+
+/// Manages saved pipelines and runs them
+pub struct PipelineLibrary {
+    runtime: Arc<SPURuntime>,
+    store: Arc<dyn PipelineStore>,
+    audit: Option<Arc<AuditLog>>,
+}
+
+impl PipelineLibrary {
+    pub fn new(runtime: Arc<SPURuntime>, store: Arc<dyn PipelineStore>) -> Self {
+        Self { runtime, store, audit: None }
+    }
+
+    /// Log saved and deleted pipelines in this audit log
+    pub fn with_audit(mut self, audit: Arc<AuditLog>) -> Self {
+        self.audit = Some(audit);
+        self
+    }
+
+    /// Create or replace a pipeline
+    pub async fn save(
+        &self,
+        workspace: &str,
+        name: &str,
+        collection: &str,
+        stages: Value,
+        parameters: HashMap<String, Value>,
+        description: Option<String>,
+    ) -> Result<SavedPipeline, String> {
+        if name.is_empty() {
+            return Err("Pipeline name cannot be empty".to_string());
+        }
+        if collection.is_empty() {
+            return Err("Collection cannot be empty".to_string());
+        }
+        let Value::Array(items) = &stages else {
+            return Err("Pipeline must be an array of stages".to_string());
+        };
+        let documents = items.iter()
+            .map(|stage| mongodb::bson::to_document(stage).map_err(|_| "Every pipeline stage must be an object".to_string()))
+            .collect::<Result<Vec<_>, _>>()?;
+        pipeline::check(&documents)?;
+
+        let mut used = Vec::new();
+        placeholders(&stages, &mut used)?;
+        if let Some(undeclared) = used.iter().find(|name| !parameters.contains_key(*name)) {
+            return Err(format!("Parameter '{}' is used but not declared", undeclared));
+        }
+
+        let now = Utc::now();
+        let existing = self.store.load(workspace, name).await?;
+        let created_at = existing.as_ref().map(|existing| existing.created_at).unwrap_or(now);
+
+        let saved = SavedPipeline {
+            workspace: workspace.to_string(),
+            name: name.to_string(),
+            collection: collection.to_string(),
+            pipeline: stages,
+            parameters,
+            description,
+            created_at,
+            updated_at: now,
+        };
+        self.store.save(&saved).await?;
+        info!("Saved pipeline {} in workspace {}", saved.name, saved.workspace);
+        self.audit("pipeline.saved", existing.as_ref(), Some(&saved)).await;
+        Ok(saved)
+    }
+
+    pub async fn load(&self, workspace: &str, name: &str) -> Result<Option<SavedPipeline>, String> {
+        self.store.load(workspace, name).await
+    }
+
+    pub async fn list(&self, workspace: &str) -> Result<Vec<SavedPipeline>, String> {
+        self.store.list(workspace).await
+    }
+
+    pub async fn delete(&self, workspace: &str, name: &str) -> Result<bool, String> {
+        let existing = self.store.load(workspace, name).await?;
+        let deleted = self.store.delete(workspace, name).await?;
+        if deleted {
+            self.audit("pipeline.deleted", existing.as_ref(), None).await;
+        }
+        Ok(deleted)
+    }
+
+    async fn audit(&self, action: &str, before: Option<&SavedPipeline>, after: Option<&SavedPipeline>) {
+        let (Some(audit), Some(saved)) = (&self.audit, after.or(before)) else {
+            return;
+        };
+        let entry = AuditEntry::new(&saved.workspace, action)
+            .on(format!("pipelines/{}", saved.name))
+            .changes(before.map(audited).as_ref(), after.map(audited).as_ref());
+        audit.log(&entry, None).await;
+    }
+
+    /// Run a saved pipeline with the given parameters; returns `aggregate`'s
+    /// `{data, count, truncated}`
+    pub async fn run(
+        &self,
+        workspace: &str,
+        name: &str,
+        params: HashMap<String, Value>,
+        limit: Option<u64>,
+    ) -> Result<Data, PipelineError> {
+        self.execute(workspace, name, params, limit, None).await
+    }
+
+    /// `run` for a user, in their workspace
+    pub async fn run_as(
+        &self,
+        caller: Caller,
+        name: &str,
+        params: HashMap<String, Value>,
+        limit: Option<u64>,
+    ) -> Result<Data, PipelineError> {
+        let workspace = caller.workspace.clone();
+        self.execute(&workspace, name, params, limit, Some(caller)).await
+    }
+
+    async fn execute(
+        &self,
+        workspace: &str,
+        name: &str,
+        params: HashMap<String, Value>,
+        limit: Option<u64>,
+        caller: Option<Caller>,
+    ) -> Result<Data, PipelineError> {
+        let saved = self.store.load(workspace, name).await
+            .map_err(PipelineError::Storage)?
+            .ok_or(PipelineError::NotFound)?;
+        let stages = saved.bind(&params).map_err(PipelineError::InvalidParameters)?;
+
+        let mut query = HashMap::new();
+        query.insert("collection".to_string(), Data::String(saved.collection.clone()));
+        query.insert("workspace".to_string(), Data::String(workspace.to_string()));
+        query.insert("pipeline".to_string(), Data::from_json(stages));
+        if let Some(limit) = limit {
+            query.insert("limit".to_string(), Data::Number(limit as f64));
+        }
+        let mut inputs = HashMap::new();
+        inputs.insert("query".to_string(), Data::Object(query));
+
+        info!("Running pipeline {} in workspace {}", name, workspace);
+        let run = match caller {
+            Some(caller) => self.runtime.execute_as(RUN_SCRIPT, inputs, caller).await,
+            None => self.runtime.execute_in(RUN_SCRIPT, inputs, workspace).await.map_err(ScriptError::from),
+        };
+        run.map_err(|e| {
+            error!("Pipeline {} in {} failed: {}", name, workspace, e);
+            match e.is_forbidden() {
+                true => PipelineError::Forbidden(e.message),
+                false => PipelineError::Failed(e.message),
+            }
+        })
+    }
+}
+
+/// What the audit log keeps of a saved pipeline
+fn audited(saved: &SavedPipeline) -> Document {
+    doc! {
+        "collection": &saved.collection,
+        "pipeline": mongodb::bson::to_bson(&saved.pipeline).unwrap_or_default(),
+        "parameters": mongodb::bson::to_bson(&saved.parameters).unwrap_or_default(),
+        "description": &saved.description,
+    }
+}
@@ -2,25 +2,72 @@
 //! 
 //! This is the main runtime that apps interact with
 
-use crate::{Coprocessor, Data, simple_parser::SimpleParser};
-use std::collections::HashMap;
+use crate::{Coprocessor, CoprocessorError, Data, Instruction, auth::policy::Caller, events::EventBus, simple_parser::SimpleParser};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::collections::{HashMap, VecDeque};
 use std::sync::Arc;
-use std::pin::Pin;
-use std::future::Future;
 use tokio::sync::RwLock;
-use tracing::{debug, error, info};
+use tracing::{debug, error, info, warn};
+
+/// A script that failed, with the error of the call that failed it
+#[derive(Debug, thiserror::Error)]
+#[error("{message}")]
+pub struct ScriptError {
+    pub message: String,
+    /// None when no call failed the script, or a TRY caught the call's error
+    pub cause: Option<CoprocessorError>,
+}
+
+impl ScriptError {
+    /// A call was refused to the script's caller
+    pub fn is_forbidden(&self) -> bool {
+        matches!(self.cause, Some(CoprocessorError::Forbidden(_)))
+    }
+    
+    /// A call was given invalid arguments
+    pub fn is_invalid(&self) -> bool {
+        matches!(self.cause, Some(CoprocessorError::InvalidArguments(_)))
+    }
+    
+    /// A call named something that does not exist
+    pub fn is_not_found(&self) -> bool {
+        matches!(self.cause, Some(CoprocessorError::NotFound(_)))
+    }
+}
+
+impl From<String> for ScriptError {
+    fn from(message: String) -> Self {
+        Self { message, cause: None }
+    }
+}
+
+impl From<ScriptError> for String {
+    fn from(e: ScriptError) -> Self {
+        e.message
+    }
+}
 
 pub struct SPURuntime {
     classes: Arc<RwLock<HashMap<String, Arc<dyn Coprocessor>>>>,
+    events: EventBus,
 }
 
 impl SPURuntime {
     pub fn new() -> Self {
         Self {
             classes: Arc::new(RwLock::new(HashMap::new())),
+            events: EventBus::default(),
         }
     }
     
+    /// Event bus that coprocessors publish to and triggers listen on
+    pub fn events(&self) -> &EventBus {
+        &self.events
+    }
+    
     /// Register a coprocessor class
     pub async fn register_class(&self, class_name: String, coprocessor: Arc<dyn Coprocessor>) {
         let mut classes = self.classes.write().await;
@@ -30,12 +77,75 @@ impl SPURuntime {
     
     /// Execute an assembly script
     pub async fn execute(&self, script: &str) -> Result<Data, String> {
+        self.execute_with_inputs(script, HashMap::new()).await
+    }
+    
+    /// Execute an assembly script with pre-set input variables
+    pub async fn execute_with_inputs(&self, script: &str, inputs: HashMap<String, Data>) -> Result<Data, String> {
+        Ok(self.run(script, inputs, None, None).await?)
+    }
+    
+    /// Execute an assembly script on behalf of a user: coprocessors see the
+    /// caller and apply their permissions, in the caller's workspace only. The
+    /// error says which call failed the script, if one did, so the caller can
+    /// tell a refusal from a bad request or a failure.
+    pub async fn execute_as(&self, script: &str, inputs: HashMap<String, Data>, caller: Caller) -> Result<Data, ScriptError> {
+        let workspace = caller.workspace.clone();
+        self.run(script, inputs, Some(caller), Some(workspace)).await
+    }
+    
+    /// Execute an assembly script confined to a workspace: calls get it as their
+    /// `workspace` argument, and naming another one fails the script
+    pub async fn execute_in(&self, script: &str, inputs: HashMap<String, Data>, workspace: &str) -> Result<Data, String> {
+        Ok(self.run(script, inputs, None, Some(workspace.to_string())).await?)
+    }
+    
+    async fn run(&self, script: &str, inputs: HashMap<String, Data>, caller: Option<Caller>, workspace: Option<String>) -> Result<Data, ScriptError> {
         info!("SPURuntime: Starting script execution");
         // Parse the script
         let instructions = SimpleParser::parse(script)?;
         info!("SPURuntime: Parsed {} instructions", instructions.len());
         
         // Create a new executor with registered classes
+        let mut executor = self.executor().await;
+        executor.variables.extend(inputs);
+        executor.caller = caller;
+        executor.workspace = workspace;
+        
+        // Execute instructions
+        executor.execute(instructions).await
+            .map_err(|message| ScriptError { message, cause: executor.failure.take() })
+    }
+    
+    /// Execute (or resume) a script durably
+    ///
+    /// Execution starts from `snapshot`: an empty snapshot runs the script from the
+    /// beginning, a saved one continues after its program counter. The executor state
+    /// is handed to the checkpointer around every side-effecting CALL, and SLEEP /
+    /// WAIT_EVENT suspend the script instead of blocking.
+    pub async fn execute_durable(
+        &self,
+        script: &str,
+        snapshot: ExecutorSnapshot,
+        context: DurableContext,
+    ) -> Result<ExecutionOutcome, String> {
+        let instructions = SimpleParser::parse(script)?;
+        info!("SPURuntime: Durable execution of workflow {} ({} instructions)", context.workflow_id, instructions.len());
+        
+        let mut executor = self.executor().await;
+        executor.restore(snapshot)?;
+        executor.workspace = context.caller.as_ref().map(|caller| caller.workspace.clone());
+        executor.caller = context.caller.clone();
+        executor.durable = Some(context);
+        
+        let result = executor.execute(instructions).await?;
+        match executor.suspension.take() {
+            Some((reason, snapshot)) => Ok(ExecutionOutcome::Suspended { reason, snapshot: Box::new(snapshot) }),
+            None => Ok(ExecutionOutcome::Completed(result)),
+        }
+    }
+    
+    async fn executor(&self) -> AssemblyExecutor {
         let classes = self.classes.read().await;
         let mut executor = crate::runtime::AssemblyExecutor::new();
         
@@ -44,16 +154,166 @@ impl SPURuntime {
             executor.register_class(name.clone(), coprocessor.clone());
         }
         
-        // Execute instructions
-        executor.execute(instructions).await
+        executor
+    }
+}
+
+// ================================================================================
+// DURABLE EXECUTION
+// ================================================================================
+
+/// One level of the program counter path
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Frame {
+    /// Index of the current instruction within its block
+    pub index: usize,
+    /// Which child block of the enclosing instruction is running:
+    /// the IF branch (0 = THEN, 1 = ELSE), the loop iteration or the task number
+    pub slot: usize,
+}
+
+/// A CALL that was started but whose completion was never checkpointed
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PendingCall {
+    pub object: String,
+    pub method: String,
+    pub target: String,
+    pub idempotency_key: String,
+}
+
+/// Serializable executor state
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ExecutorSnapshot {
+    /// Path to the last completed instruction (or the in-flight CALL), outermost block first
+    pub pc: Vec<Frame>,
+    pub variables: HashMap<String, Data>,
+    /// Instantiated objects: instance name -> class name
+    pub instances: HashMap<String, String>,
+    /// ASYNC handles whose results are held in `variables`
+    pub handles: Vec<String>,
+    pub pending: Option<PendingCall>,
+}
+
+impl ExecutorSnapshot {
+    /// Snapshot for a fresh run with the given input variables
+    pub fn with_inputs(inputs: HashMap<String, Data>) -> Self {
+        Self {
+            variables: inputs,
+            ..Default::default()
+        }
     }
 }
 
+/// How a CALL interrupted by a crash is treated when the script resumes
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CallPolicy {
+    /// Never invoke the call again; the script sees an error it can CATCH
+    #[default]
+    AtMostOnce,
+    /// Invoke it again with the same `idempotency_key` argument, which the
+    /// database, email and http coprocessors deduplicate on
+    Idempotent,
+}
+
+/// Why a durable script stopped before completing
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Suspension {
+    Sleep { until: DateTime<Utc> },
+    WaitEvent { event: String, target: String },
+}
+
+#[derive(Debug, Clone)]
+pub enum ExecutionOutcome {
+    Completed(Data),
+    Suspended { reason: Suspension, snapshot: Box<ExecutorSnapshot> },
+}
+
+/// Receives executor snapshots during a durable execution
+#[async_trait]
+pub trait Checkpointer: Send + Sync {
+    async fn checkpoint(&self, snapshot: &ExecutorSnapshot) -> Result<(), String>;
+}
+
+/// Settings for a durable execution
+#[derive(Clone)]
+pub struct DurableContext {
+    pub workflow_id: String,
+    pub policy: CallPolicy,
+    pub checkpointer: Arc<dyn Checkpointer>,
+    /// The user the script runs for, confined to their workspace; trusted when absent
+    pub caller: Option<Caller>,
+}
+
+/// Longest SLEEP outside a durable workflow, which holds the task meanwhile
+pub const MAX_PLAIN_SLEEP: std::time::Duration = std::time::Duration::from_secs(300);
+
+/// Parse a SLEEP duration such as `30`, `30s`, `15m`, `2h` or `1d`
+pub fn parse_duration(value: &str) -> Result<chrono::Duration, String> {
+    let trimmed = value.trim().trim_matches('"');
+    let (amount, unit) = match trimmed.find(|c: char| !c.is_ascii_digit()) {
+        Some(pos) => trimmed.split_at(pos),
+        None => (trimmed, "s"),
+    };
+    let amount: i64 = amount.parse()
+        .map_err(|_| format!("Invalid duration: {}", value))?;
+    
+    let duration = match unit {
+        "ms" => chrono::Duration::try_milliseconds(amount),
+        "s" => chrono::Duration::try_seconds(amount),
+        "m" => chrono::Duration::try_minutes(amount),
+        "h" => chrono::Duration::try_hours(amount),
+        "d" => chrono::Duration::try_days(amount),
+        _ => return Err(format!("Invalid duration unit in: {}", value)),
+    };
+    duration.ok_or_else(|| format!("Invalid duration: {}", value))
+}
+
+/// Kind of instruction block being executed
+#[derive(Clone, Copy, PartialEq)]
+enum BlockKind {
+    /// The script itself: HALT stops execution
+    Top,
+    /// IF / TRY / CATCH bodies
+    Body,
+    /// WHILE / FOREACH bodies: BREAK and CONTINUE are honoured
+    Loop,
+}
+
+/// How a loop body finished
+#[derive(PartialEq)]
+enum LoopControl {
+    Next,
+    Break,
+}
+
 /// Assembly Script Executor (internal)
 struct AssemblyExecutor {
     instances: HashMap<String, Arc<dyn Coprocessor>>,
     variables: HashMap<String, Data>,
     classes: HashMap<String, Arc<dyn Coprocessor>>,
+    /// Class name of each instance, needed to rebuild instances on resume
+    instance_classes: HashMap<String, String>,
+    handles: Vec<String>,
+    /// Program counter path, one frame per nested block
+    pc: Vec<Frame>,
+    /// Remaining frames of the path being resumed
+    resume: Option<VecDeque<Frame>>,
+    /// Interrupted CALL found in the snapshot being resumed
+    pending: Option<PendingCall>,
+    durable: Option<DurableContext>,
+    suspension: Option<(Suspension, ExecutorSnapshot)>,
+    /// Depth of PARALLEL / RACE blocks, inside which nothing is checkpointed
+    atomic_depth: usize,
+    /// Open transactions as (object, transaction id), oldest first
+    transactions: Vec<(String, String)>,
+    /// The user the script runs for, if any
+    caller: Option<Caller>,
+    /// The only workspace the script may reach, if confined
+    workspace: Option<String>,
+    /// Error of the last call that failed, until a TRY catches it
+    failure: Option<CoprocessorError>,
 }
 
 impl AssemblyExecutor {
@@ -62,6 +322,18 @@ impl AssemblyExecutor {
             instances: HashMap::new(),
             variables: HashMap::new(),
             classes: HashMap::new(),
+            instance_classes: HashMap::new(),
+            handles: Vec::new(),
+            pc: Vec::new(),
+            resume: None,
+            pending: None,
+            durable: None,
+            suspension: None,
+            atomic_depth: 0,
+            transactions: Vec::new(),
+            caller: None,
+            workspace: None,
+            failure: None,
         }
     }
     
@@ -69,49 +341,306 @@ impl AssemblyExecutor {
         self.classes.insert(class_name, coprocessor);
     }
     
-    async fn execute(&mut self, instructions: Vec<crate::Instruction>) -> Result<Data, String> {
+    async fn execute(&mut self, instructions: Vec<Instruction>) -> Result<Data, String> {
+        let outcome = self.execute_block(&instructions, 0, BlockKind::Top).await;
+        
+        // Nothing is committed without an explicit COMMIT
+        if !self.transactions.is_empty() {
+            warn!("Rolling back {} transaction(s) left open by the script", self.transactions.len());
+            self.rollback_from(0).await;
+        }
+        
+        let last_result = match outcome {
+            Ok((result, _)) => result,
+            Err(e) => {
+                error!("Execution error: {}", e);
+                return Err(e);
+            }
+        };
+        
+        // Return the "result" variable if set, otherwise last result
+        if let Some(result) = self.variables.get("result") {
+            Ok(result.clone())
+        } else {
+            Ok(last_result)
+        }
+    }
+    
+    /// Execute a block of instructions, tracking its position in the program counter
+    async fn execute_block(&mut self, block: &[Instruction], slot: usize, kind: BlockKind) -> Result<(Data, LoopControl), String> {
+        let start = self.enter_block();
+        self.pc.push(Frame { index: start, slot });
+        
         let mut last_result = Data::Null;
+        let mut control = LoopControl::Next;
         
-        for instruction in instructions {
-            // Check if this is a HALT instruction
-            if matches!(instruction, crate::Instruction::Halt) {
-                info!("HALT: Stopping execution");
-                break; // Stop executing further instructions
+        for (index, instruction) in block.iter().enumerate().skip(start) {
+            if let Some(frame) = self.pc.last_mut() {
+                frame.index = index;
+            }
+            
+            match (kind, instruction) {
+                (BlockKind::Top, Instruction::Halt) => {
+                    info!("HALT: Stopping execution");
+                    break; // Stop executing further instructions
+                }
+                (BlockKind::Loop, Instruction::Break) => {
+                    debug!("BREAK encountered");
+                    control = LoopControl::Break;
+                    break;
+                }
+                (BlockKind::Loop, Instruction::Continue) => {
+                    debug!("CONTINUE encountered");
+                    break; // Skip the rest of this iteration
+                }
+                _ => {}
             }
             
-            match self.execute_instruction(instruction).await {
+            match Box::pin(self.execute_instruction_impl(instruction.clone())).await {
                 Ok(result) => last_result = result,
                 Err(e) => {
-                    error!("Execution error: {}", e);
+                    self.pc.pop();
                     return Err(e);
                 }
             }
+            
+            if self.suspension.is_some() {
+                break;
+            }
         }
         
-        // Return the "result" variable if set, otherwise last result
-        if let Some(result) = self.variables.get("result") {
-            Ok(result.clone())
+        self.pc.pop();
+        Ok((last_result, control))
+    }
+    
+    /// Index to start a block at, consuming one frame of the path being resumed
+    fn enter_block(&mut self) -> usize {
+        let Some(resume) = self.resume.as_mut() else {
+            return 0;
+        };
+        let Some(frame) = resume.pop_front() else {
+            self.resume = None;
+            return 0;
+        };
+        
+        if !resume.is_empty() {
+            // The instruction at this index is a block we have to re-enter
+            return frame.index;
+        }
+        
+        // Innermost frame: the instruction there already completed,
+        // unless it is a CALL that was interrupted
+        self.resume = None;
+        if self.pending.is_some() {
+            frame.index
         } else {
-            Ok(last_result)
+            frame.index + 1
         }
     }
     
-    fn execute_instruction<'a>(&'a mut self, instruction: crate::Instruction) 
-        -> Pin<Box<dyn Future<Output = Result<Data, String>> + Send + 'a>> {
-        Box::pin(async move {
-            self.execute_instruction_impl(instruction).await
-        })
+    /// Slot recorded for the next block when resuming inside it
+    fn resume_slot(&self) -> Option<usize> {
+        self.resume.as_ref().and_then(|r| r.front()).map(|f| f.slot)
     }
     
-    async fn execute_instruction_impl(&mut self, instruction: crate::Instruction) -> Result<Data, String> {
-        use crate::Instruction;
+    fn restore(&mut self, snapshot: ExecutorSnapshot) -> Result<(), String> {
+        for (object_id, class_name) in &snapshot.instances {
+            let coprocessor = self.classes.get(class_name)
+                .ok_or_else(|| format!("Unknown class: {}", class_name))?;
+            self.instances.insert(object_id.clone(), coprocessor.clone());
+        }
         
+        self.instance_classes = snapshot.instances;
+        self.variables = snapshot.variables;
+        self.handles = snapshot.handles;
+        self.pending = snapshot.pending;
+        if !snapshot.pc.is_empty() {
+            self.resume = Some(snapshot.pc.into());
+        }
+        Ok(())
+    }
+    
+    fn snapshot(&self, pending: Option<PendingCall>) -> ExecutorSnapshot {
+        ExecutorSnapshot {
+            pc: self.pc.clone(),
+            variables: self.variables.clone(),
+            instances: self.instance_classes.clone(),
+            handles: self.handles.clone(),
+            pending,
+        }
+    }
+    
+    async fn checkpoint(&self, pending: Option<PendingCall>) -> Result<(), String> {
+        if self.atomic_depth > 0 {
+            return Ok(());
+        }
+        if let Some(durable) = &self.durable {
+            durable.checkpointer.checkpoint(&self.snapshot(pending)).await?;
+        }
+        Ok(())
+    }
+    
+    fn suspend(&mut self, reason: Suspension) -> Result<Data, String> {
+        if self.durable.is_none() {
+            return Err("WAIT_EVENT requires durable execution".to_string());
+        }
+        if self.atomic_depth > 0 {
+            return Err("Cannot suspend inside PARALLEL or RACE".to_string());
+        }
+        info!("Suspending durable execution: {:?}", reason);
+        let snapshot = self.snapshot(None);
+        self.suspension = Some((reason, snapshot));
+        Ok(Data::Null)
+    }
+    
+    /// Invoke a coprocessor method, checkpointing around it in durable mode
+    ///
+    /// Calls on an object with an open transaction join it, unless their arguments
+    /// name a transaction themselves. Scripts confined to a workspace call in it.
+    async fn invoke_method(&mut self, object: &str, method: &str, args: Data, target: &str) -> Result<Data, String> {
+        let coprocessor = self.instances.get(object)
+            .cloned()
+            .ok_or_else(|| format!("Unknown object: {}", object))?;
+        
+        let mut args = args;
+        if let (Some(workspace), Data::Object(obj)) = (&self.workspace, &mut args) {
+            match obj.get("workspace") {
+                Some(Data::String(named)) if named != workspace => {
+                    let refused = CoprocessorError::Forbidden(format!(
+                        "scripts of workspace '{}' may not reach workspace '{}'",
+                        workspace, named
+                    ));
+                    let message = refused.to_string();
+                    self.failure = Some(refused);
+                    return Err(message);
+                }
+                _ => {
+                    obj.insert("workspace".to_string(), Data::String(workspace.clone()));
+                }
+            }
+        }
+        if let (Some(transaction), Data::Object(obj)) = (self.transaction_of(object), &mut args) {
+            obj.entry("transaction".to_string())
+                .or_insert_with(|| Data::String(transaction.to_string()));
+        }
+        
+        let Some(durable) = self.durable.clone() else {
+            let result = coprocessor.invoke_as(method, args, self.caller.as_ref()).await;
+            return result.map_err(|e| self.failed(e));
+        };
+        
+        let path: Vec<String> = self.pc.iter()
+            .map(|f| format!("{}.{}", f.slot, f.index))
+            .collect();
+        let pending = PendingCall {
+            object: object.to_string(),
+            method: method.to_string(),
+            target: target.to_string(),
+            idempotency_key: format!("{}:{}", durable.workflow_id, path.join("/")),
+        };
+        
+        if let Some(interrupted) = self.pending.take() {
+            if durable.policy == CallPolicy::AtMostOnce {
+                return Err(format!(
+                    "Call {}.{} was interrupted and is not retried (at-most-once)",
+                    interrupted.object, interrupted.method
+                ));
+            }
+            info!("Retrying interrupted call {}.{} with key {}", object, method, pending.idempotency_key);
+        }
+        if durable.policy == CallPolicy::Idempotent {
+            if let Data::Object(ref mut obj) = args {
+                obj.insert("idempotency_key".to_string(), Data::String(pending.idempotency_key.clone()));
+            }
+        }
+        
+        self.checkpoint(Some(pending)).await?;
+        let result = coprocessor.invoke_as(method, args, self.caller.as_ref()).await;
+        let result = result.map_err(|e| self.failed(e))?;
+        self.variables.insert(target.to_string(), result.clone());
+        self.checkpoint(None).await?;
+        
+        Ok(result)
+    }
+    
+    /// Keep the error of a failed call for `ScriptError`, returning its message
+    fn failed(&mut self, e: CoprocessorError) -> String {
+        let message = format!("Method call failed: {}", e);
+        self.failure = Some(e);
+        message
+    }
+    
+    fn transaction_of(&self, object: &str) -> Option<&str> {
+        self.transactions.iter()
+            .find(|(owner, _)| owner == object)
+            .map(|(_, id)| id.as_str())
+    }
+    
+    /// Start a transaction on an object, whose later calls then run in it
+    async fn begin(&mut self, object: &str) -> Result<Data, String> {
+        if self.durable.is_some() {
+            return Err("Transactions are not supported in durable execution".to_string());
+        }
+        if self.transaction_of(object).is_some() {
+            return Err(format!("A transaction is already open on {}", object));
+        }
+        let coprocessor = self.instances.get(object)
+            .cloned()
+            .ok_or_else(|| format!("Unknown object: {}", object))?;
+        
+        let begun = coprocessor.invoke("begin", Data::Null).await
+            .map_err(|e| format!("BEGIN {} failed: {}", object, e))?;
+        let id = match &begun {
+            Data::Object(obj) => match obj.get("transaction") {
+                Some(Data::String(id)) => id.clone(),
+                _ => return Err(format!("BEGIN {}: no transaction returned", object)),
+            },
+            _ => return Err(format!("BEGIN {}: no transaction returned", object)),
+        };
+        
+        info!("Began transaction {} on {}", id, object);
+        self.transactions.push((object.to_string(), id.clone()));
+        Ok(Data::String(id))
+    }
+    
+    /// Commit or roll back the transaction open on an object
+    async fn end_transaction(&mut self, object: &str, commit: bool) -> Result<Data, String> {
+        let position = self.transactions.iter()
+            .position(|(owner, _)| owner == object)
+            .ok_or_else(|| format!("No transaction open on {}", object))?;
+        let (_, id) = self.transactions.remove(position);
+        let coprocessor = self.instances.get(object)
+            .cloned()
+            .ok_or_else(|| format!("Unknown object: {}", object))?;
+        
+        let (method, verb) = if commit { ("commit", "COMMIT") } else { ("abort", "ROLLBACK") };
+        let mut args = HashMap::new();
+        args.insert("transaction".to_string(), Data::String(id.clone()));
+        coprocessor.invoke(method, Data::Object(args)).await
+            .map_err(|e| format!("{} {} failed: {}", verb, object, e))?;
+        
+        info!("{} of transaction {} on {}", verb, id, object);
+        Ok(Data::Null)
+    }
+    
+    /// Roll back the transactions opened after the first `keep`, newest first
+    async fn rollback_from(&mut self, keep: usize) {
+        while self.transactions.len() > keep {
+            let Some((object, _)) = self.transactions.last().cloned() else { break };
+            if let Err(e) = self.end_transaction(&object, false).await {
+                warn!("{}", e);
+            }
+        }
+    }
+    
+    async fn execute_instruction_impl(&mut self, instruction: Instruction) -> Result<Data, String> {
         match instruction {
             Instruction::Instantiate { class_name, object_id } => {
                 debug!("INSTANTIATE {} as {}", class_name, object_id);
                 
                 if let Some(coprocessor) = self.classes.get(&class_name) {
                     self.instances.insert(object_id.clone(), coprocessor.clone());
+                    self.instance_classes.insert(object_id.clone(), class_name.clone());
                     info!("Instantiated {} as {}", class_name, object_id);
                     Ok(Data::String(object_id))
                 } else {
@@ -123,19 +652,11 @@ impl AssemblyExecutor {
                 debug!("CALL {}.{} with args: {:?}", object, method, args);
                 
                 let resolved_args = self.resolve_data(args)?;
+                let result = self.invoke_method(&object, &method, resolved_args, &target).await?;
                 
-                if let Some(coprocessor) = self.instances.get(&object) {
-                    match coprocessor.invoke(&method, resolved_args).await {
-                        Ok(result) => {
-                            info!("Called {}.{} -> stored in {}", object, method, target);
-                            self.variables.insert(target, result.clone());
-                            Ok(result)
-                        }
-                        Err(e) => Err(format!("Method call failed: {}", e))
-                    }
-                } else {
-                    Err(format!("Unknown object: {}", object))
-                }
+                info!("Called {}.{} -> stored in {}", object, method, target);
+                self.variables.insert(target, result.clone());
+                Ok(result)
             }
             
             Instruction::Set { variable, value } => {
@@ -205,21 +726,21 @@ impl AssemblyExecutor {
             
             Instruction::Try { instructions } => {
                 debug!("TRY block with {} instructions", instructions.len());
+                let open = self.transactions.len();
                 
                 // Execute instructions, catching any errors
-                let mut last_result = Data::Null;
-                for instr in instructions {
-                    match Box::pin(self.execute_instruction_impl(instr)).await {
-                        Ok(result) => last_result = result,
-                        Err(e) => {
-                            // Store error for potential CATCH block
-                            self.variables.insert("_error".to_string(), Data::String(e.clone()));
-                            debug!("Error in TRY block: {}", e);
-                            return Ok(Data::String(format!("Error: {}", e)));
-                        }
+                match self.execute_block(&instructions, 0, BlockKind::Body).await {
+                    Ok((last_result, _)) => Ok(last_result),
+                    Err(e) => {
+                        // Nothing written by transactions begun in the block survives it
+                        self.rollback_from(open).await;
+                        self.failure = None;
+                        // Store error for potential CATCH block
+                        self.variables.insert("_error".to_string(), Data::String(e.clone()));
+                        debug!("Error in TRY block: {}", e);
+                        Ok(Data::String(format!("Error: {}", e)))
                     }
                 }
-                Ok(last_result)
             }
             
             Instruction::Catch { error_type, handler } => {
@@ -230,14 +751,10 @@ impl AssemblyExecutor {
                     info!("Handling error: {:?}", error);
                     
                     // Execute handler instructions
-                    let mut last_result = Data::Null;
-                    for instr in handler {
-                        match Box::pin(self.execute_instruction_impl(instr)).await {
-                            Ok(result) => last_result = result,
-                            Err(e) => return Err(format!("Error in CATCH handler: {}", e))
-                        }
+                    match self.execute_block(&handler, 0, BlockKind::Body).await {
+                        Ok((last_result, _)) => Ok(last_result),
+                        Err(e) => Err(format!("Error in CATCH handler: {}", e))
                     }
-                    Ok(last_result)
                 } else {
                     // No error to catch
                     Ok(Data::Null)
@@ -294,22 +811,18 @@ impl AssemblyExecutor {
             
             Instruction::If { condition, then_branch, else_branch } => {
                 debug!("IF {}", condition);
-                let cond_result = self.evaluate_condition(&condition)?;
+                // When resuming inside the IF, take the branch recorded in the snapshot
+                let cond_result = match self.resume_slot() {
+                    Some(slot) => slot == 0,
+                    None => self.evaluate_condition(&condition)?,
+                };
                 
                 if cond_result {
                     debug!("Executing THEN branch");
-                    let mut last_result = Data::Null;
-                    for instr in then_branch {
-                        last_result = Box::pin(self.execute_instruction_impl(instr)).await?;
-                    }
-                    Ok(last_result)
+                    Ok(self.execute_block(&then_branch, 0, BlockKind::Body).await?.0)
                 } else if let Some(else_instructions) = else_branch {
                     debug!("Executing ELSE branch");
-                    let mut last_result = Data::Null;
-                    for instr in else_instructions {
-                        last_result = Box::pin(self.execute_instruction_impl(instr)).await?;
-                    }
-                    Ok(last_result)
+                    Ok(self.execute_block(&else_instructions, 1, BlockKind::Body).await?.0)
                 } else {
                     Ok(Data::Null)
                 }
@@ -321,22 +834,22 @@ impl AssemblyExecutor {
                 let mut iteration = 0;
                 const MAX_ITERATIONS: usize = 10000; // Safety limit
                 
-                while self.evaluate_condition(&condition)? && iteration < MAX_ITERATIONS {
+                // When resuming inside the body, finish that iteration before re-checking the condition
+                let mut resuming = match self.resume_slot() {
+                    Some(slot) => {
+                        iteration = slot;
+                        true
+                    }
+                    None => false,
+                };
+                
+                while (resuming || self.evaluate_condition(&condition)?) && iteration < MAX_ITERATIONS {
+                    resuming = false;
                     debug!("While iteration {}", iteration);
-                    for instr in body.clone() {
-                        match instr {
-                            Instruction::Break => {
-                                debug!("BREAK encountered");
-                                return Ok(last_result);
-                            }
-                            Instruction::Continue => {
-                                debug!("CONTINUE encountered");
-                                break; // Break inner loop, continue outer
-                            }
-                            _ => {
-                                last_result = Box::pin(self.execute_instruction_impl(instr)).await?;
-                            }
-                        }
+                    let (result, control) = self.execute_block(&body, iteration, BlockKind::Loop).await?;
+                    last_result = result;
+                    if control == LoopControl::Break || self.suspension.is_some() {
+                        return Ok(last_result);
                     }
                     iteration += 1;
                 }
@@ -365,23 +878,22 @@ impl AssemblyExecutor {
                 };
                 
                 let mut last_result = Data::Null;
-                for item_value in items {
-                    self.variables.insert(item.clone(), item_value);
+                // When resuming inside the body, continue from the recorded item;
+                // the item variable itself comes back with the snapshot
+                let (first, resuming) = match self.resume_slot() {
+                    Some(slot) => (slot, true),
+                    None => (0, false),
+                };
+                
+                for (iteration, item_value) in items.into_iter().enumerate().skip(first) {
+                    if !(resuming && iteration == first) {
+                        self.variables.insert(item.clone(), item_value);
+                    }
                     
-                    for instr in body.clone() {
-                        match instr {
-                            Instruction::Break => {
-                                debug!("BREAK in FOREACH");
-                                return Ok(last_result);
-                            }
-                            Instruction::Continue => {
-                                debug!("CONTINUE in FOREACH");
-                                break; // Skip to next item
-                            }
-                            _ => {
-                                last_result = Box::pin(self.execute_instruction_impl(instr)).await?;
-                            }
-                        }
+                    let (result, control) = self.execute_block(&body, iteration, BlockKind::Loop).await?;
+                    last_result = result;
+                    if control == LoopControl::Break || self.suspension.is_some() {
+                        return Ok(last_result);
                     }
                 }
                 
@@ -404,18 +916,15 @@ impl AssemblyExecutor {
                 // For now, just execute synchronously and store handle
                 // Real async implementation would spawn a task
                 let resolved_args = self.resolve_data(args)?;
+                self.handles.push(handle.clone());
                 
-                if let Some(coprocessor) = self.instances.get(&object) {
-                    match coprocessor.invoke(&method, resolved_args).await {
-                        Ok(result) => {
-                            self.variables.insert(handle.clone(), result.clone());
-                            info!("Async call stored in handle {}", handle);
-                            Ok(Data::String(handle))
-                        }
-                        Err(e) => Err(format!("Async method call failed: {}", e))
+                match self.invoke_method(&object, &method, resolved_args, &handle).await {
+                    Ok(result) => {
+                        self.variables.insert(handle.clone(), result);
+                        info!("Async call stored in handle {}", handle);
+                        Ok(Data::String(handle))
                     }
-                } else {
-                    Err(format!("Unknown object: {}", object))
+                    Err(e) => Err(format!("Async method call failed: {}", e))
                 }
             }
             
@@ -461,6 +970,7 @@ impl AssemblyExecutor {
                     Data::Array(ref arr) => arr.len(),
                     Data::String(ref s) => s.len(),
                     Data::Object(ref obj) => obj.len(),
+                    Data::Bytes(ref bytes) => bytes.len(),
                     _ => 0
                 };
                 
@@ -477,17 +987,22 @@ impl AssemblyExecutor {
                 // Real implementation would use tokio::join!
                 let mut results = Vec::new();
                 
+                self.atomic_depth += 1;
                 for (i, task_instructions) in tasks.iter().enumerate() {
                     debug!("Executing parallel task {}", i);
-                    let mut last_result = Data::Null;
-                    for instr in task_instructions {
-                        last_result = Box::pin(self.execute_instruction_impl(instr.clone())).await?;
+                    match self.execute_block(task_instructions, i, BlockKind::Body).await {
+                        Ok((last_result, _)) => results.push(last_result),
+                        Err(e) => {
+                            self.atomic_depth -= 1;
+                            return Err(e);
+                        }
                     }
-                    results.push(last_result);
                 }
+                self.atomic_depth -= 1;
                 
                 let result_data = Data::Array(results);
                 self.variables.insert(target.clone(), result_data.clone());
+                self.checkpoint(None).await?;
                 info!("Parallel execution complete -> {}", target);
                 Ok(result_data)
             }
@@ -497,11 +1012,13 @@ impl AssemblyExecutor {
                 // Simplified: just execute first task
                 // Real implementation would use tokio::select!
                 if let Some(first_task) = tasks.first() {
-                    let mut last_result = Data::Null;
-                    for instr in first_task {
-                        last_result = Box::pin(self.execute_instruction_impl(instr.clone())).await?;
-                    }
+                    self.atomic_depth += 1;
+                    let outcome = self.execute_block(first_task, 0, BlockKind::Body).await;
+                    self.atomic_depth -= 1;
+                    let last_result = outcome?.0;
+                    
                     self.variables.insert(target.clone(), last_result.clone());
+                    self.checkpoint(None).await?;
                     info!("Race winner stored in {}", target);
                     Ok(last_result)
                 } else {
@@ -509,6 +1026,54 @@ impl AssemblyExecutor {
                 }
             }
             
+            Instruction::Sleep { duration } => {
+                debug!("SLEEP {}", duration);
+                let resolved = match self.resolve_data(Data::String(duration.clone()))? {
+                    Data::Number(n) => format!("{}", n as i64),
+                    Data::Integer(i) => i.to_string(),
+                    Data::String(s) => s,
+                    other => return Err(format!("Invalid duration: {:?}", other)),
+                };
+                let duration = parse_duration(&resolved)?;
+                
+                if self.durable.is_some() {
+                    let until = Utc::now().checked_add_signed(duration)
+                        .ok_or_else(|| format!("Invalid duration: {}", resolved))?;
+                    self.suspend(Suspension::Sleep { until })
+                } else {
+                    // Plain executions just block the task, so not for long
+                    let duration = duration.to_std().unwrap_or_default();
+                    if duration > MAX_PLAIN_SLEEP {
+                        return Err(format!(
+                            "SLEEP {} is longer than {}s; run the script as a workflow to sleep longer",
+                            resolved, MAX_PLAIN_SLEEP.as_secs()
+                        ));
+                    }
+                    tokio::time::sleep(duration).await;
+                    Ok(Data::Null)
+                }
+            }
+            
+            Instruction::WaitEvent { event, target } => {
+                debug!("WAIT_EVENT {} -> {}", event, target);
+                self.suspend(Suspension::WaitEvent { event, target })
+            }
+            
+            Instruction::Begin { object } => {
+                debug!("BEGIN {}", object);
+                self.begin(&object).await
+            }
+            
+            Instruction::Commit { object } => {
+                debug!("COMMIT {}", object);
+                self.end_transaction(&object, true).await
+            }
+            
+            Instruction::Rollback { object } => {
+                debug!("ROLLBACK {}", object);
+                self.end_transaction(&object, false).await
+            }
+            
             Instruction::GetMethods { object, target } => {
                 debug!("GET_METHODS {} -> {}", object, target);
                 
@@ -597,6 +1162,9 @@ impl AssemblyExecutor {
                                     let replacement = match var_value {
                                         Data::String(s) => s,
                                         Data::Number(n) => n.to_string(),
+                                        Data::Integer(i) => i.to_string(),
+                                        Data::DateTime(dt) => dt.to_rfc3339(),
+                                        Data::Id(id) => id.to_hex(),
                                         Data::Bool(b) => b.to_string(),
                                         _ => format!("{:?}", var_value),
                                     };
@@ -721,6 +1289,7 @@ impl AssemblyExecutor {
                     Data::Bool(b) => b,
                     Data::Null => false,
                     Data::Number(n) => n != 0.0,
+                    Data::Integer(i) => i != 0,
                     Data::String(ref s) => !s.is_empty(),
                     _ => true
                 });
@@ -737,10 +1306,10 @@ impl AssemblyExecutor {
         match op {
             "==" => Ok(self.values_equal(&left_value, &right_value)),
             "!=" => Ok(!self.values_equal(&left_value, &right_value)),
-            ">" => self.compare_numeric(&left_value, &right_value, |a, b| a > b),
-            "<" => self.compare_numeric(&left_value, &right_value, |a, b| a < b),
-            ">=" => self.compare_numeric(&left_value, &right_value, |a, b| a >= b),
-            "<=" => self.compare_numeric(&left_value, &right_value, |a, b| a <= b),
+            ">" => self.compare_numeric(&left_value, &right_value, Ordering::is_gt),
+            "<" => self.compare_numeric(&left_value, &right_value, Ordering::is_lt),
+            ">=" => self.compare_numeric(&left_value, &right_value, Ordering::is_ge),
+            "<=" => self.compare_numeric(&left_value, &right_value, Ordering::is_le),
             _ => Err(format!("Unknown operator: {}", op))
         }
     }
@@ -783,15 +1352,37 @@ impl AssemblyExecutor {
             (Data::Bool(a), Data::Bool(b)) => a == b,
             (Data::Number(a), Data::Number(b)) => (a - b).abs() < 0.0001,
             (Data::String(a), Data::String(b)) => a == b,
+            (Data::Integer(a), Data::Integer(b)) => a == b,
+            (Data::Integer(i), Data::Number(n)) | (Data::Number(n), Data::Integer(i)) => (*i as f64 - n).abs() < 0.0001,
+            (Data::Bytes(a), Data::Bytes(b)) => a == b,
+            (Data::Id(a), Data::Id(b)) => a == b,
+            // Ids and dates compare with their string forms, as written in scripts
+            (Data::Id(id), Data::String(s)) | (Data::String(s), Data::Id(id)) => id.to_hex() == *s,
+            (Data::DateTime(_), _) | (_, Data::DateTime(_)) => {
+                self.compare_numeric(left, right, Ordering::is_eq).unwrap_or(false)
+            }
             _ => false
         }
     }
     
-    fn compare_numeric(&self, left: &Data, right: &Data, op: fn(f64, f64) -> bool) -> Result<bool, String> {
-        match (left, right) {
-            (Data::Number(a), Data::Number(b)) => Ok(op(*a, *b)),
-            _ => Err("Numeric comparison requires numbers".to_string())
-        }
+    fn compare_numeric(&self, left: &Data, right: &Data, op: fn(Ordering) -> bool) -> Result<bool, String> {
+        let parse_date = |s: &str| DateTime::parse_from_rfc3339(s)
+            .map(|dt| dt.with_timezone(&Utc))
+            .map_err(|_| format!("Cannot compare a date with '{}'", s));
+        
+        let ordering = match (left, right) {
+            (Data::Number(a), Data::Number(b)) => a.partial_cmp(b),
+            (Data::Integer(a), Data::Integer(b)) => Some(a.cmp(b)),
+            (Data::Integer(a), Data::Number(b)) => (*a as f64).partial_cmp(b),
+            (Data::Number(a), Data::Integer(b)) => a.partial_cmp(&(*b as f64)),
+            (Data::DateTime(a), Data::DateTime(b)) => Some(a.cmp(b)),
+            (Data::DateTime(a), Data::String(b)) => Some(a.cmp(&parse_date(b)?)),
+            (Data::String(a), Data::DateTime(b)) => Some(parse_date(a)?.cmp(b)),
+            _ => return Err("Numeric comparison requires numbers".to_string())
+        };
+        
+        // NaN compares false with everything
+        Ok(ordering.is_some_and(op))
     }
     
     fn evaluate_expression(&self, expression: &str) -> Result<Data, String> {
@@ -813,8 +1404,12 @@ impl AssemblyExecutor {
             let left = self.parse_value(&expr[..pos])?;
             let right = self.parse_value(&expr[pos+3..])?;
             
+            if let Some((a, b)) = integer_operands(&left, &right) {
+                return a.checked_add(b).map(Data::Integer).ok_or_else(|| "Integer overflow".to_string());
+            }
             match (left, right) {
                 (Data::Number(a), Data::Number(b)) => Ok(Data::Number(a + b)),
+                (Data::Integer(a), Data::Number(b)) | (Data::Number(b), Data::Integer(a)) => Ok(Data::Number(a as f64 + b)),
                 (Data::String(a), Data::String(b)) => Ok(Data::String(format!("{}{}", a, b))),
                 _ => Err("Invalid operands for +".to_string())
             }
@@ -822,23 +1417,37 @@ impl AssemblyExecutor {
             let left = self.parse_value(&expr[..pos])?;
             let right = self.parse_value(&expr[pos+3..])?;
             
+            if let Some((a, b)) = integer_operands(&left, &right) {
+                return a.checked_sub(b).map(Data::Integer).ok_or_else(|| "Integer overflow".to_string());
+            }
             match (left, right) {
                 (Data::Number(a), Data::Number(b)) => Ok(Data::Number(a - b)),
+                (Data::Integer(a), Data::Number(b)) => Ok(Data::Number(a as f64 - b)),
+                (Data::Number(a), Data::Integer(b)) => Ok(Data::Number(a - b as f64)),
                 _ => Err("Subtraction requires numbers".to_string())
             }
         } else if let Some(pos) = expr.find(" * ") {
             let left = self.parse_value(&expr[..pos])?;
             let right = self.parse_value(&expr[pos+3..])?;
             
+            if let Some((a, b)) = integer_operands(&left, &right) {
+                return a.checked_mul(b).map(Data::Integer).ok_or_else(|| "Integer overflow".to_string());
+            }
             match (left, right) {
                 (Data::Number(a), Data::Number(b)) => Ok(Data::Number(a * b)),
+                (Data::Integer(a), Data::Number(b)) | (Data::Number(b), Data::Integer(a)) => Ok(Data::Number(a as f64 * b)),
                 _ => Err("Multiplication requires numbers".to_string())
             }
         } else if let Some(pos) = expr.find(" / ") {
             let left = self.parse_value(&expr[..pos])?;
             let right = self.parse_value(&expr[pos+3..])?;
             
-            match (left, right) {
+            // Division always gives a float
+            let as_float = |d: Data| match d {
+                Data::Integer(i) => Data::Number(i as f64),
+                other => other,
+            };
+            match (as_float(left), as_float(right)) {
                 (Data::Number(a), Data::Number(b)) if b != 0.0 => Ok(Data::Number(a / b)),
                 (Data::Number(_), Data::Number(_)) => Err("Division by zero".to_string()),
                 _ => Err("Division requires numbers".to_string())
@@ -854,4 +1463,18 @@ impl AssemblyExecutor {
             }
         }
     }
-}
\ No newline at end of file
+}
+
+/// Integer arithmetic applies when one side is an integer and the other is
+/// whole, so that `$count + 1` stays an integer
+fn integer_operands(left: &Data, right: &Data) -> Option<(i64, i64)> {
+    let whole = |d: &Data| match d {
+        Data::Integer(i) => Some(*i),
+        Data::Number(n) if n.fract() == 0.0 && n.abs() < 9.0e15 => Some(*n as i64),
+        _ => None,
+    };
+    match (left, right) {
+        (Data::Integer(_), _) | (_, Data::Integer(_)) => Some((whole(left)?, whole(right)?)),
+        _ => None,
+    }
+}
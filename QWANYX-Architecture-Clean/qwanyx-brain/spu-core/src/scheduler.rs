@@ -0,0 +1,647 @@
+//! Scheduled Scripts
+//!
+//! Named SPU scripts fired by cron expressions in a given timezone. A background
+//! task checks for due schedules and the outcome of every run is recorded. Each
+//! occurrence is claimed in the store, so only one server fires it, and a
+//! schedule never runs twice at the same time in one process; a run outlasting
+//! the next occurrence may still overlap with it on another server. Schedules
+//! of a workspace run confined to it and are named within it; the others are
+//! the server's own. With an audit log (`with_audit`), saving and deleting the
+//! schedules of a workspace is logged.
+
+use crate::audit::{self, AuditEntry, AuditLog};
+use crate::runtime::SPURuntime;
+use crate::workflow::bson_datetime;
+use crate::Data;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use chrono_tz::Tz;
+use mongodb::{Client as MongoClient, Collection, bson::{doc, Document}};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::sync::Mutex;
+use tokio::sync::RwLock;
+use tracing::{error, info, warn};
+use uuid::Uuid;
+
+/// A named script and when to run it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Schedule {
+    pub name: String,
+    /// Cron expression, 5 fields (minute precision) or 6-7 fields (with seconds)
+    pub cron: String,
+    /// IANA timezone the expression is evaluated in
+    pub timezone: String,
+    pub script: String,
+    /// Workspace the script is confined to; the server's own schedule when absent
+    #[serde(default)]
+    pub workspace: Option<String>,
+    pub enabled: bool,
+    pub next_run: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+impl Schedule {
+    /// Next fire time strictly after `after`
+    pub fn next_after(&self, after: DateTime<Utc>) -> Result<Option<DateTime<Utc>>, String> {
+        let cron = parse_cron(&self.cron)?;
+        let timezone = parse_timezone(&self.timezone)?;
+        Ok(cron.after(&after.with_timezone(&timezone)).next().map(|t| t.with_timezone(&Utc)))
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "name": self.name,
+            "cron": self.cron,
+            "timezone": self.timezone,
+            "script": self.script,
+            "workspace": self.workspace,
+            "enabled": self.enabled,
+            "next_run": self.next_run.map(|t| t.to_rfc3339()),
+            "created_at": self.created_at.to_rfc3339(),
+            "updated_at": self.updated_at.to_rfc3339(),
+        })
+    }
+}
+
+/// Parse a cron expression; 5-field expressions fire at second 0
+pub fn parse_cron(expression: &str) -> Result<cron::Schedule, String> {
+    let fields = expression.split_whitespace().count();
+    let expression = match fields {
+        5 => format!("0 {}", expression.trim()),
+        6 | 7 => expression.trim().to_string(),
+        _ => return Err(format!("Invalid cron expression '{}': expected 5 to 7 fields", expression)),
+    };
+    cron::Schedule::from_str(&expression)
+        .map_err(|e| format!("Invalid cron expression '{}': {}", expression, e))
+}
+
+pub fn parse_timezone(timezone: &str) -> Result<Tz, String> {
+    Tz::from_str(timezone).map_err(|_| format!("Unknown timezone: {}", timezone))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RunStatus {
+    Succeeded,
+    Failed,
+    /// The previous run was still going when this one was due
+    Skipped,
+}
+
+impl RunStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RunStatus::Succeeded => "succeeded",
+            RunStatus::Failed => "failed",
+            RunStatus::Skipped => "skipped",
+        }
+    }
+}
+
+/// Outcome of one run of a schedule
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScheduleRun {
+    pub id: String,
+    pub schedule: String,
+    /// Workspace of the schedule; the server's own when absent
+    #[serde(default)]
+    pub workspace: Option<String>,
+    pub status: RunStatus,
+    pub result: Option<Data>,
+    pub error: Option<String>,
+    pub started_at: DateTime<Utc>,
+    pub finished_at: DateTime<Utc>,
+}
+
+impl ScheduleRun {
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "id": self.id,
+            "schedule": self.schedule,
+            "workspace": self.workspace,
+            "status": self.status.as_str(),
+            "result": self.result.as_ref().map(|r| r.to_plain_json()),
+            "error": self.error,
+            "started_at": self.started_at.to_rfc3339(),
+            "finished_at": self.finished_at.to_rfc3339(),
+        })
+    }
+}
+
+// ================================================================================
+// STORAGE
+// ================================================================================
+
+/// Persistence for schedules and their run history, keyed by workspace and name;
+/// the server's own schedules have no workspace
+#[async_trait]
+pub trait ScheduleStore: Send + Sync {
+    async fn save(&self, schedule: &Schedule) -> Result<(), String>;
+
+    async fn load(&self, workspace: Option<&str>, name: &str) -> Result<Option<Schedule>, String>;
+
+    /// Every schedule, of all workspaces and the server
+    async fn list(&self) -> Result<Vec<Schedule>, String>;
+
+    /// Returns false if there was no such schedule
+    async fn delete(&self, workspace: Option<&str>, name: &str) -> Result<bool, String>;
+
+    /// Atomically move `next_run` from `due` to `next`; false if another worker already did
+    async fn claim(&self, workspace: Option<&str>, name: &str, due: DateTime<Utc>, next: Option<DateTime<Utc>>) -> Result<bool, String>;
+
+    async fn record_run(&self, run: &ScheduleRun) -> Result<(), String>;
+
+    /// Most recent runs of a schedule, newest first
+    async fn runs(&self, workspace: Option<&str>, name: &str, limit: usize) -> Result<Vec<ScheduleRun>, String>;
+}
+
+fn key(workspace: Option<&str>, name: &str) -> (Option<String>, String) {
+    (workspace.map(str::to_string), name.to_string())
+}
+
+/// In-memory store, for tests and local development
+#[derive(Default)]
+pub struct MemoryScheduleStore {
+    schedules: RwLock<HashMap<(Option<String>, String), Schedule>>,
+    runs: RwLock<Vec<ScheduleRun>>,
+}
+
+impl MemoryScheduleStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[async_trait]
+impl ScheduleStore for MemoryScheduleStore {
+    async fn save(&self, schedule: &Schedule) -> Result<(), String> {
+        let mut schedules = self.schedules.write().await;
+        schedules.insert((schedule.workspace.clone(), schedule.name.clone()), schedule.clone());
+        Ok(())
+    }
+
+    async fn load(&self, workspace: Option<&str>, name: &str) -> Result<Option<Schedule>, String> {
+        let schedules = self.schedules.read().await;
+        Ok(schedules.get(&key(workspace, name)).cloned())
+    }
+
+    async fn list(&self) -> Result<Vec<Schedule>, String> {
+        let schedules = self.schedules.read().await;
+        let mut list: Vec<Schedule> = schedules.values().cloned().collect();
+        list.sort_by(|a, b| (&a.workspace, &a.name).cmp(&(&b.workspace, &b.name)));
+        Ok(list)
+    }
+
+    async fn delete(&self, workspace: Option<&str>, name: &str) -> Result<bool, String> {
+        let mut schedules = self.schedules.write().await;
+        Ok(schedules.remove(&key(workspace, name)).is_some())
+    }
+
+    async fn claim(&self, workspace: Option<&str>, name: &str, due: DateTime<Utc>, next: Option<DateTime<Utc>>) -> Result<bool, String> {
+        let mut schedules = self.schedules.write().await;
+        match schedules.get_mut(&key(workspace, name)) {
+            Some(schedule) if schedule.next_run == Some(due) => {
+                schedule.next_run = next;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    async fn record_run(&self, run: &ScheduleRun) -> Result<(), String> {
+        let mut runs = self.runs.write().await;
+        runs.push(run.clone());
+        Ok(())
+    }
+
+    async fn runs(&self, workspace: Option<&str>, name: &str, limit: usize) -> Result<Vec<ScheduleRun>, String> {
+        let runs = self.runs.read().await;
+        Ok(runs.iter()
+            .rev()
+            .filter(|r| r.workspace.as_deref() == workspace && r.schedule == name)
+            .take(limit)
+            .cloned()
+            .collect())
+    }
+}
+
+/// MongoDB store
+///
+/// Schedules are keyed by `{workspace, name}` in `schedules`, runs go to
+/// `schedule_runs`. As with workflows, queryable fields are top-level and the full
+/// record is JSON in `record`.
+pub struct MongoScheduleStore {
+    schedules: Collection<Document>,
+    runs: Collection<Document>,
+}
+
+impl MongoScheduleStore {
+    /// In the `SPU_SYSTEM_DB` database (default `spu_system`)
+    pub fn new(client: MongoClient) -> Self {
+        let database = std::env::var("SPU_SYSTEM_DB")
+            .unwrap_or_else(|_| "spu_system".to_string());
+        let database = client.database(&database);
+
+        Self {
+            schedules: database.collection("schedules"),
+            runs: database.collection("schedule_runs"),
+        }
+    }
+
+    fn id(workspace: Option<&str>, name: &str) -> Document {
+        doc! { "workspace": workspace, "name": name }
+    }
+
+    fn from_document(document: &Document) -> Result<Schedule, String> {
+        let json = document.get_str("record")
+            .map_err(|e| format!("Corrupt schedule document: {}", e))?;
+        let mut schedule: Schedule = serde_json::from_str(json)
+            .map_err(|e| format!("Corrupt schedule document: {}", e))?;
+
+        // next_run is updated on its own by claim()
+        schedule.next_run = document.get_datetime("next_run")
+            .ok()
+            .and_then(|t| DateTime::from_timestamp_millis(t.timestamp_millis()));
+        Ok(schedule)
+    }
+}
+
+#[async_trait]
+impl ScheduleStore for MongoScheduleStore {
+    async fn save(&self, schedule: &Schedule) -> Result<(), String> {
+        let json = serde_json::to_string(schedule)
+            .map_err(|e| format!("Failed to serialize schedule: {}", e))?;
+        let id = Self::id(schedule.workspace.as_deref(), &schedule.name);
+        let document = doc! {
+            "_id": id.clone(),
+            "enabled": schedule.enabled,
+            "next_run": schedule.next_run.map(bson_datetime),
+            "record": json,
+        };
+
+        let options = mongodb::options::ReplaceOptions::builder().upsert(true).build();
+        self.schedules.replace_one(doc! { "_id": id }, document, options).await
+            .map_err(|e| format!("Failed to save schedule: {}", e))?;
+        Ok(())
+    }
+
+    async fn load(&self, workspace: Option<&str>, name: &str) -> Result<Option<Schedule>, String> {
+        let document = self.schedules.find_one(doc! { "_id": Self::id(workspace, name) }, None).await
+            .map_err(|e| format!("Failed to load schedule: {}", e))?;
+        document.as_ref().map(Self::from_document).transpose()
+    }
+
+    async fn list(&self) -> Result<Vec<Schedule>, String> {
+        use futures::stream::TryStreamExt;
+        let options = mongodb::options::FindOptions::builder().sort(doc! { "_id": 1 }).build();
+        let cursor = self.schedules.find(None, options).await
+            .map_err(|e| format!("Query failed: {}", e))?;
+        let documents: Vec<Document> = cursor.try_collect().await
+            .map_err(|e| format!("Failed to collect results: {}", e))?;
+        documents.iter().map(Self::from_document).collect()
+    }
+
+    async fn delete(&self, workspace: Option<&str>, name: &str) -> Result<bool, String> {
+        let result = self.schedules.delete_one(doc! { "_id": Self::id(workspace, name) }, None).await
+            .map_err(|e| format!("Failed to delete schedule: {}", e))?;
+        Ok(result.deleted_count == 1)
+    }
+
+    async fn claim(&self, workspace: Option<&str>, name: &str, due: DateTime<Utc>, next: Option<DateTime<Utc>>) -> Result<bool, String> {
+        let result = self.schedules.update_one(
+            doc! { "_id": Self::id(workspace, name), "next_run": bson_datetime(due) },
+            doc! { "$set": { "next_run": next.map(bson_datetime) } },
+            None,
+        ).await.map_err(|e| format!("Failed to claim schedule: {}", e))?;
+        Ok(result.modified_count == 1)
+    }
+
+    async fn record_run(&self, run: &ScheduleRun) -> Result<(), String> {
+        let json = serde_json::to_string(run)
+            .map_err(|e| format!("Failed to serialize run: {}", e))?;
+        self.runs.insert_one(doc! {
+            "_id": &run.id,
+            "schedule": &run.schedule,
+            "workspace": run.workspace.as_deref(),
+            "status": run.status.as_str(),
+            "started_at": bson_datetime(run.started_at),
+            "record": json,
+        }, None).await.map_err(|e| format!("Failed to record run: {}", e))?;
+        Ok(())
+    }
+
+    async fn runs(&self, workspace: Option<&str>, name: &str, limit: usize) -> Result<Vec<ScheduleRun>, String> {
+        use futures::stream::TryStreamExt;
+        let options = mongodb::options::FindOptions::builder()
+            .sort(doc! { "started_at": -1 })
+            .limit(limit as i64)
+            .build();
+        let cursor = self.runs.find(doc! { "workspace": workspace, "schedule": name }, options).await
+            .map_err(|e| format!("Query failed: {}", e))?;
+        let documents: Vec<Document> = cursor.try_collect().await
+            .map_err(|e| format!("Failed to collect results: {}", e))?;
+
+        documents.iter()
+            .map(|d| {
+                let json = d.get_str("record")
+                    .map_err(|e| format!("Corrupt run document: {}", e))?;
+                serde_json::from_str(json)
+                    .map_err(|e| format!("Corrupt run document: {}", e))
+            })
+            .collect()
+    }
+}
+
+// ================================================================================
+// SCHEDULER
+// ================================================================================
+
+/// Fires due schedules and keeps their run history
+pub struct Scheduler {
+    runtime: Arc<SPURuntime>,
+    store: Arc<dyn ScheduleStore>,
+    /// Schedules with a run in progress in this process, by workspace and name
+    running: Mutex<HashSet<(Option<String>, String)>>,
+    audit: Option<Arc<AuditLog>>,
+}
+
+/// A schedule's entry in `Scheduler::running`, removed when dropped, also when
+/// its run panics or is cancelled
+struct Running<'a> {
+    running: &'a Mutex<HashSet<(Option<String>, String)>>,
+    key: (Option<String>, String),
+}
+
+impl<'a> Running<'a> {
+    /// None if the schedule is running already
+    fn start(running: &'a Mutex<HashSet<(Option<String>, String)>>, schedule: &Schedule) -> Option<Self> {
+        let key = (schedule.workspace.clone(), schedule.name.clone());
+        let started = running.lock().unwrap().insert(key.clone());
+        started.then(|| Self { running, key })
+    }
+}
+
+impl Drop for Running<'_> {
+    fn drop(&mut self) {
+        self.running.lock().unwrap().remove(&self.key);
+    }
+}
+
+impl Scheduler {
+    pub fn new(runtime: Arc<SPURuntime>, store: Arc<dyn ScheduleStore>) -> Self {
+        Self {
+            runtime,
+            store,
+            running: Mutex::new(HashSet::new()),
+            audit: None,
+        }
+    }
+
+    /// Log saved and deleted schedules of workspaces in this audit log
+    pub fn with_audit(mut self, audit: Arc<AuditLog>) -> Self {
+        self.audit = Some(audit);
+        self
+    }
+
+    /// Create or replace a schedule of the server
+    pub async fn upsert(
+        &self,
+        name: &str,
+        cron: &str,
+        timezone: Option<&str>,
+        script: &str,
+        enabled: bool,
+    ) -> Result<Schedule, String> {
+        self.save(None, name, cron, timezone, script, enabled).await
+    }
+
+    /// Create or replace a schedule of a workspace, confined to it
+    pub async fn upsert_in(
+        &self,
+        workspace: &str,
+        name: &str,
+        cron: &str,
+        timezone: Option<&str>,
+        script: &str,
+        enabled: bool,
+    ) -> Result<Schedule, String> {
+        self.save(Some(workspace), name, cron, timezone, script, enabled).await
+    }
+
+    async fn save(
+        &self,
+        workspace: Option<&str>,
+        name: &str,
+        cron: &str,
+        timezone: Option<&str>,
+        script: &str,
+        enabled: bool,
+    ) -> Result<Schedule, String> {
+        if name.is_empty() {
+            return Err("Schedule name cannot be empty".to_string());
+        }
+        parse_cron(cron)?;
+        let timezone = timezone.unwrap_or("UTC");
+        parse_timezone(timezone)?;
+        crate::simple_parser::SimpleParser::parse(script)?;
+
+        let now = Utc::now();
+        let existing = self.store.load(workspace, name).await?;
+        let created_at = existing.as_ref().map(|existing| existing.created_at).unwrap_or(now);
+
+        let mut schedule = Schedule {
+            name: name.to_string(),
+            cron: cron.to_string(),
+            timezone: timezone.to_string(),
+            script: script.to_string(),
+            workspace: workspace.map(str::to_string),
+            enabled,
+            next_run: None,
+            created_at,
+            updated_at: now,
+        };
+        if enabled {
+            schedule.next_run = schedule.next_after(now)?;
+        }
+
+        self.store.save(&schedule).await?;
+        info!("Saved schedule {} ({} {})", schedule.name, schedule.cron, schedule.timezone);
+        self.audit("schedule.saved", existing.as_ref(), Some(&schedule)).await;
+        Ok(schedule)
+    }
+
+    /// A schedule of the server
+    pub async fn get(&self, name: &str) -> Result<Option<Schedule>, String> {
+        self.store.load(None, name).await
+    }
+
+    /// A schedule of a workspace
+    pub async fn get_in(&self, workspace: &str, name: &str) -> Result<Option<Schedule>, String> {
+        self.store.load(Some(workspace), name).await
+    }
+
+    /// Every schedule, of all workspaces and the server
+    pub async fn list(&self) -> Result<Vec<Schedule>, String> {
+        self.store.list().await
+    }
+
+    /// The schedules of a workspace
+    pub async fn list_in(&self, workspace: &str) -> Result<Vec<Schedule>, String> {
+        let mut list = self.store.list().await?;
+        list.retain(|schedule| schedule.workspace.as_deref() == Some(workspace));
+        Ok(list)
+    }
+
+    pub async fn delete(&self, name: &str) -> Result<bool, String> {
+        self.store.delete(None, name).await
+    }
+
+    pub async fn delete_in(&self, workspace: &str, name: &str) -> Result<bool, String> {
+        let existing = self.store.load(Some(workspace), name).await?;
+        let deleted = self.store.delete(Some(workspace), name).await?;
+        if deleted {
+            self.audit("schedule.deleted", existing.as_ref(), None).await;
+        }
+        Ok(deleted)
+    }
+
+    /// Only schedules of a workspace are logged; the audit log is per workspace
+    async fn audit(&self, action: &str, before: Option<&Schedule>, after: Option<&Schedule>) {
+        let (Some(audit), Some(schedule)) = (&self.audit, after.or(before)) else {
+            return;
+        };
+        let Some(workspace) = &schedule.workspace else {
+            return;
+        };
+        let entry = AuditEntry::new(workspace, action)
+            .on(format!("schedules/{}", schedule.name))
+            .changes(before.map(audited).as_ref(), after.map(audited).as_ref());
+        audit.log(&entry, None).await;
+    }
+
+    pub async fn runs(&self, name: &str, limit: usize) -> Result<Vec<ScheduleRun>, String> {
+        self.store.runs(None, name, limit).await
+    }
+
+    pub async fn runs_in(&self, workspace: &str, name: &str, limit: usize) -> Result<Vec<ScheduleRun>, String> {
+        self.store.runs(Some(workspace), name, limit).await
+    }
+
+    /// Run a schedule of the server immediately, regardless of its next fire time
+    pub async fn run_now(&self, name: &str) -> Result<ScheduleRun, String> {
+        self.run(None, name).await
+    }
+
+    /// Run a schedule of a workspace immediately
+    pub async fn run_now_in(&self, workspace: &str, name: &str) -> Result<ScheduleRun, String> {
+        self.run(Some(workspace), name).await
+    }
+
+    async fn run(&self, workspace: Option<&str>, name: &str) -> Result<ScheduleRun, String> {
+        let schedule = self.store.load(workspace, name).await?
+            .ok_or_else(|| format!("Unknown schedule: {}", name))?;
+        Ok(self.execute(&schedule).await)
+    }
+
+    /// Start every enabled schedule that is due at `now`
+    ///
+    /// Each run happens on its own task; the handles are returned so callers can wait.
+    pub async fn tick(self: &Arc<Self>, now: DateTime<Utc>) -> Result<Vec<tokio::task::JoinHandle<ScheduleRun>>, String> {
+        let mut started = Vec::new();
+
+        for schedule in self.store.list().await? {
+            let Some(due) = schedule.next_run else {
+                continue;
+            };
+            if !schedule.enabled || due > now {
+                continue;
+            }
+
+            // Occurrences missed while down are collapsed into this one run
+            let next = schedule.next_after(now)?;
+            if !self.store.claim(schedule.workspace.as_deref(), &schedule.name, due, next).await? {
+                continue;
+            }
+
+            let scheduler = self.clone();
+            started.push(tokio::spawn(async move {
+                scheduler.execute(&schedule).await
+            }));
+        }
+
+        Ok(started)
+    }
+
+    /// Spawn a background task that fires due schedules every `interval`
+    pub fn spawn_worker(self: Arc<Self>, interval: std::time::Duration) -> tokio::task::JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            loop {
+                ticker.tick().await;
+                if let Err(e) = self.tick(Utc::now()).await {
+                    error!("Scheduler tick failed: {}", e);
+                }
+            }
+        })
+    }
+
+    async fn execute(&self, schedule: &Schedule) -> ScheduleRun {
+        let started_at = Utc::now();
+        let mut run = ScheduleRun {
+            id: Uuid::new_v4().to_string(),
+            schedule: schedule.name.clone(),
+            workspace: schedule.workspace.clone(),
+            status: RunStatus::Skipped,
+            result: None,
+            error: None,
+            started_at,
+            finished_at: started_at,
+        };
+
+        let running = Running::start(&self.running, schedule);
+        if running.is_none() {
+            warn!("Schedule {} is still running, skipping", schedule.name);
+            run.error = Some("Previous run still in progress".to_string());
+        } else {
+            info!("Running schedule {}", schedule.name);
+            let script = async {
+                match &schedule.workspace {
+                    Some(workspace) => self.runtime.execute_in(&schedule.script, HashMap::new(), workspace).await,
+                    None => self.runtime.execute(&schedule.script).await,
+                }
+            };
+            match audit::as_script(format!("schedule:{}", schedule.name), script).await {
+                Ok(result) => {
+                    run.status = RunStatus::Succeeded;
+                    run.result = Some(result);
+                }
+                Err(e) => {
+                    error!("Schedule {} failed: {}", schedule.name, e);
+                    run.status = RunStatus::Failed;
+                    run.error = Some(e);
+                }
+            }
+            drop(running);
+            run.finished_at = Utc::now();
+        }
+
+        if let Err(e) = self.store.record_run(&run).await {
+            error!("Failed to record run of schedule {}: {}", schedule.name, e);
+        }
+        run
+    }
+}
+
+/// What the audit log keeps of a schedule
+fn audited(schedule: &Schedule) -> Document {
+    doc! {
+        "cron": &schedule.cron,
+        "timezone": &schedule.timezone,
+        "script": &schedule.script,
+        "enabled": schedule.enabled,
+    }
+}
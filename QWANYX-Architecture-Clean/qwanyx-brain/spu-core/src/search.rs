@@ -0,0 +1,857 @@
+//! Search
+//!
+//! Relevance-ranked full-text search over the collections of a workspace. A
+//! collection is searchable once it has a `SearchConfig`, saved in the
+//! workspace's `spu_search` collection: the fields to search, their boosts, the
+//! fields to count as facets and the language of the content.
+//!
+//! Each searchable collection gets an embedded tantivy index, built from the
+//! document store the first time it is searched and then kept in sync by
+//! following the store's `watch`. Indexes live in memory unless the engine is
+//! given a directory; either way they are rebuilt when the server starts, so
+//! the documents stay the one source of truth.
+//!
+//! Text goes through the same analysis at index and query time: lowercased,
+//! stemmed (French by default, as Autodin's content is) and folded to ASCII, so
+//! "Réparations" finds "reparation". Query words of four letters or more also
+//! match words one typo away, two from eight letters on; exact matches rank
+//! higher. Hits come back with the matched words of each field wrapped in
+//! `<mark>`.
+//!
+//! With an audit log (`with_audit`), configuring and removing search is logged.
+
+use crate::audit::{AuditEntry, AuditLog};
+use crate::bson_data::document_to_data;
+use crate::store::{query, ChangeEvent, ChangeKind, ChangeStream, DocumentStore, FindOptions, Namespace, UpdateOptions};
+use futures::{FutureExt, StreamExt};
+use mongodb::bson::{doc, Bson, Document};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tantivy::collector::{Count, FacetCollector, TopDocs};
+use tantivy::query::{AllQuery, BooleanQuery, BoostQuery, FuzzyTermQuery, Occur, Query, TermQuery};
+use tantivy::schema::{Facet, FacetOptions, Field, IndexRecordOption, Schema, TextFieldIndexing, TextOptions, Value as _, STORED, STRING};
+use tantivy::tokenizer::{AsciiFoldingFilter, LowerCaser, RemoveLongFilter, SimpleTokenizer, Stemmer, TextAnalyzer};
+use tantivy::{Index, IndexReader, IndexWriter, ReloadPolicy, TantivyDocument, Term};
+use thiserror::Error;
+use tokio::task::JoinHandle;
+use tracing::{info, warn};
+
+/// Collection of each workspace holding its search configurations, by collection
+pub const SEARCH_COLLECTION: &str = "spu_search";
+
+/// Hits returned unless told otherwise
+const DEFAULT_LIMIT: usize = 20;
+
+/// Most hits returned in one search
+const MAX_LIMIT: usize = 100;
+
+/// Values listed per facet
+const FACET_VALUES: usize = 20;
+
+/// Characters of a field shown around its first match
+const SNIPPET_CHARS: usize = 200;
+
+/// How long to wait before following a store again after its changes broke off,
+/// or checking again for changes when it has no change feed
+const RESYNC: Duration = Duration::from_secs(30);
+
+/// Memory the index writer of a collection may use
+const WRITER_MEMORY: usize = 15_000_000;
+
+/// Content language, for stemming
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SearchLanguage {
+    #[default]
+    French,
+    English,
+}
+
+impl SearchLanguage {
+    fn tokenizer(self) -> &'static str {
+        match self {
+            SearchLanguage::French => "spu_french",
+            SearchLanguage::English => "spu_english",
+        }
+    }
+
+    fn analyzer(self) -> TextAnalyzer {
+        let language = match self {
+            SearchLanguage::French => tantivy::tokenizer::Language::French,
+            SearchLanguage::English => tantivy::tokenizer::Language::English,
+        };
+        // Stemmed before folding, as the stemmers know their accents
+        TextAnalyzer::builder(SimpleTokenizer::default())
+            .filter(RemoveLongFilter::limit(40))
+            .filter(LowerCaser)
+            .filter(Stemmer::new(language))
+            .filter(AsciiFoldingFilter)
+            .build()
+    }
+}
+
+/// What to search in a collection
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SearchConfig {
+    /// Dotted paths of the text fields to search; arrays are searched item by item
+    pub fields: Vec<String>,
+    /// Weight of matches per field, 1 for fields not listed
+    #[serde(default)]
+    pub boosts: HashMap<String, f32>,
+    /// Fields whose values are counted and can be filtered on
+    #[serde(default)]
+    pub facets: Vec<String>,
+    #[serde(default)]
+    pub language: SearchLanguage,
+}
+
+impl SearchConfig {
+    pub fn from_json(value: Value) -> Result<Self, SearchError> {
+        let config: SearchConfig = serde_json::from_value(value)
+            .map_err(|e| SearchError::Invalid(format!("Invalid search configuration: {}", e)))?;
+        config.check()?;
+        Ok(config)
+    }
+
+    pub fn check(&self) -> Result<(), SearchError> {
+        let invalid = |message: String| Err(SearchError::Invalid(message));
+        if self.fields.is_empty() {
+            return invalid("A search configuration needs at least one field".to_string());
+        }
+        let mut seen = HashSet::new();
+        for field in self.fields.iter().chain(&self.facets) {
+            if field.is_empty() || field.starts_with('$') || field.split('.').any(str::is_empty) {
+                return invalid(format!("Invalid field '{}'", field));
+            }
+        }
+        for field in &self.fields {
+            if !seen.insert(field) {
+                return invalid(format!("Field '{}' is listed twice", field));
+            }
+        }
+        if self.facets.iter().collect::<HashSet<_>>().len() != self.facets.len() {
+            return invalid("A facet is listed twice".to_string());
+        }
+        for (field, boost) in &self.boosts {
+            if !self.fields.contains(field) {
+                return invalid(format!("Boost for '{}', which is not a searched field", field));
+            }
+            if !boost.is_finite() || *boost <= 0.0 {
+                return invalid(format!("The boost of '{}' must be a positive number", field));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn to_json(&self) -> Value {
+        serde_json::to_value(self).unwrap_or(Value::Null)
+    }
+}
+
+/// Whether a hit needs every query word or any of them
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum MatchMode {
+    #[default]
+    All,
+    Any,
+}
+
+/// A search of one collection
+#[derive(Debug, Clone, PartialEq)]
+pub struct SearchRequest {
+    /// Words to look for; empty lists everything, e.g. to browse facets
+    pub query: String,
+    /// Facet values hits must have, by facet
+    pub filters: Vec<(String, String)>,
+    /// Facets to count over every hit
+    pub facets: Vec<String>,
+    pub limit: usize,
+    pub offset: usize,
+    /// Match words a typo or two away
+    pub fuzzy: bool,
+    pub mode: MatchMode,
+    /// Configured fields to search, e.g. those the caller may see; all of them when `None`
+    pub fields: Option<Vec<String>>,
+}
+
+impl Default for SearchRequest {
+    fn default() -> Self {
+        Self {
+            query: String::new(),
+            filters: Vec::new(),
+            facets: Vec::new(),
+            limit: DEFAULT_LIMIT,
+            offset: 0,
+            fuzzy: true,
+            mode: MatchMode::All,
+            fields: None,
+        }
+    }
+}
+
+impl SearchRequest {
+    pub fn new(query: &str) -> Self {
+        Self { query: query.to_string(), ..Self::default() }
+    }
+
+    /// From `{"query", "filters": {facet: value}, "facets": [...], "limit",
+    /// "offset", "fuzzy", "mode": "all" | "any"}`
+    pub fn from_json(value: &Value) -> Result<Self, SearchError> {
+        let invalid = |message: &str| SearchError::Invalid(message.to_string());
+        let obj = value.as_object().ok_or_else(|| invalid("Expected a search object"))?;
+        let mut request = Self::default();
+        if let Some(query) = obj.get("query").filter(|q| !q.is_null()) {
+            request.query = query.as_str().ok_or_else(|| invalid("'query' must be a string"))?.to_string();
+        }
+        if let Some(filters) = obj.get("filters").filter(|f| !f.is_null()) {
+            let filters = filters.as_object().ok_or_else(|| invalid("'filters' must be an object"))?;
+            for (facet, value) in filters {
+                let value = match value {
+                    Value::String(s) => s.clone(),
+                    Value::Number(n) => n.to_string(),
+                    Value::Bool(b) => b.to_string(),
+                    _ => return Err(invalid("Facet filters must be strings, numbers or booleans")),
+                };
+                request.filters.push((facet.clone(), value));
+            }
+        }
+        if let Some(facets) = obj.get("facets").filter(|f| !f.is_null()) {
+            request.facets = facets.as_array()
+                .and_then(|facets| facets.iter().map(|f| f.as_str().map(str::to_string)).collect())
+                .ok_or_else(|| invalid("'facets' must be an array of strings"))?;
+        }
+        if let Some(limit) = obj.get("limit").filter(|l| !l.is_null()) {
+            request.limit = count(limit).ok_or_else(|| invalid("'limit' must be a positive number"))?;
+        }
+        if let Some(offset) = obj.get("offset").filter(|o| !o.is_null()) {
+            request.offset = count(offset).ok_or_else(|| invalid("'offset' must be a positive number"))?;
+        }
+        if let Some(fuzzy) = obj.get("fuzzy").filter(|f| !f.is_null()) {
+            request.fuzzy = fuzzy.as_bool().ok_or_else(|| invalid("'fuzzy' must be a boolean"))?;
+        }
+        if let Some(mode) = obj.get("mode").filter(|m| !m.is_null()) {
+            request.mode = match mode.as_str() {
+                Some("all") => MatchMode::All,
+                Some("any") => MatchMode::Any,
+                _ => return Err(invalid("'mode' must be \"all\" or \"any\"")),
+            };
+        }
+        Ok(request)
+    }
+}
+
+fn count(value: &Value) -> Option<usize> {
+    value.as_u64()
+        .or_else(|| value.as_f64().filter(|n| *n >= 0.0 && n.fract() == 0.0).map(|n| n as u64))
+        .map(|n| n as usize)
+}
+
+/// A matching document
+#[derive(Debug, Clone, PartialEq)]
+pub struct SearchHit {
+    pub id: Bson,
+    pub score: f32,
+    pub document: Document,
+    /// Matched fields, as HTML snippets with the matches in `<mark>`
+    pub highlights: HashMap<String, String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SearchResults {
+    pub hits: Vec<SearchHit>,
+    /// Documents matching, beyond the page of hits
+    pub total: usize,
+    /// Most common values and their counts, by requested facet
+    pub facets: HashMap<String, Vec<(String, u64)>>,
+}
+
+impl SearchResults {
+    pub fn to_json(&self) -> Value {
+        let hits: Vec<Value> = self.hits.iter()
+            .map(|hit| json!({
+                "id": crate::bson_data::bson_to_data(&hit.id).to_json(),
+                "score": hit.score,
+                "document": document_to_data(&hit.document).to_json(),
+                "highlights": hit.highlights,
+            }))
+            .collect();
+        let facets: serde_json::Map<String, Value> = self.facets.iter()
+            .map(|(facet, values)| {
+                let values = values.iter().map(|(value, count)| json!({ "value": value, "count": count })).collect();
+                (facet.clone(), Value::Array(values))
+            })
+            .collect();
+        json!({ "hits": hits, "total": self.total, "facets": facets })
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum SearchError {
+    #[error("{0} is not searchable")]
+    NotConfigured(String),
+
+    #[error("Invalid search: {0}")]
+    Invalid(String),
+
+    #[error("Search failed: {0}")]
+    Failed(String),
+}
+
+impl From<tantivy::TantivyError> for SearchError {
+    fn from(e: tantivy::TantivyError) -> Self {
+        SearchError::Failed(e.to_string())
+    }
+}
+
+/// The fields of a collection's index
+struct Fields {
+    id: Field,
+    /// In the order of the configuration's `fields`
+    text: Vec<Field>,
+    /// In the order of the configuration's `facets`
+    facets: Vec<Field>,
+}
+
+/// The index of a collection
+struct Indexed {
+    config: SearchConfig,
+    fields: Fields,
+    index: Index,
+    reader: IndexReader,
+    writer: Mutex<IndexWriter>,
+}
+
+impl Indexed {
+    fn create(config: SearchConfig, dir: Option<PathBuf>) -> Result<Self, SearchError> {
+        let mut builder = Schema::builder();
+        let id = builder.add_text_field("_id", STRING | STORED);
+        let indexing = TextFieldIndexing::default()
+            .set_tokenizer(config.language.tokenizer())
+            .set_index_option(IndexRecordOption::WithFreqsAndPositions);
+        let text = (0..config.fields.len())
+            .map(|n| builder.add_text_field(&format!("text{}", n), TextOptions::default().set_indexing_options(indexing.clone())))
+            .collect();
+        let facets = (0..config.facets.len())
+            .map(|n| builder.add_facet_field(&format!("facet{}", n), FacetOptions::default()))
+            .collect();
+        let schema = builder.build();
+
+        let index = match dir {
+            Some(dir) => {
+                // Rebuilt from the documents anyway
+                if dir.exists() {
+                    std::fs::remove_dir_all(&dir).map_err(|e| SearchError::Failed(format!("Failed to clear {}: {}", dir.display(), e)))?;
+                }
+                std::fs::create_dir_all(&dir).map_err(|e| SearchError::Failed(format!("Failed to create {}: {}", dir.display(), e)))?;
+                Index::create_in_dir(&dir, schema)?
+            }
+            None => Index::create_in_ram(schema),
+        };
+        index.tokenizers().register(config.language.tokenizer(), config.language.analyzer());
+        let reader = index.reader_builder().reload_policy(ReloadPolicy::Manual).try_into()?;
+        let writer = index.writer_with_num_threads(1, WRITER_MEMORY)?;
+
+        Ok(Self { config, fields: Fields { id, text, facets }, index, reader, writer: Mutex::new(writer) })
+    }
+
+    fn to_index(&self, document: &Document) -> TantivyDocument {
+        let mut indexed = TantivyDocument::default();
+        if let Some(id) = document.get("_id") {
+            indexed.add_text(self.fields.id, id_key(id));
+        }
+        for (path, field) in self.config.fields.iter().zip(&self.fields.text) {
+            for text in texts_at(document, path) {
+                indexed.add_text(*field, text);
+            }
+        }
+        for (path, field) in self.config.facets.iter().zip(&self.fields.facets) {
+            for value in texts_at(document, path).into_iter().filter(|v| !v.is_empty()) {
+                indexed.add_facet(*field, Facet::from_path([value]));
+            }
+        }
+        indexed
+    }
+
+    /// Replace everything with `documents`; returns how many were indexed
+    fn rebuild(&self, documents: &[Document]) -> Result<u64, SearchError> {
+        let mut writer = self.writer.lock().unwrap();
+        writer.delete_all_documents()?;
+        for document in documents {
+            writer.add_document(self.to_index(document))?;
+        }
+        writer.commit()?;
+        drop(writer);
+        self.reader.reload()?;
+        Ok(self.reader.searcher().num_docs())
+    }
+
+    fn apply(&self, changes: &[ChangeEvent]) -> Result<(), SearchError> {
+        let mut writer = self.writer.lock().unwrap();
+        for change in changes {
+            writer.delete_term(Term::from_field_text(self.fields.id, &id_key(&change.id)));
+            // Deletes, and updates of documents deleted since, only remove
+            if let (ChangeKind::Insert | ChangeKind::Update, Some(document)) = (change.kind, &change.document) {
+                writer.add_document(self.to_index(document))?;
+            }
+        }
+        writer.commit()?;
+        drop(writer);
+        self.reader.reload()?;
+        Ok(())
+    }
+
+    fn analyze(&self, text: &str) -> Vec<String> {
+        let mut analyzer = self.index.tokenizers().get(self.config.language.tokenizer())
+            .unwrap_or_else(|| self.config.language.analyzer());
+        let mut stream = analyzer.token_stream(text);
+        let mut terms = Vec::new();
+        while let Some(token) = stream.next() {
+            if !terms.contains(&token.text) {
+                terms.push(token.text.clone());
+            }
+        }
+        terms
+    }
+
+    fn query(&self, terms: &[String], request: &SearchRequest) -> Result<Box<dyn Query>, SearchError> {
+        let words: Vec<(Occur, Box<dyn Query>)> = terms.iter()
+            .map(|term| {
+                let mut alternatives: Vec<(Occur, Box<dyn Query>)> = Vec::new();
+                for (path, field) in self.searched(request) {
+                    let boost = self.config.boosts.get(path).copied().unwrap_or(1.0);
+                    let exact = TermQuery::new(Term::from_field_text(*field, term), IndexRecordOption::WithFreqs);
+                    // Exact matches outrank near ones
+                    alternatives.push((Occur::Should, Box::new(BoostQuery::new(Box::new(exact), 2.0 * boost))));
+                    let distance = typos_allowed(term);
+                    if request.fuzzy && distance > 0 {
+                        let near = FuzzyTermQuery::new(Term::from_field_text(*field, term), distance, true);
+                        alternatives.push((Occur::Should, Box::new(BoostQuery::new(Box::new(near), boost))));
+                    }
+                }
+                let occur = match request.mode {
+                    MatchMode::All => Occur::Must,
+                    MatchMode::Any => Occur::Should,
+                };
+                (occur, Box::new(BooleanQuery::new(alternatives)) as Box<dyn Query>)
+            })
+            .collect();
+        let text: Box<dyn Query> = if words.is_empty() { Box::new(AllQuery) } else { Box::new(BooleanQuery::new(words)) };
+        if request.filters.is_empty() {
+            return Ok(text);
+        }
+
+        let mut clauses: Vec<(Occur, Box<dyn Query>)> = vec![(Occur::Must, text)];
+        for (facet, value) in &request.filters {
+            let field = self.facet_field(facet)?;
+            let term = Term::from_facet(field, &Facet::from_path([value]));
+            clauses.push((Occur::Must, Box::new(TermQuery::new(term, IndexRecordOption::Basic))));
+        }
+        Ok(Box::new(BooleanQuery::new(clauses)))
+    }
+
+    /// The configured text fields the request searches, with their index fields
+    fn searched<'a>(&'a self, request: &'a SearchRequest) -> impl Iterator<Item = (&'a String, &'a Field)> {
+        self.config.fields.iter().zip(&self.fields.text)
+            .filter(|(path, _)| request.fields.as_ref().is_none_or(|fields| fields.contains(path)))
+    }
+
+    fn facet_field(&self, facet: &str) -> Result<Field, SearchError> {
+        self.config.facets.iter().position(|f| f == facet)
+            .map(|n| self.fields.facets[n])
+            .ok_or_else(|| SearchError::Invalid(format!("'{}' is not a facet", facet)))
+    }
+
+    /// Matching `_id`s with their scores, the total and the facet counts
+    #[allow(clippy::type_complexity)]
+    fn run(&self, request: &SearchRequest) -> Result<(Vec<(Bson, f32)>, usize, HashMap<String, Vec<(String, u64)>>), SearchError> {
+        let terms = self.analyze(&request.query);
+        let query = self.query(&terms, request)?;
+        let searcher = self.reader.searcher();
+
+        let total = searcher.search(&query, &Count)?;
+        let mut ids = Vec::new();
+        if request.limit > 0 {
+            let top = searcher.search(&query, &TopDocs::with_limit(request.limit).and_offset(request.offset))?;
+            for (score, address) in top {
+                let document: TantivyDocument = searcher.doc(address)?;
+                let id = document.get_first(self.fields.id)
+                    .and_then(|value| value.as_str())
+                    .and_then(|key| serde_json::from_str::<Value>(key).ok())
+                    .and_then(|key| Bson::try_from(key).ok());
+                if let Some(id) = id {
+                    ids.push((id, score));
+                }
+            }
+        }
+
+        let mut facets = HashMap::new();
+        for facet in &request.facets {
+            let field = self.facet_field(facet)?;
+            let mut collector = FacetCollector::for_field(self.index.schema().get_field_name(field));
+            collector.add_facet(Facet::root());
+            let counts = searcher.search(&query, &collector)?;
+            let values = counts.top_k(Facet::root(), FACET_VALUES).into_iter()
+                .map(|(value, count)| (value.to_path().concat(), count))
+                .collect();
+            facets.insert(facet.clone(), values);
+        }
+        Ok((ids, total, facets))
+    }
+
+    /// Snippets of the searched fields of `document` where `terms` match
+    fn highlights(&self, document: &Document, terms: &[String], request: &SearchRequest) -> HashMap<String, String> {
+        let mut analyzer = self.config.language.analyzer();
+        let mut highlights = HashMap::new();
+        if terms.is_empty() {
+            return highlights;
+        }
+        for (path, _) in self.searched(request) {
+            let text = texts_at(document, path).join(" … ");
+            let mut marks = Vec::new();
+            let mut stream = analyzer.token_stream(&text);
+            while let Some(token) = stream.next() {
+                let hit = terms.iter().any(|term| {
+                    *term == token.text || (request.fuzzy && typos(term, &token.text) <= typos_allowed(term) as usize)
+                });
+                if hit {
+                    marks.push((token.offset_from, token.offset_to));
+                }
+            }
+            if !marks.is_empty() {
+                highlights.insert(path.clone(), snippet(&text, &marks));
+            }
+        }
+        highlights
+    }
+}
+
+/// How far a query word may be from a word of the index
+fn typos_allowed(term: &str) -> u8 {
+    match term.chars().count() {
+        0..=3 => 0,
+        4..=7 => 1,
+        _ => 2,
+    }
+}
+
+/// Edits between two words, a swap of neighbours counting as one
+fn typos(a: &str, b: &str) -> usize {
+    let (a, b): (Vec<char>, Vec<char>) = (a.chars().collect(), b.chars().collect());
+    let mut distances = vec![vec![0; b.len() + 1]; a.len() + 1];
+    for (i, row) in distances.iter_mut().enumerate() {
+        row[0] = i;
+    }
+    distances[0] = (0..=b.len()).collect();
+    for i in 1..=a.len() {
+        for j in 1..=b.len() {
+            let substitution = if a[i - 1] == b[j - 1] { 0 } else { 1 };
+            let mut distance = (distances[i - 1][j] + 1)
+                .min(distances[i][j - 1] + 1)
+                .min(distances[i - 1][j - 1] + substitution);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                distance = distance.min(distances[i - 2][j - 2] + 1);
+            }
+            distances[i][j] = distance;
+        }
+    }
+    distances[a.len()][b.len()]
+}
+
+/// The text of `text` around its first mark, escaped for HTML, with every mark
+/// in view wrapped in `<mark>`; `marks` are byte ranges in order
+fn snippet(text: &str, marks: &[(usize, usize)]) -> String {
+    let boundaries: Vec<usize> = text.char_indices().map(|(i, _)| i).chain([text.len()]).collect();
+    let (mut start, mut end) = (0, text.len());
+    if boundaries.len() > SNIPPET_CHARS + 1 {
+        let first = boundaries.iter().position(|&i| i >= marks[0].0).unwrap_or(0);
+        let from = first.saturating_sub(SNIPPET_CHARS / 4).min(boundaries.len() - 1 - SNIPPET_CHARS);
+        start = boundaries[from];
+        end = boundaries[from + SNIPPET_CHARS];
+    }
+
+    let mut snippet = String::new();
+    if start > 0 {
+        snippet.push('…');
+    }
+    let mut at = start;
+    for &(from, to) in marks.iter().filter(|(from, to)| *from >= start && *to <= end) {
+        snippet.push_str(&escape(&text[at..from]));
+        snippet.push_str("<mark>");
+        snippet.push_str(&escape(&text[from..to]));
+        snippet.push_str("</mark>");
+        at = to;
+    }
+    snippet.push_str(&escape(&text[at..end]));
+    if end < text.len() {
+        snippet.push('…');
+    }
+    snippet
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+/// The text of every value at a dotted path
+fn texts_at(document: &Document, path: &str) -> Vec<String> {
+    let mut texts = Vec::new();
+    for value in query::values_at(document, path) {
+        match value {
+            Bson::Array(items) => texts.extend(items.iter().filter_map(text_of)),
+            other => texts.extend(text_of(other)),
+        }
+    }
+    texts
+}
+
+fn text_of(value: &Bson) -> Option<String> {
+    match value {
+        Bson::String(s) => Some(s.clone()),
+        Bson::Int32(n) => Some(n.to_string()),
+        Bson::Int64(n) => Some(n.to_string()),
+        Bson::Double(n) => Some(n.to_string()),
+        Bson::Boolean(b) => Some(b.to_string()),
+        _ => None,
+    }
+}
+
+/// How an `_id` is kept in the index: its canonical extended JSON
+fn id_key(id: &Bson) -> String {
+    id.clone().into_canonical_extjson().to_string()
+}
+
+/// An open index and the task keeping it in sync
+struct Open {
+    indexed: Arc<Indexed>,
+    sync: JoinHandle<()>,
+}
+
+impl Drop for Open {
+    fn drop(&mut self) {
+        self.sync.abort();
+    }
+}
+
+/// Searches the collections of every workspace of a document store
+pub struct SearchEngine {
+    store: Arc<dyn DocumentStore>,
+    /// Where indexes are kept, in memory when `None`
+    dir: Option<PathBuf>,
+    open: tokio::sync::Mutex<HashMap<(String, String), Arc<Open>>>,
+    audit: Option<Arc<AuditLog>>,
+}
+
+impl SearchEngine {
+    /// Keeps its indexes in memory
+    pub fn new(store: Arc<dyn DocumentStore>) -> Self {
+        Self { store, dir: None, open: tokio::sync::Mutex::new(HashMap::new()), audit: None }
+    }
+
+    /// Keep indexes in `dir`, one directory per workspace and collection
+    pub fn with_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.dir = Some(dir.into());
+        self
+    }
+
+    /// Log configured and removed search in this audit log
+    pub fn with_audit(mut self, audit: Arc<AuditLog>) -> Self {
+        self.audit = Some(audit);
+        self
+    }
+
+    /// The engine over `store`, with its indexes in `SEARCH_INDEX_PATH` if set
+    pub fn from_env(store: Arc<dyn DocumentStore>) -> Self {
+        match std::env::var("SEARCH_INDEX_PATH") {
+            Ok(dir) => Self::new(store).with_dir(dir),
+            Err(_) => Self::new(store),
+        }
+    }
+
+    /// Make a collection searchable, or change how it is searched; its index is
+    /// rebuilt on the next search
+    pub async fn configure(&self, workspace: &str, collection: &str, config: SearchConfig) -> Result<(), SearchError> {
+        if collection.is_empty() || collection.starts_with("spu_") {
+            return Err(SearchError::Invalid(format!("'{}' cannot be searched", collection)));
+        }
+        config.check()?;
+        // A corrupt configuration can still be replaced or removed
+        let before = self.config(workspace, collection).await.ok().flatten();
+        let update = doc! {
+            "$set": {
+                "config": config.to_json().to_string(),
+                "updated_at": mongodb::bson::DateTime::now(),
+            }
+        };
+        let options = UpdateOptions { many: false, upsert: true };
+        self.store.update(Namespace::new(workspace, SEARCH_COLLECTION), doc! { "_id": collection }, update, options).await
+            .map_err(|e| SearchError::Failed(format!("Failed to save the search configuration: {}", e)))?;
+        self.open.lock().await.remove(&(workspace.to_string(), collection.to_string()));
+        info!("Configured search of {} in workspace {}", collection, workspace);
+        self.audit(workspace, collection, "search.configured", before.as_ref(), Some(&config)).await;
+        Ok(())
+    }
+
+    /// How a collection is searched, if it is
+    pub async fn config(&self, workspace: &str, collection: &str) -> Result<Option<SearchConfig>, SearchError> {
+        let options = FindOptions { limit: Some(1), ..FindOptions::default() };
+        let found = self.store.find(Namespace::new(workspace, SEARCH_COLLECTION), doc! { "_id": collection }, options).await
+            .map_err(|e| SearchError::Failed(format!("Failed to load the search configuration: {}", e)))?;
+        match found.first().and_then(|saved| saved.get_str("config").ok()) {
+            Some(config) => {
+                let config = serde_json::from_str(config)
+                    .map_err(|e| SearchError::Failed(format!("Corrupt search configuration of {}: {}", collection, e)))?;
+                Ok(Some(config))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Stop searching a collection and drop its index; false if it was not searchable
+    pub async fn remove(&self, workspace: &str, collection: &str) -> Result<bool, SearchError> {
+        // A corrupt configuration can still be replaced or removed
+        let before = self.config(workspace, collection).await.ok().flatten();
+        let deleted = self.store.delete(Namespace::new(workspace, SEARCH_COLLECTION), doc! { "_id": collection }).await
+            .map_err(|e| SearchError::Failed(format!("Failed to delete the search configuration: {}", e)))?;
+        self.open.lock().await.remove(&(workspace.to_string(), collection.to_string()));
+        if deleted > 0 {
+            self.audit(workspace, collection, "search.removed", before.as_ref(), None).await;
+        }
+        Ok(deleted > 0)
+    }
+
+    async fn audit(&self, workspace: &str, collection: &str, action: &str, before: Option<&SearchConfig>, after: Option<&SearchConfig>) {
+        let Some(audit) = &self.audit else {
+            return;
+        };
+        let audited = |config: &SearchConfig| mongodb::bson::to_document(config).unwrap_or_default();
+        let entry = AuditEntry::new(workspace, action)
+            .on(format!("search/{}", collection))
+            .changes(before.map(audited).as_ref(), after.map(audited).as_ref());
+        audit.log(&entry, None).await;
+    }
+
+    /// Rebuild the index of a collection from its documents; returns how many
+    /// were indexed
+    pub async fn reindex(&self, workspace: &str, collection: &str) -> Result<u64, SearchError> {
+        self.open.lock().await.remove(&(workspace.to_string(), collection.to_string()));
+        let open = self.index_of(workspace, collection).await?;
+        Ok(open.indexed.reader.searcher().num_docs())
+    }
+
+    pub async fn search(&self, workspace: &str, collection: &str, request: &SearchRequest) -> Result<SearchResults, SearchError> {
+        if request.limit > MAX_LIMIT {
+            return Err(SearchError::Invalid(format!("'limit' cannot be over {}", MAX_LIMIT)));
+        }
+        let open = self.index_of(workspace, collection).await?;
+        let indexed = &open.indexed;
+        let (ids, total, facets) = indexed.run(request)?;
+
+        // The documents themselves come from the store, as they are now
+        let filter = doc! { "_id": { "$in": ids.iter().map(|(id, _)| id.clone()).collect::<Vec<_>>() } };
+        let documents = self.store.find(Namespace::new(workspace, collection), filter, FindOptions::default()).await
+            .map_err(|e| SearchError::Failed(format!("Failed to load the hits: {}", e)))?;
+        let terms = indexed.analyze(&request.query);
+        let hits = ids.into_iter()
+            .filter_map(|(id, score)| {
+                let document = documents.iter().find(|d| d.get("_id").is_some_and(|d| query::equal(d, &id)))?;
+                let highlights = indexed.highlights(document, &terms, request);
+                Some(SearchHit { id, score, document: document.clone(), highlights })
+            })
+            .collect();
+        Ok(SearchResults { hits, total, facets })
+    }
+
+    /// The open index of a collection, building it on first use
+    async fn index_of(&self, workspace: &str, collection: &str) -> Result<Arc<Open>, SearchError> {
+        let key = (workspace.to_string(), collection.to_string());
+        let mut open = self.open.lock().await;
+        if let Some(index) = open.get(&key) {
+            return Ok(index.clone());
+        }
+        let config = self.config(workspace, collection).await?
+            .ok_or_else(|| SearchError::NotConfigured(collection.to_string()))?;
+        let dir = self.dir.as_ref().map(|dir| dir.join(path_safe(workspace)).join(path_safe(collection)));
+        let indexed = Arc::new(Indexed::create(config, dir)?);
+
+        // Following before reading, so that nothing written in between is missed
+        let changes = self.store.watch(Namespace::new(workspace, collection)).await;
+        let indexed_count = rebuild(self.store.as_ref(), workspace, collection, &indexed).await?;
+        info!("Indexed {} documents of {} in workspace {}", indexed_count, collection, workspace);
+
+        let sync = tokio::spawn(follow(self.store.clone(), key.clone(), indexed.clone(), changes));
+        let index = Arc::new(Open { indexed, sync });
+        open.insert(key, index.clone());
+        Ok(index)
+    }
+}
+
+fn path_safe(name: &str) -> String {
+    name.chars().map(|c| if c.is_alphanumeric() || c == '-' || c == '_' { c } else { '_' }).collect()
+}
+
+async fn rebuild(store: &dyn DocumentStore, workspace: &str, collection: &str, indexed: &Indexed) -> Result<u64, SearchError> {
+    let documents = store.find(Namespace::new(workspace, collection), Document::new(), FindOptions::default()).await
+        .map_err(|e| SearchError::Failed(format!("Failed to read {}: {}", collection, e)))?;
+    indexed.rebuild(&documents)
+}
+
+/// Apply the changes of a collection to its index for as long as it is open;
+/// when they break off, follow again and rebuild
+async fn follow(store: Arc<dyn DocumentStore>, (workspace, collection): (String, String), indexed: Arc<Indexed>, mut changes: Result<ChangeStream, String>) {
+    loop {
+        match changes {
+            Ok(mut stream) => loop {
+                let change = match stream.next().await {
+                    Some(Ok(change)) => change,
+                    Some(Err(e)) => {
+                        warn!("Search index of {} in {} lost track of changes: {}", collection, workspace, e);
+                        break;
+                    }
+                    None => {
+                        warn!("Changes of {} in {} ended", collection, workspace);
+                        tokio::time::sleep(RESYNC).await;
+                        break;
+                    }
+                };
+                // Whatever else is already waiting goes in the same commit
+                let mut batch = vec![change];
+                let mut broken = None;
+                while let Some(next) = stream.next().now_or_never() {
+                    match next {
+                        Some(Ok(change)) => batch.push(change),
+                        Some(Err(e)) => broken = Some(e),
+                        None => broken = Some("the change feed closed".to_string()),
+                    }
+                    if broken.is_some() {
+                        break;
+                    }
+                }
+                if let Err(e) = indexed.apply(&batch) {
+                    warn!("Failed to update the search index of {} in {}: {}", collection, workspace, e);
+                    break;
+                }
+                if let Some(e) = broken {
+                    warn!("Search index of {} in {} lost track of changes: {}", collection, workspace, e);
+                    break;
+                }
+            },
+            Err(e) => {
+                warn!("Cannot follow changes of {} in {}, reindexing every {}s: {}", collection, workspace, RESYNC.as_secs(), e);
+                tokio::time::sleep(RESYNC).await;
+            }
+        }
+        changes = store.watch(Namespace::new(&workspace, &collection)).await;
+        if let Err(e) = rebuild(store.as_ref(), &workspace, &collection, &indexed).await {
+            warn!("Failed to reindex {} in {}: {}", collection, workspace, e);
+        }
+    }
+}
@@ -10,6 +10,9 @@
 //! 7. CATCH error_type (catches errors)
 //! 8. TRACE message
 //! 9. HALT
+//!
+//! Durable workflows add SLEEP duration and WAIT_EVENT event target.
+//! Transactions add BEGIN instance, COMMIT instance and ROLLBACK instance.
 
 use crate::{Instruction, Data, JoinMode, BackoffStrategy};
 use serde_json::Value;
@@ -544,6 +547,37 @@ impl SimpleParser {
                     }
                 }
                 
+                "SLEEP" => {
+                    if parts.len() != 2 {
+                        return Err(format!("Line {}: SLEEP needs a duration", i + 1));
+                    }
+                    Instruction::Sleep {
+                        duration: parts[1].to_string(),
+                    }
+                }
+                
+                "WAIT_EVENT" => {
+                    if parts.len() != 3 {
+                        return Err(format!("Line {}: WAIT_EVENT needs event and target", i + 1));
+                    }
+                    Instruction::WaitEvent {
+                        event: parts[1].to_string(),
+                        target: parts[2].to_string(),
+                    }
+                }
+                
+                "BEGIN" | "COMMIT" | "ROLLBACK" => {
+                    if parts.len() != 2 {
+                        return Err(format!("Line {}: {} needs instance", i + 1, parts[0].to_uppercase()));
+                    }
+                    let object = parts[1].to_string();
+                    match parts[0].to_uppercase().as_str() {
+                        "BEGIN" => Instruction::Begin { object },
+                        "COMMIT" => Instruction::Commit { object },
+                        _ => Instruction::Rollback { object },
+                    }
+                }
+                
                 "FOREACH" => {
                     // FOREACH item IN collection
                     if parts.len() < 4 || parts[2].to_uppercase() != "IN" {
@@ -696,4 +730,38 @@ HALT
         let instructions = SimpleParser::parse(script).unwrap();
         assert_eq!(instructions.len(), 3); // TRY, CATCH, HALT
     }
-}
\ No newline at end of file
+    
+    #[test]
+    fn test_parse_sleep_and_wait_event() {
+        let script = "SLEEP 5m\nWAIT_EVENT order.paid payment";
+        let instructions = SimpleParser::parse(script).unwrap();
+        assert_eq!(instructions.len(), 2);
+        match &instructions[0] {
+            Instruction::Sleep { duration } => assert_eq!(duration, "5m"),
+            _ => panic!("Expected Sleep")
+        }
+        match &instructions[1] {
+            Instruction::WaitEvent { event, target } => {
+                assert_eq!(event, "order.paid");
+                assert_eq!(target, "payment");
+            }
+            _ => panic!("Expected WaitEvent")
+        }
+        
+        assert!(SimpleParser::parse("SLEEP").is_err());
+        assert!(SimpleParser::parse("WAIT_EVENT order.paid").is_err());
+    }
+    
+    #[test]
+    fn test_parse_transactions() {
+        let script = "BEGIN db\nCALL db update $args updated\nCOMMIT db\nROLLBACK db";
+        let instructions = SimpleParser::parse(script).unwrap();
+        assert_eq!(instructions.len(), 4);
+        assert!(matches!(&instructions[0], Instruction::Begin { object } if object == "db"));
+        assert!(matches!(&instructions[2], Instruction::Commit { object } if object == "db"));
+        assert!(matches!(&instructions[3], Instruction::Rollback { object } if object == "db"));
+        
+        assert!(SimpleParser::parse("BEGIN").is_err());
+        assert!(SimpleParser::parse("COMMIT db now").is_err());
+    }
+}
@@ -0,0 +1,66 @@
+//! Idempotent Calls
+//!
+//! Durable workflows retrying a call cut short by a crash
+//! (`CallPolicy::Idempotent`) pass the same `idempotency_key` each time.
+//! Coprocessors with side effects record what they answered under that key in
+//! the workspace's `spu_idempotency` collection, and answer repeats with it
+//! instead of acting again. Records are kept for `RETENTION`, long enough for
+//! any retry; older ones are deleted as calls are looked up.
+
+use super::{DocumentStore, FindOptions, Namespace};
+use crate::Data;
+use mongodb::bson::{doc, DateTime};
+use tracing::warn;
+
+/// Collection of each workspace holding the answers to idempotent calls
+pub const IDEMPOTENCY_COLLECTION: &str = "spu_idempotency";
+
+/// How long what a call answered is kept for its retries
+pub const RETENTION: std::time::Duration = std::time::Duration::from_secs(30 * 24 * 3600);
+
+/// The `idempotency_key` argument of a call, if it has one
+pub fn key_of(args: &Data) -> Option<String> {
+    match args {
+        Data::Object(obj) => match obj.get("idempotency_key") {
+            Some(Data::String(key)) if !key.is_empty() => Some(key.clone()),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// What the call with `key` answered, if it was already made; answers older
+/// than `RETENTION` are forgotten while at it
+pub async fn recorded<S: DocumentStore + ?Sized>(store: &S, ns: Namespace<'_>, key: &str) -> Result<Option<Data>, String> {
+    let ns = Namespace { collection: IDEMPOTENCY_COLLECTION, ..ns };
+    let expired = DateTime::from_millis(DateTime::now().timestamp_millis() - RETENTION.as_millis() as i64);
+    if let Err(e) = store.delete(ns, doc! { "created_at": { "$lt": expired } }).await {
+        warn!("Failed to delete expired calls of {}: {}", ns.workspace, e);
+    }
+    let found = store.find(ns, doc! { "_id": key }, FindOptions::default()).await
+        .map_err(|e| format!("Failed to look up call {}: {}", key, e))?;
+    found.first()
+        .map(|document| {
+            let result = document.get_str("result")
+                .map_err(|e| format!("Corrupt idempotency document: {}", e))?;
+            serde_json::from_str(result)
+                .map(Data::from_json)
+                .map_err(|e| format!("Corrupt idempotency document: {}", e))
+        })
+        .transpose()
+}
+
+/// Remember what the call with `key` answered, in the transaction of `ns` if
+/// it has one
+pub async fn record<S: DocumentStore + ?Sized>(store: &S, ns: Namespace<'_>, key: &str, method: &str, result: &Data) -> Result<(), String> {
+    let ns = Namespace { collection: IDEMPOTENCY_COLLECTION, ..ns };
+    let document = doc! {
+        "_id": key,
+        "method": method,
+        "result": result.to_json().to_string(),
+        "created_at": DateTime::now(),
+    };
+    store.insert(ns, document).await
+        .map(|_| ())
+        .map_err(|e| format!("Failed to record call {}: {}", key, e))
+}
@@ -0,0 +1,815 @@
+//! Local Document Stores
+//!
+//! Collections held in process and queried with `query`. `MemoryStore` keeps them
+//! in memory only; `EmbeddedStore` loads each collection from sled on first use and
+//! writes every change through to disk. Both suit tests and local development
+//! rather than large data sets.
+//!
+//! Writes run on a copy of the collection, so a failing `update_many` or
+//! `insert_many` leaves it untouched. A transaction works on private copies of
+//! the collections it touches, taken on first use; commit applies them together,
+//! or fails without applying anything if another write changed one of those
+//! collections in the meantime.
+//!
+//! Indexes are kept with their collection and only serve to enforce unique keys:
+//! a write that would give two documents the same key fails and changes nothing.
+//!
+//! Committed writes are announced to watchers by comparing the collection before
+//! and after, so they see one change per document whatever the operation.
+//! Renaming a collection is not announced.
+
+use super::{
+    pipeline, query, ChangeEvent, ChangeKind, ChangeStream, DocumentStore, FindOneAndUpdateOptions, FindOptions, Index,
+    Namespace, UpdateOptions, UpdateOutcome, ID_INDEX,
+};
+use async_trait::async_trait;
+use sled::Transactional;
+use mongodb::bson::{doc, oid::ObjectId, Bson, Document};
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+use tokio::sync::{broadcast, Mutex, RwLock};
+use uuid::Uuid;
+
+/// Transactions left open longer than this are discarded
+const TRANSACTION_LIFETIME: Duration = Duration::from_secs(60);
+
+/// Changes a slow watcher may fall behind by before its stream ends
+const WATCH_CAPACITY: usize = 1024;
+
+/// How long opening an embedded store waits for its directory to be unlocked
+const OPEN_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// (workspace, collection)
+type Key = (String, String);
+
+/// Where a `LocalStore` keeps its collections besides memory
+pub trait Persistence: Send + Sync {
+    /// Documents of a collection, read on its first use
+    fn load(&self, workspace: &str, collection: &str) -> Result<Vec<Document>, String>;
+
+    /// Write changed collections, all or nothing
+    fn save(&self, changes: &[Change<'_>]) -> Result<(), String>;
+
+    /// Indexes of a collection, read on its first use
+    fn load_indexes(&self, _workspace: &str, _collection: &str) -> Result<Vec<Index>, String> {
+        Ok(Vec::new())
+    }
+
+    /// Replace the indexes of a collection
+    fn save_indexes(&self, _workspace: &str, _collection: &str, _indexes: &[Index]) -> Result<(), String> {
+        Ok(())
+    }
+
+    fn health(&self) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+/// A collection before and after a write
+pub struct Change<'a> {
+    pub workspace: &'a str,
+    pub collection: &'a str,
+    pub before: &'a [Document],
+    pub after: &'a [Document],
+}
+
+/// Nothing besides memory
+#[derive(Default)]
+pub struct Volatile;
+
+impl Persistence for Volatile {
+    fn load(&self, _workspace: &str, _collection: &str) -> Result<Vec<Document>, String> {
+        Ok(Vec::new())
+    }
+
+    fn save(&self, _changes: &[Change<'_>]) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+/// sled on disk: one tree per workspace and collection, documents keyed by their
+/// `_id` and stored as BSON; index definitions share a tree of their own
+pub struct Sled {
+    db: sled::Db,
+}
+
+/// Tree of index definitions, keyed by workspace and collection
+const INDEX_TREE: &str = "\0indexes";
+
+impl Sled {
+    fn tree(&self, workspace: &str, collection: &str) -> Result<sled::Tree, String> {
+        self.db.open_tree(format!("{}\0{}", workspace, collection))
+            .map_err(|e| format!("Failed to open collection {}: {}", collection, e))
+    }
+
+    fn index_tree(&self) -> Result<sled::Tree, String> {
+        self.db.open_tree(INDEX_TREE).map_err(|e| format!("Failed to open indexes: {}", e))
+    }
+}
+
+impl Persistence for Sled {
+    fn load(&self, workspace: &str, collection: &str) -> Result<Vec<Document>, String> {
+        self.tree(workspace, collection)?
+            .iter()
+            .map(|entry| {
+                let (_, value) = entry.map_err(|e| format!("Failed to read document: {}", e))?;
+                Document::from_reader(value.as_ref()).map_err(|e| format!("Corrupt document: {}", e))
+            })
+            .collect()
+    }
+
+    fn save(&self, changes: &[Change<'_>]) -> Result<(), String> {
+        let mut trees = Vec::with_capacity(changes.len());
+        let mut batches = Vec::with_capacity(changes.len());
+        for change in changes {
+            trees.push(self.tree(change.workspace, change.collection)?);
+            batches.push(batch_for(change)?);
+        }
+
+        let written: sled::transaction::TransactionResult<()> = trees[..].transaction(|trees| {
+            for (tree, batch) in trees.iter().zip(&batches) {
+                tree.apply_batch(batch)?;
+            }
+            Ok(())
+        });
+        written.map_err(|e| format!("Failed to write documents: {:?}", e))?;
+
+        self.db.flush()
+            .map(|_| ())
+            .map_err(|e| format!("Failed to flush document store: {}", e))
+    }
+
+    fn load_indexes(&self, workspace: &str, collection: &str) -> Result<Vec<Index>, String> {
+        let Some(value) = self.index_tree()?
+            .get(format!("{}\0{}", workspace, collection))
+            .map_err(|e| format!("Failed to read indexes: {}", e))?
+        else {
+            return Ok(Vec::new());
+        };
+        let stored = Document::from_reader(value.as_ref()).map_err(|e| format!("Corrupt indexes: {}", e))?;
+        stored.get_array("indexes")
+            .map_err(|e| format!("Corrupt indexes: {}", e))?
+            .iter()
+            .map(|index| match index {
+                Bson::Document(index) => Ok(Index {
+                    name: index.get_str("name").map_err(|e| format!("Corrupt index: {}", e))?.to_string(),
+                    keys: index.get_document("keys").map_err(|e| format!("Corrupt index: {}", e))?.clone(),
+                    unique: index.get_bool("unique").unwrap_or(false),
+                }),
+                _ => Err("Corrupt index".to_string()),
+            })
+            .collect()
+    }
+
+    fn save_indexes(&self, workspace: &str, collection: &str, indexes: &[Index]) -> Result<(), String> {
+        let tree = self.index_tree()?;
+        let key = format!("{}\0{}", workspace, collection);
+        if indexes.is_empty() {
+            tree.remove(key).map_err(|e| format!("Failed to write indexes: {}", e))?;
+        } else {
+            let indexes: Vec<Bson> = indexes.iter()
+                .map(|index| Bson::Document(doc! { "name": &index.name, "keys": index.keys.clone(), "unique": index.unique }))
+                .collect();
+            tree.insert(key, encode(&doc! { "indexes": indexes })?)
+                .map_err(|e| format!("Failed to write indexes: {}", e))?;
+        }
+        self.db.flush()
+            .map(|_| ())
+            .map_err(|e| format!("Failed to flush document store: {}", e))
+    }
+
+    fn health(&self) -> Result<(), String> {
+        self.db.size_on_disk()
+            .map(|_| ())
+            .map_err(|e| format!("Document store unavailable: {}", e))
+    }
+}
+
+/// Inserts for new and changed documents, removals for deleted ones
+fn batch_for(change: &Change<'_>) -> Result<sled::Batch, String> {
+    let mut before = HashMap::with_capacity(change.before.len());
+    for document in change.before {
+        before.insert(key_of(document)?, encode(document)?);
+    }
+
+    let mut batch = sled::Batch::default();
+    let mut kept = HashSet::with_capacity(change.after.len());
+    for document in change.after {
+        let (key, value) = (key_of(document)?, encode(document)?);
+        if before.get(&key) != Some(&value) {
+            batch.insert(key.clone(), value);
+        }
+        kept.insert(key);
+    }
+    for key in before.keys().filter(|key| !kept.contains(*key)) {
+        batch.remove(key.clone());
+    }
+    Ok(batch)
+}
+
+fn key_of(document: &Document) -> Result<Vec<u8>, String> {
+    let id = document.get("_id").cloned().unwrap_or(Bson::Null);
+    mongodb::bson::to_vec(&doc! { "_id": id }).map_err(|e| format!("Invalid _id: {}", e))
+}
+
+fn encode(document: &Document) -> Result<Vec<u8>, String> {
+    mongodb::bson::to_vec(document).map_err(|e| format!("Failed to encode document: {}", e))
+}
+
+#[derive(Clone, Default)]
+struct Collection {
+    /// Bumped on every committed write, to detect transaction conflicts
+    version: u64,
+    documents: Vec<Document>,
+    indexes: Vec<Index>,
+}
+
+struct Transaction {
+    started: Instant,
+    /// Private copies, with the version they were taken at
+    collections: HashMap<Key, Collection>,
+    written: HashSet<Key>,
+}
+
+/// Collections in memory, persisted by `P`
+pub struct LocalStore<P: Persistence> {
+    persistence: P,
+    collections: RwLock<HashMap<Key, Collection>>,
+    transactions: Mutex<HashMap<String, Transaction>>,
+    changes: broadcast::Sender<ChangeEvent>,
+}
+
+/// In-memory store, for tests
+pub type MemoryStore = LocalStore<Volatile>;
+
+/// On-disk store, for local development without a MongoDB server
+pub type EmbeddedStore = LocalStore<Sled>;
+
+impl LocalStore<Volatile> {
+    pub fn new() -> Self {
+        Self::with_persistence(Volatile)
+    }
+}
+
+impl Default for LocalStore<Volatile> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LocalStore<Sled> {
+    /// Open (or create) a store in a directory. Sled unlocks the directory from
+    /// background threads, so a store just closed may keep it locked a moment;
+    /// opening waits for it up to `OPEN_TIMEOUT`.
+    pub fn open(path: &str) -> Result<Self, String> {
+        let started = Instant::now();
+        let db = loop {
+            match sled::open(path) {
+                // Sled reports the lock as an `Other` error, naming the `WouldBlock` behind it
+                Err(sled::Error::Io(e)) if e.to_string().contains("could not acquire lock") && started.elapsed() < OPEN_TIMEOUT => {
+                    std::thread::sleep(Duration::from_millis(20));
+                }
+                result => break result.map_err(|e| format!("Failed to open document store at {}: {}", path, e))?,
+            }
+        };
+        Ok(Self::with_persistence(Sled { db }))
+    }
+}
+
+fn key(ns: Namespace<'_>) -> Key {
+    (ns.workspace.to_string(), ns.collection.to_string())
+}
+
+fn open_transaction<'t>(transactions: &'t mut HashMap<String, Transaction>, id: &str) -> Result<&'t mut Transaction, String> {
+    if transactions.get(id).is_some_and(|t| t.started.elapsed() > TRANSACTION_LIFETIME) {
+        transactions.remove(id);
+    }
+    transactions.get_mut(id)
+        .ok_or_else(|| format!("Unknown transaction {} (it may have expired)", id))
+}
+
+impl<P: Persistence> LocalStore<P> {
+    pub fn with_persistence(persistence: P) -> Self {
+        Self {
+            persistence,
+            collections: RwLock::new(HashMap::new()),
+            transactions: Mutex::new(HashMap::new()),
+            changes: broadcast::channel(WATCH_CAPACITY).0,
+        }
+    }
+
+    /// Tell watchers how a collection changed
+    fn announce(&self, workspace: &str, collection: &str, before: &[Document], after: &[Document]) {
+        if self.changes.receiver_count() == 0 {
+            return;
+        }
+        for change in changes_between(workspace, collection, before, after) {
+            let _ = self.changes.send(change);
+        }
+    }
+
+    /// Make sure a collection is in memory
+    async fn load(&self, key: &Key) -> Result<(), String> {
+        if self.collections.read().await.contains_key(key) {
+            return Ok(());
+        }
+        let mut collections = self.collections.write().await;
+        if !collections.contains_key(key) {
+            let documents = self.persistence.load(&key.0, &key.1)?;
+            let indexes = self.persistence.load_indexes(&key.0, &key.1)?;
+            collections.insert(key.clone(), Collection { version: 0, documents, indexes });
+        }
+        Ok(())
+    }
+
+    async fn committed(&self, key: &Key) -> Result<Collection, String> {
+        self.load(key).await?;
+        Ok(self.collections.read().await.get(key).cloned().unwrap_or_default())
+    }
+
+    /// The transaction's copy of a collection, taken on first use
+    async fn working<'t>(&self, transaction: &'t mut Transaction, key: &Key) -> Result<&'t mut Collection, String> {
+        match transaction.collections.entry(key.clone()) {
+            Entry::Occupied(entry) => Ok(entry.into_mut()),
+            Entry::Vacant(entry) => Ok(entry.insert(self.committed(key).await?)),
+        }
+    }
+
+    /// Documents of a collection as `ns` sees them
+    async fn read(&self, ns: Namespace<'_>) -> Result<Vec<Document>, String> {
+        let key = key(ns);
+        match ns.transaction {
+            Some(id) => {
+                let mut transactions = self.transactions.lock().await;
+                let transaction = open_transaction(&mut transactions, id)?;
+                Ok(self.working(transaction, &key).await?.documents.clone())
+            }
+            None => Ok(self.committed(&key).await?.documents),
+        }
+    }
+
+    /// Run a write on a copy of the collection, keeping the copy only if it succeeds
+    async fn write<T: Send>(
+        &self,
+        ns: Namespace<'_>,
+        operation: impl FnOnce(&mut Vec<Document>) -> Result<T, String> + Send,
+    ) -> Result<T, String> {
+        let key = key(ns);
+        match ns.transaction {
+            Some(id) => {
+                let mut transactions = self.transactions.lock().await;
+                let transaction = open_transaction(&mut transactions, id)?;
+                let collection = self.working(transaction, &key).await?;
+                let mut documents = collection.documents.clone();
+                let result = operation(&mut documents)?;
+                check_unique(&collection.indexes, &documents)?;
+                collection.documents = documents;
+                transaction.written.insert(key);
+                Ok(result)
+            }
+            None => {
+                self.load(&key).await?;
+                let mut collections = self.collections.write().await;
+                let collection = collections.entry(key).or_default();
+                let mut documents = collection.documents.clone();
+                let result = operation(&mut documents)?;
+                if documents != collection.documents {
+                    check_unique(&collection.indexes, &documents)?;
+                    self.persistence.save(&[Change {
+                        workspace: ns.workspace,
+                        collection: ns.collection,
+                        before: &collection.documents,
+                        after: &documents,
+                    }])?;
+                    self.announce(ns.workspace, ns.collection, &collection.documents, &documents);
+                    collection.documents = documents;
+                    collection.version += 1;
+                }
+                Ok(result)
+            }
+        }
+    }
+}
+
+impl<P: Persistence> LocalStore<P> {
+    /// Change the indexes of a collection, outside any transaction
+    async fn change_indexes(
+        &self,
+        ns: Namespace<'_>,
+        change: impl FnOnce(&mut Vec<Index>, &[Document]) -> Result<bool, String> + Send,
+    ) -> Result<(), String> {
+        if ns.transaction.is_some() {
+            return Err("Indexes cannot be changed in a transaction".to_string());
+        }
+        let key = key(ns);
+        self.load(&key).await?;
+        let mut collections = self.collections.write().await;
+        let collection = collections.entry(key).or_default();
+        let mut indexes = collection.indexes.clone();
+        if change(&mut indexes, &collection.documents)? {
+            self.persistence.save_indexes(ns.workspace, ns.collection, &indexes)?;
+            collection.indexes = indexes;
+            // Transactions that took a copy before the change must not commit over it
+            collection.version += 1;
+        }
+        Ok(())
+    }
+}
+
+// ================================================================================
+// OPERATIONS
+// ================================================================================
+
+/// One change per document inserted, changed or removed, by `_id`
+fn changes_between(workspace: &str, collection: &str, before: &[Document], after: &[Document]) -> Vec<ChangeEvent> {
+    let before: HashMap<Vec<u8>, &Document> = before.iter()
+        .filter_map(|document| Some((key_of(document).ok()?, document)))
+        .collect();
+    let mut kept = HashSet::with_capacity(after.len());
+    let change = |kind, document: &Document, full: bool| ChangeEvent {
+        kind,
+        workspace: workspace.to_string(),
+        collection: collection.to_string(),
+        id: document.get("_id").cloned().unwrap_or(Bson::Null),
+        document: full.then(|| document.clone()),
+    };
+
+    let mut changes = Vec::new();
+    for document in after {
+        let Ok(key) = key_of(document) else { continue };
+        match before.get(&key) {
+            None => changes.push(change(ChangeKind::Insert, document, true)),
+            Some(previous) if *previous != document => changes.push(change(ChangeKind::Update, document, true)),
+            Some(_) => {}
+        }
+        kept.insert(key);
+    }
+    for (key, document) in &before {
+        if !kept.contains(key) {
+            changes.push(change(ChangeKind::Delete, document, false));
+        }
+    }
+    changes
+}
+
+/// The values of an index's keys in a document, missing ones as null
+fn index_key(index: &Index, document: &Document) -> Document {
+    index.keys.keys()
+        .map(|field| {
+            let value = query::values_at(document, field).first().map(|v| (*v).clone()).unwrap_or(Bson::Null);
+            (field.clone(), value)
+        })
+        .collect()
+}
+
+/// Fails if two documents have the same key under a unique index
+fn check_unique(indexes: &[Index], documents: &[Document]) -> Result<(), String> {
+    for index in indexes.iter().filter(|index| index.unique) {
+        let mut keys: Vec<Bson> = documents.iter().map(|document| Bson::Document(index_key(index, document))).collect();
+        keys.sort_by(query::compare);
+        if let Some(pair) = keys.windows(2).find(|pair| query::compare(&pair[0], &pair[1]).is_eq()) {
+            return Err(format!("Duplicate key: {} already exists in unique index {}", pair[0], index.name));
+        }
+    }
+    Ok(())
+}
+
+fn add_index(indexes: &mut Vec<Index>, documents: &[Document], index: Index) -> Result<bool, String> {
+    if index.keys.is_empty() {
+        return Err("An index needs at least one key".to_string());
+    }
+    for (field, direction) in &index.keys {
+        let valid = match direction {
+            Bson::String(kind) => matches!(kind.as_str(), "text" | "hashed" | "2d" | "2dsphere"),
+            other => query::type_rank(other) == 2 && query::as_f64(other) != 0.0,
+        };
+        if !valid {
+            return Err(format!("Invalid direction {} for index key {}", direction, field));
+        }
+    }
+    if index.name == ID_INDEX {
+        return Err(format!("Index name {} is reserved", ID_INDEX));
+    }
+    if let Some(existing) = indexes.iter().find(|existing| existing.name == index.name) {
+        return if *existing == index {
+            Ok(false)
+        } else {
+            Err(format!("Index {} already exists with different keys or options", index.name))
+        };
+    }
+    if let Some(existing) = indexes.iter().find(|existing| existing.keys == index.keys) {
+        return Err(format!("Index {} already exists with the same keys", existing.name));
+    }
+    check_unique(std::slice::from_ref(&index), documents)
+        .map_err(|e| format!("Cannot create index {}: {}", index.name, e))?;
+    indexes.push(index);
+    Ok(true)
+}
+
+fn remove_index(indexes: &mut Vec<Index>, name: &str) -> Result<bool, String> {
+    if name == ID_INDEX {
+        return Err(format!("Cannot drop the {} index", ID_INDEX));
+    }
+    let before = indexes.len();
+    indexes.retain(|index| index.name != name);
+    if indexes.len() == before {
+        return Err(format!("Index {} not found", name));
+    }
+    Ok(true)
+}
+
+/// The document with an ObjectId `_id` first if it had none
+fn with_id(document: Document) -> Document {
+    if document.contains_key("_id") {
+        return document;
+    }
+    let mut with_id = doc! { "_id": ObjectId::new() };
+    with_id.extend(document);
+    with_id
+}
+
+fn insert_documents(documents: &mut Vec<Document>, new: Vec<Document>) -> Result<Vec<Bson>, String> {
+    let mut ids = Vec::with_capacity(new.len());
+    for document in new {
+        let document = with_id(document);
+        let id = document.get("_id").cloned().unwrap_or(Bson::Null);
+        if documents.iter().any(|existing| existing.get("_id").is_some_and(|existing| query::equal(existing, &id))) {
+            return Err(format!("Duplicate key: _id {} already exists", id));
+        }
+        ids.push(id);
+        documents.push(document);
+    }
+    Ok(ids)
+}
+
+/// Insert what an upsert builds from its filter and update
+fn upsert(documents: &mut Vec<Document>, filter: &Document, update: &Document) -> Result<Document, String> {
+    let mut document = query::upsert_seed(filter)?;
+    query::apply_update(&mut document, update, true)?;
+    let document = with_id(document);
+    insert_documents(documents, vec![document.clone()])?;
+    Ok(document)
+}
+
+fn update_documents(documents: &mut Vec<Document>, filter: &Document, update: &Document, options: UpdateOptions) -> Result<UpdateOutcome, String> {
+    query::validate_update(update)?;
+    let mut outcome = UpdateOutcome::default();
+    for document in documents.iter_mut() {
+        if query::matches(document, filter)? {
+            outcome.matched += 1;
+            if query::apply_update(document, update, false)? {
+                outcome.modified += 1;
+            }
+            if !options.many {
+                break;
+            }
+        }
+    }
+    if outcome.matched == 0 && options.upsert {
+        let inserted = upsert(documents, filter, update)?;
+        outcome.upserted_id = inserted.get("_id").cloned();
+    }
+    Ok(outcome)
+}
+
+fn find_one_and_update_document(
+    documents: &mut Vec<Document>,
+    filter: &Document,
+    update: &Document,
+    options: &FindOneAndUpdateOptions,
+) -> Result<Option<Document>, String> {
+    query::validate_update(update)?;
+    let mut matched = Vec::new();
+    for (index, document) in documents.iter().enumerate() {
+        if query::matches(document, filter)? {
+            matched.push(index);
+        }
+    }
+    if let Some(sort) = &options.sort {
+        matched.sort_by(|a, b| query::compare_by(sort, &documents[*a], &documents[*b]));
+    }
+
+    let result = match matched.first() {
+        Some(&index) => {
+            let before = documents[index].clone();
+            query::apply_update(&mut documents[index], update, false)?;
+            Some(if options.return_updated { documents[index].clone() } else { before })
+        }
+        None if options.upsert => {
+            let inserted = upsert(documents, filter, update)?;
+            options.return_updated.then_some(inserted)
+        }
+        None => None,
+    };
+
+    match (result, &options.projection) {
+        (Some(document), Some(projection)) if !projection.is_empty() => query::project(&document, projection).map(Some),
+        (result, _) => Ok(result),
+    }
+}
+
+fn delete_documents(documents: &mut Vec<Document>, filter: &Document) -> Result<u64, String> {
+    // Check every document first so that a bad filter deletes nothing
+    let mut keep = Vec::with_capacity(documents.len());
+    for document in documents.iter() {
+        keep.push(!query::matches(document, filter)?);
+    }
+    let before = documents.len();
+    let mut keep = keep.into_iter();
+    documents.retain(|_| keep.next().unwrap_or(true));
+    Ok((before - documents.len()) as u64)
+}
+
+#[async_trait]
+impl<P: Persistence> DocumentStore for LocalStore<P> {
+    async fn insert(&self, ns: Namespace<'_>, document: Document) -> Result<Bson, String> {
+        let mut ids = self.insert_many(ns, vec![document]).await?;
+        Ok(ids.remove(0))
+    }
+
+    async fn insert_many(&self, ns: Namespace<'_>, documents: Vec<Document>) -> Result<Vec<Bson>, String> {
+        self.write(ns, |existing| insert_documents(existing, documents)).await
+    }
+
+    async fn find(&self, ns: Namespace<'_>, filter: Document, options: FindOptions) -> Result<Vec<Document>, String> {
+        query::find(self.read(ns).await?, &filter, &options)
+    }
+
+    async fn count(&self, ns: Namespace<'_>, filter: Document) -> Result<u64, String> {
+        let mut count = 0;
+        for document in self.read(ns).await? {
+            if query::matches(&document, &filter)? {
+                count += 1;
+            }
+        }
+        Ok(count)
+    }
+
+    async fn update(&self, ns: Namespace<'_>, filter: Document, update: Document, options: UpdateOptions) -> Result<UpdateOutcome, String> {
+        self.write(ns, |documents| update_documents(documents, &filter, &update, options)).await
+    }
+
+    async fn find_one_and_update(&self, ns: Namespace<'_>, filter: Document, update: Document, options: FindOneAndUpdateOptions) -> Result<Option<Document>, String> {
+        self.write(ns, |documents| find_one_and_update_document(documents, &filter, &update, &options)).await
+    }
+
+    async fn delete(&self, ns: Namespace<'_>, filter: Document) -> Result<u64, String> {
+        self.write(ns, |documents| delete_documents(documents, &filter)).await
+    }
+
+    async fn aggregate(&self, ns: Namespace<'_>, pipeline: Vec<Document>, limit: usize) -> Result<Vec<Document>, String> {
+        pipeline::check(&pipeline)?;
+        let mut foreign = HashMap::new();
+        for collection in pipeline::lookups(&pipeline) {
+            if let Entry::Vacant(entry) = foreign.entry(collection) {
+                let documents = self.read(Namespace { collection: entry.key(), ..ns }).await?;
+                entry.insert(documents);
+            }
+        }
+        let mut documents = pipeline::run(self.read(ns).await?, &pipeline, &foreign)?;
+        documents.truncate(limit);
+        Ok(documents)
+    }
+
+    async fn create_index(&self, ns: Namespace<'_>, index: Index) -> Result<(), String> {
+        self.change_indexes(ns, |indexes, documents| add_index(indexes, documents, index)).await
+    }
+
+    async fn drop_index(&self, ns: Namespace<'_>, name: &str) -> Result<(), String> {
+        self.change_indexes(ns, |indexes, _| remove_index(indexes, name)).await
+    }
+
+    async fn list_indexes(&self, ns: Namespace<'_>) -> Result<Vec<Index>, String> {
+        let mut indexes = vec![Index::new(doc! { "_id": 1 }).named(ID_INDEX).unique(true)];
+        indexes.extend(self.committed(&key(ns)).await?.indexes);
+        Ok(indexes)
+    }
+
+    async fn rename_collection(&self, ns: Namespace<'_>, to: &str) -> Result<(), String> {
+        if ns.transaction.is_some() {
+            return Err("Collections cannot be renamed in a transaction".to_string());
+        }
+        if to.is_empty() || to == ns.collection {
+            return Err(format!("Cannot rename {} to '{}'", ns.collection, to));
+        }
+        let (from, target) = (key(ns), key(Namespace { collection: to, ..ns }));
+        self.load(&from).await?;
+        self.load(&target).await?;
+
+        let mut collections = self.collections.write().await;
+        let source = collections.get(&from).cloned().unwrap_or_default();
+        let existing = collections.get(&target).cloned().unwrap_or_default();
+        if source.documents.is_empty() && source.indexes.is_empty() {
+            return Err(format!("Collection {} does not exist", ns.collection));
+        }
+        if !existing.documents.is_empty() || !existing.indexes.is_empty() {
+            return Err(format!("Collection {} already exists", to));
+        }
+
+        self.persistence.save(&[
+            Change { workspace: ns.workspace, collection: ns.collection, before: &source.documents, after: &[] },
+            Change { workspace: ns.workspace, collection: to, before: &[], after: &source.documents },
+        ])?;
+        self.persistence.save_indexes(ns.workspace, to, &source.indexes)?;
+        self.persistence.save_indexes(ns.workspace, ns.collection, &[])?;
+
+        collections.insert(target, Collection {
+            version: existing.version + 1,
+            documents: source.documents,
+            indexes: source.indexes,
+        });
+        collections.insert(from, Collection { version: source.version + 1, ..Collection::default() });
+        Ok(())
+    }
+
+    async fn watch(&self, ns: Namespace<'_>) -> Result<ChangeStream, String> {
+        let (workspace, collection) = key(ns);
+        let receiver = self.changes.subscribe();
+        // The receiver, or None once the stream has ended on a missed change
+        let stream = futures::stream::unfold(Some(receiver), move |receiver| {
+            let (workspace, collection) = (workspace.clone(), collection.clone());
+            async move {
+                let mut receiver = receiver?;
+                loop {
+                    match receiver.recv().await {
+                        Ok(change) if change.workspace == workspace && change.collection == collection => {
+                            return Some((Ok(change), Some(receiver)));
+                        }
+                        Ok(_) => {}
+                        Err(broadcast::error::RecvError::Lagged(missed)) => {
+                            return Some((Err(format!("Watcher fell behind by {} changes", missed)), None));
+                        }
+                        Err(broadcast::error::RecvError::Closed) => return None,
+                    }
+                }
+            }
+        });
+        Ok(Box::pin(stream))
+    }
+
+    async fn begin(&self) -> Result<String, String> {
+        let mut transactions = self.transactions.lock().await;
+        transactions.retain(|_, t| t.started.elapsed() <= TRANSACTION_LIFETIME);
+
+        let id = Uuid::new_v4().to_string();
+        transactions.insert(id.clone(), Transaction {
+            started: Instant::now(),
+            collections: HashMap::new(),
+            written: HashSet::new(),
+        });
+        Ok(id)
+    }
+
+    async fn commit(&self, transaction: &str) -> Result<(), String> {
+        let mut transaction = {
+            let mut transactions = self.transactions.lock().await;
+            open_transaction(&mut transactions, transaction)?;
+            transactions.remove(transaction).unwrap_or_else(|| unreachable!())
+        };
+
+        let mut collections = self.collections.write().await;
+        let mut written: Vec<Key> = transaction.written.drain().collect();
+        written.sort();
+
+        for key in &written {
+            let committed = collections.get(key).map(|c| c.version);
+            if committed != transaction.collections.get(key).map(|c| c.version) {
+                return Err(format!("Write conflict: {} changed during the transaction, nothing was committed", key.1));
+            }
+        }
+
+        let changes: Vec<Change<'_>> = written.iter()
+            .filter_map(|key| Some(Change {
+                workspace: &key.0,
+                collection: &key.1,
+                before: &collections.get(key)?.documents,
+                after: &transaction.collections.get(key)?.documents,
+            }))
+            .collect();
+        if !changes.is_empty() {
+            self.persistence.save(&changes)?;
+        }
+        drop(changes);
+
+        for key in written {
+            if let (Some(collection), Some(copy)) = (collections.get_mut(&key), transaction.collections.remove(&key)) {
+                self.announce(&key.0, &key.1, &collection.documents, &copy.documents);
+                collection.documents = copy.documents;
+                collection.version += 1;
+            }
+        }
+        Ok(())
+    }
+
+    async fn abort(&self, transaction: &str) -> Result<(), String> {
+        let mut transactions = self.transactions.lock().await;
+        open_transaction(&mut transactions, transaction)?;
+        transactions.remove(transaction);
+        Ok(())
+    }
+
+    async fn health(&self) -> Result<(), String> {
+        self.persistence.health()
+    }
+}
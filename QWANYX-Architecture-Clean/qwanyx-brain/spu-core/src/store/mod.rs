@@ -0,0 +1,301 @@
+//! Document Stores
+//!
+//! The storage behind the database coprocessor. Documents live in collections
+//! inside a workspace, and are queried with MongoDB filter, sort, projection and
+//! update documents whatever the backend:
+//!
+//! - `MongoStore` - one MongoDB database per workspace
+//! - `MemoryStore` - in process, for tests
+//! - `EmbeddedStore` - on disk with sled, for local development without a server
+//!
+//! The in-memory and embedded stores evaluate queries themselves (see `query`),
+//! covering the operators scripts use: comparisons, `$in`, `$exists`, `$regex`,
+//! `$and`/`$or`/`$nor`, `$not`, `$size`, `$all` and `$elemMatch`, and run
+//! read-only aggregation pipelines (see `pipeline`). Collections may have a JSON
+//! Schema (see `schema`), which the database coprocessor enforces on writes.
+//!
+//! Collections may also have indexes; the local stores keep them only to enforce
+//! unique ones, MongoDB uses them for queries as well.
+//!
+//! Every store supports multi-document transactions: `begin` returns an id that
+//! operations take through their `Namespace`, and nothing they write is visible
+//! to others until `commit`.
+//!
+//! `watch` follows the committed changes of a collection: MongoDB change streams
+//! (which need a replica set), or an in-process feed for the local stores.
+
+pub mod idempotency;
+mod local;
+mod mongo;
+pub mod pipeline;
+pub mod query;
+pub mod schema;
+
+pub use local::{Change, EmbeddedStore, LocalStore, MemoryStore, Persistence, Sled, Volatile};
+pub use mongo::{connect_mongo, MongoStore};
+
+use async_trait::async_trait;
+use futures::stream::Stream;
+use mongodb::bson::{Bson, Document};
+use std::pin::Pin;
+use std::sync::Arc;
+
+/// Where an operation applies: a collection of a workspace, optionally as part
+/// of a transaction
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Namespace<'a> {
+    pub workspace: &'a str,
+    pub collection: &'a str,
+    pub transaction: Option<&'a str>,
+}
+
+impl<'a> Namespace<'a> {
+    pub fn new(workspace: &'a str, collection: &'a str) -> Self {
+        Self { workspace, collection, transaction: None }
+    }
+
+    pub fn in_transaction(mut self, transaction: Option<&'a str>) -> Self {
+        self.transaction = transaction;
+        self
+    }
+}
+
+/// Projection, sort and paging of a find
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FindOptions {
+    pub projection: Option<Document>,
+    /// Fields and directions (1 or -1), in order
+    pub sort: Option<Document>,
+    pub limit: Option<i64>,
+    pub skip: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct UpdateOptions {
+    /// Update every match rather than the first
+    pub many: bool,
+    /// Insert a document built from the filter and the update when nothing matches
+    pub upsert: bool,
+}
+
+/// Documents matched and changed by an update
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct UpdateOutcome {
+    pub matched: u64,
+    pub modified: u64,
+    pub upserted_id: Option<Bson>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FindOneAndUpdateOptions {
+    /// Which match to update when there are several
+    pub sort: Option<Document>,
+    pub projection: Option<Document>,
+    pub upsert: bool,
+    /// Return the document as updated rather than as it was
+    pub return_updated: bool,
+}
+
+/// An index of a collection
+#[derive(Debug, Clone, PartialEq)]
+pub struct Index {
+    pub name: String,
+    /// Fields and directions, in order, e.g. `{"uuid": 1}`
+    pub keys: Document,
+    /// Whether two documents may not have the same values for the keys
+    pub unique: bool,
+}
+
+impl Index {
+    /// A non-unique index named after its keys like MongoDB does, e.g. `uuid_1`
+    pub fn new(keys: Document) -> Self {
+        let name = keys.iter()
+            .map(|(field, direction)| match direction {
+                Bson::String(kind) => format!("{}_{}", field, kind),
+                other => format!("{}_{}", field, if query::as_f64(other) < 0.0 { -1 } else { 1 }),
+            })
+            .collect::<Vec<_>>()
+            .join("_");
+        Self { name, keys, unique: false }
+    }
+
+    pub fn unique(mut self, unique: bool) -> Self {
+        self.unique = unique;
+        self
+    }
+
+    pub fn named(mut self, name: impl Into<String>) -> Self {
+        self.name = name.into();
+        self
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ChangeKind {
+    Insert,
+    /// Updated or replaced
+    Update,
+    Delete,
+}
+
+/// A committed change to one document
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChangeEvent {
+    pub kind: ChangeKind,
+    pub workspace: String,
+    pub collection: String,
+    pub id: Bson,
+    /// The document after the change; none for deletes
+    pub document: Option<Document>,
+}
+
+/// Changes of a collection as they are committed; an error ends the stream, as
+/// changes may have been missed
+pub type ChangeStream = Pin<Box<dyn Stream<Item = Result<ChangeEvent, String>> + Send>>;
+
+/// The index every collection has on `_id`
+pub const ID_INDEX: &str = "_id_";
+
+#[async_trait]
+pub trait DocumentStore: Send + Sync {
+    /// Insert a document, giving it an ObjectId `_id` if it has none; returns the `_id`
+    async fn insert(&self, ns: Namespace<'_>, document: Document) -> Result<Bson, String>;
+
+    /// Insert several documents in order, stopping at the first failure; returns their `_id`s
+    async fn insert_many(&self, ns: Namespace<'_>, documents: Vec<Document>) -> Result<Vec<Bson>, String>;
+
+    async fn find(&self, ns: Namespace<'_>, filter: Document, options: FindOptions) -> Result<Vec<Document>, String>;
+
+    async fn count(&self, ns: Namespace<'_>, filter: Document) -> Result<u64, String>;
+
+    /// Apply an update document, e.g. `{"$set": {...}}`, to the first or every match
+    async fn update(&self, ns: Namespace<'_>, filter: Document, update: Document, options: UpdateOptions) -> Result<UpdateOutcome, String>;
+
+    /// Update the first match atomically and return it, for counters and locks
+    async fn find_one_and_update(&self, ns: Namespace<'_>, filter: Document, update: Document, options: FindOneAndUpdateOptions) -> Result<Option<Document>, String>;
+
+    /// Delete every match; returns how many were deleted
+    async fn delete(&self, ns: Namespace<'_>, filter: Document) -> Result<u64, String>;
+
+    /// Run an aggregation pipeline, returning at most `limit` documents; `$lookup`
+    /// reads other collections of the same workspace
+    async fn aggregate(&self, ns: Namespace<'_>, pipeline: Vec<Document>, limit: usize) -> Result<Vec<Document>, String>;
+
+    /// Create an index; creating one that already exists with the same keys is a no-op
+    async fn create_index(&self, ns: Namespace<'_>, index: Index) -> Result<(), String>;
+
+    /// Drop an index by name
+    async fn drop_index(&self, ns: Namespace<'_>, name: &str) -> Result<(), String>;
+
+    /// The indexes of a collection, starting with the one on `_id`
+    async fn list_indexes(&self, ns: Namespace<'_>) -> Result<Vec<Index>, String>;
+
+    /// Rename a collection within its workspace, with its indexes; fails if `to` exists
+    async fn rename_collection(&self, ns: Namespace<'_>, to: &str) -> Result<(), String>;
+
+    /// Follow the changes of a collection from now on
+    async fn watch(&self, ns: Namespace<'_>) -> Result<ChangeStream, String>;
+
+    /// Start a transaction; returns its id
+    async fn begin(&self) -> Result<String, String>;
+
+    /// Apply everything written in the transaction, or nothing if it conflicts
+    async fn commit(&self, transaction: &str) -> Result<(), String>;
+
+    /// Discard everything written in the transaction
+    async fn abort(&self, transaction: &str) -> Result<(), String>;
+
+    /// Whether the store can serve requests
+    async fn health(&self) -> Result<(), String>;
+}
+
+#[async_trait]
+impl<S: DocumentStore + ?Sized> DocumentStore for Arc<S> {
+    async fn insert(&self, ns: Namespace<'_>, document: Document) -> Result<Bson, String> {
+        (**self).insert(ns, document).await
+    }
+
+    async fn insert_many(&self, ns: Namespace<'_>, documents: Vec<Document>) -> Result<Vec<Bson>, String> {
+        (**self).insert_many(ns, documents).await
+    }
+
+    async fn find(&self, ns: Namespace<'_>, filter: Document, options: FindOptions) -> Result<Vec<Document>, String> {
+        (**self).find(ns, filter, options).await
+    }
+
+    async fn count(&self, ns: Namespace<'_>, filter: Document) -> Result<u64, String> {
+        (**self).count(ns, filter).await
+    }
+
+    async fn update(&self, ns: Namespace<'_>, filter: Document, update: Document, options: UpdateOptions) -> Result<UpdateOutcome, String> {
+        (**self).update(ns, filter, update, options).await
+    }
+
+    async fn find_one_and_update(&self, ns: Namespace<'_>, filter: Document, update: Document, options: FindOneAndUpdateOptions) -> Result<Option<Document>, String> {
+        (**self).find_one_and_update(ns, filter, update, options).await
+    }
+
+    async fn delete(&self, ns: Namespace<'_>, filter: Document) -> Result<u64, String> {
+        (**self).delete(ns, filter).await
+    }
+
+    async fn aggregate(&self, ns: Namespace<'_>, pipeline: Vec<Document>, limit: usize) -> Result<Vec<Document>, String> {
+        (**self).aggregate(ns, pipeline, limit).await
+    }
+
+    async fn create_index(&self, ns: Namespace<'_>, index: Index) -> Result<(), String> {
+        (**self).create_index(ns, index).await
+    }
+
+    async fn drop_index(&self, ns: Namespace<'_>, name: &str) -> Result<(), String> {
+        (**self).drop_index(ns, name).await
+    }
+
+    async fn list_indexes(&self, ns: Namespace<'_>) -> Result<Vec<Index>, String> {
+        (**self).list_indexes(ns).await
+    }
+
+    async fn rename_collection(&self, ns: Namespace<'_>, to: &str) -> Result<(), String> {
+        (**self).rename_collection(ns, to).await
+    }
+
+    async fn watch(&self, ns: Namespace<'_>) -> Result<ChangeStream, String> {
+        (**self).watch(ns).await
+    }
+
+    async fn begin(&self) -> Result<String, String> {
+        (**self).begin().await
+    }
+
+    async fn commit(&self, transaction: &str) -> Result<(), String> {
+        (**self).commit(transaction).await
+    }
+
+    async fn abort(&self, transaction: &str) -> Result<(), String> {
+        (**self).abort(transaction).await
+    }
+
+    async fn health(&self) -> Result<(), String> {
+        (**self).health().await
+    }
+}
+
+/// The store named by `DOCUMENT_STORE` - `mongo` (default) over the `mongo`
+/// client, `memory` or `embedded`, the latter kept in `DOCUMENT_STORE_PATH`
+/// (default `./data/documents`)
+pub fn from_env(mongo: Option<&mongodb::Client>) -> Result<Arc<dyn DocumentStore>, String> {
+    let backend = std::env::var("DOCUMENT_STORE").unwrap_or_else(|_| "mongo".to_string());
+    match backend.as_str() {
+        "mongo" => match mongo {
+            Some(client) => Ok(Arc::new(MongoStore::with_client(client.clone()))),
+            None => Err("MongoDB is not reachable".to_string()),
+        },
+        "memory" => Ok(Arc::new(MemoryStore::new())),
+        "embedded" => {
+            let path = std::env::var("DOCUMENT_STORE_PATH")
+                .unwrap_or_else(|_| "./data/documents".to_string());
+            Ok(Arc::new(EmbeddedStore::open(&path)?))
+        }
+        other => Err(format!("Unknown DOCUMENT_STORE '{}', expected mongo, memory or embedded", other)),
+    }
+}
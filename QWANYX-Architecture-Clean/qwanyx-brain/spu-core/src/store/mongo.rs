@@ -0,0 +1,333 @@
+//! MongoDB document store; each workspace is a database
+//!
+//! Transactions are client sessions, so they need a replica set or sharded cluster.
+
+use super::{
+    pipeline, ChangeEvent, ChangeKind, ChangeStream, DocumentStore, FindOneAndUpdateOptions, FindOptions, Index, Namespace,
+    UpdateOptions, UpdateOutcome, ID_INDEX,
+};
+use async_trait::async_trait;
+use futures::stream::{StreamExt, TryStreamExt};
+use mongodb::{Client as MongoClient, ClientSession, Collection, IndexModel, bson::{doc, Bson, Document}, options};
+use mongodb::change_stream::event::OperationType;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use tracing::{error, info};
+use std::time::Duration;
+use uuid::Uuid;
+
+/// Longest the server may spend on one aggregation
+const AGGREGATE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A client for `MONGO_URI`, once the server has answered a ping; the driver
+/// itself only connects on first use, so an unreachable server would otherwise
+/// only show when a call fails
+pub async fn connect_mongo() -> Result<MongoClient, String> {
+    let mongo_uri = std::env::var("MONGO_URI")
+        .unwrap_or_else(|_| "mongodb://localhost:27017/?serverSelectionTimeoutMS=5000".to_string());
+    let failed = |e: mongodb::error::Error| format!("MongoDB connection failed: {}", e);
+
+    let client = MongoClient::with_uri_str(&mongo_uri).await.map_err(failed)?;
+    client.database("admin").run_command(doc! { "ping": 1 }, None).await.map_err(failed)?;
+    info!("Connected to MongoDB");
+    Ok(client)
+}
+
+pub struct MongoStore {
+    client: Option<Arc<MongoClient>>,
+    sessions: Mutex<HashMap<String, Arc<Mutex<ClientSession>>>>,
+}
+
+impl MongoStore {
+    /// A store that is not connected yet; every call fails until `connect`
+    pub fn new() -> Self {
+        Self { client: None, sessions: Mutex::new(HashMap::new()) }
+    }
+
+    /// A store over a client already connected, shared with other stores
+    pub fn with_client(client: MongoClient) -> Self {
+        Self { client: Some(Arc::new(client)), sessions: Mutex::new(HashMap::new()) }
+    }
+
+    pub async fn connect(&mut self) -> Result<(), String> {
+        let client = connect_mongo().await.inspect_err(|e| error!("{}", e))?;
+        self.client = Some(Arc::new(client));
+        Ok(())
+    }
+
+    fn client(&self) -> Result<&MongoClient, String> {
+        self.client.as_deref().ok_or_else(|| "Database not connected".to_string())
+    }
+
+    fn collection(&self, ns: Namespace<'_>) -> Result<Collection<Document>, String> {
+        Ok(self.client()?.database(ns.workspace).collection(ns.collection))
+    }
+
+    /// The session of an operation's transaction, if it has one
+    async fn session(&self, ns: Namespace<'_>) -> Result<Option<Arc<Mutex<ClientSession>>>, String> {
+        match ns.transaction {
+            Some(id) => self.sessions.lock().await.get(id).cloned()
+                .map(Some)
+                .ok_or_else(|| format!("Unknown transaction {}", id)),
+            None => Ok(None),
+        }
+    }
+
+    async fn end(&self, transaction: &str) -> Result<Arc<Mutex<ClientSession>>, String> {
+        self.sessions.lock().await.remove(transaction)
+            .ok_or_else(|| format!("Unknown transaction {}", transaction))
+    }
+}
+
+impl Default for MongoStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[async_trait]
+impl DocumentStore for MongoStore {
+    async fn insert(&self, ns: Namespace<'_>, document: Document) -> Result<Bson, String> {
+        let collection = self.collection(ns)?;
+        let result = match self.session(ns).await? {
+            Some(session) => collection.insert_one_with_session(document, None, &mut *session.lock().await).await,
+            None => collection.insert_one(document, None).await,
+        };
+        Ok(result.map_err(|e| e.to_string())?.inserted_id)
+    }
+
+    async fn insert_many(&self, ns: Namespace<'_>, documents: Vec<Document>) -> Result<Vec<Bson>, String> {
+        let collection = self.collection(ns)?;
+        let result = match self.session(ns).await? {
+            Some(session) => collection.insert_many_with_session(documents, None, &mut *session.lock().await).await,
+            None => collection.insert_many(documents, None).await,
+        };
+        let mut ids: Vec<(usize, Bson)> = result.map_err(|e| e.to_string())?.inserted_ids.into_iter().collect();
+        ids.sort_by_key(|(index, _)| *index);
+        Ok(ids.into_iter().map(|(_, id)| id).collect())
+    }
+
+    async fn find(&self, ns: Namespace<'_>, filter: Document, options: FindOptions) -> Result<Vec<Document>, String> {
+        let options = options::FindOptions::builder()
+            .projection(options.projection)
+            .sort(options.sort)
+            .limit(options.limit)
+            .skip(options.skip)
+            .build();
+        let collection = self.collection(ns)?;
+        match self.session(ns).await? {
+            Some(session) => {
+                let mut session = session.lock().await;
+                let mut cursor = collection.find_with_session(filter, options, &mut session).await
+                    .map_err(|e| e.to_string())?;
+                let documents = cursor.stream(&mut session).try_collect().await;
+                documents.map_err(|e| e.to_string())
+            }
+            None => {
+                let cursor = collection.find(filter, options).await.map_err(|e| e.to_string())?;
+                cursor.try_collect().await.map_err(|e| e.to_string())
+            }
+        }
+    }
+
+    async fn count(&self, ns: Namespace<'_>, filter: Document) -> Result<u64, String> {
+        let collection = self.collection(ns)?;
+        let result = match self.session(ns).await? {
+            Some(session) => collection.count_documents_with_session(filter, None, &mut *session.lock().await).await,
+            None => collection.count_documents(filter, None).await,
+        };
+        result.map_err(|e| e.to_string())
+    }
+
+    async fn update(&self, ns: Namespace<'_>, filter: Document, update: Document, options: UpdateOptions) -> Result<UpdateOutcome, String> {
+        let collection = self.collection(ns)?;
+        let driver_options = options::UpdateOptions::builder().upsert(options.upsert).build();
+        let session = self.session(ns).await?;
+        let result = match (&session, options.many) {
+            (Some(session), true) => collection.update_many_with_session(filter, update, driver_options, &mut *session.lock().await).await,
+            (Some(session), false) => collection.update_one_with_session(filter, update, driver_options, &mut *session.lock().await).await,
+            (None, true) => collection.update_many(filter, update, driver_options).await,
+            (None, false) => collection.update_one(filter, update, driver_options).await,
+        }
+        .map_err(|e| e.to_string())?;
+        Ok(UpdateOutcome {
+            matched: result.matched_count,
+            modified: result.modified_count,
+            upserted_id: result.upserted_id,
+        })
+    }
+
+    async fn find_one_and_update(&self, ns: Namespace<'_>, filter: Document, update: Document, options: FindOneAndUpdateOptions) -> Result<Option<Document>, String> {
+        let return_document = if options.return_updated {
+            options::ReturnDocument::After
+        } else {
+            options::ReturnDocument::Before
+        };
+        let driver_options = options::FindOneAndUpdateOptions::builder()
+            .sort(options.sort)
+            .projection(options.projection)
+            .upsert(options.upsert)
+            .return_document(return_document)
+            .build();
+        let collection = self.collection(ns)?;
+        let result = match self.session(ns).await? {
+            Some(session) => collection.find_one_and_update_with_session(filter, update, driver_options, &mut *session.lock().await).await,
+            None => collection.find_one_and_update(filter, update, driver_options).await,
+        };
+        result.map_err(|e| e.to_string())
+    }
+
+    async fn delete(&self, ns: Namespace<'_>, filter: Document) -> Result<u64, String> {
+        let collection = self.collection(ns)?;
+        let result = match self.session(ns).await? {
+            Some(session) => collection.delete_many_with_session(filter, None, &mut *session.lock().await).await,
+            None => collection.delete_many(filter, None).await,
+        };
+        Ok(result.map_err(|e| e.to_string())?.deleted_count)
+    }
+
+    async fn aggregate(&self, ns: Namespace<'_>, pipeline: Vec<Document>, limit: usize) -> Result<Vec<Document>, String> {
+        pipeline::check(&pipeline)?;
+        let options = options::AggregateOptions::builder()
+            .max_time(AGGREGATE_TIMEOUT)
+            .build();
+        let collection = self.collection(ns)?;
+        // Only as many documents as asked for leave the server
+        match self.session(ns).await? {
+            Some(session) => {
+                let mut session = session.lock().await;
+                let mut cursor = collection.aggregate_with_session(pipeline, options, &mut session).await
+                    .map_err(|e| e.to_string())?;
+                let mut documents = Vec::new();
+                let mut stream = cursor.stream(&mut session);
+                while documents.len() < limit {
+                    match stream.try_next().await.map_err(|e| e.to_string())? {
+                        Some(document) => documents.push(document),
+                        None => break,
+                    }
+                }
+                Ok(documents)
+            }
+            None => {
+                let cursor = collection.aggregate(pipeline, options).await.map_err(|e| e.to_string())?;
+                cursor.take(limit).try_collect().await.map_err(|e| e.to_string())
+            }
+        }
+    }
+
+    async fn create_index(&self, ns: Namespace<'_>, index: Index) -> Result<(), String> {
+        if ns.transaction.is_some() {
+            return Err("Indexes cannot be changed in a transaction".to_string());
+        }
+        let model = IndexModel::builder()
+            .keys(index.keys)
+            .options(options::IndexOptions::builder().name(index.name).unique(index.unique).build())
+            .build();
+        self.collection(ns)?.create_index(model, None).await
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    }
+
+    async fn drop_index(&self, ns: Namespace<'_>, name: &str) -> Result<(), String> {
+        if ns.transaction.is_some() {
+            return Err("Indexes cannot be changed in a transaction".to_string());
+        }
+        if name == ID_INDEX {
+            return Err(format!("Cannot drop the {} index", ID_INDEX));
+        }
+        self.collection(ns)?.drop_index(name, None).await.map_err(|e| e.to_string())
+    }
+
+    async fn list_indexes(&self, ns: Namespace<'_>) -> Result<Vec<Index>, String> {
+        let cursor = self.collection(ns)?.list_indexes(None).await.map_err(|e| e.to_string())?;
+        let models: Vec<IndexModel> = cursor.try_collect().await.map_err(|e| e.to_string())?;
+        Ok(models.into_iter()
+            .map(|model| {
+                let options = model.options.unwrap_or_default();
+                let name = options.name.unwrap_or_default();
+                // The _id index is unique without saying so
+                let unique = name == ID_INDEX || options.unique.unwrap_or(false);
+                Index { name, keys: model.keys, unique }
+            })
+            .collect())
+    }
+
+    async fn rename_collection(&self, ns: Namespace<'_>, to: &str) -> Result<(), String> {
+        if ns.transaction.is_some() {
+            return Err("Collections cannot be renamed in a transaction".to_string());
+        }
+        let command = doc! {
+            "renameCollection": format!("{}.{}", ns.workspace, ns.collection),
+            "to": format!("{}.{}", ns.workspace, to),
+        };
+        self.client()?.database("admin").run_command(command, None).await
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    }
+
+    async fn watch(&self, ns: Namespace<'_>) -> Result<ChangeStream, String> {
+        let options = options::ChangeStreamOptions::builder()
+            .full_document(Some(options::FullDocumentType::UpdateLookup))
+            .build();
+        let stream = self.collection(ns)?.watch(None, options).await
+            .map_err(|e| format!("Change stream unavailable: {}", e))?;
+
+        let (workspace, collection) = (ns.workspace.to_string(), ns.collection.to_string());
+        let changes = stream.filter_map(move |event| {
+            let (workspace, collection) = (workspace.clone(), collection.clone());
+            async move {
+                let event = match event {
+                    Ok(event) => event,
+                    Err(e) => return Some(Err(e.to_string())),
+                };
+                let kind = match event.operation_type {
+                    OperationType::Insert => ChangeKind::Insert,
+                    OperationType::Update | OperationType::Replace => ChangeKind::Update,
+                    OperationType::Delete => ChangeKind::Delete,
+                    OperationType::Drop | OperationType::Rename | OperationType::DropDatabase | OperationType::Invalidate => {
+                        return Some(Err(format!("{} was dropped or renamed", collection)));
+                    }
+                    _ => return None,
+                };
+                let id = event.document_key.and_then(|key| key.get("_id").cloned()).unwrap_or(Bson::Null);
+                Some(Ok(ChangeEvent { kind, workspace, collection, id, document: event.full_document }))
+            }
+        });
+        Ok(Box::pin(changes))
+    }
+
+    async fn begin(&self) -> Result<String, String> {
+        let mut session = self.client()?.start_session(None).await
+            .map_err(|e| format!("Failed to start session: {}", e))?;
+        session.start_transaction(None).await
+            .map_err(|e| format!("Failed to start transaction: {}", e))?;
+
+        let id = Uuid::new_v4().to_string();
+        self.sessions.lock().await.insert(id.clone(), Arc::new(Mutex::new(session)));
+        Ok(id)
+    }
+
+    async fn commit(&self, transaction: &str) -> Result<(), String> {
+        let session = self.end(transaction).await?;
+        let mut session = session.lock().await;
+        if let Err(e) = session.commit_transaction().await {
+            session.abort_transaction().await.ok();
+            return Err(format!("Transaction failed, nothing was committed: {}", e));
+        }
+        Ok(())
+    }
+
+    async fn abort(&self, transaction: &str) -> Result<(), String> {
+        let session = self.end(transaction).await?;
+        let result = session.lock().await.abort_transaction().await;
+        result.map_err(|e| format!("Failed to abort transaction: {}", e))
+    }
+
+    async fn health(&self) -> Result<(), String> {
+        match &self.client {
+            Some(_) => Ok(()),
+            None => Err("Not connected to MongoDB".to_string()),
+        }
+    }
+}
@@ -0,0 +1,735 @@
+//! Aggregation Pipelines
+//!
+//! MongoDB aggregation for the local stores. Supported stages: `$match`,
+//! `$project`, `$addFields`/`$set`, `$unset`, `$group`, `$sort`, `$skip`,
+//! `$limit`, `$count`, `$unwind`, `$lookup` (the `localField`/`foreignField`
+//! form), `$sortByCount` and `$replaceRoot`/`$replaceWith`.
+//!
+//! Expressions cover field paths, `$$ROOT`, `$literal`, arithmetic, comparisons,
+//! `$and`/`$or`/`$not`, `$cond`, `$ifNull`, `$concat`, `$toLower`/`$toUpper`,
+//! `$toString`, `$size`, `$in`, `$arrayElemAt` and date parts such as `$year`.
+//! Group accumulators are `$sum`, `$avg`, `$min`, `$max`, `$first`, `$last`,
+//! `$push`, `$addToSet` and `$count`.
+
+use super::query::{self, as_f64, as_i64, truthy, type_rank};
+use chrono::{Datelike, Timelike};
+use mongodb::bson::{doc, Bson, Document};
+use std::collections::HashMap;
+
+/// Stages that write to other collections, refused by `aggregate` on every store
+pub const WRITE_STAGES: [&str; 2] = ["$out", "$merge"];
+
+/// Check the shape of a pipeline, and of those nested in its stages: one
+/// operator per stage, no writes, and every other collection it reads named
+pub fn check(pipeline: &[Document]) -> Result<(), String> {
+    for (index, stage) in pipeline.iter().enumerate() {
+        let name = stage_name(stage)
+            .ok_or_else(|| format!("Stage {} must be a single operator such as {{\"$match\": ...}}", index + 1))?;
+        if WRITE_STAGES.contains(&name) {
+            return Err(format!("{} is not allowed, aggregations are read-only", name));
+        }
+        let (_, pipelines) = reads(name, &stage[name])?;
+        for pipeline in pipelines {
+            check(&pipeline)?;
+        }
+    }
+    Ok(())
+}
+
+fn stage_name(stage: &Document) -> Option<&str> {
+    match stage.keys().next() {
+        Some(name) if stage.len() == 1 && name.starts_with('$') => Some(name),
+        _ => None,
+    }
+}
+
+/// Collections a pipeline reads besides its own, through `$lookup`,
+/// `$graphLookup` and `$unionWith`, however deep in `$facet` and `$lookup`
+/// sub-pipelines
+pub fn lookups(pipeline: &[Document]) -> Vec<String> {
+    let mut collections = Vec::new();
+    for stage in pipeline {
+        let Some((name, spec)) = stage.iter().next() else { continue };
+        if let Ok((from, pipelines)) = reads(name, spec) {
+            collections.extend(from);
+            for pipeline in pipelines {
+                collections.extend(lookups(&pipeline));
+            }
+        }
+    }
+    collections
+}
+
+/// The collection a stage reads besides the pipeline's own, and the pipelines
+/// nested in it
+fn reads(name: &str, spec: &Bson) -> Result<(Option<String>, Vec<Vec<Document>>), String> {
+    match (name, spec) {
+        ("$lookup" | "$graphLookup", Bson::Document(spec)) => {
+            let from = spec.get_str("from")
+                .map_err(|_| format!("{} needs the name of a collection as 'from'", name))?;
+            let pipelines = match spec.get("pipeline") {
+                Some(pipeline) => vec![stages(pipeline, name)?],
+                None => Vec::new(),
+            };
+            Ok((Some(from.to_string()), pipelines))
+        }
+        ("$unionWith", Bson::String(coll)) => Ok((Some(coll.clone()), Vec::new())),
+        ("$unionWith", Bson::Document(spec)) => {
+            let coll = spec.get_str("coll")
+                .map_err(|_| "$unionWith needs the name of a collection as 'coll'".to_string())?;
+            let pipelines = match spec.get("pipeline") {
+                Some(pipeline) => vec![stages(pipeline, name)?],
+                None => Vec::new(),
+            };
+            Ok((Some(coll.to_string()), pipelines))
+        }
+        ("$facet", Bson::Document(facets)) => {
+            let pipelines = facets.values()
+                .map(|pipeline| stages(pipeline, name))
+                .collect::<Result<_, _>>()?;
+            Ok((None, pipelines))
+        }
+        ("$lookup" | "$graphLookup" | "$unionWith" | "$facet", _) => Err(format!("{} needs a document", name)),
+        _ => Ok((None, Vec::new())),
+    }
+}
+
+/// A pipeline nested in a stage
+fn stages(pipeline: &Bson, name: &str) -> Result<Vec<Document>, String> {
+    match pipeline {
+        Bson::Array(stages) => stages.iter()
+            .map(|stage| match stage {
+                Bson::Document(stage) => Ok(stage.clone()),
+                _ => Err(format!("The stages of {} must be documents", name)),
+            })
+            .collect(),
+        _ => Err(format!("The pipeline of {} must be an array", name)),
+    }
+}
+
+/// Run a pipeline over a collection; `foreign` holds the collections named by
+/// `lookups`
+pub fn run(documents: Vec<Document>, pipeline: &[Document], foreign: &HashMap<String, Vec<Document>>) -> Result<Vec<Document>, String> {
+    check(pipeline)?;
+    let mut documents = documents;
+    for stage in pipeline {
+        let Some((name, spec)) = stage.iter().next() else { continue };
+        documents = match name.as_str() {
+            "$match" => {
+                let filter = document_spec(spec, name)?;
+                let mut matched = Vec::new();
+                for document in documents {
+                    if query::matches(&document, filter)? {
+                        matched.push(document);
+                    }
+                }
+                matched
+            }
+            "$project" => {
+                let spec = document_spec(spec, name)?;
+                documents.iter().map(|document| project(document, spec)).collect::<Result<_, _>>()?
+            }
+            "$addFields" | "$set" => {
+                let spec = document_spec(spec, name)?;
+                documents.into_iter().map(|document| add_fields(document, spec)).collect::<Result<_, _>>()?
+            }
+            "$unset" => {
+                let paths = field_names(spec, name)?;
+                for document in documents.iter_mut() {
+                    for path in &paths {
+                        query::remove_path(document, path);
+                    }
+                }
+                documents
+            }
+            "$group" => group(&documents, document_spec(spec, name)?)?,
+            "$sort" => {
+                let sort = document_spec(spec, name)?;
+                documents.sort_by(|a, b| query::compare_by(sort, a, b));
+                documents
+            }
+            "$skip" => documents.into_iter().skip(count_spec(spec, name)?).collect(),
+            "$limit" => documents.into_iter().take(count_spec(spec, name)?).collect(),
+            "$count" => {
+                let field = match spec {
+                    Bson::String(field) if !field.is_empty() && !field.starts_with('$') => field,
+                    _ => return Err("$count needs a field name".to_string()),
+                };
+                if documents.is_empty() {
+                    Vec::new()
+                } else {
+                    vec![doc! { field: integer(documents.len() as i64) }]
+                }
+            }
+            "$unwind" => unwind(documents, spec)?,
+            "$lookup" => lookup(documents, document_spec(spec, name)?, foreign)?,
+            "$sortByCount" => {
+                let mut groups = group(&documents, &doc! { "_id": spec.clone(), "count": { "$sum": 1 } })?;
+                groups.sort_by(|a, b| query::compare_by(&doc! { "count": -1 }, a, b));
+                groups
+            }
+            "$replaceRoot" | "$replaceWith" => {
+                let root = if name == "$replaceRoot" {
+                    document_spec(spec, name)?.get("newRoot").ok_or("$replaceRoot needs newRoot")?
+                } else {
+                    spec
+                };
+                documents.iter()
+                    .map(|document| match evaluate(root, document)? {
+                        Bson::Document(replacement) => Ok(replacement),
+                        other => Err(format!("{} must produce a document, got {}", name, other)),
+                    })
+                    .collect::<Result<_, String>>()?
+            }
+            other => return Err(format!("Unsupported aggregation stage '{}'", other)),
+        };
+    }
+    Ok(documents)
+}
+
+fn document_spec<'a>(spec: &'a Bson, stage: &str) -> Result<&'a Document, String> {
+    match spec {
+        Bson::Document(spec) => Ok(spec),
+        _ => Err(format!("{} needs a document", stage)),
+    }
+}
+
+fn count_spec(spec: &Bson, stage: &str) -> Result<usize, String> {
+    as_i64(spec)
+        .filter(|n| *n >= 0)
+        .map(|n| n as usize)
+        .ok_or_else(|| format!("{} needs a non-negative number", stage))
+}
+
+fn field_names(spec: &Bson, stage: &str) -> Result<Vec<String>, String> {
+    match spec {
+        Bson::String(field) => Ok(vec![field.clone()]),
+        Bson::Array(fields) => fields.iter()
+            .map(|field| match field {
+                Bson::String(field) => Ok(field.clone()),
+                _ => Err(format!("{} needs field names", stage)),
+            })
+            .collect(),
+        _ => Err(format!("{} needs a field name or a list of them", stage)),
+    }
+}
+
+/// Int32 when it fits
+fn integer(n: i64) -> Bson {
+    i32::try_from(n).map(Bson::Int32).unwrap_or(Bson::Int64(n))
+}
+
+/// Missing values count as null once stored
+fn defined(value: Bson) -> Bson {
+    match value {
+        Bson::Undefined => Bson::Null,
+        value => value,
+    }
+}
+
+/// Set a computed field, leaving it out when the expression found nothing
+fn set_computed(document: &mut Document, path: &str, value: Bson) -> Result<(), String> {
+    if matches!(value, Bson::Undefined) {
+        return Ok(());
+    }
+    query::set_path(document, path, value)
+}
+
+// ================================================================================
+// STAGES
+// ================================================================================
+
+fn project(document: &Document, spec: &Document) -> Result<Document, String> {
+    let is_flag = |value: &Bson| matches!(value, Bson::Boolean(_) | Bson::Int32(_) | Bson::Int64(_) | Bson::Double(_));
+    if spec.values().all(is_flag) {
+        return query::project(document, spec);
+    }
+
+    // Computed fields make it an inclusion projection
+    if spec.iter().any(|(field, value)| field != "_id" && is_flag(value) && !truthy(value)) {
+        return Err("$project cannot mix exclusions and computed fields".to_string());
+    }
+    let mut projected = Document::new();
+    if spec.get("_id").map(|id| !is_flag(id) || truthy(id)).unwrap_or(true) {
+        if let Some(id) = document.get("_id") {
+            projected.insert("_id", id.clone());
+        }
+    }
+    for (field, value) in spec {
+        if is_flag(value) {
+            if field != "_id" {
+                query::copy_path(document, &mut projected, field);
+            }
+        } else {
+            set_computed(&mut projected, field, evaluate(value, document)?)?;
+        }
+    }
+    Ok(projected)
+}
+
+fn add_fields(document: Document, spec: &Document) -> Result<Document, String> {
+    let mut extended = document.clone();
+    for (field, expression) in spec {
+        set_computed(&mut extended, field, evaluate(expression, &document)?)?;
+    }
+    Ok(extended)
+}
+
+fn unwind(documents: Vec<Document>, spec: &Bson) -> Result<Vec<Document>, String> {
+    let (path, preserve) = match spec {
+        Bson::String(path) => (path.as_str(), false),
+        Bson::Document(options) => (
+            options.get_str("path").map_err(|_| "$unwind needs a path".to_string())?,
+            options.get_bool("preserveNullAndEmptyArrays").unwrap_or(false),
+        ),
+        _ => return Err("$unwind needs a path such as \"$tags\"".to_string()),
+    };
+    let path = path.strip_prefix('$').ok_or("$unwind path must start with '$'")?;
+
+    let mut unwound = Vec::new();
+    for document in documents {
+        match field_path(&document, path) {
+            Bson::Array(items) if !items.is_empty() => {
+                for item in items {
+                    let mut copy = document.clone();
+                    query::set_path(&mut copy, path, item)?;
+                    unwound.push(copy);
+                }
+            }
+            Bson::Array(_) | Bson::Null | Bson::Undefined => {
+                if preserve {
+                    let mut copy = document;
+                    query::remove_path(&mut copy, path);
+                    unwound.push(copy);
+                }
+            }
+            _ => unwound.push(document),
+        }
+    }
+    Ok(unwound)
+}
+
+fn lookup(documents: Vec<Document>, spec: &Document, foreign: &HashMap<String, Vec<Document>>) -> Result<Vec<Document>, String> {
+    if spec.contains_key("pipeline") {
+        return Err("$lookup with a pipeline is not supported, use localField and foreignField".to_string());
+    }
+    let field = |name: &str| spec.get_str(name).map_err(|_| format!("$lookup needs '{}'", name));
+    let (from, local_field, foreign_field, target) = (field("from")?, field("localField")?, field("foreignField")?, field("as")?);
+    let candidates = foreign.get(from).map(Vec::as_slice).unwrap_or_default();
+
+    // Arrays match on any element; missing fields match null
+    let keys = |document: &Document, path: &str| -> Vec<Bson> {
+        let mut keys = Vec::new();
+        for value in query::values_at(document, path) {
+            match value {
+                Bson::Array(items) => keys.extend(items.iter().cloned()),
+                value => keys.push(value.clone()),
+            }
+        }
+        if keys.is_empty() {
+            keys.push(Bson::Null);
+        }
+        keys
+    };
+
+    let mut joined = Vec::with_capacity(documents.len());
+    for mut document in documents {
+        let local = keys(&document, local_field);
+        let matches: Vec<Bson> = candidates.iter()
+            .filter(|candidate| {
+                let remote = keys(candidate, foreign_field);
+                local.iter().any(|l| remote.iter().any(|r| query::equal(l, r)))
+            })
+            .cloned()
+            .map(Bson::Document)
+            .collect();
+        query::set_path(&mut document, target, Bson::Array(matches))?;
+        joined.push(document);
+    }
+    Ok(joined)
+}
+
+const ACCUMULATORS: [&str; 9] = ["$sum", "$avg", "$min", "$max", "$first", "$last", "$push", "$addToSet", "$count"];
+
+enum Accumulator {
+    Sum(Bson),
+    Avg { total: f64, count: u64 },
+    Min(Option<Bson>),
+    Max(Option<Bson>),
+    First(Option<Bson>),
+    Last(Bson),
+    Push(Vec<Bson>),
+    AddToSet(Vec<Bson>),
+    Count(i64),
+}
+
+impl Accumulator {
+    fn new(operator: &str) -> Self {
+        match operator {
+            "$sum" => Accumulator::Sum(Bson::Int32(0)),
+            "$avg" => Accumulator::Avg { total: 0.0, count: 0 },
+            "$min" => Accumulator::Min(None),
+            "$max" => Accumulator::Max(None),
+            "$first" => Accumulator::First(None),
+            "$last" => Accumulator::Last(Bson::Null),
+            "$push" => Accumulator::Push(Vec::new()),
+            "$addToSet" => Accumulator::AddToSet(Vec::new()),
+            _ => Accumulator::Count(0),
+        }
+    }
+
+    fn add(&mut self, value: Bson) -> Result<(), String> {
+        let present = !matches!(value, Bson::Null | Bson::Undefined);
+        match self {
+            // Non-numeric values are ignored, as MongoDB does
+            Accumulator::Sum(total) if type_rank(&value) == 2 => *total = arithmetic("$add", total, &value)?,
+            Accumulator::Sum(_) => {}
+            Accumulator::Avg { total, count } if type_rank(&value) == 2 => {
+                *total += as_f64(&value);
+                *count += 1;
+            }
+            Accumulator::Avg { .. } => {}
+            Accumulator::Min(current) if present => {
+                if current.as_ref().is_none_or(|c| query::compare(&value, c).is_lt()) {
+                    *current = Some(value);
+                }
+            }
+            Accumulator::Max(current) if present => {
+                if current.as_ref().is_none_or(|c| query::compare(&value, c).is_gt()) {
+                    *current = Some(value);
+                }
+            }
+            Accumulator::Min(_) | Accumulator::Max(_) => {}
+            Accumulator::First(first) => {
+                if first.is_none() {
+                    *first = Some(defined(value));
+                }
+            }
+            Accumulator::Last(last) => *last = defined(value),
+            Accumulator::Push(items) => {
+                if !matches!(value, Bson::Undefined) {
+                    items.push(value);
+                }
+            }
+            Accumulator::AddToSet(items) => {
+                if !matches!(value, Bson::Undefined) && !items.iter().any(|item| query::equal(item, &value)) {
+                    items.push(value);
+                }
+            }
+            Accumulator::Count(count) => *count += 1,
+        }
+        Ok(())
+    }
+
+    fn finish(self) -> Bson {
+        match self {
+            Accumulator::Sum(total) => total,
+            Accumulator::Avg { count: 0, .. } => Bson::Null,
+            Accumulator::Avg { total, count } => Bson::Double(total / count as f64),
+            Accumulator::Min(value) | Accumulator::Max(value) | Accumulator::First(value) => value.unwrap_or(Bson::Null),
+            Accumulator::Last(value) => value,
+            Accumulator::Push(items) | Accumulator::AddToSet(items) => Bson::Array(items),
+            Accumulator::Count(count) => integer(count),
+        }
+    }
+}
+
+fn group(documents: &[Document], spec: &Document) -> Result<Vec<Document>, String> {
+    let id = spec.get("_id").ok_or("$group needs an _id")?;
+    let mut fields = Vec::new();
+    for (field, accumulator) in spec.iter().filter(|(field, _)| *field != "_id") {
+        let (operator, argument) = match accumulator {
+            Bson::Document(accumulator) if accumulator.len() == 1 => accumulator.iter().next().ok_or("empty accumulator")?,
+            _ => return Err(format!("$group field '{}' needs one accumulator such as {{\"$sum\": 1}}", field)),
+        };
+        if !ACCUMULATORS.contains(&operator.as_str()) {
+            return Err(format!("Unsupported accumulator '{}'", operator));
+        }
+        fields.push((field.as_str(), operator.as_str(), argument));
+    }
+
+    // Groups in order of first appearance
+    let mut groups: Vec<(Bson, Vec<Accumulator>)> = Vec::new();
+    for document in documents {
+        let key = defined(evaluate(id, document)?);
+        let index = match groups.iter().position(|(existing, _)| query::equal(existing, &key)) {
+            Some(index) => index,
+            None => {
+                groups.push((key, fields.iter().map(|(_, operator, _)| Accumulator::new(operator)).collect()));
+                groups.len() - 1
+            }
+        };
+        for ((_, _, argument), accumulator) in fields.iter().zip(groups[index].1.iter_mut()) {
+            accumulator.add(evaluate(argument, document)?)?;
+        }
+    }
+
+    Ok(groups.into_iter()
+        .map(|(key, accumulators)| {
+            let mut grouped = doc! { "_id": key };
+            for ((field, _, _), accumulator) in fields.iter().zip(accumulators) {
+                grouped.insert(*field, accumulator.finish());
+            }
+            grouped
+        })
+        .collect())
+}
+
+// ================================================================================
+// EXPRESSIONS
+// ================================================================================
+
+/// Value of an aggregation expression against a document; fields that don't
+/// exist are `Undefined`
+pub fn evaluate(expression: &Bson, document: &Document) -> Result<Bson, String> {
+    match expression {
+        Bson::String(reference) if reference.starts_with("$$") => {
+            let (variable, path) = match reference.split_once('.') {
+                Some((variable, path)) => (variable, Some(path)),
+                None => (reference.as_str(), None),
+            };
+            if variable != "$$ROOT" && variable != "$$CURRENT" {
+                return Err(format!("Unknown variable '{}'", variable));
+            }
+            Ok(match path {
+                Some(path) => field_path(document, path),
+                None => Bson::Document(document.clone()),
+            })
+        }
+        Bson::String(reference) if reference.starts_with('$') => Ok(field_path(document, &reference[1..])),
+        Bson::Document(object) => match object.iter().next() {
+            Some((name, argument)) if object.len() == 1 && name.starts_with('$') => operator(name, argument, document),
+            _ => {
+                let mut evaluated = Document::new();
+                for (field, value) in object {
+                    set_computed(&mut evaluated, field, evaluate(value, document)?)?;
+                }
+                Ok(Bson::Document(evaluated))
+            }
+        },
+        Bson::Array(items) => items.iter()
+            .map(|item| evaluate(item, document).map(defined))
+            .collect::<Result<Vec<_>, _>>()
+            .map(Bson::Array),
+        literal => Ok(literal.clone()),
+    }
+}
+
+/// Field path value; through an array it collects the field of every element
+fn field_path(document: &Document, path: &str) -> Bson {
+    let (head, rest) = match path.split_once('.') {
+        Some((head, rest)) => (head, Some(rest)),
+        None => (path, None),
+    };
+    match (document.get(head), rest) {
+        (None, _) => Bson::Undefined,
+        (Some(value), None) => value.clone(),
+        (Some(Bson::Document(inner)), Some(rest)) => field_path(inner, rest),
+        (Some(Bson::Array(items)), Some(rest)) => Bson::Array(items.iter()
+            .filter_map(|item| match item {
+                Bson::Document(inner) => Some(field_path(inner, rest)),
+                _ => None,
+            })
+            .filter(|value| !matches!(value, Bson::Undefined))
+            .collect()),
+        _ => Bson::Undefined,
+    }
+}
+
+fn operator(name: &str, argument: &Bson, document: &Document) -> Result<Bson, String> {
+    match name {
+        "$literal" => return Ok(argument.clone()),
+        "$cond" => {
+            let (condition, then, otherwise) = match argument {
+                Bson::Array(branches) if branches.len() == 3 => (&branches[0], &branches[1], &branches[2]),
+                Bson::Document(branches) => match (branches.get("if"), branches.get("then"), branches.get("else")) {
+                    (Some(condition), Some(then), Some(otherwise)) => (condition, then, otherwise),
+                    _ => return Err("$cond needs if, then and else".to_string()),
+                },
+                _ => return Err("$cond needs if, then and else".to_string()),
+            };
+            let branch = if truthy(&evaluate(condition, document)?) { then } else { otherwise };
+            return evaluate(branch, document);
+        }
+        _ => {}
+    }
+
+    let args = match argument {
+        Bson::Array(items) => items.iter().map(|item| evaluate(item, document)).collect::<Result<Vec<_>, _>>()?,
+        single => vec![evaluate(single, document)?],
+    };
+    let arity = |n: usize| {
+        if args.len() == n {
+            Ok(())
+        } else {
+            Err(format!("{} needs {} argument(s)", name, n))
+        }
+    };
+    let is_null = |value: &Bson| matches!(value, Bson::Null | Bson::Undefined);
+
+    match name {
+        "$add" | "$multiply" => {
+            if args.iter().any(is_null) {
+                return Ok(Bson::Null);
+            }
+            let start = if name == "$add" { Bson::Int32(0) } else { Bson::Int32(1) };
+            args.iter().try_fold(start, |total, value| arithmetic(name, &total, value))
+        }
+        "$subtract" | "$divide" | "$mod" => {
+            arity(2)?;
+            if args.iter().any(is_null) {
+                return Ok(Bson::Null);
+            }
+            arithmetic(name, &args[0], &args[1])
+        }
+        "$eq" | "$ne" | "$gt" | "$gte" | "$lt" | "$lte" => {
+            arity(2)?;
+            let (a, b) = (defined(args[0].clone()), defined(args[1].clone()));
+            let ordering = query::compare(&a, &b);
+            Ok(Bson::Boolean(match name {
+                "$eq" => query::equal(&a, &b),
+                "$ne" => !query::equal(&a, &b),
+                "$gt" => ordering.is_gt(),
+                "$gte" => ordering.is_ge(),
+                "$lt" => ordering.is_lt(),
+                _ => ordering.is_le(),
+            }))
+        }
+        "$and" => Ok(Bson::Boolean(args.iter().all(truthy))),
+        "$or" => Ok(Bson::Boolean(args.iter().any(truthy))),
+        "$not" => {
+            arity(1)?;
+            Ok(Bson::Boolean(!truthy(&args[0])))
+        }
+        "$ifNull" => Ok(args.iter()
+            .find(|value| !is_null(value))
+            .or(args.last())
+            .cloned()
+            .map(defined)
+            .unwrap_or(Bson::Null)),
+        "$concat" => {
+            let mut concatenated = String::new();
+            for value in &args {
+                match value {
+                    Bson::String(s) => concatenated.push_str(s),
+                    value if is_null(value) => return Ok(Bson::Null),
+                    other => return Err(format!("$concat needs strings, got {}", other)),
+                }
+            }
+            Ok(Bson::String(concatenated))
+        }
+        "$toLower" | "$toUpper" | "$toString" => {
+            arity(1)?;
+            let text = match &args[0] {
+                value if is_null(value) => return Ok(if name == "$toString" { Bson::Null } else { Bson::String(String::new()) }),
+                Bson::String(s) => s.clone(),
+                Bson::ObjectId(oid) => oid.to_hex(),
+                Bson::DateTime(date) => date.try_to_rfc3339_string().map_err(|e| e.to_string())?,
+                Bson::Boolean(b) => b.to_string(),
+                Bson::Int32(i) => i.to_string(),
+                Bson::Int64(i) => i.to_string(),
+                Bson::Double(d) => d.to_string(),
+                other => return Err(format!("{} cannot convert {}", name, other)),
+            };
+            Ok(Bson::String(match name {
+                "$toLower" => text.to_lowercase(),
+                "$toUpper" => text.to_uppercase(),
+                _ => text,
+            }))
+        }
+        "$size" => {
+            arity(1)?;
+            match &args[0] {
+                Bson::Array(items) => Ok(integer(items.len() as i64)),
+                other => Err(format!("$size needs an array, got {}", other)),
+            }
+        }
+        "$in" => {
+            arity(2)?;
+            match &args[1] {
+                Bson::Array(items) => Ok(Bson::Boolean(items.iter().any(|item| query::equal(item, &args[0])))),
+                other => Err(format!("$in needs an array, got {}", other)),
+            }
+        }
+        "$arrayElemAt" => {
+            arity(2)?;
+            match (&args[0], as_i64(&args[1])) {
+                (Bson::Array(items), Some(index)) => {
+                    let index = if index < 0 { items.len() as i64 + index } else { index };
+                    Ok(usize::try_from(index).ok().and_then(|i| items.get(i)).cloned().unwrap_or(Bson::Undefined))
+                }
+                (value, _) if is_null(value) => Ok(Bson::Null),
+                _ => Err("$arrayElemAt needs an array and an index".to_string()),
+            }
+        }
+        "$year" | "$month" | "$dayOfMonth" | "$dayOfWeek" | "$hour" | "$minute" => {
+            arity(1)?;
+            let millis = match &args[0] {
+                Bson::DateTime(date) => date.timestamp_millis(),
+                value if is_null(value) => return Ok(Bson::Null),
+                other => return Err(format!("{} needs a date, got {}", name, other)),
+            };
+            let date = chrono::DateTime::from_timestamp_millis(millis)
+                .ok_or_else(|| format!("{} got a date out of range", name))?;
+            Ok(Bson::Int32(match name {
+                "$year" => date.year(),
+                "$month" => date.month() as i32,
+                "$dayOfMonth" => date.day() as i32,
+                // Sunday is 1, as in MongoDB
+                "$dayOfWeek" => date.weekday().num_days_from_sunday() as i32 + 1,
+                "$hour" => date.hour() as i32,
+                _ => date.minute() as i32,
+            }))
+        }
+        other => Err(format!("Unsupported expression operator '{}'", other)),
+    }
+}
+
+/// Arithmetic keeping integers while they fit; dates move by milliseconds
+fn arithmetic(operator: &str, a: &Bson, b: &Bson) -> Result<Bson, String> {
+    match (a, b) {
+        (Bson::DateTime(date), offset) | (offset, Bson::DateTime(date)) if operator == "$add" && type_rank(offset) == 2 => {
+            Ok(Bson::DateTime(mongodb::bson::DateTime::from_millis(date.timestamp_millis() + as_f64(offset) as i64)))
+        }
+        (Bson::DateTime(x), Bson::DateTime(y)) if operator == "$subtract" => {
+            Ok(Bson::Int64(x.timestamp_millis() - y.timestamp_millis()))
+        }
+        (Bson::DateTime(date), offset) if operator == "$subtract" && type_rank(offset) == 2 => {
+            Ok(Bson::DateTime(mongodb::bson::DateTime::from_millis(date.timestamp_millis() - as_f64(offset) as i64)))
+        }
+        _ if type_rank(a) != 2 || type_rank(b) != 2 => Err(format!("{} needs numbers, got {} and {}", operator, a, b)),
+        (Bson::Int32(_) | Bson::Int64(_), Bson::Int32(_) | Bson::Int64(_)) if operator != "$divide" => {
+            let (x, y) = (as_i64(a).unwrap_or(0), as_i64(b).unwrap_or(0));
+            let result = match operator {
+                "$add" => x.checked_add(y),
+                "$subtract" => x.checked_sub(y),
+                "$multiply" => x.checked_mul(y),
+                _ if y == 0 => return Err("$mod by zero".to_string()),
+                _ => x.checked_rem(y),
+            };
+            let both_int32 = matches!((a, b), (Bson::Int32(_), Bson::Int32(_)));
+            Ok(match result {
+                Some(n) if both_int32 => integer(n),
+                Some(n) => Bson::Int64(n),
+                None => Bson::Double(float(operator, x as f64, y as f64)),
+            })
+        }
+        _ => {
+            let (x, y) = (as_f64(a), as_f64(b));
+            if operator == "$divide" && y == 0.0 {
+                return Err("$divide by zero".to_string());
+            }
+            Ok(Bson::Double(float(operator, x, y)))
+        }
+    }
+}
+
+fn float(operator: &str, x: f64, y: f64) -> f64 {
+    match operator {
+        "$add" => x + y,
+        "$subtract" => x - y,
+        "$multiply" => x * y,
+        "$divide" => x / y,
+        _ => x % y,
+    }
+}
@@ -0,0 +1,652 @@
+//! Query Evaluation
+//!
+//! MongoDB query semantics over documents held in process: filters, sort order,
+//! projection and update operators. Values compare the way MongoDB compares them -
+//! numbers across Int32/Int64/Double, and types ordered MinKey < null < numbers <
+//! strings < documents < arrays < binary < ObjectId < booleans < dates <
+//! timestamps < regexes < MaxKey.
+
+use super::FindOptions;
+use mongodb::bson::{Bson, Document};
+use regex::Regex;
+use std::cmp::Ordering;
+
+/// Filter, sort, skip, limit and project a collection
+pub fn find(documents: impl IntoIterator<Item = Document>, filter: &Document, options: &FindOptions) -> Result<Vec<Document>, String> {
+    let mut found = Vec::new();
+    for document in documents {
+        if matches(&document, filter)? {
+            found.push(document);
+        }
+    }
+
+    if let Some(sort) = &options.sort {
+        found.sort_by(|a, b| compare_by(sort, a, b));
+    }
+
+    let skip = options.skip.unwrap_or(0) as usize;
+    let limit = match options.limit {
+        Some(limit) if limit != 0 => limit.unsigned_abs() as usize,
+        _ => usize::MAX,
+    };
+    let page = found.into_iter().skip(skip).take(limit);
+
+    match &options.projection {
+        Some(projection) if !projection.is_empty() => page.map(|document| project(&document, projection)).collect(),
+        _ => Ok(page.collect()),
+    }
+}
+
+/// Whether a document matches a filter
+pub fn matches(document: &Document, filter: &Document) -> Result<bool, String> {
+    for (key, condition) in filter {
+        let matched = match key.as_str() {
+            "$and" => clauses(condition, key)?.iter().try_fold(true, |all, clause| Ok::<_, String>(all && matches(document, clause)?))?,
+            "$or" => clauses(condition, key)?.iter().try_fold(false, |any, clause| Ok::<_, String>(any || matches(document, clause)?))?,
+            "$nor" => !clauses(condition, key)?.iter().try_fold(false, |any, clause| Ok::<_, String>(any || matches(document, clause)?))?,
+            operator if operator.starts_with('$') => return Err(format!("Unsupported query operator '{}'", operator)),
+            path => field_matches(&values_at(document, path), condition)?,
+        };
+        if !matched {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+fn clauses<'a>(condition: &'a Bson, operator: &str) -> Result<Vec<&'a Document>, String> {
+    match condition {
+        Bson::Array(items) if !items.is_empty() => items.iter()
+            .map(|item| match item {
+                Bson::Document(clause) => Ok(clause),
+                _ => Err(format!("{} entries must be documents", operator)),
+            })
+            .collect(),
+        _ => Err(format!("{} needs a non-empty array", operator)),
+    }
+}
+
+/// Condition on the values found at a path, either `{"$op": ...}` or a value to equal
+fn field_matches(values: &[&Bson], condition: &Bson) -> Result<bool, String> {
+    match condition {
+        Bson::Document(operators) if is_operator_document(operators) => {
+            for (operator, argument) in operators {
+                if operator == "$options" {
+                    continue;
+                }
+                if !operator_matches(values, operator, argument, operators)? {
+                    return Ok(false);
+                }
+            }
+            Ok(true)
+        }
+        Bson::RegularExpression(regex) => {
+            let regex = compile(&regex.pattern, &regex.options)?;
+            Ok(candidates(values).any(|v| matches!(v, Bson::String(s) if regex.is_match(s))))
+        }
+        _ => Ok(equals_any(values, condition)),
+    }
+}
+
+fn is_operator_document(document: &Document) -> bool {
+    !document.is_empty() && document.keys().all(|k| k.starts_with('$'))
+}
+
+fn operator_matches(values: &[&Bson], operator: &str, argument: &Bson, operators: &Document) -> Result<bool, String> {
+    let compared = |accept: fn(Ordering) -> bool| {
+        candidates(values).any(|v| same_bracket(v, argument) && accept(compare(v, argument)))
+    };
+    Ok(match operator {
+        "$eq" => equals_any(values, argument),
+        "$ne" => !equals_any(values, argument),
+        "$gt" => compared(Ordering::is_gt),
+        "$gte" => compared(Ordering::is_ge),
+        "$lt" => compared(Ordering::is_lt),
+        "$lte" => compared(Ordering::is_le),
+        "$in" => in_list(values, argument)?,
+        "$nin" => !in_list(values, argument)?,
+        "$exists" => values.is_empty() != truthy(argument),
+        "$regex" => {
+            let (pattern, mut options) = match argument {
+                Bson::String(pattern) => (pattern.clone(), String::new()),
+                Bson::RegularExpression(regex) => (regex.pattern.clone(), regex.options.clone()),
+                _ => return Err("$regex needs a string".to_string()),
+            };
+            if let Some(Bson::String(extra)) = operators.get("$options") {
+                options.push_str(extra);
+            }
+            let regex = compile(&pattern, &options)?;
+            candidates(values).any(|v| matches!(v, Bson::String(s) if regex.is_match(s)))
+        }
+        "$not" => !field_matches(values, argument)?,
+        "$size" => {
+            let size = as_i64(argument).ok_or("$size needs a number")?;
+            values.iter().any(|v| matches!(v, Bson::Array(items) if items.len() as i64 == size))
+        }
+        "$all" => match argument {
+            Bson::Array(required) => !required.is_empty() && required.iter().all(|r| equals_any(values, r)),
+            _ => return Err("$all needs an array".to_string()),
+        },
+        "$elemMatch" => match argument {
+            Bson::Document(condition) => values.iter().any(|v| match v {
+                Bson::Array(items) => items.iter().any(|item| element_matches(item, condition).unwrap_or(false)),
+                _ => false,
+            }),
+            _ => return Err("$elemMatch needs a document".to_string()),
+        },
+        other => return Err(format!("Unsupported query operator '{}'", other)),
+    })
+}
+
+/// `$elemMatch` takes operators on the element itself, or a filter on its fields
+fn element_matches(item: &Bson, condition: &Document) -> Result<bool, String> {
+    if is_operator_document(condition) {
+        return field_matches(&[item], &Bson::Document(condition.clone()));
+    }
+    match item {
+        Bson::Document(document) => matches(document, condition),
+        _ => Ok(false),
+    }
+}
+
+fn in_list(values: &[&Bson], argument: &Bson) -> Result<bool, String> {
+    match argument {
+        Bson::Array(options) => {
+            for option in options {
+                if field_matches(values, option)? {
+                    return Ok(true);
+                }
+            }
+            Ok(false)
+        }
+        _ => Err("$in and $nin need an array".to_string()),
+    }
+}
+
+/// Equality, where a missing field equals null and an array equals any of its elements
+fn equals_any(values: &[&Bson], expected: &Bson) -> bool {
+    if values.is_empty() {
+        return matches!(expected, Bson::Null);
+    }
+    values.iter().any(|value| {
+        equal(value, expected) || matches!(value, Bson::Array(items) if items.iter().any(|item| equal(item, expected)))
+    })
+}
+
+/// Values to compare: those at the path, and the elements of arrays found there
+fn candidates<'a>(values: &'a [&'a Bson]) -> impl Iterator<Item = &'a Bson> {
+    values.iter().flat_map(|value| match value {
+        Bson::Array(items) => items.iter().collect::<Vec<_>>(),
+        other => vec![*other],
+    })
+}
+
+/// Every value at a dotted path, descending into arrays of documents on the way
+pub fn values_at<'a>(document: &'a Document, path: &str) -> Vec<&'a Bson> {
+    let (head, rest) = match path.split_once('.') {
+        Some((head, rest)) => (head, Some(rest)),
+        None => (path, None),
+    };
+    match (document.get(head), rest) {
+        (None, _) => Vec::new(),
+        (Some(value), None) => vec![value],
+        (Some(value), Some(rest)) => nested_values(value, rest),
+    }
+}
+
+fn nested_values<'a>(value: &'a Bson, path: &str) -> Vec<&'a Bson> {
+    match value {
+        Bson::Document(inner) => values_at(inner, path),
+        Bson::Array(items) => {
+            let (head, rest) = match path.split_once('.') {
+                Some((head, rest)) => (head, Some(rest)),
+                None => (path, None),
+            };
+            // `items.0` is a position, `items.sku` a field of every element
+            if let Ok(index) = head.parse::<usize>() {
+                return match (items.get(index), rest) {
+                    (None, _) => Vec::new(),
+                    (Some(item), None) => vec![item],
+                    (Some(item), Some(rest)) => nested_values(item, rest),
+                };
+            }
+            items.iter()
+                .filter_map(|item| match item {
+                    Bson::Document(inner) => Some(values_at(inner, path)),
+                    _ => None,
+                })
+                .flatten()
+                .collect()
+        }
+        _ => Vec::new(),
+    }
+}
+
+/// Order of two documents under a sort document
+pub fn compare_by(sort: &Document, a: &Document, b: &Document) -> Ordering {
+    sort.iter()
+        .map(|(field, direction)| {
+            let ordering = compare(&sort_value(a, field), &sort_value(b, field));
+            if direction_of(direction) < 0 { ordering.reverse() } else { ordering }
+        })
+        .find(|ordering| ordering.is_ne())
+        .unwrap_or(Ordering::Equal)
+}
+
+fn sort_value(document: &Document, path: &str) -> Bson {
+    values_at(document, path).first().map(|v| (*v).clone()).unwrap_or(Bson::Null)
+}
+
+fn direction_of(direction: &Bson) -> i64 {
+    as_i64(direction).unwrap_or(1)
+}
+
+// ================================================================================
+// COMPARISON
+// ================================================================================
+
+/// MongoDB equality; numbers are equal across types
+pub fn equal(a: &Bson, b: &Bson) -> bool {
+    match (a, b) {
+        (Bson::Document(x), Bson::Document(y)) => {
+            x.len() == y.len() && x.iter().zip(y.iter()).all(|((kx, vx), (ky, vy))| kx == ky && equal(vx, vy))
+        }
+        (Bson::Array(x), Bson::Array(y)) => x.len() == y.len() && x.iter().zip(y).all(|(vx, vy)| equal(vx, vy)),
+        _ if type_rank(a) == 2 && type_rank(b) == 2 => compare(a, b).is_eq(),
+        _ => a == b,
+    }
+}
+
+/// Total order of BSON values, as MongoDB sorts them
+pub fn compare(a: &Bson, b: &Bson) -> Ordering {
+    let by_type = type_rank(a).cmp(&type_rank(b));
+    if by_type.is_ne() {
+        return by_type;
+    }
+    match (a, b) {
+        (Bson::Int32(_) | Bson::Int64(_), Bson::Int32(_) | Bson::Int64(_)) => as_i64(a).cmp(&as_i64(b)),
+        _ if type_rank(a) == 2 => compare_f64(as_f64(a), as_f64(b)),
+        (Bson::String(x) | Bson::Symbol(x), Bson::String(y) | Bson::Symbol(y)) => x.cmp(y),
+        (Bson::Document(x), Bson::Document(y)) => {
+            for ((kx, vx), (ky, vy)) in x.iter().zip(y.iter()) {
+                let ordering = compare(vx, vy).then_with(|| kx.cmp(ky));
+                if ordering.is_ne() {
+                    return ordering;
+                }
+            }
+            x.len().cmp(&y.len())
+        }
+        (Bson::Array(x), Bson::Array(y)) => {
+            for (vx, vy) in x.iter().zip(y) {
+                let ordering = compare(vx, vy);
+                if ordering.is_ne() {
+                    return ordering;
+                }
+            }
+            x.len().cmp(&y.len())
+        }
+        (Bson::Binary(x), Bson::Binary(y)) => x.bytes.len().cmp(&y.bytes.len())
+            .then_with(|| u8::from(x.subtype).cmp(&u8::from(y.subtype)))
+            .then_with(|| x.bytes.cmp(&y.bytes)),
+        (Bson::ObjectId(x), Bson::ObjectId(y)) => x.bytes().cmp(&y.bytes()),
+        (Bson::Boolean(x), Bson::Boolean(y)) => x.cmp(y),
+        (Bson::DateTime(x), Bson::DateTime(y)) => x.cmp(y),
+        (Bson::Timestamp(x), Bson::Timestamp(y)) => (x.time, x.increment).cmp(&(y.time, y.increment)),
+        (Bson::RegularExpression(x), Bson::RegularExpression(y)) => (&x.pattern, &x.options).cmp(&(&y.pattern, &y.options)),
+        _ => Ordering::Equal,
+    }
+}
+
+/// NaN sorts before every other number
+fn compare_f64(x: f64, y: f64) -> Ordering {
+    match (x.is_nan(), y.is_nan()) {
+        (false, false) => x.partial_cmp(&y).unwrap_or(Ordering::Equal),
+        (x_nan, y_nan) => y_nan.cmp(&x_nan),
+    }
+}
+
+pub(super) fn type_rank(value: &Bson) -> u8 {
+    match value {
+        Bson::MinKey => 0,
+        Bson::Null | Bson::Undefined => 1,
+        Bson::Int32(_) | Bson::Int64(_) | Bson::Double(_) | Bson::Decimal128(_) => 2,
+        Bson::String(_) | Bson::Symbol(_) => 3,
+        Bson::Document(_) => 4,
+        Bson::Array(_) => 5,
+        Bson::Binary(_) => 6,
+        Bson::ObjectId(_) => 7,
+        Bson::Boolean(_) => 8,
+        Bson::DateTime(_) => 9,
+        Bson::Timestamp(_) => 10,
+        Bson::RegularExpression(_) => 11,
+        Bson::JavaScriptCode(_) | Bson::JavaScriptCodeWithScope(_) | Bson::DbPointer(_) => 12,
+        Bson::MaxKey => 13,
+    }
+}
+
+/// Range comparisons only match values of the same kind, so `{"$gt": 5}` never
+/// matches a string
+fn same_bracket(a: &Bson, b: &Bson) -> bool {
+    type_rank(a) == type_rank(b)
+}
+
+pub(super) fn as_i64(value: &Bson) -> Option<i64> {
+    match value {
+        Bson::Int32(i) => Some(*i as i64),
+        Bson::Int64(i) => Some(*i),
+        Bson::Double(d) if d.fract() == 0.0 => Some(*d as i64),
+        _ => None,
+    }
+}
+
+pub(super) fn as_f64(value: &Bson) -> f64 {
+    match value {
+        Bson::Int32(i) => *i as f64,
+        Bson::Int64(i) => *i as f64,
+        Bson::Double(d) => *d,
+        Bson::Decimal128(d) => d.to_string().parse().unwrap_or(f64::NAN),
+        _ => f64::NAN,
+    }
+}
+
+pub(super) fn truthy(value: &Bson) -> bool {
+    match value {
+        Bson::Boolean(b) => *b,
+        Bson::Null | Bson::Undefined => false,
+        other => as_f64(other) != 0.0,
+    }
+}
+
+fn compile(pattern: &str, options: &str) -> Result<Regex, String> {
+    let flags: String = options.chars().filter(|c| matches!(c, 'i' | 'm' | 's' | 'x')).collect();
+    let pattern = if flags.is_empty() { pattern.to_string() } else { format!("(?{}){}", flags, pattern) };
+    Regex::new(&pattern).map_err(|e| format!("Invalid regex: {}", e))
+}
+
+// ================================================================================
+// PROJECTION
+// ================================================================================
+
+/// Keep (`{"a": 1}`) or drop (`{"a": 0}`) fields; `_id` is kept unless excluded
+pub fn project(document: &Document, projection: &Document) -> Result<Document, String> {
+    let inclusive = projection.iter().any(|(field, value)| field != "_id" && truthy(value));
+    let exclusive = projection.iter().any(|(field, value)| field != "_id" && !truthy(value));
+    if inclusive && exclusive {
+        return Err("Projection cannot mix inclusion and exclusion".to_string());
+    }
+    let keep_id = projection.get("_id").map(truthy).unwrap_or(true);
+
+    let mut projected = if inclusive {
+        let mut projected = Document::new();
+        for (field, _) in projection.iter().filter(|(field, _)| *field != "_id") {
+            copy_path(document, &mut projected, field);
+        }
+        projected
+    } else {
+        let mut projected = document.clone();
+        for (field, _) in projection.iter().filter(|(field, _)| *field != "_id") {
+            remove_path(&mut projected, field);
+        }
+        projected
+    };
+
+    projected.remove("_id");
+    if keep_id {
+        if let Some(id) = document.get("_id") {
+            let mut with_id = Document::new();
+            with_id.insert("_id", id.clone());
+            with_id.extend(projected);
+            projected = with_id;
+        }
+    }
+    Ok(projected)
+}
+
+pub(super) fn copy_path(from: &Document, to: &mut Document, path: &str) {
+    match path.split_once('.') {
+        Some((head, rest)) => {
+            if let Some(Bson::Document(inner)) = from.get(head) {
+                if !matches!(to.get(head), Some(Bson::Document(_))) {
+                    to.insert(head, Document::new());
+                }
+                if let Some(Bson::Document(target)) = to.get_mut(head) {
+                    copy_path(inner, target, rest);
+                }
+            }
+        }
+        None => {
+            if let Some(value) = from.get(path) {
+                to.insert(path, value.clone());
+            }
+        }
+    }
+}
+
+pub fn remove_path(document: &mut Document, path: &str) {
+    match path.split_once('.') {
+        Some((head, rest)) => {
+            if let Some(Bson::Document(inner)) = document.get_mut(head) {
+                remove_path(inner, rest);
+            }
+        }
+        None => {
+            document.remove(path);
+        }
+    }
+}
+
+// ================================================================================
+// UPDATES
+// ================================================================================
+
+const UPDATE_OPERATORS: [&str; 12] = [
+    "$set", "$setOnInsert", "$unset", "$inc", "$mul", "$min", "$max",
+    "$rename", "$currentDate", "$push", "$addToSet", "$pull",
+];
+
+/// Check an update before applying it, so that a bad one fails even when
+/// nothing matches
+pub fn validate_update(update: &Document) -> Result<(), String> {
+    if update.is_empty() || !is_operator_document(update) {
+        return Err("Update must use operators, e.g. {\"$set\": {...}}".to_string());
+    }
+    for (operator, fields) in update {
+        if !UPDATE_OPERATORS.contains(&operator.as_str()) {
+            return Err(format!("Unsupported update operator '{}'", operator));
+        }
+        if !matches!(fields, Bson::Document(_)) {
+            return Err(format!("{} needs a document", operator));
+        }
+    }
+    Ok(())
+}
+
+/// Apply an update document; returns whether the document changed
+///
+/// Supports `$set`, `$unset`, `$inc`, `$mul`, `$min`, `$max`, `$rename`,
+/// `$currentDate`, `$push` and `$addToSet` (with `$each`), `$pull`, and
+/// `$setOnInsert`, which only applies when `inserting` an upserted document.
+pub fn apply_update(document: &mut Document, update: &Document, inserting: bool) -> Result<bool, String> {
+    validate_update(update)?;
+    let before = document.clone();
+    for (operator, fields) in update {
+        let fields = match fields {
+            Bson::Document(fields) => fields,
+            _ => return Err(format!("{} needs a document", operator)),
+        };
+        for (path, argument) in fields {
+            if !inserting && (path == "_id" || path.starts_with("_id.")) {
+                return Err("_id cannot be updated".to_string());
+            }
+            match operator.as_str() {
+                "$set" => set_path(document, path, argument.clone())?,
+                "$setOnInsert" => {
+                    if inserting {
+                        set_path(document, path, argument.clone())?;
+                    }
+                }
+                "$unset" => remove_path(document, path),
+                "$inc" | "$mul" => {
+                    let current = values_at(document, path).first().map(|v| (*v).clone());
+                    let value = arithmetic(operator, path, current.as_ref(), argument)?;
+                    set_path(document, path, value)?;
+                }
+                "$min" | "$max" => {
+                    let current = values_at(document, path).first().map(|v| (*v).clone());
+                    let replace = match &current {
+                        None => true,
+                        Some(current) if operator == "$min" => compare(argument, current).is_lt(),
+                        Some(current) => compare(argument, current).is_gt(),
+                    };
+                    if replace {
+                        set_path(document, path, argument.clone())?;
+                    }
+                }
+                "$rename" => {
+                    let to = match argument {
+                        Bson::String(to) => to,
+                        _ => return Err(format!("$rename of '{}' needs a field name", path)),
+                    };
+                    if let Some(value) = values_at(document, path).first().map(|v| (*v).clone()) {
+                        remove_path(document, path);
+                        set_path(document, to, value)?;
+                    }
+                }
+                "$currentDate" => {
+                    set_path(document, path, Bson::DateTime(mongodb::bson::DateTime::now()))?;
+                }
+                "$push" | "$addToSet" => {
+                    let items = match argument {
+                        Bson::Document(each) if each.contains_key("$each") => match each.get("$each") {
+                            Some(Bson::Array(items)) => items.clone(),
+                            _ => return Err(format!("$each in {} needs an array", operator)),
+                        },
+                        item => vec![item.clone()],
+                    };
+                    let array = array_at(document, path, operator)?;
+                    for item in items {
+                        if operator == "$push" || !array.iter().any(|existing| equal(existing, &item)) {
+                            array.push(item);
+                        }
+                    }
+                }
+                "$pull" => {
+                    let array = array_at(document, path, operator)?;
+                    let mut kept = Vec::with_capacity(array.len());
+                    for item in array.drain(..) {
+                        let pulled = match argument {
+                            Bson::Document(condition) => element_matches(&item, condition)?,
+                            value => equal(&item, value),
+                        };
+                        if !pulled {
+                            kept.push(item);
+                        }
+                    }
+                    *array = kept;
+                }
+                other => return Err(format!("Unsupported update operator '{}'", other)),
+            }
+        }
+    }
+    Ok(*document != before)
+}
+
+/// `$inc` / `$mul` keep integer types, widening Int32 to Int64 on overflow
+fn arithmetic(operator: &str, path: &str, current: Option<&Bson>, argument: &Bson) -> Result<Bson, String> {
+    if type_rank(argument) != 2 {
+        return Err(format!("{} of '{}' needs a number", operator, path));
+    }
+    // A missing field counts as 0
+    let zero = Bson::Int32(0);
+    let current = current.unwrap_or(&zero);
+    let add = operator == "$inc";
+    let i64_op = if add { i64::checked_add } else { i64::checked_mul };
+    match (current, argument) {
+        (Bson::Int32(a), Bson::Int32(b)) => {
+            let result = i64_op(*a as i64, *b as i64)
+                .ok_or_else(|| format!("{} of '{}' overflows", operator, path))?;
+            Ok(i32::try_from(result).map(Bson::Int32).unwrap_or(Bson::Int64(result)))
+        }
+        (Bson::Int32(_) | Bson::Int64(_), Bson::Int32(_) | Bson::Int64(_)) => {
+            let (a, b) = (as_i64(current).unwrap_or(0), as_i64(argument).unwrap_or(0));
+            i64_op(a, b).map(Bson::Int64).ok_or_else(|| format!("{} of '{}' overflows", operator, path))
+        }
+        (current, _) if type_rank(current) == 2 => {
+            let (a, b) = (as_f64(current), as_f64(argument));
+            Ok(Bson::Double(if add { a + b } else { a * b }))
+        }
+        _ => Err(format!("Cannot apply {} to '{}', which is not a number", operator, path)),
+    }
+}
+
+/// The array at a path, created when missing
+fn array_at<'a>(document: &'a mut Document, path: &str, operator: &str) -> Result<&'a mut Vec<Bson>, String> {
+    if values_at(document, path).is_empty() {
+        set_path(document, path, Bson::Array(Vec::new()))?;
+    }
+    let (parent, field) = match path.rsplit_once('.') {
+        Some((parent, field)) => (document_at(document, parent), field),
+        None => (Some(document), path),
+    };
+    match parent.and_then(|parent| parent.get_mut(field)) {
+        Some(Bson::Array(array)) => Ok(array),
+        _ => Err(format!("Cannot apply {} to '{}', which is not an array", operator, path)),
+    }
+}
+
+fn document_at<'a>(document: &'a mut Document, path: &str) -> Option<&'a mut Document> {
+    let mut current = document;
+    for part in path.split('.') {
+        current = match current.get_mut(part) {
+            Some(Bson::Document(inner)) => inner,
+            _ => return None,
+        };
+    }
+    Some(current)
+}
+
+pub(super) fn set_path(document: &mut Document, path: &str, value: Bson) -> Result<(), String> {
+    match path.split_once('.') {
+        Some((head, rest)) => {
+            if document.get(head).is_none() {
+                document.insert(head, Document::new());
+            }
+            match document.get_mut(head) {
+                Some(Bson::Document(inner)) => set_path(inner, rest, value),
+                _ => Err(format!("Cannot set '{}': '{}' is not a document", path, head)),
+            }
+        }
+        None => {
+            document.insert(path, value);
+            Ok(())
+        }
+    }
+}
+
+/// The document an upsert starts from: the equality conditions of its filter
+pub fn upsert_seed(filter: &Document) -> Result<Document, String> {
+    let mut seed = Document::new();
+    add_equalities(&mut seed, filter)?;
+    Ok(seed)
+}
+
+fn add_equalities(seed: &mut Document, filter: &Document) -> Result<(), String> {
+    for (key, condition) in filter {
+        match (key.as_str(), condition) {
+            ("$and", _) => {
+                for clause in clauses(condition, key)? {
+                    add_equalities(seed, clause)?;
+                }
+            }
+            (operator, _) if operator.starts_with('$') => {}
+            (path, Bson::Document(operators)) if is_operator_document(operators) => {
+                if let Some(value) = operators.get("$eq") {
+                    set_path(seed, path, value.clone())?;
+                }
+            }
+            (_, Bson::RegularExpression(_)) => {}
+            (path, value) => set_path(seed, path, value.clone())?,
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,428 @@
+//! Collection Schemas
+//!
+//! JSON Schema for the documents of a collection, checked against BSON so that
+//! integers, dates and ids keep their type. Supported keywords:
+//!
+//! - `type` (`object`, `array`, `string`, `number`, `integer`, `boolean`, `null`)
+//! - `properties`, `required`, `additionalProperties`
+//! - `items`, `minItems`, `maxItems`, `uniqueItems`
+//! - `enum`, `const`
+//! - `minimum`, `maximum`, `exclusiveMinimum`, `exclusiveMaximum`, `multipleOf`
+//! - `minLength`, `maxLength`, `pattern`, `format` (`email`, `date-time`, `date`)
+//! - `allOf`, `anyOf`, `oneOf`, `not`
+//! - `default`, filled in for missing properties when documents are inserted
+//!
+//! Other keywords such as `title` or `description` are ignored; `$ref` is
+//! refused. Dates count as strings, as do ids, which scripts see as hex strings.
+
+use super::query;
+use crate::Data;
+use mongodb::bson::{Bson, Document};
+use regex::Regex;
+use serde_json::Value;
+use std::fmt;
+
+/// Collection of each workspace holding its schemas, keyed by collection name
+pub const SCHEMA_COLLECTION: &str = "spu_schemas";
+
+/// Start of the error returned when a write doesn't match its schema
+pub const SCHEMA_VIOLATION: &str = "Schema violation";
+
+const TYPES: [&str; 7] = ["object", "array", "string", "number", "integer", "boolean", "null"];
+
+/// Where a document breaks its schema
+#[derive(Debug, Clone, PartialEq)]
+pub struct Violation {
+    /// Dotted path of the offending field, empty for the document itself
+    pub path: String,
+    pub message: String,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.path.is_empty() {
+            write!(f, "document {}", self.message)
+        } else {
+            write!(f, "{} {}", self.path, self.message)
+        }
+    }
+}
+
+/// Violations as one readable line
+pub fn describe(violations: &[Violation]) -> String {
+    violations.iter().map(Violation::to_string).collect::<Vec<_>>().join("; ")
+}
+
+// ================================================================================
+// CHECKING SCHEMAS
+// ================================================================================
+
+/// Check that a schema can be registered for a collection
+pub fn check(schema: &Value) -> Result<(), String> {
+    if schema.get("type") != Some(&Value::String("object".to_string())) {
+        return Err("A collection schema must have \"type\": \"object\"".to_string());
+    }
+    check_at(schema, "")
+}
+
+fn check_at(schema: &Value, path: &str) -> Result<(), String> {
+    let at = |message: String| if path.is_empty() { message } else { format!("{}: {}", path, message) };
+    let object = match schema {
+        Value::Bool(_) => return Ok(()),
+        Value::Object(object) => object,
+        _ => return Err(at("a schema must be an object".to_string())),
+    };
+
+    for (keyword, value) in object {
+        let valid = match keyword.as_str() {
+            "$ref" => return Err(at("$ref is not supported, write the schema inline".to_string())),
+            "type" => match value {
+                Value::String(t) => TYPES.contains(&t.as_str()),
+                Value::Array(types) => !types.is_empty() && types.iter().all(|t| t.as_str().is_some_and(|t| TYPES.contains(&t))),
+                _ => false,
+            },
+            "properties" => match value {
+                Value::Object(properties) => {
+                    for (name, property) in properties {
+                        check_at(property, &join(path, name))?;
+                    }
+                    true
+                }
+                _ => false,
+            },
+            "required" => value.as_array().is_some_and(|names| names.iter().all(Value::is_string)),
+            "additionalProperties" | "items" | "not" => {
+                if value.is_array() {
+                    return Err(at(format!("{} must be a single schema", keyword)));
+                }
+                check_at(value, &join(path, keyword))?;
+                true
+            }
+            "allOf" | "anyOf" | "oneOf" => match value {
+                Value::Array(schemas) if !schemas.is_empty() => {
+                    for (index, schema) in schemas.iter().enumerate() {
+                        check_at(schema, &join(path, &format!("{}.{}", keyword, index)))?;
+                    }
+                    true
+                }
+                _ => false,
+            },
+            "enum" => value.as_array().is_some_and(|values| !values.is_empty()),
+            "minimum" | "maximum" | "exclusiveMinimum" | "exclusiveMaximum" => value.is_number(),
+            "multipleOf" => value.as_f64().is_some_and(|n| n > 0.0),
+            "minLength" | "maxLength" | "minItems" | "maxItems" => count(value).is_some(),
+            "uniqueItems" => value.is_boolean(),
+            "pattern" => match value {
+                Value::String(pattern) => {
+                    Regex::new(pattern).map_err(|e| at(format!("invalid pattern: {}", e)))?;
+                    true
+                }
+                _ => false,
+            },
+            "format" => value.is_string(),
+            _ => true,
+        };
+        if !valid {
+            return Err(at(format!("invalid value for '{}': {}", keyword, value)));
+        }
+    }
+
+    if let Some(default) = object.get("default") {
+        let default = to_bson(default)?;
+        let mut violations = Vec::new();
+        validate_value(schema, &default, path, &mut violations);
+        if !violations.is_empty() {
+            return Err(at(format!("the default doesn't match the schema: {}", describe(&violations))));
+        }
+    }
+    Ok(())
+}
+
+/// A non-negative whole number; schemas that went through scripts have `3.0`
+/// for `3`
+fn count(value: &Value) -> Option<u64> {
+    value.as_u64().or_else(|| value.as_f64().filter(|n| *n >= 0.0 && n.fract() == 0.0).map(|n| n as u64))
+}
+
+/// JSON as it would be written by a script
+fn to_bson(value: &Value) -> Result<Bson, String> {
+    crate::bson_data::data_to_bson(&Data::from_json(value.clone()))
+}
+
+fn join(path: &str, field: &str) -> String {
+    if path.is_empty() {
+        field.to_string()
+    } else {
+        format!("{}.{}", path, field)
+    }
+}
+
+// ================================================================================
+// DEFAULTS
+// ================================================================================
+
+/// Defaults of the properties missing from a document, as dotted paths; nested
+/// objects get theirs when they are present or defaulted themselves
+pub fn defaults(schema: &Value, document: &Document) -> Result<Vec<(String, Bson)>, String> {
+    let mut missing = Vec::new();
+    collect_defaults(schema, document, "", &mut missing)?;
+    Ok(missing)
+}
+
+fn collect_defaults(schema: &Value, document: &Document, path: &str, missing: &mut Vec<(String, Bson)>) -> Result<(), String> {
+    let Some(properties) = schema.get("properties").and_then(Value::as_object) else {
+        return Ok(());
+    };
+    for (name, property) in properties {
+        let field = join(path, name);
+        match document.get(name) {
+            Some(Bson::Document(inner)) => collect_defaults(property, inner, &field, missing)?,
+            Some(_) => {}
+            None => {
+                if let Some(default) = property.get("default") {
+                    let mut value = to_bson(default)?;
+                    if let Bson::Document(inner) = &mut value {
+                        let mut nested = Vec::new();
+                        collect_defaults(property, inner, "", &mut nested)?;
+                        for (nested_path, nested_value) in nested {
+                            query::set_path(inner, &nested_path, nested_value)?;
+                        }
+                    }
+                    missing.push((field, value));
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Fill in the defaults of missing properties
+pub fn apply_defaults(schema: &Value, document: &mut Document) -> Result<(), String> {
+    for (path, value) in defaults(schema, document)? {
+        query::set_path(document, &path, value)?;
+    }
+    Ok(())
+}
+
+// ================================================================================
+// VALIDATION
+// ================================================================================
+
+/// Everything wrong with a document; empty when it matches
+pub fn validate(schema: &Value, document: &Document) -> Vec<Violation> {
+    let mut violations = Vec::new();
+    validate_value(schema, &Bson::Document(document.clone()), "", &mut violations);
+    violations
+}
+
+fn validate_value(schema: &Value, value: &Bson, path: &str, violations: &mut Vec<Violation>) {
+    let mut fail = |message: String| violations.push(Violation { path: path.to_string(), message });
+    let object = match schema {
+        Value::Bool(true) => return,
+        Value::Bool(false) => return fail("is not allowed".to_string()),
+        Value::Object(object) => object,
+        _ => return,
+    };
+
+    if let Some(expected) = object.get("type") {
+        let types: Vec<&str> = match expected {
+            Value::String(t) => vec![t.as_str()],
+            Value::Array(types) => types.iter().filter_map(Value::as_str).collect(),
+            _ => Vec::new(),
+        };
+        if !types.is_empty() && !types.iter().any(|t| has_type(value, t)) {
+            // Nothing else is worth reporting about a value of the wrong type
+            return fail(format!("must be {}, got {}", articled(&types), type_name(value)));
+        }
+    }
+
+    if let Some(Value::Array(allowed)) = object.get("enum") {
+        if !allowed.iter().any(|a| to_bson(a).is_ok_and(|a| query::equal(&a, value))) {
+            let list: Vec<String> = allowed.iter().map(Value::to_string).collect();
+            fail(format!("must be one of {}", list.join(", ")));
+        }
+    }
+    if let Some(constant) = object.get("const") {
+        if !to_bson(constant).is_ok_and(|c| query::equal(&c, value)) {
+            fail(format!("must be {}", constant));
+        }
+    }
+
+    if query::type_rank(value) == 2 {
+        let n = query::as_f64(value);
+        let bound = |keyword: &str| object.get(keyword).and_then(Value::as_f64);
+        if let Some(min) = bound("minimum").filter(|min| n < *min) {
+            fail(format!("must be at least {}", min));
+        }
+        if let Some(max) = bound("maximum").filter(|max| n > *max) {
+            fail(format!("must be at most {}", max));
+        }
+        if let Some(min) = bound("exclusiveMinimum").filter(|min| n <= *min) {
+            fail(format!("must be greater than {}", min));
+        }
+        if let Some(max) = bound("exclusiveMaximum").filter(|max| n >= *max) {
+            fail(format!("must be less than {}", max));
+        }
+        if let Some(step) = bound("multipleOf").filter(|step| *step > 0.0) {
+            let ratio = n / step;
+            if (ratio - ratio.round()).abs() > 1e-9 {
+                fail(format!("must be a multiple of {}", step));
+            }
+        }
+    }
+
+    if let Bson::String(s) = value {
+        let length = s.chars().count() as u64;
+        let limit = |keyword: &str| object.get(keyword).and_then(count);
+        if let Some(min) = limit("minLength").filter(|min| length < *min) {
+            fail(format!("must be at least {} characters long", min));
+        }
+        if let Some(max) = limit("maxLength").filter(|max| length > *max) {
+            fail(format!("must be at most {} characters long", max));
+        }
+        if let Some(pattern) = object.get("pattern").and_then(Value::as_str) {
+            if Regex::new(pattern).is_ok_and(|re| !re.is_match(s)) {
+                fail(format!("must match the pattern {}", pattern));
+            }
+        }
+        match object.get("format").and_then(Value::as_str) {
+            Some("email") if !is_email(s) => fail("must be an email address".to_string()),
+            Some("date-time") if chrono::DateTime::parse_from_rfc3339(s).is_err() => {
+                fail("must be a date and time such as 2024-05-01T09:30:00Z".to_string())
+            }
+            Some("date") if chrono::NaiveDate::parse_from_str(s, "%Y-%m-%d").is_err() => {
+                fail("must be a date such as 2024-05-01".to_string())
+            }
+            _ => {}
+        }
+    }
+
+    if let Bson::Array(items) = value {
+        let limit = |keyword: &str| object.get(keyword).and_then(count);
+        if let Some(min) = limit("minItems").filter(|min| (items.len() as u64) < *min) {
+            fail(format!("must have at least {} item(s)", min));
+        }
+        if let Some(max) = limit("maxItems").filter(|max| (items.len() as u64) > *max) {
+            fail(format!("must have at most {} item(s)", max));
+        }
+        if object.get("uniqueItems") == Some(&Value::Bool(true))
+            && items.iter().enumerate().any(|(i, a)| items[..i].iter().any(|b| query::equal(a, b)))
+        {
+            fail("must not contain duplicates".to_string());
+        }
+        if let Some(item_schema) = object.get("items") {
+            for (index, item) in items.iter().enumerate() {
+                validate_value(item_schema, item, &join(path, &index.to_string()), violations);
+            }
+        }
+    }
+
+    if let Bson::Document(document) = value {
+        if let Some(Value::Array(required)) = object.get("required") {
+            for name in required.iter().filter_map(Value::as_str) {
+                if !document.contains_key(name) {
+                    violations.push(Violation { path: join(path, name), message: "is required".to_string() });
+                }
+            }
+        }
+        let properties = object.get("properties").and_then(Value::as_object);
+        for (name, field) in document {
+            match properties.and_then(|p| p.get(name)) {
+                Some(property) => validate_value(property, field, &join(path, name), violations),
+                None => match object.get("additionalProperties") {
+                    // `_id` is the store's, not the schema's
+                    Some(_) if path.is_empty() && name == "_id" => {}
+                    Some(Value::Bool(false)) => violations.push(Violation {
+                        path: join(path, name),
+                        message: "is not allowed".to_string(),
+                    }),
+                    Some(extra) => validate_value(extra, field, &join(path, name), violations),
+                    None => {}
+                },
+            }
+        }
+    }
+
+    let matching = |schemas: &[Value]| {
+        schemas.iter()
+            .filter(|schema| {
+                let mut nested = Vec::new();
+                validate_value(schema, value, path, &mut nested);
+                nested.is_empty()
+            })
+            .count()
+    };
+    if let Some(Value::Array(schemas)) = object.get("allOf") {
+        for schema in schemas {
+            validate_value(schema, value, path, violations);
+        }
+    }
+    let mut fail = |message: &str| violations.push(Violation { path: path.to_string(), message: message.to_string() });
+    if let Some(Value::Array(schemas)) = object.get("anyOf") {
+        if matching(schemas) == 0 {
+            fail("must match at least one of the allowed forms");
+        }
+    }
+    if let Some(Value::Array(schemas)) = object.get("oneOf") {
+        if matching(schemas) != 1 {
+            fail("must match exactly one of the allowed forms");
+        }
+    }
+    if let Some(excluded) = object.get("not") {
+        if matching(std::slice::from_ref(excluded)) == 1 {
+            fail("must not match the excluded form");
+        }
+    }
+}
+
+fn has_type(value: &Bson, expected: &str) -> bool {
+    match expected {
+        "object" => matches!(value, Bson::Document(_)),
+        "array" => matches!(value, Bson::Array(_)),
+        "string" => matches!(value, Bson::String(_) | Bson::DateTime(_) | Bson::ObjectId(_)),
+        "number" => query::type_rank(value) == 2,
+        "integer" => matches!(value, Bson::Int32(_) | Bson::Int64(_)) || matches!(value, Bson::Double(d) if d.fract() == 0.0),
+        "boolean" => matches!(value, Bson::Boolean(_)),
+        "null" => matches!(value, Bson::Null),
+        _ => false,
+    }
+}
+
+fn type_name(value: &Bson) -> &'static str {
+    match value {
+        Bson::Document(_) => "an object",
+        Bson::Array(_) => "an array",
+        Bson::String(_) => "a string",
+        Bson::Boolean(_) => "a boolean",
+        Bson::Null => "null",
+        Bson::DateTime(_) => "a date",
+        Bson::ObjectId(_) => "an id",
+        value if query::type_rank(value) == 2 => "a number",
+        _ => "another type",
+    }
+}
+
+/// "a string", "an object or null"
+fn articled(types: &[&str]) -> String {
+    let named: Vec<String> = types.iter()
+        .map(|t| match *t {
+            "null" => "null".to_string(),
+            "object" | "array" | "integer" => format!("an {}", t),
+            other => format!("a {}", other),
+        })
+        .collect();
+    named.join(" or ")
+}
+
+fn is_email(s: &str) -> bool {
+    match s.split_once('@') {
+        Some((local, domain)) => {
+            !local.is_empty()
+                && !domain.contains('@')
+                && domain.split('.').count() >= 2
+                && domain.split('.').all(|part| !part.is_empty())
+                && !s.chars().any(char::is_whitespace)
+        }
+        None => false,
+    }
+}
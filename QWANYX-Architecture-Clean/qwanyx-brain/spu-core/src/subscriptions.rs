@@ -0,0 +1,303 @@
+//! Subscriptions
+//!
+//! Live changes of a collection, for clients that would otherwise poll
+//! `GET /data/{collection}`. A subscription names a workspace, a collection and
+//! a filter, and is told about:
+//!
+//! - `insert` and `update` of documents matching the filter, with the document
+//! - `delete` of any document of the collection, by `_id`
+//! - `leave` when an update takes a document out of the filter, by `_id`
+//!
+//! Subscribers hold a bearer token and see what they may read through `/data`:
+//! the workspace's access policy narrows the filter to the documents they may
+//! read and strips the fields they may not see from every event.
+//!
+//! Changes come from the document store's `watch`: MongoDB change streams, or the
+//! in-process feed of the local stores. A subscription ends with an error when
+//! the feed breaks or falls behind; clients then subscribe again and refetch.
+//!
+//! Each subscription is also authorized when it is opened by a
+//! `SubscriptionPolicy`; without one configured, none are. The `spu_*`
+//! collections of the server itself cannot be followed.
+
+use crate::auth::policy::{self, Caller, Grant, Operation};
+use crate::auth::sessions::{SessionError, Sessions};
+use crate::auth::tokens::Claims;
+use crate::bson_data::{bson_to_data, document_to_data};
+use crate::coprocessors::database::filter_to_document;
+use crate::runtime::SPURuntime;
+use crate::simple_parser::SimpleParser;
+use crate::store::{query, ChangeEvent, ChangeKind, DocumentStore, Namespace};
+use crate::Data;
+use async_trait::async_trait;
+use futures::stream::{self, Stream, StreamExt};
+use mongodb::bson::Document;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
+use thiserror::Error;
+use tracing::{info, warn};
+
+/// How long a quiet subscription waits before sending a keep-alive
+pub const HEARTBEAT: Duration = Duration::from_secs(15);
+
+/// What a client follows
+#[derive(Debug, Clone, PartialEq)]
+pub struct Subscription {
+    pub workspace: String,
+    pub collection: String,
+    pub filter: Document,
+}
+
+impl Subscription {
+    pub fn new(workspace: &str, collection: &str, filter: Document) -> Self {
+        Self { workspace: workspace.to_string(), collection: collection.to_string(), filter }
+    }
+
+    /// The subscription as a script variable
+    pub fn to_data(&self) -> Data {
+        let mut subscription = HashMap::new();
+        subscription.insert("workspace".to_string(), Data::String(self.workspace.clone()));
+        subscription.insert("collection".to_string(), Data::String(self.collection.clone()));
+        subscription.insert("filter".to_string(), document_to_data(&self.filter));
+        Data::Object(subscription)
+    }
+}
+
+/// A filter given as JSON, converted like the filters of `/data`
+pub fn parse_filter(json: &str) -> Result<Document, String> {
+    match serde_json::from_str::<Value>(json) {
+        Ok(value @ Value::Object(_)) => match Data::from_json(value) {
+            Data::Object(filter) => filter_to_document(&filter),
+            _ => Ok(Document::new()),
+        },
+        _ => Err("'filter' must be a JSON object".to_string()),
+    }
+}
+
+/// Something for a subscriber
+#[derive(Debug, Clone, PartialEq)]
+pub enum Notification {
+    /// The subscription is live; changes from now on follow
+    Ready,
+    /// `insert`, `update`, `delete` or `leave`, with the collection, `id` and
+    /// the document for inserts and updates
+    Change { event: &'static str, data: Value },
+    /// The subscription ended and may have missed changes
+    Error(String),
+    /// Nothing happened for a while; keeps proxies from closing the connection
+    KeepAlive,
+}
+
+impl Notification {
+    /// Server-Sent Events wire format
+    pub fn to_sse(&self) -> String {
+        match self {
+            Notification::Ready => "event: ready\ndata: {}\n\n".to_string(),
+            Notification::Change { event, data } => format!("event: {}\ndata: {}\n\n", event, data),
+            Notification::Error(message) => format!("event: error\ndata: {}\n\n", json!({ "error": message })),
+            Notification::KeepAlive => ": keep-alive\n\n".to_string(),
+        }
+    }
+}
+
+/// Why a subscription was refused
+#[derive(Debug, Error)]
+pub enum SubscriptionError {
+    #[error("Invalid subscription: {0}")]
+    Invalid(String),
+
+    #[error("Unauthorized: {0}")]
+    Unauthorized(String),
+
+    #[error("Forbidden: {0}")]
+    Forbidden(String),
+
+    #[error("Changes unavailable: {0}")]
+    Unavailable(String),
+}
+
+/// Decides who may follow what
+#[async_trait]
+pub trait SubscriptionPolicy: Send + Sync {
+    /// Whether the holder of `claims` may open `subscription`
+    async fn authorize(&self, subscription: &Subscription, claims: &Claims) -> Result<(), SubscriptionError>;
+}
+
+/// No one may follow anything, when no policy is configured
+pub struct ClosedPolicy;
+
+#[async_trait]
+impl SubscriptionPolicy for ClosedPolicy {
+    async fn authorize(&self, _subscription: &Subscription, _claims: &Claims) -> Result<(), SubscriptionError> {
+        Err(SubscriptionError::Forbidden("Subscriptions are not enabled".to_string()))
+    }
+}
+
+/// An SPU script decides, given `$subscription` (workspace, collection and filter)
+/// and `$auth` (the subscriber's claims), running as the subscriber. It allows
+/// the subscription by returning `true` or `{"allowed": true}`; anything else,
+/// or failing, refuses it, with the `reason` of the returned object if there is one.
+pub struct ScriptPolicy {
+    runtime: Arc<SPURuntime>,
+    script: String,
+}
+
+impl ScriptPolicy {
+    pub fn new(runtime: Arc<SPURuntime>, script: &str) -> Result<Self, String> {
+        SimpleParser::parse(script).map_err(|e| format!("Invalid subscription policy: {}", e))?;
+        Ok(Self { runtime, script: script.to_string() })
+    }
+}
+
+#[async_trait]
+impl SubscriptionPolicy for ScriptPolicy {
+    async fn authorize(&self, subscription: &Subscription, claims: &Claims) -> Result<(), SubscriptionError> {
+        let mut inputs = HashMap::new();
+        inputs.insert("subscription".to_string(), subscription.to_data());
+        inputs.insert("auth".to_string(), claims.to_data());
+
+        let decision = self.runtime.execute_as(&self.script, inputs, Caller::from(claims)).await
+            .map_err(|e| SubscriptionError::Forbidden(format!("Authorization failed: {}", e)))?;
+        // `RETURN true` and `RETURN {...}` give back their text
+        let decision = match decision {
+            Data::String(text) => serde_json::from_str(&text).map(Data::from_json).unwrap_or(Data::String(text)),
+            other => other,
+        };
+        match decision {
+            Data::Bool(true) => Ok(()),
+            Data::Object(obj) if matches!(obj.get("allowed"), Some(Data::Bool(true))) => Ok(()),
+            Data::Object(obj) => match obj.get("reason") {
+                Some(Data::String(reason)) => Err(SubscriptionError::Forbidden(reason.clone())),
+                _ => Err(SubscriptionError::Forbidden("Subscription denied".to_string())),
+            },
+            _ => Err(SubscriptionError::Forbidden("Subscription denied".to_string())),
+        }
+    }
+}
+
+/// The policy named by `SUBSCRIPTION_POLICY_SCRIPT`, a file holding a script for
+/// `ScriptPolicy`; without it every subscription is refused
+pub fn policy_from_env(runtime: Arc<SPURuntime>) -> Result<Arc<dyn SubscriptionPolicy>, String> {
+    match std::env::var("SUBSCRIPTION_POLICY_SCRIPT") {
+        Ok(path) => {
+            let script = std::fs::read_to_string(&path)
+                .map_err(|e| format!("Failed to read subscription policy {}: {}", path, e))?;
+            info!("Authorizing subscriptions with {}", path);
+            Ok(Arc::new(ScriptPolicy::new(runtime, &script)?))
+        }
+        Err(_) => {
+            warn!("SUBSCRIPTION_POLICY_SCRIPT is not set, subscriptions are refused");
+            Ok(Arc::new(ClosedPolicy))
+        }
+    }
+}
+
+pub type NotificationStream = Pin<Box<dyn Stream<Item = Notification> + Send>>;
+
+/// Opens subscriptions over a document store
+pub struct Subscriptions {
+    store: Arc<dyn DocumentStore>,
+    sessions: Arc<Sessions>,
+    policy: Arc<dyn SubscriptionPolicy>,
+    heartbeat: Duration,
+}
+
+impl Subscriptions {
+    pub fn new(store: Arc<dyn DocumentStore>, sessions: Arc<Sessions>, policy: Arc<dyn SubscriptionPolicy>) -> Self {
+        Self { store, sessions, policy, heartbeat: HEARTBEAT }
+    }
+
+    pub fn with_heartbeat(mut self, heartbeat: Duration) -> Self {
+        self.heartbeat = heartbeat;
+        self
+    }
+
+    /// The claims of a subscriber's bearer token, refusing revoked ones
+    pub async fn authenticate(&self, token: Option<&str>) -> Result<Claims, SubscriptionError> {
+        let token = token.ok_or_else(|| SubscriptionError::Unauthorized("Missing bearer token".to_string()))?;
+        self.sessions.authenticate(token).await.map_err(|e| match e {
+            SessionError::Storage(e) => SubscriptionError::Unavailable(e),
+            e => SubscriptionError::Unauthorized(e.to_string()),
+        })
+    }
+
+    /// Check and authorize a subscription for the holder of `claims`, then
+    /// follow its collection; the stream starts with `Ready` and ends after an
+    /// `Error`
+    pub async fn subscribe(&self, subscription: Subscription, claims: &Claims) -> Result<NotificationStream, SubscriptionError> {
+        if subscription.collection.is_empty() {
+            return Err(SubscriptionError::Invalid("Missing collection".to_string()));
+        }
+        if subscription.collection.starts_with("spu_") {
+            return Err(SubscriptionError::Forbidden(format!("{} cannot be followed", subscription.collection)));
+        }
+        // An unknown operator would otherwise only show once a change arrives
+        query::matches(&Document::new(), &subscription.filter).map_err(SubscriptionError::Invalid)?;
+        let grant = self.grant(&subscription, claims).await?;
+        self.policy.authorize(&subscription, claims).await?;
+
+        let ns = Namespace::new(&subscription.workspace, &subscription.collection);
+        let changes = self.store.watch(ns).await.map_err(SubscriptionError::Unavailable)?;
+        info!("Subscribed to {} in {}", subscription.collection, subscription.workspace);
+
+        let (filter, heartbeat) = (grant.restrict(subscription.filter), self.heartbeat);
+        // The changes, or None once the subscription has ended
+        let notifications = stream::unfold(Some(changes), move |changes| {
+            let (filter, grant) = (filter.clone(), grant.clone());
+            async move {
+                let mut changes = changes?;
+                loop {
+                    match tokio::time::timeout(heartbeat, changes.next()).await {
+                        Err(_) => return Some((Notification::KeepAlive, Some(changes))),
+                        Ok(None) => return Some((Notification::Error("The change feed closed".to_string()), None)),
+                        Ok(Some(Err(e))) => return Some((Notification::Error(e), None)),
+                        Ok(Some(Ok(change))) => {
+                            if let Some(notification) = notify(&change, &filter, &grant) {
+                                return Some((notification, Some(changes)));
+                            }
+                        }
+                    }
+                }
+            }
+        });
+        Ok(Box::pin(stream::once(async { Notification::Ready }).chain(notifications)))
+    }
+
+    /// What the subscriber may read of the collection, as through `/data`
+    async fn grant(&self, subscription: &Subscription, claims: &Claims) -> Result<Grant, SubscriptionError> {
+        let policy = policy::stored(self.store.as_ref(), &subscription.workspace).await
+            .map_err(SubscriptionError::Unavailable)?
+            .unwrap_or_default();
+        let forbidden = |e: policy::PolicyError| SubscriptionError::Forbidden(e.to_string());
+        let grant = policy.grant(&Caller::from(claims), &subscription.workspace, &subscription.collection, Operation::Read)
+            .map_err(forbidden)?;
+        grant.check_filter(&subscription.filter).map_err(forbidden)?;
+        Ok(grant)
+    }
+}
+
+/// What a subscriber with `filter`, already narrowed by their `grant`, hears
+/// of a change, if anything
+fn notify(change: &ChangeEvent, filter: &Document, grant: &Grant) -> Option<Notification> {
+    let matching = change.document.as_ref()
+        .filter(|document| query::matches(document, filter).unwrap_or(false));
+    let (event, document) = match (change.kind, matching) {
+        (ChangeKind::Delete, _) => ("delete", None),
+        (ChangeKind::Insert, Some(document)) => ("insert", Some(document)),
+        (ChangeKind::Update, Some(document)) => ("update", Some(document)),
+        (ChangeKind::Insert, None) => return None,
+        // Whether it matched before is unknown, so the subscriber drops it if it has it
+        (ChangeKind::Update, None) => ("leave", None),
+    };
+
+    let mut data = json!({ "collection": change.collection, "id": bson_to_data(&change.id).to_plain_json() });
+    if let Some(document) = document {
+        let mut document = document.clone();
+        grant.hide(&mut document);
+        data["document"] = document_to_data(&document).to_plain_json();
+    }
+    Some(Notification::Change { event, data })
+}
@@ -0,0 +1,355 @@
+//! Event Triggers
+//!
+//! Scripts bound to events in a workspace. The dispatcher listens on the runtime's
+//! event bus and runs every matching trigger with the event in the `event` variable,
+//! confined to the event's workspace. With an audit log (`with_audit`), saving
+//! and deleting triggers is logged.
+
+use crate::audit::{self, AuditEntry, AuditLog};
+use crate::events::{Event, TRIGGER_DEPTH};
+use crate::runtime::SPURuntime;
+use crate::Data;
+use crate::workspaces::WorkspaceDatabases;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use mongodb::{Collection, bson::{doc, Document}};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::{broadcast, RwLock};
+use tracing::{error, info, warn};
+
+/// Triggers fired by events from trigger runs stop at this depth, so that a
+/// script writing to the collection it listens on can't loop forever
+pub const MAX_TRIGGER_DEPTH: u32 = 4;
+
+/// A script to run when an event happens in a workspace
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Trigger {
+    pub workspace: String,
+    pub name: String,
+    /// Event name to listen for, e.g. `database.inserted`
+    pub event: String,
+    /// Payload fields that must be equal, e.g. `{"collection": "requests"}`
+    #[serde(default)]
+    pub filter: HashMap<String, Data>,
+    pub script: String,
+    pub enabled: bool,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+impl Trigger {
+    pub fn matches(&self, event: &Event) -> bool {
+        if !self.enabled || self.event != event.name || self.workspace != event.workspace {
+            return false;
+        }
+        match &event.payload {
+            Data::Object(payload) => self.filter.iter().all(|(k, v)| payload.get(k) == Some(v)),
+            _ => self.filter.is_empty(),
+        }
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        let filter: serde_json::Map<String, serde_json::Value> = self.filter.iter()
+            .map(|(k, v)| (k.clone(), v.to_json()))
+            .collect();
+        serde_json::json!({
+            "workspace": self.workspace,
+            "name": self.name,
+            "event": self.event,
+            "filter": filter,
+            "script": self.script,
+            "enabled": self.enabled,
+            "created_at": self.created_at.to_rfc3339(),
+            "updated_at": self.updated_at.to_rfc3339(),
+        })
+    }
+}
+
+// ================================================================================
+// STORAGE
+// ================================================================================
+
+/// Persistence for trigger configuration, scoped by workspace
+#[async_trait]
+pub trait TriggerStore: Send + Sync {
+    async fn save(&self, trigger: &Trigger) -> Result<(), String>;
+
+    async fn load(&self, workspace: &str, name: &str) -> Result<Option<Trigger>, String>;
+
+    async fn list(&self, workspace: &str) -> Result<Vec<Trigger>, String>;
+
+    /// Returns false if there was no such trigger
+    async fn delete(&self, workspace: &str, name: &str) -> Result<bool, String>;
+
+    /// Enabled triggers in the workspace listening for the event
+    async fn for_event(&self, workspace: &str, event: &str) -> Result<Vec<Trigger>, String>;
+}
+
+/// In-memory store, for tests and local development
+#[derive(Default)]
+pub struct MemoryTriggerStore {
+    triggers: RwLock<HashMap<(String, String), Trigger>>,
+}
+
+impl MemoryTriggerStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[async_trait]
+impl TriggerStore for MemoryTriggerStore {
+    async fn save(&self, trigger: &Trigger) -> Result<(), String> {
+        let mut triggers = self.triggers.write().await;
+        triggers.insert((trigger.workspace.clone(), trigger.name.clone()), trigger.clone());
+        Ok(())
+    }
+
+    async fn load(&self, workspace: &str, name: &str) -> Result<Option<Trigger>, String> {
+        let triggers = self.triggers.read().await;
+        Ok(triggers.get(&(workspace.to_string(), name.to_string())).cloned())
+    }
+
+    async fn list(&self, workspace: &str) -> Result<Vec<Trigger>, String> {
+        let triggers = self.triggers.read().await;
+        let mut list: Vec<Trigger> = triggers.values()
+            .filter(|t| t.workspace == workspace)
+            .cloned()
+            .collect();
+        list.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(list)
+    }
+
+    async fn delete(&self, workspace: &str, name: &str) -> Result<bool, String> {
+        let mut triggers = self.triggers.write().await;
+        Ok(triggers.remove(&(workspace.to_string(), name.to_string())).is_some())
+    }
+
+    async fn for_event(&self, workspace: &str, event: &str) -> Result<Vec<Trigger>, String> {
+        let triggers = self.triggers.read().await;
+        Ok(triggers.values()
+            .filter(|t| t.enabled && t.workspace == workspace && t.event == event)
+            .cloned()
+            .collect())
+    }
+}
+
+/// MongoDB store
+///
+/// Triggers live in the workspace's own database, in the `spu_triggers` collection,
+/// keyed by trigger name.
+pub struct MongoTriggerStore {
+    databases: WorkspaceDatabases,
+}
+
+impl MongoTriggerStore {
+    pub fn new(databases: WorkspaceDatabases) -> Self {
+        Self { databases }
+    }
+
+    fn collection(&self, workspace: &str) -> Result<Collection<Document>, String> {
+        Ok(self.databases.database(workspace)?.collection("spu_triggers"))
+    }
+
+    fn from_document(document: &Document) -> Result<Trigger, String> {
+        let json = document.get_str("record")
+            .map_err(|e| format!("Corrupt trigger document: {}", e))?;
+        serde_json::from_str(json)
+            .map_err(|e| format!("Corrupt trigger document: {}", e))
+    }
+
+    async fn find(&self, workspace: &str, filter: Document) -> Result<Vec<Trigger>, String> {
+        use futures::stream::TryStreamExt;
+        let options = mongodb::options::FindOptions::builder().sort(doc! { "_id": 1 }).build();
+        let cursor = self.collection(workspace)?.find(filter, options).await
+            .map_err(|e| format!("Query failed: {}", e))?;
+        let documents: Vec<Document> = cursor.try_collect().await
+            .map_err(|e| format!("Failed to collect results: {}", e))?;
+        documents.iter().map(Self::from_document).collect()
+    }
+}
+
+#[async_trait]
+impl TriggerStore for MongoTriggerStore {
+    async fn save(&self, trigger: &Trigger) -> Result<(), String> {
+        let json = serde_json::to_string(trigger)
+            .map_err(|e| format!("Failed to serialize trigger: {}", e))?;
+        let document = doc! {
+            "_id": &trigger.name,
+            "event": &trigger.event,
+            "enabled": trigger.enabled,
+            "record": json,
+        };
+
+        let options = mongodb::options::ReplaceOptions::builder().upsert(true).build();
+        self.collection(&trigger.workspace)?
+            .replace_one(doc! { "_id": &trigger.name }, document, options).await
+            .map_err(|e| format!("Failed to save trigger: {}", e))?;
+        Ok(())
+    }
+
+    async fn load(&self, workspace: &str, name: &str) -> Result<Option<Trigger>, String> {
+        let document = self.collection(workspace)?.find_one(doc! { "_id": name }, None).await
+            .map_err(|e| format!("Failed to load trigger: {}", e))?;
+        document.as_ref().map(Self::from_document).transpose()
+    }
+
+    async fn list(&self, workspace: &str) -> Result<Vec<Trigger>, String> {
+        self.find(workspace, doc! {}).await
+    }
+
+    async fn delete(&self, workspace: &str, name: &str) -> Result<bool, String> {
+        let result = self.collection(workspace)?.delete_one(doc! { "_id": name }, None).await
+            .map_err(|e| format!("Failed to delete trigger: {}", e))?;
+        Ok(result.deleted_count == 1)
+    }
+
+    async fn for_event(&self, workspace: &str, event: &str) -> Result<Vec<Trigger>, String> {
+        self.find(workspace, doc! { "event": event, "enabled": true }).await
+    }
+}
+
+// ================================================================================
+// DISPATCHER
+// ================================================================================
+
+/// Runs triggers for the events published on the runtime's bus
+pub struct TriggerDispatcher {
+    runtime: Arc<SPURuntime>,
+    store: Arc<dyn TriggerStore>,
+    audit: Option<Arc<AuditLog>>,
+}
+
+impl TriggerDispatcher {
+    pub fn new(runtime: Arc<SPURuntime>, store: Arc<dyn TriggerStore>) -> Self {
+        Self { runtime, store, audit: None }
+    }
+
+    /// Log saved and deleted triggers in this audit log
+    pub fn with_audit(mut self, audit: Arc<AuditLog>) -> Self {
+        self.audit = Some(audit);
+        self
+    }
+
+    /// Create or replace a trigger
+    pub async fn save(
+        &self,
+        workspace: &str,
+        name: &str,
+        event: &str,
+        filter: HashMap<String, Data>,
+        script: &str,
+        enabled: bool,
+    ) -> Result<Trigger, String> {
+        if name.is_empty() || event.is_empty() {
+            return Err("Trigger name and event cannot be empty".to_string());
+        }
+        crate::simple_parser::SimpleParser::parse(script)?;
+
+        let now = Utc::now();
+        let existing = self.store.load(workspace, name).await?;
+        let created_at = existing.as_ref().map(|existing| existing.created_at).unwrap_or(now);
+
+        let trigger = Trigger {
+            workspace: workspace.to_string(),
+            name: name.to_string(),
+            event: event.to_string(),
+            filter,
+            script: script.to_string(),
+            enabled,
+            created_at,
+            updated_at: now,
+        };
+        self.store.save(&trigger).await?;
+        info!("Saved trigger {} on {} in workspace {}", trigger.name, trigger.event, trigger.workspace);
+        self.audit("trigger.saved", existing.as_ref(), Some(&trigger)).await;
+        Ok(trigger)
+    }
+
+    pub async fn list(&self, workspace: &str) -> Result<Vec<Trigger>, String> {
+        self.store.list(workspace).await
+    }
+
+    pub async fn delete(&self, workspace: &str, name: &str) -> Result<bool, String> {
+        let existing = self.store.load(workspace, name).await?;
+        let deleted = self.store.delete(workspace, name).await?;
+        if deleted {
+            self.audit("trigger.deleted", existing.as_ref(), None).await;
+        }
+        Ok(deleted)
+    }
+
+    async fn audit(&self, action: &str, before: Option<&Trigger>, after: Option<&Trigger>) {
+        let (Some(audit), Some(trigger)) = (&self.audit, after.or(before)) else {
+            return;
+        };
+        let entry = AuditEntry::new(&trigger.workspace, action)
+            .on(format!("triggers/{}", trigger.name))
+            .changes(before.map(audited).as_ref(), after.map(audited).as_ref());
+        audit.log(&entry, None).await;
+    }
+
+    /// Run every trigger matching the event; returns each trigger's name and outcome
+    pub async fn dispatch(&self, event: &Event) -> Result<Vec<(String, Result<Data, String>)>, String> {
+        if event.depth >= MAX_TRIGGER_DEPTH {
+            warn!("Not dispatching {} in {}: trigger depth {} reached", event.name, event.workspace, event.depth);
+            return Ok(Vec::new());
+        }
+
+        let mut outcomes = Vec::new();
+        for trigger in self.store.for_event(&event.workspace, &event.name).await? {
+            if !trigger.matches(event) {
+                continue;
+            }
+            info!("Running trigger {} for {} in {}", trigger.name, event.name, event.workspace);
+
+            let mut inputs = HashMap::new();
+            inputs.insert("event".to_string(), event.to_data());
+            let run = self.runtime.execute_in(&trigger.script, inputs, &event.workspace);
+            let result = TRIGGER_DEPTH
+                .scope(event.depth + 1, audit::as_script(format!("trigger:{}", trigger.name), run))
+                .await;
+
+            if let Err(e) = &result {
+                error!("Trigger {} failed: {}", trigger.name, e);
+            }
+            outcomes.push((trigger.name, result));
+        }
+        Ok(outcomes)
+    }
+
+    /// Spawn a background task that dispatches every event published on the runtime's bus
+    pub fn spawn(self: Arc<Self>) -> tokio::task::JoinHandle<()> {
+        let mut receiver = self.runtime.events().subscribe();
+        tokio::spawn(async move {
+            loop {
+                match receiver.recv().await {
+                    Ok(event) => {
+                        let dispatcher = self.clone();
+                        tokio::spawn(async move {
+                            if let Err(e) = dispatcher.dispatch(&event).await {
+                                error!("Failed to dispatch event {}: {}", event.name, e);
+                            }
+                        });
+                    }
+                    Err(broadcast::error::RecvError::Lagged(missed)) => {
+                        warn!("Trigger dispatcher fell behind, {} events dropped", missed);
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                }
+            }
+        })
+    }
+}
+
+/// What the audit log keeps of a trigger
+fn audited(trigger: &Trigger) -> Document {
+    doc! {
+        "event": &trigger.event,
+        "filter": mongodb::bson::to_bson(&trigger.to_json()["filter"]).unwrap_or_default(),
+        "script": &trigger.script,
+        "enabled": trigger.enabled,
+    }
+}
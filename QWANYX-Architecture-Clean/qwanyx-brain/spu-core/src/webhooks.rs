@@ -0,0 +1,438 @@
+//! Inbound Webhooks
+//!
+//! Configurable `POST /hooks/{workspace}/{name}` routes. Each route checks an HMAC
+//! signature or a shared token, runs its bound script with the request body in
+//! `$body`, and answers with the script's result. Scripts only reach the
+//! route's workspace. With an audit log (`with_audit`), saving and deleting
+//! routes is logged, secrets by fingerprint only.
+
+use crate::audit::{self, AuditEntry, AuditLog};
+use crate::runtime::SPURuntime;
+use crate::Data;
+use crate::workspaces::WorkspaceDatabases;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use mongodb::{Collection, bson::{doc, Document}};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::Arc;
+use thiserror::Error;
+use tokio::sync::RwLock;
+use tracing::{error, info, warn};
+
+/// How a webhook caller proves who it is
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum WebhookAuth {
+    /// Hex HMAC-SHA256 of the raw body, GitHub style: `X-Hub-Signature-256: sha256=<hex>`
+    Hmac {
+        secret: String,
+        #[serde(default = "default_signature_header")]
+        header: String,
+        #[serde(default = "default_signature_prefix")]
+        prefix: String,
+    },
+    /// Shared token sent as-is or as `Bearer <token>`
+    Token {
+        token: String,
+        #[serde(default = "default_token_header")]
+        header: String,
+    },
+}
+
+fn default_signature_header() -> String {
+    "X-Hub-Signature-256".to_string()
+}
+
+fn default_signature_prefix() -> String {
+    "sha256=".to_string()
+}
+
+fn default_token_header() -> String {
+    "X-Webhook-Token".to_string()
+}
+
+impl WebhookAuth {
+    /// Check a request; `headers` must have lowercase names
+    pub fn verify(&self, headers: &HashMap<String, String>, body: &[u8]) -> Result<(), String> {
+        match self {
+            WebhookAuth::Hmac { secret, header, prefix } => {
+                let signature = headers.get(&header.to_lowercase())
+                    .ok_or_else(|| format!("Missing {} header", header))?;
+                let signature = signature.strip_prefix(prefix.as_str())
+                    .ok_or_else(|| "Malformed signature".to_string())?;
+                let signature = hex::decode(signature)
+                    .map_err(|_| "Malformed signature".to_string())?;
+
+                let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes())
+                    .map_err(|e| format!("Invalid HMAC secret: {}", e))?;
+                mac.update(body);
+                mac.verify_slice(&signature)
+                    .map_err(|_| "Invalid signature".to_string())
+            }
+            WebhookAuth::Token { token, header } => {
+                let provided = headers.get(&header.to_lowercase())
+                    .ok_or_else(|| format!("Missing {} header", header))?;
+                let provided = provided.strip_prefix("Bearer ").unwrap_or(provided);
+                if constant_time_eq(provided.as_bytes(), token.as_bytes()) {
+                    Ok(())
+                } else {
+                    Err("Invalid token".to_string())
+                }
+            }
+        }
+    }
+
+    /// Configuration with the secret hidden, for API responses
+    pub fn to_json(&self) -> serde_json::Value {
+        match self {
+            WebhookAuth::Hmac { header, prefix, .. } => serde_json::json!({
+                "type": "hmac",
+                "header": header,
+                "prefix": prefix,
+            }),
+            WebhookAuth::Token { header, .. } => serde_json::json!({
+                "type": "token",
+                "header": header,
+            }),
+        }
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// A webhook route bound to a script
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WebhookRoute {
+    pub workspace: String,
+    pub name: String,
+    pub auth: WebhookAuth,
+    pub script: String,
+    /// HTTP status returned when the script succeeds
+    pub status: u16,
+    pub enabled: bool,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+impl WebhookRoute {
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "workspace": self.workspace,
+            "name": self.name,
+            "path": format!("/hooks/{}/{}", self.workspace, self.name),
+            "auth": self.auth.to_json(),
+            "script": self.script,
+            "status": self.status,
+            "enabled": self.enabled,
+            "created_at": self.created_at.to_rfc3339(),
+            "updated_at": self.updated_at.to_rfc3339(),
+        })
+    }
+}
+
+/// Why a webhook call was refused or failed
+#[derive(Debug, Error)]
+pub enum WebhookError {
+    #[error("Webhook not found")]
+    NotFound,
+
+    #[error("Unauthorized: {0}")]
+    Unauthorized(String),
+
+    #[error("Script failed: {0}")]
+    ScriptFailed(String),
+
+    #[error("Storage error: {0}")]
+    Storage(String),
+}
+
+// ================================================================================
+// STORAGE
+// ================================================================================
+
+/// Persistence for webhook routes, scoped by workspace
+#[async_trait]
+pub trait WebhookStore: Send + Sync {
+    async fn save(&self, route: &WebhookRoute) -> Result<(), String>;
+
+    async fn load(&self, workspace: &str, name: &str) -> Result<Option<WebhookRoute>, String>;
+
+    async fn list(&self, workspace: &str) -> Result<Vec<WebhookRoute>, String>;
+
+    /// Returns false if there was no such route
+    async fn delete(&self, workspace: &str, name: &str) -> Result<bool, String>;
+}
+
+/// In-memory store, for tests and local development
+#[derive(Default)]
+pub struct MemoryWebhookStore {
+    routes: RwLock<HashMap<(String, String), WebhookRoute>>,
+}
+
+impl MemoryWebhookStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[async_trait]
+impl WebhookStore for MemoryWebhookStore {
+    async fn save(&self, route: &WebhookRoute) -> Result<(), String> {
+        let mut routes = self.routes.write().await;
+        routes.insert((route.workspace.clone(), route.name.clone()), route.clone());
+        Ok(())
+    }
+
+    async fn load(&self, workspace: &str, name: &str) -> Result<Option<WebhookRoute>, String> {
+        let routes = self.routes.read().await;
+        Ok(routes.get(&(workspace.to_string(), name.to_string())).cloned())
+    }
+
+    async fn list(&self, workspace: &str) -> Result<Vec<WebhookRoute>, String> {
+        let routes = self.routes.read().await;
+        let mut list: Vec<WebhookRoute> = routes.values()
+            .filter(|r| r.workspace == workspace)
+            .cloned()
+            .collect();
+        list.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(list)
+    }
+
+    async fn delete(&self, workspace: &str, name: &str) -> Result<bool, String> {
+        let mut routes = self.routes.write().await;
+        Ok(routes.remove(&(workspace.to_string(), name.to_string())).is_some())
+    }
+}
+
+/// MongoDB store
+///
+/// Routes live in the workspace's own database, in the `spu_webhooks` collection,
+/// keyed by route name.
+pub struct MongoWebhookStore {
+    databases: WorkspaceDatabases,
+}
+
+impl MongoWebhookStore {
+    pub fn new(databases: WorkspaceDatabases) -> Self {
+        Self { databases }
+    }
+
+    fn collection(&self, workspace: &str) -> Result<Collection<Document>, String> {
+        Ok(self.databases.database(workspace)?.collection("spu_webhooks"))
+    }
+
+    fn from_document(document: &Document) -> Result<WebhookRoute, String> {
+        let json = document.get_str("record")
+            .map_err(|e| format!("Corrupt webhook document: {}", e))?;
+        serde_json::from_str(json)
+            .map_err(|e| format!("Corrupt webhook document: {}", e))
+    }
+}
+
+#[async_trait]
+impl WebhookStore for MongoWebhookStore {
+    async fn save(&self, route: &WebhookRoute) -> Result<(), String> {
+        let json = serde_json::to_string(route)
+            .map_err(|e| format!("Failed to serialize webhook: {}", e))?;
+        let document = doc! {
+            "_id": &route.name,
+            "enabled": route.enabled,
+            "record": json,
+        };
+
+        let options = mongodb::options::ReplaceOptions::builder().upsert(true).build();
+        self.collection(&route.workspace)?
+            .replace_one(doc! { "_id": &route.name }, document, options).await
+            .map_err(|e| format!("Failed to save webhook: {}", e))?;
+        Ok(())
+    }
+
+    async fn load(&self, workspace: &str, name: &str) -> Result<Option<WebhookRoute>, String> {
+        let document = self.collection(workspace)?.find_one(doc! { "_id": name }, None).await
+            .map_err(|e| format!("Failed to load webhook: {}", e))?;
+        document.as_ref().map(Self::from_document).transpose()
+    }
+
+    async fn list(&self, workspace: &str) -> Result<Vec<WebhookRoute>, String> {
+        use futures::stream::TryStreamExt;
+        let options = mongodb::options::FindOptions::builder().sort(doc! { "_id": 1 }).build();
+        let cursor = self.collection(workspace)?.find(None, options).await
+            .map_err(|e| format!("Query failed: {}", e))?;
+        let documents: Vec<Document> = cursor.try_collect().await
+            .map_err(|e| format!("Failed to collect results: {}", e))?;
+        documents.iter().map(Self::from_document).collect()
+    }
+
+    async fn delete(&self, workspace: &str, name: &str) -> Result<bool, String> {
+        let result = self.collection(workspace)?.delete_one(doc! { "_id": name }, None).await
+            .map_err(|e| format!("Failed to delete webhook: {}", e))?;
+        Ok(result.deleted_count == 1)
+    }
+}
+
+// ================================================================================
+// ROUTER
+// ================================================================================
+
+/// Manages webhook routes and runs their scripts
+pub struct WebhookRouter {
+    runtime: Arc<SPURuntime>,
+    store: Arc<dyn WebhookStore>,
+    audit: Option<Arc<AuditLog>>,
+}
+
+impl WebhookRouter {
+    pub fn new(runtime: Arc<SPURuntime>, store: Arc<dyn WebhookStore>) -> Self {
+        Self { runtime, store, audit: None }
+    }
+
+    /// Log saved and deleted routes in this audit log
+    pub fn with_audit(mut self, audit: Arc<AuditLog>) -> Self {
+        self.audit = Some(audit);
+        self
+    }
+
+    /// Create or replace a route
+    pub async fn save(
+        &self,
+        workspace: &str,
+        name: &str,
+        auth: WebhookAuth,
+        script: &str,
+        status: u16,
+        enabled: bool,
+    ) -> Result<WebhookRoute, String> {
+        if name.is_empty() {
+            return Err("Webhook name cannot be empty".to_string());
+        }
+        if !(200..300).contains(&status) {
+            return Err(format!("Status must be a 2xx code, got {}", status));
+        }
+        match &auth {
+            WebhookAuth::Hmac { secret, .. } if secret.is_empty() => {
+                return Err("HMAC secret cannot be empty".to_string());
+            }
+            WebhookAuth::Token { token, .. } if token.is_empty() => {
+                return Err("Token cannot be empty".to_string());
+            }
+            _ => {}
+        }
+        crate::simple_parser::SimpleParser::parse(script)?;
+
+        let now = Utc::now();
+        let existing = self.store.load(workspace, name).await?;
+        let created_at = existing.as_ref().map(|existing| existing.created_at).unwrap_or(now);
+
+        let route = WebhookRoute {
+            workspace: workspace.to_string(),
+            name: name.to_string(),
+            auth,
+            script: script.to_string(),
+            status,
+            enabled,
+            created_at,
+            updated_at: now,
+        };
+        self.store.save(&route).await?;
+        info!("Saved webhook /hooks/{}/{}", route.workspace, route.name);
+        self.audit("webhook.saved", existing.as_ref(), Some(&route)).await;
+        Ok(route)
+    }
+
+    pub async fn list(&self, workspace: &str) -> Result<Vec<WebhookRoute>, String> {
+        self.store.list(workspace).await
+    }
+
+    pub async fn delete(&self, workspace: &str, name: &str) -> Result<bool, String> {
+        let existing = self.store.load(workspace, name).await?;
+        let deleted = self.store.delete(workspace, name).await?;
+        if deleted {
+            self.audit("webhook.deleted", existing.as_ref(), None).await;
+        }
+        Ok(deleted)
+    }
+
+    async fn audit(&self, action: &str, before: Option<&WebhookRoute>, after: Option<&WebhookRoute>) {
+        let (Some(audit), Some(route)) = (&self.audit, after.or(before)) else {
+            return;
+        };
+        let entry = AuditEntry::new(&route.workspace, action)
+            .on(format!("webhooks/{}", route.name))
+            .changes(before.map(audited).as_ref(), after.map(audited).as_ref());
+        audit.log(&entry, None).await;
+    }
+
+    /// Verify and run a webhook call; returns the route's status and the script result
+    ///
+    /// The script gets `$body` (parsed JSON, or the raw text if it isn't JSON),
+    /// `$headers` (lowercase names) and `$query`.
+    pub async fn handle(
+        &self,
+        workspace: &str,
+        name: &str,
+        headers: HashMap<String, String>,
+        query: HashMap<String, String>,
+        body: &[u8],
+    ) -> Result<(u16, Data), WebhookError> {
+        let route = self.store.load(workspace, name).await
+            .map_err(WebhookError::Storage)?
+            .filter(|r| r.enabled)
+            .ok_or(WebhookError::NotFound)?;
+
+        if let Err(e) = route.auth.verify(&headers, body) {
+            warn!("Rejected call to webhook /hooks/{}/{}: {}", workspace, name, e);
+            return Err(WebhookError::Unauthorized(e));
+        }
+
+        let mut inputs = HashMap::new();
+        inputs.insert("body".to_string(), parse_body(body));
+        inputs.insert("headers".to_string(), string_map(headers));
+        inputs.insert("query".to_string(), string_map(query));
+
+        info!("Running webhook /hooks/{}/{}", workspace, name);
+        let run = self.runtime.execute_in(&route.script, inputs, workspace);
+        match audit::as_script(format!("webhook:{}", name), run).await {
+            Ok(result) => Ok((route.status, result)),
+            Err(e) => {
+                error!("Webhook /hooks/{}/{} failed: {}", workspace, name, e);
+                Err(WebhookError::ScriptFailed(e))
+            }
+        }
+    }
+}
+
+fn parse_body(body: &[u8]) -> Data {
+    if body.is_empty() {
+        return Data::Null;
+    }
+    match serde_json::from_slice::<serde_json::Value>(body) {
+        Ok(json) => Data::from_json(json),
+        Err(_) => Data::String(String::from_utf8_lossy(body).into_owned()),
+    }
+}
+
+fn string_map(map: HashMap<String, String>) -> Data {
+    Data::Object(map.into_iter().map(|(k, v)| (k, Data::String(v))).collect())
+}
+
+/// What the audit log keeps of a route: its secret only as a fingerprint,
+/// enough to tell that it changed
+fn audited(route: &WebhookRoute) -> Document {
+    let secret = match &route.auth {
+        WebhookAuth::Hmac { secret, .. } => secret,
+        WebhookAuth::Token { token, .. } => token,
+    };
+    let fingerprint = hex::encode(&Sha256::digest(secret.as_bytes())[..6]);
+    doc! {
+        "auth": mongodb::bson::to_bson(&route.auth.to_json()).unwrap_or_default(),
+        "secret": fingerprint,
+        "script": &route.script,
+        "status": route.status as i32,
+        "enabled": route.enabled,
+    }
+}
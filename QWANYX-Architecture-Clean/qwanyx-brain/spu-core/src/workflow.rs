@@ -0,0 +1,535 @@
+//! Durable Workflows
+//!
+//! Runs SPU scripts whose state survives restarts. The executor is checkpointed
+//! to a `WorkflowStore` around every side-effecting CALL, and scripts can SLEEP
+//! or WAIT_EVENT until a worker resumes them.
+
+use crate::audit;
+use crate::auth::policy::Caller;
+use crate::runtime::{
+    CallPolicy, Checkpointer, DurableContext, ExecutionOutcome, ExecutorSnapshot, SPURuntime, Suspension,
+};
+use crate::Data;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use mongodb::{Client as MongoClient, Collection, bson::{doc, Document}};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::RwLock;
+use tracing::{error, info};
+use uuid::Uuid;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WorkflowStatus {
+    Running,
+    Sleeping,
+    Waiting,
+    Completed,
+    Failed,
+}
+
+impl WorkflowStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            WorkflowStatus::Running => "running",
+            WorkflowStatus::Sleeping => "sleeping",
+            WorkflowStatus::Waiting => "waiting",
+            WorkflowStatus::Completed => "completed",
+            WorkflowStatus::Failed => "failed",
+        }
+    }
+}
+
+/// A durable script execution and its saved state
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WorkflowRecord {
+    pub id: String,
+    pub script: String,
+    pub status: WorkflowStatus,
+    pub policy: CallPolicy,
+    /// When a sleeping workflow becomes due
+    pub wake_at: Option<DateTime<Utc>>,
+    /// Event a waiting workflow is blocked on
+    pub event: Option<String>,
+    /// Variable that receives the event payload
+    pub event_target: Option<String>,
+    pub snapshot: ExecutorSnapshot,
+    pub result: Option<Data>,
+    pub error: Option<String>,
+    /// The user it runs for, in their workspace; started by the server itself when absent
+    #[serde(default)]
+    pub caller: Option<Caller>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+impl WorkflowRecord {
+    /// The workspace of the user it runs for
+    pub fn workspace(&self) -> Option<&str> {
+        self.caller.as_ref().map(|caller| caller.workspace.as_str())
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "id": self.id,
+            "workspace": self.workspace(),
+            "started_by": self.caller.as_ref().map(|caller| &caller.sub),
+            "status": self.status.as_str(),
+            "policy": self.policy,
+            "wake_at": self.wake_at.map(|t| t.to_rfc3339()),
+            "event": self.event,
+            "result": self.result.as_ref().map(|r| r.to_plain_json()),
+            "error": self.error,
+            "created_at": self.created_at.to_rfc3339(),
+            "updated_at": self.updated_at.to_rfc3339(),
+        })
+    }
+}
+
+// ================================================================================
+// STORAGE
+// ================================================================================
+
+/// Persistence for workflow records
+#[async_trait]
+pub trait WorkflowStore: Send + Sync {
+    async fn save(&self, record: &WorkflowRecord) -> Result<(), String>;
+
+    async fn load(&self, id: &str) -> Result<Option<WorkflowRecord>, String>;
+
+    /// Replace the executor state of a running workflow
+    async fn update_snapshot(&self, id: &str, snapshot: &ExecutorSnapshot) -> Result<(), String>;
+
+    /// Atomically move a workflow from `from` to running; false if someone else got it first
+    async fn claim(&self, id: &str, from: WorkflowStatus) -> Result<bool, String>;
+
+    /// Sleeping workflows whose wake time has passed
+    async fn due(&self, now: DateTime<Utc>) -> Result<Vec<String>, String>;
+
+    /// Workflows waiting for the given event
+    async fn waiting_for(&self, event: &str) -> Result<Vec<String>, String>;
+
+    /// Workflows left running, i.e. interrupted by a restart
+    async fn interrupted(&self) -> Result<Vec<String>, String>;
+}
+
+/// In-memory store, for tests and local development
+#[derive(Default)]
+pub struct MemoryWorkflowStore {
+    records: RwLock<HashMap<String, WorkflowRecord>>,
+}
+
+impl MemoryWorkflowStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    async fn ids_where(&self, predicate: impl Fn(&WorkflowRecord) -> bool) -> Vec<String> {
+        let records = self.records.read().await;
+        records.values().filter(|r| predicate(r)).map(|r| r.id.clone()).collect()
+    }
+}
+
+#[async_trait]
+impl WorkflowStore for MemoryWorkflowStore {
+    async fn save(&self, record: &WorkflowRecord) -> Result<(), String> {
+        let mut records = self.records.write().await;
+        records.insert(record.id.clone(), record.clone());
+        Ok(())
+    }
+
+    async fn load(&self, id: &str) -> Result<Option<WorkflowRecord>, String> {
+        let records = self.records.read().await;
+        Ok(records.get(id).cloned())
+    }
+
+    async fn update_snapshot(&self, id: &str, snapshot: &ExecutorSnapshot) -> Result<(), String> {
+        let mut records = self.records.write().await;
+        let record = records.get_mut(id)
+            .ok_or_else(|| format!("Unknown workflow: {}", id))?;
+        record.snapshot = snapshot.clone();
+        record.updated_at = Utc::now();
+        Ok(())
+    }
+
+    async fn claim(&self, id: &str, from: WorkflowStatus) -> Result<bool, String> {
+        let mut records = self.records.write().await;
+        match records.get_mut(id) {
+            Some(record) if record.status == from => {
+                record.status = WorkflowStatus::Running;
+                record.updated_at = Utc::now();
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    async fn due(&self, now: DateTime<Utc>) -> Result<Vec<String>, String> {
+        Ok(self.ids_where(|r| {
+            r.status == WorkflowStatus::Sleeping && r.wake_at.is_some_and(|t| t <= now)
+        }).await)
+    }
+
+    async fn waiting_for(&self, event: &str) -> Result<Vec<String>, String> {
+        Ok(self.ids_where(|r| {
+            r.status == WorkflowStatus::Waiting && r.event.as_deref() == Some(event)
+        }).await)
+    }
+
+    async fn interrupted(&self) -> Result<Vec<String>, String> {
+        Ok(self.ids_where(|r| r.status == WorkflowStatus::Running).await)
+    }
+}
+
+/// MongoDB store
+///
+/// Each record is one document keyed by workflow id. The queryable fields are kept
+/// at the top level, the full record is serialized as JSON in `record`.
+pub struct MongoWorkflowStore {
+    collection: Collection<Document>,
+}
+
+impl MongoWorkflowStore {
+    /// In the `SPU_SYSTEM_DB` database (default `spu_system`)
+    pub fn new(client: MongoClient) -> Self {
+        let database = std::env::var("SPU_SYSTEM_DB")
+            .unwrap_or_else(|_| "spu_system".to_string());
+
+        Self {
+            collection: client.database(&database).collection("workflows"),
+        }
+    }
+
+    fn to_document(record: &WorkflowRecord) -> Result<Document, String> {
+        let json = serde_json::to_string(record)
+            .map_err(|e| format!("Failed to serialize workflow: {}", e))?;
+
+        Ok(doc! {
+            "_id": &record.id,
+            "status": record.status.as_str(),
+            "wake_at": record.wake_at.map(bson_datetime),
+            "event": record.event.clone(),
+            "updated_at": bson_datetime(record.updated_at),
+            "record": json,
+        })
+    }
+
+    fn from_document(document: &Document) -> Result<WorkflowRecord, String> {
+        let json = document.get_str("record")
+            .map_err(|e| format!("Corrupt workflow document: {}", e))?;
+        let mut record: WorkflowRecord = serde_json::from_str(json)
+            .map_err(|e| format!("Corrupt workflow document: {}", e))?;
+
+        // The status field is updated on its own by claim()
+        if let Ok(status) = document.get_str("status") {
+            if let Ok(status) = serde_json::from_value(serde_json::Value::String(status.to_string())) {
+                record.status = status;
+            }
+        }
+        Ok(record)
+    }
+
+    async fn ids(&self, filter: Document) -> Result<Vec<String>, String> {
+        use futures::stream::TryStreamExt;
+        let cursor = self.collection.find(filter, None).await
+            .map_err(|e| format!("Query failed: {}", e))?;
+        let documents: Vec<Document> = cursor.try_collect().await
+            .map_err(|e| format!("Failed to collect results: {}", e))?;
+
+        Ok(documents.iter()
+            .filter_map(|d| d.get_str("_id").ok().map(|s| s.to_string()))
+            .collect())
+    }
+}
+
+#[async_trait]
+impl WorkflowStore for MongoWorkflowStore {
+    async fn save(&self, record: &WorkflowRecord) -> Result<(), String> {
+        let document = Self::to_document(record)?;
+        let options = mongodb::options::ReplaceOptions::builder().upsert(true).build();
+        self.collection.replace_one(doc! { "_id": &record.id }, document, options).await
+            .map_err(|e| format!("Failed to save workflow: {}", e))?;
+        Ok(())
+    }
+
+    async fn load(&self, id: &str) -> Result<Option<WorkflowRecord>, String> {
+        let document = self.collection.find_one(doc! { "_id": id }, None).await
+            .map_err(|e| format!("Failed to load workflow: {}", e))?;
+        document.as_ref().map(Self::from_document).transpose()
+    }
+
+    async fn update_snapshot(&self, id: &str, snapshot: &ExecutorSnapshot) -> Result<(), String> {
+        let mut record = self.load(id).await?
+            .ok_or_else(|| format!("Unknown workflow: {}", id))?;
+        record.snapshot = snapshot.clone();
+        record.updated_at = Utc::now();
+        self.save(&record).await
+    }
+
+    async fn claim(&self, id: &str, from: WorkflowStatus) -> Result<bool, String> {
+        let result = self.collection.update_one(
+            doc! { "_id": id, "status": from.as_str() },
+            doc! { "$set": { "status": WorkflowStatus::Running.as_str() } },
+            None,
+        ).await.map_err(|e| format!("Failed to claim workflow: {}", e))?;
+        Ok(result.modified_count == 1)
+    }
+
+    async fn due(&self, now: DateTime<Utc>) -> Result<Vec<String>, String> {
+        self.ids(doc! {
+            "status": WorkflowStatus::Sleeping.as_str(),
+            "wake_at": { "$lte": bson_datetime(now) },
+        }).await
+    }
+
+    async fn waiting_for(&self, event: &str) -> Result<Vec<String>, String> {
+        self.ids(doc! { "status": WorkflowStatus::Waiting.as_str(), "event": event }).await
+    }
+
+    async fn interrupted(&self) -> Result<Vec<String>, String> {
+        self.ids(doc! { "status": WorkflowStatus::Running.as_str() }).await
+    }
+}
+
+pub(crate) fn bson_datetime(time: DateTime<Utc>) -> mongodb::bson::DateTime {
+    mongodb::bson::DateTime::from_millis(time.timestamp_millis())
+}
+
+/// Checkpoints a running workflow into its store
+struct StoreCheckpointer {
+    store: Arc<dyn WorkflowStore>,
+    id: String,
+}
+
+#[async_trait]
+impl Checkpointer for StoreCheckpointer {
+    async fn checkpoint(&self, snapshot: &ExecutorSnapshot) -> Result<(), String> {
+        self.store.update_snapshot(&self.id, snapshot).await
+    }
+}
+
+// ================================================================================
+// ENGINE
+// ================================================================================
+
+/// Starts durable scripts and resumes them when they are due
+pub struct WorkflowEngine {
+    runtime: Arc<SPURuntime>,
+    store: Arc<dyn WorkflowStore>,
+}
+
+impl WorkflowEngine {
+    pub fn new(runtime: Arc<SPURuntime>, store: Arc<dyn WorkflowStore>) -> Self {
+        Self { runtime, store }
+    }
+
+    /// Start a new workflow and run it until it completes or suspends
+    pub async fn start(
+        &self,
+        script: &str,
+        inputs: HashMap<String, Data>,
+        policy: CallPolicy,
+    ) -> Result<WorkflowRecord, String> {
+        self.launch(script, inputs, policy, None).await
+    }
+
+    /// `start` on behalf of a user: its calls apply their permissions, in
+    /// their workspace only, every time it resumes
+    pub async fn start_as(
+        &self,
+        script: &str,
+        inputs: HashMap<String, Data>,
+        policy: CallPolicy,
+        caller: Caller,
+    ) -> Result<WorkflowRecord, String> {
+        self.launch(script, inputs, policy, Some(caller)).await
+    }
+
+    async fn launch(
+        &self,
+        script: &str,
+        inputs: HashMap<String, Data>,
+        policy: CallPolicy,
+        caller: Option<Caller>,
+    ) -> Result<WorkflowRecord, String> {
+        // Reject scripts that don't parse before anything is stored
+        crate::simple_parser::SimpleParser::parse(script)?;
+
+        let now = Utc::now();
+        let record = WorkflowRecord {
+            id: Uuid::new_v4().to_string(),
+            script: script.to_string(),
+            status: WorkflowStatus::Running,
+            policy,
+            wake_at: None,
+            event: None,
+            event_target: None,
+            snapshot: ExecutorSnapshot::with_inputs(inputs),
+            result: None,
+            error: None,
+            caller,
+            created_at: now,
+            updated_at: now,
+        };
+        self.store.save(&record).await?;
+        info!("Started workflow {}", record.id);
+
+        self.run(record).await
+    }
+
+    pub async fn get(&self, id: &str) -> Result<Option<WorkflowRecord>, String> {
+        self.store.load(id).await
+    }
+
+    /// Resume a sleeping workflow, whether or not its wake time has passed
+    pub async fn wake(&self, id: &str) -> Result<WorkflowRecord, String> {
+        self.resume(id, WorkflowStatus::Sleeping, None).await
+    }
+
+    /// Deliver an event to every workflow waiting for it
+    pub async fn deliver_event(&self, event: &str, payload: Data) -> Result<Vec<WorkflowRecord>, String> {
+        self.deliver(event, payload, None).await
+    }
+
+    /// Deliver an event to the workflows of a workspace waiting for it
+    pub async fn deliver_event_in(&self, workspace: &str, event: &str, payload: Data) -> Result<Vec<WorkflowRecord>, String> {
+        self.deliver(event, payload, Some(workspace)).await
+    }
+
+    async fn deliver(&self, event: &str, payload: Data, workspace: Option<&str>) -> Result<Vec<WorkflowRecord>, String> {
+        let mut resumed = Vec::new();
+        for id in self.store.waiting_for(event).await? {
+            if let Some(workspace) = workspace {
+                let record = self.store.load(&id).await?;
+                if record.as_ref().and_then(WorkflowRecord::workspace) != Some(workspace) {
+                    continue;
+                }
+            }
+            match self.resume(&id, WorkflowStatus::Waiting, Some(payload.clone())).await {
+                Ok(record) => resumed.push(record),
+                Err(e) => error!("Failed to resume workflow {} on event {}: {}", id, event, e),
+            }
+        }
+        Ok(resumed)
+    }
+
+    /// Resume every sleeping workflow that is due; returns how many were resumed
+    pub async fn tick(&self) -> Result<usize, String> {
+        let mut count = 0;
+        for id in self.store.due(Utc::now()).await? {
+            match self.resume(&id, WorkflowStatus::Sleeping, None).await {
+                Ok(_) => count += 1,
+                Err(e) => error!("Failed to resume workflow {}: {}", id, e),
+            }
+        }
+        Ok(count)
+    }
+
+    /// Resume workflows left running by a previous process
+    ///
+    /// Only call this at startup, when no other worker can be running them.
+    pub async fn recover(&self) -> Result<usize, String> {
+        let mut count = 0;
+        for id in self.store.interrupted().await? {
+            let Some(record) = self.store.load(&id).await? else {
+                continue;
+            };
+            info!("Recovering interrupted workflow {}", id);
+            match self.run(record).await {
+                Ok(_) => count += 1,
+                Err(e) => error!("Failed to recover workflow {}: {}", id, e),
+            }
+        }
+        Ok(count)
+    }
+
+    /// Spawn a background task that resumes due workflows every `interval`
+    pub fn spawn_worker(self: Arc<Self>, interval: std::time::Duration) -> tokio::task::JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            loop {
+                ticker.tick().await;
+                if let Err(e) = self.tick().await {
+                    error!("Workflow worker tick failed: {}", e);
+                }
+            }
+        })
+    }
+
+    async fn resume(&self, id: &str, from: WorkflowStatus, payload: Option<Data>) -> Result<WorkflowRecord, String> {
+        if !self.store.claim(id, from).await? {
+            return Err(format!("Workflow {} is not {}", id, from.as_str()));
+        }
+        let mut record = self.store.load(id).await?
+            .ok_or_else(|| format!("Unknown workflow: {}", id))?;
+        record.status = WorkflowStatus::Running;
+
+        // WAIT_EVENT stores the event payload in its target variable
+        if let (Some(payload), Some(target)) = (payload, record.event_target.take()) {
+            record.snapshot.variables.insert(target, payload);
+        }
+        record.wake_at = None;
+        record.event = None;
+        info!("Resuming workflow {}", id);
+
+        self.run(record).await
+    }
+
+    async fn run(&self, mut record: WorkflowRecord) -> Result<WorkflowRecord, String> {
+        let context = DurableContext {
+            workflow_id: record.id.clone(),
+            policy: record.policy,
+            checkpointer: Arc::new(StoreCheckpointer {
+                store: self.store.clone(),
+                id: record.id.clone(),
+            }),
+            caller: record.caller.clone(),
+        };
+
+        // Checkpoints only carry the snapshot, so the record must exist in running state first
+        self.store.save(&record).await?;
+
+        let run = self.runtime.execute_durable(&record.script, record.snapshot.clone(), context);
+        let outcome = audit::as_script(format!("workflow:{}", record.id), run).await;
+
+        // Pick up the latest checkpoint
+        if let Some(saved) = self.store.load(&record.id).await? {
+            record.snapshot = saved.snapshot;
+        }
+
+        match outcome {
+            Ok(ExecutionOutcome::Completed(result)) => {
+                info!("Workflow {} completed", record.id);
+                record.status = WorkflowStatus::Completed;
+                record.result = Some(result);
+            }
+            Ok(ExecutionOutcome::Suspended { reason, snapshot }) => {
+                record.snapshot = *snapshot;
+                match reason {
+                    Suspension::Sleep { until } => {
+                        info!("Workflow {} sleeping until {}", record.id, until);
+                        record.status = WorkflowStatus::Sleeping;
+                        record.wake_at = Some(until);
+                    }
+                    Suspension::WaitEvent { event, target } => {
+                        info!("Workflow {} waiting for event {}", record.id, event);
+                        record.status = WorkflowStatus::Waiting;
+                        record.event = Some(event);
+                        record.event_target = Some(target);
+                    }
+                }
+            }
+            Err(e) => {
+                error!("Workflow {} failed: {}", record.id, e);
+                record.status = WorkflowStatus::Failed;
+                record.error = Some(e);
+            }
+        }
+
+        record.updated_at = Utc::now();
+        self.store.save(&record).await?;
+        Ok(record)
+    }
+}
@@ -0,0 +1,386 @@
+//! Workspace Registry
+//!
+//! The workspaces this server serves: each has an id, the database its
+//! documents live in, the hostnames and browser origins it is reached from,
+//! optionally the emails that may sign in to it, and the OpenID Connect
+//! providers its users may sign in with (see `auth::oidc`).
+//!
+//! Requests are resolved to a workspace from their verified token or, before
+//! signing in, from the hostname they were sent to. `WorkspaceStore` puts each
+//! workspace in its own database and refuses unknown ones, as
+//! `WorkspaceDatabases` does for the auth and configuration stores, and
+//! scripts run in a workspace (`SPURuntime::execute_in`) cannot reach any other.
+//!
+//! `WORKSPACES_FILE` names a JSON file:
+//!
+//! ```json
+//! {
+//!   "default": "autodin",
+//!   "workspaces": [
+//!     {"id": "autodin", "database": "autodin_prod", "hostnames": ["autodin.be"],
+//!      "origins": ["https://autodin.be"], "users": ["admin@autodin.be"]}
+//!   ]
+//! }
+//! ```
+//!
+//! Without it, `WORKSPACES` lists workspace ids, comma separated (default
+//! `autodin`), each in the database of the same name and open to any origin.
+
+use crate::auth::oidc::ProviderConfig;
+use crate::store::{ChangeStream, DocumentStore, FindOneAndUpdateOptions, FindOptions, Index, Namespace, UpdateOptions, UpdateOutcome};
+use async_trait::async_trait;
+use futures::StreamExt;
+use mongodb::bson::{Bson, Document};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::sync::Arc;
+
+/// Characters MongoDB does not allow in database names
+const FORBIDDEN_DATABASE_CHARS: [char; 7] = ['/', '\\', '.', ' ', '"', '$', '\0'];
+
+/// Longest database name MongoDB accepts
+const MAX_DATABASE_LEN: usize = 63;
+
+/// A workspace and where it is reached from
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Workspace {
+    pub id: String,
+    /// Display name; the id when not set
+    #[serde(default)]
+    pub name: Option<String>,
+    /// Database holding the workspace's documents; the id when not set
+    #[serde(default)]
+    pub database: Option<String>,
+    /// Hostnames serving the workspace, e.g. `autodin.be`
+    #[serde(default)]
+    pub hostnames: Vec<String>,
+    /// Browser origins allowed to call the API, e.g. `https://autodin.be`;
+    /// any origin when empty
+    #[serde(default)]
+    pub origins: Vec<String>,
+    /// Emails that may sign in; anyone when not set
+    #[serde(default)]
+    pub users: Option<Vec<String>>,
+    /// Providers users may sign in with besides email codes
+    #[serde(default)]
+    pub providers: Vec<ProviderConfig>,
+}
+
+impl Workspace {
+    /// A workspace in the database of its name, reached from anywhere
+    pub fn new(id: &str) -> Self {
+        Self {
+            id: id.to_string(),
+            name: None,
+            database: None,
+            hostnames: Vec::new(),
+            origins: Vec::new(),
+            users: None,
+            providers: Vec::new(),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        self.name.as_deref().unwrap_or(&self.id)
+    }
+
+    pub fn database(&self) -> &str {
+        self.database.as_deref().unwrap_or(&self.id)
+    }
+
+    /// Whether `email` may sign in
+    pub fn admits(&self, email: &str) -> bool {
+        match &self.users {
+            Some(users) => users.iter().any(|user| user.eq_ignore_ascii_case(email.trim())),
+            None => true,
+        }
+    }
+
+    fn serves_host(&self, host: &str) -> bool {
+        self.hostnames.iter().any(|hostname| hostname.eq_ignore_ascii_case(host))
+    }
+
+    fn allows_origin(&self, origin: &str) -> bool {
+        self.origins.is_empty() || self.origins.iter().any(|allowed| allowed == "*" || allowed.eq_ignore_ascii_case(origin))
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if self.id.trim().is_empty() {
+            return Err("A workspace has an empty id".to_string());
+        }
+        let database = self.database();
+        if database.is_empty()
+            || database.len() > MAX_DATABASE_LEN
+            || database.contains(FORBIDDEN_DATABASE_CHARS)
+        {
+            return Err(format!("Workspace '{}' has an invalid database name '{}'", self.id, database));
+        }
+        for (i, provider) in self.providers.iter().enumerate() {
+            provider.validate()?;
+            if self.providers[..i].iter().any(|other| other.id == provider.id) {
+                return Err(format!("Sign-in provider '{}' is listed twice for workspace '{}'", provider.id, self.id));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RegistryFile {
+    default: Option<String>,
+    workspaces: Vec<Workspace>,
+}
+
+/// The known workspaces, one of them the default
+#[derive(Debug, Clone, PartialEq)]
+pub struct Workspaces {
+    workspaces: Vec<Workspace>,
+    default: String,
+}
+
+impl Workspaces {
+    /// A registry of `workspaces`, defaulting to `default`
+    pub fn new(workspaces: Vec<Workspace>, default: &str) -> Result<Self, String> {
+        for (i, workspace) in workspaces.iter().enumerate() {
+            workspace.validate()?;
+            if workspaces[..i].iter().any(|other| other.id == workspace.id) {
+                return Err(format!("Workspace '{}' is listed twice", workspace.id));
+            }
+            if let Some(host) = workspace.hostnames.iter().find(|host| workspaces[..i].iter().any(|other| other.serves_host(host))) {
+                return Err(format!("Hostname '{}' is listed for two workspaces", host));
+            }
+        }
+        if !workspaces.iter().any(|workspace| workspace.id == default) {
+            return Err(format!("Default workspace '{}' is not listed", default));
+        }
+        Ok(Self { workspaces, default: default.to_string() })
+    }
+
+    /// Workspaces named by id, each in the database of its name
+    pub fn of(ids: &[&str]) -> Result<Self, String> {
+        let default = ids.first().ok_or("No workspaces listed")?;
+        Self::new(ids.iter().map(|id| Workspace::new(id)).collect(), default)
+    }
+
+    /// A registry file's contents; the default is the first workspace unless named
+    pub fn from_json(value: Value) -> Result<Self, String> {
+        let file: RegistryFile = serde_json::from_value(value)
+            .map_err(|e| format!("Invalid workspace registry: {}", e))?;
+        let default = match file.default {
+            Some(default) => default,
+            None => file.workspaces.first().map(|w| w.id.clone()).ok_or("No workspaces listed")?,
+        };
+        Self::new(file.workspaces, &default)
+    }
+
+    /// `WORKSPACES_FILE`, or else the ids listed by `WORKSPACES`
+    pub fn from_env() -> Result<Self, String> {
+        match std::env::var("WORKSPACES_FILE") {
+            Ok(path) => {
+                let contents = std::fs::read_to_string(&path)
+                    .map_err(|e| format!("Cannot read {}: {}", path, e))?;
+                let value = serde_json::from_str(&contents)
+                    .map_err(|e| format!("{} is not JSON: {}", path, e))?;
+                Self::from_json(value)
+            }
+            Err(_) => {
+                let ids = std::env::var("WORKSPACES").unwrap_or_else(|_| "autodin".to_string());
+                let ids: Vec<&str> = ids.split(',').map(str::trim).filter(|id| !id.is_empty()).collect();
+                Self::of(&ids)
+            }
+        }
+    }
+
+    pub fn get(&self, id: &str) -> Option<&Workspace> {
+        self.workspaces.iter().find(|workspace| workspace.id == id)
+    }
+
+    pub fn default_workspace(&self) -> &Workspace {
+        self.get(&self.default).expect("the default workspace is listed")
+    }
+
+    pub fn ids(&self) -> Vec<String> {
+        self.workspaces.iter().map(|workspace| workspace.id.clone()).collect()
+    }
+
+    /// The workspace served on a hostname; a port, as in a `Host` header, is ignored
+    pub fn by_host(&self, host: &str) -> Option<&Workspace> {
+        let host = match host.rsplit_once(':') {
+            Some((name, port)) if port.chars().all(|c| c.is_ascii_digit()) => name,
+            _ => host,
+        };
+        self.workspaces.iter().find(|workspace| workspace.serves_host(host))
+    }
+
+    /// The workspace of a request sent to `host` that names `requested`, if
+    /// anything: a hostname serving a workspace decides, then the name, then
+    /// the default. Naming another workspace than the hostname's, or an
+    /// unknown one, is refused.
+    pub fn resolve(&self, host: Option<&str>, requested: Option<&str>) -> Result<&Workspace, String> {
+        let requested = requested.filter(|id| !id.is_empty());
+        match (host.and_then(|host| self.by_host(host)), requested) {
+            (Some(served), Some(id)) if served.id != id => Err(format!(
+                "Workspace '{}' is not served here", id
+            )),
+            (Some(served), _) => Ok(served),
+            (None, Some(id)) => self.get(id).ok_or_else(|| format!("Unknown workspace '{}'", id)),
+            (None, None) => Ok(self.default_workspace()),
+        }
+    }
+
+    /// Whether a browser on `origin` may call the API
+    pub fn allows_origin(&self, origin: &str) -> bool {
+        self.workspaces.iter().any(|workspace| workspace.allows_origin(origin))
+    }
+
+    /// The database of a workspace, or an error for unknown ones
+    pub fn database_of(&self, id: &str) -> Result<&str, String> {
+        self.get(id)
+            .map(Workspace::database)
+            .ok_or_else(|| format!("Unknown workspace '{}'", id))
+    }
+
+    /// Whose database a database name is
+    fn by_database(&self, database: &str) -> Option<&Workspace> {
+        self.workspaces.iter().find(|workspace| workspace.database() == database)
+    }
+}
+
+/// The MongoDB databases of the known workspaces, for the stores keeping their
+/// state next to each workspace's documents
+#[derive(Clone)]
+pub struct WorkspaceDatabases {
+    client: mongodb::Client,
+    workspaces: Arc<Workspaces>,
+}
+
+impl WorkspaceDatabases {
+    pub fn new(client: mongodb::Client, workspaces: Arc<Workspaces>) -> Self {
+        Self { client, workspaces }
+    }
+
+    /// The database of a workspace, or an error for unknown ones
+    pub fn database(&self, workspace: &str) -> Result<mongodb::Database, String> {
+        Ok(self.client.database(self.workspaces.database_of(workspace)?))
+    }
+
+    /// Have MongoDB delete the documents of `collection`, in every workspace,
+    /// once their `field` date has passed; a no-op where the index exists
+    pub async fn expire_at(&self, collection: &str, field: &str) -> Result<(), String> {
+        let options = mongodb::options::IndexOptions::builder()
+            .expire_after(std::time::Duration::ZERO)
+            .build();
+        for workspace in self.workspaces.ids() {
+            let model = mongodb::IndexModel::builder()
+                .keys(mongodb::bson::doc! { field: 1 })
+                .options(options.clone())
+                .build();
+            self.database(&workspace)?.collection::<Document>(collection).create_index(model, None).await
+                .map_err(|e| format!("Failed to index {}.{} of {}: {}", collection, field, workspace, e))?;
+        }
+        Ok(())
+    }
+}
+
+/// A document store keeping each known workspace in its database, and
+/// refusing unknown workspaces
+pub struct WorkspaceStore<S: DocumentStore> {
+    inner: S,
+    workspaces: Arc<Workspaces>,
+}
+
+impl<S: DocumentStore> WorkspaceStore<S> {
+    pub fn new(inner: S, workspaces: Arc<Workspaces>) -> Self {
+        Self { inner, workspaces }
+    }
+
+    pub fn workspaces(&self) -> &Arc<Workspaces> {
+        &self.workspaces
+    }
+
+    fn ns<'a>(&'a self, ns: Namespace<'a>) -> Result<Namespace<'a>, String> {
+        let database = self.workspaces.database_of(ns.workspace)?;
+        Ok(Namespace { workspace: database, ..ns })
+    }
+}
+
+#[async_trait]
+impl<S: DocumentStore> DocumentStore for WorkspaceStore<S> {
+    async fn insert(&self, ns: Namespace<'_>, document: Document) -> Result<Bson, String> {
+        self.inner.insert(self.ns(ns)?, document).await
+    }
+
+    async fn insert_many(&self, ns: Namespace<'_>, documents: Vec<Document>) -> Result<Vec<Bson>, String> {
+        self.inner.insert_many(self.ns(ns)?, documents).await
+    }
+
+    async fn find(&self, ns: Namespace<'_>, filter: Document, options: FindOptions) -> Result<Vec<Document>, String> {
+        self.inner.find(self.ns(ns)?, filter, options).await
+    }
+
+    async fn count(&self, ns: Namespace<'_>, filter: Document) -> Result<u64, String> {
+        self.inner.count(self.ns(ns)?, filter).await
+    }
+
+    async fn update(&self, ns: Namespace<'_>, filter: Document, update: Document, options: UpdateOptions) -> Result<UpdateOutcome, String> {
+        self.inner.update(self.ns(ns)?, filter, update, options).await
+    }
+
+    async fn find_one_and_update(&self, ns: Namespace<'_>, filter: Document, update: Document, options: FindOneAndUpdateOptions) -> Result<Option<Document>, String> {
+        self.inner.find_one_and_update(self.ns(ns)?, filter, update, options).await
+    }
+
+    async fn delete(&self, ns: Namespace<'_>, filter: Document) -> Result<u64, String> {
+        self.inner.delete(self.ns(ns)?, filter).await
+    }
+
+    async fn aggregate(&self, ns: Namespace<'_>, pipeline: Vec<Document>, limit: usize) -> Result<Vec<Document>, String> {
+        self.inner.aggregate(self.ns(ns)?, pipeline, limit).await
+    }
+
+    async fn create_index(&self, ns: Namespace<'_>, index: Index) -> Result<(), String> {
+        self.inner.create_index(self.ns(ns)?, index).await
+    }
+
+    async fn drop_index(&self, ns: Namespace<'_>, name: &str) -> Result<(), String> {
+        self.inner.drop_index(self.ns(ns)?, name).await
+    }
+
+    async fn list_indexes(&self, ns: Namespace<'_>) -> Result<Vec<Index>, String> {
+        self.inner.list_indexes(self.ns(ns)?).await
+    }
+
+    async fn rename_collection(&self, ns: Namespace<'_>, to: &str) -> Result<(), String> {
+        self.inner.rename_collection(self.ns(ns)?, to).await
+    }
+
+    /// Changes name the workspace, not its database
+    async fn watch(&self, ns: Namespace<'_>) -> Result<ChangeStream, String> {
+        let changes = self.inner.watch(self.ns(ns)?).await?;
+        let workspaces = self.workspaces.clone();
+        Ok(Box::pin(changes.map(move |change| change.map(|mut change| {
+            if let Some(workspace) = workspaces.by_database(&change.workspace) {
+                change.workspace = workspace.id.clone();
+            }
+            change
+        }))))
+    }
+
+    async fn begin(&self) -> Result<String, String> {
+        self.inner.begin().await
+    }
+
+    async fn commit(&self, transaction: &str) -> Result<(), String> {
+        self.inner.commit(transaction).await
+    }
+
+    async fn abort(&self, transaction: &str) -> Result<(), String> {
+        self.inner.abort(transaction).await
+    }
+
+    async fn health(&self) -> Result<(), String> {
+        self.inner.health().await
+    }
+}
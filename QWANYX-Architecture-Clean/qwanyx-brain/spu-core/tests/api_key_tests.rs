@@ -0,0 +1,123 @@
+//! API key tests
+//!
+//! Creating keys for service accounts, accepting them, rotating them with and
+//! without a grace period, expiry and revocation, on the in-memory store.
+
+use chrono::{Duration, Utc};
+use spu_core::auth::api_keys::{self, ApiKeyError, ApiKeyStore, ApiKeys, MemoryApiKeyStore};
+use std::sync::Arc;
+
+fn api_keys() -> (ApiKeys, Arc<MemoryApiKeyStore>) {
+    let store = Arc::new(MemoryApiKeyStore::new());
+    (ApiKeys::new(store.clone()), store)
+}
+
+fn deployer() -> Vec<String> {
+    vec!["deployer".to_string()]
+}
+
+#[test]
+fn test_authorization_header() {
+    assert_eq!(api_keys::api_key(Some("ApiKey spk_autodin.abc.def")), Some("spk_autodin.abc.def"));
+    assert_eq!(api_keys::api_key(Some("apikey  spk_x.y.z ")), Some("spk_x.y.z"));
+    assert_eq!(api_keys::api_key(Some("Bearer eyJ...")), None);
+    assert_eq!(api_keys::api_key(Some("ApiKey ")), None);
+    assert_eq!(api_keys::api_key(None), None);
+}
+
+#[tokio::test]
+async fn test_create_and_authenticate() {
+    let (keys, store) = api_keys();
+    let issued = keys.create("autodin", "deploy", deployer(), None, "u-admin").await.unwrap();
+    assert!(issued.key.starts_with(&format!("spk_autodin.{}.", issued.api_key.id)));
+
+    // Only the hash is kept, and listings leave it out
+    let stored = store.load("autodin", &issued.api_key.id).await.unwrap().unwrap();
+    assert_ne!(stored.key_hash, issued.key);
+    assert!(!issued.key.contains(&stored.key_hash));
+    let listed = serde_json::to_value(spu_core::api::ApiKey::from(&stored)).unwrap();
+    assert!(listed.get("key_hash").is_none());
+    assert_eq!(listed["prefix"], format!("spk_autodin.{}", issued.api_key.id));
+    assert_eq!(stored.created_by, "u-admin");
+
+    let claims = keys.authenticate(&issued.key).await.unwrap();
+    assert_eq!(claims.sub, "service:deploy");
+    assert_eq!(claims.workspace, "autodin");
+    assert_eq!(claims.roles, deployer());
+    assert!(claims.exp > Utc::now().timestamp());
+
+    // Uses are recorded
+    let used = store.load("autodin", &issued.api_key.id).await.unwrap().unwrap();
+    assert!(used.last_used_at.is_some());
+
+    // A wrong secret, or a key of another workspace, is just invalid
+    let (prefix, _) = issued.key.rsplit_once('.').unwrap();
+    assert_eq!(keys.authenticate(&format!("{}.{}", prefix, "0".repeat(64))).await.unwrap_err(), ApiKeyError::Invalid);
+    let elsewhere = issued.key.replacen("spk_autodin.", "spk_garage.", 1);
+    assert_eq!(keys.authenticate(&elsewhere).await.unwrap_err(), ApiKeyError::Invalid);
+    assert_eq!(keys.authenticate("not a key").await.unwrap_err(), ApiKeyError::Invalid);
+
+    assert!(matches!(keys.create("autodin", " ", deployer(), None, "u-admin").await, Err(ApiKeyError::InvalidArguments(_))));
+    assert!(matches!(keys.create("autodin", "deploy", deployer(), Some(Duration::zero()), "u-admin").await, Err(ApiKeyError::InvalidArguments(_))));
+}
+
+#[tokio::test]
+async fn test_expiry() {
+    let (keys, store) = api_keys();
+    let issued = keys.create("autodin", "deploy", deployer(), Some(Duration::days(90)), "u-admin").await.unwrap();
+    let expires_at = issued.api_key.expires_at.unwrap();
+    assert_eq!(keys.authenticate(&issued.key).await.unwrap().exp, expires_at.timestamp());
+
+    store.expire("autodin", &issued.api_key.id, Utc::now() - Duration::seconds(1)).await.unwrap();
+    assert_eq!(keys.authenticate(&issued.key).await.unwrap_err(), ApiKeyError::Expired);
+    assert!(keys.list("autodin").await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn test_rotation() {
+    let (keys, _) = api_keys();
+    let old = keys.create("autodin", "deploy", deployer(), Some(Duration::days(90)), "u-admin").await.unwrap();
+
+    // The old key works during the grace period, alongside the new one
+    let new = keys.rotate("autodin", &old.api_key.id, Duration::minutes(10), "u-other").await.unwrap();
+    assert_eq!(new.api_key.account, "deploy");
+    assert_eq!(new.api_key.roles, deployer());
+    assert_eq!(new.api_key.replaces.as_deref(), Some(old.api_key.id.as_str()));
+    assert_eq!(new.api_key.created_by, "u-other");
+    assert_eq!(new.api_key.expires_at.unwrap() - new.api_key.created_at, Duration::days(90));
+    assert_eq!(keys.authenticate(&new.key).await.unwrap().sub, "service:deploy");
+    assert_eq!(keys.authenticate(&old.key).await.unwrap().sub, "service:deploy");
+
+    let listed = keys.list("autodin").await.unwrap();
+    assert_eq!(listed.len(), 2);
+    let old_listed = listed.iter().find(|k| k.id == old.api_key.id).unwrap();
+    assert!(old_listed.expires_at.unwrap() <= Utc::now() + Duration::minutes(10));
+
+    // Without one, it stops at once
+    let newer = keys.rotate("autodin", &new.api_key.id, Duration::zero(), "u-admin").await.unwrap();
+    assert_eq!(keys.authenticate(&new.key).await.unwrap_err(), ApiKeyError::Revoked);
+    assert!(keys.authenticate(&newer.key).await.is_ok());
+
+    assert_eq!(keys.rotate("autodin", &new.api_key.id, Duration::zero(), "u-admin").await.unwrap_err(), ApiKeyError::NotFound);
+    assert_eq!(keys.rotate("garage", &newer.api_key.id, Duration::zero(), "u-admin").await.unwrap_err(), ApiKeyError::NotFound);
+
+    // A grace period past the end of the calendar is refused, not a panic
+    let error = keys.rotate("autodin", &newer.api_key.id, Duration::MAX, "u-admin").await.unwrap_err();
+    assert!(matches!(error, ApiKeyError::InvalidArguments(_)));
+    assert!(keys.authenticate(&newer.key).await.is_ok());
+}
+
+#[tokio::test]
+async fn test_revocation() {
+    let (keys, _) = api_keys();
+    let issued = keys.create("autodin", "webhooks", vec![], None, "u-admin").await.unwrap();
+    let other = keys.create("autodin", "deploy", deployer(), None, "u-admin").await.unwrap();
+
+    assert!(keys.revoke("autodin", &issued.api_key.id).await.unwrap());
+    assert!(!keys.revoke("autodin", &issued.api_key.id).await.unwrap());
+    assert_eq!(keys.authenticate(&issued.key).await.unwrap_err(), ApiKeyError::Revoked);
+
+    let listed: Vec<String> = keys.list("autodin").await.unwrap().into_iter().map(|k| k.account).collect();
+    assert_eq!(listed, ["deploy"]);
+    assert!(keys.authenticate(&other.key).await.is_ok());
+}
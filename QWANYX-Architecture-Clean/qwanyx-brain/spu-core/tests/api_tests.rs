@@ -0,0 +1,221 @@
+//! HTTP API conventions tests
+//!
+//! The error envelope, validated JSON bodies, the 400s for bodies and query
+//! strings that don't parse, and the generated OpenAPI document.
+
+use actix_web::{test as actix_test, web, App, HttpResponse};
+use serde::Deserialize;
+use serde_json::Value;
+use spu_core::api::{self, ApiError, OptionalJson, ValidJson};
+use validator::Validate;
+
+#[derive(Deserialize, Validate)]
+struct Signup {
+    #[validate(email)]
+    email: String,
+    #[validate(length(min = 1, max = 10))]
+    name: String,
+    #[validate(range(min = 1, max = 5))]
+    seats: u32,
+}
+
+async fn signup(req: ValidJson<Signup>) -> HttpResponse {
+    HttpResponse::Ok().json(serde_json::json!({ "name": req.name, "seats": req.seats }))
+}
+
+#[derive(Default, Deserialize, Validate)]
+struct Rotate {
+    #[serde(default)]
+    #[validate(range(min = 0, max = 60))]
+    grace: i64,
+}
+
+async fn rotate(req: OptionalJson<Rotate>) -> HttpResponse {
+    let req = req.into_inner().unwrap_or_default();
+    HttpResponse::Ok().json(serde_json::json!({ "grace": req.grace }))
+}
+
+#[derive(Deserialize)]
+struct Page {
+    #[allow(dead_code)]
+    limit: u32,
+}
+
+async fn page(_query: web::Query<Page>) -> HttpResponse {
+    HttpResponse::Ok().finish()
+}
+
+/// Posts `body` as JSON to an app configured like the server's, returning the status and body
+async fn post(body: &str, content_type: &str) -> (u16, Value) {
+    let app = actix_test::init_service(
+        App::new()
+            .app_data(web::JsonConfig::default().error_handler(api::json_error))
+            .route("/signup", web::post().to(signup)),
+    ).await;
+    let req = actix_test::TestRequest::post()
+        .uri("/signup")
+        .insert_header(("content-type", content_type))
+        .set_payload(body.to_string())
+        .to_request();
+    let resp = actix_test::call_service(&app, req).await;
+    let status = resp.status().as_u16();
+    let bytes = actix_test::read_body(resp).await;
+    (status, serde_json::from_slice(&bytes).unwrap_or(Value::Null))
+}
+
+#[actix_web::test]
+async fn test_valid_body_reaches_handler() {
+    let (status, body) = post(r#"{"email":"ann@example.com","name":"Ann","seats":2}"#, "application/json").await;
+    assert_eq!(status, 200);
+    assert_eq!(body["name"], "Ann");
+    assert_eq!(body["seats"], 2);
+}
+
+#[actix_web::test]
+async fn test_invalid_body_lists_fields() {
+    let (status, body) = post(r#"{"email":"ann","name":"","seats":9}"#, "application/json").await;
+    assert_eq!(status, 400);
+    assert_eq!(body["success"], false);
+    let fields: Vec<(&str, &str)> = body["fields"].as_array().unwrap().iter()
+        .map(|f| (f["field"].as_str().unwrap(), f["message"].as_str().unwrap()))
+        .collect();
+    assert_eq!(fields, vec![
+        ("email", "must be an email address"),
+        ("name", "must not be empty"),
+        ("seats", "must be between 1 and 5"),
+    ]);
+    let error = body["error"].as_str().unwrap();
+    assert!(error.starts_with("Invalid request: 'email' must be an email address"), "{}", error);
+}
+
+#[actix_web::test]
+async fn test_too_long_field() {
+    let (status, body) = post(r#"{"email":"ann@example.com","name":"Annabelle Smith","seats":1}"#, "application/json").await;
+    assert_eq!(status, 400);
+    assert_eq!(body["fields"][0]["field"], "name");
+    assert_eq!(body["fields"][0]["message"], "must be 1 to 10 characters");
+}
+
+#[actix_web::test]
+async fn test_missing_field_and_malformed_json() {
+    let (status, body) = post(r#"{"email":"ann@example.com","name":"Ann"}"#, "application/json").await;
+    assert_eq!(status, 400);
+    assert_eq!(body["success"], false);
+    assert!(body["error"].as_str().unwrap().contains("missing field `seats`"));
+    assert!(body.get("fields").is_none());
+
+    let (status, body) = post(r#"{"email":"#, "application/json").await;
+    assert_eq!(status, 400);
+    assert!(body["error"].as_str().unwrap().starts_with("Invalid JSON body"));
+}
+
+#[actix_web::test]
+async fn test_wrong_content_type() {
+    let (status, body) = post(r#"{"email":"ann@example.com","name":"Ann","seats":2}"#, "text/plain").await;
+    assert_eq!(status, 400);
+    assert_eq!(body["error"], "Expected a JSON body with Content-Type: application/json");
+}
+
+#[actix_web::test]
+async fn test_optional_body() {
+    let app = actix_test::init_service(App::new().route("/rotate", web::post().to(rotate))).await;
+    let call = |body: &'static str, content_type: Option<&'static str>| {
+        let mut req = actix_test::TestRequest::post().uri("/rotate").set_payload(body);
+        if let Some(content_type) = content_type {
+            req = req.insert_header(("content-type", content_type));
+        }
+        req.to_request()
+    };
+
+    // No body at all, with or without a content type, takes the defaults
+    for content_type in [None, Some("application/json")] {
+        let resp = actix_test::call_service(&app, call("", content_type)).await;
+        assert_eq!(resp.status().as_u16(), 200);
+        let body: Value = serde_json::from_slice(&actix_test::read_body(resp).await).unwrap();
+        assert_eq!(body["grace"], 0);
+    }
+
+    let resp = actix_test::call_service(&app, call(r#"{"grace":5}"#, Some("application/json"))).await;
+    let body: Value = serde_json::from_slice(&actix_test::read_body(resp).await).unwrap();
+    assert_eq!(body["grace"], 5);
+
+    // A body that is there is never silently dropped
+    for (payload, content_type, error) in [
+        (r#"{"grace":"#, Some("application/json"), "Invalid JSON body"),
+        (r#"{"grace":"five"}"#, Some("application/json"), "Invalid JSON body"),
+        (r#"{"grace":5}"#, None, "Expected a JSON body"),
+        (r#"{"grace":600}"#, Some("application/json"), "Invalid request: 'grace' must be between 0 and 60"),
+    ] {
+        let resp = actix_test::call_service(&app, call(payload, content_type)).await;
+        assert_eq!(resp.status().as_u16(), 400, "{}", payload);
+        let body: Value = serde_json::from_slice(&actix_test::read_body(resp).await).unwrap();
+        assert_eq!(body["success"], false);
+        assert!(body["error"].as_str().unwrap().starts_with(error), "{}", body["error"]);
+    }
+}
+
+#[actix_web::test]
+async fn test_invalid_query_string() {
+    let app = actix_test::init_service(
+        App::new()
+            .app_data(web::QueryConfig::default().error_handler(api::query_error))
+            .route("/page", web::get().to(page)),
+    ).await;
+    let resp = actix_test::call_service(&app, actix_test::TestRequest::get().uri("/page?limit=abc").to_request()).await;
+    assert_eq!(resp.status().as_u16(), 400);
+    let body: Value = serde_json::from_slice(&actix_test::read_body(resp).await).unwrap();
+    assert_eq!(body["success"], false);
+    assert!(body["error"].as_str().unwrap().starts_with("Invalid query string"));
+}
+
+#[test]
+fn test_error_envelope_omits_empty_fields() {
+    let json = serde_json::to_value(ApiError::new("Not found")).unwrap();
+    assert_eq!(json, serde_json::json!({ "success": false, "error": "Not found" }));
+}
+
+#[test]
+fn test_script_results_become_typed_bodies() {
+    let report = api::ValidationReport::from_result(serde_json::json!({
+        "checked": 3.0, "valid": 2.0, "invalid": 1.0,
+        "documents": [{ "_id": "a", "errors": ["'name' is required"] }],
+    }));
+    assert_eq!(serde_json::to_value(report).unwrap(), serde_json::json!({
+        "success": true, "checked": 3, "valid": 2, "invalid": 1,
+        "documents": [{ "_id": "a", "errors": ["'name' is required"] }],
+    }));
+
+    let results = api::SearchResults::from_result(serde_json::json!({ "hits": "not hits" }));
+    assert!(results.hits.is_empty());
+    assert_eq!(results.total, 0);
+    assert_eq!(results.facets, serde_json::json!({}));
+}
+
+#[test]
+fn test_openapi_subcommand() {
+    let output = std::process::Command::new(env!("CARGO_BIN_EXE_spu-core"))
+        .arg("openapi")
+        .output()
+        .expect("run spu-core openapi");
+    assert!(output.status.success());
+    let doc: Value = serde_json::from_slice(&output.stdout).expect("OpenAPI JSON");
+
+    assert!(doc["openapi"].as_str().unwrap().starts_with("3."));
+    for path in ["/health", "/auth/register", "/auth/verify-code", "/auth/refresh", "/api-keys", "/audit", "/data/{collection}"] {
+        assert!(doc["paths"].get(path).is_some(), "missing {}", path);
+    }
+    let schemas = &doc["components"]["schemas"];
+    for schema in ["ApiError", "Session", "RegisterRequest", "ApiKey", "Workflow", "Schedules", "Webhook", "SearchResults", "Stored"] {
+        assert!(schemas.get(schema).is_some(), "missing schema {}", schema);
+    }
+    assert_eq!(
+        doc["paths"]["/auth/register"]["post"]["requestBody"]["content"]["application/json"]["schema"]["$ref"],
+        "#/components/schemas/RegisterRequest",
+    );
+    assert_eq!(
+        doc["paths"]["/schedules"]["get"]["responses"]["200"]["content"]["application/json"]["schema"]["$ref"],
+        "#/components/schemas/Schedules",
+    );
+    assert!(doc["components"]["securitySchemes"].get("bearer").is_some());
+    assert!(doc["components"]["securitySchemes"].get("api_key").is_some());
+}
@@ -0,0 +1,235 @@
+//! Audit log tests
+//!
+//! Recording and querying entries, the writes of the database coprocessor, the
+//! events of the auth coprocessor, changes to webhooks, triggers and migration
+//! steps, transactions and retention, on the in-memory store.
+
+use chrono::{Duration, Utc};
+use mongodb::bson::doc;
+use serde_json::json;
+use spu_core::{
+    audit::{self, AuditEntry, AuditLog, AuditQuery, Context, AUDIT_COLLECTION},
+    auth::policy::Caller,
+    coprocessors::{AuthCoprocessor, DatabaseCoprocessor},
+    migrations::{Migration, Migrator, Step},
+    runtime::SPURuntime,
+    store::{DocumentStore, MemoryStore, Namespace},
+    triggers::{MemoryTriggerStore, TriggerDispatcher},
+    webhooks::{MemoryWebhookStore, WebhookRouter},
+    Coprocessor, CoprocessorError, Data,
+};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+fn audit_log() -> Arc<AuditLog> {
+    Arc::new(AuditLog::new(Arc::new(MemoryStore::new())))
+}
+
+fn object(json: serde_json::Value) -> Data {
+    Data::from_json(json)
+}
+
+fn query(json: serde_json::Value) -> AuditQuery {
+    serde_json::from_value(json).unwrap()
+}
+
+#[tokio::test]
+async fn test_entries_and_queries() {
+    let audit = audit_log();
+
+    // Entries take who is acting from the context they are made in
+    let context = Context::default().with_actor("u-1").with_ip("10.0.0.7");
+    let entry = audit::scope(context, audit::as_script("trigger:notify", async {
+        AuditEntry::new("autodin", "database.updated").on("users/42")
+    })).await;
+    assert_eq!(entry.actor, "u-1");
+    assert_eq!(entry.ip.as_deref(), Some("10.0.0.7"));
+    assert_eq!(entry.script.as_deref(), Some("trigger:notify"));
+    assert_eq!(AuditEntry::new("autodin", "auth.signed_in").actor, audit::SYSTEM_ACTOR);
+
+    audit.record(&entry, None).await.unwrap();
+    audit.record(&AuditEntry::new("autodin", "auth.signed_in").by("u-2").on("sessions/s-1"), None).await.unwrap();
+    audit.record(&AuditEntry::new("autodin", "authority.changed").by("u-2"), None).await.unwrap();
+    audit.record(&AuditEntry::new("garage", "auth.signed_in").by("u-3"), None).await.unwrap();
+
+    // Newest first, in the workspace asked for
+    let entries = audit.query("autodin", &AuditQuery::default()).await.unwrap();
+    let actions: Vec<&str> = entries.iter().map(|e| e.action.as_str()).collect();
+    assert_eq!(actions, ["authority.changed", "auth.signed_in", "database.updated"]);
+    assert_eq!(entries[2], entry);
+
+    let found = audit.query("autodin", &query(json!({ "action": "auth" }))).await.unwrap();
+    assert_eq!(found.len(), 1);
+    assert_eq!(found[0].target.as_deref(), Some("sessions/s-1"));
+    assert_eq!(audit.query("autodin", &query(json!({ "target": "users" }))).await.unwrap().len(), 1);
+    assert_eq!(audit.query("autodin", &query(json!({ "actor": "u-2", "limit": 1 }))).await.unwrap().len(), 1);
+    let since = (Utc::now() + Duration::minutes(1)).to_rfc3339();
+    assert!(audit.query("autodin", &query(json!({ "since": since }))).await.unwrap().is_empty());
+    assert!(audit.query("autodin", &query(json!({ "limit": 0 }))).await.is_err());
+}
+
+/// Documents and their log in the same store, as transactions need
+fn database() -> (DatabaseCoprocessor<Arc<dyn DocumentStore>>, Arc<AuditLog>) {
+    let store: Arc<dyn DocumentStore> = Arc::new(MemoryStore::new());
+    let audit = Arc::new(AuditLog::new(store.clone()));
+    (DatabaseCoprocessor::with_store(store).with_audit(audit.clone()), audit)
+}
+
+#[tokio::test]
+async fn test_database_writes() {
+    let (db, audit) = database();
+    let admin = Caller::new("u-admin", "autodin", vec!["admin".to_string()]);
+
+    let stored = db.invoke("store", object(json!({ "workspace": "autodin", "collection": "users", "data": { "name": "Marie", "role": "user" } }))).await.unwrap();
+    let id = stored.to_json()["id"].as_str().unwrap().to_string();
+
+    let update = object(json!({ "workspace": "autodin", "collection": "users", "filter": { "_id": id }, "update": { "role": "admin" } }));
+    audit::scope(Context::default().with_ip("10.0.0.7"), db.invoke_as("update", update, Some(&admin))).await.unwrap();
+    let delete = object(json!({ "workspace": "autodin", "collection": "users", "filter": { "_id": id } }));
+    db.invoke_as("delete", delete, Some(&admin)).await.unwrap();
+
+    let entries = audit.query("autodin", &AuditQuery::default()).await.unwrap();
+    let target = format!("users/{}", id);
+    assert!(entries.iter().all(|e| e.target.as_deref() == Some(target.as_str())));
+
+    // Only the fields an update changed are kept, as they were and are
+    let (deleted, updated, inserted) = (&entries[0], &entries[1], &entries[2]);
+    assert_eq!((inserted.action.as_str(), inserted.actor.as_str()), ("database.inserted", audit::SYSTEM_ACTOR));
+    assert_eq!(inserted.after.as_ref().unwrap().get_str("name").unwrap(), "Marie");
+    assert!(inserted.before.is_none());
+    assert_eq!((updated.action.as_str(), updated.actor.as_str()), ("database.updated", "u-admin"));
+    assert_eq!(updated.before, Some(doc! { "role": "user" }));
+    assert_eq!(updated.after, Some(doc! { "role": "admin" }));
+    assert_eq!(updated.ip.as_deref(), Some("10.0.0.7"));
+    assert_eq!(deleted.action, "database.deleted");
+    assert_eq!(deleted.before.as_ref().unwrap().get_str("role").unwrap(), "admin");
+    assert!(deleted.after.is_none());
+
+    // Updates that change nothing are not logged
+    let noop = object(json!({ "workspace": "autodin", "collection": "users", "filter": { "name": "Nobody" }, "update": { "role": "admin" } }));
+    db.invoke("update", noop).await.unwrap();
+    assert_eq!(audit.query("autodin", &AuditQuery::default()).await.unwrap().len(), 3);
+
+    // Not even admins write to the log
+    let forged = object(json!({ "workspace": "autodin", "collection": AUDIT_COLLECTION, "filter": {} }));
+    match db.invoke("delete", forged).await {
+        Err(CoprocessorError::Forbidden(_)) => {}
+        other => panic!("expected the log to be refused, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn test_transactions() {
+    let (db, audit) = database();
+    let store = |transaction: &str, name: &str| object(json!({
+        "workspace": "autodin", "collection": "cars", "transaction": transaction, "data": { "model": name }
+    }));
+
+    // Entries of aborted writes go with them
+    let aborted = db.invoke("begin", object(json!({}))).await.unwrap().to_json()["transaction"].as_str().unwrap().to_string();
+    db.invoke("store", store(&aborted, "Clio")).await.unwrap();
+    db.invoke("abort", object(json!({ "transaction": aborted }))).await.unwrap();
+    assert!(audit.query("autodin", &AuditQuery::default()).await.unwrap().is_empty());
+
+    let committed = db.invoke("begin", object(json!({}))).await.unwrap().to_json()["transaction"].as_str().unwrap().to_string();
+    db.invoke("store", store(&committed, "Megane")).await.unwrap();
+    db.invoke("commit", object(json!({ "transaction": committed }))).await.unwrap();
+    let entries = audit.query("autodin", &AuditQuery::default()).await.unwrap();
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0].after.as_ref().unwrap().get_str("model").unwrap(), "Megane");
+}
+
+#[tokio::test]
+async fn test_retention() {
+    let store = Arc::new(MemoryStore::new());
+    let audit = AuditLog::new(store.clone()).with_retention(Some(Duration::days(30)));
+
+    let mut old = AuditEntry::new("autodin", "auth.signed_in");
+    old.at = Utc::now() - Duration::days(31);
+    audit.record(&old, None).await.unwrap();
+    audit.record(&AuditEntry::new("autodin", "auth.signed_in"), None).await.unwrap();
+
+    assert_eq!(audit.prune("autodin", Utc::now()).await.unwrap(), 1);
+    assert_eq!(audit.query("autodin", &AuditQuery::default()).await.unwrap().len(), 1);
+
+    // Kept forever without a retention
+    let forever = AuditLog::new(store.clone());
+    assert_eq!(forever.prune("autodin", Utc::now() + Duration::days(3650)).await.unwrap(), 0);
+    assert_eq!(store.count(Namespace::new("autodin", AUDIT_COLLECTION), doc! {}).await.unwrap(), 1);
+}
+
+#[tokio::test]
+async fn test_auth_events() {
+    let audit = audit_log();
+    let auth = AuthCoprocessor::new().with_audit(audit.clone());
+
+    let request = object(json!({ "email": "marie@garage.be", "workspace": "autodin", "ip": "10.0.0.7" }));
+    let code = auth.invoke("generate_code", request).await.unwrap().to_json()["code"].as_str().unwrap().to_string();
+    let wrong = if code == "000000" { "111111" } else { "000000" };
+    let verify = |code: &str| object(json!({ "email": "marie@garage.be", "code": code, "workspace": "autodin" }));
+    auth.invoke("verify_code", verify(wrong)).await.unwrap();
+    let verified = auth.invoke("verify_code", verify(&code)).await.unwrap().to_json();
+
+    let entries = audit.query("autodin", &query(json!({ "action": "auth" }))).await.unwrap();
+    let actions: Vec<&str> = entries.iter().map(|e| e.action.as_str()).collect();
+    assert_eq!(actions, ["auth.signed_in", "auth.sign_in_failed", "auth.code_requested"]);
+    assert!(entries.iter().all(|e| e.actor == "marie@garage.be"));
+    assert_eq!(entries[2].ip.as_deref(), Some("10.0.0.7"));
+    assert_eq!(entries[0].target, Some(format!("sessions/{}", verified["session_id"].as_str().unwrap())));
+}
+
+#[tokio::test]
+async fn test_configuration_changes() {
+    let audit = audit_log();
+    let runtime = Arc::new(SPURuntime::new());
+
+    // Webhook secrets are logged by fingerprint only
+    let webhooks = WebhookRouter::new(runtime.clone(), Arc::new(MemoryWebhookStore::new())).with_audit(audit.clone());
+    let auth = |secret: &str| serde_json::from_value(json!({ "type": "hmac", "secret": secret })).unwrap();
+    let context = Context::default().with_actor("u-1");
+    audit::scope(context, async {
+        webhooks.save("autodin", "orders", auth("first-secret"), "SET result ok", 200, true).await.unwrap();
+        webhooks.save("autodin", "orders", auth("second-secret"), "SET result ok", 202, true).await.unwrap();
+        assert!(webhooks.delete("autodin", "orders").await.unwrap());
+    }).await;
+    assert!(!webhooks.delete("autodin", "orders").await.unwrap());
+
+    let entries = audit.query("autodin", &query(json!({ "target": "webhooks/orders" }))).await.unwrap();
+    let actions: Vec<&str> = entries.iter().map(|e| e.action.as_str()).collect();
+    assert_eq!(actions, ["webhook.deleted", "webhook.saved", "webhook.saved"]);
+    assert!(entries.iter().all(|e| e.actor == "u-1"));
+    let changed = entries[1].to_json();
+    assert_eq!(changed["before"]["status"], 200);
+    assert_eq!(changed["after"]["status"], 202);
+    assert_ne!(changed["before"]["secret"], changed["after"]["secret"]);
+    let logged = serde_json::to_string(&entries.iter().map(AuditEntry::to_json).collect::<Vec<_>>()).unwrap();
+    assert!(!logged.contains("first-secret") && !logged.contains("second-secret"));
+
+    // Triggers, in their own workspace
+    let triggers = TriggerDispatcher::new(runtime.clone(), Arc::new(MemoryTriggerStore::new())).with_audit(audit.clone());
+    triggers.save("garage", "notify", "database.inserted", HashMap::new(), "SET result ok", true).await.unwrap();
+    triggers.delete("garage", "notify").await.unwrap();
+    let entries = audit.query("garage", &query(json!({ "target": "triggers/notify" }))).await.unwrap();
+    let actions: Vec<&str> = entries.iter().map(|e| e.action.as_str()).collect();
+    assert_eq!(actions, ["trigger.deleted", "trigger.saved"]);
+    assert_eq!(entries[1].to_json()["after"]["event"], "database.inserted");
+
+    // Declarative migration steps, with the documents they changed
+    let store = Arc::new(MemoryStore::new());
+    store.insert(Namespace::new("autodin", "users"), doc! { "mail": "marie@garage.be" }).await.unwrap();
+    let migration = Migration {
+        version: 1,
+        name: "rename_mail".to_string(),
+        description: None,
+        up: vec![Step::RenameField { collection: "users".to_string(), from: "mail".to_string(), to: "email".to_string() }],
+        down: None,
+    };
+    let migrator = Migrator::new(runtime, store, vec![migration]).with_audit(audit.clone());
+    migrator.migrate("autodin", None, false).await.unwrap();
+    let entries = audit.query("autodin", &query(json!({ "action": "migration" }))).await.unwrap();
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0].action, "migration.rename_field");
+    assert_eq!(entries[0].target.as_deref(), Some("users"));
+    assert_eq!(entries[0].script.as_deref(), Some("migration:1"));
+    assert_eq!(entries[0].to_json()["after"]["modified"], 1);
+}
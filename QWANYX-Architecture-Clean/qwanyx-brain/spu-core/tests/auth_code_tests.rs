@@ -0,0 +1,135 @@
+//! Login code tests
+//!
+//! Issuing and checking one-time codes on the in-memory store: hashing, single
+//! use, replacement, expiry, attempt limits and rate limits, and the `auth`
+//! coprocessor on top.
+
+use chrono::{Duration, Utc};
+use spu_core::{
+    auth::codes::{CodeError, CodePolicy, CodeStore, LoginCodes, MemoryCodeStore},
+    coprocessors::AuthCoprocessor,
+    runtime::SPURuntime,
+    Data,
+};
+use std::sync::Arc;
+
+fn codes(policy: CodePolicy) -> (LoginCodes, Arc<MemoryCodeStore>) {
+    let store = Arc::new(MemoryCodeStore::new());
+    (LoginCodes::new(store.clone(), "test-secret").with_policy(policy), store)
+}
+
+#[tokio::test]
+async fn test_codes_are_hashed_and_used_once() {
+    let (codes, store) = codes(CodePolicy::default());
+
+    let (code, expires_at) = codes.issue("autodin", " Marie@Garage.be ", None).await.unwrap();
+    assert_eq!(code.len(), 6);
+    assert!(expires_at > Utc::now() + Duration::minutes(9));
+
+    let record = store.load("autodin", "marie@garage.be").await.unwrap().unwrap();
+    assert_ne!(record.hash, code);
+    assert!(!record.hash.contains(&code));
+
+    // Other workspaces and emails have codes of their own
+    assert_eq!(codes.verify("belgique", "marie@garage.be", &code).await, Err(CodeError::Invalid));
+    assert_eq!(codes.verify("autodin", "paul@garage.be", &code).await, Err(CodeError::Invalid));
+
+    assert_eq!(codes.verify("autodin", "MARIE@garage.be", &code).await, Ok(()));
+    assert_eq!(codes.verify("autodin", "marie@garage.be", &code).await, Err(CodeError::Invalid));
+}
+
+#[tokio::test]
+async fn test_new_codes_replace_old_ones() {
+    let (codes, _store) = codes(CodePolicy::default());
+
+    let (first, _) = codes.issue("autodin", "marie@garage.be", None).await.unwrap();
+    let (second, _) = codes.issue("autodin", "marie@garage.be", None).await.unwrap();
+    if first != second {
+        assert_eq!(codes.verify("autodin", "marie@garage.be", &first).await, Err(CodeError::Invalid));
+    }
+    assert_eq!(codes.verify("autodin", "marie@garage.be", &second).await, Ok(()));
+}
+
+#[tokio::test]
+async fn test_expired_codes() {
+    let (codes, store) = codes(CodePolicy::default());
+
+    let (code, _) = codes.issue("autodin", "marie@garage.be", None).await.unwrap();
+    let mut record = store.load("autodin", "marie@garage.be").await.unwrap().unwrap();
+    record.expires_at = Utc::now() - Duration::seconds(1);
+    store.replace(&record).await.unwrap();
+
+    assert_eq!(codes.verify("autodin", "marie@garage.be", &code).await, Err(CodeError::Expired));
+    assert!(store.load("autodin", "marie@garage.be").await.unwrap().is_none());
+}
+
+#[tokio::test]
+async fn test_wrong_codes_burn_the_code() {
+    let (codes, _store) = codes(CodePolicy { max_attempts: 3, ..CodePolicy::default() });
+
+    let (code, _) = codes.issue("autodin", "marie@garage.be", None).await.unwrap();
+    let wrong = if code == "000000" { "111111" } else { "000000" };
+    assert_eq!(codes.verify("autodin", "marie@garage.be", wrong).await, Err(CodeError::Invalid));
+    assert_eq!(codes.verify("autodin", "marie@garage.be", wrong).await, Err(CodeError::Invalid));
+    assert_eq!(codes.verify("autodin", "marie@garage.be", wrong).await, Err(CodeError::TooManyAttempts));
+    // Even the right code is refused now
+    assert_eq!(codes.verify("autodin", "marie@garage.be", &code).await, Err(CodeError::TooManyAttempts));
+
+    // Until a new one is issued
+    let (code, _) = codes.issue("autodin", "marie@garage.be", None).await.unwrap();
+    assert_eq!(codes.verify("autodin", "marie@garage.be", &code).await, Ok(()));
+}
+
+#[tokio::test]
+async fn test_requests_are_rate_limited() {
+    let (codes, _store) = codes(CodePolicy { per_email: 2, per_ip: 3, ..CodePolicy::default() });
+
+    codes.issue("autodin", "marie@garage.be", Some("10.0.0.1")).await.unwrap();
+    codes.issue("autodin", "Marie@garage.be", Some("10.0.0.2")).await.unwrap();
+    assert_eq!(codes.issue("autodin", "marie@garage.be", Some("10.0.0.3")).await, Err(CodeError::RateLimited));
+    // Other workspaces count separately
+    codes.issue("belgique", "marie@garage.be", None).await.unwrap();
+
+    codes.issue("autodin", "paul@garage.be", Some("10.0.0.1")).await.unwrap();
+    codes.issue("autodin", "jean@garage.be", Some("10.0.0.1")).await.unwrap();
+    assert_eq!(codes.issue("autodin", "luc@garage.be", Some("10.0.0.1")).await, Err(CodeError::RateLimited));
+}
+
+#[tokio::test]
+async fn test_auth_coprocessor_codes() {
+    let runtime = SPURuntime::new();
+    let store = Arc::new(MemoryCodeStore::new());
+    let policy = CodePolicy { per_email: 2, ..CodePolicy::default() };
+    runtime.register_class("auth".to_string(), Arc::new(AuthCoprocessor::new().with_codes(store, policy))).await;
+
+    let register = r#"
+INSTANTIATE auth auth1
+SET user {"email": "marie@garage.be", "firstName": "Marie", "workspace": "belgique"}
+CALL auth1 register $user result
+"#;
+    let code = match runtime.execute(register).await.unwrap() {
+        Data::Object(obj) => obj["code"].clone(),
+        other => panic!("expected the registration, got {:?}", other),
+    };
+
+    let mut inputs = std::collections::HashMap::new();
+    inputs.insert("code".to_string(), code);
+    let verify = r#"
+INSTANTIATE auth auth1
+SET verify {"email": "marie@garage.be", "code": "$code", "workspace": "belgique"}
+CALL auth1 verify_code $verify result
+"#;
+    let first = runtime.execute_with_inputs(verify, inputs.clone()).await.unwrap().to_json();
+    assert_eq!(first["valid"], serde_json::json!(true));
+    let second = runtime.execute_with_inputs(verify, inputs).await.unwrap().to_json();
+    assert_eq!((&second["valid"], &second["error"]), (&serde_json::json!(false), &serde_json::json!("Invalid code")));
+
+    let ask = r#"
+INSTANTIATE auth auth1
+SET input {"email": "marie@garage.be", "workspace": "belgique", "ip": "10.0.0.1"}
+CALL auth1 generate_code $input result
+"#;
+    assert!(matches!(runtime.execute(ask).await, Ok(Data::Object(_))));
+    let error = runtime.execute(ask).await.unwrap_err();
+    assert!(error.contains("Too many code requests"), "{}", error);
+}
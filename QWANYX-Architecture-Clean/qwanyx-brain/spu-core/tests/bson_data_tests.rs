@@ -0,0 +1,189 @@
+//! BSON ⇄ Data conversion tests
+//!
+//! Property tests that any BSON value survives the trip through `Data`, and through
+//! JSON, with its type intact (Int32 coming back as Int64).
+
+use mongodb::bson::{
+    doc, oid::ObjectId, spec::BinarySubtype, Binary, Bson, DateTime, Document, Regex, Timestamp,
+};
+use proptest::prelude::*;
+use spu_core::{
+    bson_data::{bson_to_data, data_to_bson, data_to_document, document_to_data},
+    Data,
+};
+use std::collections::HashMap;
+
+fn field_name() -> impl Strategy<Value = String> {
+    "[a-zA-Z_][a-zA-Z0-9_]{0,8}"
+}
+
+fn bson_leaf() -> impl Strategy<Value = Bson> {
+    prop_oneof![
+        any::<f64>().prop_filter("finite", |d| d.is_finite()).prop_map(Bson::Double),
+        ".{0,12}".prop_map(Bson::String),
+        any::<bool>().prop_map(Bson::Boolean),
+        Just(Bson::Null),
+        any::<i32>().prop_map(Bson::Int32),
+        any::<i64>().prop_map(Bson::Int64),
+        any::<i64>().prop_map(|ms| Bson::DateTime(DateTime::from_millis(ms))),
+        any::<[u8; 12]>().prop_map(|bytes| Bson::ObjectId(ObjectId::from_bytes(bytes))),
+        proptest::collection::vec(any::<u8>(), 0..32)
+            .prop_map(|bytes| Bson::Binary(Binary { subtype: BinarySubtype::Generic, bytes })),
+        (any::<u32>(), any::<u32>())
+            .prop_map(|(time, increment)| Bson::Timestamp(Timestamp { time, increment })),
+        ("[a-z.*^$]{0,8}", proptest::sample::subsequence(vec!['i', 'm', 's', 'x'], 0..4))
+            .prop_map(|(pattern, options)| Bson::RegularExpression(Regex {
+                pattern,
+                options: options.into_iter().collect(),
+            })),
+        "[a-z ();]{0,12}".prop_map(Bson::JavaScriptCode),
+        Just(Bson::MinKey),
+        Just(Bson::MaxKey),
+    ]
+}
+
+fn bson_value() -> impl Strategy<Value = Bson> {
+    bson_leaf().prop_recursive(3, 48, 6, |inner| {
+        prop_oneof![
+            proptest::collection::vec(inner.clone(), 0..6).prop_map(Bson::Array),
+            proptest::collection::hash_map(field_name(), inner, 0..6)
+                .prop_map(|fields| Bson::Document(fields.into_iter().collect())),
+        ]
+    })
+}
+
+fn data_value() -> impl Strategy<Value = Data> {
+    let leaf = prop_oneof![
+        Just(Data::Null),
+        any::<bool>().prop_map(Data::Bool),
+        any::<f64>().prop_filter("finite", |d| d.is_finite()).prop_map(Data::Number),
+        ".{0,12}".prop_map(Data::String),
+    ];
+    leaf.prop_recursive(3, 48, 6, |inner| {
+        prop_oneof![
+            proptest::collection::vec(inner.clone(), 0..6).prop_map(Data::Array),
+            proptest::collection::hash_map(field_name(), inner, 0..6).prop_map(Data::Object),
+        ]
+    })
+}
+
+/// What a value is written back as: Int32 widened to Int64
+fn widened(value: Bson) -> Bson {
+    match value {
+        Bson::Int32(i) => Bson::Int64(i64::from(i)),
+        Bson::Array(items) => Bson::Array(items.into_iter().map(widened).collect()),
+        Bson::Document(doc) => Bson::Document(doc.into_iter().map(|(k, v)| (k, widened(v))).collect()),
+        other => other,
+    }
+}
+
+proptest! {
+    #[test]
+    fn prop_bson_survives_data(value in bson_value()) {
+        let data = bson_to_data(&value);
+        prop_assert_eq!(data_to_bson(&data), Ok(widened(value)));
+    }
+
+    #[test]
+    fn prop_bson_survives_json(value in bson_value()) {
+        // What the HTTP API and workflow records go through
+        let json = bson_to_data(&value).to_json().to_string();
+        let data = Data::from_json(serde_json::from_str(&json).unwrap());
+        prop_assert_eq!(data_to_bson(&data), Ok(widened(value)));
+    }
+
+    #[test]
+    fn prop_data_survives_bson(value in data_value()) {
+        let bson = data_to_bson(&value).unwrap();
+        prop_assert_eq!(bson_to_data(&bson), value);
+    }
+}
+
+fn object(pairs: Vec<(&str, Data)>) -> HashMap<String, Data> {
+    pairs.into_iter().map(|(k, v)| (k.to_string(), v)).collect()
+}
+
+#[test]
+fn test_typed_values_are_plain_json_on_the_wire() {
+    let oid = ObjectId::parse_str("64b7f0c2a1b2c3d4e5f60718").unwrap();
+    let document = doc! {
+        "_id": oid,
+        "count": 7_i32,
+        "big": i64::MAX,
+        "price": 7.0,
+        "created": DateTime::parse_rfc3339_str("2024-05-01T10:00:00Z").unwrap(),
+        "nested": { "owner": oid, "seen": DateTime::parse_rfc3339_str("2024-05-01T10:00:00Z").unwrap() },
+    };
+
+    let data = document_to_data(&document);
+    match &data {
+        Data::Object(obj) => assert_eq!(obj.get("count"), Some(&Data::Integer(7))),
+        other => panic!("Unexpected document: {:?}", other),
+    }
+
+    // What /data and /users clients got before the typed variants
+    let json = data.to_plain_json();
+    assert_eq!(json["_id"], serde_json::json!("64b7f0c2a1b2c3d4e5f60718"));
+    assert_eq!(json["count"], serde_json::json!(7));
+    assert_eq!(json["big"], serde_json::json!(i64::MAX));
+    assert_eq!(json["price"], serde_json::json!(7.0));
+    assert_eq!(json["created"], serde_json::json!("2024-05-01T10:00:00Z"));
+    assert_eq!(
+        json["nested"],
+        serde_json::json!({ "owner": "64b7f0c2a1b2c3d4e5f60718", "seen": "2024-05-01T10:00:00Z" })
+    );
+
+    // Internal round-trips keep the types
+    let internal = data.to_json();
+    assert_eq!(internal["_id"], serde_json::json!({ "$oid": "64b7f0c2a1b2c3d4e5f60718" }));
+    assert_eq!(internal["count"], serde_json::json!({ "$numberLong": "7" }));
+}
+
+#[test]
+fn test_nested_documents_and_arrays_are_kept() {
+    let document = doc! {
+        "customer": { "name": "Jean", "address": { "city": "Liège" } },
+        "lines": [{ "sku": "A1", "qty": 2_i64 }, { "sku": "B2", "qty": 1_i64 }],
+        "tags": ["urgent", 3.5, null, [true]],
+    };
+    let data = document_to_data(&document);
+    match &data {
+        Data::Object(obj) => match obj.get("lines") {
+            Some(Data::Array(lines)) => assert_eq!(lines.len(), 2),
+            other => panic!("Unexpected lines: {:?}", other),
+        },
+        other => panic!("Unexpected document: {:?}", other),
+    }
+
+    match data {
+        Data::Object(obj) => assert_eq!(data_to_document(&obj), Ok(document)),
+        _ => unreachable!(),
+    }
+}
+
+#[test]
+fn test_malformed_extended_json_is_an_error() {
+    let bad_oid = object(vec![("_id", Data::Object(object(vec![("$oid", Data::String("nope".to_string()))])))]);
+    assert!(data_to_document(&bad_oid).is_err());
+
+    let bad_date = object(vec![("at", Data::Object(object(vec![("$date", Data::Bool(true))])))]);
+    assert!(data_to_document(&bad_date).is_err());
+
+    // Operators and other `$` keys are ordinary documents
+    let filter = object(vec![("price", Data::Object(object(vec![("$gt", Data::Number(10.0))])))]);
+    let expected: Document = doc! { "price": { "$gt": 10.0 } };
+    assert_eq!(data_to_document(&filter), Ok(expected));
+}
+
+#[test]
+fn test_dates_written_from_scripts() {
+    // Scripts can write dates as ISO strings or epoch milliseconds
+    let iso = Data::Object(object(vec![("$date", Data::String("2024-05-01T10:00:00Z".to_string()))]));
+    let millis = Data::Object(object(vec![(
+        "$date",
+        Data::Object(object(vec![("$numberLong", Data::String("1714557600000".to_string()))])),
+    )]));
+    let expected = Bson::DateTime(DateTime::from_millis(1_714_557_600_000));
+    assert_eq!(data_to_bson(&iso), Ok(expected.clone()));
+    assert_eq!(data_to_bson(&millis), Ok(expected));
+}
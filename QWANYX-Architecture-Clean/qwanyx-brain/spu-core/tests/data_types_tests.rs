@@ -0,0 +1,180 @@
+//! Typed Data variant tests
+//!
+//! Wire compatibility of plain JSON, extended JSON forms for integers, dates, bytes
+//! and ids, their plain form in HTTP responses, and how scripts compare and compute
+//! with them.
+
+use chrono::{TimeZone, Utc};
+use mongodb::bson::{oid::ObjectId, Bson};
+use spu_core::{bson_data, runtime::SPURuntime, Data};
+use std::collections::HashMap;
+
+fn typed_values() -> Vec<(Data, serde_json::Value)> {
+    let oid = ObjectId::parse_str("64b7f0c2a1b2c3d4e5f60718").unwrap();
+    vec![
+        (
+            Data::Integer(9_007_199_254_740_993),
+            serde_json::json!({ "$numberLong": "9007199254740993" }),
+        ),
+        (
+            Data::DateTime(Utc.with_ymd_and_hms(2024, 5, 1, 10, 0, 0).unwrap()),
+            serde_json::json!({ "$date": "2024-05-01T10:00:00Z" }),
+        ),
+        (
+            Data::Bytes(b"%PDF-1.7".to_vec()),
+            serde_json::json!({ "$binary": { "base64": "JVBERi0xLjc=", "subType": "00" } }),
+        ),
+        (
+            Data::Id(oid),
+            serde_json::json!({ "$oid": "64b7f0c2a1b2c3d4e5f60718" }),
+        ),
+    ]
+}
+
+#[test]
+fn test_plain_json_is_unchanged() {
+    let json = r#"{"name": "Clio", "price": 12, "tags": ["a", true, null], "nested": {"x": 1.5}}"#;
+    let data: Data = serde_json::from_str(json).unwrap();
+    match &data {
+        Data::Object(obj) => {
+            assert_eq!(obj.get("price"), Some(&Data::Number(12.0)));
+            assert_eq!(obj.get("name"), Some(&Data::String("Clio".to_string())));
+        }
+        other => panic!("Unexpected data: {:?}", other),
+    }
+    assert_eq!(data, Data::from_json(serde_json::from_str(json).unwrap()));
+
+    // Serializing the original variants gives the same JSON as before
+    assert_eq!(serde_json::to_string(&Data::Number(12.0)).unwrap(), "12.0");
+    assert_eq!(serde_json::to_string(&Data::Null).unwrap(), "null");
+    assert_eq!(
+        serde_json::to_value(&data).unwrap(),
+        serde_json::json!({ "name": "Clio", "price": 12.0, "tags": ["a", true, null], "nested": { "x": 1.5 } })
+    );
+}
+
+#[test]
+fn test_typed_values_round_trip_through_serde_and_json() {
+    for (data, json) in typed_values() {
+        assert_eq!(data.to_json(), json);
+        assert_eq!(Data::from_json(json.clone()), data);
+        assert_eq!(serde_json::to_value(&data).unwrap(), json);
+        assert_eq!(serde_json::from_value::<Data>(json).unwrap(), data);
+
+        // Inside containers too, as in workflow records
+        let wrapped = Data::Array(vec![Data::Object(HashMap::from([("value".to_string(), data.clone())]))]);
+        let text = serde_json::to_string(&wrapped).unwrap();
+        assert_eq!(serde_json::from_str::<Data>(&text).unwrap(), wrapped);
+    }
+
+    let epoch_millis = serde_json::json!({ "$date": { "$numberLong": "1714557600000" } });
+    assert_eq!(
+        Data::from_json(epoch_millis),
+        Data::DateTime(Utc.with_ymd_and_hms(2024, 5, 1, 10, 0, 0).unwrap())
+    );
+}
+
+#[test]
+fn test_http_responses_use_plain_json() {
+    let plain = [
+        serde_json::json!(9_007_199_254_740_993_i64),
+        serde_json::json!("2024-05-01T10:00:00Z"),
+        serde_json::json!("JVBERi0xLjc="),
+        serde_json::json!("64b7f0c2a1b2c3d4e5f60718"),
+    ];
+    for ((data, _), json) in typed_values().into_iter().zip(plain) {
+        assert_eq!(data.to_plain_json(), json);
+
+        let wrapped = Data::Array(vec![Data::Object(HashMap::from([("value".to_string(), data)]))]);
+        assert_eq!(wrapped.to_plain_json(), serde_json::json!([{ "value": json }]));
+    }
+}
+
+#[test]
+fn test_lookalike_objects_stay_objects() {
+    for json in [
+        serde_json::json!({ "$oid": "nope" }),
+        serde_json::json!({ "$numberLong": "12", "extra": 1 }),
+        serde_json::json!({ "$numberLong": 12 }),
+        serde_json::json!({ "$date": "yesterday" }),
+        serde_json::json!({ "$binary": { "base64": "AAAA", "subType": "80" } }),
+    ] {
+        assert!(matches!(Data::from_json(json), Data::Object(_)));
+    }
+}
+
+#[test]
+fn test_typed_values_map_to_bson() {
+    for (data, _) in typed_values() {
+        let bson = bson_data::data_to_bson(&data).unwrap();
+        assert!(matches!(bson, Bson::Int64(_) | Bson::DateTime(_) | Bson::Binary(_) | Bson::ObjectId(_)));
+        assert_eq!(bson_data::bson_to_data(&bson), data);
+    }
+}
+
+async fn eval(script: &str, inputs: Vec<(&str, Data)>) -> Data {
+    let runtime = SPURuntime::new();
+    let inputs = inputs.into_iter().map(|(k, v)| (k.to_string(), v)).collect();
+    runtime.execute_with_inputs(script, inputs).await.unwrap()
+}
+
+#[tokio::test]
+async fn test_integer_arithmetic_keeps_precision() {
+    let big = Data::Integer(9_007_199_254_740_993);
+    let script = "EXPR \"$counter + 1\" result";
+    assert_eq!(eval(script, vec![("counter", big.clone())]).await, Data::Integer(9_007_199_254_740_994));
+
+    let script = "EXPR \"$counter * 2\" result";
+    assert_eq!(eval(script, vec![("counter", Data::Integer(21))]).await, Data::Integer(42));
+
+    // Fractions and division give floats
+    let script = "EXPR \"$counter + 0.5\" result";
+    assert_eq!(eval(script, vec![("counter", Data::Integer(1))]).await, Data::Number(1.5));
+    let script = "EXPR \"$counter / 2\" result";
+    assert_eq!(eval(script, vec![("counter", Data::Integer(5))]).await, Data::Number(2.5));
+
+    let runtime = SPURuntime::new();
+    let inputs = HashMap::from([("counter".to_string(), Data::Integer(i64::MAX))]);
+    assert!(runtime.execute_with_inputs("EXPR \"$counter + 1\" result", inputs).await.is_err());
+}
+
+#[tokio::test]
+async fn test_comparisons_across_types() {
+    let checks = vec![
+        ("EXPR \"$value == 3\" result", Data::Integer(3), true),
+        ("EXPR \"$value > 2.5\" result", Data::Integer(3), true),
+        ("EXPR \"$value == '64b7f0c2a1b2c3d4e5f60718'\" result",
+            Data::Id(ObjectId::parse_str("64b7f0c2a1b2c3d4e5f60718").unwrap()), true),
+        ("EXPR \"$value > '2024-01-01T00:00:00Z'\" result",
+            Data::DateTime(Utc.with_ymd_and_hms(2024, 5, 1, 10, 0, 0).unwrap()), true),
+        ("EXPR \"$value == '2024-05-01T12:00:00+02:00'\" result",
+            Data::DateTime(Utc.with_ymd_and_hms(2024, 5, 1, 10, 0, 0).unwrap()), true),
+    ];
+    for (script, value, expected) in checks {
+        assert_eq!(eval(script, vec![("value", value)]).await, Data::Bool(expected), "{}", script);
+    }
+
+    // Integers beyond 2^53 still compare exactly with each other
+    let result = eval("EXPR \"$value < $limit\" result", vec![
+        ("value", Data::Integer(9_007_199_254_740_992)),
+        ("limit", Data::Integer(9_007_199_254_740_993)),
+    ]).await;
+    assert_eq!(result, Data::Bool(true));
+}
+
+#[tokio::test]
+async fn test_typed_values_in_templates_and_len() {
+    let script = "SET message {\"text\": \"Order $id placed at $at, total $total\"}\nGET message.text result";
+    let result = eval(script, vec![
+        ("id", Data::Id(ObjectId::parse_str("64b7f0c2a1b2c3d4e5f60718").unwrap())),
+        ("at", Data::DateTime(Utc.with_ymd_and_hms(2024, 5, 1, 10, 0, 0).unwrap())),
+        ("total", Data::Integer(1250)),
+    ]).await;
+    assert_eq!(
+        result,
+        Data::String("Order 64b7f0c2a1b2c3d4e5f60718 placed at 2024-05-01T10:00:00+00:00, total 1250".to_string())
+    );
+
+    let result = eval("LEN $file result", vec![("file", Data::Bytes(vec![0; 12]))]).await;
+    assert_eq!(result, Data::Number(12.0));
+}
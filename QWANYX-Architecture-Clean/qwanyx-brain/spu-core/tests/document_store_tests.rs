@@ -0,0 +1,720 @@
+//! Document store tests
+//!
+//! The same behaviour from the in-memory and embedded stores, and the database
+//! coprocessor running on them without a MongoDB server.
+
+use mongodb::bson::{doc, oid::ObjectId, Bson, Document};
+use spu_core::{
+    coprocessors::DatabaseCoprocessor,
+    runtime::SPURuntime,
+    store::{idempotency, query, DocumentStore, EmbeddedStore, FindOneAndUpdateOptions, FindOptions, Index, MemoryStore, Namespace, UpdateOptions, UpdateOutcome},
+    Coprocessor, Data,
+};
+use std::sync::Arc;
+
+const CARS: Namespace<'static> = Namespace { workspace: "autodin", collection: "cars", transaction: None };
+
+fn temp_path() -> String {
+    std::env::temp_dir()
+        .join(format!("spu-store-{}", uuid::Uuid::new_v4()))
+        .to_string_lossy()
+        .to_string()
+}
+
+async fn seed(store: &dyn DocumentStore) {
+    for document in [
+        doc! { "brand": "Renault", "model": "Clio", "price": 12_500, "year": 2019, "tags": ["city", "used"] },
+        doc! { "brand": "Renault", "model": "Megane", "price": 18_000.5, "year": 2021, "owner": { "city": "Liège" } },
+        doc! { "brand": "Peugeot", "model": "208", "price": 15_000_i64, "year": 2020, "tags": ["city"] },
+        doc! { "brand": "Citroën", "model": "C3", "price": Bson::Null, "year": 2018 },
+    ] {
+        store.insert(CARS, document).await.unwrap();
+    }
+}
+
+async fn models(store: &dyn DocumentStore, filter: Document, options: FindOptions) -> Vec<String> {
+    store.find(CARS, filter, options).await.unwrap()
+        .iter()
+        .map(|d| d.get_str("model").unwrap_or("?").to_string())
+        .collect()
+}
+
+/// Behaviour every store shares
+async fn exercise(store: &dyn DocumentStore) {
+    seed(store).await;
+    let all = FindOptions::default;
+    let sorted = |sort: Document| FindOptions { sort: Some(sort), ..FindOptions::default() };
+
+    // Filters
+    assert_eq!(models(store, doc! { "brand": "Renault" }, sorted(doc! { "model": 1 })).await, vec!["Clio", "Megane"]);
+    assert_eq!(models(store, doc! { "price": { "$gte": 15_000, "$lt": 18_000.5 } }, all()).await, vec!["208"]);
+    assert_eq!(models(store, doc! { "year": { "$in": [2018, 2020] } }, sorted(doc! { "year": 1 })).await, vec!["C3", "208"]);
+    assert_eq!(models(store, doc! { "tags": "city" }, sorted(doc! { "model": 1 })).await, vec!["208", "Clio"]);
+    assert_eq!(models(store, doc! { "tags": { "$size": 2 } }, all()).await, vec!["Clio"]);
+    assert_eq!(models(store, doc! { "owner.city": "Liège" }, all()).await, vec!["Megane"]);
+    assert_eq!(models(store, doc! { "owner": { "$exists": false }, "price": null }, all()).await, vec!["C3"]);
+    assert_eq!(models(store, doc! { "model": { "$regex": "^c", "$options": "i" } }, sorted(doc! { "model": 1 })).await, vec!["C3", "Clio"]);
+    assert_eq!(
+        models(store, doc! { "$or": [{ "year": 2018 }, { "brand": "Peugeot" }] }, sorted(doc! { "model": -1 })).await,
+        vec!["C3", "208"]
+    );
+    assert_eq!(models(store, doc! { "brand": { "$ne": "Renault" }, "year": { "$not": { "$gt": 2019 } } }, all()).await, vec!["C3"]);
+    assert!(store.find(CARS, doc! { "$where": "true" }, all()).await.is_err());
+
+    // Sort across number types, null first, then paging and projection
+    let options = FindOptions {
+        sort: Some(doc! { "price": -1 }),
+        skip: Some(1),
+        limit: Some(2),
+        projection: Some(doc! { "model": 1, "_id": 0 }),
+    };
+    let page = store.find(CARS, Document::new(), options).await.unwrap();
+    assert_eq!(page, vec![doc! { "model": "208" }, doc! { "model": "Clio" }]);
+    assert_eq!(models(store, Document::new(), sorted(doc! { "price": 1 })).await[0], "C3");
+
+    // Count, update, delete
+    assert_eq!(store.count(CARS, doc! { "brand": "Renault" }).await.unwrap(), 2);
+    assert_eq!(store.count(Namespace::new("other", "cars"), Document::new()).await.unwrap(), 0);
+
+    let outcome = store.update(CARS, doc! { "model": "Clio" }, doc! { "$set": { "price": 11_000, "owner.city": "Namur" } }, UpdateOptions::default()).await.unwrap();
+    assert_eq!(outcome, UpdateOutcome { matched: 1, modified: 1, ..Default::default() });
+    let clio = store.find(CARS, doc! { "model": "Clio" }, all()).await.unwrap().remove(0);
+    assert_eq!(clio.get_i32("price").unwrap(), 11_000);
+    assert_eq!(clio.get_document("owner").unwrap(), &doc! { "city": "Namur" });
+
+    let unchanged = store.update(CARS, doc! { "model": "Clio" }, doc! { "$set": { "price": 11_000 } }, UpdateOptions::default()).await.unwrap();
+    assert_eq!(unchanged, UpdateOutcome { matched: 1, modified: 0, ..Default::default() });
+    let missing = store.update(CARS, doc! { "model": "Twingo" }, doc! { "$set": { "price": 1 } }, UpdateOptions::default()).await.unwrap();
+    assert_eq!(missing, UpdateOutcome::default());
+    assert!(store.update(CARS, doc! { "model": "Clio" }, doc! { "price": 1 }, UpdateOptions::default()).await.is_err());
+
+    assert_eq!(store.delete(CARS, doc! { "brand": "Renault" }).await.unwrap(), 2);
+    assert_eq!(store.count(CARS, Document::new()).await.unwrap(), 2);
+
+    // Ids are generated, kept when given, and unique
+    let oid = ObjectId::new();
+    assert_eq!(store.insert(CARS, doc! { "_id": oid, "model": "Twingo" }).await.unwrap(), Bson::ObjectId(oid));
+    assert!(store.insert(CARS, doc! { "_id": oid, "model": "Twingo" }).await.is_err());
+    let generated = store.insert(CARS, doc! { "model": "Zoe" }).await.unwrap();
+    assert!(matches!(generated, Bson::ObjectId(_)));
+    assert_eq!(models(store, doc! { "_id": generated }, all()).await, vec!["Zoe"]);
+
+    assert!(store.health().await.is_ok());
+}
+
+/// Update operators, upserts, bulk writes and find_one_and_update
+async fn exercise_writes(store: &dyn DocumentStore) {
+    let counters = Namespace::new("autodin", "counters");
+    let many = UpdateOptions { many: true, ..UpdateOptions::default() };
+    let upsert = UpdateOptions { upsert: true, ..UpdateOptions::default() };
+
+    // insert_many is all or nothing on a duplicate _id
+    let ids = store.insert_many(CARS, vec![
+        doc! { "_id": 1, "model": "Clio", "price": 10, "tags": ["city"] },
+        doc! { "_id": 2, "model": "Megane", "price": 20, "tags": [] },
+    ]).await.unwrap();
+    assert_eq!(ids, vec![Bson::Int32(1), Bson::Int32(2)]);
+    assert!(store.insert_many(CARS, vec![doc! { "_id": 3 }, doc! { "_id": 1 }]).await.is_err());
+    assert_eq!(store.count(CARS, Document::new()).await.unwrap(), 2);
+
+    // Operators
+    let update = doc! {
+        "$inc": { "price": 5, "views": 1 },
+        "$push": { "tags": { "$each": ["used", "diesel"] } },
+        "$unset": { "model": "" },
+        "$currentDate": { "updatedAt": true },
+    };
+    store.update(CARS, doc! { "_id": 1 }, update, UpdateOptions::default()).await.unwrap();
+    let clio = store.find(CARS, doc! { "_id": 1 }, FindOptions::default()).await.unwrap().remove(0);
+    assert_eq!(clio.get_i32("price").unwrap(), 15);
+    assert_eq!(clio.get_i32("views").unwrap(), 1);
+    assert_eq!(clio.get_array("tags").unwrap(), &vec![Bson::from("city"), Bson::from("used"), Bson::from("diesel")]);
+    assert!(!clio.contains_key("model"));
+    assert!(clio.get_datetime("updatedAt").is_ok());
+
+    store.update(CARS, doc! { "_id": 1 }, doc! { "$pull": { "tags": "used" }, "$addToSet": { "tags": "city" }, "$max": { "price": 12 } }, UpdateOptions::default()).await.unwrap();
+    let clio = store.find(CARS, doc! { "_id": 1 }, FindOptions::default()).await.unwrap().remove(0);
+    assert_eq!(clio.get_array("tags").unwrap(), &vec![Bson::from("city"), Bson::from("diesel")]);
+    assert_eq!(clio.get_i32("price").unwrap(), 15);
+
+    assert!(store.update(CARS, doc! { "_id": 1 }, doc! { "$set": { "_id": 5 } }, UpdateOptions::default()).await.is_err());
+    assert!(store.update(CARS, doc! { "_id": 1 }, doc! { "$inc": { "tags": 1 } }, UpdateOptions::default()).await.is_err());
+    assert!(store.update(CARS, doc! { "_id": 9 }, doc! { "$rename": 1 }, UpdateOptions::default()).await.is_err());
+
+    // update_many
+    let outcome = store.update(CARS, Document::new(), doc! { "$mul": { "price": 2 } }, many).await.unwrap();
+    assert_eq!((outcome.matched, outcome.modified), (2, 2));
+    assert_eq!(models_and_prices(store).await, vec![30, 40]);
+
+    // Upserts build on the filter's equalities
+    let outcome = store.update(counters, doc! { "name": "invoice", "year": 2024 }, doc! { "$inc": { "seq": 1 }, "$setOnInsert": { "prefix": "INV" } }, upsert).await.unwrap();
+    assert_eq!(outcome.matched, 0);
+    let id = outcome.upserted_id.expect("upserted id");
+    let counter = store.find(counters, doc! { "_id": id }, FindOptions::default()).await.unwrap().remove(0);
+    assert_eq!((counter.get_str("name").unwrap(), counter.get_i32("year").unwrap()), ("invoice", 2024));
+    assert_eq!((counter.get_i32("seq").unwrap(), counter.get_str("prefix").unwrap()), (1, "INV"));
+
+    let outcome = store.update(counters, doc! { "name": "invoice" }, doc! { "$inc": { "seq": 1 }, "$setOnInsert": { "prefix": "X" } }, upsert).await.unwrap();
+    assert_eq!((outcome.matched, outcome.upserted_id), (1, None));
+
+    // Counters: every caller gets its own number
+    let next = FindOneAndUpdateOptions { upsert: true, return_updated: true, ..Default::default() };
+    let mut numbers = Vec::new();
+    for _ in 0..3 {
+        let counter = store.find_one_and_update(counters, doc! { "name": "quote" }, doc! { "$inc": { "seq": 1 } }, next.clone()).await.unwrap();
+        numbers.push(counter.unwrap().get_i32("seq").unwrap());
+    }
+    assert_eq!(numbers, vec![1, 2, 3]);
+
+    // Locks: take the oldest free one and get it as it was
+    store.insert_many(Namespace::new("autodin", "locks"), vec![
+        doc! { "_id": "b", "at": 2, "owner": Bson::Null },
+        doc! { "_id": "a", "at": 1, "owner": Bson::Null },
+    ]).await.unwrap();
+    let take = FindOneAndUpdateOptions {
+        sort: Some(doc! { "at": 1 }),
+        projection: Some(doc! { "owner": 1 }),
+        ..Default::default()
+    };
+    let taken = store.find_one_and_update(Namespace::new("autodin", "locks"), doc! { "owner": Bson::Null }, doc! { "$set": { "owner": "worker-1" } }, take.clone()).await.unwrap();
+    assert_eq!(taken, Some(doc! { "_id": "a", "owner": Bson::Null }));
+    let taken = store.find_one_and_update(Namespace::new("autodin", "locks"), doc! { "owner": Bson::Null }, doc! { "$set": { "owner": "worker-2" } }, take.clone()).await.unwrap();
+    assert_eq!(taken.unwrap().get_str("_id").unwrap(), "b");
+    let none = store.find_one_and_update(Namespace::new("autodin", "locks"), doc! { "owner": Bson::Null }, doc! { "$set": { "owner": "worker-3" } }, take).await.unwrap();
+    assert_eq!(none, None);
+}
+
+async fn models_and_prices(store: &dyn DocumentStore) -> Vec<i32> {
+    let sorted = FindOptions { sort: Some(doc! { "_id": 1 }), ..FindOptions::default() };
+    store.find(CARS, Document::new(), sorted).await.unwrap()
+        .iter()
+        .map(|d| d.get_i32("price").unwrap())
+        .collect()
+}
+
+/// Isolation, commit, abort and conflicts
+async fn exercise_transactions(store: &dyn DocumentStore) {
+    let orders = Namespace::new("autodin", "orders");
+    store.insert(CARS, doc! { "_id": 1, "model": "Clio", "stock": 2 }).await.unwrap();
+
+    // Writes are only visible inside the transaction until commit
+    let tx = store.begin().await.unwrap();
+    store.update(CARS.in_transaction(Some(&tx)), doc! { "_id": 1 }, doc! { "$inc": { "stock": -1 } }, UpdateOptions::default()).await.unwrap();
+    store.insert(orders.in_transaction(Some(&tx)), doc! { "car": 1 }).await.unwrap();
+    assert_eq!(store.count(orders.in_transaction(Some(&tx)), Document::new()).await.unwrap(), 1);
+    assert_eq!(store.count(orders, Document::new()).await.unwrap(), 0);
+    assert_eq!(stock(store, CARS).await, 2);
+    store.commit(&tx).await.unwrap();
+    assert_eq!(stock(store, CARS).await, 1);
+    assert_eq!(store.count(orders, Document::new()).await.unwrap(), 1);
+    assert!(store.commit(&tx).await.is_err());
+
+    // Aborted transactions leave nothing behind
+    let tx = store.begin().await.unwrap();
+    store.update(CARS.in_transaction(Some(&tx)), doc! { "_id": 1 }, doc! { "$inc": { "stock": -1 } }, UpdateOptions::default()).await.unwrap();
+    store.delete(orders.in_transaction(Some(&tx)), Document::new()).await.unwrap();
+    store.abort(&tx).await.unwrap();
+    assert_eq!(stock(store, CARS).await, 1);
+    assert_eq!(store.count(orders, Document::new()).await.unwrap(), 1);
+
+    // A concurrent write makes the commit fail as a whole
+    let tx = store.begin().await.unwrap();
+    store.insert(orders.in_transaction(Some(&tx)), doc! { "car": 1 }).await.unwrap();
+    store.update(CARS.in_transaction(Some(&tx)), doc! { "_id": 1 }, doc! { "$inc": { "stock": -1 } }, UpdateOptions::default()).await.unwrap();
+    store.update(CARS, doc! { "_id": 1 }, doc! { "$set": { "stock": 10 } }, UpdateOptions::default()).await.unwrap();
+    let conflict = store.commit(&tx).await.unwrap_err();
+    assert!(conflict.contains("conflict"), "{}", conflict);
+    assert_eq!(stock(store, CARS).await, 10);
+    assert_eq!(store.count(orders, Document::new()).await.unwrap(), 1);
+
+    assert!(store.insert(orders.in_transaction(Some("unknown")), doc! {}).await.is_err());
+}
+
+/// Report-style pipelines over requests and parts
+async fn exercise_aggregation(store: &dyn DocumentStore) {
+    let requests = Namespace::new("autodin", "requests");
+    let parts = Namespace::new("autodin", "parts");
+    store.insert_many(requests, vec![
+        doc! { "_id": 1, "brand": "Renault", "urgency": "high", "status": "open", "part": "P1", "price": 120 },
+        doc! { "_id": 2, "brand": "Renault", "urgency": "low", "status": "open", "part": "P2", "price": 80.5 },
+        doc! { "_id": 3, "brand": "Peugeot", "urgency": "high", "status": "open", "part": "P1", "price": 100 },
+        doc! { "_id": 4, "brand": "Peugeot", "urgency": "high", "status": "closed", "part": "P3" },
+        doc! { "_id": 5, "brand": "Citroën", "urgency": "low", "status": "open", "tags": ["rear", "left"] },
+    ]).await.unwrap();
+    store.insert_many(parts, vec![
+        doc! { "_id": "a", "ref": "P1", "name": "Brake pad" },
+        doc! { "_id": "b", "ref": "P1", "name": "Brake pad (generic)" },
+        doc! { "_id": "c", "ref": "P2", "name": "Mirror" },
+    ]).await.unwrap();
+
+    // Requests per brand, most first
+    let per_brand = store.aggregate(requests, vec![
+        doc! { "$group": { "_id": "$brand", "requests": { "$sum": 1 }, "total": { "$sum": "$price" }, "average": { "$avg": "$price" } } },
+        doc! { "$sort": { "requests": -1, "_id": 1 } },
+    ], 100).await.unwrap();
+    assert_eq!(per_brand, vec![
+        doc! { "_id": "Peugeot", "requests": 2, "total": 100, "average": 100.0 },
+        doc! { "_id": "Renault", "requests": 2, "total": 200.5, "average": 100.25 },
+        doc! { "_id": "Citroën", "requests": 1, "total": 0, "average": Bson::Null },
+    ]);
+
+    // Open requests by urgency
+    let by_urgency = store.aggregate(requests, vec![
+        doc! { "$match": { "status": "open" } },
+        doc! { "$sortByCount": "$urgency" },
+    ], 100).await.unwrap();
+    assert_eq!(by_urgency, vec![doc! { "_id": "high", "count": 2 }, doc! { "_id": "low", "count": 2 }]);
+
+    // Matching parts, with computed fields
+    let matching = store.aggregate(requests, vec![
+        doc! { "$match": { "part": { "$exists": true } } },
+        doc! { "$lookup": { "from": "parts", "localField": "part", "foreignField": "ref", "as": "parts" } },
+        doc! { "$project": { "brand": 1, "parts": { "$size": "$parts" }, "label": { "$concat": [{ "$toUpper": "$brand" }, "-", "$part"] } } },
+        doc! { "$sort": { "_id": 1 } },
+        doc! { "$limit": 2 },
+    ], 100).await.unwrap();
+    assert_eq!(matching, vec![
+        doc! { "_id": 1, "brand": "Renault", "parts": 2, "label": "RENAULT-P1" },
+        doc! { "_id": 2, "brand": "Renault", "parts": 1, "label": "RENAULT-P2" },
+    ]);
+
+    // $unwind, $count and the result limit
+    let tags = store.aggregate(requests, vec![doc! { "$unwind": "$tags" }, doc! { "$count": "tags" }], 100).await.unwrap();
+    assert_eq!(tags, vec![doc! { "tags": 2 }]);
+    assert_eq!(store.aggregate(requests, vec![], 3).await.unwrap().len(), 3);
+
+    // Inside a transaction the pipeline sees its writes
+    let transaction = store.begin().await.unwrap();
+    let pending = requests.in_transaction(Some(&transaction));
+    store.insert(pending, doc! { "_id": 6, "brand": "Renault", "urgency": "high", "status": "open" }).await.unwrap();
+    let count = doc! { "$match": { "brand": "Renault" } };
+    assert_eq!(store.aggregate(pending, vec![count.clone(), doc! { "$count": "n" }], 10).await.unwrap(), vec![doc! { "n": 3 }]);
+    assert_eq!(store.aggregate(requests, vec![count, doc! { "$count": "n" }], 10).await.unwrap(), vec![doc! { "n": 2 }]);
+    store.abort(&transaction).await.unwrap();
+
+    // Read-only, and unknown stages are reported
+    let out = store.aggregate(requests, vec![doc! { "$out": "copy" }], 10).await.unwrap_err();
+    assert!(out.contains("$out"), "{}", out);
+    let unknown = store.aggregate(requests, vec![doc! { "$bucket": {} }], 10).await.unwrap_err();
+    assert!(unknown.contains("$bucket"), "{}", unknown);
+}
+
+async fn exercise_indexes(store: &dyn DocumentStore) {
+    let users = Namespace::new("autodin", "users");
+    store.insert_many(users, vec![doc! { "uuid": "a", "email": "a@x.be" }, doc! { "uuid": "b", "email": "b@x.be" }]).await.unwrap();
+
+    let uuid = Index::new(doc! { "uuid": 1 }).unique(true);
+    assert_eq!(uuid.name, "uuid_1");
+    store.create_index(users, uuid.clone()).await.unwrap();
+    // Again with the same keys and options is a no-op, differently is not
+    store.create_index(users, uuid.clone()).await.unwrap();
+    assert!(store.create_index(users, uuid.clone().unique(false)).await.is_err());
+    store.create_index(users, Index::new(doc! { "email": 1, "created_at": -1 })).await.unwrap();
+
+    let names: Vec<String> = store.list_indexes(users).await.unwrap().into_iter().map(|index| index.name).collect();
+    assert_eq!(names, ["_id_", "uuid_1", "email_1_created_at_-1"]);
+
+    // Unique keys hold for inserts, updates and transactions, and failed writes change nothing
+    let duplicate = store.insert(users, doc! { "uuid": "a" }).await.unwrap_err();
+    assert!(duplicate.contains("uuid_1"), "{}", duplicate);
+    assert!(store.insert_many(users, vec![doc! { "uuid": "c" }, doc! { "uuid": "c" }]).await.is_err());
+    assert!(store.update(users, doc! { "uuid": "b" }, doc! { "$set": { "uuid": "a" } }, UpdateOptions::default()).await.is_err());
+    let transaction = store.begin().await.unwrap();
+    assert!(store.insert(users.in_transaction(Some(&transaction)), doc! { "uuid": "b" }).await.is_err());
+    store.abort(&transaction).await.unwrap();
+    assert_eq!(store.count(users, Document::new()).await.unwrap(), 2);
+    // Numbers are the same key whatever their type; missing keys are null
+    store.insert(users, doc! { "uuid": 7 }).await.unwrap();
+    assert!(store.insert(users, doc! { "uuid": 7.0 }).await.is_err());
+    store.insert(users, doc! { "email": "none@x.be" }).await.unwrap();
+    assert!(store.insert(users, doc! { "email": "other@x.be" }).await.is_err());
+
+    // A unique index cannot be created over duplicates, nor can _id_ be dropped
+    let over_duplicates = store.create_index(users, Index::new(doc! { "brand": 1 }).unique(true)).await.unwrap_err();
+    assert!(over_duplicates.contains("Duplicate key"), "{}", over_duplicates);
+    assert!(store.drop_index(users, "_id_").await.is_err());
+    assert!(store.drop_index(users, "missing").await.is_err());
+    store.drop_index(users, "uuid_1").await.unwrap();
+    store.insert(users, doc! { "uuid": "a" }).await.unwrap();
+
+    // Renaming moves documents and indexes
+    store.rename_collection(users, "members").await.unwrap();
+    let members = Namespace::new("autodin", "members");
+    assert_eq!(store.count(users, Document::new()).await.unwrap(), 0);
+    assert_eq!(store.count(members, Document::new()).await.unwrap(), 5);
+    assert_eq!(store.list_indexes(members).await.unwrap().len(), 2);
+    assert_eq!(store.list_indexes(users).await.unwrap().len(), 1);
+    assert!(store.rename_collection(users, "members").await.unwrap_err().contains("does not exist"));
+    store.insert(CARS, doc! { "model": "Clio" }).await.unwrap();
+    assert!(store.rename_collection(CARS, "members").await.unwrap_err().contains("already exists"));
+}
+
+async fn stock(store: &dyn DocumentStore, ns: Namespace<'_>) -> i32 {
+    store.find(ns, doc! { "_id": 1 }, FindOptions::default()).await.unwrap()[0].get_i32("stock").unwrap()
+}
+
+#[tokio::test]
+async fn test_memory_store() {
+    exercise(&MemoryStore::new()).await;
+    exercise_writes(&MemoryStore::new()).await;
+    exercise_transactions(&MemoryStore::new()).await;
+    exercise_aggregation(&MemoryStore::new()).await;
+    exercise_indexes(&MemoryStore::new()).await;
+}
+
+#[tokio::test]
+async fn test_embedded_store() {
+    for run in 0..5 {
+        let path = temp_path();
+        let store = EmbeddedStore::open(&path).unwrap();
+        match run {
+            0 => exercise(&store).await,
+            1 => exercise_writes(&store).await,
+            2 => exercise_transactions(&store).await,
+            3 => exercise_aggregation(&store).await,
+            _ => exercise_indexes(&store).await,
+        }
+        drop(store);
+        std::fs::remove_dir_all(&path).ok();
+    }
+}
+
+#[tokio::test]
+async fn test_embedded_store_persists() {
+    let path = temp_path();
+    {
+        let store = EmbeddedStore::open(&path).unwrap();
+        store.insert(Namespace::new("autodin", "requests"), doc! { "urgency": "high", "at": mongodb::bson::DateTime::from_millis(1_714_557_600_000) }).await.unwrap();
+    }
+
+    {
+        let store = EmbeddedStore::open(&path).unwrap();
+        let found = store.find(Namespace::new("autodin", "requests"), doc! { "urgency": "high" }, FindOptions::default()).await.unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].get_datetime("at").unwrap().timestamp_millis(), 1_714_557_600_000);
+
+        // Committed transactions reach the disk, aborted ones don't
+        let kept = store.begin().await.unwrap();
+        store.update(Namespace::new("autodin", "requests").in_transaction(Some(&kept)), Document::new(), doc! { "$set": { "urgency": "low" } }, UpdateOptions::default()).await.unwrap();
+        store.insert(Namespace::new("autodin", "notes").in_transaction(Some(&kept)), doc! { "text": "called back" }).await.unwrap();
+        store.commit(&kept).await.unwrap();
+        let dropped = store.begin().await.unwrap();
+        store.delete(Namespace::new("autodin", "notes").in_transaction(Some(&dropped)), Document::new()).await.unwrap();
+        store.abort(&dropped).await.unwrap();
+    }
+
+    {
+        let store = EmbeddedStore::open(&path).unwrap();
+        assert_eq!(store.count(Namespace::new("autodin", "requests"), doc! { "urgency": "low" }).await.unwrap(), 1);
+        assert_eq!(store.count(Namespace::new("autodin", "notes"), Document::new()).await.unwrap(), 1);
+        store.create_index(Namespace::new("autodin", "notes"), Index::new(doc! { "text": 1 }).unique(true)).await.unwrap();
+    }
+
+    // Indexes too
+    let store = EmbeddedStore::open(&path).unwrap();
+    assert_eq!(store.list_indexes(Namespace::new("autodin", "notes")).await.unwrap()[1].name, "text_1");
+    assert!(store.insert(Namespace::new("autodin", "notes"), doc! { "text": "called back" }).await.is_err());
+
+    // Reopening waits for a closed store to unlock its directory, not for an open one
+    assert!(EmbeddedStore::open(&path).err().unwrap().contains("could not acquire lock"));
+    std::fs::remove_dir_all(&path).ok();
+}
+
+#[test]
+fn test_values_compare_like_mongodb() {
+    assert!(query::equal(&Bson::Int32(3), &Bson::Double(3.0)));
+    assert!(query::equal(&Bson::Int64(3), &Bson::Int32(3)));
+    assert!(!query::equal(&Bson::String("3".to_string()), &Bson::Int32(3)));
+    assert!(query::compare(&Bson::Null, &Bson::Int32(-5)).is_lt());
+    assert!(query::compare(&Bson::Int64(i64::MAX), &Bson::Int64(i64::MAX - 1)).is_gt());
+    assert!(query::compare(&Bson::Double(f64::NAN), &Bson::Double(f64::NEG_INFINITY)).is_lt());
+    assert!(query::compare(&Bson::String("z".to_string()), &Bson::Boolean(false)).is_lt());
+
+    // Range operators don't cross types
+    let document = doc! { "price": "12" };
+    assert!(!query::matches(&document, &doc! { "price": { "$gt": 5 } }).unwrap());
+}
+
+fn object(json: serde_json::Value) -> Data {
+    Data::from_json(json)
+}
+
+fn field<'a>(data: &'a Data, name: &str) -> &'a Data {
+    match data {
+        Data::Object(obj) => obj.get(name).unwrap_or(&Data::Null),
+        other => panic!("Expected object, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn test_database_coprocessor_without_mongodb() {
+    let db = DatabaseCoprocessor::with_store(MemoryStore::new());
+    assert!(matches!(db.health().await, spu_core::Health::Healthy));
+
+    for (model, price) in [("Clio", 12_500), ("Megane", 18_000), ("208", 15_000)] {
+        let stored = db.invoke("store", object(serde_json::json!({
+            "collection": "cars",
+            "workspace": "autodin",
+            "data": { "model": model, "price": price }
+        }))).await.unwrap();
+        assert_eq!(field(&stored, "success"), &Data::Bool(true));
+    }
+
+    // Paged retrieve, following the cursor
+    let args = |cursor: Data| object(serde_json::json!({
+        "collection": "cars",
+        "workspace": "autodin",
+        "filter": { "price": { "$gt": 12_000 } },
+        "sort": "-price",
+        "limit": 2,
+        "projection": { "model": 1 },
+        "cursor": cursor.to_json()
+    }));
+    let first = db.invoke("retrieve", args(Data::Null)).await.unwrap();
+    assert_eq!(field(&first, "total"), &Data::Number(3.0));
+    assert_eq!(field(&first, "has_more"), &Data::Bool(true));
+    let second = db.invoke("retrieve", args(field(&first, "next_cursor").clone())).await.unwrap();
+    assert_eq!(field(&second, "has_more"), &Data::Bool(false));
+
+    let models: Vec<Data> = [first, second].iter()
+        .flat_map(|page| match field(page, "data") {
+            Data::Array(docs) => docs.iter().map(|d| field(d, "model").clone()).collect::<Vec<_>>(),
+            other => panic!("Expected array, got {:?}", other),
+        })
+        .collect();
+    assert_eq!(models, vec![
+        Data::String("Megane".to_string()),
+        Data::String("208".to_string()),
+        Data::String("Clio".to_string()),
+    ]);
+
+    let updated = db.invoke("update", object(serde_json::json!({
+        "collection": "cars",
+        "filter": { "model": "Clio" },
+        "update": { "price": 11_000 }
+    }))).await.unwrap();
+    assert_eq!(field(&updated, "modified"), &Data::Number(1.0));
+
+    let deleted = db.invoke("delete", object(serde_json::json!({
+        "collection": "cars",
+        "filter": { "price": { "$lt": 16_000 } }
+    }))).await.unwrap();
+    assert_eq!(field(&deleted, "deleted_count"), &Data::Number(2.0));
+
+    let count = db.invoke("count", object(serde_json::json!({ "collection": "cars" }))).await.unwrap();
+    assert_eq!(field(&count, "count"), &Data::Number(1.0));
+}
+
+#[tokio::test]
+async fn test_scripts_use_the_embedded_store() {
+    let path = temp_path();
+    let runtime = SPURuntime::new();
+    let store = Arc::new(EmbeddedStore::open(&path).unwrap());
+    runtime.register_class("database".to_string(), Arc::new(DatabaseCoprocessor::with_store(store))).await;
+
+    let script = r#"
+INSTANTIATE database db
+CALL db store {"collection": "requests", "workspace": "autodin", "data": {"urgency": "high"}} stored
+CALL db retrieve {"collection": "requests", "workspace": "autodin", "filter": {"_id": "$stored.id"}} found
+GET found.count result
+"#;
+    assert_eq!(runtime.execute(script).await.unwrap(), Data::Number(1.0));
+    std::fs::remove_dir_all(&path).ok();
+}
+
+#[tokio::test]
+async fn test_database_coprocessor_bulk_and_atomic_methods() {
+    let runtime = SPURuntime::new();
+    let db = DatabaseCoprocessor::with_store(MemoryStore::new()).with_events(runtime.events().clone());
+    let mut events = runtime.events().subscribe();
+
+    let inserted = db.invoke("insert_many", object(serde_json::json!({
+        "collection": "cars",
+        "documents": [{ "model": "Clio", "price": 10 }, { "model": "Megane", "price": 20 }]
+    }))).await.unwrap();
+    assert_eq!(field(&inserted, "inserted"), &Data::Number(2.0));
+    assert!(matches!(field(&inserted, "ids"), Data::Array(ids) if ids.len() == 2));
+    assert!(db.invoke("insert_many", object(serde_json::json!({ "collection": "cars", "documents": [1] }))).await.is_err());
+
+    let updated = db.invoke("update_many", object(serde_json::json!({
+        "collection": "cars",
+        "filter": {},
+        "update": { "$inc": { "price": 1 } }
+    }))).await.unwrap();
+    assert_eq!(field(&updated, "modified"), &Data::Number(2.0));
+
+    let upserted = db.invoke("update", object(serde_json::json!({
+        "collection": "cars",
+        "filter": { "model": "Zoe" },
+        "update": { "price": 30 },
+        "upsert": true
+    }))).await.unwrap();
+    assert!(matches!(field(&upserted, "upserted_id"), Data::String(_)));
+
+    let next = || object(serde_json::json!({
+        "collection": "counters",
+        "filter": { "_id": "quote" },
+        "update": { "$inc": { "seq": 1 } },
+        "upsert": true
+    }));
+    db.invoke("find_one_and_update", next()).await.unwrap();
+    let second = db.invoke("find_one_and_update", next()).await.unwrap();
+    assert_eq!(field(field(&second, "data"), "seq"), &Data::Number(2.0));
+
+    // Events of a transaction wait for its commit, and vanish with an abort
+    while events.try_recv().is_ok() {}
+    for commit in [false, true] {
+        let begun = db.invoke("begin", Data::Null).await.unwrap();
+        let transaction = field(&begun, "transaction").clone();
+        db.invoke("store", object(serde_json::json!({
+            "collection": "cars",
+            "data": { "model": "Twingo" },
+            "transaction": transaction.to_json()
+        }))).await.unwrap();
+        assert!(events.try_recv().is_err());
+
+        let end = if commit { "commit" } else { "abort" };
+        db.invoke(end, object(serde_json::json!({ "transaction": transaction.to_json() }))).await.unwrap();
+        assert_eq!(events.try_recv().is_ok(), commit);
+    }
+    let count = db.invoke("count", object(serde_json::json!({ "collection": "cars", "filter": { "model": "Twingo" } }))).await.unwrap();
+    assert_eq!(field(&count, "count"), &Data::Number(1.0));
+    assert!(db.invoke("commit", object(serde_json::json!({}))).await.is_err());
+}
+
+#[tokio::test]
+async fn test_retried_writes_are_made_once() {
+    let db = DatabaseCoprocessor::with_store(MemoryStore::new());
+    let bump = |key: &str| object(serde_json::json!({
+        "collection": "counters",
+        "filter": { "_id": "quote" },
+        "update": { "$inc": { "seq": 1 } },
+        "upsert": true,
+        "idempotency_key": key
+    }));
+
+    let first = db.invoke("find_one_and_update", bump("wf-1:0.1")).await.unwrap();
+    let retried = db.invoke("find_one_and_update", bump("wf-1:0.1")).await.unwrap();
+    assert_eq!(retried, first);
+    db.invoke("find_one_and_update", bump("wf-1:0.2")).await.unwrap();
+
+    let found = db.invoke("retrieve", object(serde_json::json!({ "collection": "counters" }))).await.unwrap();
+    match field(&found, "data") {
+        Data::Array(docs) => assert_eq!(field(&docs[0], "seq"), &Data::Number(2.0)),
+        other => panic!("Expected array, got {:?}", other),
+    }
+
+    // A failed write leaves nothing recorded, so its retry is made
+    let store = |data: serde_json::Value| object(serde_json::json!({
+        "collection": "cars", "data": data, "idempotency_key": "wf-2:0.1"
+    }));
+    assert!(db.invoke("store", store(serde_json::json!("not a document"))).await.is_err());
+    let stored = db.invoke("store", store(serde_json::json!({ "model": "Clio" }))).await.unwrap();
+    assert_eq!(db.invoke("store", store(serde_json::json!({ "model": "Clio" }))).await.unwrap(), stored);
+    let count = db.invoke("count", object(serde_json::json!({ "collection": "cars" }))).await.unwrap();
+    assert_eq!(field(&count, "count"), &Data::Number(1.0));
+}
+
+#[tokio::test]
+async fn test_old_call_records_are_forgotten() {
+    let store = Arc::new(MemoryStore::new());
+    let db = DatabaseCoprocessor::with_store(store.clone());
+    let ns = Namespace::new("autodin", idempotency::IDEMPOTENCY_COLLECTION);
+    let old = mongodb::bson::DateTime::from_millis(
+        mongodb::bson::DateTime::now().timestamp_millis() - idempotency::RETENTION.as_millis() as i64 - 1000,
+    );
+    store.insert(ns, doc! { "_id": "wf-0:0.1", "method": "store", "result": "{}", "created_at": old }).await.unwrap();
+
+    let args = object(serde_json::json!({ "collection": "cars", "data": { "model": "Clio" }, "idempotency_key": "wf-1:0.1" }));
+    db.invoke("store", args).await.unwrap();
+
+    let ids: Vec<_> = store.find(ns, doc! {}, FindOptions::default()).await.unwrap().iter()
+        .map(|document| document.get_str("_id").unwrap().to_string())
+        .collect();
+    assert_eq!(ids, vec!["wf-1:0.1"]);
+}
+
+#[tokio::test]
+async fn test_scripts_roll_back_failed_transactions() {
+    let runtime = SPURuntime::new();
+    let store = Arc::new(MemoryStore::new());
+    runtime.register_class("database".to_string(), Arc::new(DatabaseCoprocessor::with_store(store.clone()))).await;
+
+    // The duplicate _id fails the TRY, so the stock update is rolled back
+    let script = r#"
+INSTANTIATE database db
+CALL db store {"collection": "cars", "data": {"_id": "clio", "stock": 2}} stored
+TRY
+  BEGIN db
+  CALL db update {"collection": "cars", "filter": {"_id": "clio"}, "update": {"$inc": {"stock": -1}}} updated
+  CALL db store {"collection": "cars", "data": {"_id": "clio"}} duplicate
+  COMMIT db
+CATCH
+  TRACE rolled back
+CALL db retrieve {"collection": "cars", "filter": {"_id": "clio"}} found
+GET found.data result
+"#;
+    match runtime.execute(script).await.unwrap() {
+        Data::Array(found) => assert_eq!(field(&found[0], "stock"), &Data::Number(2.0)),
+        other => panic!("Expected array, got {:?}", other),
+    }
+
+    // Committed blocks apply; transactions left open are rolled back
+    let script = r#"
+INSTANTIATE database db
+BEGIN db
+CALL db update {"collection": "cars", "filter": {"_id": "clio"}, "update": {"$inc": {"stock": -1}}} updated
+COMMIT db
+BEGIN db
+CALL db delete {"collection": "cars", "filter": {}} deleted
+"#;
+    runtime.execute(script).await.unwrap();
+    let clio = store.find(CARS, doc! { "_id": "clio" }, FindOptions::default()).await.unwrap();
+    assert_eq!(clio[0].get_f64("stock").unwrap(), 1.0);
+
+    assert!(runtime.execute("INSTANTIATE database db\nCOMMIT db").await.is_err());
+    assert!(runtime.execute("INSTANTIATE database db\nBEGIN db\nBEGIN db").await.is_err());
+}
+
+#[tokio::test]
+async fn test_database_coprocessor_aggregate() {
+    let db = DatabaseCoprocessor::with_store(MemoryStore::new());
+    let stored = db.invoke("insert_many", object(serde_json::json!({
+        "collection": "requests",
+        "workspace": "autodin",
+        "documents": [
+            { "brand": "Renault", "urgency": "high" },
+            { "brand": "Renault", "urgency": "low" },
+            { "brand": "Peugeot", "urgency": "high" }
+        ]
+    }))).await.unwrap();
+    let first_id = match field(&stored, "ids") {
+        Data::Array(ids) => ids[0].clone(),
+        other => panic!("Expected array, got {:?}", other),
+    };
+
+    let args = |pipeline: serde_json::Value, limit: u64| object(serde_json::json!({
+        "collection": "requests",
+        "workspace": "autodin",
+        "pipeline": pipeline,
+        "limit": limit
+    }));
+    let per_brand = db.invoke("aggregate", args(serde_json::json!([
+        { "$group": { "_id": "$brand", "requests": { "$sum": 1 } } },
+        { "$sort": { "requests": -1 } }
+    ]), 1)).await.unwrap();
+    assert_eq!(field(&per_brand, "count"), &Data::Number(1.0));
+    assert_eq!(field(&per_brand, "truncated"), &Data::Bool(true));
+    assert_eq!(field(&per_brand, "data"), &Data::from_json(serde_json::json!([{ "_id": "Renault", "requests": 2 }])));
+
+    // $match converts _id strings like filters do, and ids come back as strings
+    let by_id = db.invoke("aggregate", args(serde_json::json!([{ "$match": { "_id": first_id.to_json() } }]), 10)).await.unwrap();
+    assert_eq!(field(&by_id, "truncated"), &Data::Bool(false));
+    match field(&by_id, "data") {
+        Data::Array(docs) => assert_eq!(field(&docs[0], "_id"), &first_id),
+        other => panic!("Expected array, got {:?}", other),
+    }
+
+    let refused = db.invoke("aggregate", args(serde_json::json!([{ "$merge": "copy" }]), 10)).await;
+    assert!(matches!(refused, Err(spu_core::CoprocessorError::InvalidArguments(_))));
+}
@@ -0,0 +1,300 @@
+//! Outbound HTTP coprocessor tests
+//!
+//! Requests against a local stub server: bodies, headers, retries, timeouts,
+//! size limits and the per-workspace allowlist, including on redirects, for
+//! calls made in the registry's default workspace unless they name another.
+
+use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer};
+use spu_core::{
+    coprocessors::HttpCoprocessor,
+    runtime::SPURuntime,
+    workspaces::Workspaces,
+    Coprocessor, CoprocessorError, Data,
+};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Counts calls to the flaky endpoint
+struct Calls(AtomicUsize);
+
+async fn json_endpoint() -> HttpResponse {
+    HttpResponse::Ok()
+        .insert_header(("x-stub", "yes"))
+        .json(serde_json::json!({ "hello": "world", "items": [1, 2, 3] }))
+}
+
+/// Echoes the method, content type, query string, custom headers and the raw body
+async fn echo(req: HttpRequest, body: web::Bytes) -> HttpResponse {
+    let header = |name: &str| req.headers().get(name).and_then(|v| v.to_str().ok()).unwrap_or("").to_string();
+    HttpResponse::Ok().json(serde_json::json!({
+        "method": req.method().as_str(),
+        "content_type": header("content-type"),
+        "x_api_key": header("x-api-key"),
+        "idempotency_key": header("idempotency-key"),
+        "query": req.query_string(),
+        "body": String::from_utf8_lossy(&body),
+    }))
+}
+
+/// Fails with 503 twice, then succeeds
+async fn flaky(calls: web::Data<Calls>) -> HttpResponse {
+    if calls.0.fetch_add(1, Ordering::SeqCst) < 2 {
+        HttpResponse::ServiceUnavailable().body("try again")
+    } else {
+        HttpResponse::Ok().body("finally")
+    }
+}
+
+async fn slow() -> HttpResponse {
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    HttpResponse::Ok().body("too late")
+}
+
+async fn big() -> HttpResponse {
+    HttpResponse::Ok().body("x".repeat(4096))
+}
+
+/// Same size as `big`, but streamed without a Content-Length
+async fn big_stream() -> HttpResponse {
+    let chunks = (0..4).map(|_| Ok::<_, actix_web::Error>(web::Bytes::from("x".repeat(1024))));
+    HttpResponse::Ok().streaming(futures::stream::iter(chunks))
+}
+
+async fn redirect_local() -> HttpResponse {
+    HttpResponse::Found().insert_header(("location", "/json")).finish()
+}
+
+/// Redirects to the same server under a host name that isn't allowed
+async fn redirect_away(req: HttpRequest) -> HttpResponse {
+    let port = req.app_config().local_addr().port();
+    HttpResponse::Found()
+        .insert_header(("location", format!("http://localhost:{}/json", port)))
+        .finish()
+}
+
+/// Start the stub server and return its base URL
+fn start_server() -> String {
+    let calls = web::Data::new(Calls(AtomicUsize::new(0)));
+    let server = HttpServer::new(move || {
+        App::new()
+            .app_data(calls.clone())
+            .route("/json", web::get().to(json_endpoint))
+            .route("/echo", web::to(echo))
+            .route("/flaky", web::get().to(flaky))
+            .route("/slow", web::get().to(slow))
+            .route("/big", web::get().to(big))
+            .route("/big-stream", web::get().to(big_stream))
+            .route("/redirect", web::get().to(redirect_local))
+            .route("/redirect-away", web::get().to(redirect_away))
+    })
+    .workers(1)
+    .bind(("127.0.0.1", 0))
+    .unwrap();
+
+    let port = server.addrs()[0].port();
+    actix_web::rt::spawn(server.run());
+    format!("http://127.0.0.1:{}", port)
+}
+
+fn create_http() -> HttpCoprocessor {
+    allowing(&["127.0.0.1"])
+}
+
+/// Requests from "autodin", the default workspace, to `hosts`
+fn allowing(hosts: &[&str]) -> HttpCoprocessor {
+    HttpCoprocessor::new()
+        .with_workspaces(Arc::new(Workspaces::of(&["autodin", "belgicomics"]).unwrap()))
+        .allow_hosts("autodin", hosts)
+}
+
+fn args(pairs: Vec<(&str, Data)>) -> Data {
+    Data::Object(pairs.into_iter().map(|(k, v)| (k.to_string(), v)).collect())
+}
+
+fn url(base: &str, path: &str) -> Data {
+    Data::String(format!("{}{}", base, path))
+}
+
+fn field<'a>(response: &'a Data, name: &str) -> &'a Data {
+    match response {
+        Data::Object(obj) => obj.get(name).unwrap_or(&Data::Null),
+        other => panic!("Unexpected response: {:?}", other),
+    }
+}
+
+#[actix_web::test]
+async fn test_get_parses_json_response() {
+    let base = start_server();
+    let http = create_http();
+
+    let response = http.invoke("get", args(vec![("url", url(&base, "/json"))])).await.unwrap();
+    assert_eq!(field(&response, "status"), &Data::Number(200.0));
+    assert_eq!(field(&response, "ok"), &Data::Bool(true));
+    assert_eq!(field(&response, "attempts"), &Data::Number(1.0));
+    assert_eq!(field(field(&response, "headers"), "x-stub"), &Data::String("yes".to_string()));
+    assert_eq!(field(field(&response, "body"), "hello"), &Data::String("world".to_string()));
+}
+
+#[actix_web::test]
+async fn test_post_sends_json_form_and_text_bodies() {
+    let base = start_server();
+    let http = create_http();
+
+    let mut payload = HashMap::new();
+    payload.insert("amount".to_string(), Data::Number(125.0));
+    let response = http.invoke("post", args(vec![
+        ("url", url(&base, "/echo")),
+        ("json", Data::Object(payload)),
+    ])).await.unwrap();
+    let echoed = field(&response, "body");
+    assert_eq!(field(echoed, "method"), &Data::String("POST".to_string()));
+    assert_eq!(field(echoed, "content_type"), &Data::String("application/json".to_string()));
+    assert_eq!(field(echoed, "body"), &Data::String(r#"{"amount":125.0}"#.to_string()));
+
+    let mut form = HashMap::new();
+    form.insert("name".to_string(), Data::String("Jean Dupont".to_string()));
+    let response = http.invoke("put", args(vec![
+        ("url", url(&base, "/echo")),
+        ("form", Data::Object(form)),
+    ])).await.unwrap();
+    let echoed = field(&response, "body");
+    assert_eq!(field(echoed, "method"), &Data::String("PUT".to_string()));
+    assert_eq!(field(echoed, "content_type"), &Data::String("application/x-www-form-urlencoded".to_string()));
+    assert_eq!(field(echoed, "body"), &Data::String("name=Jean+Dupont".to_string()));
+
+    let response = http.invoke("post", args(vec![
+        ("url", url(&base, "/echo")),
+        ("body", Data::String("plain text".to_string())),
+    ])).await.unwrap();
+    assert_eq!(field(field(&response, "body"), "body"), &Data::String("plain text".to_string()));
+
+    let result = http.invoke("post", args(vec![
+        ("url", url(&base, "/echo")),
+        ("json", Data::Null),
+        ("body", Data::String("both".to_string())),
+    ])).await;
+    assert!(matches!(result, Err(CoprocessorError::InvalidArguments(_))));
+}
+
+#[actix_web::test]
+async fn test_headers_query_and_custom_method() {
+    let base = start_server();
+    let http = create_http();
+
+    let mut headers = HashMap::new();
+    headers.insert("X-Api-Key".to_string(), Data::String("k-123".to_string()));
+    let mut query = HashMap::new();
+    query.insert("page".to_string(), Data::Number(2.0));
+
+    let response = http.invoke("request", args(vec![
+        ("method", Data::String("patch".to_string())),
+        ("url", url(&base, "/echo")),
+        ("headers", Data::Object(headers)),
+        ("query", Data::Object(query)),
+        ("idempotency_key", Data::String("wf-1:0.1".to_string())),
+    ])).await.unwrap();
+    let echoed = field(&response, "body");
+    assert_eq!(field(echoed, "method"), &Data::String("PATCH".to_string()));
+    assert_eq!(field(echoed, "x_api_key"), &Data::String("k-123".to_string()));
+    assert_eq!(field(echoed, "idempotency_key"), &Data::String("wf-1:0.1".to_string()));
+    assert_eq!(field(echoed, "query"), &Data::String("page=2".to_string()));
+
+    let result = http.invoke("request", args(vec![("url", url(&base, "/echo"))])).await;
+    assert!(matches!(result, Err(CoprocessorError::InvalidArguments(_))));
+}
+
+#[actix_web::test]
+async fn test_allowlist_is_per_workspace_and_checked_on_redirects() {
+    let base = start_server();
+    let http = create_http();
+
+    let other_workspace = http.invoke("get", args(vec![
+        ("url", url(&base, "/json")),
+        ("workspace", Data::String("belgicomics".to_string())),
+    ])).await;
+    assert!(matches!(other_workspace, Err(CoprocessorError::InvalidArguments(_))));
+
+    // Without a registry, calls must name their workspace
+    let unnamed = HttpCoprocessor::new().allow_hosts("autodin", &["127.0.0.1"])
+        .invoke("get", args(vec![("url", url(&base, "/json"))])).await;
+    assert!(matches!(unnamed, Err(CoprocessorError::InvalidArguments(_))));
+
+    let port = base.rsplit(':').next().unwrap();
+    let other_host = http.invoke("get", args(vec![
+        ("url", Data::String(format!("http://localhost:{}/json", port))),
+    ])).await;
+    assert!(matches!(other_host, Err(CoprocessorError::InvalidArguments(_))));
+
+    let scheme = http.invoke("get", args(vec![("url", Data::String("file:///etc/passwd".to_string()))])).await;
+    assert!(matches!(scheme, Err(CoprocessorError::InvalidArguments(_))));
+
+    // Redirects on the same host are followed, to another host they fail
+    let response = http.invoke("get", args(vec![("url", url(&base, "/redirect"))])).await.unwrap();
+    assert_eq!(field(&response, "url"), &url(&base, "/json"));
+    let away = http.invoke("get", args(vec![("url", url(&base, "/redirect-away"))])).await;
+    assert!(matches!(away, Err(CoprocessorError::ExecutionError(_))));
+
+    // Port-pinned and wildcard patterns
+    let pinned = allowing(&[&format!("127.0.0.1:{}", port)]);
+    assert!(pinned.invoke("get", args(vec![("url", url(&base, "/json"))])).await.is_ok());
+    let wrong_port = allowing(&["127.0.0.1:1"]);
+    assert!(wrong_port.invoke("get", args(vec![("url", url(&base, "/json"))])).await.is_err());
+    let wildcard = allowing(&["*.0.0.1"]);
+    assert!(wildcard.invoke("get", args(vec![("url", url(&base, "/json"))])).await.is_ok());
+}
+
+#[actix_web::test]
+async fn test_retries_on_server_errors() {
+    let base = start_server();
+    let http = create_http();
+
+    // Without retries the 503 is returned as is
+    let response = http.invoke("get", args(vec![("url", url(&base, "/flaky"))])).await.unwrap();
+    assert_eq!(field(&response, "status"), &Data::Number(503.0));
+    assert_eq!(field(&response, "ok"), &Data::Bool(false));
+
+    let response = http.invoke("get", args(vec![
+        ("url", url(&base, "/flaky")),
+        ("retries", Data::Number(3.0)),
+        ("retry_delay_ms", Data::Number(10.0)),
+    ])).await.unwrap();
+    assert_eq!(field(&response, "status"), &Data::Number(200.0));
+    assert_eq!(field(&response, "attempts"), &Data::Number(2.0));
+    assert_eq!(field(&response, "body"), &Data::String("finally".to_string()));
+}
+
+#[actix_web::test]
+async fn test_timeout_and_size_limit() {
+    let base = start_server();
+    let http = create_http().with_max_response_bytes(1024);
+
+    let result = http.invoke("get", args(vec![
+        ("url", url(&base, "/slow")),
+        ("timeout_ms", Data::Number(100.0)),
+    ])).await;
+    assert!(matches!(result, Err(CoprocessorError::Timeout)));
+
+    let result = http.invoke("get", args(vec![("url", url(&base, "/big"))])).await;
+    assert!(matches!(result, Err(CoprocessorError::ExecutionError(_))));
+    let result = http.invoke("get", args(vec![("url", url(&base, "/big-stream"))])).await;
+    assert!(matches!(result, Err(CoprocessorError::ExecutionError(_))));
+
+    let result = http.invoke("get", args(vec![("url", url(&base, "/json"))])).await;
+    assert!(result.is_ok());
+}
+
+#[actix_web::test]
+async fn test_script_calls_external_api() {
+    let base = start_server();
+    let runtime = SPURuntime::new();
+    runtime.register_class("http".to_string(), Arc::new(create_http())).await;
+
+    let script = format!(
+        "INSTANTIATE http h\nCALL h get {{\"url\": \"{}/json\"}} response\nGET response.body.hello result",
+        base
+    );
+    let result = runtime.execute(&script).await.unwrap();
+    assert_eq!(result, Data::String("world".to_string()));
+}
@@ -0,0 +1,227 @@
+//! Migration tests
+//!
+//! Loading migration files, applying them up and down per workspace, dry runs,
+//! claims and the command line, on the in-memory store.
+
+use mongodb::bson::{doc, Document};
+use spu_core::{
+    coprocessors::DatabaseCoprocessor,
+    migrations::{self, Direction, MigrateAction, MigrateCommand, Migration, Migrator, Step, MIGRATION_COLLECTION},
+    runtime::SPURuntime,
+    store::{DocumentStore, FindOptions, MemoryStore, Namespace},
+    workspaces::Workspaces,
+};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+const INDEXES: &str = r#"{
+    "description": "Index users on uuid, as the docs ask",
+    "up": [
+        { "op": "create_index", "collection": "users", "keys": { "uuid": 1 }, "unique": true },
+        { "op": "rename_field", "collection": "users", "from": "mail", "to": "email" }
+    ]
+}"#;
+
+const BACKFILL_UP: &str = r#"INSTANTIATE database db
+CALL db update_many {"collection": "users", "workspace": "$workspace", "filter": {}, "update": {"$set": {"status": "active"}}} result
+DESTROY db"#;
+
+const BACKFILL_DOWN: &str = r#"INSTANTIATE database db
+CALL db update_many {"collection": "users", "workspace": "$workspace", "filter": {}, "update": {"$unset": {"status": ""}}} result
+DESTROY db"#;
+
+const RENAME: &str = r#"{
+    "up": [{ "op": "rename_collection", "from": "users", "to": "members" }]
+}"#;
+
+fn migrations_dir(files: &[(&str, &str)]) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("spu-migrations-{}", uuid::Uuid::new_v4()));
+    std::fs::create_dir_all(&dir).unwrap();
+    for (name, content) in files {
+        std::fs::write(dir.join(name), content).unwrap();
+    }
+    dir
+}
+
+async fn create_migrator(files: &[(&str, &str)]) -> (Migrator, Arc<MemoryStore>) {
+    let store = Arc::new(MemoryStore::new());
+    let runtime = Arc::new(SPURuntime::new());
+    runtime.register_class("database".to_string(), Arc::new(DatabaseCoprocessor::with_store(store.clone()))).await;
+
+    let dir = migrations_dir(files);
+    let loaded = migrations::load(&dir).unwrap();
+    std::fs::remove_dir_all(&dir).ok();
+    (Migrator::new(runtime, store.clone(), loaded), store)
+}
+
+async fn users(store: &MemoryStore, workspace: &str, collection: &str) -> Vec<Document> {
+    let options = FindOptions { projection: Some(doc! { "_id": 0 }), ..FindOptions::default() };
+    store.find(Namespace::new(workspace, collection), doc! {}, options).await.unwrap()
+}
+
+const ALL: [(&str, &str); 5] = [
+    ("0001_user_indexes.json", INDEXES),
+    ("0002_backfill_status.up.spu", BACKFILL_UP),
+    ("0002_backfill_status.down.spu", BACKFILL_DOWN),
+    ("0003_members.json", RENAME),
+    ("README.md", "Not a migration"),
+];
+
+#[tokio::test]
+async fn test_load_files() {
+    let dir = migrations_dir(&ALL);
+    let loaded = migrations::load(&dir).unwrap();
+    std::fs::remove_dir_all(&dir).ok();
+
+    assert_eq!(loaded.iter().map(|m| (m.version, m.name.as_str())).collect::<Vec<_>>(), [
+        (1, "user_indexes"),
+        (2, "backfill_status"),
+        (3, "members"),
+    ]);
+    // Index creation and renames are undone automatically, in reverse
+    assert_eq!(loaded[0].down, Some(vec![
+        Step::RenameField { collection: "users".to_string(), from: "email".to_string(), to: "mail".to_string() },
+        Step::DropIndex { collection: "users".to_string(), name: "uuid_1".to_string() },
+    ]));
+
+    for (files, expected) in [
+        (vec![("0001_a.json", r#"{"up": [{"op": "drop_table"}]}"#)], "drop_table"),
+        (vec![("0001_a.json", r#"{"up": [{"op": "create_index", "collection": "users", "keys": {"uuid": 2}}]}"#)], "Invalid direction"),
+        (vec![("0001_a.up.spu", "CALL")], "Invalid script"),
+        (vec![("0001_a.json", RENAME), ("0001_b.json", RENAME)], "used by both"),
+        (vec![("0001_a.json", RENAME), ("0001_a.up.spu", BACKFILL_UP)], "both a JSON file and scripts"),
+        (vec![("0001_a.down.spu", BACKFILL_DOWN)], "no up script"),
+        (vec![("0000_a.json", RENAME)], "version 0"),
+    ] {
+        let dir = migrations_dir(&files);
+        let error = migrations::load(&dir).unwrap_err();
+        std::fs::remove_dir_all(&dir).ok();
+        assert!(error.contains(expected), "{}", error);
+    }
+}
+
+#[tokio::test]
+async fn test_migrate_up_and_down() {
+    let (migrator, store) = create_migrator(&ALL).await;
+    store.insert_many(Namespace::new("autodin", "users"), vec![
+        doc! { "uuid": "a", "mail": "a@x.be" },
+        doc! { "uuid": "b", "mail": "b@x.be" },
+    ]).await.unwrap();
+
+    // A dry run reports without changing anything
+    let planned = migrator.migrate("autodin", None, true).await.unwrap();
+    assert_eq!((planned.from, planned.to, planned.migrations.clone()), (0, 3, vec![1, 2, 3]));
+    assert_eq!(planned.steps[0], "0001 user_indexes: create unique index uuid_1 on users");
+    assert_eq!(migrator.status("autodin").await.unwrap().current, 0);
+    assert_eq!(store.list_indexes(Namespace::new("autodin", "users")).await.unwrap().len(), 1);
+
+    let report = migrator.migrate("autodin", Some(2), false).await.unwrap();
+    assert_eq!((report.direction, report.to, report.migrations), (Direction::Up, 2, vec![1, 2]));
+    assert_eq!(users(&store, "autodin", "users").await, [
+        doc! { "uuid": "a", "email": "a@x.be", "status": "active" },
+        doc! { "uuid": "b", "email": "b@x.be", "status": "active" },
+    ]);
+    assert!(store.insert(Namespace::new("autodin", "users"), doc! { "uuid": "a" }).await.is_err());
+
+    let status = migrator.status("autodin").await.unwrap();
+    assert_eq!((status.current, status.applied, status.pending), (2, vec![1, 2], vec![3]));
+    // Other workspaces are migrated separately
+    assert_eq!(migrator.status("other").await.unwrap().pending, [1, 2, 3]);
+
+    migrator.migrate("autodin", None, false).await.unwrap();
+    assert_eq!(users(&store, "autodin", "members").await.len(), 2);
+    assert_eq!(migrator.migrate("autodin", None, false).await.unwrap().migrations, Vec::<u32>::new());
+
+    // Down undoes in reverse, scripts with their down script
+    let report = migrator.migrate("autodin", Some(0), false).await.unwrap();
+    assert_eq!((report.direction, report.from, report.to, report.migrations), (Direction::Down, 3, 0, vec![3, 2, 1]));
+    assert_eq!(users(&store, "autodin", "users").await, [
+        doc! { "uuid": "a", "mail": "a@x.be" },
+        doc! { "uuid": "b", "mail": "b@x.be" },
+    ]);
+    assert_eq!(store.list_indexes(Namespace::new("autodin", "users")).await.unwrap().len(), 1);
+    assert_eq!(migrator.status("autodin").await.unwrap().applied, Vec::<u32>::new());
+
+    assert!(migrator.migrate("autodin", Some(7), false).await.unwrap_err().contains("Unknown migration version"));
+}
+
+#[tokio::test]
+async fn test_failed_and_claimed_migrations() {
+    let scripts_only = [("0001_backfill.up.spu", BACKFILL_UP), ("0002_users.json", INDEXES)];
+    let (migrator, store) = create_migrator(&scripts_only).await;
+    // Duplicate uuids make the unique index fail
+    store.insert_many(Namespace::new("autodin", "users"), vec![doc! { "uuid": "a" }, doc! { "uuid": "a" }]).await.unwrap();
+
+    let error = migrator.migrate("autodin", None, false).await.unwrap_err();
+    assert!(error.contains("Migration 2 (users) failed at step 1"), "{}", error);
+    let status = migrator.status("autodin").await.unwrap();
+    assert_eq!((status.applied, status.pending, status.running), (vec![1], vec![2], Vec::<u32>::new()));
+
+    // Once the data is fixed, the failed migration is retried
+    store.delete(Namespace::new("autodin", "users"), doc! {}).await.unwrap();
+    migrator.migrate("autodin", None, false).await.unwrap();
+    assert_eq!(migrator.status("autodin").await.unwrap().current, 2);
+
+    // Script migrations without a down script cannot be undone
+    let error = migrator.migrate("autodin", Some(0), false).await.unwrap_err();
+    assert!(error.contains("Migration 1 (backfill) cannot be undone"), "{}", error);
+    assert_eq!(migrator.migrate("autodin", Some(1), false).await.unwrap().migrations, [2]);
+
+    // A migration claimed by another run is not applied again
+    store.insert(Namespace::new("autodin", MIGRATION_COLLECTION), doc! { "_id": 2_i64, "name": "users", "state": "running" }).await.unwrap();
+    let error = migrator.migrate("autodin", None, false).await.unwrap_err();
+    assert!(error.contains("still marked as running"), "{}", error);
+    assert_eq!(migrator.status("autodin").await.unwrap().running, [2]);
+}
+
+#[test]
+fn test_parse_command() {
+    let args = |args: &[&str]| args.iter().map(|arg| arg.to_string()).collect::<Vec<_>>();
+    let registry = Workspaces::of(&["autodin", "belgique", "garage"]).unwrap();
+    let parse = |line: &[&str]| MigrateCommand::parse(&args(line), &registry);
+
+    let command = parse(&["down", "--to", "2", "-w", "autodin", "--workspace", "belgique", "--dry-run", "--dir", "db/migrations"]).unwrap();
+    assert_eq!(command, MigrateCommand {
+        action: MigrateAction::Down,
+        workspaces: vec!["autodin".to_string(), "belgique".to_string()],
+        target: Some(2),
+        dry_run: true,
+        dir: "db/migrations".to_string(),
+    });
+
+    assert_eq!(parse(&["status"]).unwrap().action, MigrateAction::Status);
+    assert_eq!(parse(&[]).unwrap().action, MigrateAction::Up);
+    assert!(parse(&["--to", "two"]).unwrap_err().contains("Invalid version"));
+    assert!(parse(&["--to"]).unwrap_err().contains("needs a value"));
+    assert!(parse(&["sideways"]).unwrap_err().contains("sideways"));
+
+    // Every workspace of the registry unless named, and only those
+    assert_eq!(parse(&["up"]).unwrap().workspaces, ["autodin", "belgique", "garage"]);
+    assert!(parse(&["up", "-w", "elsewhere"]).unwrap_err().contains("Unknown workspace 'elsewhere'"));
+}
+
+#[tokio::test]
+async fn test_run_command() {
+    let (migrator, _store) = create_migrator(&ALL).await;
+    let args = |args: &[&str]| args.iter().map(|arg| arg.to_string()).collect::<Vec<_>>();
+    let registry = Workspaces::of(&["autodin", "belgique"]).unwrap();
+    let run = |line: &[&str]| MigrateCommand::parse(&args(line), &registry).unwrap();
+
+    let results = run(&["up", "-w", "autodin", "-w", "belgique"]).run(&migrator).await.unwrap();
+    assert_eq!(results.iter().map(|r| r["to"].clone()).collect::<Vec<_>>(), [3, 3]);
+
+    // Down goes back one migration unless told where
+    let down = run(&["down", "-w", "autodin"]).run(&migrator).await.unwrap();
+    assert_eq!((down[0]["migrations"].clone(), down[0]["to"].clone()), (serde_json::json!([3]), serde_json::json!(2)));
+
+    let status = run(&["status", "-w", "autodin"]).run(&migrator).await.unwrap();
+    assert_eq!(status[0]["pending"], serde_json::json!([3]));
+    assert!(run(&["down", "-w", "autodin", "--to", "3"]).run(&migrator).await.unwrap_err().contains("below"));
+}
+
+#[test]
+fn test_from_scripts() {
+    let migration = Migration::from_scripts(4, "backfill", BACKFILL_UP, None).unwrap();
+    assert_eq!(migration.up.len(), 1);
+    assert_eq!(migration.down, None);
+}
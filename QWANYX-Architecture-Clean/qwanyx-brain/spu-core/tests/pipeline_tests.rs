@@ -0,0 +1,145 @@
+//! Saved pipeline tests
+//!
+//! Saving, parameter binding and running reports through the database
+//! coprocessor, on the in-memory stores.
+
+use serde_json::json;
+use spu_core::{
+    auth::policy::Caller,
+    coprocessors::DatabaseCoprocessor,
+    pipelines::{MemoryPipelineStore, PipelineError, PipelineLibrary},
+    runtime::SPURuntime,
+    store::MemoryStore,
+};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+async fn create_library() -> PipelineLibrary {
+    let runtime = Arc::new(SPURuntime::new());
+    runtime.register_class("database".to_string(), Arc::new(DatabaseCoprocessor::with_store(MemoryStore::new()))).await;
+
+    let seed = r#"
+INSTANTIATE database db
+CALL db insert_many {"collection": "requests", "workspace": "autodin", "documents": [{"brand": "Renault", "urgency": "high", "status": "open"}, {"brand": "Renault", "urgency": "low", "status": "open"}, {"brand": "Peugeot", "urgency": "high", "status": "open"}, {"brand": "Peugeot", "urgency": "high", "status": "closed"}]} result
+"#;
+    runtime.execute(seed).await.unwrap();
+    PipelineLibrary::new(runtime, Arc::new(MemoryPipelineStore::new()))
+}
+
+fn params(json: serde_json::Value) -> HashMap<String, serde_json::Value> {
+    serde_json::from_value(json).unwrap()
+}
+
+async fn save_by_urgency(library: &PipelineLibrary) {
+    library.save(
+        "autodin",
+        "open-by-urgency",
+        "requests",
+        json!([
+            { "$match": { "status": { "$param": "status" }, "brand": { "$param": "brand" } } },
+            { "$group": { "_id": "$urgency", "count": { "$sum": 1 } } },
+            { "$sort": { "count": -1, "_id": 1 } }
+        ]),
+        params(json!({ "status": "open", "brand": null })),
+        Some("Requests of a brand by urgency".to_string()),
+    ).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_run_with_parameters_and_defaults() {
+    let library = create_library().await;
+    save_by_urgency(&library).await;
+
+    let renault = library.run("autodin", "open-by-urgency", params(json!({ "brand": "Renault" })), None).await.unwrap();
+    assert_eq!(renault.to_json(), json!({
+        "data": [{ "_id": "high", "count": 1.0 }, { "_id": "low", "count": 1.0 }],
+        "count": 2.0,
+        "truncated": false
+    }));
+
+    // Overriding the default, with a limit
+    let closed = library.run("autodin", "open-by-urgency", params(json!({ "brand": "Peugeot", "status": "closed" })), Some(1)).await.unwrap();
+    assert_eq!(closed.to_json()["data"], json!([{ "_id": "high", "count": 1.0 }]));
+
+    let truncated = library.run("autodin", "open-by-urgency", params(json!({ "brand": "Renault" })), Some(1)).await.unwrap();
+    assert_eq!(truncated.to_json()["count"], json!(1.0));
+    assert_eq!(truncated.to_json()["truncated"], json!(true));
+}
+
+#[tokio::test]
+async fn test_parameters_are_checked() {
+    let library = create_library().await;
+    save_by_urgency(&library).await;
+
+    let missing = library.run("autodin", "open-by-urgency", HashMap::new(), None).await;
+    assert!(matches!(missing, Err(PipelineError::InvalidParameters(e)) if e.contains("brand")));
+
+    let unknown = library.run("autodin", "open-by-urgency", params(json!({ "brand": "Renault", "colour": "red" })), None).await;
+    assert!(matches!(unknown, Err(PipelineError::InvalidParameters(e)) if e.contains("colour")));
+
+    let undeclared = library.save(
+        "autodin", "broken", "requests",
+        json!([{ "$match": { "brand": { "$param": "brand" } } }]),
+        HashMap::new(), None,
+    ).await;
+    assert!(undeclared.unwrap_err().contains("not declared"));
+}
+
+#[tokio::test]
+async fn test_save_rejects_writes_and_malformed_stages() {
+    let library = create_library().await;
+
+    for (pipeline, expected) in [
+        (json!([{ "$out": "copy" }]), "$out"),
+        (json!({ "$match": {} }), "array"),
+        (json!([{ "$match": {}, "$limit": 1 }]), "single operator"),
+        (json!(["$match"]), "object"),
+    ] {
+        let error = library.save("autodin", "bad", "requests", pipeline, HashMap::new(), None).await.unwrap_err();
+        assert!(error.contains(expected), "{}", error);
+    }
+    assert!(library.list("autodin").await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn test_pipelines_are_scoped_by_workspace() {
+    let library = create_library().await;
+    save_by_urgency(&library).await;
+
+    assert_eq!(library.list("autodin").await.unwrap().len(), 1);
+    assert!(library.list("other").await.unwrap().is_empty());
+    assert!(matches!(
+        library.run("other", "open-by-urgency", params(json!({ "brand": "Renault" })), None).await,
+        Err(PipelineError::NotFound)
+    ));
+
+    let saved = library.load("autodin", "open-by-urgency").await.unwrap().unwrap();
+    assert_eq!(saved.to_json()["description"], json!("Requests of a brand by urgency"));
+
+    assert!(library.delete("autodin", "open-by-urgency").await.unwrap());
+    assert!(!library.delete("autodin", "open-by-urgency").await.unwrap());
+
+    // Running a pipeline the database rejects reports the failure
+    library.save("autodin", "bad-stage", "requests", json!([{ "$bucket": {} }]), HashMap::new(), None).await.unwrap();
+    let failed = library.run("autodin", "bad-stage", HashMap::new(), None).await;
+    assert!(matches!(failed, Err(PipelineError::Failed(e)) if e.contains("$bucket")));
+}
+
+#[tokio::test]
+async fn test_run_for_a_user_sees_what_they_may_read() {
+    let library = create_library().await;
+    save_by_urgency(&library).await;
+    let renault = || params(json!({ "brand": "Renault" }));
+
+    let admin = Caller::new("a-1", "autodin", vec!["admin".to_string()]);
+    let all = library.run_as(admin, "open-by-urgency", renault(), None).await.unwrap();
+    assert_eq!(all.to_json()["count"], json!(2.0));
+
+    // Users only read their own documents by default, and own none of these
+    let marie = Caller::new("u-1", "autodin", vec!["particulier".to_string()]);
+    let own = library.run_as(marie, "open-by-urgency", renault(), None).await.unwrap();
+    assert_eq!(own.to_json()["count"], json!(0.0));
+
+    let elsewhere = Caller::new("u-2", "other", vec!["admin".to_string()]);
+    assert!(matches!(library.run_as(elsewhere, "open-by-urgency", renault(), None).await, Err(PipelineError::NotFound)));
+}
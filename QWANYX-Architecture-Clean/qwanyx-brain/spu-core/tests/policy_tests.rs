@@ -0,0 +1,344 @@
+//! Access policy tests
+//!
+//! Grants on their own, then enforcement by the database coprocessor on the
+//! in-memory store, directly and through scripts run for a user.
+
+use mongodb::bson::{doc, oid::ObjectId};
+use serde_json::json;
+use spu_core::{
+    auth::policy::{Access, Caller, Operation, Policy, PolicyError},
+    coprocessors::DatabaseCoprocessor,
+    runtime::SPURuntime,
+    store::MemoryStore,
+    Coprocessor, CoprocessorError, Data,
+};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+fn caller(sub: &str, role: &str) -> Caller {
+    Caller::new(sub, "autodin", vec![role.to_string()])
+}
+
+fn garage_policy() -> Policy {
+    Policy::from_json(json!({
+        "admin": ["admin"],
+        "collections": {
+            "requests": {
+                "read": ["professionnel"],
+                "read_own": ["particulier"],
+                "write_own": ["particulier"],
+                "owner": "client",
+                "fields": {
+                    "internal_notes": { "read": ["professionnel"], "write": ["professionnel"] },
+                    "price": { "write": ["professionnel"] }
+                }
+            },
+            "brands": { "read": ["*"] }
+        }
+    })).unwrap()
+}
+
+fn forbidden<T: std::fmt::Debug>(result: Result<T, PolicyError>) -> String {
+    match result {
+        Err(PolicyError::Forbidden(message)) => message,
+        other => panic!("expected a refusal, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_default_policy() {
+    let policy = Policy::default();
+    let marie = caller("u-1", "particulier");
+
+    let grant = policy.grant(&marie, "autodin", "listings", Operation::Write).unwrap();
+    assert_eq!(grant.access, Access::Own { field: "owner".to_string(), owner: "u-1".to_string() });
+
+    // Users are their own documents, whose roles only admins change
+    let id = ObjectId::new().to_hex();
+    let grant = policy.grant(&caller(&id, "particulier"), "autodin", "users", Operation::Write).unwrap();
+    assert_eq!(grant.restrict(doc! {}), doc! { "_id": ObjectId::parse_str(&id).unwrap() });
+    assert_eq!(forbidden(grant.check_update(&doc! { "$set": { "role": "admin" } })), "Not allowed to write role");
+    assert!(grant.check_update(&doc! { "$set": { "firstName": "Marie" } }).is_ok());
+
+    assert!(policy.grant(&caller("u-2", "superuser"), "autodin", "users", Operation::Write).unwrap().is_unrestricted());
+    assert!(policy.is_admin(&caller("u-2", "admin"), "autodin"));
+
+    // Roles count in the token's workspace only
+    let admin = caller("u-2", "admin");
+    assert_eq!(forbidden(policy.grant(&admin, "belgique", "users", Operation::Read)), "Signed in to autodin, not belgique");
+    assert!(!policy.is_admin(&admin, "belgique"));
+
+    assert_eq!(forbidden(policy.grant(&marie, "autodin", "spu_policy", Operation::Write)), "Not allowed to write spu_policy");
+    assert_eq!(forbidden(policy.require_admin(&marie, "autodin")), "Only admins of autodin may do this");
+}
+
+#[test]
+fn test_policy_rules() {
+    let policy = garage_policy();
+    let pro = caller("p-1", "professionnel");
+    let marie = caller("u-1", "particulier");
+
+    assert!(policy.grant(&pro, "autodin", "requests", Operation::Read).unwrap().is_unrestricted());
+    assert_eq!(forbidden(policy.grant(&pro, "autodin", "requests", Operation::Write)), "Not allowed to write requests");
+    assert_eq!(forbidden(policy.grant(&marie, "autodin", "invoices", Operation::Read)), "Not allowed to read invoices");
+    assert_eq!(policy.grant(&marie, "autodin", "brands", Operation::Read).unwrap().access, Access::All);
+
+    let read = policy.grant(&marie, "autodin", "requests", Operation::Read).unwrap();
+    assert_eq!(read.denied, vec!["internal_notes"]);
+    assert_eq!(read.restrict(doc! { "status": "open" }), doc! { "status": "open", "client": "u-1" });
+    assert_eq!(
+        read.restrict(doc! { "client": "u-2" }),
+        doc! { "$and": [{ "client": "u-2" }, { "client": "u-1" }] },
+    );
+    assert!(read.check_filter(&doc! { "$or": [{ "status": "open" }, { "brand": "Audi" }] }).is_ok());
+    assert_eq!(forbidden(read.check_filter(&doc! { "$or": [{ "internal_notes.author": "p-1" }] })), "Not allowed to read internal_notes");
+    assert!(read.check_filter(&doc! { "$expr": { "$gt": ["$price", 10] } }).is_err());
+
+    let mut document = doc! { "_id": 1, "brand": "Audi", "internal_notes": "slow payer", "client": "u-1" };
+    read.hide(&mut document);
+    assert_eq!(document, doc! { "_id": 1, "brand": "Audi", "client": "u-1" });
+
+    // Owners write their documents, without giving them away
+    let write = policy.grant(&marie, "autodin", "requests", Operation::Write).unwrap();
+    let mut request = doc! { "brand": "Audi", "contact": { "phone": "0470" } };
+    assert!(write.admit(&mut request).unwrap());
+    assert_eq!(request.get_str("client").unwrap(), "u-1");
+    assert!(!write.admit(&mut doc! { "brand": "Audi", "client": "u-1" }).unwrap());
+    assert!(forbidden(write.admit(&mut doc! { "brand": "Audi", "client": "u-2" })).contains("owned by others"));
+    assert_eq!(forbidden(write.admit(&mut doc! { "price": 10 })), "Not allowed to write price");
+    assert_eq!(forbidden(write.check_update(&doc! { "$set": { "client": "u-2" } })), "Not allowed to write client");
+    assert_eq!(forbidden(write.check_update(&doc! { "$rename": { "brand": "price" } })), "Not allowed to write price");
+    assert!(write.check_update(&doc! { "$inc": { "views": 1 } }).is_ok());
+
+    assert_eq!(Policy::from_json(policy.to_json()).unwrap(), policy);
+    assert!(matches!(Policy::from_json(json!({ "collections": { "requests": { "reads": ["*"] } } })), Err(PolicyError::Invalid(_))));
+    assert!(matches!(Policy::from_json(json!({ "collections": { "requests": { "owner": "" } } })), Err(PolicyError::Invalid(_))));
+}
+
+fn object(json: serde_json::Value) -> Data {
+    Data::from_json(json)
+}
+
+async fn garage() -> DatabaseCoprocessor<Arc<MemoryStore>> {
+    let db = DatabaseCoprocessor::with_store(Arc::new(MemoryStore::new()));
+    let policy = object(json!({ "workspace": "autodin", "policy": garage_policy().to_json() }));
+    db.invoke_as("set_policy", policy, Some(&caller("a-1", "admin"))).await.unwrap();
+    db.invoke("insert_many", object(json!({
+        "workspace": "autodin",
+        "collection": "requests",
+        "documents": [
+            { "brand": "Audi", "client": "u-1", "internal_notes": "slow payer", "price": 100 },
+            { "brand": "BMW", "client": "u-2", "price": 200 },
+            { "brand": "Citroën", "client": "u-1", "price": 300 },
+        ]
+    }))).await.unwrap();
+    db
+}
+
+fn brands(result: &Data) -> Vec<String> {
+    let json = result.to_json();
+    let mut brands: Vec<String> = json["data"].as_array().unwrap().iter()
+        .map(|d| d["brand"].as_str().unwrap().to_string())
+        .collect();
+    brands.sort();
+    brands
+}
+
+fn refusal(result: Result<Data, CoprocessorError>) -> String {
+    match result {
+        Err(CoprocessorError::Forbidden(message)) => message,
+        other => panic!("expected a refusal, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn test_database_reads_for_callers() {
+    let db = garage().await;
+    let marie = caller("u-1", "particulier");
+    let query = object(json!({ "workspace": "autodin", "collection": "requests", "sort": "brand" }));
+
+    let own = db.invoke_as("retrieve", query.clone(), Some(&marie)).await.unwrap();
+    assert_eq!(brands(&own), ["Audi", "Citroën"]);
+    assert_eq!(own.to_json()["total"], json!(2.0));
+    assert!(own.to_json()["data"][0].get("internal_notes").is_none());
+
+    let pro = db.invoke_as("retrieve", query.clone(), Some(&caller("p-1", "professionnel"))).await.unwrap();
+    assert_eq!(brands(&pro), ["Audi", "BMW", "Citroën"]);
+    assert_eq!(pro.to_json()["data"][0]["internal_notes"], json!("slow payer"));
+
+    let counted = db.invoke_as("count", object(json!({ "workspace": "autodin", "collection": "requests", "filter": { "price": { "$gte": 200 } } })), Some(&marie)).await.unwrap();
+    assert_eq!(counted.to_json()["count"], json!(1.0));
+
+    let probing = object(json!({ "workspace": "autodin", "collection": "requests", "filter": { "internal_notes": { "$exists": true } } }));
+    assert_eq!(refusal(db.invoke_as("retrieve", probing, Some(&marie)).await), "Not allowed to read internal_notes");
+    let sorted = object(json!({ "workspace": "autodin", "collection": "requests", "sort": "-internal_notes" }));
+    assert_eq!(refusal(db.invoke_as("retrieve", sorted, Some(&marie)).await), "Not allowed to read internal_notes");
+
+    let report = db.invoke_as("aggregate", object(json!({
+        "workspace": "autodin",
+        "collection": "requests",
+        "pipeline": [{ "$group": { "_id": null, "total": { "$sum": "$price" }, "notes": { "$push": "$internal_notes" } } }]
+    })), Some(&marie)).await.unwrap();
+    assert_eq!(report.to_json()["data"][0]["total"].as_f64(), Some(400.0));
+    assert_eq!(report.to_json()["data"][0]["notes"], json!([]));
+    let joined = object(json!({
+        "workspace": "autodin",
+        "collection": "requests",
+        "pipeline": [{ "$lookup": { "from": "requests", "localField": "client", "foreignField": "client", "as": "others" } }]
+    }));
+    assert_eq!(refusal(db.invoke_as("aggregate", joined, Some(&marie)).await), "Not allowed to join requests");
+
+    // Every way of reading another collection is checked, however deep
+    for stage in [
+        json!({ "$unionWith": "requests" }),
+        json!({ "$unionWith": { "coll": "requests", "pipeline": [{ "$match": {} }] } }),
+        json!({ "$graphLookup": { "from": "requests", "startWith": "$brand", "connectFromField": "brand", "connectToField": "brand", "as": "others" } }),
+        json!({ "$facet": { "all": [{ "$lookup": { "from": "requests", "localField": "brand", "foreignField": "brand", "as": "others" } }] } }),
+        json!({ "$lookup": { "from": "brands", "as": "others", "pipeline": [
+            { "$lookup": { "from": "requests", "localField": "brand", "foreignField": "brand", "as": "nested" } }
+        ] } }),
+    ] {
+        let sneaking = object(json!({ "workspace": "autodin", "collection": "brands", "pipeline": [stage] }));
+        assert_eq!(refusal(db.invoke_as("aggregate", sneaking, Some(&marie)).await), "Not allowed to join requests", "{}", stage);
+    }
+    let keys = object(json!({ "workspace": "autodin", "collection": "brands", "pipeline": [{ "$unionWith": "spu_api_keys" }] }));
+    assert_eq!(refusal(db.invoke_as("aggregate", keys, Some(&marie)).await), "Not allowed to read spu_api_keys");
+    let unnamed = object(json!({ "workspace": "autodin", "collection": "brands", "pipeline": [{ "$unionWith": { "pipeline": [] } }] }));
+    assert!(matches!(db.invoke_as("aggregate", unnamed, Some(&marie)).await, Err(CoprocessorError::InvalidArguments(_))));
+
+    // Other workspaces and the server's own collections are out of reach
+    let elsewhere = object(json!({ "workspace": "belgique", "collection": "requests" }));
+    assert_eq!(refusal(db.invoke_as("retrieve", elsewhere, Some(&marie)).await), "Signed in to autodin, not belgique");
+    let internal = object(json!({ "workspace": "autodin", "collection": "spu_policy" }));
+    assert_eq!(refusal(db.invoke_as("retrieve", internal, Some(&marie)).await), "Not allowed to read spu_policy");
+
+    // Scripts running for no one see everything
+    assert_eq!(brands(&db.invoke("retrieve", query).await.unwrap()), ["Audi", "BMW", "Citroën"]);
+}
+
+#[tokio::test]
+async fn test_database_writes_for_callers() {
+    let db = garage().await;
+    let marie = caller("u-1", "particulier");
+    let all = object(json!({ "workspace": "autodin", "collection": "requests" }));
+
+    let stored = db.invoke_as("store", object(json!({ "workspace": "autodin", "collection": "requests", "data": { "brand": "Dacia" } })), Some(&marie)).await.unwrap();
+    assert_eq!(stored.to_json()["success"], json!(true));
+    let theirs = object(json!({ "workspace": "autodin", "collection": "requests", "data": { "brand": "Ford", "client": "u-2" } }));
+    assert!(refusal(db.invoke_as("store", theirs, Some(&marie)).await).contains("owned by others"));
+    let priced = object(json!({ "workspace": "autodin", "collection": "requests", "data": { "brand": "Ford", "price": 1 } }));
+    assert_eq!(refusal(db.invoke_as("store", priced, Some(&marie)).await), "Not allowed to write price");
+
+    // Updates and deletes only reach the caller's documents
+    let update = object(json!({ "workspace": "autodin", "collection": "requests", "filter": {}, "update": { "status": "seen" } }));
+    let updated = db.invoke_as("update_many", update, Some(&marie)).await.unwrap();
+    assert_eq!(updated.to_json()["modified"], json!(3.0));
+    let reprice = object(json!({ "workspace": "autodin", "collection": "requests", "filter": { "brand": "Audi" }, "update": { "price": 1 } }));
+    assert_eq!(refusal(db.invoke_as("update", reprice, Some(&marie)).await), "Not allowed to write price");
+    let give_away = object(json!({ "workspace": "autodin", "collection": "requests", "filter": { "brand": "Audi" }, "update": { "client": "u-2" } }));
+    assert_eq!(refusal(db.invoke_as("update", give_away, Some(&marie)).await), "Not allowed to write client");
+
+    let taken = db.invoke_as("find_one_and_update", object(json!({
+        "workspace": "autodin", "collection": "requests", "filter": { "brand": "Audi" }, "update": { "status": "closed" }
+    })), Some(&marie)).await.unwrap();
+    assert_eq!(taken.to_json()["data"]["status"], json!("closed"));
+    assert!(taken.to_json()["data"].get("internal_notes").is_none());
+    let not_hers = db.invoke_as("find_one_and_update", object(json!({
+        "workspace": "autodin", "collection": "requests", "filter": { "brand": "BMW" }, "update": { "status": "closed" }
+    })), Some(&marie)).await.unwrap();
+    assert_eq!(not_hers.to_json()["found"], json!(false));
+
+    // Nor can their filters probe fields the caller may not read
+    let probe = object(json!({ "workspace": "autodin", "collection": "requests", "filter": { "internal_notes": "fragile" }, "update": { "status": "seen" } }));
+    assert_eq!(refusal(db.invoke_as("update_many", probe, Some(&marie)).await), "Not allowed to read internal_notes");
+    let probe = object(json!({ "workspace": "autodin", "collection": "requests", "filter": { "internal_notes": { "$exists": true } } }));
+    assert_eq!(refusal(db.invoke_as("delete", probe, Some(&marie)).await), "Not allowed to read internal_notes");
+
+    let deleted = db.invoke_as("delete", object(json!({ "workspace": "autodin", "collection": "requests", "filter": {} })), Some(&marie)).await.unwrap();
+    assert_eq!(deleted.to_json()["deleted_count"], json!(3.0));
+    let left = db.invoke("retrieve", all).await.unwrap();
+    assert_eq!(brands(&left), ["BMW"]);
+    assert_eq!(left.to_json()["data"][0].get("status"), None);
+
+    // Professionals read everything but write nothing
+    let pro = caller("p-1", "professionnel");
+    let write = object(json!({ "workspace": "autodin", "collection": "requests", "filter": {}, "update": { "status": "seen" } }));
+    assert_eq!(refusal(db.invoke_as("update", write, Some(&pro)).await), "Not allowed to write requests");
+}
+
+#[tokio::test]
+async fn test_policy_management() {
+    let db = garage().await;
+    let admin = caller("a-1", "admin");
+    let marie = caller("u-1", "particulier");
+
+    let current = db.invoke_as("get_policy", object(json!({ "workspace": "autodin" })), Some(&admin)).await.unwrap();
+    assert_eq!(current.to_json()["default"], json!(false));
+    assert_eq!(Policy::from_json(current.to_json()["policy"].clone()).unwrap(), garage_policy());
+    assert!(db.invoke_as("get_policy", object(json!({ "workspace": "autodin" })), Some(&marie)).await.is_err());
+    let belgique = Caller::new("a-2", "belgique", vec!["admin".to_string()]);
+    let fresh = db.invoke_as("get_policy", object(json!({ "workspace": "belgique" })), Some(&belgique)).await.unwrap();
+    assert_eq!(fresh.to_json()["default"], json!(true));
+    // Scripts running for no one administer nothing
+    assert_eq!(refusal(db.invoke("get_policy", object(json!({ "workspace": "belgique" }))).await), "Only admins of belgique may do this");
+    assert_eq!(refusal(db.invoke("set_policy", object(json!({ "workspace": "belgique", "policy": {} }))).await), "Only admins of belgique may do this");
+
+    // Admins change it, but cannot lock themselves out
+    let open = json!({ "admin": ["admin"], "collections": { "*": { "read": ["*"], "write": ["*"] } } });
+    let attempt = object(json!({ "workspace": "autodin", "policy": open }));
+    assert_eq!(refusal(db.invoke_as("set_policy", attempt.clone(), Some(&marie)).await), "Only admins of autodin may do this");
+    let lockout = object(json!({ "workspace": "autodin", "policy": { "admin": ["owner"] } }));
+    assert!(matches!(db.invoke_as("set_policy", lockout, Some(&admin)).await, Err(CoprocessorError::InvalidArguments(_))));
+    let invalid = object(json!({ "workspace": "autodin", "policy": { "collections": [] } }));
+    assert!(matches!(db.invoke_as("set_policy", invalid, Some(&admin)).await, Err(CoprocessorError::InvalidArguments(_))));
+    db.invoke_as("set_policy", attempt, Some(&admin)).await.unwrap();
+    let everything = db.invoke_as("retrieve", object(json!({ "workspace": "autodin", "collection": "requests" })), Some(&marie)).await.unwrap();
+    assert_eq!(brands(&everything), ["Audi", "BMW", "Citroën"]);
+
+    // Schemas are for admins too
+    let schema = object(json!({ "workspace": "autodin", "collection": "requests", "schema": { "type": "object" } }));
+    assert_eq!(refusal(db.invoke_as("set_schema", schema.clone(), Some(&marie)).await), "Only admins of autodin may do this");
+    db.invoke_as("set_schema", schema, Some(&admin)).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_scripts_run_for_a_user() {
+    let runtime = SPURuntime::new();
+    runtime.register_class("database".to_string(), Arc::new(garage().await)).await;
+    let script = r#"
+INSTANTIATE database db
+SET query {"workspace": "autodin", "collection": "requests", "sort": "brand"}
+CALL db retrieve $query result
+RETURN $result
+"#;
+
+    let own = runtime.execute_as(script, HashMap::new(), caller("u-1", "particulier")).await.unwrap();
+    assert_eq!(brands(&own), ["Audi", "Citroën"]);
+    assert_eq!(brands(&runtime.execute(script).await.unwrap()), ["Audi", "BMW", "Citroën"]);
+
+    let error = runtime.execute_as(script, HashMap::new(), caller("u-1", "mecanicien")).await.unwrap_err();
+    assert!(error.is_forbidden() && error.message.contains("Forbidden: Not allowed to read requests"), "{}", error);
+
+    // A refusal the script catches does not make a later failure one
+    let caught = r#"
+INSTANTIATE database db
+SET query {"workspace": "autodin", "collection": "requests"}
+TRY
+    CALL db retrieve $query result
+CATCH
+THROW Failed "after the refusal"
+"#;
+    let error = runtime.execute_as(caught, HashMap::new(), caller("u-1", "mecanicien")).await.unwrap_err();
+    assert!(!error.is_forbidden() && error.cause.is_none(), "{}", error);
+
+    // The owner filter holds whatever the script asks for
+    let sneaky = r#"
+INSTANTIATE database db
+SET query {"workspace": "autodin", "collection": "requests", "filter": {"$or": [{"client": "u-2"}, {"client": {"$ne": "u-2"}}]}}
+CALL db retrieve $query result
+RETURN $result
+"#;
+    let own = runtime.execute_as(sneaky, HashMap::new(), caller("u-1", "particulier")).await.unwrap();
+    assert_eq!(brands(&own), ["Audi", "Citroën"]);
+    assert_eq!(own.to_json()["total"], json!(2.0));
+}
@@ -0,0 +1,226 @@
+//! Scheduler tests
+//!
+//! Cron evaluation, run recording, overlap prevention, also after a cancelled
+//! run, and names per workspace, against the in-memory store.
+
+use chrono::{DateTime, Duration, Utc};
+use spu_core::{
+    runtime::SPURuntime,
+    scheduler::{parse_cron, MemoryScheduleStore, RunStatus, ScheduleStore, Scheduler},
+    Coprocessor, CoprocessorError, Data, MethodSignature,
+};
+use std::sync::Arc;
+use tokio::sync::Notify;
+
+/// Blocks every call until released
+#[derive(Default)]
+struct GateCoprocessor {
+    release: Notify,
+}
+
+#[async_trait::async_trait]
+impl Coprocessor for GateCoprocessor {
+    fn class_name(&self) -> String {
+        "gate".to_string()
+    }
+
+    fn methods(&self) -> Vec<MethodSignature> {
+        vec![]
+    }
+
+    async fn invoke(&self, method: &str, _args: Data) -> Result<Data, CoprocessorError> {
+        match method {
+            "wait" => {
+                self.release.notified().await;
+                Ok(Data::Bool(true))
+            }
+            _ => Err(CoprocessorError::MethodNotFound(method.to_string())),
+        }
+    }
+}
+
+async fn create_scheduler() -> (Arc<Scheduler>, Arc<MemoryScheduleStore>, Arc<GateCoprocessor>) {
+    let runtime = SPURuntime::new();
+    let gate = Arc::new(GateCoprocessor::default());
+    runtime.register_class("gate".to_string(), gate.clone()).await;
+
+    let store = Arc::new(MemoryScheduleStore::new());
+    let scheduler = Arc::new(Scheduler::new(Arc::new(runtime), store.clone()));
+    (scheduler, store, gate)
+}
+
+fn utc(s: &str) -> DateTime<Utc> {
+    DateTime::parse_from_rfc3339(s).unwrap().with_timezone(&Utc)
+}
+
+#[test]
+fn test_parse_cron_field_counts() {
+    assert!(parse_cron("*/5 * * * *").is_ok());
+    assert!(parse_cron("30 0 3 * * *").is_ok());
+    assert!(parse_cron("* * *").is_err());
+    assert!(parse_cron("not a cron at all").is_err());
+}
+
+#[tokio::test]
+async fn test_next_run_respects_timezone() {
+    let (scheduler, _, _) = create_scheduler().await;
+    let schedule = scheduler
+        .upsert("morning", "0 9 * * *", Some("Europe/Brussels"), "TRACE hello", true)
+        .await
+        .unwrap();
+
+    // 09:00 in Brussels is 08:00 UTC in winter and 07:00 UTC in summer
+    let winter = schedule.next_after(utc("2026-01-15T00:00:00Z")).unwrap();
+    assert_eq!(winter, Some(utc("2026-01-15T08:00:00Z")));
+    let summer = schedule.next_after(utc("2026-07-15T00:00:00Z")).unwrap();
+    assert_eq!(summer, Some(utc("2026-07-15T07:00:00Z")));
+}
+
+#[tokio::test]
+async fn test_upsert_validates_input() {
+    let (scheduler, _, _) = create_scheduler().await;
+    assert!(scheduler.upsert("bad", "61 * * * *", None, "TRACE x", true).await.is_err());
+    assert!(scheduler.upsert("bad", "* * * * *", Some("Mars/Olympus"), "TRACE x", true).await.is_err());
+    assert!(scheduler.upsert("bad", "* * * * *", None, "BOGUS instruction", true).await.is_err());
+    assert!(scheduler.list().await.unwrap().is_empty());
+
+    let disabled = scheduler.upsert("off", "* * * * *", None, "TRACE x", false).await.unwrap();
+    assert!(disabled.next_run.is_none());
+}
+
+#[tokio::test]
+async fn test_run_now_records_outcome() {
+    let (scheduler, _, _) = create_scheduler().await;
+    scheduler.upsert("ok", "0 3 * * *", None, "SET result 42", true).await.unwrap();
+    scheduler.upsert("broken", "0 3 * * *", None, "CALL missing go {} result", true).await.unwrap();
+
+    let run = scheduler.run_now("ok").await.unwrap();
+    assert_eq!(run.status, RunStatus::Succeeded);
+    assert!(matches!(run.result, Some(Data::Number(n)) if n == 42.0));
+
+    let run = scheduler.run_now("broken").await.unwrap();
+    assert_eq!(run.status, RunStatus::Failed);
+    assert!(run.error.is_some());
+
+    let runs = scheduler.runs("ok", 10).await.unwrap();
+    assert_eq!(runs.len(), 1);
+    assert!(scheduler.run_now("nope").await.is_err());
+}
+
+#[tokio::test]
+async fn test_tick_fires_due_schedules_once() {
+    let (scheduler, store, _) = create_scheduler().await;
+    let schedule = scheduler.upsert("every-minute", "* * * * *", None, "SET result 1", true).await.unwrap();
+    scheduler.upsert("disabled", "* * * * *", None, "SET result 1", false).await.unwrap();
+    let due = schedule.next_run.unwrap();
+
+    // Nothing is due before the fire time
+    assert!(scheduler.tick(due - Duration::seconds(1)).await.unwrap().is_empty());
+
+    let handles = scheduler.tick(due).await.unwrap();
+    assert_eq!(handles.len(), 1);
+    for handle in handles {
+        assert_eq!(handle.await.unwrap().status, RunStatus::Succeeded);
+    }
+
+    // The next fire time moved on, so a second worker ticking at the same instant does nothing
+    let saved = store.load(None, "every-minute").await.unwrap().unwrap();
+    assert_eq!(saved.next_run, Some(due + Duration::minutes(1)));
+    assert!(scheduler.tick(due).await.unwrap().is_empty());
+    assert!(scheduler.runs("disabled", 10).await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn test_overlapping_run_is_skipped() {
+    let (scheduler, store, gate) = create_scheduler().await;
+    let script = "INSTANTIATE gate g\nCALL g wait {} result";
+    scheduler.upsert("slow", "* * * * * *", None, script, true).await.unwrap();
+
+    let first = {
+        let scheduler = scheduler.clone();
+        tokio::spawn(async move { scheduler.run_now("slow").await.unwrap() })
+    };
+    tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+
+    // Due while the first run is still blocked
+    let due = store.load(None, "slow").await.unwrap().unwrap().next_run.unwrap();
+    let handles = scheduler.tick(due + Duration::seconds(5)).await.unwrap();
+    assert_eq!(handles.len(), 1);
+    for handle in handles {
+        let run = handle.await.unwrap();
+        assert_eq!(run.status, RunStatus::Skipped);
+    }
+
+    gate.release.notify_one();
+    assert_eq!(first.await.unwrap().status, RunStatus::Succeeded);
+
+    let statuses: Vec<RunStatus> = scheduler.runs("slow", 10).await.unwrap()
+        .iter()
+        .map(|r| r.status)
+        .collect();
+    assert_eq!(statuses, vec![RunStatus::Succeeded, RunStatus::Skipped]);
+}
+
+#[tokio::test]
+async fn test_cancelled_run_frees_its_schedule() {
+    let (scheduler, _, gate) = create_scheduler().await;
+    let script = "INSTANTIATE gate g\nCALL g wait {} result";
+    scheduler.upsert("slow", "0 0 * * *", None, script, true).await.unwrap();
+
+    let first = {
+        let scheduler = scheduler.clone();
+        tokio::spawn(async move { scheduler.run_now("slow").await.unwrap() })
+    };
+    tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+    first.abort();
+    assert!(first.await.unwrap_err().is_cancelled());
+
+    // Not left marked as running
+    gate.release.notify_one();
+    assert_eq!(scheduler.run_now("slow").await.unwrap().status, RunStatus::Succeeded);
+}
+
+#[tokio::test]
+async fn test_workspace_schedule_is_confined() {
+    let (scheduler, _, _) = create_scheduler().await;
+    let script = "INSTANTIATE gate g\nCALL g nope {\"workspace\": \"other\"} result";
+    let schedule = scheduler.upsert_in("garage", "escape", "0 3 * * *", None, script, true).await.unwrap();
+    assert_eq!(schedule.workspace.as_deref(), Some("garage"));
+
+    let run = scheduler.run_now_in("garage", "escape").await.unwrap();
+    assert_eq!(run.status, RunStatus::Failed);
+    assert!(run.error.unwrap().starts_with("Forbidden"));
+    assert!(scheduler.run_now("escape").await.is_err());
+}
+
+#[tokio::test]
+async fn test_schedule_names_are_per_workspace() {
+    let (scheduler, _, _) = create_scheduler().await;
+    scheduler.upsert_in("garage", "nightly", "0 3 * * *", None, "SET result 1", true).await.unwrap();
+    scheduler.upsert_in("other", "nightly", "0 4 * * *", None, "SET result 2", true).await.unwrap();
+    scheduler.upsert("nightly", "0 5 * * *", None, "SET result 3", true).await.unwrap();
+
+    assert_eq!(scheduler.list().await.unwrap().len(), 3);
+    let garage = scheduler.list_in("garage").await.unwrap();
+    assert_eq!(garage.len(), 1);
+    assert_eq!(garage[0].cron, "0 3 * * *");
+    assert_eq!(scheduler.get_in("other", "nightly").await.unwrap().unwrap().cron, "0 4 * * *");
+
+    let run = scheduler.run_now_in("other", "nightly").await.unwrap();
+    assert!(matches!(run.result, Some(Data::Number(n)) if n == 2.0));
+    assert_eq!(scheduler.runs_in("other", "nightly", 10).await.unwrap().len(), 1);
+    assert!(scheduler.runs_in("garage", "nightly", 10).await.unwrap().is_empty());
+    assert!(scheduler.runs("nightly", 10).await.unwrap().is_empty());
+
+    // Runs of same-named schedules don't hold each other up
+    let due = garage[0].next_run.unwrap().max(scheduler.get_in("other", "nightly").await.unwrap().unwrap().next_run.unwrap());
+    let handles = scheduler.tick(due + Duration::hours(2)).await.unwrap();
+    assert_eq!(handles.len(), 3);
+    for handle in handles {
+        assert_eq!(handle.await.unwrap().status, RunStatus::Succeeded);
+    }
+
+    assert!(scheduler.delete_in("garage", "nightly").await.unwrap());
+    assert!(scheduler.get_in("garage", "nightly").await.unwrap().is_none());
+    assert!(scheduler.get("nightly").await.unwrap().is_some());
+}
@@ -0,0 +1,326 @@
+//! Collection schema tests
+//!
+//! The validator and defaults on their own, then enforcement by the database
+//! coprocessor on the in-memory store.
+
+use mongodb::bson::{doc, oid::ObjectId, Bson};
+use serde_json::json;
+use spu_core::{
+    auth::policy::Caller,
+    coprocessors::DatabaseCoprocessor,
+    store::{schema, DocumentStore, MemoryStore, Namespace},
+    Coprocessor, CoprocessorError, Data,
+};
+use std::sync::Arc;
+
+fn requests_schema() -> serde_json::Value {
+    json!({
+        "type": "object",
+        "required": ["brand", "urgency"],
+        "properties": {
+            "brand": { "type": "string", "minLength": 2 },
+            "urgency": { "enum": ["low", "normal", "high"] },
+            "status": { "type": "string", "enum": ["open", "closed"], "default": "open" },
+            "price": { "type": ["number", "null"], "minimum": 0 },
+            "email": { "type": "string", "format": "email" },
+            "parts": {
+                "type": "array",
+                "maxItems": 3,
+                "items": { "type": "object", "required": ["ref"], "properties": { "ref": { "type": "string" } } }
+            },
+            "contact": {
+                "type": "object",
+                "properties": { "channel": { "type": "string", "default": "email" } },
+                "additionalProperties": false
+            }
+        }
+    })
+}
+
+fn messages(violations: &[schema::Violation]) -> Vec<String> {
+    violations.iter().map(|v| v.to_string()).collect()
+}
+
+#[test]
+fn test_validation_errors_are_readable() {
+    let schema = requests_schema();
+    schema::check(&schema).unwrap();
+
+    let valid = doc! { "_id": ObjectId::new(), "brand": "Renault", "urgency": "high", "price": 120, "parts": [{ "ref": "P1" }] };
+    assert!(schema::validate(&schema, &valid).is_empty());
+
+    let invalid = doc! {
+        "brand": "R",
+        "status": "pending",
+        "price": -5.5,
+        "email": "not an email",
+        "parts": [{ "ref": "P1" }, { "name": "mirror" }, {}, {}],
+        "contact": { "phone": "0470" },
+    };
+    assert_eq!(messages(&schema::validate(&schema, &invalid)), vec![
+        "urgency is required",
+        "brand must be at least 2 characters long",
+        "status must be one of \"open\", \"closed\"",
+        "price must be at least 0",
+        "email must be an email address",
+        "parts must have at most 3 item(s)",
+        "parts.1.ref is required",
+        "parts.2.ref is required",
+        "parts.3.ref is required",
+        "contact.phone is not allowed",
+    ]);
+
+    // A value of the wrong type only reports that
+    assert_eq!(
+        messages(&schema::validate(&schema, &doc! { "brand": 42, "urgency": "low", "price": "cheap" })),
+        vec!["brand must be a string, got a number", "price must be a number or null, got a string"]
+    );
+}
+
+#[test]
+fn test_integers_dates_and_combinators() {
+    let schema = json!({
+        "type": "object",
+        "properties": {
+            "count": { "type": "integer", "multipleOf": 5 },
+            "created": { "type": "string", "format": "date-time" },
+            "day": { "type": "string", "format": "date" },
+            "code": { "anyOf": [{ "type": "string", "pattern": "^[A-Z]{3}$" }, { "type": "integer" }] },
+            "kind": { "oneOf": [{ "const": "a" }, { "const": "b" }] },
+            "label": { "not": { "const": "" } }
+        }
+    });
+    schema::check(&schema).unwrap();
+
+    let valid = doc! {
+        "count": 10_i64,
+        "created": Bson::DateTime(mongodb::bson::DateTime::now()),
+        "day": "2024-05-01",
+        "code": "ABC",
+        "kind": "a",
+        "label": "x",
+    };
+    assert!(schema::validate(&schema, &valid).is_empty());
+    assert!(schema::validate(&schema, &doc! { "count": 15.0, "created": "2024-05-01T09:30:00Z", "code": 7 }).is_empty());
+
+    let invalid = doc! { "count": 12, "created": "yesterday", "day": "01/05/2024", "code": "abc", "kind": "c", "label": "" };
+    assert_eq!(messages(&schema::validate(&schema, &invalid)), vec![
+        "count must be a multiple of 5",
+        "created must be a date and time such as 2024-05-01T09:30:00Z",
+        "day must be a date such as 2024-05-01",
+        "code must match at least one of the allowed forms",
+        "kind must match exactly one of the allowed forms",
+        "label must not match the excluded form",
+    ]);
+    assert_eq!(messages(&schema::validate(&schema, &doc! { "count": 2.5 })), vec!["count must be an integer, got a number"]);
+}
+
+#[test]
+fn test_defaults_fill_missing_properties() {
+    let schema = requests_schema();
+
+    let mut document = doc! { "brand": "Renault", "urgency": "low", "contact": {} };
+    schema::apply_defaults(&schema, &mut document).unwrap();
+    assert_eq!(document, doc! { "brand": "Renault", "urgency": "low", "contact": { "channel": "email" }, "status": "open" });
+
+    // Present values are kept, and missing objects aren't created
+    let mut document = doc! { "status": "closed" };
+    schema::apply_defaults(&schema, &mut document).unwrap();
+    assert_eq!(document, doc! { "status": "closed" });
+}
+
+#[test]
+fn test_bad_schemas_are_refused() {
+    for (bad, expected) in [
+        (json!({ "properties": {} }), "\"type\": \"object\""),
+        (json!({ "type": "object", "properties": { "a": { "type": "text" } } }), "a: invalid value for 'type'"),
+        (json!({ "type": "object", "properties": { "a": { "$ref": "#/defs/a" } } }), "$ref is not supported"),
+        (json!({ "type": "object", "properties": { "a": { "pattern": "(" } } }), "a: invalid pattern"),
+        (json!({ "type": "object", "required": "a" }), "invalid value for 'required'"),
+        (json!({ "type": "object", "properties": { "a": { "type": "integer", "default": "one" } } }), "default doesn't match"),
+    ] {
+        let error = schema::check(&bad).unwrap_err();
+        assert!(error.contains(expected), "{}", error);
+    }
+}
+
+fn object(json: serde_json::Value) -> Data {
+    Data::from_json(json)
+}
+
+fn field<'a>(data: &'a Data, name: &str) -> &'a Data {
+    match data {
+        Data::Object(obj) => obj.get(name).unwrap_or(&Data::Null),
+        other => panic!("Expected object, got {:?}", other),
+    }
+}
+
+fn violation(result: Result<Data, CoprocessorError>) -> String {
+    match result {
+        Err(CoprocessorError::InvalidArguments(message)) if message.starts_with(schema::SCHEMA_VIOLATION) => message,
+        other => panic!("Expected a schema violation, got {:?}", other),
+    }
+}
+
+/// Schemas are for admins
+fn admin() -> Caller {
+    Caller::new("a-1", "autodin", vec!["admin".to_string()])
+}
+
+async fn database_with_schema() -> (DatabaseCoprocessor<Arc<MemoryStore>>, Arc<MemoryStore>) {
+    let store = Arc::new(MemoryStore::new());
+    let db = DatabaseCoprocessor::with_store(store.clone());
+    db.invoke_as("set_schema", object(json!({
+        "collection": "requests",
+        "workspace": "autodin",
+        "schema": requests_schema()
+    })), Some(&admin())).await.unwrap();
+    (db, store)
+}
+
+#[tokio::test]
+async fn test_inserts_get_defaults_and_are_checked() {
+    let (db, store) = database_with_schema().await;
+
+    let stored = db.invoke("store", object(json!({
+        "collection": "requests",
+        "workspace": "autodin",
+        "data": { "brand": "Renault", "urgency": "high" }
+    }))).await.unwrap();
+    assert_eq!(field(&stored, "success"), &Data::Bool(true));
+    let saved = store.find(Namespace::new("autodin", "requests"), doc! {}, Default::default()).await.unwrap();
+    assert_eq!(saved[0].get_str("status").unwrap(), "open");
+
+    let refused = violation(db.invoke("store", object(json!({
+        "collection": "requests",
+        "workspace": "autodin",
+        "data": { "brand": "Renault", "urgency": "urgent" }
+    }))).await);
+    assert_eq!(refused, "Schema violation in 'requests': urgency must be one of \"low\", \"normal\", \"high\"");
+
+    // One bad document refuses the whole batch
+    let refused = violation(db.invoke("insert_many", object(json!({
+        "collection": "requests",
+        "workspace": "autodin",
+        "documents": [{ "brand": "Peugeot", "urgency": "low" }, { "brand": "Peugeot" }]
+    }))).await);
+    assert!(refused.ends_with("urgency is required (document 2)"), "{}", refused);
+    assert_eq!(store.count(Namespace::new("autodin", "requests"), doc! {}).await.unwrap(), 1);
+
+    // Other collections and workspaces are untouched
+    for (collection, workspace) in [("notes", "autodin"), ("requests", "other")] {
+        db.invoke("store", object(json!({
+            "collection": collection,
+            "workspace": workspace,
+            "data": { "anything": true }
+        }))).await.unwrap();
+    }
+}
+
+#[tokio::test]
+async fn test_updates_are_checked_against_the_result() {
+    let (db, store) = database_with_schema().await;
+    db.invoke("insert_many", object(json!({
+        "collection": "requests",
+        "workspace": "autodin",
+        "documents": [
+            { "brand": "Renault", "urgency": "low", "price": 10 },
+            { "brand": "Peugeot", "urgency": "low", "price": 3 }
+        ]
+    }))).await.unwrap();
+    let update = |filter: serde_json::Value, update: serde_json::Value| object(json!({
+        "collection": "requests",
+        "workspace": "autodin",
+        "filter": filter,
+        "update": update
+    }));
+
+    // Plain fields, operators and removals
+    let refused = violation(db.invoke("update", update(json!({ "brand": "Renault" }), json!({ "status": "lost" }))).await);
+    assert!(refused.contains("status must be one of"), "{}", refused);
+    let refused = violation(db.invoke("update_many", update(json!({}), json!({ "$inc": { "price": -5 } }))).await);
+    assert!(refused.contains("price must be at least 0"), "{}", refused);
+    let refused = violation(db.invoke("update", update(json!({ "brand": "Renault" }), json!({ "$unset": { "urgency": "" } }))).await);
+    assert!(refused.contains("urgency is required"), "{}", refused);
+
+    let updated = db.invoke("update_many", update(json!({}), json!({ "$inc": { "price": -2 }, "$set": { "status": "closed" } }))).await.unwrap();
+    assert_eq!(field(&updated, "modified"), &Data::Number(2.0));
+
+    // find_one_and_update checks the document it would pick
+    let refused = violation(db.invoke("find_one_and_update", object(json!({
+        "collection": "requests",
+        "workspace": "autodin",
+        "filter": {},
+        "sort": "price",
+        "update": { "$inc": { "price": -2 } }
+    }))).await);
+    assert!(refused.contains("price must be at least 0"), "{}", refused);
+
+    // update_many checks every match, not just a first batch of them
+    let fiats = (0..1200)
+        .map(|n| doc! { "_id": format!("f{:04}", n), "brand": "Fiat", "urgency": "low", "price": if n == 1100 { 1 } else { 10 } })
+        .collect();
+    store.insert_many(Namespace::new("autodin", "requests"), fiats).await.unwrap();
+    let refused = violation(db.invoke("update_many", update(json!({ "brand": "Fiat" }), json!({ "$inc": { "price": -2 } }))).await);
+    assert!(refused.contains("updating \"f1100\": price must be at least 0"), "{}", refused);
+    let fiats = store.count(Namespace::new("autodin", "requests"), doc! { "brand": "Fiat", "price": 10 }).await.unwrap();
+    assert_eq!(fiats, 1199);
+
+    // Upserts are checked as inserted, and get the defaults
+    let upsert = |filter: serde_json::Value, set: serde_json::Value| object(json!({
+        "collection": "requests",
+        "workspace": "autodin",
+        "filter": filter,
+        "update": { "$set": set },
+        "upsert": true
+    }));
+    let refused = violation(db.invoke("update", upsert(json!({ "brand": "Citroën" }), json!({ "price": 5 }))).await);
+    assert!(refused.contains("inserting: urgency is required"), "{}", refused);
+    db.invoke("update", upsert(json!({ "brand": "Citroën" }), json!({ "urgency": "normal" }))).await.unwrap();
+    let citroen = store.find(Namespace::new("autodin", "requests"), doc! { "brand": "Citroën" }, Default::default()).await.unwrap();
+    assert_eq!(citroen[0].get_str("status").unwrap(), "open");
+}
+
+#[tokio::test]
+async fn test_existing_documents_can_be_validated() {
+    let store = Arc::new(MemoryStore::new());
+    let db = DatabaseCoprocessor::with_store(store.clone());
+    let requests = Namespace::new("autodin", "requests");
+    store.insert_many(requests, vec![
+        doc! { "_id": "r1", "brand": "Renault", "urgency": "low" },
+        doc! { "_id": "r2", "brand": "Renault" },
+        doc! { "_id": "r3", "brand": "R", "urgency": "now" },
+    ]).await.unwrap();
+
+    let validate = |limit: u64| object(json!({ "collection": "requests", "workspace": "autodin", "limit": limit }));
+    assert!(matches!(db.invoke_as("validate_collection", validate(10), Some(&admin())).await, Err(CoprocessorError::NotFound(_))));
+
+    // Registering a schema doesn't touch what is already there
+    db.invoke_as("set_schema", object(json!({ "collection": "requests", "workspace": "autodin", "schema": requests_schema() })), Some(&admin())).await.unwrap();
+    let report = db.invoke_as("validate_collection", validate(10), Some(&admin())).await.unwrap();
+    assert_eq!(report.to_json(), json!({
+        "checked": 3.0,
+        "valid": 1.0,
+        "invalid": 2.0,
+        "documents": [
+            { "_id": "r2", "errors": ["urgency is required"] },
+            { "_id": "r3", "errors": ["brand must be at least 2 characters long", "urgency must be one of \"low\", \"normal\", \"high\""] }
+        ]
+    }));
+    let report = db.invoke_as("validate_collection", validate(1), Some(&admin())).await.unwrap();
+    assert_eq!(field(&report, "invalid"), &Data::Number(2.0));
+    assert!(matches!(field(&report, "documents"), Data::Array(docs) if docs.len() == 1));
+
+    // Management
+    let listed = db.invoke_as("list_schemas", object(json!({ "workspace": "autodin" })), Some(&admin())).await.unwrap();
+    assert_eq!(listed.to_json()["schemas"][0]["collection"], json!("requests"));
+    let got = db.invoke("get_schema", object(json!({ "collection": "requests", "workspace": "autodin" }))).await.unwrap();
+    assert_eq!(got.to_json()["schema"]["required"], json!(["brand", "urgency"]));
+
+    let bad = db.invoke_as("set_schema", object(json!({ "collection": "requests", "workspace": "autodin", "schema": { "type": "array" } })), Some(&admin())).await;
+    assert!(matches!(bad, Err(CoprocessorError::InvalidArguments(e)) if e.contains("Invalid schema")));
+
+    let deleted = db.invoke_as("delete_schema", object(json!({ "collection": "requests", "workspace": "autodin" })), Some(&admin())).await.unwrap();
+    assert_eq!(field(&deleted, "deleted"), &Data::Bool(true));
+    db.invoke("store", object(json!({ "collection": "requests", "workspace": "autodin", "data": { "free": "form" } }))).await.unwrap();
+}
@@ -0,0 +1,293 @@
+//! Search tests
+//!
+//! Stemming, accents, typos, facets and highlights over the in-memory store,
+//! indexes following writes, searching from scripts and never over hidden fields.
+
+use mongodb::bson::{doc, Document};
+use serde_json::json;
+use spu_core::{
+    auth::policy::Caller,
+    coprocessors::DatabaseCoprocessor,
+    runtime::SPURuntime,
+    search::{MatchMode, SearchConfig, SearchEngine, SearchError, SearchRequest, SearchResults},
+    store::{DocumentStore, MemoryStore, Namespace, UpdateOptions},
+    Coprocessor, Data,
+};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+const REQUESTS: Namespace<'static> = Namespace { workspace: "autodin", collection: "requests", transaction: None };
+
+fn requests_config() -> SearchConfig {
+    SearchConfig::from_json(json!({
+        "fields": ["title", "description", "parts.name"],
+        "boosts": { "title": 3 },
+        "facets": ["brand", "status"]
+    })).unwrap()
+}
+
+async fn searchable() -> (SearchEngine, Arc<MemoryStore>) {
+    let store = Arc::new(MemoryStore::new());
+    store.insert_many(REQUESTS, vec![
+        doc! { "_id": "r1", "title": "Réparation de l'embrayage", "description": "Embrayage qui patine en côte", "brand": "Renault", "status": "open" },
+        doc! { "_id": "r2", "title": "Pneus d'hiver", "description": "Quatre pneus pour une Clio", "brand": "Renault", "status": "closed",
+            "parts": [{ "name": "Pneu Michelin" }] },
+        doc! { "_id": "r3", "title": "Vidange", "description": "Vidange et réparations diverses sur la boîte de vitesses", "brand": "Peugeot", "status": "open" },
+        doc! { "_id": "r4", "title": "Carrosserie", "description": "Rayure sur la portière", "brand": "Citroën", "status": "open" },
+    ]).await.unwrap();
+    let engine = SearchEngine::new(store.clone());
+    engine.configure("autodin", "requests", requests_config()).await.unwrap();
+    (engine, store)
+}
+
+async fn search(engine: &SearchEngine, request: SearchRequest) -> SearchResults {
+    engine.search("autodin", "requests", &request).await.unwrap()
+}
+
+fn ids(results: &SearchResults) -> Vec<String> {
+    results.hits.iter().map(|hit| hit.id.as_str().unwrap().to_string()).collect()
+}
+
+/// Search until `done` holds, as indexes catch up with writes in the background
+async fn eventually(engine: &SearchEngine, query: &str, done: impl Fn(&SearchResults) -> bool) -> SearchResults {
+    for _ in 0..100 {
+        let results = search(engine, SearchRequest::new(query)).await;
+        if done(&results) {
+            return results;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    panic!("the index never caught up for '{}'", query);
+}
+
+#[tokio::test]
+async fn test_stemming_accents_and_ranking() {
+    let (engine, store) = searchable().await;
+
+    // Plural, no accents, and the title outranks the description
+    let results = search(&engine, SearchRequest::new("reparations")).await;
+    assert_eq!(ids(&results), ["r1", "r3"]);
+    assert_eq!(results.total, 2);
+    assert!(results.hits[0].score > results.hits[1].score);
+    assert_eq!(results.hits[0].document.get_str("brand").unwrap(), "Renault");
+
+    assert_eq!(ids(&search(&engine, SearchRequest::new("BOITE vitesse")).await), ["r3"]);
+    // Arrays of documents are searched too
+    assert_eq!(ids(&search(&engine, SearchRequest::new("michelin")).await), ["r2"]);
+
+    // Every word must match, unless any will do
+    assert!(search(&engine, SearchRequest::new("embrayage pneus")).await.hits.is_empty());
+    let any = SearchRequest { mode: MatchMode::Any, ..SearchRequest::new("embrayage pneus") };
+    assert_eq!(search(&engine, any).await.total, 2);
+
+    // English content stems the English way
+    let notes = Namespace::new("autodin", "notes");
+    store.insert(notes, doc! { "_id": "n1", "text": "The engines were running hot" }).await.unwrap();
+    engine.configure("autodin", "notes", SearchConfig::from_json(json!({ "fields": ["text"], "language": "english" })).unwrap()).await.unwrap();
+    let results = engine.search("autodin", "notes", &SearchRequest::new("engine runs")).await.unwrap();
+    assert_eq!(results.total, 1);
+}
+
+#[tokio::test]
+async fn test_typos() {
+    let (engine, _store) = searchable().await;
+
+    assert_eq!(ids(&search(&engine, SearchRequest::new("embrayge")).await), ["r1"]);
+    assert_eq!(ids(&search(&engine, SearchRequest::new("carosseire")).await), ["r4"]);
+    let exact = SearchRequest { fuzzy: false, ..SearchRequest::new("embrayge") };
+    assert!(search(&engine, exact).await.hits.is_empty());
+    // Short words must be spelled right
+    assert!(search(&engine, SearchRequest::new("pnu")).await.hits.is_empty());
+}
+
+#[tokio::test]
+async fn test_facets_and_filters() {
+    let (engine, _store) = searchable().await;
+
+    let browse = SearchRequest { facets: vec!["brand".to_string(), "status".to_string()], ..SearchRequest::default() };
+    let results = search(&engine, browse).await;
+    assert_eq!(results.total, 4);
+    assert_eq!(results.facets["brand"][0], ("Renault".to_string(), 2));
+    assert_eq!(results.facets["brand"].len(), 3);
+    assert_eq!(results.facets["status"], [("open".to_string(), 3), ("closed".to_string(), 1)]);
+
+    let renault = SearchRequest {
+        filters: vec![("brand".to_string(), "Renault".to_string())],
+        facets: vec!["status".to_string()],
+        ..SearchRequest::default()
+    };
+    let results = search(&engine, renault).await;
+    assert_eq!(results.total, 2);
+    assert_eq!(results.facets["status"], [("closed".to_string(), 1), ("open".to_string(), 1)]);
+
+    let open_repairs = SearchRequest { filters: vec![("status".to_string(), "open".to_string())], ..SearchRequest::new("reparation") };
+    assert_eq!(search(&engine, open_repairs).await.total, 2);
+
+    let page = SearchRequest { limit: 1, offset: 1, ..SearchRequest::new("reparation") };
+    let results = search(&engine, page).await;
+    assert_eq!((ids(&results), results.total), (vec!["r3".to_string()], 2));
+}
+
+#[tokio::test]
+async fn test_highlights() {
+    let (engine, store) = searchable().await;
+
+    let results = search(&engine, SearchRequest::new("embrayage")).await;
+    assert_eq!(results.hits[0].highlights["title"], "Réparation de l&#39;<mark>embrayage</mark>");
+    assert_eq!(results.hits[0].highlights["description"], "<mark>Embrayage</mark> qui patine en côte");
+
+    // Long fields are cut around the first match
+    let long = format!("{} pare-brise <fissuré> {}", "avant ".repeat(100), "arrière ".repeat(100));
+    store.insert(REQUESTS, doc! { "_id": "r5", "title": "Vitrage", "description": long }).await.unwrap();
+    let results = eventually(&engine, "fissure", |r| r.total == 1).await;
+    let snippet = &results.hits[0].highlights["description"];
+    assert!(snippet.starts_with('…') && snippet.ends_with('…'), "{}", snippet);
+    assert!(snippet.contains("pare-brise &lt;<mark>fissuré</mark>&gt;"), "{}", snippet);
+    assert!(!results.hits[0].highlights.contains_key("title"));
+}
+
+#[tokio::test]
+async fn test_index_follows_writes() {
+    let (engine, store) = searchable().await;
+    assert_eq!(search(&engine, SearchRequest::new("vidange")).await.total, 1);
+
+    store.insert(REQUESTS, doc! { "_id": "r6", "title": "Vidange moteur" }).await.unwrap();
+    eventually(&engine, "vidange", |r| r.total == 2).await;
+
+    store.update(REQUESTS, doc! { "_id": "r3" }, doc! { "$set": { "title": "Freinage" } }, UpdateOptions::default()).await.unwrap();
+    eventually(&engine, "freinage", |r| r.total == 1).await;
+    // Still found by its description
+    assert_eq!(ids(&search(&engine, SearchRequest::new("vidange")).await), ["r6", "r3"]);
+
+    store.delete(REQUESTS, doc! { "_id": "r6" }).await.unwrap();
+    eventually(&engine, "moteur", |r| r.total == 0).await;
+
+    // Writes in a transaction show once committed
+    let transaction = store.begin().await.unwrap();
+    store.insert(REQUESTS.in_transaction(Some(&transaction)), doc! { "_id": "r7", "title": "Climatisation" }).await.unwrap();
+    store.commit(&transaction).await.unwrap();
+    eventually(&engine, "climatisation", |r| r.total == 1).await;
+
+    assert_eq!(engine.reindex("autodin", "requests").await.unwrap(), 5);
+}
+
+#[tokio::test]
+async fn test_configuration() {
+    let (engine, _store) = searchable().await;
+
+    assert_eq!(engine.config("autodin", "requests").await.unwrap(), Some(requests_config()));
+    assert!(matches!(
+        engine.search("autodin", "notes", &SearchRequest::new("x")).await,
+        Err(SearchError::NotConfigured(_))
+    ));
+    assert!(matches!(
+        engine.search("other", "requests", &SearchRequest::new("x")).await,
+        Err(SearchError::NotConfigured(_))
+    ));
+
+    for (config, expected) in [
+        (json!({ "fields": [] }), "at least one field"),
+        (json!({ "fields": ["title", "title"] }), "twice"),
+        (json!({ "fields": ["title"], "boosts": { "brand": 2 } }), "not a searched field"),
+        (json!({ "fields": ["title"], "boosts": { "title": 0 } }), "positive"),
+        (json!({ "fields": ["title"], "language": "klingon" }), "klingon"),
+        (json!({ "fields": ["title"], "weights": {} }), "weights"),
+    ] {
+        match SearchConfig::from_json(config) {
+            Err(SearchError::Invalid(e)) => assert!(e.contains(expected), "{}", e),
+            other => panic!("expected an invalid configuration, got {:?}", other),
+        }
+    }
+    assert!(engine.configure("autodin", "spu_search", requests_config()).await.is_err());
+
+    let unknown_facet = SearchRequest { filters: vec![("color".to_string(), "red".to_string())], ..SearchRequest::default() };
+    assert!(matches!(engine.search("autodin", "requests", &unknown_facet).await, Err(SearchError::Invalid(_))));
+    let too_many = SearchRequest { limit: 1000, ..SearchRequest::default() };
+    assert!(matches!(engine.search("autodin", "requests", &too_many).await, Err(SearchError::Invalid(_))));
+
+    assert!(engine.remove("autodin", "requests").await.unwrap());
+    assert!(!engine.remove("autodin", "requests").await.unwrap());
+    assert!(matches!(
+        engine.search("autodin", "requests", &SearchRequest::new("x")).await,
+        Err(SearchError::NotConfigured(_))
+    ));
+}
+
+#[tokio::test]
+async fn test_search_from_scripts() {
+    let store = Arc::new(MemoryStore::new());
+    let engine = Arc::new(SearchEngine::new(store.clone()));
+    let runtime = SPURuntime::new();
+    runtime.register_class("database".to_string(), Arc::new(DatabaseCoprocessor::with_store(store.clone()).with_search(engine))).await;
+    store.insert(REQUESTS, doc! { "_id": "r1", "title": "Phare cassé", "brand": "Renault" }).await.unwrap();
+
+    let script = r#"
+INSTANTIATE database db
+CALL db configure_search {"collection": "requests", "workspace": "autodin", "config": {"fields": ["title"], "facets": ["brand"]}} saved
+CALL db search {"collection": "requests", "workspace": "autodin", "query": "phares casses", "facets": ["brand"]} found
+RETURN $found
+"#;
+    let admin = Caller::new("a-1", "autodin", vec!["admin".to_string()]);
+    let found = runtime.execute_as(script, HashMap::new(), admin).await.unwrap().to_json();
+    assert_eq!(found["total"], json!(1.0));
+    assert_eq!(found["hits"][0]["document"]["title"], json!("Phare cassé"));
+    assert_eq!(found["hits"][0]["highlights"]["title"], json!("<mark>Phare</mark> <mark>cassé</mark>"));
+    assert_eq!(found["facets"]["brand"], json!([{ "value": "Renault", "count": 1.0 }]));
+
+    let invalid = runtime.execute(r#"
+INSTANTIATE database db
+CALL db search {"collection": "requests", "workspace": "autodin", "mode": "some"} found
+"#).await;
+    assert!(invalid.unwrap_err().contains("'mode'"));
+    let unconfigured = runtime.execute(r#"
+INSTANTIATE database db
+CALL db drop_search {"collection": "requests", "workspace": "autodin"} dropped
+"#).await;
+    assert!(unconfigured.unwrap_err().contains("Only admins of autodin"));
+    assert_eq!(store.count(Namespace::new("autodin", "spu_search"), Document::new()).await.unwrap(), 1);
+}
+
+#[tokio::test]
+async fn test_hidden_fields_are_not_searched() {
+    let store = Arc::new(MemoryStore::new());
+    let engine = Arc::new(SearchEngine::new(store.clone()));
+    let db = DatabaseCoprocessor::with_store(store.clone()).with_search(engine);
+    let admin = Caller::new("a-1", "autodin", vec!["admin".to_string()]);
+    let policy = json!({
+        "workspace": "autodin",
+        "policy": {
+            "admin": ["admin"],
+            "collections": {
+                "requests": {
+                    "read": ["*"],
+                    "fields": { "internal_notes": { "read": ["professionnel"] } }
+                }
+            }
+        }
+    });
+    db.invoke_as("set_policy", Data::from_json(policy), Some(&admin)).await.unwrap();
+    store.insert_many(REQUESTS, vec![
+        doc! { "_id": "r1", "title": "Phare cassé", "internal_notes": "Mauvais payeur" },
+        doc! { "_id": "r2", "title": "Mauvais démarrage", "internal_notes": "Client fidèle" },
+    ]).await.unwrap();
+    let config = json!({ "collection": "requests", "workspace": "autodin", "config": { "fields": ["title", "internal_notes"] } });
+    db.invoke_as("configure_search", Data::from_json(config), Some(&admin)).await.unwrap();
+    let pro = Caller::new("p-1", "autodin", vec!["professionnel".to_string()]);
+    let marie = Caller::new("u-1", "autodin", vec!["particulier".to_string()]);
+    let query = |query: &str| Data::from_json(json!({ "collection": "requests", "workspace": "autodin", "query": query }));
+
+    // Those who may read the notes find documents by them
+    let found = db.invoke_as("search", query("payeur"), Some(&pro)).await.unwrap().to_plain_json();
+    assert_eq!(found["total"], json!(1.0));
+    assert_eq!(found["hits"][0]["id"], json!("r1"));
+
+    // Others only by the fields they see
+    let found = db.invoke_as("search", query("payeur"), Some(&marie)).await.unwrap().to_plain_json();
+    assert_eq!(found["total"], json!(0.0));
+    let found = db.invoke_as("search", query("mauvais"), Some(&marie)).await.unwrap().to_plain_json();
+    assert_eq!(found["total"], json!(1.0));
+    assert_eq!(found["hits"][0]["id"], json!("r2"));
+    assert_eq!(found["hits"][0]["document"], json!({ "_id": "r2", "title": "Mauvais démarrage" }));
+    assert_eq!(found["hits"][0]["highlights"], json!({ "title": "<mark>Mauvais</mark> démarrage" }));
+}
@@ -0,0 +1,196 @@
+//! Session tests
+//!
+//! Starting sessions, rotating refresh tokens and catching their reuse,
+//! logging out one or all devices, the revocation list, and the `auth`
+//! coprocessor on top, all on the in-memory store.
+
+use chrono::{Duration, Utc};
+use serde_json::json;
+use spu_core::{
+    auth::sessions::{MemorySessionStore, SessionError, SessionPolicy, SessionStore, Sessions},
+    auth::tokens::{Claims, KeySet, TokenError, TokenKey, Tokens},
+    coprocessors::AuthCoprocessor,
+    runtime::SPURuntime,
+    Data,
+};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+fn sessions() -> (Sessions, Arc<MemorySessionStore>) {
+    let store = Arc::new(MemorySessionStore::new());
+    let tokens = Arc::new(Tokens::new(KeySet::new(TokenKey::hs256("k1", b"secret"))));
+    (Sessions::new(store.clone(), tokens), store)
+}
+
+fn marie(sessions: &Sessions) -> Claims {
+    let mut claims = sessions.tokens().claims("u-1", "autodin");
+    claims.email = Some("marie@garage.be".to_string());
+    claims.roles = vec!["particulier".to_string()];
+    claims
+}
+
+#[tokio::test]
+async fn test_sessions_start_and_refresh() {
+    let (sessions, store) = sessions();
+    let grant = sessions.start(marie(&sessions), Some("Firefox"), Some("10.0.0.1")).await.unwrap();
+
+    let claims = sessions.authenticate(&grant.access_token).await.unwrap();
+    assert_eq!(claims.sid.as_deref(), Some(grant.session.id.as_str()));
+    assert_eq!(grant.session.expires_at - grant.session.created_at, Duration::days(30));
+
+    // Only a hash of the refresh token is kept
+    let stored = store.load("autodin", &grant.session.id).await.unwrap().unwrap();
+    assert!(!stored.refresh_hash.contains(&grant.refresh_token));
+    assert_eq!((stored.device.as_deref(), stored.ip.as_deref()), (Some("Firefox"), Some("10.0.0.1")));
+
+    let refreshed = sessions.refresh("autodin", &grant.refresh_token, Some("10.0.0.2")).await.unwrap();
+    assert_ne!(refreshed.refresh_token, grant.refresh_token);
+    assert_eq!(refreshed.session.id, grant.session.id);
+    let claims = sessions.authenticate(&refreshed.access_token).await.unwrap();
+    assert_eq!((claims.sub.as_str(), claims.roles.clone()), ("u-1", vec!["particulier".to_string()]));
+    assert_eq!(claims.email.as_deref(), Some("marie@garage.be"));
+    let stored = store.load("autodin", &grant.session.id).await.unwrap().unwrap();
+    assert_eq!(stored.ip.as_deref(), Some("10.0.0.2"));
+
+    // Garbage, or a token from another workspace, is just invalid
+    assert_eq!(sessions.refresh("autodin", "nope", None).await, Err(SessionError::Invalid));
+    assert_eq!(sessions.refresh("belgique", &refreshed.refresh_token, None).await, Err(SessionError::Invalid));
+    let forged = format!("{}.{}", grant.session.id, "0".repeat(64));
+    assert_eq!(sessions.refresh("autodin", &forged, None).await, Err(SessionError::Invalid));
+    assert!(sessions.refresh("autodin", &refreshed.refresh_token, None).await.is_ok());
+}
+
+#[tokio::test]
+async fn test_reused_refresh_tokens_revoke_the_session() {
+    let (sessions, _store) = sessions();
+    let grant = sessions.start(marie(&sessions), None, None).await.unwrap();
+    let refreshed = sessions.refresh("autodin", &grant.refresh_token, None).await.unwrap();
+
+    // The replaced token comes back: whoever holds either is cut off
+    assert_eq!(sessions.refresh("autodin", &grant.refresh_token, None).await, Err(SessionError::Revoked));
+    assert_eq!(sessions.refresh("autodin", &refreshed.refresh_token, None).await, Err(SessionError::Revoked));
+    assert_eq!(sessions.authenticate(&refreshed.access_token).await, Err(SessionError::Token(TokenError::Revoked)));
+}
+
+#[tokio::test]
+async fn test_expired_sessions() {
+    let store = Arc::new(MemorySessionStore::new());
+    let tokens = Arc::new(Tokens::new(KeySet::new(TokenKey::hs256("k1", b"secret"))));
+    let sessions = Sessions::new(store, tokens).with_policy(SessionPolicy { refresh_ttl: Duration::seconds(-1) });
+    let grant = sessions.start(marie(&sessions), None, None).await.unwrap();
+
+    assert_eq!(sessions.refresh("autodin", &grant.refresh_token, None).await, Err(SessionError::Expired));
+    assert!(sessions.list("autodin", "u-1").await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn test_logout() {
+    let (sessions, _store) = sessions();
+    let laptop = sessions.start(marie(&sessions), Some("laptop"), None).await.unwrap();
+    let phone = sessions.start(marie(&sessions), Some("phone"), None).await.unwrap();
+
+    let listed = sessions.list("autodin", "u-1").await.unwrap();
+    assert_eq!(listed.iter().map(|s| s.device.as_deref().unwrap()).collect::<Vec<_>>(), ["phone", "laptop"]);
+
+    // The access token stops working at once, not when it expires
+    let claims = sessions.authenticate(&laptop.access_token).await.unwrap();
+    assert!(sessions.logout(&claims).await.unwrap());
+    assert_eq!(sessions.authenticate(&laptop.access_token).await, Err(SessionError::Token(TokenError::Revoked)));
+    assert_eq!(sessions.refresh("autodin", &laptop.refresh_token, None).await, Err(SessionError::Revoked));
+    assert!(!sessions.logout(&claims).await.unwrap());
+
+    // The other device is untouched
+    assert!(sessions.authenticate(&phone.access_token).await.is_ok());
+    assert_eq!(sessions.list("autodin", "u-1").await.unwrap().len(), 1);
+
+    // Sessions are only ended by their owner
+    let paul = sessions.start(sessions.tokens().claims("u-2", "autodin"), None, None).await.unwrap();
+    assert!(!sessions.revoke_for("autodin", "u-2", &phone.session.id).await.unwrap());
+    assert!(sessions.revoke_for("autodin", "u-1", &phone.session.id).await.unwrap());
+    assert_eq!(sessions.authenticate(&phone.access_token).await, Err(SessionError::Token(TokenError::Revoked)));
+    assert!(sessions.authenticate(&paul.access_token).await.is_ok());
+}
+
+#[tokio::test]
+async fn test_logout_all_devices() {
+    let (sessions, store) = sessions();
+    let grants = [
+        sessions.start(marie(&sessions), Some("laptop"), None).await.unwrap(),
+        sessions.start(marie(&sessions), Some("phone"), None).await.unwrap(),
+    ];
+    let paul = sessions.start(sessions.tokens().claims("u-2", "autodin"), None, None).await.unwrap();
+
+    let claims = sessions.authenticate(&grants[0].access_token).await.unwrap();
+    assert_eq!(sessions.logout_all(&claims).await.unwrap(), 2);
+    for grant in &grants {
+        assert_eq!(sessions.authenticate(&grant.access_token).await, Err(SessionError::Token(TokenError::Revoked)));
+        assert_eq!(sessions.refresh("autodin", &grant.refresh_token, None).await, Err(SessionError::Revoked));
+    }
+    assert!(sessions.list("autodin", "u-1").await.unwrap().is_empty());
+    assert!(sessions.authenticate(&paul.access_token).await.is_ok());
+
+    // Tokens without a session are revoked by their id
+    let loose = sessions.tokens().claims("u-3", "autodin");
+    let token = sessions.tokens().sign(&loose).unwrap();
+    assert!(!sessions.logout(&loose).await.unwrap());
+    assert_eq!(sessions.authenticate(&token).await, Err(SessionError::Token(TokenError::Revoked)));
+
+    // Revocations lapse with the tokens they concern
+    store.deny("autodin", "old", Utc::now() - Duration::seconds(1)).await.unwrap();
+    assert!(!store.is_denied("autodin", &["old"]).await.unwrap());
+}
+
+#[tokio::test]
+async fn test_auth_coprocessor_sessions() {
+    let (sessions, _store) = sessions();
+    let sessions = Arc::new(sessions);
+    let runtime = SPURuntime::new();
+    runtime.register_class("auth".to_string(), Arc::new(AuthCoprocessor::new().with_sessions(sessions.clone()))).await;
+
+    let code = runtime.execute(r#"
+INSTANTIATE auth auth1
+CALL auth1 generate_code {"email": "marie@garage.be"} result
+"#).await.unwrap().to_json()["code"].clone();
+
+    let mut verify = HashMap::new();
+    verify.insert("email".to_string(), Data::String("marie@garage.be".to_string()));
+    verify.insert("code".to_string(), Data::from_json(code));
+    verify.insert("device".to_string(), Data::String("Mozilla/5.0 \"quoted\"".to_string()));
+    verify.insert("ip".to_string(), Data::String("10.0.0.1".to_string()));
+    let mut inputs = HashMap::new();
+    inputs.insert("verify".to_string(), Data::Object(verify));
+    let signed_in = runtime.execute_with_inputs("INSTANTIATE auth auth1\nCALL auth1 verify_code $verify result", inputs).await.unwrap().to_json();
+    assert_eq!(signed_in["valid"], json!(true));
+    let access = signed_in["token"].as_str().unwrap().to_string();
+
+    let call = |method: &str, args: serde_json::Value| {
+        let mut inputs = HashMap::new();
+        inputs.insert("args".to_string(), Data::from_json(args));
+        let script = format!("INSTANTIATE auth auth1\nCALL auth1 {} $args result", method);
+        let runtime = &runtime;
+        async move { runtime.execute_with_inputs(&script, inputs).await }
+    };
+
+    let listed = call("sessions", json!({ "token": access })).await.unwrap().to_json();
+    assert_eq!(listed["sessions"][0]["device"], json!("Mozilla/5.0 \"quoted\""));
+    assert_eq!(listed["sessions"][0]["ip"], json!("10.0.0.1"));
+    assert_eq!(listed["sessions"][0]["current"], json!(true));
+    assert!(listed["sessions"][0].get("refresh_hash").is_none());
+
+    let refreshed = call("refresh", json!({ "refresh_token": signed_in["refresh_token"] })).await.unwrap().to_json();
+    assert_eq!(refreshed["valid"], json!(true));
+    assert_eq!(refreshed["session_id"], signed_in["session_id"]);
+    let again = call("refresh", json!({ "refresh_token": "nope" })).await.unwrap().to_json();
+    assert_eq!((&again["valid"], &again["error"]), (&json!(false), &json!("Invalid refresh token")));
+
+    let token = refreshed["token"].as_str().unwrap();
+    let other = call("revoke_session", json!({ "token": token, "session_id": "someone-else" })).await.unwrap().to_json();
+    assert_eq!(other["revoked"], json!(false));
+    let out = call("logout", json!({ "token": token })).await.unwrap().to_json();
+    assert_eq!(out["revoked"], json!(true));
+    let error = call("sessions", json!({ "token": token })).await.unwrap_err();
+    assert!(error.contains("Token revoked"), "{}", error);
+
+    let checked = call("verify_token", json!({ "token": token })).await.unwrap().to_json();
+    assert_eq!((&checked["valid"], &checked["error"]), (&json!(false), &json!("Token revoked")));
+}
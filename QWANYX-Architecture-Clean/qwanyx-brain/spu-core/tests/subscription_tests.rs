@@ -0,0 +1,240 @@
+//! Subscription tests
+//!
+//! Live changes from the in-memory store: filtering, transactions, authorization
+//! and how subscriptions end.
+
+use futures::StreamExt;
+use mongodb::bson::{doc, Document};
+use serde_json::json;
+use spu_core::{
+    auth::policy::{POLICY_COLLECTION, POLICY_ID},
+    auth::sessions::{MemorySessionStore, Sessions},
+    auth::tokens::{Claims, KeySet, TokenKey, Tokens},
+    coprocessors::DatabaseCoprocessor,
+    runtime::SPURuntime,
+    store::{DocumentStore, MemoryStore, Namespace, UpdateOptions},
+    subscriptions::{parse_filter, ClosedPolicy, Notification, NotificationStream, ScriptPolicy, SubscriptionPolicy, Subscription, SubscriptionError, Subscriptions},
+};
+use std::sync::Arc;
+use std::time::Duration;
+
+const REQUESTS: Namespace<'static> = Namespace { workspace: "autodin", collection: "requests", transaction: None };
+
+fn tokens() -> Arc<Tokens> {
+    Arc::new(Tokens::new(KeySet::new(TokenKey::hs256("k1", b"secret"))))
+}
+
+/// A signed token and its claims, for `sub` of autodin with `roles`
+fn sign_in(sub: &str, roles: &[&str]) -> (String, Claims) {
+    let tokens = tokens();
+    let mut claims = tokens.claims(sub, "autodin");
+    claims.roles = roles.iter().map(|role| role.to_string()).collect();
+    (tokens.sign(&claims).unwrap(), claims)
+}
+
+fn admin() -> Claims {
+    sign_in("boss", &["admin"]).1
+}
+
+fn open_with(store: &Arc<MemoryStore>, policy: Arc<dyn SubscriptionPolicy>) -> Subscriptions {
+    let sessions = Sessions::new(Arc::new(MemorySessionStore::new()), tokens());
+    Subscriptions::new(store.clone(), Arc::new(sessions), policy)
+}
+
+/// Subscriptions for anyone signed in
+fn open(store: &Arc<MemoryStore>) -> Subscriptions {
+    open_with(store, Arc::new(ScriptPolicy::new(Arc::new(SPURuntime::new()), "RETURN true").unwrap()))
+}
+
+async fn next(notifications: &mut NotificationStream) -> Notification {
+    tokio::time::timeout(Duration::from_secs(5), notifications.next()).await
+        .expect("no notification")
+        .expect("subscription ended")
+}
+
+/// The event name and data of the next change
+async fn next_change(notifications: &mut NotificationStream) -> (&'static str, serde_json::Value) {
+    match next(notifications).await {
+        Notification::Change { event, data } => (event, data),
+        other => panic!("expected a change, got {:?}", other),
+    }
+}
+
+async fn subscribe(subscriptions: &Subscriptions, filter: Document) -> NotificationStream {
+    subscribe_as(subscriptions, filter, &admin()).await
+}
+
+async fn subscribe_as(subscriptions: &Subscriptions, filter: Document, claims: &Claims) -> NotificationStream {
+    let mut notifications = subscriptions.subscribe(Subscription::new("autodin", "requests", filter), claims).await.unwrap();
+    assert_eq!(next(&mut notifications).await, Notification::Ready);
+    notifications
+}
+
+#[tokio::test]
+async fn test_changes_follow_the_filter() {
+    let store = Arc::new(MemoryStore::new());
+    let subscriptions = open(&store);
+    let mut open_requests = subscribe(&subscriptions, doc! { "status": "open" }).await;
+
+    store.insert(REQUESTS, doc! { "_id": "r1", "status": "open", "brand": "Renault" }).await.unwrap();
+    // Outside the filter, and in another collection or workspace
+    store.insert(REQUESTS, doc! { "_id": "r2", "status": "closed" }).await.unwrap();
+    store.insert(Namespace::new("autodin", "notes"), doc! { "status": "open" }).await.unwrap();
+    store.insert(Namespace::new("other", "requests"), doc! { "status": "open" }).await.unwrap();
+    store.update(REQUESTS, doc! { "_id": "r1" }, doc! { "$set": { "brand": "Peugeot" } }, UpdateOptions::default()).await.unwrap();
+    store.update(REQUESTS, doc! { "_id": "r1" }, doc! { "$set": { "status": "closed" } }, UpdateOptions::default()).await.unwrap();
+    store.delete(REQUESTS, doc! { "_id": "r2" }).await.unwrap();
+
+    assert_eq!(next_change(&mut open_requests).await, ("insert", json!({
+        "collection": "requests", "id": "r1", "document": { "_id": "r1", "status": "open", "brand": "Renault" }
+    })));
+    assert_eq!(next_change(&mut open_requests).await.1["document"]["brand"], json!("Peugeot"));
+    assert_eq!(next_change(&mut open_requests).await, ("leave", json!({ "collection": "requests", "id": "r1" })));
+    assert_eq!(next_change(&mut open_requests).await, ("delete", json!({ "collection": "requests", "id": "r2" })));
+}
+
+#[tokio::test]
+async fn test_only_committed_changes_are_seen() {
+    let store = Arc::new(MemoryStore::new());
+    let subscriptions = open(&store);
+    let mut requests = subscribe(&subscriptions, Document::new()).await;
+
+    let aborted = store.begin().await.unwrap();
+    store.insert(REQUESTS.in_transaction(Some(&aborted)), doc! { "_id": "dropped" }).await.unwrap();
+    store.abort(&aborted).await.unwrap();
+
+    let committed = store.begin().await.unwrap();
+    store.insert(REQUESTS.in_transaction(Some(&committed)), doc! { "_id": "kept" }).await.unwrap();
+    assert!(tokio::time::timeout(Duration::from_millis(50), requests.next()).await.is_err());
+    store.commit(&committed).await.unwrap();
+
+    assert_eq!(next_change(&mut requests).await.1["id"], json!("kept"));
+}
+
+#[tokio::test]
+async fn test_scripts_writes_are_seen() {
+    let store = Arc::new(MemoryStore::new());
+    let runtime = SPURuntime::new();
+    runtime.register_class("database".to_string(), Arc::new(DatabaseCoprocessor::with_store(store.clone()))).await;
+    let subscriptions = open(&store);
+    let mut requests = subscribe(&subscriptions, doc! { "urgency": "high" }).await;
+
+    let script = r#"
+INSTANTIATE database db
+CALL db insert_many {"collection": "requests", "workspace": "autodin", "documents": [{"urgency": "low"}, {"urgency": "high"}]} result
+"#;
+    runtime.execute(script).await.unwrap();
+
+    let (event, data) = next_change(&mut requests).await;
+    assert_eq!((event, &data["document"]["urgency"]), ("insert", &json!("high")));
+    assert!(data["id"].is_string());
+}
+
+#[tokio::test]
+async fn test_subscriptions_are_authorized() {
+    let store = Arc::new(MemoryStore::new());
+    let runtime = Arc::new(SPURuntime::new());
+    let policy = ScriptPolicy::new(runtime, r#"
+IF $auth.sub == "board"
+    RETURN true
+ELSE
+    RETURN {"allowed": false, "reason": "Not the board"}
+ENDIF
+"#).unwrap();
+    let subscriptions = open_with(&store, Arc::new(policy));
+    let subscription = Subscription::new("autodin", "requests", Document::new());
+
+    // Only verified tokens are accepted
+    let (token, _) = sign_in("board", &[]);
+    let board = subscriptions.authenticate(Some(&token)).await.unwrap();
+    assert_eq!(board.sub, "board");
+    assert!(matches!(subscriptions.authenticate(Some("guess")).await, Err(SubscriptionError::Unauthorized(_))));
+    assert!(matches!(subscriptions.authenticate(None).await, Err(SubscriptionError::Unauthorized(_))));
+
+    let mut allowed = subscriptions.subscribe(subscription.clone(), &board).await.unwrap();
+    assert_eq!(next(&mut allowed).await, Notification::Ready);
+    assert!(matches!(
+        subscriptions.subscribe(subscription.clone(), &sign_in("mechanic", &[]).1).await,
+        Err(SubscriptionError::Forbidden(reason)) if reason == "Not the board"
+    ));
+    // Nor another workspace than the token's
+    let belgique = Subscription::new("belgique", "requests", Document::new());
+    assert!(matches!(subscriptions.subscribe(belgique, &board).await, Err(SubscriptionError::Forbidden(_))));
+
+    // Without a policy, no one may follow anything
+    let closed = open_with(&store, Arc::new(ClosedPolicy));
+    assert!(matches!(closed.subscribe(subscription, &admin()).await, Err(SubscriptionError::Forbidden(_))));
+
+    // A policy that does not parse is refused up front
+    assert!(ScriptPolicy::new(Arc::new(SPURuntime::new()), "CALL").is_err());
+}
+
+#[tokio::test]
+async fn test_subscribers_see_what_they_may_read() {
+    let store = Arc::new(MemoryStore::new());
+    let subscriptions = open(&store);
+    let policy = json!({ "collections": {
+        "requests": { "read_own": ["*"], "fields": { "margin": { "read": ["admin"] } } }
+    } });
+    store.insert(Namespace::new("autodin", POLICY_COLLECTION), doc! { "_id": POLICY_ID, "policy": policy.to_string() }).await.unwrap();
+    let paul = sign_in("paul", &[]).1;
+    let mut own = subscribe_as(&subscriptions, Document::new(), &paul).await;
+
+    store.insert(REQUESTS, doc! { "_id": "theirs", "owner": "marie", "margin": 120 }).await.unwrap();
+    store.insert(REQUESTS, doc! { "_id": "mine", "owner": "paul", "margin": 80 }).await.unwrap();
+    assert_eq!(next_change(&mut own).await, ("insert", json!({
+        "collection": "requests", "id": "mine", "document": { "_id": "mine", "owner": "paul" }
+    })));
+
+    // Nor may they filter on what they cannot see
+    assert!(matches!(
+        subscriptions.subscribe(Subscription::new("autodin", "requests", doc! { "margin": 80 }), &paul).await,
+        Err(SubscriptionError::Forbidden(_))
+    ));
+}
+
+#[tokio::test]
+async fn test_invalid_subscriptions() {
+    let store = Arc::new(MemoryStore::new());
+    let subscriptions = open(&store);
+
+    for (subscription, expected) in [
+        (Subscription::new("autodin", "requests", doc! { "status": { "$near": 1 } }), "$near"),
+        (Subscription::new("autodin", "", Document::new()), "Missing collection"),
+    ] {
+        match subscriptions.subscribe(subscription, &admin()).await {
+            Err(SubscriptionError::Invalid(e)) => assert!(e.contains(expected), "{}", e),
+            other => panic!("expected an invalid subscription, got {:?}", other.err()),
+        }
+    }
+    assert!(matches!(
+        subscriptions.subscribe(Subscription::new("autodin", "spu_schemas", Document::new()), &admin()).await,
+        Err(SubscriptionError::Forbidden(_))
+    ));
+
+    assert_eq!(parse_filter(r#"{"status": "open"}"#).unwrap(), doc! { "status": "open" });
+    assert!(parse_filter("[1]").is_err());
+}
+
+#[tokio::test]
+async fn test_quiet_and_lagging_subscriptions() {
+    let store = Arc::new(MemoryStore::new());
+    let subscriptions = open(&store).with_heartbeat(Duration::from_millis(20));
+    let mut requests = subscribe(&subscriptions, Document::new()).await;
+    assert_eq!(next(&mut requests).await, Notification::KeepAlive);
+    assert_eq!(Notification::KeepAlive.to_sse(), ": keep-alive\n\n");
+
+    // A subscriber too far behind is told so, and the subscription ends
+    let documents = (0..1100).map(|n| doc! { "n": n }).collect();
+    store.insert_many(REQUESTS, documents).await.unwrap();
+    let error = loop {
+        match next(&mut requests).await {
+            Notification::Error(e) => break e,
+            Notification::Change { .. } | Notification::KeepAlive => {}
+            Notification::Ready => panic!("ready twice"),
+        }
+    };
+    assert!(error.contains("fell behind"), "{}", error);
+    assert!(requests.next().await.is_none());
+    assert!(Notification::Error(error).to_sse().starts_with("event: error\ndata: {\"error\":"));
+}
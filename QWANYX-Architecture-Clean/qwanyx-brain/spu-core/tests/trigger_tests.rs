@@ -0,0 +1,214 @@
+//! Event trigger tests
+//!
+//! Publishing from coprocessors, matching triggers by workspace, event and filter,
+//! and the depth limit on trigger chains.
+
+use spu_core::{
+    coprocessors::{AuthCoprocessor, EmailCoprocessor},
+    events::{Event, EventBus},
+    runtime::SPURuntime,
+    triggers::{MemoryTriggerStore, TriggerDispatcher, MAX_TRIGGER_DEPTH},
+    Coprocessor, CoprocessorError, Data, MethodSignature,
+};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Records what triggers pass it, and can publish events of its own
+struct RecorderCoprocessor {
+    events: EventBus,
+    records: Mutex<Vec<Data>>,
+}
+
+impl RecorderCoprocessor {
+    fn records(&self) -> Vec<Data> {
+        self.records.lock().unwrap().clone()
+    }
+}
+
+#[async_trait::async_trait]
+impl Coprocessor for RecorderCoprocessor {
+    fn class_name(&self) -> String {
+        "recorder".to_string()
+    }
+
+    fn methods(&self) -> Vec<MethodSignature> {
+        vec![]
+    }
+
+    async fn invoke(&self, method: &str, args: Data) -> Result<Data, CoprocessorError> {
+        match method {
+            "record" => {
+                self.records.lock().unwrap().push(args);
+                Ok(Data::Bool(true))
+            }
+            "emit" => {
+                self.records.lock().unwrap().push(args.clone());
+                self.events.publish(Event::new("test.ping", "autodin", args));
+                Ok(Data::Bool(true))
+            }
+            _ => Err(CoprocessorError::MethodNotFound(method.to_string())),
+        }
+    }
+}
+
+async fn create_dispatcher() -> (Arc<SPURuntime>, Arc<TriggerDispatcher>, Arc<RecorderCoprocessor>) {
+    let runtime = Arc::new(SPURuntime::new());
+    let recorder = Arc::new(RecorderCoprocessor {
+        events: runtime.events().clone(),
+        records: Mutex::new(Vec::new()),
+    });
+    runtime.register_class("recorder".to_string(), recorder.clone()).await;
+    runtime.register_class(
+        "auth".to_string(),
+        Arc::new(AuthCoprocessor::new().with_events(runtime.events().clone())),
+    ).await;
+
+    let dispatcher = Arc::new(TriggerDispatcher::new(runtime.clone(), Arc::new(MemoryTriggerStore::new())));
+    (runtime, dispatcher, recorder)
+}
+
+fn inserted(workspace: &str, collection: &str) -> Event {
+    let mut payload = HashMap::new();
+    payload.insert("collection".to_string(), Data::String(collection.to_string()));
+    payload.insert("id".to_string(), Data::String("abc123".to_string()));
+    Event::new("database.inserted", workspace, Data::Object(payload))
+}
+
+/// Wait until the recorder has seen `count` records
+async fn wait_for_records(recorder: &RecorderCoprocessor, count: usize) -> Vec<Data> {
+    for _ in 0..100 {
+        if recorder.records().len() >= count {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    recorder.records()
+}
+
+#[tokio::test]
+async fn test_trigger_matches_workspace_event_and_filter() {
+    let (_, dispatcher, recorder) = create_dispatcher().await;
+    let mut filter = HashMap::new();
+    filter.insert("collection".to_string(), Data::String("requests".to_string()));
+    dispatcher.save(
+        "autodin", "new-request", "database.inserted", filter,
+        "INSTANTIATE recorder r\nCALL r record $event result", true,
+    ).await.unwrap();
+
+    // Wrong collection, wrong workspace, wrong event: nothing runs
+    assert!(dispatcher.dispatch(&inserted("autodin", "users")).await.unwrap().is_empty());
+    assert!(dispatcher.dispatch(&inserted("belgicomics", "requests")).await.unwrap().is_empty());
+    let deleted = Event::new("database.deleted", "autodin", Data::Null);
+    assert!(dispatcher.dispatch(&deleted).await.unwrap().is_empty());
+
+    let outcomes = dispatcher.dispatch(&inserted("autodin", "requests")).await.unwrap();
+    assert_eq!(outcomes.len(), 1);
+    assert!(outcomes[0].1.is_ok());
+
+    // The script received the whole event as $event
+    let records = recorder.records();
+    assert_eq!(records.len(), 1);
+    match &records[0] {
+        Data::Object(event) => {
+            assert_eq!(event.get("name"), Some(&Data::String("database.inserted".to_string())));
+            assert_eq!(event.get("workspace"), Some(&Data::String("autodin".to_string())));
+            match event.get("payload") {
+                Some(Data::Object(payload)) => {
+                    assert_eq!(payload.get("id"), Some(&Data::String("abc123".to_string())));
+                }
+                other => panic!("Unexpected payload: {:?}", other),
+            }
+        }
+        other => panic!("Unexpected record: {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn test_disabled_and_deleted_triggers_do_not_run() {
+    let (_, dispatcher, _) = create_dispatcher().await;
+    let script = "INSTANTIATE recorder r\nCALL r record $event result";
+    dispatcher.save("autodin", "off", "database.inserted", HashMap::new(), script, false).await.unwrap();
+    dispatcher.save("autodin", "gone", "database.inserted", HashMap::new(), script, true).await.unwrap();
+    assert!(dispatcher.delete("autodin", "gone").await.unwrap());
+    assert!(!dispatcher.delete("autodin", "gone").await.unwrap());
+
+    assert!(dispatcher.dispatch(&inserted("autodin", "requests")).await.unwrap().is_empty());
+    assert_eq!(dispatcher.list("autodin").await.unwrap().len(), 1);
+    assert!(dispatcher.list("belgicomics").await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn test_invalid_script_is_rejected() {
+    let (_, dispatcher, _) = create_dispatcher().await;
+    let result = dispatcher.save("autodin", "bad", "database.inserted", HashMap::new(), "NOT_AN_INSTRUCTION", true).await;
+    assert!(result.is_err());
+}
+
+#[tokio::test]
+async fn test_user_verified_runs_trigger_through_bus() {
+    let (runtime, dispatcher, recorder) = create_dispatcher().await;
+    dispatcher.save(
+        "autodin", "welcome", "auth.user_verified", HashMap::new(),
+        "INSTANTIATE recorder r\nCALL r record $event.payload result", true,
+    ).await.unwrap();
+    dispatcher.clone().spawn();
+
+    let script = r#"
+INSTANTIATE auth a
+CALL a generate_code {"email": "jean@example.com", "workspace": "autodin"} issued
+GET issued.code code
+SET verify {"email": "jean@example.com", "code": "$code", "workspace": "autodin"}
+CALL a verify_code $verify result
+"#;
+    runtime.execute(script).await.unwrap();
+
+    let records = wait_for_records(&recorder, 1).await;
+    assert_eq!(records.len(), 1);
+    match &records[0] {
+        Data::Object(payload) => {
+            assert_eq!(payload.get("email"), Some(&Data::String("jean@example.com".to_string())));
+        }
+        other => panic!("Unexpected record: {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn test_trigger_chains_stop_at_max_depth() {
+    let (runtime, dispatcher, recorder) = create_dispatcher().await;
+
+    // Each run publishes the event it listens to
+    dispatcher.save(
+        "autodin", "echo", "test.ping", HashMap::new(),
+        "INSTANTIATE recorder r\nCALL r emit {} result", true,
+    ).await.unwrap();
+    dispatcher.clone().spawn();
+
+    runtime.events().publish(Event::new("test.ping", "autodin", Data::Null));
+
+    let expected = MAX_TRIGGER_DEPTH as usize;
+    let records = wait_for_records(&recorder, expected).await;
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert_eq!(records.len(), expected);
+    assert_eq!(recorder.records().len(), expected);
+}
+
+#[tokio::test]
+async fn test_check_inbox_publishes_received_emails() {
+    let events = EventBus::default();
+    let mut receiver = events.subscribe();
+    let email = EmailCoprocessor::new().with_events(events);
+
+    let mut args = HashMap::new();
+    args.insert("limit".to_string(), Data::Number(2.0));
+    args.insert("workspace".to_string(), Data::String("belgicomics".to_string()));
+    email.invoke("check_inbox", Data::Object(args)).await.unwrap();
+
+    for _ in 0..2 {
+        let event = receiver.recv().await.unwrap();
+        assert_eq!(event.name, "email.received");
+        assert_eq!(event.workspace, "belgicomics");
+        assert_eq!(event.depth, 0);
+    }
+    assert!(receiver.try_recv().is_err());
+}
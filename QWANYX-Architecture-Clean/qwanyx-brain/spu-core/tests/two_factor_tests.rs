@@ -0,0 +1,254 @@
+//! Second factor and magic link tests
+//!
+//! TOTP codes against the RFC 6238 vectors, enrolling and confirming an
+//! authenticator app, replays, recovery codes, sign-in challenges and their
+//! attempt limits, per-role requirements, magic links, and the `auth`
+//! coprocessor handing out a challenge instead of a session.
+
+use chrono::Utc;
+use data_encoding::BASE32_NOPAD;
+use mongodb::bson::doc;
+use spu_core::{
+    auth::{
+        codes::{CodePolicy, MemoryCodeStore},
+        magic_links::{self, LinkError, MagicLinks, MemoryLinkStore},
+        policy::{Policy, POLICY_COLLECTION, POLICY_ID},
+        tokens::{Claims, KeySet, TokenKey, Tokens},
+        two_factor::{self, Factor, MemoryTwoFactorStore, TwoFactor, TwoFactorError, TwoFactorPolicy},
+    },
+    coprocessors::AuthCoprocessor,
+    runtime::SPURuntime,
+    store::{DocumentStore, MemoryStore, Namespace},
+    Data,
+};
+use std::sync::Arc;
+
+fn two_factor() -> TwoFactor {
+    TwoFactor::new(Arc::new(MemoryTwoFactorStore::new()), "test-secret")
+}
+
+/// The app code of the current time step, moved by `offset` steps
+fn code(secret: &str, offset: i64) -> String {
+    let secret = BASE32_NOPAD.decode(secret.as_bytes()).unwrap();
+    let policy = TwoFactorPolicy::default();
+    two_factor::totp(&secret, Utc::now().timestamp().div_euclid(policy.period) + offset, policy.digits)
+}
+
+fn claims(sub: &str, roles: &[&str]) -> Claims {
+    let mut claims = Tokens::new(KeySet::new(TokenKey::hs256("k1", b"secret"))).claims(sub, "garage");
+    claims.email = Some(format!("{}@garage.be", sub));
+    claims.roles = roles.iter().map(|r| r.to_string()).collect();
+    claims
+}
+
+#[test]
+fn test_totp_vectors() {
+    // RFC 6238 appendix B, SHA-1
+    let secret = b"12345678901234567890";
+    assert_eq!(two_factor::totp(secret, 59 / 30, 8), "94287082");
+    assert_eq!(two_factor::totp(secret, 1111111109 / 30, 8), "07081804");
+    assert_eq!(two_factor::totp(secret, 2000000000 / 30, 8), "69279037");
+    assert_eq!(two_factor::totp(secret, 59 / 30, 6), "287082");
+}
+
+#[tokio::test]
+async fn test_enroll_confirm_and_verify() {
+    let two_factor = two_factor();
+    let setup = two_factor.enroll("garage", "u-luc", "luc@garage.be", "Garage Luc").await.unwrap();
+    assert!(setup.uri.starts_with("otpauth://totp/Garage%20Luc:luc%40garage.be?secret="));
+    assert!(setup.uri.contains(&setup.secret));
+    assert!(setup.qr_svg.starts_with("<?xml") || setup.qr_svg.contains("<svg"));
+    assert_eq!(setup.recovery_codes.len(), 10);
+
+    // Not in effect until confirmed, and codes are refused until then
+    assert!(!two_factor.is_enrolled("garage", "u-luc").await.unwrap());
+    assert_eq!(two_factor.verify("garage", "u-luc", &code(&setup.secret, 0)).await, Err(TwoFactorError::NotEnrolled));
+    assert_eq!(two_factor.confirm("garage", "u-luc", "000000").await.unwrap_err(), TwoFactorError::InvalidCode);
+    two_factor.confirm("garage", "u-luc", &code(&setup.secret, 0)).await.unwrap();
+    assert!(two_factor.is_enrolled("garage", "u-luc").await.unwrap());
+    assert_eq!(
+        two_factor.enroll("garage", "u-luc", "luc@garage.be", "Garage Luc").await.unwrap_err(),
+        TwoFactorError::AlreadyEnrolled
+    );
+
+    // A code works once: the one confirming is spent, the next step's is not
+    assert_eq!(two_factor.verify("garage", "u-luc", &code(&setup.secret, 0)).await, Err(TwoFactorError::InvalidCode));
+    assert_eq!(two_factor.verify("garage", "u-luc", &code(&setup.secret, 1)).await, Ok(Factor::App));
+    assert_eq!(two_factor.verify("garage", "u-luc", &code(&setup.secret, 1)).await, Err(TwoFactorError::InvalidCode));
+
+    // Other workspaces know nothing of it
+    assert!(!two_factor.is_enrolled("autodin", "u-luc").await.unwrap());
+}
+
+#[tokio::test]
+async fn test_recovery_codes() {
+    let two_factor = two_factor();
+    let setup = two_factor.enroll("garage", "u-luc", "luc@garage.be", "Garage").await.unwrap();
+    two_factor.confirm("garage", "u-luc", &code(&setup.secret, 0)).await.unwrap();
+
+    // Each works once, however it is typed
+    let first = &setup.recovery_codes[0];
+    let typed = first.replace('-', "").to_uppercase();
+    assert_eq!(two_factor.verify("garage", "u-luc", &typed).await, Ok(Factor::RecoveryCode(9)));
+    assert_eq!(two_factor.verify("garage", "u-luc", first).await, Err(TwoFactorError::InvalidCode));
+
+    // New ones replace the old
+    let fresh = two_factor.regenerate_recovery_codes("garage", "u-luc", &setup.recovery_codes[1]).await.unwrap();
+    assert_eq!(fresh.len(), 10);
+    assert_eq!(two_factor.verify("garage", "u-luc", &setup.recovery_codes[2]).await, Err(TwoFactorError::InvalidCode));
+    assert_eq!(two_factor.verify("garage", "u-luc", &fresh[0]).await, Ok(Factor::RecoveryCode(9)));
+}
+
+#[tokio::test]
+async fn test_challenges() {
+    let two_factor = two_factor().with_policy(TwoFactorPolicy { max_attempts: 3, ..TwoFactorPolicy::default() });
+    let setup = two_factor.enroll("garage", "u-luc", "luc@garage.be", "Garage").await.unwrap();
+
+    // Passing a challenge with a first code confirms the enrollment
+    let (token, challenge) = two_factor.challenge(&claims("u-luc", &["admin"]), Some("Firefox"), Some("10.0.0.1")).await.unwrap();
+    assert_ne!(challenge.id, token);
+    assert_eq!(two_factor.pending("garage", &token).await.unwrap().user, "u-luc");
+    assert_eq!(two_factor.pending("autodin", &token).await.unwrap_err(), TwoFactorError::ChallengeExpired);
+    let (passed, factor) = two_factor.pass("garage", &token, &code(&setup.secret, 0)).await.unwrap();
+    assert_eq!((passed.roles, passed.device, factor), (vec!["admin".to_string()], Some("Firefox".to_string()), Factor::App));
+    assert!(two_factor.is_enrolled("garage", "u-luc").await.unwrap());
+
+    // Each challenge is passed once
+    assert_eq!(two_factor.pass("garage", &token, &code(&setup.secret, 1)).await.unwrap_err(), TwoFactorError::ChallengeExpired);
+
+    // Wrong codes burn it
+    let (token, _) = two_factor.challenge(&claims("u-luc", &[]), None, None).await.unwrap();
+    assert_eq!(two_factor.pass("garage", &token, "000000").await.unwrap_err(), TwoFactorError::InvalidCode);
+    assert_eq!(two_factor.pass("garage", &token, "111111").await.unwrap_err(), TwoFactorError::InvalidCode);
+    assert_eq!(two_factor.pass("garage", &token, "222222").await.unwrap_err(), TwoFactorError::TooManyAttempts);
+    assert_eq!(two_factor.pass("garage", &token, &code(&setup.secret, 1)).await.unwrap_err(), TwoFactorError::ChallengeExpired);
+}
+
+#[tokio::test]
+async fn test_wrong_codes_lock_changes() {
+    let two_factor = two_factor().with_policy(TwoFactorPolicy { max_attempts: 3, ..TwoFactorPolicy::default() });
+    let setup = two_factor.enroll("garage", "u-luc", "luc@garage.be", "Garage").await.unwrap();
+    two_factor.confirm("garage", "u-luc", &code(&setup.secret, 0)).await.unwrap();
+
+    // A right code clears the wrong ones before it
+    for wrong in ["000000", "111111"] {
+        assert_eq!(two_factor.disable("garage", "u-luc", &[], wrong).await.unwrap_err(), TwoFactorError::InvalidCode);
+    }
+    let fresh = two_factor.regenerate_recovery_codes("garage", "u-luc", &code(&setup.secret, 1)).await.unwrap();
+
+    // Wrong codes to disable it or to renew its recovery codes add up
+    assert_eq!(two_factor.disable("garage", "u-luc", &[], "000000").await.unwrap_err(), TwoFactorError::InvalidCode);
+    assert_eq!(two_factor.regenerate_recovery_codes("garage", "u-luc", "111111").await.unwrap_err(), TwoFactorError::InvalidCode);
+    assert_eq!(two_factor.disable("garage", "u-luc", &[], "222222").await.unwrap_err(), TwoFactorError::Locked);
+
+    // Then even a right code is refused for a while
+    assert_eq!(
+        two_factor.disable("garage", "u-luc", &[], &fresh[0]).await.unwrap_err(),
+        TwoFactorError::Locked
+    );
+    assert!(two_factor.is_enrolled("garage", "u-luc").await.unwrap());
+}
+
+#[tokio::test]
+async fn test_required_by_role() {
+    let store: Arc<dyn DocumentStore> = Arc::new(MemoryStore::new());
+    let policy = Policy { two_factor: vec!["admin".to_string()], ..Policy::default() };
+    let document = doc! { "_id": POLICY_ID, "policy": policy.to_json().to_string() };
+    store.insert(Namespace::new("garage", POLICY_COLLECTION), document).await.unwrap();
+    let two_factor = two_factor().with_policies(store);
+
+    let admin = vec!["admin".to_string()];
+    assert!(two_factor.required("garage", &admin).await.unwrap());
+    assert!(!two_factor.required("garage", &["client".to_string()]).await.unwrap());
+    assert!(!two_factor.required("autodin", &admin).await.unwrap());
+
+    let setup = two_factor.enroll("garage", "u-luc", "luc@garage.be", "Garage").await.unwrap();
+    two_factor.confirm("garage", "u-luc", &code(&setup.secret, 0)).await.unwrap();
+    assert_eq!(
+        two_factor.disable("garage", "u-luc", &admin, &code(&setup.secret, 1)).await.unwrap_err(),
+        TwoFactorError::Required
+    );
+    two_factor.disable("garage", "u-luc", &[], &code(&setup.secret, 1)).await.unwrap();
+    assert!(!two_factor.is_enrolled("garage", "u-luc").await.unwrap());
+}
+
+#[tokio::test]
+async fn test_magic_links() {
+    let links = MagicLinks::new(Arc::new(MemoryLinkStore::new()), "test-secret");
+    let (token, expires_at) = links.issue("garage", " Luc@Garage.be").await.unwrap();
+    assert!(token.starts_with("mlk_garage."));
+    assert!(expires_at > Utc::now());
+    assert_eq!(
+        magic_links::link("https://garage.be/auth/magic-link", "mlk_a.b"),
+        "https://garage.be/auth/magic-link?token=mlk_a.b"
+    );
+    assert_eq!(magic_links::link("https://garage.be/?lang=fr", "t"), "https://garage.be/?lang=fr&token=t");
+
+    // Tampered tokens and tokens of other workspaces are turned away
+    let (signed, _) = token.rsplit_once('.').unwrap();
+    assert_eq!(links.verify("garage", &format!("{}.{}", signed, "0".repeat(64))).await, Err(LinkError::Invalid));
+    assert_eq!(links.verify("autodin", &token).await, Err(LinkError::Invalid));
+    assert_eq!(links.verify("garage", "not a link").await, Err(LinkError::Invalid));
+
+    // Each works once
+    assert_eq!(links.verify("garage", &token).await, Ok("luc@garage.be".to_string()));
+    assert_eq!(links.verify("garage", &token).await, Err(LinkError::Invalid));
+
+    // Expired ones are told apart
+    let expired = MagicLinks::new(Arc::new(MemoryLinkStore::new()), "test-secret").with_ttl(chrono::Duration::seconds(-1));
+    let (token, _) = expired.issue("garage", "luc@garage.be").await.unwrap();
+    assert_eq!(expired.verify("garage", &token).await, Err(LinkError::Expired));
+}
+
+#[tokio::test]
+async fn test_auth_coprocessor_second_factor() {
+    let runtime = SPURuntime::new();
+    let two_factor = Arc::new(two_factor());
+    let auth = AuthCoprocessor::new()
+        .with_codes(Arc::new(MemoryCodeStore::new()), CodePolicy::default())
+        .with_links(Arc::new(MemoryLinkStore::new()), chrono::Duration::minutes(15))
+        .with_two_factor(two_factor.clone());
+    runtime.register_class("auth".to_string(), Arc::new(auth)).await;
+
+    let setup = two_factor.enroll("garage", "luc@garage.be", "luc@garage.be", "Garage").await.unwrap();
+    two_factor.confirm("garage", "luc@garage.be", &code(&setup.secret, 0)).await.unwrap();
+
+    // The link is right, but the session waits for the second factor
+    let ask = r#"
+INSTANTIATE auth auth1
+SET input {"email": "luc@garage.be", "workspace": "garage", "url": "https://garage.be/auth/magic-link"}
+CALL auth1 generate_magic_link $input result
+"#;
+    let link = runtime.execute(ask).await.unwrap().to_json();
+    assert!(link["link"].as_str().unwrap().starts_with("https://garage.be/auth/magic-link?token=mlk_garage."));
+
+    let mut inputs = std::collections::HashMap::new();
+    inputs.insert("token".to_string(), Data::String(link["token"].as_str().unwrap().to_string()));
+    let verify = r#"
+INSTANTIATE auth auth1
+SET verify {"token": "$token", "workspace": "garage"}
+CALL auth1 verify_magic_link $verify result
+"#;
+    let signed_in = runtime.execute_with_inputs(verify, inputs).await.unwrap().to_json();
+    assert_eq!(signed_in["valid"], serde_json::json!(false));
+    assert_eq!(signed_in["two_factor_required"], serde_json::json!(true));
+    assert!(signed_in["token"].is_null());
+    let challenge = signed_in["challenge"].as_str().unwrap().to_string();
+
+    let mut inputs = std::collections::HashMap::new();
+    inputs.insert("challenge".to_string(), Data::String(challenge));
+    inputs.insert("code".to_string(), Data::String("000000".to_string()));
+    let pass = r#"
+INSTANTIATE auth auth1
+SET verify {"challenge": "$challenge", "code": "$code", "workspace": "garage"}
+CALL auth1 verify_two_factor $verify result
+"#;
+    let refused = runtime.execute_with_inputs(pass, inputs.clone()).await.unwrap().to_json();
+    assert_eq!((&refused["valid"], &refused["error"]), (&serde_json::json!(false), &serde_json::json!("Invalid code")));
+
+    inputs.insert("code".to_string(), Data::String(code(&setup.secret, 1)));
+    let passed = runtime.execute_with_inputs(pass, inputs).await.unwrap().to_json();
+    assert_eq!(passed["valid"], serde_json::json!(true));
+    assert_eq!(passed["email"], serde_json::json!("luc@garage.be"));
+    assert!(passed["token"].as_str().is_some_and(|token| !token.is_empty()));
+}
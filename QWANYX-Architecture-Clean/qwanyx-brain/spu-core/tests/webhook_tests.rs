@@ -0,0 +1,153 @@
+//! Inbound webhook tests
+//!
+//! Signature and token checks, body parsing and script results, against the
+//! in-memory store.
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use spu_core::{
+    runtime::SPURuntime,
+    webhooks::{MemoryWebhookStore, WebhookAuth, WebhookError, WebhookRouter},
+    Data,
+};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+const SECRET: &str = "webhook-test-secret";
+
+fn create_router() -> WebhookRouter {
+    WebhookRouter::new(Arc::new(SPURuntime::new()), Arc::new(MemoryWebhookStore::new()))
+}
+
+fn hmac_auth() -> WebhookAuth {
+    serde_json::from_value(serde_json::json!({ "type": "hmac", "secret": SECRET })).unwrap()
+}
+
+fn token_auth() -> WebhookAuth {
+    serde_json::from_value(serde_json::json!({
+        "type": "token",
+        "token": "s3cret",
+        "header": "Authorization"
+    })).unwrap()
+}
+
+fn sign(body: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(SECRET.as_bytes()).unwrap();
+    mac.update(body);
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+fn headers(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+    pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+}
+
+#[tokio::test]
+async fn test_hmac_signed_call_runs_script() {
+    let router = create_router();
+    router.save("autodin", "orders", hmac_auth(), "GET body.amount result", 202, true).await.unwrap();
+
+    let body = br#"{"amount": 125, "currency": "EUR"}"#;
+    let signed = headers(&[("x-hub-signature-256", &sign(body))]);
+
+    let (status, result) = router.handle("autodin", "orders", signed, HashMap::new(), body).await.unwrap();
+    assert_eq!(status, 202);
+    assert_eq!(result, Data::Number(125.0));
+}
+
+#[tokio::test]
+async fn test_bad_or_missing_signature_is_rejected() {
+    let router = create_router();
+    router.save("autodin", "orders", hmac_auth(), "SET result ok", 200, true).await.unwrap();
+    let body = br#"{"amount": 125}"#;
+
+    // Signed for a different body
+    let tampered = headers(&[("x-hub-signature-256", &sign(br#"{"amount": 1}"#))]);
+    let result = router.handle("autodin", "orders", tampered, HashMap::new(), body).await;
+    assert!(matches!(result, Err(WebhookError::Unauthorized(_))));
+
+    let result = router.handle("autodin", "orders", HashMap::new(), HashMap::new(), body).await;
+    assert!(matches!(result, Err(WebhookError::Unauthorized(_))));
+
+    let garbage = headers(&[("x-hub-signature-256", "sha256=not-hex")]);
+    let result = router.handle("autodin", "orders", garbage, HashMap::new(), body).await;
+    assert!(matches!(result, Err(WebhookError::Unauthorized(_))));
+}
+
+#[tokio::test]
+async fn test_token_auth_accepts_bearer() {
+    let router = create_router();
+    router.save("autodin", "ping", token_auth(), "SET result pong", 200, true).await.unwrap();
+
+    let bearer = headers(&[("authorization", "Bearer s3cret")]);
+    let (_, result) = router.handle("autodin", "ping", bearer, HashMap::new(), b"").await.unwrap();
+    assert_eq!(result, Data::String("pong".to_string()));
+
+    let raw = headers(&[("authorization", "s3cret")]);
+    assert!(router.handle("autodin", "ping", raw, HashMap::new(), b"").await.is_ok());
+
+    let wrong = headers(&[("authorization", "Bearer s3cres")]);
+    let result = router.handle("autodin", "ping", wrong, HashMap::new(), b"").await;
+    assert!(matches!(result, Err(WebhookError::Unauthorized(_))));
+}
+
+#[tokio::test]
+async fn test_script_gets_raw_body_headers_and_query() {
+    let router = create_router();
+    router.save("autodin", "raw", token_auth(), "SET result $body", 200, true).await.unwrap();
+    router.save("autodin", "source", token_auth(), "GET headers.x-source result", 200, true).await.unwrap();
+    router.save("autodin", "reference", token_auth(), "GET query.ref result", 200, true).await.unwrap();
+
+    let request_headers = headers(&[("authorization", "s3cret"), ("x-source", "crm")]);
+    let query = headers(&[("ref", "42")]);
+
+    let (_, result) = router.handle("autodin", "raw", request_headers.clone(), query.clone(), b"plain text body").await.unwrap();
+    assert_eq!(result, Data::String("plain text body".to_string()));
+
+    let (_, result) = router.handle("autodin", "source", request_headers.clone(), query.clone(), b"").await.unwrap();
+    assert_eq!(result, Data::String("crm".to_string()));
+
+    let (_, result) = router.handle("autodin", "reference", request_headers, query, b"").await.unwrap();
+    assert_eq!(result, Data::String("42".to_string()));
+}
+
+#[tokio::test]
+async fn test_routes_are_scoped_and_can_be_disabled() {
+    let router = create_router();
+    router.save("autodin", "orders", token_auth(), "SET result ok", 200, true).await.unwrap();
+    router.save("autodin", "paused", token_auth(), "SET result ok", 200, false).await.unwrap();
+    let auth = headers(&[("authorization", "s3cret")]);
+
+    let result = router.handle("belgicomics", "orders", auth.clone(), HashMap::new(), b"").await;
+    assert!(matches!(result, Err(WebhookError::NotFound)));
+    let result = router.handle("autodin", "paused", auth.clone(), HashMap::new(), b"").await;
+    assert!(matches!(result, Err(WebhookError::NotFound)));
+
+    assert!(router.delete("autodin", "orders").await.unwrap());
+    let result = router.handle("autodin", "orders", auth, HashMap::new(), b"").await;
+    assert!(matches!(result, Err(WebhookError::NotFound)));
+}
+
+#[tokio::test]
+async fn test_failing_script_is_reported() {
+    let router = create_router();
+    router.save("autodin", "broken", token_auth(), "CALL nothing here {} result", 200, true).await.unwrap();
+
+    let auth = headers(&[("authorization", "s3cret")]);
+    let result = router.handle("autodin", "broken", auth, HashMap::new(), b"{}").await;
+    assert!(matches!(result, Err(WebhookError::ScriptFailed(_))));
+}
+
+#[tokio::test]
+async fn test_save_validates_and_hides_secrets() {
+    let router = create_router();
+    assert!(router.save("autodin", "bad", hmac_auth(), "SET result ok", 500, true).await.is_err());
+    assert!(router.save("autodin", "bad", hmac_auth(), "NOPE", 200, true).await.is_err());
+    let empty: WebhookAuth = serde_json::from_value(serde_json::json!({ "type": "token", "token": "" })).unwrap();
+    assert!(router.save("autodin", "bad", empty, "SET result ok", 200, true).await.is_err());
+
+    let route = router.save("autodin", "orders", hmac_auth(), "SET result ok", 200, true).await.unwrap();
+    let json = route.to_json().to_string();
+    assert!(!json.contains(SECRET));
+    assert!(json.contains("/hooks/autodin/orders"));
+    assert_eq!(router.list("autodin").await.unwrap().len(), 1);
+}
@@ -0,0 +1,326 @@
+//! Durable workflow tests
+//!
+//! Run against the in-memory store with a counting coprocessor so that
+//! suspension, resumption and retry behaviour can be observed directly.
+
+use spu_core::{
+    auth::policy::Caller,
+    runtime::{CallPolicy, SPURuntime},
+    workflow::{MemoryWorkflowStore, WorkflowEngine, WorkflowStatus, WorkflowStore},
+    Coprocessor, CoprocessorError, Data, MethodSignature,
+};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Records every call it receives; `hang` never returns the first time
+#[derive(Default)]
+struct CounterCoprocessor {
+    calls: Mutex<Vec<Data>>,
+    hung: AtomicBool,
+}
+
+impl CounterCoprocessor {
+    fn calls(&self) -> Vec<Data> {
+        self.calls.lock().unwrap().clone()
+    }
+}
+
+#[async_trait::async_trait]
+impl Coprocessor for CounterCoprocessor {
+    fn class_name(&self) -> String {
+        "counter".to_string()
+    }
+
+    fn methods(&self) -> Vec<MethodSignature> {
+        vec![]
+    }
+
+    async fn invoke(&self, method: &str, args: Data) -> Result<Data, CoprocessorError> {
+        let count = {
+            let mut calls = self.calls.lock().unwrap();
+            calls.push(args);
+            calls.len()
+        };
+        match method {
+            "bump" => Ok(Data::Number(count as f64)),
+            "hang" => {
+                if !self.hung.swap(true, Ordering::SeqCst) {
+                    tokio::time::sleep(Duration::from_secs(3600)).await;
+                }
+                Ok(Data::Number(count as f64))
+            }
+            _ => Err(CoprocessorError::MethodNotFound(method.to_string())),
+        }
+    }
+}
+
+async fn create_engine() -> (Arc<WorkflowEngine>, Arc<MemoryWorkflowStore>, Arc<CounterCoprocessor>) {
+    let runtime = SPURuntime::new();
+    let counter = Arc::new(CounterCoprocessor::default());
+    runtime.register_class("counter".to_string(), counter.clone()).await;
+
+    let store = Arc::new(MemoryWorkflowStore::new());
+    let engine = Arc::new(WorkflowEngine::new(Arc::new(runtime), store.clone()));
+    (engine, store, counter)
+}
+
+#[tokio::test]
+async fn test_sleep_suspends_and_wakes() {
+    let (engine, _, counter) = create_engine().await;
+    let script = r#"
+INSTANTIATE counter c
+CALL c bump {} first
+SLEEP 1h
+CALL c bump {} result
+"#;
+
+    let record = engine.start(script, HashMap::new(), CallPolicy::AtMostOnce).await.unwrap();
+    assert_eq!(record.status, WorkflowStatus::Sleeping);
+    assert!(record.wake_at.is_some());
+    assert_eq!(counter.calls().len(), 1);
+
+    // Not due yet
+    assert_eq!(engine.tick().await.unwrap(), 0);
+
+    let record = engine.wake(&record.id).await.unwrap();
+    assert_eq!(record.status, WorkflowStatus::Completed);
+    assert!(matches!(record.result, Some(Data::Number(n)) if n == 2.0));
+    assert_eq!(counter.calls().len(), 2);
+
+    // A completed workflow can't be woken again
+    assert!(engine.wake(&record.id).await.is_err());
+}
+
+#[tokio::test]
+async fn test_tick_resumes_due_sleepers() {
+    let (engine, _, _) = create_engine().await;
+    let script = "SLEEP 0s\nSET result done";
+
+    let record = engine.start(script, HashMap::new(), CallPolicy::AtMostOnce).await.unwrap();
+    assert_eq!(record.status, WorkflowStatus::Sleeping);
+
+    assert_eq!(engine.tick().await.unwrap(), 1);
+    let record = engine.get(&record.id).await.unwrap().unwrap();
+    assert_eq!(record.status, WorkflowStatus::Completed);
+    assert!(matches!(record.result, Some(Data::String(ref s)) if s == "done"));
+}
+
+#[tokio::test]
+async fn test_wait_event_receives_payload() {
+    let (engine, _, _) = create_engine().await;
+    let script = r#"
+WAIT_EVENT order.paid payment
+GET payment.amount result
+"#;
+
+    let record = engine.start(script, HashMap::new(), CallPolicy::AtMostOnce).await.unwrap();
+    assert_eq!(record.status, WorkflowStatus::Waiting);
+    assert_eq!(record.event.as_deref(), Some("order.paid"));
+
+    // Other events don't resume it
+    assert!(engine.deliver_event("order.cancelled", Data::Null).await.unwrap().is_empty());
+
+    let payload = Data::from_json(serde_json::json!({"amount": 42}));
+    let resumed = engine.deliver_event("order.paid", payload).await.unwrap();
+    assert_eq!(resumed.len(), 1);
+    assert_eq!(resumed[0].status, WorkflowStatus::Completed);
+    assert!(matches!(resumed[0].result, Some(Data::Number(n)) if n == 42.0));
+}
+
+#[tokio::test]
+async fn test_started_as_caller_stays_in_their_workspace() {
+    let (engine, _, counter) = create_engine().await;
+    let caller = Caller::new("u-1", "autodin", vec!["admin".to_string()]);
+    let script = r#"
+INSTANTIATE counter c
+SLEEP 1h
+CALL c bump {} result
+"#;
+
+    let record = engine.start_as(script, HashMap::new(), CallPolicy::AtMostOnce, caller.clone()).await.unwrap();
+    assert_eq!(record.workspace(), Some("autodin"));
+
+    // Still confined after resuming from the store
+    let record = engine.wake(&record.id).await.unwrap();
+    assert_eq!(record.status, WorkflowStatus::Completed);
+    assert_eq!(record.caller, Some(caller.clone()));
+    match &counter.calls()[0] {
+        Data::Object(args) => assert!(matches!(args.get("workspace"), Some(Data::String(w)) if w == "autodin")),
+        other => panic!("Unexpected argument: {:?}", other),
+    }
+
+    let script = r#"
+INSTANTIATE counter c
+CALL c bump {"workspace": "garage"} result
+"#;
+    let record = engine.start_as(script, HashMap::new(), CallPolicy::AtMostOnce, caller).await.unwrap();
+    assert_eq!(record.status, WorkflowStatus::Failed);
+    assert!(record.error.unwrap().contains("Forbidden"));
+}
+
+#[tokio::test]
+async fn test_events_delivered_in_a_workspace() {
+    let (engine, _, _) = create_engine().await;
+    let script = "WAIT_EVENT order.paid payment\nSET result paid";
+    let autodin = Caller::new("u-1", "autodin", vec![]);
+    let garage = Caller::new("u-2", "garage", vec![]);
+
+    let ours = engine.start_as(script, HashMap::new(), CallPolicy::AtMostOnce, autodin).await.unwrap();
+    let theirs = engine.start_as(script, HashMap::new(), CallPolicy::AtMostOnce, garage).await.unwrap();
+
+    let resumed = engine.deliver_event_in("autodin", "order.paid", Data::Null).await.unwrap();
+    assert_eq!(resumed.len(), 1);
+    assert_eq!(resumed[0].id, ours.id);
+    let theirs = engine.get(&theirs.id).await.unwrap().unwrap();
+    assert_eq!(theirs.status, WorkflowStatus::Waiting);
+}
+
+#[tokio::test]
+async fn test_checkpoint_after_call() {
+    let (engine, store, _) = create_engine().await;
+    let script = r#"
+INSTANTIATE counter c
+CALL c bump {} first
+WAIT_EVENT go payload
+"#;
+
+    let record = engine.start(script, HashMap::new(), CallPolicy::AtMostOnce).await.unwrap();
+    let saved = store.load(&record.id).await.unwrap().unwrap();
+    assert!(saved.snapshot.pending.is_none());
+    assert!(matches!(saved.snapshot.variables.get("first"), Some(Data::Number(n)) if *n == 1.0));
+    assert_eq!(saved.snapshot.instances.get("c").map(String::as_str), Some("counter"));
+}
+
+#[tokio::test]
+async fn test_resume_inside_loop() {
+    let (engine, _, counter) = create_engine().await;
+    let script = r#"
+INSTANTIATE counter c
+FOREACH item IN $items
+    CALL c bump $item result
+    SLEEP 1h
+ENDFOREACH
+"#;
+
+    let mut inputs = HashMap::new();
+    inputs.insert("items".to_string(), Data::from_json(serde_json::json!(["a", "b", "c"])));
+
+    let mut record = engine.start(script, inputs, CallPolicy::AtMostOnce).await.unwrap();
+    for expected in 1..=3 {
+        assert_eq!(record.status, WorkflowStatus::Sleeping);
+        assert_eq!(counter.calls().len(), expected);
+        record = engine.wake(&record.id).await.unwrap();
+    }
+    assert_eq!(record.status, WorkflowStatus::Completed);
+
+    // Each item is processed exactly once, in order
+    let items: Vec<String> = counter.calls().into_iter()
+        .map(|d| match d {
+            Data::String(s) => s,
+            other => panic!("Unexpected argument: {:?}", other),
+        })
+        .collect();
+    assert_eq!(items, vec!["a", "b", "c"]);
+}
+
+#[tokio::test]
+async fn test_resume_inside_if_branch() {
+    let (engine, _, counter) = create_engine().await;
+    let script = r#"
+INSTANTIATE counter c
+IF $approved == true
+    SLEEP 1h
+    CALL c bump {} result
+ELSE
+    CALL c bump {} rejected
+ENDIF
+"#;
+
+    let mut inputs = HashMap::new();
+    inputs.insert("approved".to_string(), Data::Bool(true));
+
+    let record = engine.start(script, inputs, CallPolicy::AtMostOnce).await.unwrap();
+    assert_eq!(record.status, WorkflowStatus::Sleeping);
+
+    // Resumes in the THEN branch, after the SLEEP
+    let record = engine.wake(&record.id).await.unwrap();
+    assert_eq!(record.status, WorkflowStatus::Completed);
+    assert!(matches!(record.result, Some(Data::Number(n)) if n == 1.0));
+    assert_eq!(counter.calls().len(), 1);
+}
+
+/// Start a workflow whose call hangs and drop it mid-call, as a crash would
+async fn interrupt_mid_call(policy: CallPolicy) -> (Arc<WorkflowEngine>, Arc<CounterCoprocessor>, String) {
+    let (engine, store, counter) = create_engine().await;
+    let script = r#"
+INSTANTIATE counter c
+CALL c hang {"order": 7} result
+"#;
+
+    let started = tokio::time::timeout(
+        Duration::from_millis(200),
+        engine.start(script, HashMap::new(), policy),
+    ).await;
+    assert!(started.is_err(), "call should still be in flight");
+
+    let ids = store.interrupted().await.unwrap();
+    assert_eq!(ids.len(), 1);
+    let saved = store.load(&ids[0]).await.unwrap().unwrap();
+    assert!(saved.snapshot.pending.is_some());
+
+    (engine, counter, ids[0].clone())
+}
+
+#[tokio::test]
+async fn test_interrupted_call_not_retried_at_most_once() {
+    let (engine, counter, id) = interrupt_mid_call(CallPolicy::AtMostOnce).await;
+
+    assert_eq!(engine.recover().await.unwrap(), 1);
+    let record = engine.get(&id).await.unwrap().unwrap();
+    assert_eq!(record.status, WorkflowStatus::Failed);
+    assert!(record.error.unwrap().contains("at-most-once"));
+    assert_eq!(counter.calls().len(), 1);
+}
+
+#[tokio::test]
+async fn test_interrupted_call_retried_with_idempotency_key() {
+    let (engine, counter, id) = interrupt_mid_call(CallPolicy::Idempotent).await;
+
+    assert_eq!(engine.recover().await.unwrap(), 1);
+    let record = engine.get(&id).await.unwrap().unwrap();
+    assert_eq!(record.status, WorkflowStatus::Completed);
+
+    // Both attempts carry the same key so the callee can deduplicate
+    let keys: Vec<Data> = counter.calls().into_iter()
+        .map(|d| match d {
+            Data::Object(obj) => obj.get("idempotency_key").cloned().unwrap(),
+            other => panic!("Unexpected argument: {:?}", other),
+        })
+        .collect();
+    assert_eq!(keys.len(), 2);
+    assert!(matches!((&keys[0], &keys[1]), (Data::String(a), Data::String(b)) if a == b && a.starts_with(&id)));
+}
+
+#[tokio::test]
+async fn test_sleep_rejects_unrepresentable_durations() {
+    let (engine, _, _) = create_engine().await;
+
+    // Out of range for chrono, then past the end of the calendar: the
+    // workflow fails instead of the executor panicking
+    for script in ["SLEEP 9223372036854775807d", "SLEEP 100000000d"] {
+        let record = engine.start(script, HashMap::new(), CallPolicy::AtMostOnce).await.unwrap();
+        assert_eq!(record.status, WorkflowStatus::Failed);
+        assert!(record.error.as_deref().unwrap().contains("Invalid duration"), "{:?}", record.error);
+    }
+}
+
+#[tokio::test]
+async fn test_plain_sleep_is_capped() {
+    let runtime = SPURuntime::new();
+
+    let error = runtime.execute("SLEEP 1h").await.unwrap_err();
+    assert!(error.contains("workflow"), "{}", error);
+    assert!(matches!(runtime.execute("SLEEP 0s").await, Ok(Data::Null)));
+}
@@ -0,0 +1,203 @@
+//! Workspace registry tests
+//!
+//! Resolving workspaces from hostnames and names, keeping each in its
+//! database, defaulting calls to the registry's default workspace, and
+//! confining scripts to their workspace.
+
+use futures::StreamExt;
+use mongodb::bson::{doc, Document};
+use serde_json::json;
+use spu_core::{
+    auth::api_keys::{ApiKeyError, ApiKeys, MongoApiKeyStore},
+    auth::oidc::{MemoryOidcStore, Oidc},
+    auth::policy::Caller,
+    coprocessors::{AuthCoprocessor, DatabaseCoprocessor},
+    runtime::SPURuntime,
+    store::{DocumentStore, FindOptions, MemoryStore, Namespace},
+    workspaces::{Workspace, WorkspaceDatabases, WorkspaceStore, Workspaces},
+    Coprocessor, CoprocessorError, Data,
+};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+fn registry() -> Workspaces {
+    Workspaces::from_json(json!({
+        "default": "autodin",
+        "workspaces": [
+            {
+                "id": "autodin",
+                "name": "Autodin",
+                "hostnames": ["autodin.be", "www.autodin.be"],
+                "origins": ["https://autodin.be"]
+            },
+            {
+                "id": "garage",
+                "database": "garage_prod",
+                "hostnames": ["garage.example"],
+                "origins": ["https://garage.example"],
+                "users": ["Admin@garage.example"]
+            }
+        ]
+    })).unwrap()
+}
+
+#[test]
+fn test_registry() {
+    let workspaces = registry();
+    assert_eq!(workspaces.ids(), ["autodin", "garage"]);
+    assert_eq!(workspaces.default_workspace().name(), "Autodin");
+    assert_eq!(workspaces.database_of("autodin").unwrap(), "autodin");
+    assert_eq!(workspaces.database_of("garage").unwrap(), "garage_prod");
+    assert_eq!(workspaces.database_of("belgique").unwrap_err(), "Unknown workspace 'belgique'");
+
+    // Only listed users may sign in to a closed workspace
+    let garage = workspaces.get("garage").unwrap();
+    assert!(garage.admits("admin@garage.example"));
+    assert!(!garage.admits("someone@else.be"));
+    assert!(workspaces.default_workspace().admits("someone@else.be"));
+
+    assert!(workspaces.allows_origin("https://garage.example"));
+    assert!(!workspaces.allows_origin("https://evil.example"));
+    assert!(Workspaces::of(&["autodin"]).unwrap().allows_origin("https://evil.example"));
+
+    // Broken registries are refused rather than half served
+    assert!(Workspaces::of(&["autodin", "autodin"]).is_err());
+    assert!(Workspaces::new(vec![Workspace::new("autodin")], "garage").is_err());
+    assert!(Workspaces::from_json(json!({ "workspaces": [{ "id": "a", "database": "a.b" }] })).is_err());
+    assert!(Workspaces::from_json(json!({ "workspaces": [{ "id": "a", "hostnames": ["x.be"] }, { "id": "b", "hostnames": ["X.be"] }] })).is_err());
+    assert!(Workspaces::from_json(json!({ "workspaces": [{ "id": "a", "domain": "a.be" }] })).is_err());
+    assert!(Workspaces::from_json(json!({ "workspaces": [] })).is_err());
+}
+
+#[test]
+fn test_resolve() {
+    let workspaces = registry();
+    let resolve = |host: Option<&str>, named: Option<&str>| workspaces.resolve(host, named).map(|w| w.id.clone());
+
+    // The hostname decides, whatever its case or port
+    assert_eq!(resolve(Some("garage.example"), None).unwrap(), "garage");
+    assert_eq!(resolve(Some("WWW.autodin.be:8443"), None).unwrap(), "autodin");
+    assert_eq!(resolve(Some("garage.example"), Some("garage")).unwrap(), "garage");
+    assert_eq!(resolve(Some("garage.example"), Some("autodin")).unwrap_err(), "Workspace 'autodin' is not served here");
+
+    // Elsewhere, the name, then the default
+    assert_eq!(resolve(Some("localhost:5002"), Some("garage")).unwrap(), "garage");
+    assert_eq!(resolve(Some("localhost:5002"), None).unwrap(), "autodin");
+    assert_eq!(resolve(None, Some("")).unwrap(), "autodin");
+    assert_eq!(resolve(None, Some("belgique")).unwrap_err(), "Unknown workspace 'belgique'");
+}
+
+#[tokio::test]
+async fn test_workspace_store() {
+    let inner = Arc::new(MemoryStore::new());
+    let store = WorkspaceStore::new(inner.clone(), Arc::new(registry()));
+
+    let mut changes = store.watch(Namespace::new("garage", "cars")).await.unwrap();
+    store.insert(Namespace::new("garage", "cars"), doc! { "model": "Clio" }).await.unwrap();
+
+    // Kept in the workspace's database, and reported under the workspace
+    let stored = inner.find(Namespace::new("garage_prod", "cars"), Document::new(), FindOptions::default()).await.unwrap();
+    assert_eq!(stored.len(), 1);
+    assert_eq!(inner.count(Namespace::new("garage", "cars"), Document::new()).await.unwrap(), 0);
+    let change = changes.next().await.unwrap().unwrap();
+    assert_eq!(change.workspace, "garage");
+
+    let error = store.count(Namespace::new("belgique", "cars"), Document::new()).await.unwrap_err();
+    assert_eq!(error, "Unknown workspace 'belgique'");
+}
+
+#[tokio::test]
+async fn test_workspace_databases() {
+    // The client only connects once a collection is used
+    let client = mongodb::Client::with_uri_str("mongodb://localhost:27017").await.unwrap();
+    let databases = WorkspaceDatabases::new(client, Arc::new(registry()));
+    assert_eq!(databases.database("garage").unwrap().name(), "garage_prod");
+    assert_eq!(databases.database("autodin").unwrap().name(), "autodin");
+    assert_eq!(databases.database("belgique").unwrap_err(), "Unknown workspace 'belgique'");
+
+    // A key naming a workspace that isn't served is refused without a query
+    let api_keys = ApiKeys::new(Arc::new(MongoApiKeyStore::new(databases)));
+    assert!(matches!(api_keys.authenticate("spk_belgique.abc.def").await, Err(ApiKeyError::Invalid)));
+}
+
+fn object(json: serde_json::Value) -> Data {
+    Data::from_json(json)
+}
+
+fn database() -> DatabaseCoprocessor<WorkspaceStore<Arc<MemoryStore>>> {
+    let workspaces = Arc::new(registry());
+    DatabaseCoprocessor::with_store(WorkspaceStore::new(Arc::new(MemoryStore::new()), workspaces.clone()))
+        .with_workspaces(workspaces)
+}
+
+fn count(result: &Data) -> serde_json::Value {
+    result.to_json()["total"].clone()
+}
+
+#[tokio::test]
+async fn test_database_workspaces() {
+    let db = database();
+    db.invoke("store", object(json!({ "collection": "cars", "data": { "model": "Clio" } }))).await.unwrap();
+
+    // Calls naming no workspace go to the default one
+    let query = object(json!({ "workspace": "autodin", "collection": "cars" }));
+    assert_eq!(count(&db.invoke("retrieve", query).await.unwrap()), json!(1.0));
+
+    let query = object(json!({ "workspace": "belgique", "collection": "cars" }));
+    match db.invoke("retrieve", query).await {
+        Err(CoprocessorError::InvalidArguments(message)) => assert_eq!(message, "Unknown workspace 'belgique'"),
+        other => panic!("expected an unknown workspace, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn test_auth_default_workspace() {
+    let workspaces = Arc::new(Workspaces::of(&["garage", "autodin"]).unwrap());
+    let auth = AuthCoprocessor::new().with_oidc(Arc::new(Oidc::new(Arc::new(MemoryOidcStore::new()), workspaces)));
+
+    // A code requested naming no workspace is one of the default workspace
+    let generated = auth.invoke("generate_code", object(json!({ "email": "marie@garage.example" }))).await.unwrap();
+    let code = generated.to_json()["code"].as_str().unwrap().to_string();
+    let verify = |workspace: &str| object(json!({ "email": "marie@garage.example", "code": code, "workspace": workspace }));
+    assert_eq!(auth.invoke("verify_code", verify("autodin")).await.unwrap().to_json()["valid"], json!(false));
+    assert_eq!(auth.invoke("verify_code", verify("garage")).await.unwrap().to_json()["valid"], json!(true));
+}
+
+#[tokio::test]
+async fn test_scripts_stay_in_their_workspace() {
+    let runtime = SPURuntime::new();
+    runtime.register_class("database".to_string(), Arc::new(database())).await;
+
+    let store = r#"
+INSTANTIATE database db
+SET args {"collection": "cars", "data": {"model": "Clio"}}
+CALL db store $args result
+RETURN $result
+"#;
+    runtime.execute_in(store, HashMap::new(), "garage").await.unwrap();
+
+    let retrieve = r#"
+INSTANTIATE database db
+SET query {"collection": "cars"}
+CALL db retrieve $query result
+RETURN $result
+"#;
+    assert_eq!(count(&runtime.execute_in(retrieve, HashMap::new(), "garage").await.unwrap()), json!(1.0));
+    assert_eq!(count(&runtime.execute_in(retrieve, HashMap::new(), "autodin").await.unwrap()), json!(0.0));
+
+    // Naming another workspace fails the script
+    let elsewhere = r#"
+INSTANTIATE database db
+SET query {"workspace": "autodin", "collection": "cars"}
+CALL db retrieve $query result
+RETURN $result
+"#;
+    let error = runtime.execute_in(elsewhere, HashMap::new(), "garage").await.unwrap_err();
+    assert!(error.contains("Forbidden: scripts of workspace 'garage' may not reach workspace 'autodin'"), "{}", error);
+
+    // Users are confined to the workspace of their token
+    let admin = Caller::new("u-1", "garage", vec!["admin".to_string()]);
+    assert_eq!(count(&runtime.execute_as(retrieve, HashMap::new(), admin.clone()).await.unwrap()), json!(1.0));
+    let error = runtime.execute_as(elsewhere, HashMap::new(), admin).await.unwrap_err();
+    assert!(error.is_forbidden(), "{}", error);
+}